@@ -0,0 +1,305 @@
+//! A KVM based VMM built from the cloud-hypervisor crates, which plugs a [`PciTestDevice`] into
+//! its PCI segment through [`ChPciSegment`].
+//!
+//! The VMM boots a Linux bzImage with an initramfs, both given on the command line. The guest
+//! finds the device on the configuration mechanism #1 and `lspci` in the initramfs lists it as
+//! 00:01.0. The console of the guest is printed until the guest reboots:
+//!
+//! ```text
+//! cargo run --example cloud_hypervisor_demo --features cloud-hypervisor-demo -- \
+//!     bzImage initramfs.cpio
+//! ```
+//!
+//! The glue in this file is what the device manager of cloud-hypervisor does on its own: the
+//! configuration mechanism sits on the I/O bus, the MSIs of the interrupt groups are signaled
+//! through KVM and every exit of the vCPU is dispatched to the buses.
+
+use kvm_bindings::{kvm_pit_config, kvm_segment, kvm_userspace_memory_region};
+use kvm_ioctls::{Kvm, VcpuExit, VcpuFd, VmFd};
+use pci::{PciBus, PciConfigIo, PciRoot};
+use pcie_tlp::{BarRelocation, ChPciSegment, KvmMsiSink, MsiSink, PciAdapter, PciTestDevice};
+use std::io;
+use std::sync::{Arc, Mutex};
+use vm_allocator::{GsiApic, SystemAllocator};
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceConfig, InterruptSourceGroup,
+    MsiIrqGroupConfig,
+};
+use vm_device::Bus;
+use vm_memory::GuestAddress;
+
+const MEM_SIZE: usize = 512 << 20;
+const GDT_ADDR: usize = 0x500;
+const ZERO_PAGE_ADDR: usize = 0x7000;
+const PML4_ADDR: usize = 0x9000;
+const PDPT_ADDR: usize = 0xa000;
+const PD_ADDR: usize = 0xb000;
+const CMDLINE_ADDR: usize = 0x20000;
+const KERNEL_ADDR: usize = 0x10_0000;
+const INITRD_ADDR: usize = 0x1000_0000;
+
+const CMDLINE: &str = "earlyprintk=serial,ttyS0,115200 keep_bootcon console=ttyS0 \
+                       noapic noacpi pci=conf1 reboot=k panic=-1 rdinit=/init";
+
+const SERIAL_DATA: u16 = 0x3f8;
+const CONFIG_ADDRESS: u64 = 0xcf8;
+
+/// Guest memory backed by an anonymous mapping.
+struct GuestRam(*mut u8);
+
+impl GuestRam {
+    fn new() -> Self {
+        let mem = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                MEM_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(mem, libc::MAP_FAILED);
+        GuestRam(mem as *mut u8)
+    }
+
+    fn write(&self, addr: usize, data: &[u8]) {
+        assert!(addr + data.len() <= MEM_SIZE);
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), self.0.add(addr), data.len()) }
+    }
+
+    fn write_u64(&self, addr: usize, value: u64) {
+        self.write(addr, &value.to_le_bytes());
+    }
+}
+
+/// Interrupt group whose vectors are signaled as MSIs through KVM.
+struct KvmMsiGroup {
+    sink: KvmMsiSink,
+    messages: Mutex<Vec<(u64, u32)>>,
+}
+
+impl InterruptSourceGroup for KvmMsiGroup {
+    fn trigger(&self, index: InterruptIndex) -> io::Result<()> {
+        let (addr, data) = self.messages.lock().unwrap()[index as usize];
+        self.sink.inject(addr, data)
+    }
+
+    fn update(&self, index: InterruptIndex, config: InterruptSourceConfig) -> io::Result<()> {
+        if let InterruptSourceConfig::MsiIrq(config) = config {
+            let addr = (config.high_addr as u64) << 32 | config.low_addr as u64;
+            self.messages.lock().unwrap()[index as usize] = (addr, config.data);
+        }
+        Ok(())
+    }
+}
+
+struct KvmMsiManager(Arc<VmFd>);
+
+impl InterruptManager for KvmMsiManager {
+    type GroupConfig = MsiIrqGroupConfig;
+
+    fn create_group(
+        &self,
+        config: MsiIrqGroupConfig,
+    ) -> io::Result<Arc<Box<dyn InterruptSourceGroup>>> {
+        Ok(Arc::new(Box::new(KvmMsiGroup {
+            sink: KvmMsiSink::new(self.0.clone()),
+            messages: Mutex::new(vec![(0, 0); config.count as usize]),
+        })))
+    }
+
+    fn destroy_group(&self, _: Arc<Box<dyn InterruptSourceGroup>>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Load the kernel, initrd and zero page following the Linux x86 64-bit boot protocol.
+/// Returns the entry point of the kernel.
+fn load_linux(ram: &GuestRam, kernel: &[u8], initrd: &[u8]) -> u64 {
+    assert_eq!(&kernel[0x202..0x206], b"HdrS", "not a bzImage");
+
+    let setup_sects = match kernel[0x1f1] {
+        0 => 4,
+        n => n as usize,
+    };
+    let setup_size = (setup_sects + 1) * 512;
+    ram.write(KERNEL_ADDR, &kernel[setup_size..]);
+    ram.write(INITRD_ADDR, initrd);
+
+    let mut cmdline = CMDLINE.as_bytes().to_vec();
+    cmdline.push(0);
+    ram.write(CMDLINE_ADDR, &cmdline);
+
+    // The setup header is copied verbatim into the zero page, then patched.
+    let mut zero_page = vec![0u8; 4096];
+    let header_end = 0x202 + kernel[0x201] as usize;
+    zero_page[0x1f1..header_end].copy_from_slice(&kernel[0x1f1..header_end]);
+    zero_page[0x210] = 0xff; // type_of_loader
+    zero_page[0x211] |= 0x1; // loadflags: LOADED_HIGH
+    zero_page[0x218..0x21c].copy_from_slice(&(INITRD_ADDR as u32).to_le_bytes());
+    zero_page[0x21c..0x220].copy_from_slice(&(initrd.len() as u32).to_le_bytes());
+    zero_page[0x228..0x22c].copy_from_slice(&(CMDLINE_ADDR as u32).to_le_bytes());
+
+    // e820 map: low memory and everything above 1MB.
+    let e820 = [
+        (0u64, 0x9fc00u64),
+        (KERNEL_ADDR as u64, (MEM_SIZE - KERNEL_ADDR) as u64),
+    ];
+    for (i, (addr, size)) in e820.iter().enumerate() {
+        let entry = 0x2d0 + i * 20;
+        zero_page[entry..entry + 8].copy_from_slice(&addr.to_le_bytes());
+        zero_page[entry + 8..entry + 16].copy_from_slice(&size.to_le_bytes());
+        zero_page[entry + 16..entry + 20].copy_from_slice(&1u32.to_le_bytes());
+    }
+    zero_page[0x1e8] = e820.len() as u8;
+    ram.write(ZERO_PAGE_ADDR, &zero_page);
+
+    KERNEL_ADDR as u64 + 0x200
+}
+
+fn segment(selector: u16, type_: u8, long: bool) -> kvm_segment {
+    kvm_segment {
+        base: 0,
+        limit: 0xffff_ffff,
+        selector,
+        type_,
+        present: 1,
+        s: 1,
+        l: long as u8,
+        db: !long as u8,
+        g: 1,
+        ..Default::default()
+    }
+}
+
+/// Enter long mode with the first 1GB identity mapped.
+fn setup_long_mode(ram: &GuestRam, vcpu: &VcpuFd, entry: u64) {
+    ram.write_u64(GDT_ADDR, 0);
+    ram.write_u64(GDT_ADDR + 8, 0x00af_9b00_0000_ffff);
+    ram.write_u64(GDT_ADDR + 16, 0x00cf_9300_0000_ffff);
+
+    ram.write_u64(PML4_ADDR, PDPT_ADDR as u64 | 0x3);
+    ram.write_u64(PDPT_ADDR, PD_ADDR as u64 | 0x3);
+    for i in 0..512 {
+        ram.write_u64(PD_ADDR + i * 8, ((i as u64) << 21) | 0x83);
+    }
+
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.gdt.base = GDT_ADDR as u64;
+    sregs.gdt.limit = 23;
+    sregs.cs = segment(0x8, 0xb, true);
+    sregs.ds = segment(0x10, 0x3, false);
+    sregs.es = sregs.ds;
+    sregs.fs = sregs.ds;
+    sregs.gs = sregs.ds;
+    sregs.ss = sregs.ds;
+    sregs.cr3 = PML4_ADDR as u64;
+    sregs.cr4 |= 0x20; // PAE
+    sregs.cr0 |= 0x8000_0001; // PG | PE
+    sregs.efer |= 0x500; // LMA | LME
+    vcpu.set_sregs(&sregs).unwrap();
+
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = entry;
+    regs.rsi = ZERO_PAGE_ADDR as u64;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <bzImage> <initramfs>", args[0]);
+        std::process::exit(1);
+    }
+    let kernel = std::fs::read(&args[1]).expect("failed to read the kernel");
+    let initrd = std::fs::read(&args[2]).expect("failed to read the initramfs");
+
+    let kvm = Kvm::new().expect("failed to open /dev/kvm");
+    let vm = Arc::new(kvm.create_vm().unwrap());
+    let ram = GuestRam::new();
+
+    unsafe {
+        vm.set_user_memory_region(kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0,
+            memory_size: MEM_SIZE as u64,
+            userspace_addr: ram.0 as u64,
+            flags: 0,
+        })
+        .unwrap();
+    }
+    vm.set_tss_address(0xfffb_d000).unwrap();
+    vm.create_irq_chip().unwrap();
+    vm.create_pit2(kvm_pit_config::default()).unwrap();
+
+    // The PCI segment: the bus behind the configuration mechanism and the device on it.
+    let io_bus = Arc::new(Bus::new());
+    let mmio_bus = Arc::new(Bus::new());
+    let relocation = Arc::new(BarRelocation::new(io_bus.clone(), mmio_bus.clone()));
+    let pci_bus = Arc::new(Mutex::new(PciBus::new(PciRoot::new(None), relocation)));
+    io_bus
+        .insert(
+            Arc::new(Mutex::new(PciConfigIo::new(pci_bus.clone()))),
+            CONFIG_ADDRESS,
+            8,
+        )
+        .unwrap();
+    let allocator = SystemAllocator::new(
+        GuestAddress(0xc000),
+        0x4000,
+        GuestAddress(0xd000_0000),
+        0x1000_0000,
+        GuestAddress(0xc000_0000),
+        0x1000_0000,
+        vec![GsiApic::new(24, 24)],
+    )
+    .unwrap();
+    let segment = ChPciSegment::new(
+        pci_bus,
+        Arc::new(Mutex::new(allocator)),
+        io_bus.clone(),
+        mmio_bus.clone(),
+        Arc::new(KvmMsiManager(vm.clone())),
+    );
+
+    let adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+    let (bdf, adapter) = segment.add_device(adapter).unwrap();
+    println!("PciTestDevice added at 00:{:02x}.{}", bdf >> 3, bdf & 0x7);
+
+    let entry = load_linux(&ram, &kernel, &initrd);
+    let mut vcpu = vm.create_vcpu(0).unwrap();
+    let cpuid = kvm
+        .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+        .unwrap();
+    vcpu.set_cpuid2(&cpuid).unwrap();
+    setup_long_mode(&ram, &vcpu, entry);
+
+    loop {
+        match vcpu.run().expect("failed to run vCPU") {
+            VcpuExit::IoOut(SERIAL_DATA, data) => print!("{}", data[0] as char),
+            VcpuExit::IoIn(port, data) => {
+                if io_bus.read(port as u64, data).is_err() {
+                    data.fill(0xff);
+                }
+            }
+            VcpuExit::IoOut(port, data) => {
+                let _ = io_bus.write(port as u64, data);
+            }
+            VcpuExit::MmioRead(addr, data) => {
+                if mmio_bus.read(addr, data).is_err() {
+                    data.fill(0xff);
+                }
+            }
+            VcpuExit::MmioWrite(addr, data) => {
+                let _ = mmio_bus.write(addr, data);
+            }
+            VcpuExit::Hlt => (),
+            VcpuExit::Shutdown => break,
+            exit => panic!("unexpected exit reason: {:?}", exit),
+        }
+    }
+
+    adapter.lock().unwrap().stop();
+}
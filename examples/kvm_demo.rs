@@ -0,0 +1,329 @@
+//! A minimal KVM based VMM which attaches a [`PciEduDevice`] through [`PciAdapter`].
+//!
+//! The guest is a few bytes of 32-bit protected mode code. It enumerates the device through
+//! the legacy 0xcf8/0xcfc configuration mechanism, reads the BAR0 address programmed by the
+//! VMM and the identification register of the device from it. It then points the MSI of the
+//! device at vector 0x41 of its local APIC, and has the device copy 16 bytes of its RAM through
+//! the DMA buffer of the device to another place with an interrupt once done. The guest shows
+//! the interrupt status of the device, the first DW copied and the IRR bits of vectors 0x40 to
+//! 0x5f, where the MSI is pending as the guest runs with interrupts disabled. Every value the
+//! guest wants to show is written to the debug port 0x10 and printed by the VMM, the guest
+//! stops by a write to port 0x11.
+//!
+//! The demo uses the EDU device rather than the [`PciTestDevice`](pcie_tlp::PciTestDevice),
+//! which only answers register accesses, as showing the DMA and the MSI paths of the adapter
+//! needs a device which masters the bus.
+//!
+//! Run it with `cargo run --example kvm_demo --features kvm-demo`.
+//!
+//! The glue in this file is what a hypervisor needs to do for a simulated device:
+//!
+//! * Decode the configuration mechanism and forward register accesses to the adapter.
+//! * Let the adapter probe and allocate the BARs with the system allocator.
+//! * Forward MMIO exits falling into the BAR regions to the adapter.
+//! * Give the adapter the guest RAM for the DMA of the device, and a [`KvmMsiSink`] to signal
+//!   its MSIs to the in-kernel interrupt controller.
+
+use kvm_bindings::{kvm_segment, kvm_userspace_memory_region};
+use kvm_ioctls::{Kvm, VcpuExit};
+use pci::PciDevice;
+use pcie_tlp::{DmaMemory, KvmMsiSink, PciAdapter, PciEduDevice};
+use std::io;
+use std::sync::Arc;
+use vm_allocator::{GsiApic, SystemAllocator};
+use vm_memory::GuestAddress;
+
+const MEM_SIZE: usize = 0x10000;
+const CODE_ADDR: u64 = 0x1000;
+/// Where the guest has the device copy from, and to.
+const DMA_SRC: usize = 0x2000;
+const DMA_PATTERN: u32 = 0x600d_f00d;
+const DEBUG_PORT: u16 = 0x10;
+const EXIT_PORT: u16 = 0x11;
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// The BDF where the simulated device is visible to the guest.
+const DEMO_DEVICE: u32 = 3 << 11;
+
+#[rustfmt::skip]
+const GUEST_CODE: &[u8] = &[
+    // Vendor ID and Device ID of 00:03.0
+    0x66, 0xba, 0xf8, 0x0c,             // mov dx, 0xcf8
+    0xb8, 0x00, 0x18, 0x00, 0x80,       // mov eax, 0x80001800
+    0xef,                               // out dx, eax
+    0x66, 0xba, 0xfc, 0x0c,             // mov dx, 0xcfc
+    0xed,                               // in eax, dx
+    0xe7, 0x10,                         // out 0x10, eax
+    // Memory Space and Bus Master Enable of 00:03.0
+    0x66, 0xba, 0xf8, 0x0c,             // mov dx, 0xcf8
+    0xb8, 0x04, 0x18, 0x00, 0x80,       // mov eax, 0x80001804
+    0xef,                               // out dx, eax
+    0x66, 0xba, 0xfc, 0x0c,             // mov dx, 0xcfc
+    0xb8, 0x06, 0x00, 0x00, 0x00,       // mov eax, 0x6
+    0xef,                               // out dx, eax
+    // BAR0 of 00:03.0
+    0x66, 0xba, 0xf8, 0x0c,             // mov dx, 0xcf8
+    0xb8, 0x10, 0x18, 0x00, 0x80,       // mov eax, 0x80001810
+    0xef,                               // out dx, eax
+    0x66, 0xba, 0xfc, 0x0c,             // mov dx, 0xcfc
+    0xed,                               // in eax, dx
+    0x25, 0xf0, 0xff, 0xff, 0xff,       // and eax, 0xfffffff0
+    0x89, 0xc3,                         // mov ebx, eax
+    0xe7, 0x10,                         // out 0x10, eax
+    // MMIO read of the identification register
+    0x8b, 0x03,                         // mov eax, [ebx]
+    0xe7, 0x10,                         // out 0x10, eax
+    // The MSI capability, the first one of the list
+    0x66, 0xba, 0xf8, 0x0c,             // mov dx, 0xcf8
+    0xb8, 0x34, 0x18, 0x00, 0x80,       // mov eax, 0x80001834
+    0xef,                               // out dx, eax
+    0x66, 0xba, 0xfc, 0x0c,             // mov dx, 0xcfc
+    0xed,                               // in eax, dx
+    0x25, 0xfc, 0x00, 0x00, 0x00,       // and eax, 0xfc
+    0x0d, 0x00, 0x18, 0x00, 0x80,       // or eax, 0x80001800
+    0x89, 0xc6,                         // mov esi, eax
+    // Message Address of APIC ID 0
+    0x8d, 0x46, 0x04,                   // lea eax, [esi + 0x4]
+    0x66, 0xba, 0xf8, 0x0c,             // mov dx, 0xcf8
+    0xef,                               // out dx, eax
+    0x66, 0xba, 0xfc, 0x0c,             // mov dx, 0xcfc
+    0xb8, 0x00, 0x00, 0xe0, 0xfe,       // mov eax, 0xfee00000
+    0xef,                               // out dx, eax
+    // Message Data of vector 0x41
+    0x8d, 0x46, 0x0c,                   // lea eax, [esi + 0xc]
+    0x66, 0xba, 0xf8, 0x0c,             // mov dx, 0xcf8
+    0xef,                               // out dx, eax
+    0x66, 0xba, 0xfc, 0x0c,             // mov dx, 0xcfc
+    0xb8, 0x41, 0x00, 0x00, 0x00,       // mov eax, 0x41
+    0xef,                               // out dx, eax
+    // MSI Enable in Message Control
+    0x89, 0xf0,                         // mov eax, esi
+    0x66, 0xba, 0xf8, 0x0c,             // mov dx, 0xcf8
+    0xef,                               // out dx, eax
+    0x66, 0xba, 0xfe, 0x0c,             // mov dx, 0xcfe
+    0x66, 0xb8, 0x01, 0x00,             // mov ax, 0x1
+    0x66, 0xef,                         // out dx, ax
+    // Software enable of the local APIC in the Spurious Interrupt Vector register
+    0xc7, 0x05, 0xf0, 0x00, 0xe0, 0xfe,
+    0xff, 0x01, 0x00, 0x00,             // mov dword [0xfee000f0], 0x1ff
+    // DMA of 16 bytes from 0x2000 to the DMA buffer at 0x40000
+    0xc7, 0x83, 0x80, 0x00, 0x00, 0x00,
+    0x00, 0x20, 0x00, 0x00,             // mov dword [ebx + 0x80], 0x2000
+    0xc7, 0x83, 0x88, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x04, 0x00,             // mov dword [ebx + 0x88], 0x40000
+    0xc7, 0x83, 0x90, 0x00, 0x00, 0x00,
+    0x10, 0x00, 0x00, 0x00,             // mov dword [ebx + 0x90], 0x10
+    0xc7, 0x83, 0x98, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00,             // mov dword [ebx + 0x98], 0x1
+    // DMA back to RAM at 0x3000, raising the interrupt once done
+    0xc7, 0x83, 0x80, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x04, 0x00,             // mov dword [ebx + 0x80], 0x40000
+    0xc7, 0x83, 0x88, 0x00, 0x00, 0x00,
+    0x00, 0x30, 0x00, 0x00,             // mov dword [ebx + 0x88], 0x3000
+    0xc7, 0x83, 0x98, 0x00, 0x00, 0x00,
+    0x07, 0x00, 0x00, 0x00,             // mov dword [ebx + 0x98], 0x7
+    // Interrupt status, 0x100 for the DMA
+    0x8b, 0x43, 0x24,                   // mov eax, [ebx + 0x24]
+    0xe7, 0x10,                         // out 0x10, eax
+    // The first DW copied
+    0xa1, 0x00, 0x30, 0x00, 0x00,       // mov eax, [0x3000]
+    0xe7, 0x10,                         // out 0x10, eax
+    // IRR of vectors 0x40 to 0x5f, bit 1 is the MSI
+    0xa1, 0x20, 0x02, 0xe0, 0xfe,       // mov eax, [0xfee00220]
+    0xe7, 0x10,                         // out 0x10, eax
+    0xe7, 0x11,                         // out 0x11, eax
+];
+
+/// The guest RAM, which the device reaches by DMA.
+#[derive(Clone, Copy)]
+struct GuestRam(*mut u8);
+
+// The mapping is never unmapped, and the guest owns what it holds anyway.
+unsafe impl Send for GuestRam {}
+
+impl GuestRam {
+    /// The host address of `len` bytes at `gpa`, if they are all in the RAM.
+    fn host(&self, gpa: u64, len: usize) -> io::Result<*mut u8> {
+        match (gpa as usize).checked_add(len) {
+            Some(end) if end <= MEM_SIZE => Ok(unsafe { self.0.add(gpa as usize) }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DMA outside of the guest RAM",
+            )),
+        }
+    }
+}
+
+impl DmaMemory for GuestRam {
+    fn read(&self, gpa: u64, data: &mut [u8]) -> io::Result<()> {
+        let src = self.host(gpa, data.len())?;
+        unsafe { std::ptr::copy_nonoverlapping(src, data.as_mut_ptr(), data.len()) };
+        Ok(())
+    }
+
+    fn write(&self, gpa: u64, data: &[u8]) -> io::Result<()> {
+        let dst = self.host(gpa, data.len())?;
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Ok(())
+    }
+}
+
+/// Decoder of the PCI configuration mechanism #1.
+struct ConfigMechanism {
+    address: u32,
+}
+
+impl ConfigMechanism {
+    /// Return the register index if the latched address targets the demo device.
+    fn target(&self) -> Option<usize> {
+        if self.address & 0x8000_0000 == 0 || self.address & 0x00ff_ff00 != DEMO_DEVICE {
+            return None;
+        }
+        Some(((self.address & 0xfc) >> 2) as usize)
+    }
+
+    fn read(&self, adapter: &mut PciAdapter, offset: usize, data: &mut [u8]) {
+        let value = match self.target() {
+            Some(reg_idx) => adapter.read_config_register(reg_idx),
+            None => u32::MAX,
+        };
+        let bytes = value.to_le_bytes();
+        data.copy_from_slice(&bytes[offset..offset + data.len()]);
+    }
+
+    fn write(&self, adapter: &mut PciAdapter, offset: usize, data: &[u8]) {
+        if let Some(reg_idx) = self.target() {
+            adapter.write_config_register(reg_idx, offset as u64, data);
+        }
+    }
+}
+
+fn flat_segment(selector: u16, type_: u8) -> kvm_segment {
+    kvm_segment {
+        base: 0,
+        limit: 0xffff_ffff,
+        selector,
+        type_,
+        present: 1,
+        dpl: 0,
+        db: 1,
+        s: 1,
+        l: 0,
+        g: 1,
+        ..Default::default()
+    }
+}
+
+fn main() {
+    let kvm = Kvm::new().expect("failed to open /dev/kvm");
+    let vm = Arc::new(kvm.create_vm().unwrap());
+
+    let mem = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            MEM_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+            -1,
+            0,
+        ) as *mut u8
+    };
+    assert_ne!(mem as *mut libc::c_void, libc::MAP_FAILED);
+
+    let region = kvm_userspace_memory_region {
+        slot: 0,
+        guest_phys_addr: 0,
+        memory_size: MEM_SIZE as u64,
+        userspace_addr: mem as u64,
+        flags: 0,
+    };
+    unsafe {
+        vm.set_user_memory_region(region).unwrap();
+        std::ptr::copy_nonoverlapping(
+            GUEST_CODE.as_ptr(),
+            mem.add(CODE_ADDR as usize),
+            GUEST_CODE.len(),
+        );
+        for i in 0..4 {
+            (mem.add(DMA_SRC) as *mut u32)
+                .add(i)
+                .write_unaligned(DMA_PATTERN);
+        }
+    }
+
+    // The in-kernel local APIC takes the MSIs of the device.
+    vm.set_tss_address(0xfffb_d000).unwrap();
+    vm.create_irq_chip().unwrap();
+
+    // Keep every BAR below 4GB since the guest runs without paging.
+    let mut allocator = SystemAllocator::new(
+        GuestAddress(0xc000),
+        0x4000,
+        GuestAddress(0xd000_0000),
+        0x1000_0000,
+        GuestAddress(0xc000_0000),
+        0x1000_0000,
+        vec![GsiApic::new(24, 24)],
+    )
+    .unwrap();
+
+    let mut adapter = PciAdapter::start(Box::new(PciEduDevice::new()));
+    adapter.set_dma_memory(Box::new(GuestRam(mem)));
+    adapter.set_msi_sink(Box::new(KvmMsiSink::new(vm.clone())));
+    let bars = adapter.allocate_bars(&mut allocator).unwrap();
+    for (addr, size, region_type) in bars.iter() {
+        println!(
+            "BAR {:?} allocated at {:#x} size {:#x}",
+            region_type, addr.0, size
+        );
+    }
+
+    let mut vcpu = vm.create_vcpu(0).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs = flat_segment(0x8, 0xb);
+    sregs.ds = flat_segment(0x10, 0x3);
+    sregs.es = sregs.ds;
+    sregs.fs = sregs.ds;
+    sregs.gs = sregs.ds;
+    sregs.ss = sregs.ds;
+    sregs.cr0 |= 0x1;
+    vcpu.set_sregs(&sregs).unwrap();
+
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = CODE_ADDR;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+
+    let mut config = ConfigMechanism { address: 0 };
+
+    loop {
+        match vcpu.run().expect("failed to run vCPU") {
+            VcpuExit::IoOut(CONFIG_ADDRESS, data) if data.len() == 4 => {
+                config.address = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            }
+            VcpuExit::IoOut(port, data) if (CONFIG_DATA..CONFIG_DATA + 4).contains(&port) => {
+                config.write(&mut adapter, (port - CONFIG_DATA) as usize, data);
+            }
+            VcpuExit::IoIn(port, data) if (CONFIG_DATA..CONFIG_DATA + 4).contains(&port) => {
+                config.read(&mut adapter, (port - CONFIG_DATA) as usize, data);
+            }
+            VcpuExit::IoOut(DEBUG_PORT, data) => {
+                let mut value = [0u8; 4];
+                value[..data.len()].copy_from_slice(data);
+                println!("guest: {:#010x}", u32::from_le_bytes(value));
+            }
+            VcpuExit::IoOut(EXIT_PORT, _) => break,
+            VcpuExit::IoIn(_, data) => data.fill(0xff),
+            VcpuExit::IoOut(_, _) => (),
+            VcpuExit::MmioRead(addr, data) => adapter.bar_mmio_read(addr, data),
+            VcpuExit::MmioWrite(addr, data) => {
+                adapter.write_bar(addr, 0, data);
+            }
+            exit => panic!("unexpected exit reason: {:?}", exit),
+        }
+    }
+
+    adapter.stop();
+    adapter.join();
+}
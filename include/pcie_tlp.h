@@ -0,0 +1,77 @@
+/*
+ * C API of the pcie-tlp crate, built with the `ffi` feature. See src/ffi.rs for the
+ * documentation of each function.
+ */
+#ifndef PCIE_TLP_H
+#define PCIE_TLP_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PCIE_TLP_GONE (-1)
+#define PCIE_TLP_INVALID (-2)
+#define PCIE_TLP_OVERFLOW (-3)
+
+#define PCIE_TLP_MEM_READ 0
+#define PCIE_TLP_MEM_WRITE 1
+#define PCIE_TLP_IO_READ 2
+#define PCIE_TLP_IO_WRITE 3
+#define PCIE_TLP_CFG_READ 4
+#define PCIE_TLP_CFG_WRITE 5
+#define PCIE_TLP_CPL 6
+#define PCIE_TLP_CPL_DATA 7
+#define PCIE_TLP_MSG 8
+#define PCIE_TLP_MSG_DATA 9
+#define PCIE_TLP_OTHER 0xff
+
+#define PCIE_TLP_ATTR_NO_SNOOP (1 << 0)
+#define PCIE_TLP_ATTR_RELAXED_ORDERING (1 << 1)
+#define PCIE_TLP_ATTR_ID_ORDERING (1 << 2)
+#define PCIE_TLP_ATTR_POISONED (1 << 3)
+
+typedef struct pcie_tlp_lane pcie_tlp_lane;
+
+struct pcie_tlp_desc {
+    uint8_t kind;
+    uint8_t tag;
+    uint16_t requester;
+    uint16_t completer;
+    uint16_t reg;
+    uint64_t address;
+    uint16_t length;
+    uint8_t byte_enable;
+    uint8_t status;
+    uint16_t byte_count;
+    uint8_t lower_address;
+    uint8_t message;
+    uint8_t attr;
+    uint8_t reserved[7];
+};
+
+struct pcie_tlp_device_ops {
+    void *ctx;
+    void (*run)(void *ctx, const pcie_tlp_lane *lane);
+    void (*on_start)(void *ctx, const uint16_t *functions, size_t count);
+    void (*on_reset)(void *ctx, int32_t function);
+    void (*release)(void *ctx);
+};
+
+int pcie_tlp_send(const pcie_tlp_lane *lane, const struct pcie_tlp_desc *desc,
+                  const uint32_t *data, size_t count);
+int pcie_tlp_recv(const pcie_tlp_lane *lane, struct pcie_tlp_desc *desc, uint32_t *data,
+                  size_t capacity, int64_t timeout_us);
+int pcie_tlp_raise_msi(const pcie_tlp_lane *lane, uint16_t requester, uint64_t addr,
+                       uint32_t data);
+
+pcie_tlp_lane *pcie_tlp_lane_connect(const char *path);
+void pcie_tlp_lane_close(pcie_tlp_lane *lane);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
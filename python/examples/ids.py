@@ -0,0 +1,27 @@
+"""A device model in Python which only has a device and vendor ID and 4 KB of memory."""
+
+import pcie_tlp
+
+
+class Ids:
+    def __init__(self):
+        self.memory = bytearray(4096)
+
+    def on_config_read(self, function, reg):
+        return 0x5678_1234 if reg == 0 else 0
+
+    def on_config_write(self, function, reg, offset, data):
+        pass
+
+    def on_mem_read(self, addr, length):
+        offset = addr & 0xfff
+        return bytes(self.memory[offset:offset + length])
+
+    def on_mem_write(self, addr, data):
+        offset = addr & 0xfff
+        self.memory[offset:offset + len(data)] = data
+
+
+adapter = pcie_tlp.Adapter(Ids())
+assert adapter.config_read(0) == 0x5678_1234
+adapter.stop()
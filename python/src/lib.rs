@@ -0,0 +1,582 @@
+//! Python bindings of the crate, to prototype device models in Python, e.g. from a cocotb
+//! testbench, while the bridge stays in Rust.
+//!
+//! A device model is a Python object handed to `Adapter`. It either answers one access at a
+//! time like a `SimpleDevice`, with the methods
+//!
+//! - `on_config_read(function, reg) -> int`
+//! - `on_config_write(function, reg, offset, data: bytes)`
+//! - `on_mem_read(addr, len) -> bytes` and `on_mem_write(addr, data: bytes)`, optional
+//! - `on_io_read(addr, len) -> bytes` and `on_io_write(addr, data: bytes)`, optional
+//!
+//! or it speaks TLPs itself with a `run(lane)` method, which takes the `Tlp`s of the bridge by
+//! `lane.recv()` and answers them by `lane.send(tlp)` like a `PciSimDevice`, with the optional
+//! `on_start(functions)`, `on_reset(function)` and `on_stop()`. `connect_unix` gives a model
+//! the lane of a bridge in another process, see `PciAdapterBuilder::start_unix`.
+//!
+//! An exception of a callback is printed and the request is completed with Completer Abort.
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use pci::PciDevice;
+use pyo3::exceptions::{PyConnectionError, PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::time::Duration;
+use tlp::*;
+use vm_allocator::{GsiApic, SystemAllocator};
+use vm_memory::{Address, GuestAddress};
+
+fn adapter_error(e: PciAdapterError) -> PyErr {
+    PyIOError::new_err(e.to_string())
+}
+
+fn lane_closed<E>(_: E) -> PyErr {
+    PyConnectionError::new_err("lane closed")
+}
+
+/// A TLP, built by the static methods or taken from a lane.
+#[pyclass(name = "Tlp")]
+#[derive(Clone)]
+struct PyTlp(Tlp);
+
+#[pymethods]
+impl PyTlp {
+    /// Decode a TLP from its wire format.
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> PyResult<Self> {
+        Tlp::from_bytes(data)
+            .map(PyTlp)
+            .map_err(|e| PyValueError::new_err(format!("{:?}", e)))
+    }
+
+    /// The wire format of the TLP.
+    fn to_bytes<'py>(&self, py: Python<'py>) -> PyResult<&'py PyBytes> {
+        let bytes = self
+            .0
+            .to_bytes()
+            .map_err(|e| PyValueError::new_err(format!("{:?}", e)))?;
+        Ok(PyBytes::new(py, &bytes))
+    }
+
+    /// A memory read of `length` DWs, with a 64-bit address above 4 GB.
+    #[staticmethod]
+    fn memory_read(requester: u16, tag: u8, addr: u64, length: u16) -> Self {
+        let builder = if addr >> 32 == 0 {
+            TlpBuilder::memory_read(MemoryExtra {
+                requester,
+                tag,
+                addr: addr as u32,
+            })
+        } else {
+            TlpBuilder::memory_read64(Memory64Extra {
+                requester,
+                tag,
+                addr,
+            })
+        };
+        let last = if length > 1 { 0xf0 } else { 0 };
+        PyTlp(builder.length(length).byte_enable(0xf | last).build())
+    }
+
+    /// A memory write of the DWs of `data`, with a 64-bit address above 4 GB.
+    #[staticmethod]
+    fn memory_write(requester: u16, tag: u8, addr: u64, data: Vec<u32>) -> Self {
+        let builder = if addr >> 32 == 0 {
+            TlpBuilder::memory_write(MemoryExtra {
+                requester,
+                tag,
+                addr: addr as u32,
+            })
+        } else {
+            TlpBuilder::memory_write64(Memory64Extra {
+                requester,
+                tag,
+                addr,
+            })
+        };
+        let last = if data.len() > 1 { 0xf0 } else { 0 };
+        PyTlp(builder.byte_enable(0xf | last).data(data).build())
+    }
+
+    /// A type 0 config read of register `reg`.
+    #[staticmethod]
+    fn config_read(requester: u16, completer: u16, tag: u8, reg: u16) -> Self {
+        let extra = ConfigExtra {
+            requester,
+            completer,
+            tag,
+            reg,
+        };
+        PyTlp(TlpBuilder::config0_read(extra).byte_enable(0xf).build())
+    }
+
+    /// A type 0 config write of `value` to the bytes of register `reg` `byte_enable` selects.
+    #[staticmethod]
+    #[pyo3(signature = (requester, completer, tag, reg, value, byte_enable = 0xf))]
+    fn config_write(
+        requester: u16,
+        completer: u16,
+        tag: u8,
+        reg: u16,
+        value: u32,
+        byte_enable: u8,
+    ) -> Self {
+        let extra = ConfigExtra {
+            requester,
+            completer,
+            tag,
+            reg,
+        };
+        let tlp = TlpBuilder::config0_write(extra)
+            .byte_enable(byte_enable & 0xf)
+            .data(vec![value])
+            .build();
+        PyTlp(tlp)
+    }
+
+    /// A completion, with data if `data` is given.
+    #[staticmethod]
+    #[pyo3(signature = (requester, completer, tag, status = CPL_SC, data = None, byte_count = 4,
+        lower_address = 0))]
+    fn completion(
+        requester: u16,
+        completer: u16,
+        tag: u8,
+        status: u8,
+        data: Option<Vec<u32>>,
+        byte_count: u16,
+        lower_address: u8,
+    ) -> Self {
+        let extra = CompletionExtra {
+            requester,
+            completer,
+            tag,
+            status,
+            bcm: false,
+            byte_count,
+            lower_address,
+        };
+        let tlp = match data {
+            Some(data) => TlpBuilder::completion_data(extra).data(data).build(),
+            None => TlpBuilder::completion(extra).build(),
+        };
+        PyTlp(tlp)
+    }
+
+    /// The packet type, e.g. `"MemoryRead64"`.
+    #[getter]
+    fn kind(&self) -> String {
+        let kind = format!("{:?}", self.0.header._type);
+        kind.split('(').next().unwrap_or_default().to_string()
+    }
+
+    #[getter]
+    fn requester(&self) -> Option<u16> {
+        use PacketType::*;
+
+        match self.0.header._type {
+            MemoryRead(e) | MemoryWrite(e) | IoRead(e) | IoWrite(e) => Some(e.requester),
+            MemoryRead64(e) | MemoryWrite64(e) => Some(e.requester),
+            Config0Read(e) | Config0Write(e) | Config1Read(e) | Config1Write(e) => {
+                Some(e.requester)
+            }
+            Completion(e) | CompletionData(e) | CompletionLocked(e) | CompletionLockedData(e) => {
+                Some(e.requester)
+            }
+            _ => None,
+        }
+    }
+
+    #[getter]
+    fn completer(&self) -> Option<u16> {
+        use PacketType::*;
+
+        match self.0.header._type {
+            Config0Read(e) | Config0Write(e) | Config1Read(e) | Config1Write(e) => {
+                Some(e.completer)
+            }
+            Completion(e) | CompletionData(e) | CompletionLocked(e) | CompletionLockedData(e) => {
+                Some(e.completer)
+            }
+            _ => None,
+        }
+    }
+
+    #[getter]
+    fn tag(&self) -> Option<u8> {
+        self.0.header.tag()
+    }
+
+    /// Address of a memory or IO request.
+    #[getter]
+    fn address(&self) -> Option<u64> {
+        use PacketType::*;
+
+        match self.0.header._type {
+            MemoryRead(e) | MemoryWrite(e) | IoRead(e) | IoWrite(e) => Some(e.addr as u64),
+            MemoryRead64(e) | MemoryWrite64(e) => Some(e.addr),
+            _ => None,
+        }
+    }
+
+    /// Register of a config request.
+    #[getter]
+    fn reg(&self) -> Option<u16> {
+        use PacketType::*;
+
+        match self.0.header._type {
+            Config0Read(e) | Config0Write(e) | Config1Read(e) | Config1Write(e) => Some(e.reg),
+            _ => None,
+        }
+    }
+
+    /// Status of a completion.
+    #[getter]
+    fn status(&self) -> Option<u8> {
+        use PacketType::*;
+
+        match self.0.header._type {
+            Completion(e) | CompletionData(e) | CompletionLocked(e) | CompletionLockedData(e) => {
+                Some(e.status)
+            }
+            _ => None,
+        }
+    }
+
+    /// Length in DWs.
+    #[getter]
+    fn length(&self) -> u16 {
+        self.0.header.length
+    }
+
+    /// First DW byte enables in bits 3:0, last DW byte enables in bits 7:4.
+    #[getter]
+    fn byte_enable(&self) -> u8 {
+        self.0.header.byte_enable
+    }
+
+    #[getter]
+    fn data(&self) -> Option<Vec<u32>> {
+        self.0.data.clone()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Tlp({:?}, data={:x?})", self.0.header._type, self.0.data)
+    }
+}
+
+/// The end of a lane a device model runs on.
+#[pyclass(name = "Lane")]
+struct PyLane {
+    tx: Sender<Tlp>,
+    rx: Receiver<Tlp>,
+}
+
+impl PyLane {
+    fn new(lane: &PciLane) -> Self {
+        PyLane {
+            tx: lane.tx.clone(),
+            rx: lane.rx.clone(),
+        }
+    }
+
+    fn lane(&self) -> PciLane {
+        PciLane {
+            tx: self.tx.clone(),
+            rx: self.rx.clone(),
+        }
+    }
+}
+
+#[pymethods]
+impl PyLane {
+    fn send(&self, tlp: &PyTlp) -> PyResult<()> {
+        self.tx.send(tlp.0.clone()).map_err(lane_closed)
+    }
+
+    /// The next TLP of the bridge, `None` if none came within `timeout` seconds. Raises
+    /// `ConnectionError` once the bridge is gone.
+    #[pyo3(signature = (timeout = None))]
+    fn recv(&self, py: Python<'_>, timeout: Option<f64>) -> PyResult<Option<PyTlp>> {
+        let rx = self.rx.clone();
+        let tlp = py.allow_threads(move || match timeout {
+            Some(timeout) => match rx.recv_timeout(Duration::from_secs_f64(timeout)) {
+                Ok(tlp) => Ok(Some(tlp)),
+                Err(RecvTimeoutError::Timeout) => Ok(None),
+                Err(e) => Err(e),
+            },
+            None => rx
+                .recv()
+                .map(Some)
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        });
+        tlp.map(|tlp| tlp.map(PyTlp)).map_err(lane_closed)
+    }
+
+    /// Write the MSI `data` to `addr`, see `PciLane::raise_msi`.
+    fn raise_msi(&self, requester: u16, addr: u64, data: u32) -> PyResult<()> {
+        self.lane()
+            .raise_msi(requester, addr, data)
+            .map_err(lane_closed)
+    }
+
+    /// Raise MSI-X `vector`, see `PciLane::raise_msix`.
+    fn raise_msix(&self, requester: u16, vector: u16) -> PyResult<()> {
+        self.lane()
+            .raise_msix(requester, vector)
+            .map_err(lane_closed)
+    }
+}
+
+/// Call the method `name` of `model` if it has one.
+fn call_optional(
+    py: Python<'_>,
+    model: &PyObject,
+    name: &str,
+    args: impl IntoPy<Py<pyo3::types::PyTuple>>,
+) -> Option<PyResult<PyObject>> {
+    match model.as_ref(py).hasattr(name) {
+        Ok(true) => Some(model.call_method1(py, name, args)),
+        Ok(false) => None,
+        Err(e) => Some(Err(e)),
+    }
+}
+
+/// The bytes an access callback of `model` returned, or the completion status it failed with.
+fn read_bytes(
+    py: Python<'_>,
+    model: &PyObject,
+    name: &str,
+    addr: u64,
+    data: &mut [u8],
+) -> std::result::Result<(), u8> {
+    let value = match call_optional(py, model, name, (addr, data.len())) {
+        Some(value) => value,
+        None => return Err(CPL_UR),
+    };
+    match value.and_then(|value| value.extract::<Vec<u8>>(py)) {
+        Ok(bytes) if bytes.len() == data.len() => {
+            data.copy_from_slice(&bytes);
+            Ok(())
+        }
+        Ok(_) => Err(CPL_CA),
+        Err(e) => {
+            e.print(py);
+            Err(CPL_CA)
+        }
+    }
+}
+
+/// A model answering one access at a time.
+struct PySimpleDevice(PyObject);
+
+impl SimpleDevice for PySimpleDevice {
+    fn on_config_read(&mut self, function: u16, reg: usize) -> u32 {
+        Python::with_gil(|py| {
+            let value = self.0.call_method1(py, "on_config_read", (function, reg));
+            value
+                .and_then(|value| value.extract::<u32>(py))
+                .unwrap_or_else(|e| {
+                    e.print(py);
+                    u32::MAX
+                })
+        })
+    }
+
+    fn on_config_write(&mut self, function: u16, reg: usize, offset: u64, data: &[u8]) {
+        Python::with_gil(|py| {
+            let args = (function, reg, offset, PyBytes::new(py, data));
+            if let Err(e) = self.0.call_method1(py, "on_config_write", args) {
+                e.print(py);
+            }
+        })
+    }
+
+    fn on_mem_read(&mut self, addr: u64, data: &mut [u8]) -> std::result::Result<(), u8> {
+        Python::with_gil(|py| read_bytes(py, &self.0, "on_mem_read", addr, data))
+    }
+
+    fn on_mem_write(&mut self, addr: u64, data: &[u8]) {
+        Python::with_gil(|py| {
+            let args = (addr, PyBytes::new(py, data));
+            if let Some(Err(e)) = call_optional(py, &self.0, "on_mem_write", args) {
+                e.print(py);
+            }
+        })
+    }
+
+    fn on_io_read(&mut self, addr: u32, data: &mut [u8]) -> std::result::Result<(), u8> {
+        Python::with_gil(|py| read_bytes(py, &self.0, "on_io_read", addr as u64, data))
+    }
+
+    fn on_io_write(&mut self, addr: u32, data: &[u8]) -> std::result::Result<(), u8> {
+        Python::with_gil(|py| {
+            let args = (addr, PyBytes::new(py, data));
+            match call_optional(py, &self.0, "on_io_write", args) {
+                Some(Ok(_)) => Ok(()),
+                Some(Err(e)) => {
+                    e.print(py);
+                    Err(CPL_CA)
+                }
+                None => Err(CPL_UR),
+            }
+        })
+    }
+}
+
+/// A model speaking TLPs itself.
+struct PyLaneDevice(PyObject);
+
+impl PyLaneDevice {
+    fn notify(&self, name: &str, args: impl IntoPy<Py<pyo3::types::PyTuple>>) {
+        Python::with_gil(|py| {
+            if let Some(Err(e)) = call_optional(py, &self.0, name, args) {
+                e.print(py);
+            }
+        })
+    }
+}
+
+impl PciSimDevice for PyLaneDevice {
+    fn on_start(&mut self, info: &LaneInfo) {
+        self.notify("on_start", (info.functions.clone(),));
+    }
+
+    fn on_reset(&mut self, kind: ResetKind) {
+        let function = match kind {
+            ResetKind::FunctionLevel(function) => Some(function),
+            ResetKind::Hot => None,
+        };
+        self.notify("on_reset", (function,));
+    }
+
+    fn on_stop(&mut self) {
+        self.notify("on_stop", ());
+    }
+
+    fn run(&mut self, lane: &PciLane) {
+        Python::with_gil(|py| {
+            let lane = PyLane::new(lane);
+            if let Err(e) = self.0.call_method1(py, "run", (lane,)) {
+                e.print(py);
+            }
+        })
+    }
+}
+
+/// The bridge with a device model written in Python.
+#[pyclass(name = "Adapter")]
+struct PyAdapter {
+    adapter: Option<PciAdapter>,
+}
+
+impl PyAdapter {
+    fn adapter(&self) -> PyResult<&PciAdapter> {
+        self.adapter
+            .as_ref()
+            .ok_or_else(|| PyConnectionError::new_err("adapter stopped"))
+    }
+}
+
+#[pymethods]
+impl PyAdapter {
+    /// Start the bridge with `model`, see the module documentation.
+    #[new]
+    fn new(py: Python<'_>, model: PyObject) -> PyResult<Self> {
+        let device: Box<dyn PciSimDevice + Send + Sync> = if model.as_ref(py).hasattr("run")? {
+            Box::new(PyLaneDevice(model))
+        } else {
+            Box::new(PySimpleDevice(model))
+        };
+        // The model thread takes the GIL for its first config reads.
+        let adapter = py.allow_threads(move || PciAdapter::start(device));
+        Ok(PyAdapter {
+            adapter: Some(adapter),
+        })
+    }
+
+    fn config_read(&self, py: Python<'_>, reg: usize) -> PyResult<u32> {
+        let pending = self.adapter()?.config_read_async(reg);
+        py.allow_threads(move || pending.wait())
+            .map_err(adapter_error)
+    }
+
+    /// Write `data` at byte `offset` of config register `reg`.
+    fn config_write(&self, py: Python<'_>, reg: usize, offset: u64, data: &[u8]) -> PyResult<()> {
+        let pending = self.adapter()?.config_write_async(reg, offset, data);
+        py.allow_threads(move || pending.wait())
+            .map_err(adapter_error)
+    }
+
+    /// Place the BARs the model implements, returns the address and size of each.
+    fn allocate_bars(&mut self, py: Python<'_>) -> PyResult<Vec<(u64, u64)>> {
+        let adapter = self
+            .adapter
+            .as_mut()
+            .ok_or_else(|| PyConnectionError::new_err("adapter stopped"))?;
+        let mut allocator = SystemAllocator::new(
+            GuestAddress(0x1000),
+            0x1000,
+            GuestAddress(0x1_0000_0000),
+            0x1000_0000,
+            GuestAddress(0xc000_0000),
+            0x1000_0000,
+            vec![GsiApic::new(24, 24)],
+        )
+        .ok_or_else(|| PyIOError::new_err("no address space"))?;
+        let bars = py
+            .allow_threads(move || adapter.allocate_bars(&mut allocator))
+            .map_err(|e| PyIOError::new_err(format!("{:?}", e)))?;
+        Ok(bars
+            .into_iter()
+            .map(|(addr, size, _)| (addr.raw_value(), size))
+            .collect())
+    }
+
+    fn mmio_read<'py>(&self, py: Python<'py>, addr: u64, len: usize) -> PyResult<&'py PyBytes> {
+        let pending = self.adapter()?.bar_mmio_read_async(addr, len);
+        let data = py
+            .allow_threads(move || pending.wait())
+            .map_err(adapter_error)?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    fn mmio_write(&self, py: Python<'_>, addr: u64, data: &[u8]) -> PyResult<()> {
+        let pending = self.adapter()?.bar_mmio_write_async(addr, data);
+        py.allow_threads(move || pending.wait())
+            .map_err(adapter_error)
+    }
+
+    /// Stop the bridge and wait for the model to end.
+    fn stop(&mut self, py: Python<'_>) {
+        if let Some(adapter) = self.adapter.take() {
+            py.allow_threads(move || {
+                adapter.stop();
+                adapter.join();
+            });
+        }
+    }
+}
+
+/// The lane of the bridge listening on the Unix domain socket at `path`, for a model running
+/// in this process.
+#[pyfunction]
+fn connect_unix(py: Python<'_>, path: String) -> PyResult<PyLane> {
+    let (lane, _) = py
+        .allow_threads(move || tlp::connect_unix(path, DeviceFeatures::default()))
+        .map_err(PyErr::from)?;
+    Ok(PyLane::new(&lane))
+}
+
+#[pymodule]
+fn pcie_tlp(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyTlp>()?;
+    m.add_class::<PyLane>()?;
+    m.add_class::<PyAdapter>()?;
+    m.add_function(wrap_pyfunction!(connect_unix, m)?)?;
+    m.add("CPL_SC", CPL_SC)?;
+    m.add("CPL_UR", CPL_UR)?;
+    m.add("CPL_CRS", CPL_CRS)?;
+    m.add("CPL_CA", CPL_CA)?;
+    Ok(())
+}
@@ -0,0 +1,179 @@
+//! Access Control Services of the downstream ports of a bridge.
+//!
+//! Each device behind a bridge sits on a link of its own, below a downstream port of the
+//! bridge. Like the hot-plug slot, the hypervisor keeps an [`AcsPort`] in the ACS extended
+//! capability of the port the guest sees and hands it to the bridge with
+//! [`crate::PciAdapter::set_acs`] whenever the guest changes it. The bridge then checks the memory
+//! requests a device sends to a device on another link:
+//!
+//! * Translation Blocking blocks the requests with a translated address.
+//! * P2P Egress Control blocks the requests to the ports set in the Egress Control Vector, port
+//!   N being the link of device N.
+//! * P2P Request Redirect sends the requests to the root complex, which handles them like any
+//!   other request of the device, unless Direct Translated P2P lets the translated ones go to
+//!   the peer.
+//!
+//! Blocked reads are completed with Completer Abort and blocked writes are dropped, both count
+//! as errors of the adapter. Requests between the functions of a device stay on their link and
+//! are not checked.
+
+/// Extended capability ID of ACS.
+pub const PCI_EXT_CAP_ID_ACS: u16 = 0x0d;
+
+/// Byte offset of the ACS Capability register in the ACS extended capability, ACS Control
+/// follows at 0x06 and the Egress Control Vector at 0x08.
+pub const PCI_ACS_CAP: usize = 0x04;
+
+/// What the bridge does with a request of a device to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AcsVerdict {
+    Forward,
+    Redirect,
+    Block,
+}
+
+/// ACS Capability, ACS Control and Egress Control Vector registers of a downstream port.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AcsPort {
+    capability: u16,
+    control: u16,
+    egress: u32,
+}
+
+impl AcsPort {
+    /// ACS Source Validation.
+    pub const SOURCE_VALIDATION: u16 = 1 << 0;
+    /// ACS Translation Blocking.
+    pub const TRANSLATION_BLOCKING: u16 = 1 << 1;
+    /// ACS P2P Request Redirect.
+    pub const REQUEST_REDIRECT: u16 = 1 << 2;
+    /// ACS P2P Completion Redirect.
+    pub const COMPLETION_REDIRECT: u16 = 1 << 3;
+    /// ACS Upstream Forwarding.
+    pub const UPSTREAM_FORWARDING: u16 = 1 << 4;
+    /// ACS P2P Egress Control.
+    pub const EGRESS_CONTROL: u16 = 1 << 5;
+    /// ACS Direct Translated P2P.
+    pub const DIRECT_TRANSLATED: u16 = 1 << 6;
+
+    const FEATURES: u16 = 0x7f;
+    /// Egress Control Vector Size, one bit per device number.
+    const EGRESS_PORTS: u16 = 32;
+
+    /// A port with the ACS features in `capability`, all of them disabled.
+    pub fn new(capability: u16) -> Self {
+        let mut capability = capability & Self::FEATURES;
+        if capability & Self::EGRESS_CONTROL != 0 {
+            capability |= Self::EGRESS_PORTS << 8;
+        }
+
+        AcsPort {
+            capability,
+            control: 0,
+            egress: 0,
+        }
+    }
+
+    pub fn control(&self) -> u16 {
+        self.control
+    }
+
+    /// The DW `dw` of the registers from [`PCI_ACS_CAP`] on: ACS Capability in the lower and
+    /// ACS Control in the upper half of the first one, then the Egress Control Vector if the
+    /// port has P2P Egress Control.
+    pub fn read(&self, dw: usize) -> u32 {
+        match dw {
+            0 => self.capability as u32 | (self.control as u32) << 16,
+            1 if self.capability & Self::EGRESS_CONTROL != 0 => self.egress,
+            _ => 0,
+        }
+    }
+
+    /// Write `data` at byte `offset` into the DW `dw`, see [`AcsPort::read`]. Only the
+    /// features of the port are enabled. Returns whether the control or the vector changed,
+    /// for the hypervisor to hand the port to the bridge again.
+    pub fn write(&mut self, dw: usize, offset: u64, data: &[u8]) -> bool {
+        let offset = offset as usize;
+        if offset + data.len() > 4 {
+            return false;
+        }
+
+        let mut mask = 0u32;
+        let mut value = 0u32;
+        for (i, b) in data.iter().enumerate() {
+            mask |= 0xff << ((offset + i) * 8);
+            value |= (*b as u32) << ((offset + i) * 8);
+        }
+
+        let old = *self;
+        match dw {
+            0 => {
+                let mask = (mask >> 16) as u16 & self.capability & Self::FEATURES;
+                self.control = (self.control & !mask) | ((value >> 16) as u16 & mask);
+            }
+            1 if self.capability & Self::EGRESS_CONTROL != 0 => {
+                self.egress = (self.egress & !mask) | (value & mask);
+            }
+            _ => (),
+        }
+        *self != old
+    }
+
+    /// What the port does with a request to the device `device` on another link, whose
+    /// address is `translated` or not.
+    pub(crate) fn check(&self, translated: bool, device: u8) -> AcsVerdict {
+        let enabled = |feature| self.control & feature != 0;
+
+        if translated && enabled(Self::TRANSLATION_BLOCKING) {
+            return AcsVerdict::Block;
+        }
+        if enabled(Self::EGRESS_CONTROL) && self.egress & 1 << (device & 0x1f) != 0 {
+            return AcsVerdict::Block;
+        }
+        if enabled(Self::REQUEST_REDIRECT) && !(translated && enabled(Self::DIRECT_TRANSLATED)) {
+            return AcsVerdict::Redirect;
+        }
+        AcsVerdict::Forward
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port() {
+        let mut port = AcsPort::new(
+            AcsPort::TRANSLATION_BLOCKING
+                | AcsPort::REQUEST_REDIRECT
+                | AcsPort::EGRESS_CONTROL
+                | AcsPort::DIRECT_TRANSLATED,
+        );
+        assert_eq!(port.read(0), 0x2066);
+        assert_eq!(port.check(true, 3), AcsVerdict::Forward);
+
+        // Upstream Forwarding is not a feature of the port, it stays disabled.
+        assert!(port.write(0, 2, &[0x16]));
+        assert_eq!(port.control(), 0x06);
+        assert!(!port.write(0, 2, &[0x06]));
+        assert_eq!(port.check(false, 3), AcsVerdict::Redirect);
+        assert_eq!(port.check(true, 3), AcsVerdict::Block);
+
+        // Direct Translated P2P lets the translated requests through.
+        port.write(0, 2, &[0x44]);
+        assert_eq!(port.check(false, 3), AcsVerdict::Redirect);
+        assert_eq!(port.check(true, 3), AcsVerdict::Forward);
+
+        // The egress to device 3 is blocked, the one to device 4 is not.
+        port.write(0, 2, &[0x20]);
+        assert!(port.write(1, 0, &[0x08]));
+        assert_eq!(port.read(1), 0x08);
+        assert_eq!(port.check(false, 3), AcsVerdict::Block);
+        assert_eq!(port.check(false, 4), AcsVerdict::Forward);
+
+        // Without the feature the vector reads as 0.
+        let mut port = AcsPort::new(AcsPort::REQUEST_REDIRECT);
+        assert!(!port.write(1, 0, &[0xff; 4]));
+        assert_eq!((port.read(0), port.read(1)), (0x0004, 0));
+    }
+}
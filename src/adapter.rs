@@ -1,20 +1,218 @@
 use crate::*;
 
-use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use crate::acs::AcsVerdict;
+use crate::aer::{DEVCTL_DW, ERR_COR, ERR_FATAL, ERR_NONFATAL};
+use crate::ats::{
+    encode_range, invalidation_ranges, AtsTranslation, ATS_INVALIDATE_COMPLETION,
+    ATS_INVALIDATE_REQUEST, ATS_PAGE_SIZE,
+};
+use crate::cache::ConfigCache;
+use crate::delay::DelayLine;
+use crate::dma::enabled_bytes;
+use crate::doorbell::DoorbellEvents;
+use crate::flow::LaneFlow;
+use crate::interrupt::{
+    MsixCap, MsixStructure, MsixTable, DEFAULT_MSI_WINDOW, INTERRUPT_REG, MSIX_VECTOR_MESSAGE,
+    PCI_CAP_ID_MSIX, PM_PME,
+};
+use crate::link::Throttle;
+use crate::ltssm::{
+    Ltssm, LNKCTL2_DW, LNKCTL_DW, LNKCTL_RL, LNKSTA_DLLLA, LNKSTA_LINK, LNKSTA_LT, PCI_CAP_ID_EXP,
+};
+use crate::message::{Destination, Routes};
+use crate::ordering::{id_of, reorder};
+use crate::ptm::PTM_REQUEST;
+use crate::reply::{Reply, ReplyPool};
+use crate::tag::{TagPool, DEFAULT_TAGS};
+use crossbeam_channel::{
+    after, bounded, never, select, unbounded, Receiver, SendError, Sender, TryRecvError,
+};
 use std::any::Any;
-use std::collections::HashMap;
-use std::sync::{Arc, Barrier};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Features implemented by the bridge, the negotiated features never exceed them.
+pub(crate) const BRIDGE_FEATURES: DeviceFeatures = DeviceFeatures {
+    extended_tags: true,
+    ten_bit_tags: false,
+    ats: true,
+    ide: false,
+    atomics: false,
+    extra_virtual_channels: 0,
+};
+
+/// Default time the bridge waits for the completion of a non-posted transaction.
+pub const DEFAULT_COMPLETION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Errors reported by the fallible API of [`PciAdapter`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PciAdapterError {
+    /// The bridge thread or the simulated device is gone.
+    Disconnected,
+    /// The simulated device did not complete the transaction in time.
+    Timeout,
+    /// The transaction is completed with a non-successful completion status.
+    Completion(u8),
+    /// The completion does not carry the data the request asks for.
+    MalformedCompletion,
+    /// The completion carries poisoned data.
+    PoisonedCompletion,
+    /// The address does not belong to any registered BAR region.
+    InvalidAddress(u64),
+    /// The access size is not supported by the region.
+    InvalidSize(usize),
+    /// The register is beyond the 4 KB configuration space.
+    InvalidRegister(usize),
+    /// The request needs a feature which is not negotiated with the device.
+    Unsupported,
+    /// The thread of the device model panicked.
+    DevicePanicked,
+    /// The BDF is taken by another function, or no function is plugged in at the BDF.
+    InvalidBdf(u16),
+    /// The snapshot does not fit the device model.
+    InvalidSnapshot,
+    /// The function does not support that many VFs, or its VFs are already enabled.
+    InvalidVfCount(u16),
+    /// No guest address range of that many bytes is left for a BAR.
+    OutOfAddressSpace(u64),
+    /// The link of the device is down, see [`PciAdapter::set_link_up`].
+    LinkDown,
+}
+
+impl fmt::Display for PciAdapterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use PciAdapterError::*;
+
+        match self {
+            Disconnected => write!(f, "simulated device disconnected"),
+            Timeout => write!(f, "completion timeout"),
+            Completion(status) => write!(f, "completion with status {:#x}", status),
+            MalformedCompletion => write!(f, "malformed completion"),
+            PoisonedCompletion => write!(f, "completion with poisoned data"),
+            InvalidAddress(addr) => write!(f, "invalid access to unknown BAR region {:#x}", addr),
+            InvalidSize(size) => write!(f, "invalid access size {}", size),
+            InvalidRegister(reg_idx) => write!(f, "invalid config register {}", reg_idx),
+            Unsupported => write!(f, "feature not negotiated with the device"),
+            DevicePanicked => write!(f, "simulated device panicked"),
+            InvalidBdf(bdf) => write!(f, "invalid BDF {:#x}", bdf),
+            InvalidSnapshot => write!(f, "snapshot does not fit the device"),
+            InvalidVfCount(vfs) => write!(f, "cannot enable {} VFs", vfs),
+            OutOfAddressSpace(len) => write!(f, "no address space left for {:#x} bytes", len),
+            LinkDown => write!(f, "link down"),
+        }
+    }
+}
+
+impl std::error::Error for PciAdapterError {}
+
+pub type Result<T> = std::result::Result<T, PciAdapterError>;
+
+/// Asynchronous notifications of the bridge, received from [`PciAdapter::events`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdapterEvent {
+    /// The device left the lane while `pending` non-posted transactions were outstanding.
+    LaneDown { pending: usize },
+    /// A new lane is attached by [`PciAdapter::reconnect`] and `replayed` outstanding
+    /// transactions are sent on it again.
+    LaneUp { replayed: usize },
+    /// `count` outstanding transactions failed because the lane went down.
+    Aborted { count: usize },
+    /// The device model serving `bdf` panicked, its outstanding transactions fail.
+    DevicePanicked { bdf: u16 },
+    /// A device model is plugged in at `bdf` by [`PciAdapter::hotplug`].
+    Attached { bdf: u16 },
+    /// The function at `bdf` is unplugged by [`PciAdapter::unplug`].
+    Detached { bdf: u16 },
+    /// The function at `bdf` sent a PM_PME message, e.g. to wake from D3hot, see
+    /// [`ConfigSpace::pme`].
+    Pme { bdf: u16 },
+    /// The link of the device of `bdf` went down, see [`PciAdapter::set_link_up`].
+    LinkDown { bdf: u16 },
+    /// The link of the device of `bdf` is trained again.
+    LinkUp { bdf: u16 },
+    /// The function at `bdf` signaled an error of `severity`, see [`crate::aer`].
+    Error { bdf: u16, severity: ErrorSeverity },
+}
+
+/// What the bridge does with outstanding non-posted transactions once the lane goes down.
+///
+/// The in-process lane of [`PciAdapter::start`] only goes down when the device thread exits,
+/// nothing could bring it back, thus [`DisconnectPolicy::FailFast`] is the default. Transports
+/// which are able to get the peer back, e.g. a socket reconnecting with a resumable handshake
+/// or a shared memory peer restarted after its death is noticed, attach the new lane with
+/// [`PciAdapter::reconnect`] and choose either to abort or to re-sync the outstanding tags.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DisconnectPolicy {
+    /// Fail every outstanding transaction with [`PciAdapterError::Disconnected`] immediately.
+    #[default]
+    FailFast,
+    /// Keep outstanding transactions for up to `window` and replay them with their original
+    /// tags on the next attached lane.
+    Resync { window: Duration },
+}
+
+/// What the bridge does with a TLP for a function whose bounded lane is full, see
+/// [`PciAdapterBuilder::lane_capacity`].
+///
+/// Blocking never loses a TLP, but the whole bridge waits for the model to make room. A model
+/// which stops taking TLPs off its lane while it waits for room on the upstream lane deadlocks
+/// with the bridge. Dropping posted requests keeps the bridge going when a model falls behind
+/// on writes, like a link which discards what it can not buffer; non-posted requests still
+/// wait, at most one per tag is in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BackpressurePolicy {
+    #[default]
+    Block,
+    /// Drop memory writes and messages, counted in [`AdapterStats::dropped`].
+    DropPosted,
+}
+
+/// The value a config read of the Vendor ID register returns under CRS Software Visibility:
+/// Vendor ID 0x0001 and all 1s for the other bytes.
+const CRS_VENDOR_ID: u32 = 0xffff_0001;
+
+/// How the bridge handles config requests completed with Configuration Request Retry Status,
+/// which a device returns while it is still initializing after reset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CrsPolicy {
+    /// Delay before the first retry, doubled after every retry.
+    pub backoff: Duration,
+    /// Upper bound of the delay between two retries.
+    pub max_backoff: Duration,
+    /// Number of retries before the request fails with the CRS status.
+    pub max_retries: u32,
+    /// CRS Software Visibility. A read of the Vendor ID register completes with 0x0001 at once
+    /// instead of being retried, so the hypervisor could poll the device by itself.
+    pub software_visibility: bool,
+}
+
+impl Default for CrsPolicy {
+    fn default() -> Self {
+        CrsPolicy {
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(100),
+            max_retries: 20,
+            software_visibility: false,
+        }
+    }
+}
 
 /// The representation of PCIe lane in this library. Basically a full-duplex stream of PCIe transactions.
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct PciLane {
     pub tx: Sender<Tlp>,
     pub rx: Receiver<Tlp>,
 }
 
 impl PciLane {
-    fn pair() -> (PciLane, PciLane) {
+    /// Create both ends of an in-process lane.
+    pub fn pair() -> (PciLane, PciLane) {
         let (c1, c2) = (unbounded(), unbounded());
 
         (
@@ -22,502 +220,8374 @@ impl PciLane {
             PciLane { tx: c2.0, rx: c1.1 },
         )
     }
+
+    /// Raise an MSI from the device side, i.e. write the programmed message `data` to the
+    /// message `addr` by a memory write TLP.
+    pub fn raise_msi(
+        &self,
+        requester: u16,
+        addr: u64,
+        data: u32,
+    ) -> std::result::Result<(), SendError<Tlp>> {
+        let dw = vec![u32::from_be_bytes(data.to_le_bytes())];
+        let tlp = if addr >> 32 == 0 {
+            TlpBuilder::memory_write(MemoryExtra {
+                requester,
+                tag: 0,
+                addr: addr as u32,
+            })
+        } else {
+            TlpBuilder::memory_write64(Memory64Extra {
+                requester,
+                tag: 0,
+                addr,
+            })
+        };
+
+        self.tx.send(tlp.byte_enable(0x0f).data(dw).build())
+    }
+
+    /// Ask for the pages at `addrs` by page request group `prg_index`, the last request of the
+    /// group is marked. The answer is awaited by [`PciLane::await_prg_response`].
+    pub fn request_pages(
+        &self,
+        requester: u16,
+        prg_index: u16,
+        addrs: &[u64],
+        write: bool,
+    ) -> std::result::Result<(), SendError<Tlp>> {
+        for (i, addr) in addrs.iter().enumerate() {
+            let tlp = TlpBuilder::page_request(PageRequestExtra {
+                requester,
+                addr: addr & !0xfff,
+                prg_index,
+                last: i == addrs.len() - 1,
+                write,
+                read: true,
+            })
+            .build();
+            self.tx.send(tlp)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the PRG Response of group `prg_index` from the device side. Other TLPs received
+    /// meanwhile are handed to `other`. Returns the response code, `None` if the lane is gone.
+    pub fn await_prg_response(&self, prg_index: u16, mut other: impl FnMut(Tlp)) -> Option<u8> {
+        loop {
+            let tlp = self.rx.recv().ok()?;
+            match tlp.header._type {
+                PacketType::PrgResponse(extra) if extra.prg_index == prg_index => {
+                    return Some(extra.response)
+                }
+                _ => other(tlp),
+            }
+        }
+    }
+
+    /// Raise an MSI-X vector of the function `requester` from the device side. The bridge
+    /// sends the message the guest programmed into the MSI-X table for the vector, or marks it
+    /// pending while masked.
+    pub fn raise_msix(
+        &self,
+        requester: u16,
+        vector: u16,
+    ) -> std::result::Result<(), SendError<Tlp>> {
+        let message =
+            MessageExtra::routed(requester, MSIX_VECTOR_MESSAGE, MessageRouting::RootComplex);
+        let tlp = TlpBuilder::message_data(message)
+            .data(vec![vector as u32])
+            .build();
+
+        self.tx.send(tlp)
+    }
 }
 
 #[derive(Debug)]
 struct ConfigData {
+    target: u16,
     reg_idx: usize,
     offset: u64,
     len: usize,
     data: u32,
 }
 
-/// The message type between the PciRunnder thread and PciAdapter thread.
-#[derive(Debug)]
+/// The message type between the PciRunnder thread and PciAdapter thread. Requests to the
+/// device start with the BDF of the function they target, memory requests go to the function
+/// whose memory window contains the address instead if there is one.
 enum AdapterMessage {
-    IoRead(u32, Sender<u32>),
-    IoWrite(u32, u8, Sender<()>),
-    MemoryRead(u64, usize, Sender<Vec<u8>>),
-    MemoryWrite(u64, Vec<u8>, Sender<()>),
-    ConfigRead(usize, Sender<u32>),
-    ConfigWrite(ConfigData, Sender<()>),
+    IoRead(u16, u32, usize, Sender<Result<Vec<u8>>>),
+    /// IO write is non-posted, the waiter is woken up by its completion.
+    IoWrite(u16, u32, Vec<u8>, Sender<Result<()>>),
+    MemoryRead(u16, u64, usize, Sender<Result<Vec<u8>>>),
+    /// One request of a memory read split by the bridge, queued in front of the backlog.
+    MemoryReadPart(u16, u64, usize, Arc<Mutex<ReadGroup>>, usize),
+    /// Memory write is a posted transaction, thus nobody waits for its completion.
+    MemoryWrite(u16, u64, Vec<u8>),
+    ConfigRead(u16, usize, Sender<Result<u32>>),
+    ConfigWrite(ConfigData, Sender<Result<()>>),
+    SetCompletionTimeout(Duration),
+    SetEventLog(EventLog),
+    SetMsiSink(Box<dyn MsiSink>),
+    SetPtmTimeSource(Box<dyn PtmTimeSource>),
+    /// The GSI an INTx pin of the bridge is routed to.
+    GetIntxLine(u8, Sender<Result<Option<u32>>>),
+    SetIntxLine(u8, u32),
+    SetDmaMemory(Box<dyn DmaMemory>),
+    SetDmaTranslator(Box<dyn DmaTranslator>),
+    SetTranslationAgent(Box<dyn TranslationAgent>),
+    SetPageRequestHandler(Box<dyn PageRequestHandler>),
+    SetMessageHandler(Box<dyn MessageHandler>),
+    /// A message of the adapter of a function, routed by the bridge.
+    SendMessage(u16, Tlp, Sender<Result<()>>),
+    AddTlpObserver(Box<dyn TlpObserver>),
+    /// Invalidate the ATS translations of a range, answered by the Invalidate Completion.
+    AtsInvalidate(u16, u64, u64, Sender<Result<()>>),
+    /// Forward a vIOMMU event to an ATS capable device.
+    NotifyIommu(u16, IommuEvent, Sender<Result<()>>),
+    /// Size of the MSI-X table and the initial message control.
+    SetMsixTable(u16, usize, u16),
+    SetMsixControl(u16, u16),
+    /// The Max Payload Size and Max Read Request Size the guest programmed into Device
+    /// Control of a function.
+    SetPayloadSizes(u16, usize, usize),
+    /// The completion timeout the guest programmed into Device Control 2 of a function.
+    SetFunctionTimeout(u16, CompletionTimeout),
+    /// The errors a function latched.
+    GetErrorLog(u16, Sender<Result<AerLog>>),
+    /// Device Control of a function, whose reporting enables say which errors are signaled.
+    SetErrorReporting(u16, u16),
+    /// The guest wrote 1 to bits of Device Status of a function, in the upper half.
+    ClearErrors(u16, u32),
+    /// The ACS registers of the downstream port above a function.
+    SetAcs(u16, AcsPort),
+    MsixRead(u16, MsixStructure, usize, Sender<Result<Vec<u8>>>),
+    MsixWrite(u16, MsixStructure, Vec<u8>),
+    /// Replace the memory windows routed to a function.
+    RouteMemory(u16, Vec<Range<u64>>),
+    /// Publish the memory windows on a peer bus, along with the sender of the adapter.
+    JoinPeerBus(PeerBus, Sender<AdapterMessage>),
+    /// A memory request of a device behind another bridge of the peer bus, whose bridge takes
+    /// the completion of a read.
+    PeerRequest(Tlp, Sender<AdapterMessage>),
+    /// The completion of a peer-to-peer read, for a function of this bridge.
+    PeerCompletion(Tlp),
+    SetDisconnectPolicy(DisconnectPolicy),
+    SetCrsPolicy(CrsPolicy),
+    Reconnect(PciLane, DeviceFeatures),
+    GetFeatures(Sender<Result<DeviceFeatures>>),
+    GetStats(Sender<Result<AdapterStats>>),
+    /// Whether the device model of a function is alive and its lane is up.
+    Health(u16, Sender<Result<()>>),
+    Attach(u16, Box<dyn PciSimDevice + Send + Sync>, Sender<Result<()>>),
+    /// The LTSSM state of the link of a function.
+    GetLinkState(u16, Sender<Result<(LtssmState, Option<Link>)>>),
+    SetLinkUp(u16, bool, Sender<Result<()>>),
+    RetrainLink(u16, Sender<Result<()>>),
+    EnterL1(u16, Sender<Result<()>>),
+    /// Train the link of a function with the link the device supports, no faster than the
+    /// target speed.
+    NegotiateLink(u16, Link, Option<LinkSpeed>, Sender<Result<Link>>),
+    /// Serve more functions on the lane of a function, the VFs of a PF.
+    AddFunctions(u16, Vec<u16>, Sender<Result<()>>),
+    /// The MSI-X table and the outstanding requests of a function.
+    SaveFunction(u16, Sender<Result<(Option<MsixSnapshot>, Vec<Tlp>)>>),
+    /// Quiesce the model of a function and save the state of the function.
+    SaveModel(u16, Sender<Result<Option<Vec<u8>>>>),
+    /// Quiesce the model of a function and load the state of the function.
+    RestoreModel(u16, Vec<u8>, Sender<Result<()>>),
+    RestoreFunction(u16, Option<MsixSnapshot>, Vec<Tlp>),
+    /// Unplug a function once its outstanding transactions are done.
+    Detach(u16, Sender<Result<()>>),
+    /// A write to a doorbell of a function, passed on to the kicks of the model.
+    Kick(u16, Sender<usize>, usize),
+    /// Reset the device model of a function.
+    Reset(u16, ResetKind, Sender<Result<()>>),
+    /// Secondary bus reset of the bridge.
+    BusReset(Sender<Result<()>>),
     Exit,
 }
 
+impl AdapterMessage {
+    /// Whether the message asks for a non-posted transaction, which needs a tag.
+    fn is_non_posted(&self) -> bool {
+        use AdapterMessage::*;
+
+        matches!(
+            self,
+            IoRead(..)
+                | IoWrite(..)
+                | MemoryRead(..)
+                | MemoryReadPart(..)
+                | ConfigRead(..)
+                | ConfigWrite(..)
+        ) || matches!(self, PeerRequest(tlp, _) if is_memory_read(tlp))
+    }
+
+    fn is_posted(&self) -> bool {
+        match self {
+            AdapterMessage::MemoryWrite(..) | AdapterMessage::SendMessage(..) => true,
+            AdapterMessage::PeerRequest(tlp, _) => !is_memory_read(tlp),
+            _ => false,
+        }
+    }
+
+    /// Whether the message must stay in order with the requests to the device. Accesses to
+    /// the emulated MSI-X structures are ordered like the BAR accesses they stand for.
+    fn is_ordered(&self) -> bool {
+        use AdapterMessage::*;
+
+        self.is_posted()
+            || self.is_non_posted()
+            || matches!(self, MsixRead(..) | MsixWrite(..) | Kick(..))
+    }
+}
+
 /// The reaction carried by a received AdapterMessage
 #[derive(Debug)]
 enum Reaction {
     /// No action requiered
-    Notify(Sender<()>),
-    ReadConfig(Sender<u32>),
-    Io(Sender<u8>),
-    ReadMemory(Sender<Vec<u8>>),
+    Notify(Sender<Result<()>>),
+    ReadConfig(Sender<Result<u32>>),
+    /// Offset inside the DW and length of an IO read.
+    ReadIo(usize, usize, Sender<Result<Vec<u8>>>),
+    /// Length of a memory read, the completion carries whole DWs.
+    ReadMemory(usize, Sender<Result<Vec<u8>>>),
+    /// Length and index of a part of a split memory read.
+    ReadPart(usize, Arc<Mutex<ReadGroup>>, usize),
+    /// A peer-to-peer read, the completion goes to the bridge of the requester with the
+    /// requester ID and the tag of the original request.
+    Peer(CompletionExtra, Sender<AdapterMessage>),
 }
 
-fn make_bdf(bus: u8, device: u8, function: u8) -> u16 {
-    ((bus as u16) << 8) | ((function as u16 & 0b111) | ((device as u16) << 5))
+impl Reaction {
+    /// Wake up the waiter of a transaction which can not complete. The waiter may be gone
+    /// already, so the result of sending is ignored.
+    fn fail(self, err: PciAdapterError) {
+        let _ = match self {
+            Reaction::Notify(sender) => sender.send(Err(err)).is_ok(),
+            Reaction::ReadConfig(sender) => sender.send(Err(err)).is_ok(),
+            Reaction::ReadIo(_, _, sender) => sender.send(Err(err)).is_ok(),
+            Reaction::ReadMemory(_, sender) => sender.send(Err(err)).is_ok(),
+            Reaction::ReadPart(_, group, _) => group.lock().unwrap().fail(err),
+            Reaction::Peer(mut cpl, peer) => {
+                cpl.status = match err {
+                    PciAdapterError::Completion(status) => status,
+                    _ => CPL_CA,
+                };
+                let tlp = TlpBuilder::completion(cpl).build();
+                peer.send(AdapterMessage::PeerCompletion(tlp)).is_ok()
+            }
+        };
+    }
 }
 
-/// The bridge between the adapter and simulated PCIe device.
-struct PciSimBridge {
-    cmd_rx: Receiver<AdapterMessage>,
-    lane: PciLane,
-    bdf: u16,
-    config_tag: u8,
-    store: HashMap<u32, Reaction>,
-    handle: JoinHandle<()>,
+/// The parts of a memory read split into several requests, see [`split_request`]. The result is
+/// sent once all parts are in or the first one fails.
+#[derive(Debug)]
+struct ReadGroup {
+    parts: Vec<Option<Vec<u8>>>,
+    sender: Option<Sender<Result<Vec<u8>>>>,
 }
 
-impl PciSimBridge {
-    pub fn run(&mut self) {
-        loop {
-            select! {
-                recv(self.cmd_rx) -> msg => {
-                    let msg = msg.unwrap();
-                    if let AdapterMessage::Exit = msg {
-                        break;
-                    }
-                    self.handle_adapter_msg(msg);
-                },
-
-                recv(self.lane.rx) -> msg => {
-                    let msg = msg.unwrap();
-                    self.handle_transaction_msg(msg);
-                }
-            }
+impl ReadGroup {
+    fn new(parts: usize, sender: Sender<Result<Vec<u8>>>) -> Self {
+        ReadGroup {
+            parts: vec![None; parts],
+            sender: Some(sender),
         }
     }
 
-    fn next_config_tag(&mut self) -> u8 {
-        let tag = self.config_tag;
-        self.config_tag += 1;
-        tag
-    }
-
-    fn next_transaction_id(&mut self) -> u32 {
-        self.next_config_tag() as u32 | ((self.bdf as u32) << 16)
+    /// Whether the read failed already, the parts not sent yet could be skipped.
+    fn is_done(&self) -> bool {
+        self.sender.is_none()
     }
 
-    fn handle_adapter_msg(&mut self, msg: AdapterMessage) {
-        use AdapterMessage::*;
-        match msg {
-            ConfigRead(idx, sender) => {
-                let trans_id = self.next_transaction_id();
-                self.store.insert(trans_id, Reaction::ReadConfig(sender));
+    fn complete(&mut self, index: usize, data: Vec<u8>) -> bool {
+        self.parts[index] = Some(data);
+        if !self.parts.iter().all(Option::is_some) {
+            return true;
+        }
 
-                let tlp = TlpBuilder::config0_read(ConfigExtra {
-                    requester: self.bdf,
-                    completer: make_bdf(0x0, 0x3, 0x0),
-                    tag: (trans_id & 0xff) as u8,
-                    reg: idx as u16,
-                })
-                .build();
+        let data = self
+            .parts
+            .iter_mut()
+            .flat_map(|p| p.take().unwrap())
+            .collect();
+        self.sender
+            .take()
+            .is_some_and(|sender| sender.send(Ok(data)).is_ok())
+    }
 
-                self.lane.tx.send(tlp).unwrap();
-            }
-            ConfigWrite(data, sender) => {
-                let trans_id = self.next_transaction_id();
-                self.store.insert(trans_id, Reaction::Notify(sender));
+    fn fail(&mut self, err: PciAdapterError) -> bool {
+        self.sender
+            .take()
+            .is_some_and(|sender| sender.send(Err(err)).is_ok())
+    }
+}
 
-                let byte_enable = (!(u8::MAX << data.len)) << data.offset;
-                let value = data.data << (data.offset * 8);
+/// Whether a TLP is a memory read, which is non-posted unlike the other memory requests.
+fn is_memory_read(tlp: &Tlp) -> bool {
+    matches!(
+        tlp.header._type,
+        PacketType::MemoryRead(_) | PacketType::MemoryRead64(_)
+    )
+}
 
-                let tlp = TlpBuilder::config0_write(ConfigExtra {
-                    requester: self.bdf,
-                    completer: make_bdf(0x0, 0x3, 0x0),
-                    tag: (trans_id & 0xff) as u8,
-                    reg: data.reg_idx as u16,
-                })
-                .byte_enable(byte_enable)
-                .data(vec![value])
-                .build();
+/// The memory windows of several adapters, for peer-to-peer requests between their devices.
+///
+/// A memory request of a device which falls into none of the windows of its own bridge goes to
+/// the bridge owning the window on the bus, which forwards it to its function and steers the
+/// completion of a read back to the requester, see [`PciAdapter::join_peer_bus`].
+#[derive(Clone, Default)]
+pub struct PeerBus {
+    windows: Arc<Mutex<Vec<PeerWindow>>>,
+}
 
-                self.lane.tx.send(tlp).unwrap();
-            }
-            MemoryRead(addr, size, sender) => {
-                let trans_id = self.next_transaction_id();
-                self.store.insert(trans_id, Reaction::ReadMemory(sender));
-
-                // TODO: handle memory read request larger than 1024 DW.
-                // We do 64 bit memory read transaction anyway.
-                // TODO: that's faulty implementation since PCIe spec explicit stated
-                // that memory transaction under 4GB boundary should use 32bit packet
-                // format. Let's fix this in the future.
-                let bits = (addr & 0b11) as u8;
-                let byte_enable = (0xff << bits) & 0xf;
-                let size = (size + 3) >> 2; // in DW
-
-                let tlp = TlpBuilder::memory_read64(Memory64Extra {
-                    requester: self.bdf,
-                    tag: (trans_id & 0xff) as u8,
-                    addr,
-                })
-                .byte_enable(byte_enable)
-                .length(size as u16)
-                .build();
+/// A memory window on a peer bus and the bridge owning it.
+type PeerWindow = (Range<u64>, Sender<AdapterMessage>);
 
-                self.lane.tx.send(tlp).unwrap();
-            }
-            _ => unimplemented!(),
-        }
+impl PeerBus {
+    pub fn new() -> Self {
+        PeerBus::default()
     }
 
-    fn handle_transaction_msg(&mut self, msg: Tlp) {
-        match msg.header._type {
-            PacketType::CompletionData(extra) => {
-                if let Some(reaction) = self.store.get(&msg.header.transaction_id()) {
-                    match reaction {
-                        Reaction::ReadConfig(sender) => {
-                            sender.send(msg.data.unwrap()[0]).unwrap();
-                        }
-                        Reaction::Notify(sender) => {
-                            sender.send(()).unwrap();
-                        }
-                        Reaction::ReadMemory(sender) => {
-                            // TODO: optimize the logic to handle non-continuously QW aligned access.
-                            let dw = msg.data.unwrap();
-                            let dw_size = dw.len();
-                            let offset = (extra.lower_address & 0b11) as usize;
-                            let first_dw = dw[0].to_be_bytes();
-                            let mut data = Vec::from(&first_dw[offset..4]);
-                            if dw_size > 1 {
-                                for i in 1..dw_size {
-                                    let offset = if i == dw_size - 1 {
-                                        4 - (msg.header.byte_enable & 0xf0 | 0x8).leading_zeros()
-                                            as usize
-                                    } else {
-                                        4
-                                    };
-                                    data.extend_from_slice(&dw[i].to_be_bytes()[0..offset]);
-                                }
-                            }
+    /// Replace the windows of the bridge taking commands on `bridge`.
+    fn publish(&self, bridge: &Sender<AdapterMessage>, windows: &[(Range<u64>, u16)]) {
+        let mut bus = self.windows.lock().unwrap();
+        bus.retain(|(_, tx)| !tx.same_channel(bridge));
+        bus.extend(
+            windows
+                .iter()
+                .map(|(window, _)| (window.clone(), bridge.clone())),
+        );
+    }
 
-                            sender.send(data).unwrap();
-                        }
-                        _ => unimplemented!(),
-                    }
-                }
-            }
-            _ => unimplemented!(),
-        }
+    /// The bridge other than `bridge` with a window containing `addr`, if any.
+    fn route(&self, bridge: &Sender<AdapterMessage>, addr: u64) -> Option<Sender<AdapterMessage>> {
+        self.windows
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(window, tx)| window.contains(&addr) && !tx.same_channel(bridge))
+            .map(|(_, tx)| tx.clone())
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct MmioRegion {
-    pub start: GuestAddress,
-    pub length: GuestUsize,
-    pub type_: PciBarRegionType,
-    pub bar_reg: usize,
-    pub slot_mapped: bool,
-    pub mem_slot: Option<u32>,
-    pub host_addr: Option<u64>,
-    pub mmap_size: Option<usize>,
+/// Split a memory request of `len` bytes at `addr` into requests of at most `max` bytes. The
+/// requests do not cross a multiple of `max`, nor a 4KB boundary as `max` divides 4KB.
+pub(crate) fn split_request(addr: u64, len: usize, max: usize) -> Vec<(u64, usize)> {
+    let max = max as u64;
+    let end = addr + len as u64;
+    let mut parts = vec![];
+    let mut start = addr;
+    while start < end {
+        let next = ((start / max + 1) * max).min(end);
+        parts.push((start, (next - start) as usize));
+        start = next;
+    }
+    parts
 }
 
-/// The adapter PCI device exporting an hypervisor friendly interface.
-pub struct PciAdapter {
-    tx: Sender<AdapterMessage>,
-    pub(crate) mmio_regions: Vec<MmioRegion>,
-    handle: JoinHandle<()>,
+/// The Max Payload Size and Max Read Request Size in bytes of the Device Control register
+/// `devctl`, bits 7:5 and 14:12 encoding 128 bytes times a power of 2.
+fn payload_sizes(devctl: u32) -> (usize, usize) {
+    let size = |shift: u32| 128 << ((devctl >> shift) & 0x7).min(5);
+    (size(5), size(12))
 }
 
-impl PciAdapter {
-    /// Request the runner thread to send a type 0 config read transaction to the simulated device.
-    /// Then block and wait for the completion transaction.
-    pub fn config_read(&self, reg_idx: usize) -> u32 {
-        let (tx, rx) = unbounded();
-        self.tx
-            .send(AdapterMessage::ConfigRead(reg_idx, tx))
-            .unwrap();
-        rx.recv().unwrap()
-    }
+/// Max Payload Size and Max Read Request Size of Device Control after reset.
+const DEFAULT_PAYLOAD_SIZES: (usize, usize) = (128, 512);
 
-    /// Request the runner thread to send a type 0 config write transaction to the simulated device.
-    /// Then block and wait for the completion transaction.
-    pub fn config_write(&self, reg_idx: usize, offset: u64, data: &[u8]) {
-        let (tx, rx) = unbounded();
-        let len = data.len();
-        let mut bytes = 0;
+/// DW of Device Control 2 in the PCI Express capability.
+const DEVCTL2_DW: usize = 10;
+/// Completion Timeout Disable of Device Control 2.
+const DEVCTL2_CTD: u32 = 1 << 4;
 
-        for b in data.iter().rev() {
-            bytes = (bytes << 8) | *b as u32;
+/// The completion timeout of the requests to a function, as its Device Control 2 holds it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompletionTimeout {
+    /// The default range, which the bridge serves with its own completion timeout.
+    Default,
+    /// The upper end of the range of the Completion Timeout Value.
+    Range(Duration),
+    /// The function disabled its completion timeout, the requests wait as long as it takes.
+    Disabled,
+}
+
+impl CompletionTimeout {
+    /// The completion timeout of Device Control 2 `devctl2`, reserved values select the
+    /// default range.
+    fn from_devctl2(devctl2: u32) -> Self {
+        if devctl2 & DEVCTL2_CTD != 0 {
+            return CompletionTimeout::Disabled;
         }
 
-        let data = ConfigData {
-            reg_idx,
-            offset,
-            len,
-            data: bytes,
+        let micros = match devctl2 & 0xf {
+            0x1 => 100,
+            0x2 => 10_000,
+            0x5 => 55_000,
+            0x6 => 210_000,
+            0x9 => 900_000,
+            0xa => 3_500_000,
+            0xd => 13_000_000,
+            0xe => 64_000_000,
+            _ => return CompletionTimeout::Default,
         };
-        self.tx.send(AdapterMessage::ConfigWrite(data, tx)).unwrap();
-        rx.recv().unwrap()
+        CompletionTimeout::Range(Duration::from_micros(micros))
     }
+}
 
-    pub fn bar_mmio_read(&self, addr: u64, data: &mut [u8]) {
-        if let Some(region) = self.find_region(addr) {
-            if data.len() > 8 {
-                error!("Invalid access to MMIO region {:#x} {}", addr, data.len());
-                data.fill(0xff);
-                return;
-            }
+/// A non-posted transaction waiting for its completion.
+#[derive(Debug)]
+struct Pending {
+    /// BDF of the function the request is sent to.
+    target: u16,
+    reaction: Reaction,
+    /// None if the function disabled its completion timeout.
+    deadline: Option<Instant>,
+    /// Kept for replaying the request on a reconnected lane or retrying it.
+    tlp: Tlp,
+    retries: u32,
+    /// When the request is sent again after a CRS completion.
+    retry_at: Option<Instant>,
+}
 
-            if region.slot_mapped {
-                error!(
-                    "Region should be memory backed, maybe you forget to register the slot? {:#x}",
-                    addr
-                );
-            }
+/// Calculate the 1st and last DW byte enables of a memory write which starts at `offset` inside
+/// the first DW and spans `len` bytes.
+pub(crate) fn write_byte_enable(offset: usize, len: usize) -> u8 {
+    let end = offset + len;
 
-            let (tx, rx) = unbounded();
-            self.tx
-                .send(AdapterMessage::MemoryRead(addr, data.len(), tx))
-                .unwrap();
-            let value = rx.recv().unwrap();
-            assert_eq!(value.len(), data.len());
-            data.copy_from_slice(&value);
-        } else {
-            error!("Invalid access to unknown BAR region {:#x}", addr);
-        }
+    if end <= 4 {
+        return (((1u16 << len) - 1) << offset) as u8;
     }
 
-    pub fn bar_write() {
-        unimplemented!();
-    }
+    let first = (0xf << offset) & 0xf;
+    let last = (1u8 << (end - (end - 1) / 4 * 4)) - 1;
+    first | (last << 4)
+}
 
-    fn config_write_u32(&self, reg_idx: usize, data: u32) {
-        self.config_write(reg_idx, 0, &data.to_le_bytes());
+/// Whether the Length field of a completion agrees with its payload.
+fn payload_matches(tlp: &Tlp) -> bool {
+    match (tlp.header._type, tlp.data.as_ref()) {
+        (PacketType::CompletionData(_), Some(dw)) => match tlp.header.length {
+            0 => dw.len() == 1024,
+            len => dw.len() == len as usize,
+        },
+        (PacketType::CompletionData(_), None) => false,
+        (_, dw) => dw.is_none(),
     }
+}
 
-    /// Helper function to return the result when we write all 1s to a BAR. The original value of
-    /// the BAR is restored after this detection.
-    fn detect_bar(&mut self, reg_idx: usize) -> u32 {
-        let pre = self.read_config_register(reg_idx);
-        self.config_write_u32(reg_idx, u32::MAX);
-        let ret = self.read_config_register(reg_idx);
-        self.config_write_u32(reg_idx, pre);
-        ret
+/// The `len` bytes asked for by a memory read out of the DWs of its completion.
+fn completion_bytes(
+    header: &TlpHeader,
+    extra: &CompletionExtra,
+    dw: &[u32],
+    len: usize,
+) -> Vec<u8> {
+    // TODO: optimize the logic to handle non-continuously QW aligned access.
+    let dw_size = dw.len();
+    let offset = (extra.lower_address & 0b11) as usize;
+    let first_dw = dw[0].to_be_bytes();
+    let mut data = Vec::from(&first_dw[offset..4]);
+    if dw_size > 1 {
+        for i in 1..dw_size {
+            let offset = if i == dw_size - 1 {
+                4 - (header.byte_enable & 0xf0 | 0x8).leading_zeros() as usize
+            } else {
+                4
+            };
+            data.extend_from_slice(&dw[i].to_be_bytes()[0..offset]);
+        }
     }
 
-    /// Find a registered BAR region which contains the given guest physical address
-    fn find_region(&self, addr: u64) -> Option<MmioRegion> {
-        for region in self.mmio_regions.iter() {
-            if addr >= region.start.raw_value()
-                && addr < region.start.unchecked_add(region.length).raw_value()
-            {
-                return Some(*region);
-            }
-        }
-        None
+    // Drop the bytes of the DWs which are not asked for.
+    data.truncate(len);
+    data
+}
+
+/// Compose the requester or completer ID of a function.
+pub fn make_bdf(bus: u8, device: u8, function: u8) -> u16 {
+    ((bus as u16) << 8) | ((device as u16 & 0x1f) << 3) | (function as u16 & 0b111)
+}
+
+/// The link of the device of a function, which its functions share.
+fn link_of(bdf: u16) -> u16 {
+    bdf & !0b111
+}
+
+/// The result of a transition of the LTSSM which only a link that is up takes.
+fn link_up(up: bool) -> Result<()> {
+    if up {
+        Ok(())
+    } else {
+        Err(PciAdapterError::LinkDown)
     }
+}
 
-    /// Scan all of the six BAR and execute the callback for them.
-    pub fn scan_bar(&mut self) -> Vec<MmioRegion> {
-        use PciBarRegionType::*;
+/// Most functions a device could have.
+const MAX_FUNCTIONS: usize = 8;
+/// Most bytes a memory read request could ask for, which are 1024 DWs.
+pub(crate) const MAX_READ_REQUEST: usize = 4096;
+/// Most bytes a TLP could carry, which are 1024 DWs.
+const MAX_PAYLOAD: usize = 4096;
+/// Most TLPs reordered together by [`OrderingPolicy::Reorder`].
+const REORDER_WINDOW: usize = 16;
+/// How often the credits of the lanes holding back TLPs are polled.
+const FC_POLL: Duration = Duration::from_millis(1);
 
-        let mut regions = vec![];
-        let mut bar_reg = BAR0_REG;
+/// The bridge between the adapter and the simulated PCIe devices.
+///
+/// The bridge acts as the root complex of a small hierarchy: it owns a lane to every function
+/// keyed by BDF, routes config requests by completer ID and memory requests by the memory
+/// windows of the functions. Completions go back by requester ID, so functions may also talk
+/// to each other.
+struct PciSimBridge {
+    cmd_rx: Receiver<AdapterMessage>,
+    /// TLPs of every function of the hierarchy.
+    upstream: Receiver<Tlp>,
+    /// Lane to each function, by BDF.
+    downstream: HashMap<u16, Sender<Tlp>>,
+    /// Flow control of the lanes whose model advertises credits.
+    flows: Vec<LaneFlow>,
+    /// Memory windows of the functions, usually their memory BARs.
+    windows: Vec<(Range<u64>, u16)>,
+    /// The peer bus the windows are published on and the sender of this bridge.
+    peers: Option<(PeerBus, Sender<AdapterMessage>)>,
+    bdf: u16,
+    features: DeviceFeatures,
+    tags: TagPool,
+    /// Requests waiting for a free tag, in the order they are issued.
+    backlog: VecDeque<AdapterMessage>,
+    store: HashMap<u32, Pending>,
+    /// The completion timeout of the functions in the default range.
+    completion_timeout: Duration,
+    /// The completion timeouts the guest programmed into the functions.
+    completion_timeouts: HashMap<u16, CompletionTimeout>,
+    /// Memory reads above this size in bytes are split into several requests.
+    max_read_request: usize,
+    /// The Max Payload Size and Max Read Request Size of the functions with a PCI Express
+    /// capability, which bound the requests sent to them.
+    payload_sizes: HashMap<u16, (usize, usize)>,
+    /// Device writes to these addresses are MSIs.
+    msi_window: Range<u64>,
+    event_log: Option<EventLog>,
+    observers: Vec<Box<dyn TlpObserver>>,
+    msi_sink: Option<Box<dyn MsiSink>>,
+    /// The PTM Master Time of the PTM Responses, see [`crate::ptm`].
+    ptm_time: Box<dyn PtmTimeSource>,
+    /// The virtual INTx wires the functions assert, by BDF and pin, INTA being pin 0.
+    intx: HashSet<(u16, u8)>,
+    /// The GSIs the INTx pins are routed to, shared by the functions on the same pin.
+    intx_lines: [Option<u32>; 4],
+    /// MSI-X tables by BDF.
+    msix: HashMap<u16, MsixTable>,
+    /// Command registers by BDF as written by the config requests of the bridge. Functions
+    /// whose command register was never written are not restricted.
+    command: HashMap<u16, u16>,
+    dma_memory: Option<Box<dyn DmaMemory>>,
+    dma_translator: Option<Box<dyn DmaTranslator>>,
+    translation_agent: Option<Box<dyn TranslationAgent>>,
+    /// Waiters of the ATS invalidations in the order they are sent. The Invalidate Completion
+    /// does not tell its function, so the functions share the queue.
+    invalidations: VecDeque<Sender<Result<()>>>,
+    page_handler: Option<Box<dyn PageRequestHandler>>,
+    message_handler: Option<Box<dyn MessageHandler>>,
+    /// Page request groups waiting for their last request, by requester and group index.
+    page_requests: HashMap<(u16, u16), Vec<PageRequestExtra>>,
+    disconnect_policy: DisconnectPolicy,
+    crs_policy: CrsPolicy,
+    backpressure: BackpressurePolicy,
+    ordering: OrderingPolicy,
+    delay: DelayModel,
+    /// TLPs held back by the delay model, to the functions by BDF and from the devices.
+    delayed_down: DelayLine<(u16, Tlp)>,
+    delayed_up: DelayLine<Tlp>,
+    link: Option<Link>,
+    /// Meters of the links by direction and device, see [`PciAdapterBuilder::link`].
+    throttles: HashMap<(Direction, u16), Throttle>,
+    /// The LTSSM of the links which left L0 since they were trained, by [`link_of`].
+    links: HashMap<u16, Ltssm>,
+    /// The errors latched by the functions, see [`crate::aer`].
+    errors: HashMap<u16, AerLog>,
+    /// Device Control of the functions, as the guest wrote it.
+    error_reporting: HashMap<u16, u16>,
+    /// The ACS registers of the downstream ports, by [`link_of`], see [`crate::acs`].
+    acs: HashMap<u16, AcsPort>,
+    connected: bool,
+    events: Sender<AdapterEvent>,
+    /// Device model threads by the BDF of their first function.
+    handles: Vec<(u16, JoinHandle<()>)>,
+    /// Hands a reset and the lane to run on afterwards to the device model threads, by the BDF
+    /// of their first function.
+    controls: HashMap<u16, Sender<ModelControl>>,
+    /// Tells the BDF of a device model whose thread ends, see [`ExitNotice`].
+    exits: Receiver<u16>,
+    /// Functions whose device model panicked.
+    panicked: HashSet<u16>,
+    stats: AdapterStats,
+    /// The sending end of the upstream lane, kept while device models are alive to hand it to
+    /// the ones plugged in later.
+    upstream_tx: Option<Sender<Tlp>>,
+    exits_tx: Sender<u16>,
+    /// Spawns the device models plugged in at runtime like the ones started with the bridge.
+    builder: PciAdapterBuilder,
+    /// Functions being unplugged and the waiters of the unplug.
+    detaching: HashMap<u16, Sender<Result<()>>>,
+    /// Dropped with the bridge, which tells the requests of the adapters that no answer comes
+    /// any more.
+    _alive: Sender<()>,
+}
 
-        while bar_reg < BAR0_REG + NUM_BAR_REGS {
-            let lsb_size: u32 = self.detect_bar(bar_reg);
-            let region_size: u64;
-            let mut slot_mapped = false;
-            let mut is_64bit = false;
+/// The thread of a device model, see [`PciAdapterBuilder::spawn_device`].
+struct ModelThread {
+    bdf: u16,
+    handle: JoinHandle<()>,
+    controls: Sender<ModelControl>,
+}
 
-            if lsb_size == 0 {
-                bar_reg += 1;
-                continue;
-            }
+/// What the bridge hands to the thread of a device model along with the lane the model runs
+/// on next. The model returned from [`PciSimDevice::run`] on the old lane before it takes it,
+/// so the model is quiesced meanwhile.
+enum ModelControl {
+    Reset(ResetKind, PciLane),
+    /// Save the state of a function, see [`PciSimDevice::state_save`].
+    Save(u16, PciLane, Sender<Result<Option<Vec<u8>>>>),
+    /// Load the state of a function, see [`PciSimDevice::state_restore`].
+    Restore(u16, Vec<u8>, PciLane, Sender<Result<()>>),
+}
 
-            let region_type = if lsb_size & 0x1 == 1 {
-                IoRegion
-            } else if (lsb_size >> 1) & 0x3 == 0x2 {
-                Memory64BitRegion
-            } else {
-                Memory32BitRegion
-            };
+/// Sends the BDF of a device model when its thread ends, by return or by panic.
+struct ExitNotice(u16, Sender<u16>);
 
-            let prefetchable = lsb_size & 0b1000 != 0;
+impl Drop for ExitNotice {
+    fn drop(&mut self) {
+        let _ = self.1.send(self.0);
+    }
+}
 
-            match region_type {
-                Memory64BitRegion => {
-                    let msb_size = self.detect_bar(bar_reg + 1);
-                    region_size =
-                        !(((msb_size as u64) << 32) | (lsb_size as u64 & 0xffff_fff0)) + 1;
-                    slot_mapped = prefetchable;
-                    is_64bit = true;
-                }
-                Memory32BitRegion => {
-                    region_size = !(lsb_size as u64 & 0xffff_fff0) + 1;
-                    slot_mapped = prefetchable;
-                }
-                IoRegion => {
-                    region_size = (!(lsb_size & 0xffff_fffc) + 1) as u64;
-                }
-            }
+/// The message of a panic payload, which is either a `&str` or a `String`.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()))
+        .unwrap_or("unknown panic")
+}
 
-            regions.push(MmioRegion {
-                start: GuestAddress(0),
-                length: region_size,
+impl PciSimBridge {
+    pub fn run(&mut self) {
+        loop {
+            // Models take TLPs off their lanes without telling the bridge, the credits of
+            // the held TLPs are polled.
+            let poll = self
+                .flows
+                .iter()
+                .any(|f| !f.held.is_empty())
+                .then(|| Instant::now() + FC_POLL);
+            let timer = match self
+                .store
+                .values()
+                .filter_map(|p| p.retry_at.or(p.deadline))
+                .chain(poll)
+                .chain(self.delayed_down.next_due())
+                .chain(self.delayed_up.next_due())
+                .min()
+            {
+                Some(deadline) => after(deadline.saturating_duration_since(Instant::now())),
+                None => never(),
+            };
+
+            select! {
+                recv(self.cmd_rx) -> msg => {
+                    match msg {
+                        Ok(AdapterMessage::Exit) | Err(_) => break,
+                        Ok(msg) => self.dispatch(msg),
+                    }
+                },
+
+                recv(self.upstream) -> msg => {
+                    match msg {
+                        Ok(msg) => self.receive(msg),
+                        Err(_) => self.disconnect(),
+                    }
+                },
+
+                recv(self.exits) -> bdf => {
+                    match bdf {
+                        Ok(bdf) => self.device_exited(bdf),
+                        Err(_) => self.exits = never(),
+                    }
+                },
+
+                recv(timer) -> _ => {
+                    self.expire_transactions();
+                    self.retry_transactions();
+                },
+            }
+
+            self.release_delayed();
+            self.release_credits();
+            self.drain_backlog();
+            self.finish_detach();
+        }
+
+        self.shutdown();
+        self.record_event(EventKind::Stopped, 0);
+    }
+
+    /// Shut down in order: commands are no longer accepted, the requests already accepted get
+    /// one completion timeout to complete, then the lanes are closed, which ends the device
+    /// models, and their threads are joined.
+    fn shutdown(&mut self) {
+        // Dropping the receiver fails the commands queued after the exit and every later one.
+        self.cmd_rx = never();
+        if let Some((bus, tx)) = self.peers.take() {
+            bus.publish(&tx, &[]);
+        }
+
+        let deadline = Instant::now() + self.completion_timeout;
+        let idle = |bridge: &Self| {
+            bridge.store.is_empty()
+                && bridge.backlog.is_empty()
+                && bridge.flows.iter().all(|f| f.held.is_empty())
+                && bridge.delayed_down.is_empty()
+                && bridge.delayed_up.is_empty()
+        };
+        while self.connected && !idle(self) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            let poll = self
+                .flows
+                .iter()
+                .any(|f| !f.held.is_empty())
+                .then(|| now + FC_POLL);
+            let timer = self
+                .store
+                .values()
+                .filter_map(|p| p.retry_at.or(p.deadline))
+                .chain(poll)
+                .chain(self.delayed_down.next_due())
+                .chain(self.delayed_up.next_due())
+                .min()
+                .unwrap_or(deadline)
+                .min(deadline);
+
+            select! {
+                recv(self.upstream) -> msg => {
+                    match msg {
+                        Ok(msg) => self.receive(msg),
+                        Err(_) => self.disconnect(),
+                    }
+                },
+
+                recv(after(timer.saturating_duration_since(now))) -> _ => {
+                    self.expire_transactions();
+                    self.retry_transactions();
+                },
+            }
+
+            self.release_delayed();
+            self.release_credits();
+            self.drain_backlog();
+        }
+
+        // Whatever is left fails, the requests in the backlog by dropping their waiters.
+        self.backlog.clear();
+        let err = if self.connected {
+            PciAdapterError::Timeout
+        } else {
+            PciAdapterError::Disconnected
+        };
+        for trans_id in self.store.keys().copied().collect::<Vec<_>>() {
+            self.retire(trans_id).unwrap().reaction.fail(err);
+        }
+        for waiter in self.invalidations.drain(..) {
+            let _ = waiter.send(Err(PciAdapterError::Disconnected));
+        }
+
+        self.downstream.clear();
+        self.controls.clear();
+        let deadline = Instant::now() + self.completion_timeout;
+        for (bdf, handle) in std::mem::take(&mut self.handles) {
+            // Keep a bounded upstream lane from filling up while the device winds down.
+            while !handle.is_finished() && Instant::now() < deadline {
+                let _ = self.upstream.recv_timeout(Duration::from_millis(1));
+            }
+
+            if !handle.is_finished() {
+                error!(
+                    "Device model {:#x} ignores the closed lane, leaving it behind",
+                    bdf
+                );
+            } else if let Err(payload) = handle.join() {
+                error!(
+                    "Device model {:#x} panicked: {}",
+                    bdf,
+                    panic_message(payload.as_ref())
+                );
+            }
+        }
+    }
+
+    /// Reap the thread of a device model which ended. If it panicked, its functions are
+    /// marked as such and their outstanding transactions fail.
+    fn device_exited(&mut self, bdf: u16) {
+        let idx = match self.handles.iter().position(|(b, _)| *b == bdf) {
+            Some(idx) => idx,
+            None => return,
+        };
+        self.controls.remove(&bdf);
+        match self.handles.remove(idx).1.join() {
+            Ok(()) => debug!("Device model {:#x} exited", bdf),
+            Err(payload) => self.device_panicked(bdf, payload.as_ref()),
+        }
+        // Nothing but the bridge keeps the upstream lane open after the last model is gone.
+        if self.handles.is_empty() {
+            self.upstream_tx = None;
+        } else if let Some(lane) = self.downstream.get(&bdf).cloned() {
+            if !self.panicked.contains(&bdf) {
+                self.lane_lost(&lane);
+            }
+        }
+
+        // The end of the lane of the model is gone with its thread, take the last TLPs it sent
+        // and notice the disconnect right away.
+        loop {
+            match self.upstream.try_recv() {
+                Ok(msg) => {
+                    self.record_tlp(Direction::Upstream, &msg);
+                    self.handle_transaction_msg(msg);
+                }
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnect();
+                    break;
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+    }
+
+    fn device_panicked(&mut self, bdf: u16, payload: &(dyn Any + Send)) {
+        error!(
+            "Device model {:#x} panicked: {}",
+            bdf,
+            panic_message(payload)
+        );
+        self.record_event(EventKind::DevicePanicked, bdf as u32);
+        self.emit(AdapterEvent::DevicePanicked { bdf });
+
+        // A model serving several functions on one lane takes all of them down.
+        let functions: Vec<u16> = match self.downstream.get(&bdf) {
+            Some(lane) if self.connected => self
+                .downstream
+                .iter()
+                .filter(|(_, other)| other.same_channel(lane))
+                .map(|(bdf, _)| *bdf)
+                .collect(),
+            _ => vec![bdf],
+        };
+        self.panicked.extend(functions);
+
+        let failed: Vec<u32> = self
+            .store
+            .iter()
+            .filter(|(_, p)| self.panicked.contains(&p.target))
+            .map(|(id, _)| *id)
+            .collect();
+        for trans_id in failed {
+            let pending = self.retire(trans_id).unwrap();
+            pending.reaction.fail(PciAdapterError::DevicePanicked);
+        }
+    }
+
+    /// Count a TLP sent or received, show it to the observers and append it to the event log.
+    fn record_tlp(&mut self, direction: Direction, tlp: &Tlp) {
+        let kind = match direction {
+            Direction::Downstream => {
+                self.stats.count_sent(tlp);
+                EventKind::TlpSent
+            }
+            Direction::Upstream => {
+                self.stats.count_received(tlp);
+                EventKind::TlpReceived
+            }
+        };
+
+        for observer in self.observers.iter_mut() {
+            observer.observe(direction, tlp);
+        }
+
+        if let Some(log) = self.event_log.as_mut() {
+            log.append_tlp(kind, tlp);
+        }
+    }
+
+    /// Count an event and append it to the event log.
+    fn record_event(&mut self, kind: EventKind, arg: u32) {
+        match kind {
+            EventKind::Timeout => self.stats.timeouts += 1,
+            EventKind::CompletionError => self.stats.completion_errors += 1,
+            _ => (),
+        }
+
+        if let Some(log) = self.event_log.as_mut() {
+            log.append(kind, arg, &[]);
+        }
+    }
+
+    /// Handle a message of the adapter. A request which can not get a tag waits in the
+    /// backlog, so does every following request to keep their order.
+    fn dispatch(&mut self, msg: AdapterMessage) {
+        let ordered = msg.is_ordered();
+        let blocked = msg.is_non_posted() && self.tags.is_exhausted();
+
+        if ordered && (blocked || !self.backlog.is_empty()) {
+            self.backlog.push_back(msg);
+        } else {
+            self.handle_adapter_msg(msg);
+        }
+    }
+
+    /// Issue the requests of the backlog as long as tags are available.
+    fn drain_backlog(&mut self) {
+        while let Some(msg) = self.backlog.front() {
+            if msg.is_non_posted() && self.tags.is_exhausted() {
+                break;
+            }
+
+            let msg = self.backlog.pop_front().unwrap();
+            self.handle_adapter_msg(msg);
+        }
+    }
+
+    /// Forget a transaction and release its tag.
+    fn retire(&mut self, trans_id: u32) -> Option<Pending> {
+        let pending = self.store.remove(&trans_id)?;
+        self.tags.free(trans_id as u8);
+        Some(pending)
+    }
+
+    fn emit(&self, event: AdapterEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Send a non-posted request and remember its reaction until the completion arrives.
+    fn submit(&mut self, target: u16, trans_id: u32, reaction: Reaction, tlp: Tlp) {
+        if self.panicked.contains(&target) {
+            self.tags.free(trans_id as u8);
+            reaction.fail(PciAdapterError::DevicePanicked);
+            return;
+        }
+        if self.detaching.contains_key(&target) || !self.downstream.contains_key(&target) {
+            self.tags.free(trans_id as u8);
+            reaction.fail(PciAdapterError::Disconnected);
+            return;
+        }
+        if !self.link_state(target).is_up() {
+            self.tags.free(trans_id as u8);
+            reaction.fail(PciAdapterError::LinkDown);
+            return;
+        }
+
+        let pending = Pending {
+            target,
+            reaction,
+            deadline: self.deadline_of(target, Instant::now()),
+            tlp: tlp.clone(),
+            retries: 0,
+            retry_at: None,
+        };
+        self.store.insert(trans_id, pending);
+        self.send(target, tlp);
+    }
+
+    /// The function whose memory window contains `addr`, if any.
+    fn route(&self, addr: u64) -> Option<u16> {
+        self.windows
+            .iter()
+            .find(|(window, _)| window.contains(&addr))
+            .map(|(_, bdf)| *bdf)
+    }
+
+    /// Whether the function at `bdf` is on a lane of the bridge and not being unplugged.
+    fn serves(&self, bdf: u16) -> bool {
+        self.downstream.contains_key(&bdf) && !self.detaching.contains_key(&bdf)
+    }
+
+    /// Where the bridge delivers `message`, see [`Destination::of`].
+    fn message_destination(
+        &self,
+        message: &MessageExtra,
+        upstream: bool,
+        local: u16,
+    ) -> Destination {
+        let functions = |bdf| self.serves(bdf);
+        let windows = |addr| self.route(addr);
+        let routes = Routes {
+            bridge: self.bdf,
+            functions: &functions,
+            windows: &windows,
+        };
+        Destination::of(message, upstream, local, &routes)
+    }
+
+    /// Route a message of the adapter of `local` to the functions.
+    fn send_message(&mut self, local: u16, mut tlp: Tlp) -> Result<()> {
+        let message = match &mut tlp.header._type {
+            PacketType::RoutedMessage(extra) | PacketType::RoutedMessageData(extra) => {
+                extra.requester = self.bdf;
+                *extra
+            }
+            _ => return Err(PciAdapterError::Unsupported),
+        };
+
+        match self.message_destination(&message, false, local) {
+            Destination::Function(target) => self.send(target, tlp),
+            Destination::All => {
+                let mut targets: Vec<u16> = self
+                    .downstream
+                    .keys()
+                    .filter(|bdf| !self.detaching.contains_key(bdf))
+                    .copied()
+                    .collect();
+                targets.sort_unstable();
+                for target in targets {
+                    self.send(target, tlp.clone());
+                }
+            }
+            Destination::Unroutable if message.routing == MessageRouting::Id => {
+                return Err(PciAdapterError::InvalidBdf(message.destination()))
+            }
+            Destination::Unroutable if message.routing == MessageRouting::Address => {
+                return Err(PciAdapterError::Completion(CPL_UR))
+            }
+            Destination::Unroutable | Destination::RootComplex => {
+                return Err(PciAdapterError::Unsupported)
+            }
+        }
+        Ok(())
+    }
+
+    fn link_state(&self, bdf: u16) -> LtssmState {
+        self.links
+            .get(&link_of(bdf))
+            .map_or(LtssmState::L0, Ltssm::state)
+    }
+
+    /// Wake the link of `bdf` out of L1 for a TLP. Returns whether the link carries TLPs.
+    fn wake_link(&mut self, bdf: u16) -> bool {
+        match self.links.get_mut(&link_of(bdf)) {
+            Some(ltssm) => ltssm.wake(),
+            None => true,
+        }
+    }
+
+    /// Drive the LTSSM of the link of `bdf`, a function of the bridge, by `transition`.
+    fn drive_link(
+        &mut self,
+        bdf: u16,
+        transition: impl FnOnce(&mut Ltssm) -> bool,
+    ) -> Result<bool> {
+        if !self.downstream.contains_key(&bdf) || self.detaching.contains_key(&bdf) {
+            return Err(PciAdapterError::InvalidBdf(bdf));
+        }
+        let link = link_of(bdf);
+        let ltssm = self.links.entry(link).or_insert_with(|| Ltssm::new(link));
+        Ok(transition(ltssm))
+    }
+
+    /// The speed and width the link of `bdf` trained at, if negotiated.
+    fn negotiated_link(&self, bdf: u16) -> Option<Link> {
+        self.links.get(&link_of(bdf)).and_then(Ltssm::negotiated)
+    }
+
+    /// Train the link of `bdf` at the fastest speed and widest width both the port and
+    /// `device` support, no faster than `target`. The meters of the link start over at the
+    /// negotiated rate.
+    fn negotiate_link(
+        &mut self,
+        bdf: u16,
+        device: Link,
+        target: Option<LinkSpeed>,
+    ) -> Result<Link> {
+        let mut link = self.link.map_or(device, |port| port.negotiate(&device));
+        if let Some(target) = target {
+            link.speed = link.speed.min(target);
+        }
+        link_up(self.drive_link(bdf, |ltssm| ltssm.negotiate(link))?)?;
+
+        let lane = link_of(bdf);
+        self.throttles.retain(|(_, l), _| *l != lane);
+        Ok(link)
+    }
+
+    /// Take the link of `bdf` down or bring it up again. The requests outstanding on the link
+    /// fail as it goes down, their completions are lost with it.
+    fn set_link_up(&mut self, bdf: u16, up: bool) -> Result<()> {
+        if !self.drive_link(bdf, |ltssm| ltssm.set_up(up))? {
+            return Ok(());
+        }
+
+        if up {
+            self.emit(AdapterEvent::LinkUp { bdf });
+            return Ok(());
+        }
+        let failed: Vec<u32> = self
+            .store
+            .iter()
+            .filter(|(_, p)| link_of(p.target) == link_of(bdf))
+            .map(|(id, _)| *id)
+            .collect();
+        for trans_id in failed {
+            let pending = self.retire(trans_id).unwrap();
+            pending.reaction.fail(PciAdapterError::LinkDown);
+        }
+        self.emit(AdapterEvent::LinkDown { bdf });
+        Ok(())
+    }
+
+    /// Latch the ERR_* message `code` of the function at `bdf`, and signal the error if the
+    /// guest enabled reporting it in Device Control. The function must be on a lane of the
+    /// bridge.
+    fn latch_error(&mut self, bdf: u16, code: u8) {
+        let severity = match ErrorSeverity::from_code(code) {
+            Some(severity) => severity,
+            None => return,
+        };
+        if !self.serves(bdf) {
+            error!(
+                "{:?} error of {:#x}, which is not a function of the bridge",
+                severity, bdf
+            );
+            self.stats.errors += 1;
+            return;
+        }
+        debug!("{:?} error of {:#x}", severity, bdf);
+        let devctl = self.error_reporting.get(&bdf).copied().unwrap_or(0);
+        if self.errors.entry(bdf).or_default().latch(severity, devctl) {
+            self.emit(AdapterEvent::Error { bdf, severity });
+        }
+    }
+
+    /// Publish the memory windows on the peer bus, if any.
+    fn publish_windows(&self) {
+        if let Some((bus, tx)) = self.peers.as_ref() {
+            bus.publish(tx, &self.windows);
+        }
+    }
+
+    /// Take a memory request of a device behind another bridge. A write goes to the function
+    /// as it is, a read is sent with a tag of this bridge and its completion goes back to
+    /// `peer` with the requester ID and the tag of the request.
+    fn peer_request(&mut self, mut tlp: Tlp, peer: Sender<AdapterMessage>) {
+        let (requester, tag, addr) = match tlp.header._type {
+            PacketType::MemoryRead(extra) | PacketType::MemoryWrite(extra) => {
+                (extra.requester, extra.tag, extra.addr as u64)
+            }
+            PacketType::MemoryRead64(extra) | PacketType::MemoryWrite64(extra) => {
+                (extra.requester, extra.tag, extra.addr)
+            }
+            _ => return,
+        };
+        let target = self.route(addr);
+
+        if !is_memory_read(&tlp) {
+            match target {
+                Some(target) => self.send(target, tlp),
+                None => debug!("Peer write to {:#x} outside of the windows", addr),
+            }
+            return;
+        }
+
+        let cpl = CompletionExtra {
+            requester,
+            completer: target.unwrap_or(self.bdf),
+            tag,
+            status: CPL_SC,
+            bcm: false,
+            byte_count: 0,
+            lower_address: 0,
+        };
+        let target = match target {
+            Some(target) => target,
+            None => {
+                Reaction::Peer(cpl, peer).fail(PciAdapterError::Completion(CPL_UR));
+                return;
+            }
+        };
+
+        let trans_id = self.next_transaction_id();
+        match &mut tlp.header._type {
+            PacketType::MemoryRead(extra) => {
+                extra.requester = self.bdf;
+                extra.tag = trans_id as u8;
+            }
+            PacketType::MemoryRead64(extra) => {
+                extra.requester = self.bdf;
+                extra.tag = trans_id as u8;
+            }
+            _ => unreachable!(),
+        }
+        self.submit(target, trans_id, Reaction::Peer(cpl, peer), tlp);
+    }
+
+    /// Put a TLP on the lane of a function, a failure means the simulated device is gone.
+    fn send(&mut self, target: u16, tlp: Tlp) {
+        let lane = link_of(target);
+        let delay = self.delay.delay_of(Direction::Downstream, &tlp)
+            + self.meter(Direction::Downstream, lane, &tlp);
+        if let Some((target, tlp)) = self.delayed_down.push(lane, delay, (target, tlp)) {
+            self.transmit(target, tlp);
+        }
+    }
+
+    /// Put a TLP on the lane of `target`, once it is no longer delayed.
+    fn transmit(&mut self, target: u16, tlp: Tlp) {
+        if self.panicked.contains(&target) {
+            debug!("TLP to panicked function {:#x} dropped", target);
+            return;
+        }
+        if !self.wake_link(target) {
+            debug!("TLP to {:#x} dropped, the link is down", target);
+            self.stats.dropped += 1;
+            return;
+        }
+
+        let (full, flow) = match self.downstream.get(&target) {
+            Some(tx) => (
+                tx.is_full(),
+                self.flows.iter().position(|f| f.lane.same_channel(tx)),
+            ),
+            None => {
+                error!(
+                    "TLP to missing function {:#x}: {:?}",
+                    target, tlp.header._type
+                );
+                return;
+            }
+        };
+
+        // The bridge is the only sender on the lane, the room it sees is there for the TLP.
+        let posted = FcClass::of(&tlp) == FcClass::Posted;
+        if full && self.backpressure == BackpressurePolicy::DropPosted && posted {
+            debug!(
+                "{:?} to {:#x} dropped on a full lane",
+                tlp.header._type, target
+            );
+            self.stats.dropped += 1;
+            return;
+        }
+
+        if let Some(flow) = flow.map(|i| &mut self.flows[i]) {
+            if !flow.admit(&tlp) {
+                flow.held.push_back(tlp);
+                return;
+            }
+        }
+
+        self.record_tlp(Direction::Downstream, &tlp);
+        let lane = self.downstream[&target].clone();
+        if lane.send(tlp).is_err() {
+            self.lane_lost(&lane);
+        }
+    }
+
+    /// Meter `tlp` on the link of the device `lane`, if the links are limited. A link runs at
+    /// the rate it negotiated, if it did.
+    fn meter(&mut self, direction: Direction, lane: u16, tlp: &Tlp) -> Duration {
+        let link = match self.link {
+            Some(link) => self.negotiated_link(lane).unwrap_or(link),
+            None => return Duration::ZERO,
+        };
+        self.throttles
+            .entry((direction, lane))
+            .or_insert_with(|| Throttle::new(link))
+            .delay_of(tlp)
+    }
+
+    /// Deliver the delayed TLPs which are due.
+    fn release_delayed(&mut self) {
+        let now = Instant::now();
+        while let Some((target, tlp)) = self.delayed_down.pop_due(now) {
+            self.transmit(target, tlp);
+        }
+        while let Some(tlp) = self.delayed_up.pop_due(now) {
+            self.take(tlp);
+        }
+    }
+
+    /// Send the held TLPs the models have the credits for again.
+    fn release_credits(&mut self) {
+        let mut lost = vec![];
+        for i in 0..self.flows.len() {
+            while let Some(tlp) = self.flows[i].release() {
+                self.record_tlp(Direction::Downstream, &tlp);
+                if self.flows[i].lane.send(tlp).is_err() {
+                    lost.push(self.flows[i].lane.clone());
+                    break;
+                }
+            }
+        }
+
+        for lane in lost {
+            self.lane_lost(&lane);
+        }
+    }
+
+    /// Forget the flow control of the lanes no function is on any more.
+    fn prune_flows(&mut self) {
+        let downstream = &self.downstream;
+        self.flows
+            .retain(|f| downstream.values().any(|tx| tx.same_channel(&f.lane)));
+    }
+
+    /// The model on `lane` is gone. If other models still run, the functions on the lane leave
+    /// the routing and their outstanding requests fail as Unsupported Requests, the rest of the
+    /// hierarchy is not affected. If it was the only lane, the whole bridge is disconnected.
+    fn lane_lost(&mut self, lane: &Sender<Tlp>) {
+        let functions: Vec<u16> = self
+            .downstream
+            .iter()
+            .filter(|(_, tx)| tx.same_channel(lane))
+            .map(|(function, _)| *function)
+            .collect();
+        if functions.is_empty() {
+            return;
+        }
+        if functions.len() == self.downstream.len() {
+            self.disconnect();
+            return;
+        }
+
+        error!(
+            "Device model of {:x?} left its lane, dropping its functions",
+            functions
+        );
+        self.release_intx(&functions);
+        for function in functions.iter() {
+            self.downstream.remove(function);
+            self.msix.remove(function);
+            self.command.remove(function);
+        }
+        self.prune_flows();
+
+        let failed: Vec<u32> = self
+            .store
+            .iter()
+            .filter(|(_, p)| functions.contains(&p.target))
+            .map(|(id, _)| *id)
+            .collect();
+        for trans_id in failed {
+            let pending = self.retire(trans_id).unwrap();
+            pending.reaction.fail(PciAdapterError::Completion(CPL_UR));
+        }
+    }
+
+    /// The simulated device has left the lane, which is replaced with a dead one until
+    /// another lane is attached. Pending transactions are handled by the disconnect policy.
+    fn disconnect(&mut self) {
+        // A panicking model sends its exit notice before its lane goes down.
+        while let Ok(bdf) = self.exits.try_recv() {
+            self.device_exited(bdf);
+        }
+
+        if self.connected {
+            if !self.store.is_empty() {
+                error!("Simulated device disconnected with pending transactions");
+            }
+            self.record_event(EventKind::Disconnected, 0);
+            self.emit(AdapterEvent::LaneDown {
+                pending: self.store.len(),
+            });
+
+            // The translations cached by the device are gone with it.
+            for waiter in self.invalidations.drain(..) {
+                let _ = waiter.send(Err(PciAdapterError::Disconnected));
+            }
+
+            self.connected = false;
+            self.upstream_tx = None;
+            let (tx, _) = unbounded();
+            for lane in self.downstream.values_mut() {
+                *lane = tx.clone();
+            }
+            self.prune_flows();
+            // The TLPs still on their way down are lost with the lane.
+            self.delayed_down.clear();
+            self.upstream = never();
+
+            if let DisconnectPolicy::Resync { window } = self.disconnect_policy {
+                let deadline = Instant::now() + window;
+                for pending in self.store.values_mut() {
+                    pending.deadline = Some(pending.deadline.map_or(deadline, |d| d.max(deadline)));
+                }
+            }
+        }
+
+        if self.disconnect_policy == DisconnectPolicy::FailFast && !self.store.is_empty() {
+            self.emit(AdapterEvent::Aborted {
+                count: self.store.len(),
+            });
+            for (trans_id, pending) in self.store.drain() {
+                self.tags.free(trans_id as u8);
+                pending.reaction.fail(PciAdapterError::Disconnected);
+            }
+        }
+    }
+
+    /// Settle the features used with the attached device and size the tag pool accordingly.
+    fn negotiate(&mut self, device: DeviceFeatures) {
+        self.features = BRIDGE_FEATURES.intersect(&device);
+        debug!("Negotiated device features: {:?}", self.features);
+
+        self.tags = TagPool::new(self.features.tags().min(DEFAULT_TAGS));
+        for trans_id in self.store.keys() {
+            self.tags.claim(*trans_id as u8);
+        }
+    }
+
+    /// Attach a new lane and replay the transactions kept by [`DisconnectPolicy::Resync`]. The
+    /// lane serves every function of the hierarchy.
+    fn reconnect(&mut self, lane: PciLane, features: DeviceFeatures) {
+        self.negotiate(features);
+        for tx in self.downstream.values_mut() {
+            *tx = lane.tx.clone();
+        }
+        self.upstream = lane.rx;
+        self.connected = true;
+        self.record_event(EventKind::Reconnected, self.store.len() as u32);
+
+        let now = Instant::now();
+        let deadlines: HashMap<u32, Option<Instant>> = self
+            .store
+            .iter()
+            .map(|(id, pending)| (*id, self.deadline_of(pending.target, now)))
+            .collect();
+        let mut replay: Vec<(u32, u16, Tlp)> = self
+            .store
+            .iter_mut()
+            .map(|(id, pending)| {
+                pending.deadline = deadlines[id];
+                pending.retry_at = None;
+                (*id, pending.target, pending.tlp.clone())
+            })
+            .collect();
+        replay.sort_by_key(|(id, _, _)| *id);
+
+        self.emit(AdapterEvent::LaneUp {
+            replayed: replay.len(),
+        });
+        for (_, target, tlp) in replay {
+            self.send(target, tlp);
+        }
+    }
+
+    /// Plug a device model in at `bdf`. It gets a lane of its own like the models started with
+    /// the bridge and must support every feature already negotiated, as the tags are shared.
+    fn attach(&mut self, bdf: u16, device: Box<dyn PciSimDevice + Send + Sync>) -> Result<()> {
+        let upstream = match self.upstream_tx.as_ref() {
+            Some(tx) if self.connected => tx.clone(),
+            _ => return Err(PciAdapterError::Disconnected),
+        };
+        if self.downstream.contains_key(&bdf) {
+            return Err(PciAdapterError::InvalidBdf(bdf));
+        }
+        if device.features().intersect(&self.features) != self.features {
+            return Err(PciAdapterError::Unsupported);
+        }
+
+        let (tx, rx) = self.builder.lane();
+        if let Some(credits) = device.flow_control() {
+            self.flows.push(LaneFlow::new(tx.clone(), credits));
+        }
+        let lane = PciLane { tx: upstream, rx };
+        let info = self.builder.lane_info(vec![bdf], self.features);
+        let model = self
+            .builder
+            .spawn_device(info, device, lane, &self.exits_tx);
+        self.handles.push((bdf, model.handle));
+        self.controls.insert(bdf, model.controls);
+        self.downstream.insert(bdf, tx);
+        self.panicked.remove(&bdf);
+        self.links.remove(&link_of(bdf));
+        self.errors.remove(&bdf);
+        self.error_reporting.remove(&bdf);
+        self.payload_sizes.remove(&bdf);
+        self.completion_timeouts.remove(&bdf);
+        self.emit(AdapterEvent::Attached { bdf });
+        Ok(())
+    }
+
+    /// Route `functions` to the lane of the function at `bdf`, whose model serves them too.
+    fn add_functions(&mut self, bdf: u16, functions: Vec<u16>) -> Result<()> {
+        let tx = match self.downstream.get(&bdf) {
+            Some(tx) if !self.detaching.contains_key(&bdf) => tx.clone(),
+            _ => return Err(PciAdapterError::InvalidBdf(bdf)),
+        };
+        if let Some(&taken) = functions
+            .iter()
+            .find(|function| self.downstream.contains_key(function))
+        {
+            return Err(PciAdapterError::InvalidBdf(taken));
+        }
+
+        for function in functions {
+            self.downstream.insert(function, tx.clone());
+            self.emit(AdapterEvent::Attached { bdf: function });
+        }
+        Ok(())
+    }
+
+    /// Start to unplug the function at `bdf`: it takes no more requests and is gone once its
+    /// outstanding transactions complete or time out, see [`PciSimBridge::finish_detach`].
+    fn detach(&mut self, bdf: u16, sender: Sender<Result<()>>) {
+        if !self.downstream.contains_key(&bdf) || self.detaching.contains_key(&bdf) {
+            let _ = sender.send(Err(PciAdapterError::InvalidBdf(bdf)));
+            return;
+        }
+
+        self.windows.retain(|(_, target)| *target != bdf);
+        self.publish_windows();
+        self.detaching.insert(bdf, sender);
+    }
+
+    /// Tear down the lanes of the functions being unplugged which have no outstanding
+    /// transactions left. A model ends once the lanes of all its functions are closed.
+    fn finish_detach(&mut self) {
+        if self.detaching.is_empty() {
+            return;
+        }
+
+        let done: Vec<u16> = self
+            .detaching
+            .keys()
+            .filter(|&&bdf| !self.store.values().any(|p| p.target == bdf))
+            .copied()
+            .collect();
+        for bdf in done {
+            let sender = self.detaching.remove(&bdf).unwrap();
+            self.release_intx(&[bdf]);
+            self.downstream.remove(&bdf);
+            self.prune_flows();
+            self.msix.remove(&bdf);
+            self.command.remove(&bdf);
+            self.panicked.remove(&bdf);
+            self.emit(AdapterEvent::Detached { bdf });
+            let _ = sender.send(Ok(()));
+        }
+    }
+
+    /// Reset the function at `bdf`, or every function of its model for a hot reset.
+    fn reset(&mut self, bdf: u16, kind: ResetKind) -> Result<()> {
+        self.hand_over(bdf, |lane| ModelControl::Reset(kind, lane))?;
+        debug!("Device model of {:#x} reset: {:?}", bdf, kind);
+
+        // Device Control and Device Control 2 of the functions which reset are back to their
+        // defaults, and their INTx wires are deasserted.
+        let lane = &self.downstream[&bdf];
+        let functions: Vec<u16> = match kind {
+            ResetKind::FunctionLevel(target) => vec![target],
+            ResetKind::Hot => self
+                .downstream
+                .iter()
+                .filter(|(_, tx)| tx.same_channel(lane))
+                .map(|(function, _)| *function)
+                .collect(),
+        };
+        for &function in &functions {
+            if let Some(sizes) = self.payload_sizes.get_mut(&function) {
+                *sizes = DEFAULT_PAYLOAD_SIZES;
+            }
+            self.completion_timeouts.remove(&function);
+        }
+        self.release_intx(&functions);
+        Ok(())
+    }
+
+    /// The completion timeout of the requests to `target`, none if the function disabled it.
+    fn completion_timeout_of(&self, target: u16) -> Option<Duration> {
+        match self.completion_timeouts.get(&target) {
+            Some(CompletionTimeout::Range(timeout)) => Some(*timeout),
+            Some(CompletionTimeout::Disabled) => None,
+            Some(CompletionTimeout::Default) | None => Some(self.completion_timeout),
+        }
+    }
+
+    /// When a request sent to `target` at `from` times out.
+    fn deadline_of(&self, target: u16, from: Instant) -> Option<Instant> {
+        self.completion_timeout_of(target)
+            .map(|timeout| from + timeout)
+    }
+
+    /// The largest memory read request the bridge sends to `target`: no larger than the
+    /// function's own Max Read Request Size, and no larger than its Max Payload Size for the
+    /// function to complete it with a single TLP.
+    fn max_read_request_of(&self, target: u16) -> usize {
+        match self.payload_sizes.get(&target) {
+            Some(&(mps, mrrs)) => self.max_read_request.min(mps).min(mrrs),
+            None => self.max_read_request,
+        }
+    }
+
+    /// The largest payload of the memory writes the bridge sends to `target`, its Max Payload
+    /// Size. Functions without a PCI Express capability take any payload a TLP carries.
+    fn mps_of(&self, target: u16) -> usize {
+        self.payload_sizes
+            .get(&target)
+            .map_or(MAX_PAYLOAD, |&(mps, _)| mps)
+    }
+
+    /// Hand the model of the function at `bdf` what `control` makes of a new lane, then close
+    /// the old lane: the model takes the TLPs already on it, and the TLPs the bridge did not
+    /// put on it yet go on the new one.
+    fn hand_over<F>(&mut self, bdf: u16, control: F) -> Result<()>
+    where
+        F: FnOnce(PciLane) -> ModelControl,
+    {
+        let upstream = match self.upstream_tx.as_ref() {
+            Some(tx) if self.connected => tx.clone(),
+            _ => return Err(PciAdapterError::Disconnected),
+        };
+        let old = match self.downstream.get(&bdf) {
+            Some(tx) if !self.detaching.contains_key(&bdf) => tx.clone(),
+            _ => return Err(PciAdapterError::InvalidBdf(bdf)),
+        };
+        if self.panicked.contains(&bdf) {
+            return Err(PciAdapterError::DevicePanicked);
+        }
+
+        // The functions of a model share its lane.
+        let functions: Vec<u16> = self
+            .downstream
+            .iter()
+            .filter(|(_, tx)| tx.same_channel(&old))
+            .map(|(function, _)| *function)
+            .collect();
+        let controls = functions
+            .iter()
+            .find_map(|function| self.controls.get(function))
+            .ok_or(PciAdapterError::InvalidBdf(bdf))?;
+
+        let (tx, rx) = self.builder.lane();
+        controls
+            .send(control(PciLane { tx: upstream, rx }))
+            .map_err(|_| PciAdapterError::Disconnected)?;
+        for function in functions {
+            self.downstream.insert(function, tx.clone());
+        }
+        if let Some(flow) = self.flows.iter_mut().find(|f| f.lane.same_channel(&old)) {
+            flow.reset(tx);
+        }
+        Ok(())
+    }
+
+    /// Reset every model behind the bridge like a hot reset of its link, and put what the
+    /// bridge keeps of their functions back to its state after reset: the MSI-X tables, the
+    /// command registers and the INTx pins they assert.
+    fn bus_reset(&mut self) -> Result<()> {
+        // One function of each model, the model resets all of its functions.
+        let mut models: Vec<(u16, Sender<Tlp>)> = vec![];
+        for (&bdf, tx) in self.downstream.iter() {
+            if !models.iter().any(|(_, other)| other.same_channel(tx)) {
+                models.push((bdf, tx.clone()));
+            }
+        }
+        for (bdf, _) in models {
+            if self.panicked.contains(&bdf) || self.detaching.contains_key(&bdf) {
+                continue;
+            }
+            self.reset(bdf, ResetKind::Hot)?;
+        }
+
+        let functions: Vec<u16> = self.msix.keys().copied().collect();
+        for target in functions {
+            self.change_msix(target, |table| {
+                table.reset();
+                vec![]
+            });
+        }
+        self.command.clear();
+        let asserting: Vec<u16> = self.intx.iter().map(|&(bdf, _)| bdf).collect();
+        self.release_intx(&asserting);
+        Ok(())
+    }
+
+    /// Bring the state saved by [`PciAdapter::snapshot`] back. The outstanding requests are
+    /// sent again with new tags, nobody waits for their completions any more.
+    fn restore_function(&mut self, target: u16, msix: Option<MsixSnapshot>, pending: Vec<Tlp>) {
+        match msix {
+            Some(msix) => {
+                let table = MsixTable::restore(&msix);
+                self.msix
+                    .entry(target)
+                    .or_insert_with(|| MsixTable::new(msix.entries.len(), 0));
+                self.change_msix(target, |current| {
+                    *current = table;
+                    vec![]
+                });
+            }
+            None => {
+                self.change_msix(target, |table| {
+                    table.reset();
+                    vec![]
+                });
+                self.msix.remove(&target);
+            }
+        }
+
+        for mut tlp in pending {
+            let tag = match self.tags.alloc() {
+                Some(tag) => tag,
+                None => {
+                    error!("No tag left to restore {:?}", tlp.header._type);
+                    continue;
+                }
+            };
+            tlp.header.set_tag(tag);
+            let trans_id = tag as u32 | ((self.bdf as u32) << 16);
+            let (sender, _) = bounded(1);
+            self.submit(target, trans_id, Reaction::Notify(sender), tlp);
+        }
+    }
+
+    /// Drop all transactions whose completion timeout expires and wake up their waiters.
+    /// Transactions kept across a disconnect fail as disconnected when the lane does not
+    /// come back in time.
+    fn expire_transactions(&mut self) {
+        let (kind, err) = if self.connected {
+            (EventKind::Timeout, PciAdapterError::Timeout)
+        } else {
+            (EventKind::Disconnected, PciAdapterError::Disconnected)
+        };
+        let now = Instant::now();
+        let expired: Vec<u32> = self
+            .store
+            .iter()
+            .filter(|(_, p)| p.deadline.is_some_and(|d| d <= now))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for trans_id in expired {
+            let pending = self.retire(trans_id).unwrap();
+            error!(
+                "Completion timeout of transaction {:#x}: {:?}",
+                trans_id, pending.reaction
+            );
+            self.record_event(kind, trans_id);
+            pending.reaction.fail(err);
+        }
+    }
+
+    /// Handle a config request completed with CRS by either scheduling a retry or completing
+    /// it under software visibility. Returns false if the request should fail instead.
+    fn retry_config(&mut self, trans_id: u32) -> bool {
+        let policy = self.crs_policy;
+        let timeout = match self.store.get(&trans_id) {
+            Some(pending) => self.completion_timeout_of(pending.target),
+            None => return false,
+        };
+
+        let pending = match self.store.get_mut(&trans_id) {
+            Some(pending) => pending,
+            None => return false,
+        };
+        let vendor_id = match pending.tlp.header._type {
+            PacketType::Config0Read(extra) => extra.reg == 0,
+            PacketType::Config0Write(_) => false,
+            _ => return false,
+        };
+
+        if vendor_id && policy.software_visibility {
+            if let Some(Pending {
+                reaction: Reaction::ReadConfig(sender),
+                ..
+            }) = self.retire(trans_id)
+            {
+                let _ = sender.send(Ok(CRS_VENDOR_ID));
+            }
+            return true;
+        }
+
+        if pending.retries >= policy.max_retries {
+            return false;
+        }
+
+        let backoff = policy
+            .backoff
+            .checked_mul(1 << pending.retries.min(16))
+            .unwrap_or(policy.max_backoff)
+            .min(policy.max_backoff);
+        let retry_at = Instant::now() + backoff;
+        pending.retries += 1;
+        pending.retry_at = Some(retry_at);
+        pending.deadline = timeout.map(|timeout| retry_at + timeout);
+        debug!(
+            "Configuration request retry of transaction {:#x} in {:?}",
+            trans_id, backoff
+        );
+        true
+    }
+
+    /// Send the requests whose CRS back-off is over again.
+    fn retry_transactions(&mut self) {
+        let now = Instant::now();
+        let due: Vec<u32> = self
+            .store
+            .iter()
+            .filter(|(_, p)| p.retry_at.is_some_and(|t| t <= now))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for trans_id in due {
+            let deadline = self.deadline_of(self.store[&trans_id].target, now);
+            let pending = self.store.get_mut(&trans_id).unwrap();
+            pending.retry_at = None;
+            pending.deadline = deadline;
+            let (target, tlp) = (pending.target, pending.tlp.clone());
+            self.send(target, tlp);
+        }
+    }
+
+    /// Allocate the transaction ID of a new non-posted request. The dispatcher makes sure a
+    /// free tag exists.
+    fn next_transaction_id(&mut self) -> u32 {
+        let tag = self.tags.alloc().expect("no free tag");
+        tag as u32 | ((self.bdf as u32) << 16)
+    }
+
+    fn handle_adapter_msg(&mut self, msg: AdapterMessage) {
+        use AdapterMessage::*;
+        match msg {
+            ConfigRead(target, idx, sender) => {
+                let trans_id = self.next_transaction_id();
+                let tlp = TlpBuilder::config0_read(ConfigExtra {
+                    requester: self.bdf,
+                    completer: target,
+                    tag: (trans_id & 0xff) as u8,
+                    reg: idx as u16,
+                })
+                .build();
+
+                self.submit(target, trans_id, Reaction::ReadConfig(sender), tlp);
+            }
+            ConfigWrite(data, sender) => {
+                self.snoop_command(&data);
+                let trans_id = self.next_transaction_id();
+                let byte_enable = (!(u8::MAX << data.len)) << data.offset;
+                let value = data.data << (data.offset * 8);
+
+                let tlp = TlpBuilder::config0_write(ConfigExtra {
+                    requester: self.bdf,
+                    completer: data.target,
+                    tag: (trans_id & 0xff) as u8,
+                    reg: data.reg_idx as u16,
+                })
+                .byte_enable(byte_enable)
+                .data(vec![value])
+                .build();
+
+                self.submit(data.target, trans_id, Reaction::Notify(sender), tlp);
+            }
+            MemoryRead(target, addr, len, sender) => {
+                let target = self.route(addr).unwrap_or(target);
+                if !self.decodes(target, COMMAND_MEMORY_SPACE) {
+                    let _ = sender.send(Err(PciAdapterError::Completion(CPL_UR)));
+                    return;
+                }
+
+                let parts = split_request(addr, len, self.max_read_request_of(target));
+                if parts.len() <= 1 {
+                    self.read_memory(target, addr, len, Reaction::ReadMemory(len, sender));
+                    return;
+                }
+
+                // The parts go first, each one waits for a tag of its own.
+                let group = Arc::new(Mutex::new(ReadGroup::new(parts.len(), sender)));
+                for (index, (addr, len)) in parts.into_iter().enumerate().rev() {
+                    self.backlog.push_front(MemoryReadPart(
+                        target,
+                        addr,
+                        len,
+                        group.clone(),
+                        index,
+                    ));
+                }
+            }
+            MemoryReadPart(target, addr, len, group, index) => {
+                if !group.lock().unwrap().is_done() {
+                    self.read_memory(target, addr, len, Reaction::ReadPart(len, group, index));
+                }
+            }
+            IoRead(target, addr, len, sender) => {
+                if !self.decodes(target, COMMAND_IO_SPACE) {
+                    let _ = sender.send(Err(PciAdapterError::Completion(CPL_UR)));
+                    return;
+                }
+                let trans_id = self.next_transaction_id();
+                let offset = (addr & 0b11) as usize;
+
+                let tlp = TlpBuilder::io_read(MemoryExtra {
+                    requester: self.bdf,
+                    tag: (trans_id & 0xff) as u8,
+                    addr: addr & !0b11,
+                })
+                .byte_enable(write_byte_enable(offset, len))
+                .build();
+
+                self.submit(target, trans_id, Reaction::ReadIo(offset, len, sender), tlp);
+            }
+            IoWrite(target, addr, data, sender) => {
+                if !self.decodes(target, COMMAND_IO_SPACE) {
+                    let _ = sender.send(Err(PciAdapterError::Completion(CPL_UR)));
+                    return;
+                }
+                let trans_id = self.next_transaction_id();
+                let offset = (addr & 0b11) as usize;
+                let mut bytes = [0u8; 4];
+                bytes[offset..offset + data.len()].copy_from_slice(&data);
+
+                let tlp = TlpBuilder::io_write(MemoryExtra {
+                    requester: self.bdf,
+                    tag: (trans_id & 0xff) as u8,
+                    addr: addr & !0b11,
+                })
+                .byte_enable(write_byte_enable(offset, data.len()))
+                .data(vec![u32::from_be_bytes(bytes)])
+                .build();
+
+                self.submit(target, trans_id, Reaction::Notify(sender), tlp);
+            }
+            MemoryWrite(target, addr, data) => {
+                let target = self.route(addr).unwrap_or(target);
+                if !self.decodes(target, COMMAND_MEMORY_SPACE) {
+                    return;
+                }
+
+                // No write carries more than the Max Payload Size of the function.
+                for (start, len) in split_request(addr, data.len(), self.mps_of(target)) {
+                    let part = &data[(start - addr) as usize..][..len];
+                    let offset = (start & 0b11) as usize;
+                    let size = (offset + len + 3) >> 2; // in DW
+                    let mut bytes = vec![0u8; size * 4];
+                    bytes[offset..offset + len].copy_from_slice(part);
+                    let dw = bytes
+                        .chunks(4)
+                        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect();
+
+                    let builder = if start >> 32 == 0 {
+                        TlpBuilder::memory_write(MemoryExtra {
+                            requester: self.bdf,
+                            tag: 0,
+                            addr: start as u32 & !0b11,
+                        })
+                    } else {
+                        TlpBuilder::memory_write64(Memory64Extra {
+                            requester: self.bdf,
+                            tag: 0,
+                            addr: start & !0b11,
+                        })
+                    };
+                    let tlp = builder
+                        .byte_enable(write_byte_enable(offset, len))
+                        .data(dw)
+                        .build();
+
+                    self.send(target, tlp);
+                }
+            }
+            Kick(target, kicks, index) => {
+                if self.decodes(target, COMMAND_MEMORY_SPACE) {
+                    let _ = kicks.send(index);
+                }
+            }
+            SetCompletionTimeout(timeout) => self.completion_timeout = timeout,
+            SetEventLog(log) => {
+                self.event_log = Some(log);
+                self.record_event(EventKind::Started, 0);
+            }
+            SetMsiSink(sink) => self.msi_sink = Some(sink),
+            SetPtmTimeSource(source) => self.ptm_time = source,
+            GetIntxLine(pin, sender) => {
+                let _ = sender.send(Ok(self.intx_lines[pin as usize - 1]));
+            }
+            SetIntxLine(pin, gsi) => self.intx_lines[pin as usize - 1] = Some(gsi),
+            SetDmaMemory(memory) => self.dma_memory = Some(memory),
+            SetDmaTranslator(translator) => self.dma_translator = Some(translator),
+            SetTranslationAgent(agent) => self.translation_agent = Some(agent),
+            SetPageRequestHandler(handler) => self.page_handler = Some(handler),
+            SetMessageHandler(handler) => self.message_handler = Some(handler),
+            SendMessage(local, tlp, sender) => {
+                let _ = sender.send(self.send_message(local, tlp));
+            }
+            AddTlpObserver(observer) => self.observers.push(observer),
+            AtsInvalidate(target, addr, size, sender) => {
+                if !self.features.ats {
+                    let _ = sender.send(Err(PciAdapterError::Unsupported));
+                    return;
+                }
+
+                let tlp = TlpBuilder::with_type(PacketType::MessageData(ATS_INVALIDATE_REQUEST))
+                    .data(encode_range(addr, size).to_vec())
+                    .build();
+                self.send(target, tlp);
+
+                if self.connected {
+                    self.invalidations.push_back(sender);
+                } else {
+                    let _ = sender.send(Err(PciAdapterError::Disconnected));
+                }
+            }
+            NotifyIommu(target, event, sender) => {
+                if !self.features.ats {
+                    let _ = sender.send(Err(PciAdapterError::Unsupported));
+                } else if !self.connected {
+                    let _ = sender.send(Err(PciAdapterError::Disconnected));
+                } else {
+                    self.send(target, event.to_message());
+                    let _ = sender.send(Ok(()));
+                }
+            }
+            SetMsixTable(target, size, control) => {
+                self.msix.insert(target, MsixTable::new(size, 0));
+                self.change_msix(target, |table| table.set_control(control));
+            }
+            SetMsixControl(target, control) => {
+                self.change_msix(target, |table| table.set_control(control));
+            }
+            SetPayloadSizes(target, mps, mrrs) => {
+                self.payload_sizes.insert(target, (mps, mrrs));
+            }
+            SetFunctionTimeout(target, timeout) => {
+                self.completion_timeouts.insert(target, timeout);
+            }
+            GetErrorLog(target, sender) => {
+                let log = if self.downstream.contains_key(&target) {
+                    Ok(self.errors.get(&target).copied().unwrap_or_default())
+                } else {
+                    Err(PciAdapterError::InvalidBdf(target))
+                };
+                let _ = sender.send(log);
+            }
+            SetAcs(target, port) => {
+                self.acs.insert(link_of(target), port);
+            }
+            SetErrorReporting(target, control) => {
+                self.error_reporting.insert(target, control);
+            }
+            ClearErrors(target, bits) => {
+                if let Some(log) = self.errors.get_mut(&target) {
+                    log.clear(bits);
+                }
+            }
+            MsixRead(target, structure, len, sender) => {
+                if !self.decodes(target, COMMAND_MEMORY_SPACE) {
+                    let _ = sender.send(Err(PciAdapterError::Completion(CPL_UR)));
+                    return;
+                }
+                let mut data = vec![0; len];
+                if let Some(table) = self.msix.get(&target) {
+                    table.read(structure, &mut data);
+                }
+                let _ = sender.send(Ok(data));
+            }
+            MsixWrite(target, structure, data) => {
+                if !self.decodes(target, COMMAND_MEMORY_SPACE) {
+                    return;
+                }
+                self.change_msix(target, |table| table.write(structure, &data));
+            }
+            RouteMemory(target, windows) => {
+                self.windows.retain(|(_, bdf)| *bdf != target);
+                self.windows
+                    .extend(windows.into_iter().map(|window| (window, target)));
+                self.publish_windows();
+            }
+            JoinPeerBus(bus, tx) => {
+                if let Some((old, tx)) = self.peers.take() {
+                    old.publish(&tx, &[]);
+                }
+                self.peers = Some((bus, tx));
+                self.publish_windows();
+            }
+            PeerRequest(tlp, peer) => self.peer_request(tlp, peer),
+            PeerCompletion(tlp) => match tlp.header._type {
+                PacketType::Completion(extra) | PacketType::CompletionData(extra) => {
+                    self.send(extra.requester, tlp)
+                }
+                _ => error!("Peer completion is no completion: {:?}", tlp.header._type),
+            },
+            SetDisconnectPolicy(policy) => self.disconnect_policy = policy,
+            SetCrsPolicy(policy) => self.crs_policy = policy,
+            Reconnect(lane, features) => self.reconnect(lane, features),
+            GetFeatures(sender) => {
+                let _ = sender.send(Ok(self.features));
+            }
+            GetStats(sender) => {
+                let stats = AdapterStats {
+                    outstanding: self.store.len(),
+                    backlog: self.backlog.len(),
+                    ..self.stats
+                };
+                let _ = sender.send(Ok(stats));
+            }
+            Attach(bdf, device, sender) => {
+                let _ = sender.send(self.attach(bdf, device));
+            }
+            GetLinkState(bdf, sender) => {
+                let state = self
+                    .drive_link(bdf, |_| true)
+                    .map(|_| (self.link_state(bdf), self.negotiated_link(bdf)));
+                let _ = sender.send(state);
+            }
+            SetLinkUp(bdf, up, sender) => {
+                let _ = sender.send(self.set_link_up(bdf, up));
+            }
+            RetrainLink(bdf, sender) => {
+                let retrained = self.drive_link(bdf, Ltssm::recover);
+                let _ = sender.send(retrained.and_then(link_up));
+            }
+            EnterL1(bdf, sender) => {
+                let parked = self.drive_link(bdf, Ltssm::enter_l1);
+                let _ = sender.send(parked.and_then(link_up));
+            }
+            NegotiateLink(bdf, device, target, sender) => {
+                let _ = sender.send(self.negotiate_link(bdf, device, target));
+            }
+            AddFunctions(bdf, functions, sender) => {
+                let _ = sender.send(self.add_functions(bdf, functions));
+            }
+            Detach(bdf, sender) => self.detach(bdf, sender),
+            Reset(bdf, kind, sender) => {
+                let _ = sender.send(self.reset(bdf, kind));
+            }
+            BusReset(sender) => {
+                let _ = sender.send(self.bus_reset());
+            }
+            SaveFunction(target, sender) => {
+                let msix = self.msix.get(&target).map(|table| table.save());
+                let mut pending: Vec<(&u32, &Pending)> = self
+                    .store
+                    .iter()
+                    .filter(|(_, p)| p.target == target)
+                    .collect();
+                pending.sort_by_key(|(id, _)| **id);
+                let pending = pending.into_iter().map(|(_, p)| p.tlp.clone()).collect();
+                let _ = sender.send(Ok((msix, pending)));
+            }
+            RestoreFunction(target, msix, pending) => self.restore_function(target, msix, pending),
+            SaveModel(target, sender) => {
+                let reply = sender.clone();
+                if let Err(e) =
+                    self.hand_over(target, |lane| ModelControl::Save(target, lane, reply))
+                {
+                    let _ = sender.send(Err(e));
+                }
+            }
+            RestoreModel(target, state, sender) => {
+                let reply = sender.clone();
+                let control = |lane| ModelControl::Restore(target, state, lane, reply);
+                if let Err(e) = self.hand_over(target, control) {
+                    let _ = sender.send(Err(e));
+                }
+            }
+            Health(target, sender) => {
+                let health = if self.panicked.contains(&target) {
+                    Err(PciAdapterError::DevicePanicked)
+                } else if !self.connected {
+                    Err(PciAdapterError::Disconnected)
+                } else {
+                    Ok(())
+                };
+                let _ = sender.send(health);
+            }
+            Exit => unreachable!("handled by run"),
+        }
+    }
+
+    /// Translate the IOVA of a DMA by the device, faults are logged. Without a translator,
+    /// IOVAs are guest physical addresses.
+    fn translate_dma(
+        &mut self,
+        requester: u16,
+        iova: u64,
+        len: u64,
+        access: DmaAccess,
+        at: AddressType,
+    ) -> Option<u64> {
+        if self.dma_memory.is_none() {
+            error!("DMA {:?} at {:#x} without guest memory", access, iova);
+            return None;
+        }
+
+        // Addresses translated by ATS are guest physical addresses already.
+        let translator = match self.dma_translator.as_ref() {
+            Some(translator) if at != AddressType::Translated => translator,
+            _ => return Some(iova),
+        };
+
+        match translator.translate(requester, iova, len, access) {
+            Ok(gpa) => Some(gpa),
+            Err(fault) => {
+                error!(
+                    "DMA {:?} of {} bytes at {:#x} by {:#x} faulted: {}",
+                    access, len, iova, requester, fault
+                );
+                if let Some(log) = self.event_log.as_mut() {
+                    log.append(EventKind::DmaFault, requester as u32, &iova.to_le_bytes());
+                }
+                None
+            }
+        }
+    }
+
+    /// Service a memory read issued by the device, which is completed with the data of guest
+    /// memory or with UR if the access faults.
+    fn upstream_read(&mut self, requester: u16, tag: u8, addr: u64, msg: Tlp) {
+        let length = match msg.header.length {
+            0 => 1024,
+            len => len as usize,
+        };
+        let enabled = enabled_bytes(length, msg.header.byte_enable);
+        let first = enabled.iter().position(|&b| b).unwrap_or(0);
+        let last = enabled.iter().rposition(|&b| b).map_or(0, |i| i + 1);
+        let addr = addr & !0b11;
+
+        let mut bytes = vec![0u8; length * 4];
+        let at = msg.header.address_type;
+        let status =
+            match self.translate_dma(requester, addr, bytes.len() as u64, DmaAccess::Read, at) {
+                Some(gpa) => match self.dma_memory.as_ref().unwrap().read(gpa, &mut bytes) {
+                    Ok(()) => CPL_SC,
+                    Err(e) => {
+                        error!("Failed to read guest memory at {:#x}: {}", gpa, e);
+                        CPL_UR
+                    }
+                },
+                None => CPL_UR,
+            };
+
+        let cpl = CompletionExtra {
+            requester,
+            completer: self.bdf,
+            tag,
+            status,
+            bcm: false,
+            // A byte count of 0 stands for 4096 bytes.
+            byte_count: ((last - first) & 0xfff) as u16,
+            lower_address: ((addr as usize + first) & 0x7f) as u8,
+        };
+        let data = bytes
+            .chunks(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        self.complete(cpl, data);
+    }
+
+    /// Queue a page request of the device. Once the group is complete it is handed to the page
+    /// request handler, whose answer is sent back to the device.
+    fn page_request(&mut self, extra: PageRequestExtra) {
+        let key = (extra.requester, extra.prg_index);
+        self.page_requests.entry(key).or_default().push(extra);
+        if !extra.last {
+            return;
+        }
+
+        let pages = self.page_requests.remove(&key).unwrap_or_default();
+        let response = match self.page_handler.as_ref() {
+            Some(handler) => handler.page_request(extra.requester, &pages),
+            None => {
+                error!("Page request of {:#x} without handler", extra.requester);
+                PRG_RESPONSE_FAILURE
+            }
+        };
+
+        let tlp = TlpBuilder::prg_response(PrgResponseExtra {
+            requester: self.bdf,
+            destination: extra.requester,
+            prg_index: extra.prg_index,
+            response,
+        })
+        .build();
+        self.send(extra.requester, tlp);
+    }
+
+    /// Dispatch a memory read of the device by its address type.
+    fn memory_request(&mut self, requester: u16, tag: u8, addr: u64, msg: Tlp) {
+        match msg.header.address_type {
+            AddressType::TranslationRequest => self.translation_request(requester, tag, addr, msg),
+            _ => self.upstream_read(requester, tag, addr, msg),
+        }
+    }
+
+    /// Complete a request of the device, the data is only sent on success.
+    fn complete(&mut self, cpl: CompletionExtra, data: Vec<u32>) {
+        let tlp = if cpl.status == CPL_SC {
+            TlpBuilder::completion_data(cpl).data(data).build()
+        } else {
+            TlpBuilder::completion(cpl).build()
+        };
+
+        self.send(cpl.requester, tlp);
+    }
+
+    /// Answer an ATS translation request of the device with one translation per 2 DWs it asks
+    /// for. Ranges the agent could not translate get an entry which allows no access.
+    fn translation_request(&mut self, requester: u16, tag: u8, addr: u64, msg: Tlp) {
+        let mut cpl = CompletionExtra {
+            requester,
+            completer: self.bdf,
+            tag,
+            status: CPL_SC,
+            bcm: false,
+            byte_count: 0,
+            lower_address: 0,
+        };
+
+        let agent = match self.translation_agent.as_ref() {
+            Some(agent) if self.features.ats => agent,
+            _ => {
+                error!("Translation request of {:#x} without ATS", requester);
+                cpl.status = CPL_UR;
+                self.complete(cpl, vec![]);
+                return;
+            }
+        };
+
+        let mut iova = addr & !(ATS_PAGE_SIZE - 1);
+        let mut data = vec![];
+        for _ in 0..(msg.header.length as usize / 2).max(1) {
+            let translation = agent
+                .translate(requester, iova)
+                .unwrap_or_else(AtsTranslation::fault);
+            data.extend_from_slice(&translation.to_entry());
+
+            let size = translation.size.max(ATS_PAGE_SIZE);
+            iova = (iova & !(size - 1)) + size;
+        }
+
+        cpl.byte_count = (data.len() * 4) as u16;
+        self.complete(cpl, data);
+    }
+
+    /// Handle a memory write issued by the device, which is an MSI if it targets the doorbell
+    /// or a DMA into guest memory otherwise.
+    fn upstream_write(&mut self, requester: u16, addr: u64, msg: Tlp) {
+        let data = match msg.data {
+            Some(data) => data,
+            None => return,
+        };
+
+        // The payload is in memory byte order, MSI data is a little endian DW.
+        if self.msi_window.contains(&addr) {
+            if let Some(dw) = data.first() {
+                self.inject_msi(requester, addr, u32::from_le_bytes(dw.to_be_bytes()));
+            }
+            return;
+        }
+
+        let addr = addr & !0b11;
+        let bytes: Vec<u8> = data.iter().flat_map(|dw| dw.to_be_bytes()).collect();
+        let enabled = enabled_bytes(data.len(), msg.header.byte_enable);
+        let at = msg.header.address_type;
+        let gpa =
+            match self.translate_dma(requester, addr, bytes.len() as u64, DmaAccess::Write, at) {
+                Some(gpa) => gpa,
+                None => return,
+            };
+        let memory = self.dma_memory.as_ref().unwrap();
+
+        // Only the runs of enabled bytes are written.
+        let mut i = 0;
+        while i < bytes.len() {
+            if !enabled[i] {
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            while i < bytes.len() && enabled[i] {
+                i += 1;
+            }
+            if let Err(e) = memory.write(gpa + start as u64, &bytes[start..i]) {
+                error!("Failed to write guest memory at {:#x}: {}", gpa, e);
+                return;
+            }
+        }
+    }
+
+    /// Raise an MSI-X vector of the function at `requester`, which must be on a lane of the
+    /// bridge, through its emulated MSI-X table.
+    fn raise_msix(&mut self, requester: u16, vector: usize) {
+        if !self.serves(requester) {
+            error!(
+                "MSI-X vector {} raised by {:#x}, which is not a function of the bridge",
+                vector, requester
+            );
+            self.stats.errors += 1;
+            return;
+        }
+
+        let message = match self.msix.get_mut(&requester) {
+            Some(table) => table.trigger(vector),
+            None => {
+                error!(
+                    "MSI-X vector {} raised by {:#x} without MSI-X capability",
+                    vector, requester
+                );
+                return;
+            }
+        };
+
+        match message {
+            Some((addr, data)) => self.inject_msix(requester, vector, addr, data),
+            None => debug!("MSI-X vector {} is masked, disabled or invalid", vector),
+        }
+    }
+
+    /// Take an Assert_INTx or Deassert_INTx message of the function at `bdf`, which must be on a
+    /// lane of the bridge. The wires of the functions are wired-OR per pin, the sink only sees
+    /// the level of a pin change.
+    fn set_intx(&mut self, bdf: u16, code: u8) {
+        if !self.downstream.contains_key(&bdf) {
+            error!(
+                "INTx message {:#x} of {:#x}, which is not a function of the bridge",
+                code, bdf
+            );
+            self.stats.errors += 1;
+            return;
+        }
+
+        let pin = (code - MSG_ASSERT_INTA) % 4;
+        let before = self.intx.iter().any(|&(_, p)| p == pin);
+        if code < MSG_DEASSERT_INTA {
+            self.intx.insert((bdf, pin));
+        } else {
+            self.intx.remove(&(bdf, pin));
+        }
+        let level = self.intx.iter().any(|&(_, p)| p == pin);
+        if level == before {
+            return;
+        }
+
+        let pin = pin as usize;
+        match self.msi_sink.as_ref() {
+            Some(sink) => {
+                let result = match self.intx_lines[pin] {
+                    Some(gsi) => sink.set_intx_line(pin as u8 + 1, gsi, level),
+                    None => sink.set_intx(pin as u8 + 1, level),
+                };
+                if let Err(e) = result {
+                    error!("Failed to set INTx pin {} to {}: {}", pin + 1, level, e);
+                }
+            }
+            None => error!("INTx pin {} set to {} without MSI sink", pin + 1, level),
+        }
+    }
+
+    /// Deassert the INTx wires of `functions`, which are reset or gone.
+    fn release_intx(&mut self, functions: &[u16]) {
+        let asserted: Vec<(u16, u8)> = self
+            .intx
+            .iter()
+            .filter(|(bdf, _)| functions.contains(bdf))
+            .copied()
+            .collect();
+        for (bdf, pin) in asserted {
+            self.set_intx(bdf, MSG_DEASSERT_INTA + pin);
+        }
+    }
+
+    /// Signal the PM_PME message of the function at `bdf`, which must be on a lane of the
+    /// bridge.
+    fn pme(&mut self, bdf: u16) {
+        if !self.serves(bdf) {
+            error!(
+                "PM_PME of {:#x}, which is not a function of the bridge",
+                bdf
+            );
+            self.stats.errors += 1;
+            return;
+        }
+        self.emit(AdapterEvent::Pme { bdf });
+    }
+
+    /// The function of requester ID `bdf` on the segment of the bridge.
+    fn address_of(&self, bdf: u16) -> PciAddress {
+        PciAddress {
+            segment: self.builder.segment,
+            bdf,
+        }
+    }
+
+    fn inject_msi(&self, requester: u16, addr: u64, data: u32) {
+        match self.msi_sink.as_ref() {
+            Some(sink) => {
+                if let Err(e) = sink.inject_from(self.address_of(requester), addr, data) {
+                    error!("Failed to inject MSI {:#x}@{:#x}: {}", data, addr, e);
+                }
+            }
+            None => error!("MSI {:#x}@{:#x} dropped without MSI sink", data, addr),
+        }
+    }
+
+    fn inject_msix(&self, requester: u16, vector: usize, addr: u64, data: u32) {
+        match self.msi_sink.as_ref() {
+            Some(sink) => {
+                let source = self.address_of(requester);
+                if let Err(e) = sink.inject_vector(source, vector as u16, addr, data) {
+                    error!(
+                        "Failed to inject MSI-X vector {} of {:#x}: {}",
+                        vector, requester, e
+                    );
+                }
+            }
+            None => error!("MSI-X vector {} dropped without MSI sink", vector),
+        }
+    }
+
+    /// Apply `change` to the MSI-X table of `target`, tell the sink when it enables or disables
+    /// MSI-X or masks or unmasks vectors, then inject the vectors `change` releases.
+    fn change_msix<F>(&mut self, target: u16, change: F)
+    where
+        F: FnOnce(&mut MsixTable) -> Vec<(usize, u64, u32)>,
+    {
+        let table = match self.msix.get_mut(&target) {
+            Some(table) => table,
+            None => return,
+        };
+        let before = table.masks();
+        let released = change(table);
+        let after = table.masks();
+
+        if let Some(sink) = self.msi_sink.as_ref() {
+            let source = self.address_of(target);
+            let result = match (&before, &after) {
+                (Some(_), None) => sink.disable_msix(source),
+                (None, Some(masks)) => {
+                    sink.enable_msix(source, masks.len() as u16).and_then(|_| {
+                        masks
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, &masked)| masked)
+                            .try_for_each(|(v, _)| sink.mask_vector(source, v as u16, true))
+                    })
+                }
+                (Some(before), Some(after)) => before
+                    .iter()
+                    .zip(after.iter())
+                    .enumerate()
+                    .filter(|(_, (before, after))| before != after)
+                    .try_for_each(|(v, (_, &masked))| sink.mask_vector(source, v as u16, masked)),
+                (None, None) => Ok(()),
+            };
+            if let Err(e) = result {
+                error!("Failed to update MSI-X of {:#x}: {}", target, e);
+            }
+        }
+
+        for (vector, addr, data) in released {
+            self.inject_msix(target, vector, addr, data);
+        }
+    }
+
+    /// Send a memory read request of at most [`PciAdapterBuilder::max_read_request_size`]
+    /// bytes.
+    fn read_memory(&mut self, target: u16, addr: u64, len: usize, reaction: Reaction) {
+        let trans_id = self.next_transaction_id();
+
+        let offset = (addr & 0b11) as usize;
+        let size = (offset + len + 3) >> 2; // in DW
+
+        // Requests below 4GB use the 3DW header.
+        let tag = (trans_id & 0xff) as u8;
+        let builder = if addr >> 32 == 0 {
+            TlpBuilder::memory_read(MemoryExtra {
+                requester: self.bdf,
+                tag,
+                addr: addr as u32 & !0b11,
+            })
+        } else {
+            TlpBuilder::memory_read64(Memory64Extra {
+                requester: self.bdf,
+                tag,
+                addr: addr & !0b11,
+            })
+        };
+        let tlp = builder
+            .byte_enable(write_byte_enable(offset, len))
+            .length(size as u16)
+            .build();
+
+        self.submit(target, trans_id, reaction, tlp);
+    }
+
+    /// Track the command register of the target of a config write.
+    fn snoop_command(&mut self, data: &ConfigData) {
+        if data.reg_idx != STATUS_REG || data.offset >= 2 {
+            return;
+        }
+
+        let command = self.command.entry(data.target).or_default();
+        let mut bytes = command.to_le_bytes();
+        for i in 0..data.len.min(2 - data.offset as usize) {
+            bytes[data.offset as usize + i] = (data.data >> (i * 8)) as u8;
+        }
+        *command = u16::from_le_bytes(bytes);
+    }
+
+    /// Whether `target` decodes the space enabled by `bits` of its command register. A
+    /// function with the space disabled does not claim the request, which ends as an
+    /// Unsupported Request.
+    fn decodes(&self, target: u16, bits: u16) -> bool {
+        let enabled = self
+            .command
+            .get(&target)
+            .is_none_or(|command| command & bits == bits);
+        if !enabled {
+            debug!(
+                "Request to {:#x} with its space {:#x} disabled",
+                target, bits
+            );
+        }
+        enabled
+    }
+
+    /// Stop the memory requests and MSI-X messages of a function whose Bus Master Enable is
+    /// clear: reads are completed with UR, writes are dropped. Returns false if the TLP is
+    /// stopped.
+    fn check_bus_master(&mut self, msg: &Tlp) -> bool {
+        use PacketType::*;
+
+        let (requester, tag, read) = match msg.header._type {
+            MemoryRead(extra) => (extra.requester, extra.tag, true),
+            MemoryRead64(extra) => (extra.requester, extra.tag, true),
+            MemoryWrite(extra) => (extra.requester, extra.tag, false),
+            MemoryWrite64(extra) => (extra.requester, extra.tag, false),
+            RoutedMessageData(MessageExtra {
+                requester,
+                code: MSIX_VECTOR_MESSAGE,
+                ..
+            }) => (requester, 0, false),
+            _ => return true,
+        };
+        if self
+            .command
+            .get(&requester)
+            .is_none_or(|command| command & COMMAND_BUS_MASTER != 0)
+        {
+            return true;
+        }
+
+        error!(
+            "{:?} of {:#x} with Bus Master Enable clear",
+            msg.header._type, requester
+        );
+        self.stats.errors += 1;
+        if read {
+            let cpl = CompletionExtra {
+                requester,
+                completer: self.bdf,
+                tag,
+                status: CPL_UR,
+                bcm: false,
+                byte_count: 0,
+                lower_address: 0,
+            };
+            self.complete(cpl, vec![]);
+        }
+        false
+    }
+
+    /// Forward a TLP of a function to another one: completions by requester ID, memory
+    /// requests by the memory windows and messages by their routing. Returns the TLP if it is
+    /// for the bridge instead.
+    fn forward(&mut self, msg: Tlp) -> Option<Tlp> {
+        let addr = match msg.header._type {
+            PacketType::RoutedMessage(extra) | PacketType::RoutedMessageData(extra) => {
+                let local = extra.requester;
+                match self.message_destination(&extra, true, local) {
+                    Destination::Function(target) => self.send(target, msg),
+                    Destination::RootComplex => return Some(msg),
+                    Destination::All | Destination::Unroutable => {
+                        error!("Unroutable message {:?} from the device", extra);
+                        self.stats.errors += 1;
+                    }
+                }
+                return None;
+            }
+            PacketType::Completion(extra) | PacketType::CompletionData(extra) => {
+                if extra.requester == self.bdf {
+                    return Some(msg);
+                }
+                self.send(extra.requester, msg);
+                return None;
+            }
+            // Translation requests are for the translation agent, whatever the address.
+            _ if msg.header.address_type == AddressType::TranslationRequest => return Some(msg),
+            PacketType::MemoryRead(extra) | PacketType::MemoryWrite(extra) => extra.addr as u64,
+            PacketType::MemoryRead64(extra) | PacketType::MemoryWrite64(extra) => extra.addr,
+            _ => return Some(msg),
+        };
+
+        if let Some(target) = self.route(addr) {
+            match self.check_acs(&msg, target) {
+                AcsVerdict::Forward => self.send(target, msg),
+                AcsVerdict::Redirect => return Some(msg),
+                AcsVerdict::Block => self.block_peer_request(&msg, target),
+            }
+            return None;
+        }
+
+        let (peer, tx) = match self.peers.as_ref() {
+            Some((bus, tx)) => match bus.route(tx, addr) {
+                Some(peer) => (peer, tx.clone()),
+                None => return Some(msg),
+            },
+            None => return Some(msg),
+        };
+        let read = is_memory_read(&msg);
+        let requester = id_of(&msg).unwrap_or(self.bdf);
+        let tag = msg.header.tag().unwrap_or(0);
+        if peer
+            .send(AdapterMessage::PeerRequest(msg, tx.clone()))
+            .is_err()
+            && read
+        {
+            let cpl = CompletionExtra {
+                requester,
+                completer: self.bdf,
+                tag,
+                status: CPL_SC,
+                bcm: false,
+                byte_count: 0,
+                lower_address: 0,
+            };
+            Reaction::Peer(cpl, tx).fail(PciAdapterError::Completion(CPL_UR));
+        }
+        None
+    }
+
+    /// What the downstream port above the requester of `msg` does with it on its way to
+    /// `target`. Requests which stay on the link of the requester pass no port.
+    fn check_acs(&self, msg: &Tlp, target: u16) -> AcsVerdict {
+        let requester = match id_of(msg) {
+            Some(requester) => requester,
+            None => return AcsVerdict::Forward,
+        };
+        if link_of(requester) == link_of(target) {
+            return AcsVerdict::Forward;
+        }
+
+        let translated = msg.header.address_type == AddressType::Translated;
+        self.acs
+            .get(&link_of(requester))
+            .map_or(AcsVerdict::Forward, |port| {
+                port.check(translated, (target >> 3) as u8)
+            })
+    }
+
+    /// Stop a request the downstream port above its requester blocks: a read is completed
+    /// with Completer Abort, a write is dropped.
+    fn block_peer_request(&mut self, msg: &Tlp, target: u16) {
+        let requester = id_of(msg).unwrap_or(self.bdf);
+        error!(
+            "ACS violation of {:?} from {:#x} to {:#x}",
+            msg.header._type, requester, target
+        );
+        self.stats.errors += 1;
+        if is_memory_read(msg) {
+            let cpl = CompletionExtra {
+                requester,
+                completer: self.bdf,
+                tag: msg.header.tag().unwrap_or(0),
+                status: CPL_CA,
+                bcm: false,
+                byte_count: 0,
+                lower_address: 0,
+            };
+            self.complete(cpl, vec![]);
+        }
+    }
+
+    /// Answer a PTM Request with the master time it arrived at.
+    fn ptm_response(&mut self, msg: &Tlp) {
+        let master_time = self.ptm_time.now();
+        let requester = match ptm::requester_of(msg) {
+            Some(requester) if self.downstream.contains_key(&requester) => requester,
+            _ => {
+                error!("PTM Request of an unknown function: {:?}", msg.data);
+                self.stats.errors += 1;
+                return;
+            }
+        };
+
+        let propagation_delay = self.ptm_time.now().saturating_sub(master_time) as u32;
+        let response = PtmResponse {
+            master_time,
+            propagation_delay,
+        };
+        self.send(requester, response.to_message());
+    }
+
+    /// Take `msg` and, when reordering, the TLPs queued behind it on the upstream lane.
+    fn receive(&mut self, msg: Tlp) {
+        let mut tlps = vec![msg];
+        if self.ordering == OrderingPolicy::Reorder {
+            while tlps.len() < REORDER_WINDOW {
+                match self.upstream.try_recv() {
+                    Ok(tlp) => tlps.push(tlp),
+                    Err(_) => break,
+                }
+            }
+            tlps = reorder(tlps);
+        }
+
+        for tlp in tlps {
+            let lane = id_of(&tlp).map_or(0, link_of);
+            let delay = self.delay.delay_of(Direction::Upstream, &tlp)
+                + self.meter(Direction::Upstream, lane, &tlp);
+            if let Some(tlp) = self.delayed_up.push(lane, delay, tlp) {
+                self.take(tlp);
+            }
+        }
+    }
+
+    /// Handle a TLP of a device, once it is no longer delayed.
+    fn take(&mut self, tlp: Tlp) {
+        if let Some(bdf) = id_of(&tlp) {
+            if !self.wake_link(bdf) {
+                debug!("TLP of {:#x} dropped, the link is down", bdf);
+                self.stats.dropped += 1;
+                return;
+            }
+        }
+        self.record_tlp(Direction::Upstream, &tlp);
+        if self.ordering == OrderingPolicy::Strict {
+            self.check_ordering(&tlp);
+        }
+        self.handle_transaction_msg(tlp);
+    }
+
+    /// Count a TLP of a device breaking the rules of the ordering attributes: MSIs must not be
+    /// relaxed or skip the snoop, which could let them pass the DMA writes they signal, and
+    /// completions carry the Relaxed Ordering and No Snoop attributes of their request.
+    fn check_ordering(&mut self, msg: &Tlp) {
+        let header = &msg.header;
+        let attributes = |header: &TlpHeader| (header.relax_ordering, header.no_snoop);
+
+        let violation = match header._type {
+            PacketType::MemoryWrite(MemoryExtra { addr, .. })
+                if self.msi_window.contains(&(addr as u64)) =>
+            {
+                attributes(header) != (false, false)
+            }
+            PacketType::MemoryWrite64(Memory64Extra { addr, .. })
+                if self.msi_window.contains(&addr) =>
+            {
+                attributes(header) != (false, false)
+            }
+            PacketType::RoutedMessageData(MessageExtra {
+                code: MSIX_VECTOR_MESSAGE,
+                ..
+            }) => attributes(header) != (false, false),
+            PacketType::Completion(extra) | PacketType::CompletionData(extra)
+                if extra.requester == self.bdf =>
+            {
+                header
+                    .transaction_id()
+                    .and_then(|trans_id| self.store.get(&trans_id))
+                    .is_some_and(|pending| attributes(&pending.tlp.header) != attributes(header))
+            }
+            _ => false,
+        };
+
+        if violation {
+            error!(
+                "{:?} breaks the ordering rules with RO {} and NS {}",
+                header._type, header.relax_ordering, header.no_snoop
+            );
+            self.stats.violations += 1;
+        }
+    }
+
+    fn handle_transaction_msg(&mut self, msg: Tlp) {
+        if !self.check_bus_master(&msg) {
+            return;
+        }
+        let msg = match self.forward(msg) {
+            Some(msg) => msg,
+            None => return,
+        };
+
+        match msg.header._type {
+            PacketType::Completion(extra) | PacketType::CompletionData(extra) => {
+                let trans_id = match msg.header.transaction_id() {
+                    Some(trans_id) => trans_id,
+                    None => return,
+                };
+                if extra.status == CPL_CRS && self.retry_config(trans_id) {
+                    return;
+                }
+
+                let pending = match self.retire(trans_id) {
+                    Some(pending) => pending,
+                    None => {
+                        error!("Unexpected completion of transaction {:#x}", trans_id);
+                        self.stats.errors += 1;
+                        return;
+                    }
+                };
+
+                let reaction = match pending.reaction {
+                    Reaction::Peer(origin, peer) => {
+                        let mut msg = msg;
+                        match &mut msg.header._type {
+                            PacketType::Completion(extra) | PacketType::CompletionData(extra) => {
+                                extra.requester = origin.requester;
+                                extra.tag = origin.tag;
+                            }
+                            _ => unreachable!(),
+                        }
+                        let _ = peer.send(AdapterMessage::PeerCompletion(msg));
+                        return;
+                    }
+                    reaction => reaction,
+                };
+
+                if extra.status != CPL_SC {
+                    self.record_event(EventKind::CompletionError, trans_id);
+                    reaction.fail(PciAdapterError::Completion(extra.status));
+                    return;
+                }
+                if !payload_matches(&msg) {
+                    self.record_event(EventKind::CompletionError, trans_id);
+                    reaction.fail(PciAdapterError::MalformedCompletion);
+                    return;
+                }
+                if msg.header.poisoned_data {
+                    self.record_event(EventKind::CompletionError, trans_id);
+                    reaction.fail(PciAdapterError::PoisonedCompletion);
+                    return;
+                }
+
+                if msg.data.is_some() || matches!(reaction, Reaction::Notify(_)) {
+                    self.stats.completions += 1;
+                }
+
+                match (reaction, msg.data) {
+                    (Reaction::Notify(sender), _) => {
+                        let _ = sender.send(Ok(()));
+                    }
+                    (reaction, None) => {
+                        self.record_event(EventKind::CompletionError, trans_id);
+                        reaction.fail(PciAdapterError::MalformedCompletion);
+                    }
+                    (Reaction::ReadConfig(sender), Some(dw)) => {
+                        let _ = sender.send(Ok(dw[0]));
+                    }
+                    (Reaction::ReadIo(offset, len, sender), Some(dw)) => {
+                        let bytes = dw[0].to_be_bytes();
+                        let _ = sender.send(Ok(bytes[offset..offset + len].to_vec()));
+                    }
+                    (Reaction::ReadMemory(len, sender), Some(dw)) => {
+                        let data = completion_bytes(&msg.header, &extra, &dw, len);
+                        let _ = sender.send(Ok(data));
+                    }
+                    (Reaction::ReadPart(len, group, index), Some(dw)) => {
+                        let data = completion_bytes(&msg.header, &extra, &dw, len);
+                        group.lock().unwrap().complete(index, data);
+                    }
+                    (Reaction::Peer(..), _) => unreachable!(),
+                }
+            }
+            PacketType::MemoryRead(MemoryExtra {
+                requester,
+                tag,
+                addr,
+            }) => self.memory_request(requester, tag, addr as u64, msg),
+            PacketType::MemoryRead64(Memory64Extra {
+                requester,
+                tag,
+                addr,
+            }) => self.memory_request(requester, tag, addr, msg),
+            PacketType::PageRequest(extra) => self.page_request(extra),
+            PacketType::RoutedMessage(MessageExtra {
+                requester,
+                code: PM_PME,
+                routing: MessageRouting::RootComplex,
+                ..
+            }) => self.pme(requester),
+            PacketType::RoutedMessage(MessageExtra {
+                requester,
+                code: code @ (ERR_COR | ERR_NONFATAL | ERR_FATAL),
+                routing: MessageRouting::RootComplex,
+                ..
+            }) => self.latch_error(requester, code),
+            PacketType::RoutedMessageData(MessageExtra {
+                requester,
+                code: MSIX_VECTOR_MESSAGE,
+                routing: MessageRouting::RootComplex,
+                ..
+            }) => {
+                if let Some(&vector) = msg.data.as_ref().and_then(|dw| dw.first()) {
+                    self.raise_msix(requester, vector as usize);
+                }
+            }
+            PacketType::RoutedMessage(MessageExtra {
+                requester,
+                code,
+                routing: MessageRouting::Local,
+                ..
+            }) if (MSG_ASSERT_INTA..MSG_DEASSERT_INTA + 4).contains(&code) => {
+                self.set_intx(requester, code)
+            }
+            PacketType::RoutedMessage(extra) | PacketType::RoutedMessageData(extra) => {
+                match self.message_handler.as_ref() {
+                    Some(handler) => handler.message(&extra, msg.data.as_deref()),
+                    None => {
+                        error!("Unhandled message {:?} from the device", extra);
+                        self.stats.errors += 1;
+                    }
+                }
+            }
+            PacketType::Message(ATS_INVALIDATE_COMPLETION) => {
+                match self.invalidations.pop_front() {
+                    Some(waiter) => {
+                        let _ = waiter.send(Ok(()));
+                    }
+                    None => {
+                        error!("Unexpected ATS invalidate completion");
+                        self.stats.errors += 1;
+                    }
+                }
+            }
+            PacketType::MemoryWrite(extra) => {
+                self.upstream_write(extra.requester, extra.addr as u64, msg)
+            }
+            PacketType::MemoryWrite64(extra) => {
+                self.upstream_write(extra.requester, extra.addr, msg)
+            }
+            PacketType::MessageData(PTM_REQUEST) => self.ptm_response(&msg),
+            _ => {
+                error!("Unexpected TLP from the device: {:?}", msg.header._type);
+                self.stats.errors += 1;
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct MmioRegion {
+    pub start: GuestAddress,
+    pub length: GuestUsize,
+    pub type_: PciBarRegionType,
+    pub bar_reg: usize,
+    pub slot_mapped: bool,
+    pub mem_slot: Option<u32>,
+    pub host_addr: Option<u64>,
+    pub mmap_size: Option<usize>,
+}
+
+/// What a device model hands to the adapter of its function and the bridge before it starts
+/// running.
+#[derive(Clone, Default)]
+struct Exports {
+    shared_regions: Vec<SharedRegion>,
+    doorbells: Option<Doorbells>,
+    credits: Option<FcCredits>,
+}
+
+impl Exports {
+    fn of(device: &(dyn PciSimDevice + Send + Sync)) -> Self {
+        Exports {
+            shared_regions: device.shared_regions(),
+            doorbells: device.doorbells(),
+            credits: device.flow_control(),
+        }
+    }
+}
+
+/// The adapter PCI device exporting an hypervisor friendly interface.
+pub struct PciAdapter {
+    tx: Sender<AdapterMessage>,
+    events: Receiver<AdapterEvent>,
+    pub(crate) mmio_regions: Vec<MmioRegion>,
+    shared_regions: Vec<SharedRegion>,
+    slot_manager: Option<Box<dyn MemorySlotManager>>,
+    doorbells: Option<Doorbells>,
+    ioevent_manager: Option<Box<dyn IoEventManager>>,
+    /// The ioeventfds of the doorbells, once they are registered.
+    doorbell_events: Option<DoorbellEvents>,
+    /// Whether the guest enabled the address decoder of the expansion ROM.
+    rom_enabled: bool,
+    /// The MSI-X capability of the device and the message control last written by the guest.
+    pub(crate) msix: Option<MsixCap>,
+    msix_control: u16,
+    /// Secondary bus resets of the bridge, shared by all its adapters, and the count the ROM
+    /// enable and the MSI-X message control are taken after.
+    bus_resets: Arc<AtomicU64>,
+    bus_resets_seen: u64,
+    /// Segment and BDF of the function the adapter stands for and the number of functions of
+    /// its device.
+    segment: u16,
+    bdf: u16,
+    functions: usize,
+    /// The bridge thread, held by the first adapter of the bridge.
+    handle: Option<JoinHandle<()>>,
+    replies: Arc<ReplyPool>,
+    /// Disconnected once the bridge thread ends.
+    alive: Receiver<()>,
+    /// See [`PciAdapterBuilder::config_cache`].
+    config_cache: Option<ConfigCache>,
+    /// The VFs enabled by [`PciAdapter::enable_vfs`] and the regions of the VF BARs which hold
+    /// their BARs.
+    vfs: Vec<u16>,
+    vf_regions: Vec<MmioRegion>,
+    /// Whether the adapter stands for a VF, whose BARs are placed by its PF.
+    virtual_function: bool,
+    /// The GSI of the INTx pin of the function, see [`PciAdapter::intx_line`].
+    intx_line: Option<u32>,
+    /// The register of the PCI Express capability holding Link Control and Link Status.
+    link_status_reg: Option<usize>,
+    /// The register of the PCI Express capability holding Device Control and Status.
+    devctl_reg: Option<usize>,
+}
+
+/// Attaches new lanes to a bridge, see [`PciAdapter::reconnect`].
+#[derive(Clone)]
+pub(crate) struct Reconnector {
+    tx: Sender<AdapterMessage>,
+    alive: Receiver<()>,
+}
+
+impl Reconnector {
+    /// Attach `lane`, false once the bridge is gone.
+    pub(crate) fn reconnect(&self, lane: PciLane, features: DeviceFeatures) -> bool {
+        self.tx
+            .send(AdapterMessage::Reconnect(lane, features))
+            .is_ok()
+    }
+
+    /// Whether the bridge thread still runs.
+    pub(crate) fn alive(&self) -> bool {
+        self.alive.try_recv() != Err(TryRecvError::Disconnected)
+    }
+}
+
+/// A request issued by one of the `*_async` methods of [`PciAdapter`]. The request is on its
+/// way once the method returns, the handle resolves when the completion arrives. Dropping the
+/// handle does not cancel the request.
+pub struct PendingRequest<T> {
+    reply: Box<Reply<Result<T>>>,
+    /// Where the channel goes back once [`PendingRequest::wait`] took the answer, `None` for
+    /// requests resolved before they are sent.
+    pool: Option<Arc<ReplyPool>>,
+    /// Disconnected once the bridge is gone. The reply channel has a sender of its own, so it
+    /// does not tell.
+    alive: Receiver<()>,
+}
+
+impl<T> PendingRequest<T> {
+    /// A request which is resolved already, e.g. rejected before it is sent.
+    fn ready(result: Result<T>) -> Self {
+        let reply = Box::new(Reply::new());
+        let _ = reply.tx.send(result);
+        PendingRequest {
+            reply,
+            pool: None,
+            alive: never(),
+        }
+    }
+
+    /// The answer which is left in the channel once the bridge is gone.
+    fn last_answer(&self) -> Result<T> {
+        self.reply
+            .rx
+            .try_recv()
+            .unwrap_or(Err(PciAdapterError::Disconnected))
+    }
+
+    /// Take the result without blocking, `None` while the request is in flight. The result
+    /// can only be taken once.
+    pub fn try_wait(&self) -> Option<Result<T>> {
+        match self.reply.rx.try_recv() {
+            Ok(result) => Some(result),
+            Err(_) => match self.alive.try_recv() {
+                Err(TryRecvError::Disconnected) => Some(self.last_answer()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Like [`PendingRequest::try_wait`], but block for at most `timeout`.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<T>> {
+        select! {
+            recv(self.reply.rx) -> result => {
+                Some(result.unwrap_or(Err(PciAdapterError::Disconnected)))
+            }
+            recv(self.alive) -> _ => Some(self.last_answer()),
+            default(timeout) => None,
+        }
+    }
+
+    /// The channel the result arrives on, e.g. to wait for several requests by `select!`. The
+    /// channel stays open when the bridge is gone, so the waiter had better have a timeout.
+    pub fn receiver(&self) -> &Receiver<Result<T>> {
+        &self.reply.rx
+    }
+}
+
+impl<T: Send + 'static> PendingRequest<T> {
+    /// Block until the request is resolved. The reply channel is reused by a later request.
+    pub fn wait(self) -> Result<T> {
+        let result = select! {
+            recv(self.reply.rx) -> result => result.unwrap_or(Err(PciAdapterError::Disconnected)),
+            recv(self.alive) -> _ => return self.last_answer(),
+        };
+
+        if let Some(pool) = self.pool {
+            pool.put(self.reply);
+        }
+        result
+    }
+}
+
+impl PciAdapter {
+    /// Send a request to the bridge thread without waiting for the answer. If the bridge is
+    /// gone, the request resolves as disconnected.
+    fn request_async<T: Send + 'static>(
+        &self,
+        msg: impl FnOnce(Sender<Result<T>>) -> AdapterMessage,
+    ) -> PendingRequest<T> {
+        let reply = self.replies.take();
+        let _ = self.tx.send(msg(reply.tx.clone()));
+        PendingRequest {
+            reply,
+            pool: Some(self.replies.clone()),
+            alive: self.alive.clone(),
+        }
+    }
+
+    /// Send a request to the bridge thread and block until it is answered.
+    fn request<T: Send + 'static>(
+        &self,
+        msg: impl FnOnce(Sender<Result<T>>) -> AdapterMessage,
+    ) -> Result<T> {
+        self.request_async(msg).wait()
+    }
+
+    /// Request the runner thread to send a type 0 config read transaction to the simulated device.
+    /// Then block and wait for the completion transaction.
+    pub fn try_config_read(&self, reg_idx: usize) -> Result<u32> {
+        let generation = self.config_cache.as_ref().map(ConfigCache::generation);
+        let value = self.config_read_async(reg_idx).wait()?;
+        self.cache_config(reg_idx, generation, value);
+        Ok(value)
+    }
+
+    /// Non-blocking version of [`PciAdapter::try_config_read`]. Its answer does not fill the
+    /// config cache.
+    pub fn config_read_async(&self, reg_idx: usize) -> PendingRequest<u32> {
+        if reg_idx >= PCIE_CONFIG_REGS {
+            return PendingRequest::ready(Err(PciAdapterError::InvalidRegister(reg_idx)));
+        }
+        if let Some(value) = self.config_cache.as_ref().and_then(|c| c.get(reg_idx)) {
+            return PendingRequest::ready(Ok(value));
+        }
+
+        self.request_async(|tx| AdapterMessage::ConfigRead(self.bdf, reg_idx, tx))
+    }
+
+    /// Read several config registers. All requests are issued before waiting for the first
+    /// completion, which saves the round trips of reading them one by one.
+    pub fn config_read_many(&self, regs: &[usize]) -> Result<Vec<u32>> {
+        let generation = self.config_cache.as_ref().map(ConfigCache::generation);
+        let reads: Vec<_> = regs
+            .iter()
+            .map(|&reg_idx| self.config_read_async(reg_idx))
+            .collect();
+
+        let values = reads
+            .into_iter()
+            .map(PendingRequest::wait)
+            .collect::<Result<Vec<_>>>()?;
+        for (&reg_idx, &value) in regs.iter().zip(values.iter()) {
+            self.cache_config(reg_idx, generation, value);
+        }
+        Ok(values)
+    }
+
+    /// Keep a value read from the device in the config cache, unless the device is not ready
+    /// to answer yet.
+    fn cache_config(&self, reg_idx: usize, generation: Option<u64>, value: u32) {
+        if let (Some(cache), Some(generation)) = (self.config_cache.as_ref(), generation) {
+            if !(reg_idx == 0 && value == CRS_VENDOR_ID) {
+                cache.fill(reg_idx, generation, value);
+            }
+        }
+    }
+
+    /// Infallible version of [`PciAdapter::try_config_read`], failed reads return all 1s.
+    pub fn config_read(&self, reg_idx: usize) -> u32 {
+        self.try_config_read(reg_idx).unwrap_or_else(|e| {
+            error!("Failed to read config register {}: {}", reg_idx, e);
+            u32::MAX
+        })
+    }
+
+    /// Request the runner thread to send a type 0 config write transaction to the simulated device.
+    /// Then block and wait for the completion transaction.
+    pub fn try_config_write(&self, reg_idx: usize, offset: u64, data: &[u8]) -> Result<()> {
+        self.config_write_async(reg_idx, offset, data).wait()
+    }
+
+    /// Non-blocking version of [`PciAdapter::try_config_write`].
+    pub fn config_write_async(
+        &self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> PendingRequest<()> {
+        let len = data.len();
+        if reg_idx >= PCIE_CONFIG_REGS {
+            return PendingRequest::ready(Err(PciAdapterError::InvalidRegister(reg_idx)));
+        }
+        if len == 0 || offset as usize + len > 4 {
+            return PendingRequest::ready(Err(PciAdapterError::InvalidSize(len)));
+        }
+
+        let mut bytes = 0;
+
+        for b in data.iter().rev() {
+            bytes = (bytes << 8) | *b as u32;
+        }
+
+        let data = ConfigData {
+            target: self.bdf,
+            reg_idx,
+            offset,
+            len,
+            data: bytes,
+        };
+        if let Some(cache) = self.config_cache.as_ref() {
+            cache.invalidate(reg_idx);
+        }
+        self.request_async(|tx| AdapterMessage::ConfigWrite(data, tx))
+    }
+
+    /// Infallible version of [`PciAdapter::try_config_write`], failures are only logged.
+    pub fn config_write(&self, reg_idx: usize, offset: u64, data: &[u8]) {
+        if let Err(e) = self.try_config_write(reg_idx, offset, data) {
+            error!("Failed to write config register {}: {}", reg_idx, e);
+        }
+    }
+
+    /// Read the configuration space at an ECAM style byte offset, only the lower 12 bits of
+    /// `offset` addressing the 4 KB space of the function are used.
+    pub fn try_ecam_read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        let offset = (offset & 0xfff) as usize;
+        let start = offset & 0b11;
+        if data.is_empty() || start + data.len() > 4 {
+            return Err(PciAdapterError::InvalidSize(data.len()));
+        }
+
+        let value = self.try_config_read(offset >> 2)?.to_le_bytes();
+        data.copy_from_slice(&value[start..start + data.len()]);
+        Ok(())
+    }
+
+    /// Write the configuration space at an ECAM style byte offset, see
+    /// [`PciAdapter::try_ecam_read`].
+    pub fn try_ecam_write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let offset = offset & 0xfff;
+        self.try_config_write((offset >> 2) as usize, offset & 0b11, data)
+    }
+
+    /// Find the BAR region of an MMIO access and validate the access size.
+    fn check_mmio_access(&self, addr: u64, len: usize) -> Result<MmioRegion> {
+        let region = self
+            .find_region(addr)
+            .ok_or(PciAdapterError::InvalidAddress(addr))?;
+
+        if len > 8 {
+            return Err(PciAdapterError::InvalidSize(len));
+        }
+
+        // The expansion ROM only decodes accesses while it is enabled.
+        if region.bar_reg == ROM_REG && !self.rom_enabled() {
+            return Err(PciAdapterError::InvalidAddress(addr));
+        }
+
+        if region.slot_mapped {
+            error!(
+                "Region should be memory backed, maybe you forget to register the slot? {:#x}",
+                addr
+            );
+        }
+
+        Ok(region)
+    }
+
+    /// Check an IO access, which could not cross a DW boundary.
+    fn check_io_access(addr: u64, len: usize) -> Result<()> {
+        if len == 0 || (addr & 0b11) as usize + len > 4 {
+            return Err(PciAdapterError::InvalidSize(len));
+        }
+        Ok(())
+    }
+
+    /// Find the MSI-X structure an access to `region` hits, if any.
+    fn msix_structure(&self, region: &MmioRegion, addr: u64) -> Option<MsixStructure> {
+        let cap = self.msix?;
+        if !(BAR0_REG..BAR0_REG + NUM_BAR_REGS).contains(&region.bar_reg) {
+            return None;
+        }
+
+        cap.locate(region.bar_reg - BAR0_REG, addr - region.start.raw_value())
+    }
+
+    /// Check an access to the MSI-X table or PBA, which must be an aligned DW or QW.
+    fn check_msix_access(addr: u64, len: usize) -> Result<()> {
+        if (len != 4 && len != 8) || !addr.is_multiple_of(len as u64) {
+            return Err(PciAdapterError::InvalidSize(len));
+        }
+        Ok(())
+    }
+
+    /// Read from a BAR region and wait for the completion. Memory regions are read by a memory
+    /// read transaction, IO regions by an IO read transaction. The MSI-X table and PBA are
+    /// emulated by the bridge. Once the guest writes the command register, reads of a space
+    /// it does not enable fail with UR.
+    pub fn try_bar_mmio_read(&self, addr: u64, data: &mut [u8]) -> Result<()> {
+        let value = self.bar_mmio_read_async(addr, data.len()).wait()?;
+        if value.len() != data.len() {
+            return Err(PciAdapterError::MalformedCompletion);
+        }
+
+        data.copy_from_slice(&value);
+        Ok(())
+    }
+
+    /// Non-blocking version of [`PciAdapter::try_bar_mmio_read`], resolves to `len` bytes.
+    pub fn bar_mmio_read_async(&self, addr: u64, len: usize) -> PendingRequest<Vec<u8>> {
+        self.start_bar_mmio_read(addr, len)
+            .unwrap_or_else(|e| PendingRequest::ready(Err(e)))
+    }
+
+    fn start_bar_mmio_read(&self, addr: u64, len: usize) -> Result<PendingRequest<Vec<u8>>> {
+        let region = self.check_mmio_access(addr, len)?;
+
+        if let Some(structure) = self.msix_structure(&region, addr) {
+            Self::check_msix_access(addr, len)?;
+            return Ok(
+                self.request_async(|tx| AdapterMessage::MsixRead(self.bdf, structure, len, tx))
+            );
+        }
+
+        if region.type_ == PciBarRegionType::IoRegion {
+            Self::check_io_access(addr, len)?;
+            return Ok(
+                self.request_async(|tx| AdapterMessage::IoRead(self.bdf, addr as u32, len, tx))
+            );
+        }
+
+        Ok(self.request_async(|tx| AdapterMessage::MemoryRead(self.bdf, addr, len, tx)))
+    }
+
+    /// Scatter/gather version of [`PciAdapter::try_bar_mmio_read`], filling each buffer from
+    /// its address. All reads are issued before waiting for the first completion.
+    pub fn bar_mmio_read_many(&self, reads: &mut [(u64, &mut [u8])]) -> Result<()> {
+        let pending: Vec<_> = reads
+            .iter()
+            .map(|(addr, data)| self.bar_mmio_read_async(*addr, data.len()))
+            .collect();
+
+        for ((_, data), pending) in reads.iter_mut().zip(pending) {
+            let value = pending.wait()?;
+            if value.len() != data.len() {
+                return Err(PciAdapterError::MalformedCompletion);
+            }
+            data.copy_from_slice(&value);
+        }
+
+        Ok(())
+    }
+
+    /// Read a block of any size from a memory BAR. The bridge splits the read into requests
+    /// of at most [`PciAdapterBuilder::max_read_request_size`] bytes and puts their
+    /// completions back together. The block must fit in the BAR and is read from the device as
+    /// is, it must not start in the MSI-X table or PBA.
+    pub fn try_bar_mmio_read_block(&self, addr: u64, data: &mut [u8]) -> Result<()> {
+        let value = self.bar_mmio_read_block_async(addr, data.len()).wait()?;
+        if value.len() != data.len() {
+            return Err(PciAdapterError::MalformedCompletion);
+        }
+
+        data.copy_from_slice(&value);
+        Ok(())
+    }
+
+    /// Non-blocking version of [`PciAdapter::try_bar_mmio_read_block`], resolves to `len`
+    /// bytes.
+    pub fn bar_mmio_read_block_async(&self, addr: u64, len: usize) -> PendingRequest<Vec<u8>> {
+        self.start_bar_mmio_read_block(addr, len)
+            .unwrap_or_else(|e| PendingRequest::ready(Err(e)))
+    }
+
+    fn start_bar_mmio_read_block(&self, addr: u64, len: usize) -> Result<PendingRequest<Vec<u8>>> {
+        let region = self
+            .find_region(addr)
+            .ok_or(PciAdapterError::InvalidAddress(addr))?;
+        let end = region.start.raw_value() + region.length;
+        if len == 0 || addr + len as u64 > end {
+            return Err(PciAdapterError::InvalidSize(len));
+        }
+
+        if region.type_ == PciBarRegionType::IoRegion
+            || (region.bar_reg == ROM_REG && !self.rom_enabled())
+            || self.msix_structure(&region, addr).is_some()
+        {
+            return Err(PciAdapterError::InvalidAddress(addr));
+        }
+
+        Ok(self.request_async(|tx| AdapterMessage::MemoryRead(self.bdf, addr, len, tx)))
+    }
+
+    /// Infallible version of [`PciAdapter::try_bar_mmio_read`], failed reads return all 1s.
+    pub fn bar_mmio_read(&self, addr: u64, data: &mut [u8]) {
+        if let Err(e) = self.try_bar_mmio_read(addr, data) {
+            error!("Failed to read MMIO region {:#x}: {}", addr, e);
+            data.fill(0xff);
+        }
+    }
+
+    /// Issue a posted memory write TLP to the BAR region which contains `addr`. The caller
+    /// returns as soon as the packet is queued, just like a posted write on a real link. Writes
+    /// to IO regions are non-posted and wait for the completion instead.
+    pub fn try_bar_mmio_write(&self, addr: u64, data: &[u8]) -> Result<()> {
+        self.bar_mmio_write_async(addr, data).wait()
+    }
+
+    /// Non-blocking version of [`PciAdapter::try_bar_mmio_write`]. Posted writes resolve as
+    /// soon as they are queued.
+    pub fn bar_mmio_write_async(&self, addr: u64, data: &[u8]) -> PendingRequest<()> {
+        self.start_bar_mmio_write(addr, data)
+            .unwrap_or_else(|e| PendingRequest::ready(Err(e)))
+    }
+
+    fn start_bar_mmio_write(&self, addr: u64, data: &[u8]) -> Result<PendingRequest<()>> {
+        let region = self.check_mmio_access(addr, data.len())?;
+
+        let msg = if let Some(structure) = self.msix_structure(&region, addr) {
+            Self::check_msix_access(addr, data.len())?;
+            AdapterMessage::MsixWrite(self.bdf, structure, data.to_vec())
+        } else if region.type_ == PciBarRegionType::IoRegion {
+            Self::check_io_access(addr, data.len())?;
+            return Ok(self.request_async(|tx| {
+                AdapterMessage::IoWrite(self.bdf, addr as u32, data.to_vec(), tx)
+            }));
+        } else if region.bar_reg == ROM_REG {
+            // Writes to the expansion ROM are dropped, just like a real ROM does.
+            return Ok(PendingRequest::ready(Ok(())));
+        } else if let Some((kicks, index)) = self.doorbell(&region, addr) {
+            AdapterMessage::Kick(self.bdf, kicks, index)
+        } else {
+            AdapterMessage::MemoryWrite(self.bdf, addr, data.to_vec())
+        };
+
+        self.tx
+            .send(msg)
+            .map_err(|_| PciAdapterError::Disconnected)?;
+        Ok(PendingRequest::ready(Ok(())))
+    }
+
+    /// Infallible version of [`PciAdapter::try_bar_mmio_write`], failures are only logged.
+    pub fn bar_mmio_write(&self, addr: u64, data: &[u8]) {
+        if let Err(e) = self.try_bar_mmio_write(addr, data) {
+            error!("Failed to write MMIO region {:#x}: {}", addr, e);
+        }
+    }
+
+    fn config_write_u32(&self, reg_idx: usize, data: u32) {
+        self.config_write(reg_idx, 0, &data.to_le_bytes());
+    }
+
+    /// Helper function to return the result when we write all 1s to BARs. The original values of
+    /// the BARs are restored after this detection. Each step issues the requests of all BARs
+    /// before waiting for them.
+    fn detect_bars(&mut self, regs: &[usize]) -> Vec<u32> {
+        let read_all = |adapter: &Self| {
+            adapter.config_read_many(regs).unwrap_or_else(|e| {
+                error!("Failed to read BARs {:?}: {}", regs, e);
+                vec![u32::MAX; regs.len()]
+            })
+        };
+        let write_all = |adapter: &Self, values: &[u32]| {
+            let writes: Vec<_> = regs
+                .iter()
+                .zip(values)
+                .map(|(&reg, value)| adapter.config_write_async(reg, 0, &value.to_le_bytes()))
+                .collect();
+            for (reg, write) in regs.iter().zip(writes) {
+                if let Err(e) = write.wait() {
+                    error!("Failed to write config register {}: {}", reg, e);
+                }
+            }
+        };
+
+        let pre = read_all(self);
+        write_all(self, &vec![u32::MAX; regs.len()]);
+        let ret = read_all(self);
+        write_all(self, &pre);
+        ret
+    }
+
+    /// Find a registered BAR region which contains the given guest physical address
+    fn find_region(&self, addr: u64) -> Option<MmioRegion> {
+        for region in self.mmio_regions.iter() {
+            if addr >= region.start.raw_value()
+                && addr < region.start.unchecked_add(region.length).raw_value()
+            {
+                return Some(*region);
+            }
+        }
+        None
+    }
+
+    /// Let the adapter map the shared memory of prefetchable BARs into the guest. Without a
+    /// slot manager, every BAR access goes through TLPs.
+    pub fn set_slot_manager(&mut self, manager: Box<dyn MemorySlotManager>) {
+        self.slot_manager = Some(manager);
+    }
+
+    /// Let the adapter register the doorbells of the device as ioeventfds. Without an
+    /// ioevent manager, the writes to the doorbells still kick the model through
+    /// [`PciAdapter::bar_mmio_write`].
+    pub fn set_ioevent_manager(&mut self, manager: Box<dyn IoEventManager>) {
+        self.ioevent_manager = Some(manager);
+        self.route_doorbells();
+    }
+
+    /// The kicks of the model and the index of the doorbell at `addr` in `region`, if any.
+    fn doorbell(&self, region: &MmioRegion, addr: u64) -> Option<(Sender<usize>, usize)> {
+        let doorbells = self.doorbells.as_ref()?;
+        let index = doorbells.find(
+            region.bar_reg.checked_sub(BAR0_REG)?,
+            addr - region.start.raw_value(),
+        )?;
+        Some((doorbells.kicks(), index))
+    }
+
+    /// Register the doorbells at the BARs as they are placed now, replacing the registrations
+    /// made before.
+    fn route_doorbells(&mut self) {
+        let (doorbells, manager) = match (self.doorbells.as_ref(), self.ioevent_manager.as_mut()) {
+            (Some(doorbells), Some(manager)) => (doorbells, manager),
+            _ => return,
+        };
+
+        if self.doorbell_events.is_none() {
+            let (tx, bdf, kicks) = (self.tx.clone(), self.bdf, doorbells.kicks());
+            let kick = move |index| {
+                let _ = tx.send(AdapterMessage::Kick(bdf, kicks.clone(), index));
+            };
+            match DoorbellEvents::new(doorbells.registers().len(), kick) {
+                Ok(events) => self.doorbell_events = Some(events),
+                Err(e) => {
+                    error!("Failed to create the doorbell events: {}", e);
+                    return;
+                }
+            }
+        }
+
+        let events = self.doorbell_events.as_mut().unwrap();
+        events.unregister_all(manager.as_mut());
+        for (index, doorbell) in doorbells.registers().iter().enumerate() {
+            let region = self.mmio_regions.iter().find(|r| {
+                r.bar_reg == BAR0_REG + doorbell.bar && r.type_ != PciBarRegionType::IoRegion
+            });
+            if let Some(region) = region {
+                events.register(
+                    manager.as_mut(),
+                    index,
+                    region.start.raw_value() + doorbell.offset,
+                );
+            }
+        }
+    }
+
+    /// Map the shared memory exported for a prefetchable BAR at the allocated address. The
+    /// region falls back to TLPs when nothing could be mapped.
+    fn map_shared_region(&mut self, region: &mut MmioRegion) {
+        if !region.slot_mapped {
+            return;
+        }
+
+        let bar = region.bar_reg - BAR0_REG;
+        let shared = self
+            .shared_regions
+            .iter()
+            .find(|r| r.bar == bar && r.size as u64 >= region.length)
+            .copied();
+
+        match (shared, self.slot_manager.as_mut()) {
+            (Some(shared), Some(manager)) => {
+                match manager.map_region(region.start.raw_value(), region.length, shared.host_addr)
+                {
+                    Ok(slot) => {
+                        region.mem_slot = Some(slot);
+                        region.host_addr = Some(shared.host_addr);
+                        region.mmap_size = Some(region.length as usize);
+                        return;
+                    }
+                    Err(e) => error!("Failed to map shared memory of BAR{}: {}", bar, e),
+                }
+            }
+            _ => debug!("BAR{} is not backed by shared memory", bar),
+        }
+
+        region.slot_mapped = false;
+    }
+
+    /// What the guest reads from config register `reg_idx`.
+    pub(crate) fn guest_config_read(&self, reg_idx: usize) -> u32 {
+        let value = self.config_read(reg_idx);
+        if value == u32::MAX {
+            return value;
+        }
+        // The bridge knows the state of the link and what it trained at, the models do not.
+        if Some(reg_idx) == self.link_status_reg {
+            if let Ok((state, link)) = self.request(|tx| AdapterMessage::GetLinkState(self.bdf, tx))
+            {
+                let mut status = (value >> 16) as u16 & !(LNKSTA_LT | LNKSTA_DLLLA);
+                if link.is_some() {
+                    status &= !LNKSTA_LINK;
+                }
+                let control = value & 0xffff & !(LNKCTL_RL as u32);
+                return control | ((status | state.link_status(link)) as u32) << 16;
+            }
+        }
+        // The bridge latched the errors the function signaled, the model did not.
+        if Some(reg_idx) == self.devctl_reg {
+            if let Ok(log) = self.error_log() {
+                return log.overlay(value);
+            }
+        }
+        if reg_idx != HEADER_TYPE_REG {
+            return value;
+        }
+
+        // The bridge knows how many functions it serves, the models may not.
+        if self.functions > 1 {
+            value | HEADER_TYPE_MULTI_FUNCTION
+        } else {
+            value & !HEADER_TYPE_MULTI_FUNCTION
+        }
+    }
+
+    /// Probe the BARs and place each of them at the address `allocate` picks, which fails with
+    /// the length of the BAR it cannot place.
+    pub(crate) fn place_bars(
+        &mut self,
+        mut allocate: impl FnMut(&MmioRegion) -> Option<GuestAddress>,
+    ) -> std::result::Result<Vec<MmioRegion>, GuestUsize> {
+        if self.msix.is_none() {
+            self.scan_capabilities();
+        }
+        let mut regions = self.scan_bar();
+        // Only x86 has port I/O, elsewhere the IO BARs stay unassigned.
+        #[cfg(not(target_arch = "x86_64"))]
+        regions.retain(|r| r.type_ != PciBarRegionType::IoRegion);
+        self.mmio_regions.clear();
+
+        for region in regions.iter_mut() {
+            region.start = allocate(region).ok_or(region.length)?;
+            if region.type_ == PciBarRegionType::Memory64BitRegion {
+                self.config_write_u32(region.bar_reg + 1, (region.start.raw_value() >> 32) as u32);
+            }
+            self.config_write_u32(region.bar_reg, region.start.raw_value() as u32);
+            self.map_shared_region(region);
+
+            debug!(
+                "allocate BAR reg{}; address: {:#x}; region_type: {}",
+                region.bar_reg,
+                region.start.raw_value(),
+                region.type_ as u8
+            );
+            self.mmio_regions.push(*region);
+        }
+
+        self.route_bars();
+        self.route_doorbells();
+        Ok(regions)
+    }
+
+    /// Remove the memory slot of a region, if any.
+    fn unmap_shared_region(&mut self, region: &mut MmioRegion) {
+        if let (Some(slot), Some(manager)) = (region.mem_slot.take(), self.slot_manager.as_mut()) {
+            if let Err(e) = manager.unmap_region(slot) {
+                error!("Failed to unmap memory slot {}: {}", slot, e);
+            }
+        }
+    }
+
+    /// Probe the size of the expansion ROM, the ROM is always mapped by memory read TLPs.
+    /// The region of the expansion ROM, given what the ROM BAR reads after writing all 1s.
+    fn scan_rom(size: u32) -> Option<MmioRegion> {
+        let size = size & ROM_ADDRESS_MASK;
+        if size == 0 {
+            return None;
+        }
+
+        Some(MmioRegion {
+            start: GuestAddress(0),
+            length: (!size).wrapping_add(1) as u64,
+            type_: PciBarRegionType::Memory32BitRegion,
+            bar_reg: ROM_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        })
+    }
+
+    /// Find the MSI-X capability, the registers of the PCI Express capability and the AER
+    /// capability.
+    fn scan_capabilities(&mut self) {
+        if self.config_read(STATUS_REG) & STATUS_CAP_LIST == 0 {
+            return;
+        }
+
+        let mut offset = self.config_read(CAP_PTR_REG) & 0xfc;
+        // Bound the walk, a broken device may link its capabilities into a loop.
+        for _ in 0..MAX_CAPS {
+            if offset == 0 {
+                break;
+            }
+
+            let reg = (offset >> 2) as usize;
+            let header = self.config_read(reg);
+            match (header & 0xff) as u8 {
+                PCI_CAP_ID_MSIX => {
+                    let cap = MsixCap::new(
+                        reg,
+                        [header, self.config_read(reg + 1), self.config_read(reg + 2)],
+                    );
+                    self.msix = Some(cap);
+                    self.msix_control = (header >> 16) as u16;
+                    let _ = self.tx.send(AdapterMessage::SetMsixTable(
+                        self.bdf,
+                        cap.table_size,
+                        self.msix_control,
+                    ));
+                }
+                PCI_CAP_ID_EXP => {
+                    self.link_status_reg = Some(reg + LNKCTL_DW);
+                    self.devctl_reg = Some(reg + DEVCTL_DW);
+                    self.sync_payload_sizes();
+                    self.sync_completion_timeout();
+                    self.train_link();
+                }
+                _ => (),
+            }
+
+            offset = (header >> 8) & 0xfc;
+        }
+    }
+
+    /// Tell the bridge the Max Payload Size and Max Read Request Size in Device Control.
+    fn sync_payload_sizes(&self) {
+        if let Some(reg) = self.devctl_reg {
+            let (mps, mrrs) = payload_sizes(self.config_read(reg));
+            let _ = self
+                .tx
+                .send(AdapterMessage::SetPayloadSizes(self.bdf, mps, mrrs));
+        }
+    }
+
+    /// Tell the bridge the completion timeout in Device Control 2.
+    fn sync_completion_timeout(&self) {
+        if let Some(reg) = self.devctl_reg {
+            let timeout =
+                CompletionTimeout::from_devctl2(self.config_read(reg - DEVCTL_DW + DEVCTL2_DW));
+            let _ = self
+                .tx
+                .send(AdapterMessage::SetFunctionTimeout(self.bdf, timeout));
+        }
+    }
+
+    /// Negotiate the link with the speed and width in Link Capabilities, no faster than the
+    /// Target Link Speed in Link Control 2, see [`crate::ltssm`].
+    fn train_link(&self) {
+        let reg = match self.link_status_reg {
+            Some(reg) => reg,
+            None => return,
+        };
+        let lnkcap = self.config_read(reg - 1);
+        let speed = match LinkSpeed::from_code(lnkcap & 0xf) {
+            Some(speed) => speed,
+            None => return debug!("Link of {:#x} has no speed", self.bdf),
+        };
+        let device = Link::new(speed, ((lnkcap >> 4) & 0x3f).max(1) as u8);
+        let target = LinkSpeed::from_code(self.config_read(reg - LNKCTL_DW + LNKCTL2_DW) & 0xf);
+        if let Err(e) =
+            self.request(|tx| AdapterMessage::NegotiateLink(self.bdf, device, target, tx))
+        {
+            error!("Failed to train the link of {:#x}: {}", self.bdf, e);
+        }
+    }
+
+    /// Scan all of the six BAR and execute the callback for them.
+    pub fn scan_bar(&mut self) -> Vec<MmioRegion> {
+        let mut regs: Vec<usize> = (BAR0_REG..BAR0_REG + NUM_BAR_REGS).collect();
+        regs.push(ROM_REG);
+        let sizes = self.detect_bars(&regs);
+        let mut regions = Self::bar_regions(&sizes);
+
+        if let Some(region) = Self::scan_rom(sizes[NUM_BAR_REGS]) {
+            regions.push(region);
+        }
+
+        regions
+    }
+
+    /// The regions of the BARs, given what the six BARs read after writing all 1s.
+    fn bar_regions(sizes: &[u32]) -> Vec<MmioRegion> {
+        use PciBarRegionType::*;
+
+        let mut regions = vec![];
+        let mut bar_reg = BAR0_REG;
+
+        while bar_reg < BAR0_REG + NUM_BAR_REGS {
+            let lsb_size: u32 = sizes[bar_reg - BAR0_REG];
+            let region_size: u64;
+            let mut slot_mapped = false;
+            let mut is_64bit = false;
+
+            if lsb_size == 0 {
+                bar_reg += 1;
+                continue;
+            }
+
+            let region_type = if lsb_size & 0x1 == 1 {
+                IoRegion
+            } else if (lsb_size >> 1) & 0x3 == 0x2 {
+                Memory64BitRegion
+            } else {
+                Memory32BitRegion
+            };
+
+            let prefetchable = lsb_size & 0b1000 != 0;
+
+            match region_type {
+                Memory64BitRegion => {
+                    let msb_size = sizes.get(bar_reg + 1 - BAR0_REG).copied().unwrap_or(0);
+                    region_size = (!(((msb_size as u64) << 32) | (lsb_size as u64 & 0xffff_fff0)))
+                        .wrapping_add(1);
+                    slot_mapped = prefetchable;
+                    is_64bit = true;
+                }
+                Memory32BitRegion => {
+                    region_size = (!(lsb_size & 0xffff_fff0)).wrapping_add(1) as u64;
+                    slot_mapped = prefetchable;
+                }
+                IoRegion => {
+                    // A BAR decoding 16 bits of IO space reads the upper half back as 0.
+                    let mut mask = lsb_size & 0xffff_fffc;
+                    if mask >> 16 == 0 {
+                        mask |= 0xffff_0000;
+                    }
+                    region_size = (!mask).wrapping_add(1) as u64;
+                }
+            }
+
+            regions.push(MmioRegion {
+                start: GuestAddress(0),
+                length: region_size,
                 type_: region_type,
                 bar_reg,
                 mem_slot: None,
                 host_addr: None,
                 mmap_size: None,
-                slot_mapped,
+                slot_mapped,
+            });
+
+            bar_reg += if is_64bit { 2 } else { 1 };
+        }
+
+        regions
+    }
+
+    /// Change the time the bridge waits for the completion of every following non-posted
+    /// transaction. Reads which time out return all 1s. The functions whose guest programmed
+    /// a completion timeout range or disabled it in Device Control 2 keep that instead.
+    pub fn set_completion_timeout(&self, timeout: Duration) {
+        let _ = self.tx.send(AdapterMessage::SetCompletionTimeout(timeout));
+    }
+
+    /// Let the bridge record every TLP, error and state change into `log`.
+    pub fn set_event_log(&self, log: EventLog) {
+        let _ = self.tx.send(AdapterMessage::SetEventLog(log));
+    }
+
+    /// Let the bridge answer the PTM Requests of the devices with the time of `source` rather
+    /// than the clock of the adapter, e.g. the time of the guest, see [`crate::ptm`].
+    pub fn set_ptm_time_source(&self, source: Box<dyn PtmTimeSource>) {
+        let _ = self.tx.send(AdapterMessage::SetPtmTimeSource(source));
+    }
+
+    /// Let the bridge deliver the MSIs raised by the device through `sink`. MSIs are dropped
+    /// until a sink is set.
+    pub fn set_msi_sink(&self, sink: Box<dyn MsiSink>) {
+        let _ = self.tx.send(AdapterMessage::SetMsiSink(sink));
+    }
+
+    /// Let the bridge service the DMA of the device from `memory`. Device reads are completed
+    /// with UR until guest memory is set. A `GuestMemoryAtomic` keeps the DMA working while the
+    /// VMM hot-adds or resizes guest RAM.
+    pub fn set_dma_memory(&self, memory: Box<dyn DmaMemory>) {
+        let _ = self.tx.send(AdapterMessage::SetDmaMemory(memory));
+    }
+
+    /// Translate every DMA of the device by `translator`, e.g. a vIOMMU, before accessing
+    /// guest memory.
+    pub fn set_dma_translator(&self, translator: Box<dyn DmaTranslator>) {
+        let _ = self.tx.send(AdapterMessage::SetDmaTranslator(translator));
+    }
+
+    /// Let `agent` answer the ATS translation requests of the device. Translation requests are
+    /// completed with UR until an agent is set.
+    pub fn set_translation_agent(&self, agent: Box<dyn TranslationAgent>) {
+        let _ = self.tx.send(AdapterMessage::SetTranslationAgent(agent));
+    }
+
+    /// Show every following TLP between the bridge and the devices to `observer`, in both
+    /// directions. Observers are called in the order they are added.
+    pub fn add_tlp_observer(&self, observer: Box<dyn TlpObserver>) {
+        let _ = self.tx.send(AdapterMessage::AddTlpObserver(observer));
+    }
+
+    /// Let `handler` serve the page requests of the device. Every page request group is
+    /// answered with Response Failure until a handler is set.
+    pub fn set_page_request_handler(&self, handler: Box<dyn PageRequestHandler>) {
+        let _ = self.tx.send(AdapterMessage::SetPageRequestHandler(handler));
+    }
+
+    /// Let `handler` take the messages the devices route to the root complex. They count as
+    /// errors until a handler is set.
+    pub fn set_message_handler(&self, handler: Box<dyn MessageHandler>) {
+        let _ = self.tx.send(AdapterMessage::SetMessageHandler(handler));
+    }
+
+    /// Send a message with the requester ID of the bridge, routed by `message.routing`, see
+    /// [`crate::message`]. A local message goes to the function of the adapter. Fails with
+    /// `InvalidBdf` for an ID and with UR for an address no function claims.
+    pub fn send_message(&self, message: MessageExtra, data: Option<Vec<u32>>) -> Result<()> {
+        let tlp = match data {
+            Some(dw) => TlpBuilder::message_data(message).data(dw).build(),
+            None => TlpBuilder::message(message).build(),
+        };
+        self.request(|tx| AdapterMessage::SendMessage(self.bdf, tlp, tx))
+    }
+
+    /// Invalidate the translations the device cached for the untranslated range of `size`
+    /// bytes at `addr`, `size` is a power of two. Blocks until the device acknowledges the
+    /// invalidation, which is only given up when the device disconnects.
+    pub fn invalidate_ats(&self, addr: u64, size: u64) -> Result<()> {
+        self.request(|tx| AdapterMessage::AtsInvalidate(self.bdf, addr, size, tx))
+    }
+
+    /// Forward a change of the vIOMMU mappings of the device to it, see [`IommuEvent`]. The
+    /// translations the device cached for an unmapped or invalidated range are invalidated
+    /// before it returns, so the device never uses a stale translation afterwards. Fails with
+    /// [`PciAdapterError::Unsupported`] unless the device does ATS.
+    pub fn notify_iommu(&self, event: IommuEvent) -> Result<()> {
+        self.request(|tx| AdapterMessage::NotifyIommu(self.bdf, event, tx))?;
+
+        match event {
+            IommuEvent::Map { .. } => Ok(()),
+            IommuEvent::Unmap { iova, size } | IommuEvent::Invalidate { iova, size } => {
+                for (addr, size) in invalidation_ranges(iova, size) {
+                    self.invalidate_ats(addr, size)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Choose what happens to outstanding transactions when the lane goes down.
+    pub fn set_disconnect_policy(&self, policy: DisconnectPolicy) {
+        let _ = self.tx.send(AdapterMessage::SetDisconnectPolicy(policy));
+    }
+
+    /// Choose how config requests completed with Configuration Request Retry Status are
+    /// retried.
+    pub fn set_crs_policy(&self, policy: CrsPolicy) {
+        let _ = self.tx.send(AdapterMessage::SetCrsPolicy(policy));
+    }
+
+    /// Attach a new lane after the previous one went down, e.g. once a transport
+    /// re-established the connection to its peer. The features are negotiated again since the
+    /// peer may be a different device.
+    pub fn reconnect(&self, lane: PciLane, features: DeviceFeatures) {
+        if let Some(cache) = self.config_cache.as_ref() {
+            cache.clear();
+        }
+        let _ = self.tx.send(AdapterMessage::Reconnect(lane, features));
+    }
+
+    /// What a transport needs to attach the lanes of reconnecting peers from a thread of its
+    /// own.
+    pub(crate) fn reconnector(&self) -> Reconnector {
+        Reconnector {
+            tx: self.tx.clone(),
+            alive: self.alive.clone(),
+        }
+    }
+
+    /// Whether the device model of the function is alive and the lane is up. A panic of the
+    /// model is reported as [`PciAdapterError::DevicePanicked`], also by every later request.
+    pub fn health(&self) -> Result<()> {
+        self.request(|tx| AdapterMessage::Health(self.bdf, tx))
+    }
+
+    /// The state of the LTSSM of the link of the device, see [`crate::ltssm`].
+    pub fn link_state(&self) -> Result<LtssmState> {
+        self.request(|tx| AdapterMessage::GetLinkState(self.bdf, tx))
+            .map(|(state, _)| state)
+    }
+
+    /// The speed and width the link of the device trained at, `None` until the adapter finds
+    /// the PCI Express capability of the device.
+    pub fn negotiated_link(&self) -> Result<Option<Link>> {
+        self.request(|tx| AdapterMessage::GetLinkState(self.bdf, tx))
+            .map(|(_, link)| link)
+    }
+
+    /// Give the bridge the ACS registers of the downstream port above the function, after the
+    /// guest changed them, see [`crate::acs`].
+    pub fn set_acs(&self, port: &AcsPort) {
+        let _ = self.tx.send(AdapterMessage::SetAcs(self.bdf, *port));
+    }
+
+    /// The errors the function reported and the guest did not clear yet, see [`crate::aer`].
+    pub fn error_log(&self) -> Result<AerLog> {
+        self.request(|tx| AdapterMessage::GetErrorLog(self.bdf, tx))
+    }
+
+    /// Take the link of the device down, e.g. to simulate a surprise down, or train it again.
+    /// The requests outstanding on the link fail as it goes down, see
+    /// [`AdapterEvent::LinkDown`].
+    pub fn set_link_up(&self, up: bool) -> Result<()> {
+        self.request(|tx| AdapterMessage::SetLinkUp(self.bdf, up, tx))
+    }
+
+    /// Retrain the link of the device through Recovery. Fails with
+    /// [`PciAdapterError::LinkDown`] if the link is down.
+    pub fn retrain_link(&self) -> Result<()> {
+        self.request(|tx| AdapterMessage::RetrainLink(self.bdf, tx))
+    }
+
+    /// Park the link of the device in L1 until the next TLP crosses it. Fails with
+    /// [`PciAdapterError::LinkDown`] if the link is down.
+    pub fn enter_l1(&self) -> Result<()> {
+        self.request(|tx| AdapterMessage::EnterL1(self.bdf, tx))
+    }
+
+    /// Plug `device` in at `bdf` behind the running bridge, e.g. a device hot-added by the
+    /// guest. The device must support the features negotiated with the device models already
+    /// running. Returns the adapter of the new function, whose BARs are still to be allocated.
+    ///
+    /// Nothing tells the guest about the new device, the hypervisor signals it through the
+    /// hot-plug slot the device sits in as the [`AdapterEvent::Attached`] events come, see
+    /// [`HotplugSlot::on_event`].
+    pub fn hotplug(
+        &self,
+        bdf: u16,
+        device: Box<dyn PciSimDevice + Send + Sync>,
+    ) -> Result<PciAdapter> {
+        let exports = Exports::of(device.as_ref());
+        self.request(|tx| AdapterMessage::Attach(bdf, device, tx))?;
+
+        Ok(PciAdapter {
+            shared_regions: exports.shared_regions,
+            doorbells: exports.doorbells,
+            ..PciAdapter::new_handle(
+                bdf,
+                self.tx.clone(),
+                self.events.clone(),
+                self.alive.clone(),
+                self.replies.clone(),
+                self.segment,
+                self.config_cache.as_ref().map(ConfigCache::renew),
+                &self.bus_resets,
+            )
+        })
+    }
+
+    /// The adapter of function `bdf` of the bridge reached through `tx`, alone in its device
+    /// and with nothing allocated, mapped or probed yet.
+    #[allow(clippy::too_many_arguments)]
+    fn new_handle(
+        bdf: u16,
+        tx: Sender<AdapterMessage>,
+        events: Receiver<AdapterEvent>,
+        alive: Receiver<()>,
+        replies: Arc<ReplyPool>,
+        segment: u16,
+        config_cache: Option<ConfigCache>,
+        bus_resets: &Arc<AtomicU64>,
+    ) -> PciAdapter {
+        PciAdapter {
+            segment,
+            tx,
+            events,
+            mmio_regions: vec![],
+            shared_regions: vec![],
+            slot_manager: None,
+            doorbells: None,
+            ioevent_manager: None,
+            doorbell_events: None,
+            rom_enabled: false,
+            msix: None,
+            msix_control: 0,
+            bus_resets: bus_resets.clone(),
+            bus_resets_seen: bus_resets.load(Ordering::SeqCst),
+            bdf,
+            functions: 1,
+            handle: None,
+            replies,
+            alive,
+            config_cache,
+            vfs: vec![],
+            vf_regions: vec![],
+            virtual_function: false,
+            intx_line: None,
+            link_status_reg: None,
+            devctl_reg: None,
+        }
+    }
+
+    /// Place the BARs in the ranges of `allocator` and route the INTx pin to one of its GSIs,
+    /// as [`PciDevice::allocate_bars`] does with a `SystemAllocator`.
+    pub fn allocate_bars_with(
+        &mut self,
+        allocator: &mut dyn AddressAllocator,
+    ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
+    {
+        use PciBarRegionType::*;
+
+        // The BARs of a VF read 0, they are placed by the PF in its VF BARs.
+        if self.virtual_function {
+            if self.msix.is_none() {
+                self.scan_capabilities();
+            }
+            self.route_bars();
+            return Ok(self
+                .mmio_regions
+                .iter()
+                .map(|r| (r.start, r.length, r.type_))
+                .collect());
+        }
+
+        let regions = self
+            .place_bars(|region| match region.type_ {
+                Memory64BitRegion => allocator.allocate_mmio(region.length, 0x10),
+                Memory32BitRegion => allocator.allocate_mmio_hole(region.length, 0x10),
+                IoRegion => allocator.allocate_io(region.length, 0x4),
+            })
+            .map_err(PciDeviceError::IoAllocationFailed)?;
+
+        self.route_intx(allocator);
+        Ok(regions
+            .iter()
+            .map(|r| (r.start, r.length, r.type_))
+            .collect())
+    }
+
+    /// Free the BARs placed by [`PciAdapter::allocate_bars_with`].
+    pub fn free_bars_with(
+        &mut self,
+        allocator: &mut dyn AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        // The PF frees the BARs of its VFs in [`PciAdapter::disable_vfs`].
+        if self.virtual_function {
+            return Ok(());
+        }
+
+        if let (Some(events), Some(manager)) =
+            (self.doorbell_events.as_mut(), self.ioevent_manager.as_mut())
+        {
+            events.unregister_all(manager.as_mut());
+        }
+        let mut regions = std::mem::take(&mut self.mmio_regions);
+
+        for region in regions.iter_mut() {
+            self.unmap_shared_region(region);
+
+            match region.type_ {
+                PciBarRegionType::IoRegion => allocator.free_io(region.start, region.length),
+                PciBarRegionType::Memory32BitRegion => {
+                    allocator.free_mmio_hole(region.start, region.length)
+                }
+                PciBarRegionType::Memory64BitRegion => {
+                    allocator.free_mmio(region.start, region.length)
+                }
+            }
+        }
+
+        self.mmio_regions = regions;
+        self.route_memory(vec![]);
+        Ok(())
+    }
+
+    /// Unplug the function of the adapter: its BARs are freed, the requests already issued
+    /// complete or time out, then its lane is closed. Later requests of the adapter fail as
+    /// disconnected. The bridge keeps running, so the first adapter of the bridge still stops
+    /// and joins it afterwards.
+    pub fn unplug(&mut self, allocator: &mut dyn AddressAllocator) -> Result<()> {
+        let _ = self.free_bars_with(allocator);
+        self.mmio_regions.clear();
+        self.request(|tx| AdapterMessage::Detach(self.bdf, tx))
+    }
+
+    /// Enable `num_vfs` VFs of the SR-IOV capability of the function, like a PF driver does:
+    /// the VF BARs are sized and allocated for all VFs, then NumVFs, VF Enable and VF MSE are
+    /// written. Returns the adapter of each VF, in routing ID order, with its BARs already
+    /// placed and routed. The model must serve the VFs, see [`PciSriovDevice`].
+    pub fn enable_vfs(
+        &mut self,
+        num_vfs: u16,
+        allocator: &mut dyn AddressAllocator,
+    ) -> Result<Vec<PciAdapter>> {
+        let sriov = self.find_extended(SriovCapability::ID);
+        let reg = |body| sriov.unwrap_or(0) + 1 + body;
+        let total_vfs = match sriov {
+            Some(_) => self.try_config_read(reg(SriovCapability::TOTAL_VFS))? >> 16,
+            None => 0,
+        };
+        if !self.vfs.is_empty() || num_vfs == 0 || num_vfs as u32 > total_vfs {
+            return Err(PciAdapterError::InvalidVfCount(num_vfs));
+        }
+
+        let offsets = self.try_config_read(reg(SriovCapability::VF_OFFSET))?;
+        let (first, stride) = (offsets & 0xffff, offsets >> 16);
+        let bdfs: Vec<u16> = (0..num_vfs as u32)
+            .map(|i| self.bdf.wrapping_add((first + i * stride) as u16))
+            .collect();
+
+        // Each VF BAR holds the BARs of all VFs back to back, aligned to the size of one.
+        let bar0 = reg(SriovCapability::VF_BAR0);
+        let sizes = self.detect_bars(&(bar0..bar0 + NUM_BAR_REGS).collect::<Vec<_>>());
+        let mut regions = Self::bar_regions(&sizes);
+        regions.retain(|r| r.type_ != PciBarRegionType::IoRegion);
+        for i in 0..regions.len() {
+            let region = &mut regions[i];
+            let length = region.length * num_vfs as u64;
+            let start = match region.type_ {
+                PciBarRegionType::Memory64BitRegion => {
+                    allocator.allocate_mmio(length, region.length)
+                }
+                _ => allocator.allocate_mmio_hole(length, region.length),
+            };
+            region.start = match start {
+                Some(start) => start,
+                None => {
+                    Self::free_vf_regions(&regions[..i], num_vfs, allocator);
+                    return Err(PciAdapterError::OutOfAddressSpace(length));
+                }
+            };
+            region.slot_mapped = false;
+
+            let bar = bar0 + region.bar_reg - BAR0_REG;
+            if region.type_ == PciBarRegionType::Memory64BitRegion {
+                self.config_write_u32(bar + 1, (region.start.raw_value() >> 32) as u32);
+            }
+            self.config_write_u32(bar, region.start.raw_value() as u32);
+        }
+
+        let control = reg(SriovCapability::CONTROL);
+        self.try_config_write(reg(SriovCapability::NUM_VFS), 0, &num_vfs.to_le_bytes())?;
+        let enable = SriovCapability::VF_ENABLE | SriovCapability::VF_MSE;
+        let value = self.try_config_read(control)? | enable;
+        self.try_config_write(control, 0, &value.to_le_bytes())?;
+        self.request(|tx| AdapterMessage::AddFunctions(self.bdf, bdfs.clone(), tx))?;
+
+        let vfs = bdfs
+            .iter()
+            .enumerate()
+            .map(|(i, &bdf)| {
+                let mmio_regions = regions
+                    .iter()
+                    .map(|r| MmioRegion {
+                        start: r.start.unchecked_add(r.length * i as u64),
+                        ..*r
+                    })
+                    .collect();
+                let vf = PciAdapter {
+                    mmio_regions,
+                    virtual_function: true,
+                    ..PciAdapter::new_handle(
+                        bdf,
+                        self.tx.clone(),
+                        self.events.clone(),
+                        self.alive.clone(),
+                        self.replies.clone(),
+                        self.segment,
+                        self.config_cache.as_ref().map(ConfigCache::renew),
+                        &self.bus_resets,
+                    )
+                };
+                vf.route_bars();
+                vf
+            })
+            .collect();
+
+        self.vfs = bdfs;
+        self.vf_regions = regions;
+        Ok(vfs)
+    }
+
+    /// Disable the VFs enabled by [`PciAdapter::enable_vfs`]: the VFs are unplugged once their
+    /// outstanding transactions are done, then VF Enable is cleared and the VF BARs are freed.
+    /// Later requests of the adapters of the VFs fail.
+    pub fn disable_vfs(&mut self, allocator: &mut dyn AddressAllocator) -> Result<()> {
+        let sriov = match self.find_extended(SriovCapability::ID) {
+            Some(sriov) if !self.vfs.is_empty() => sriov,
+            _ => return Err(PciAdapterError::InvalidVfCount(0)),
+        };
+
+        let num_vfs = self.vfs.len() as u16;
+        for bdf in std::mem::take(&mut self.vfs) {
+            self.request(|tx| AdapterMessage::Detach(bdf, tx))?;
+        }
+
+        let control = sriov + 1 + SriovCapability::CONTROL;
+        let enable = SriovCapability::VF_ENABLE | SriovCapability::VF_MSE;
+        let value = self.try_config_read(control)? & !enable;
+        self.try_config_write(control, 0, &value.to_le_bytes())?;
+        self.try_config_write(sriov + 1 + SriovCapability::NUM_VFS, 0, &[0, 0])?;
+
+        let regions = std::mem::take(&mut self.vf_regions);
+        Self::free_vf_regions(&regions, num_vfs, allocator);
+        Ok(())
+    }
+
+    /// Free the VF BARs holding the BARs of `num_vfs` VFs.
+    fn free_vf_regions(regions: &[MmioRegion], num_vfs: u16, allocator: &mut dyn AddressAllocator) {
+        for region in regions {
+            let length = region.length * num_vfs as u64;
+            match region.type_ {
+                PciBarRegionType::Memory64BitRegion => allocator.free_mmio(region.start, length),
+                _ => allocator.free_mmio_hole(region.start, length),
+            }
+        }
+    }
+
+    /// Walk the extended capability list for the capability `id`, returns its first register.
+    fn find_extended(&self, id: u16) -> Option<usize> {
+        let mut reg = PCI_CONFIG_REGS;
+        // Bound the walk, a broken device may link its capabilities into a loop.
+        for _ in 0..MAX_CAPS {
+            let header = self.config_read(reg);
+            if header & 0xffff == id as u32 {
+                return Some(reg);
+            }
+
+            reg = (header >> 20) as usize / 4;
+            if reg < PCI_CONFIG_REGS {
+                break;
+            }
+        }
+        None
+    }
+
+    /// Function Level Reset of the function of the adapter. The model finishes the requests
+    /// already sent to it and then resets, see [`PciSimDevice::on_reset`].
+    pub fn function_reset(&self) -> Result<()> {
+        self.reset(ResetKind::FunctionLevel(self.bdf))
+    }
+
+    /// Hot reset of the link of the function, which resets every function of its device
+    /// model. The adapters of the other functions keep their config cache.
+    pub fn hot_reset(&self) -> Result<()> {
+        self.reset(ResetKind::Hot)
+    }
+
+    /// Secondary Bus Reset of the bridge, e.g. as the guest set it in the Bridge Control
+    /// register of the bridge the hypervisor emulates above the devices. Every device model
+    /// behind the bridge resets like on [`PciAdapter::hot_reset`], and the bridge forgets the
+    /// MSI-X tables, the command registers and the asserted INTx pins of their functions. The
+    /// config caches of all adapters of the bridge are dropped. The BARs stay allocated, the
+    /// guest programs them again.
+    pub fn secondary_bus_reset(&mut self) -> Result<()> {
+        self.request(AdapterMessage::BusReset)?;
+        if let Some(cache) = self.config_cache.as_ref() {
+            cache.clear_bus();
+        }
+        self.bus_resets.fetch_add(1, Ordering::SeqCst);
+        self.sync_bus_resets();
+        Ok(())
+    }
+
+    /// Whether the guest enabled the expansion ROM and no bus reset disabled it since.
+    fn rom_enabled(&self) -> bool {
+        self.rom_enabled && self.bus_resets_seen == self.bus_resets.load(Ordering::SeqCst)
+    }
+
+    /// Take the secondary bus resets done through any adapter of the bridge into the ROM
+    /// enable and the MSI-X message control of this one.
+    fn sync_bus_resets(&mut self) {
+        let bus_resets = self.bus_resets.load(Ordering::SeqCst);
+        if self.bus_resets_seen != bus_resets {
+            self.bus_resets_seen = bus_resets;
+            self.rom_enabled = false;
+            // Only the table size of the message control survives a reset.
+            self.msix_control &= 0x7ff;
+        }
+    }
+
+    fn reset(&self, kind: ResetKind) -> Result<()> {
+        self.request(|tx| AdapterMessage::Reset(self.bdf, kind, tx))?;
+        // The config header goes back to its initial values.
+        if let Some(cache) = self.config_cache.as_ref() {
+            cache.clear();
+        }
+        Ok(())
+    }
+
+    /// Save the state of the function for migration, see [`crate::snapshot`]. The guest is
+    /// expected to be paused. The model is quiesced while it saves its state, see
+    /// [`PciSimDevice::state_save`], requests still outstanding are saved to be sent again on
+    /// the destination.
+    pub fn snapshot(&self) -> Result<AdapterSnapshot> {
+        let device = self.request(|tx| AdapterMessage::SaveModel(self.bdf, tx))?;
+        let (msix, pending) = self.request(|tx| AdapterMessage::SaveFunction(self.bdf, tx))?;
+
+        Ok(AdapterSnapshot {
+            bdf: self.bdf,
+            bars: self
+                .mmio_regions
+                .iter()
+                .map(|region| BarSnapshot {
+                    bar_reg: region.bar_reg,
+                    type_: region.type_,
+                    start: region.start.raw_value(),
+                    length: region.length,
+                    slot_mapped: region.slot_mapped,
+                })
+                .collect(),
+            rom_enabled: self.rom_enabled(),
+            msix,
+            pending,
+            device: device.unwrap_or_default(),
+        })
+    }
+
+    /// Load a snapshot into the adapter of a freshly started device model, in place of
+    /// [`PciDevice::allocate_bars`]. The hypervisor reserves the BAR ranges of the snapshot in
+    /// its allocator itself. The state of the model is loaded first, so the BAR and MSI-X
+    /// registers read back from its configuration space are the saved ones.
+    pub fn restore(&mut self, snapshot: &AdapterSnapshot) -> Result<()> {
+        let state = snapshot.device.clone();
+        self.request(|tx| AdapterMessage::RestoreModel(self.bdf, state, tx))?;
+
+        for mut region in std::mem::take(&mut self.mmio_regions) {
+            self.unmap_shared_region(&mut region);
+        }
+        for bar in snapshot.bars.iter() {
+            let mut region = MmioRegion {
+                start: GuestAddress(bar.start),
+                length: bar.length,
+                type_: bar.type_,
+                bar_reg: bar.bar_reg,
+                slot_mapped: bar.slot_mapped,
+                mem_slot: None,
+                host_addr: None,
+                mmap_size: None,
+            };
+            self.map_shared_region(&mut region);
+            self.mmio_regions.push(region);
+        }
+        self.route_bars();
+
+        if let Some(cache) = self.config_cache.as_ref() {
+            cache.clear();
+        }
+        self.sync_bus_resets();
+        self.rom_enabled = snapshot.rom_enabled;
+        self.msix = None;
+        if let Some(msix) = snapshot.msix.as_ref() {
+            self.scan_capabilities();
+            self.msix_control = msix.control;
+        }
+        let _ = self.tx.send(AdapterMessage::RestoreFunction(
+            self.bdf,
+            snapshot.msix.clone(),
+            snapshot.pending.clone(),
+        ));
+        Ok(())
+    }
+
+    /// Counters of the traffic of the bridge, which is shared by all functions behind it.
+    pub fn stats(&self) -> Result<AdapterStats> {
+        self.request(AdapterMessage::GetStats)
+    }
+
+    /// The features negotiated with the attached device.
+    pub fn features(&self) -> Result<DeviceFeatures> {
+        self.request(AdapterMessage::GetFeatures)
+    }
+
+    /// The GSI the INTx pin of the function is routed to, as the guest reads it in the
+    /// Interrupt Line register. Set by [`PciDevice::allocate_bars`] when the model reports an
+    /// Interrupt Pin, the [`MsiSink`] is then told the GSI along with the level of the pin.
+    pub fn intx_line(&self) -> Option<u32> {
+        self.intx_line
+    }
+
+    /// Receiver of the lane state changes reported by the bridge.
+    pub fn events(&self) -> Receiver<AdapterEvent> {
+        self.events.clone()
+    }
+
+    /// Requester and completer ID of the function the adapter stands for.
+    pub fn bdf(&self) -> u16 {
+        self.bdf
+    }
+
+    /// Segment and BDF of the function the adapter stands for, see
+    /// [`PciAdapterBuilder::segment`].
+    pub fn address(&self) -> PciAddress {
+        PciAddress {
+            segment: self.segment,
+            bdf: self.bdf,
+        }
+    }
+
+    /// Function number of the adapter within its device.
+    pub fn function(&self) -> u8 {
+        (self.bdf & 0b111) as u8
+    }
+
+    /// Put the adapter on a peer bus: memory requests of its devices to the memory windows of
+    /// the other adapters on the bus go to their devices and the completions of reads come
+    /// back, see [`PeerBus`]. The windows of the adapter are published on the bus as they are
+    /// routed, see [`PciAdapter::route_memory`]. An adapter is on one bus at a time and leaves
+    /// it when it stops.
+    pub fn join_peer_bus(&self, bus: &PeerBus) {
+        let _ = self
+            .tx
+            .send(AdapterMessage::JoinPeerBus(bus.clone(), self.tx.clone()));
+    }
+
+    /// Route memory requests within `windows` to the function of the adapter, whichever
+    /// function issues them. The memory BARs are routed once they are allocated.
+    pub fn route_memory(&self, windows: Vec<Range<u64>>) {
+        let _ = self.tx.send(AdapterMessage::RouteMemory(self.bdf, windows));
+    }
+
+    /// Route the INTx pin the model reports in its Interrupt Pin register to a GSI and tell the
+    /// guest by the Interrupt Line register. The pins of the functions are wired-OR, so the
+    /// functions on the same pin share its GSI.
+    pub(crate) fn route_intx(&mut self, allocator: &mut dyn AddressAllocator) {
+        let pin = (self.config_read(INTERRUPT_REG) >> 8) as u8;
+        if !(1..=4).contains(&pin) {
+            return;
+        }
+
+        let gsi = match self.request(|sender| AdapterMessage::GetIntxLine(pin, sender)) {
+            Ok(Some(gsi)) => gsi,
+            Ok(None) => match allocator.allocate_irq() {
+                Some(gsi) => {
+                    let _ = self.tx.send(AdapterMessage::SetIntxLine(pin, gsi));
+                    gsi
+                }
+                None => {
+                    error!("No GSI left for INTx pin {}", pin);
+                    return;
+                }
+            },
+            Err(e) => {
+                error!("Failed to route INTx pin {}: {}", pin, e);
+                return;
+            }
+        };
+
+        debug!("route INTx pin {} to GSI {}", pin, gsi);
+        self.config_write(INTERRUPT_REG, 0, &[gsi as u8]);
+        self.intx_line = Some(gsi);
+    }
+
+    /// Route the memory BARs of the function to it.
+    fn route_bars(&self) {
+        let windows = self
+            .mmio_regions
+            .iter()
+            .filter(|r| r.type_ != PciBarRegionType::IoRegion)
+            .map(|r| r.start.raw_value()..r.start.raw_value() + r.length)
+            .collect();
+        self.route_memory(windows);
+    }
+
+    /// Wait for the bridge thread to exit, which only the first adapter of the bridge can do.
+    pub fn join(self) {
+        if let Some(handle) = self.handle {
+            handle.join().unwrap();
+        }
+    }
+
+    /// Shut the bridge and the device models down. Requests already issued still complete or
+    /// time out, later ones fail as disconnected. [`PciAdapter::join`] waits for the shutdown.
+    pub fn stop(&self) {
+        let _ = self.tx.send(AdapterMessage::Exit);
+    }
+
+    pub fn start(device: Box<dyn PciSimDevice + Send + Sync>) -> PciAdapter {
+        PciAdapterBuilder::new().start(device)
+    }
+
+    /// Start a multi-function device with one model per function, `devices[n]` being
+    /// function `n`. All functions sit behind one bridge, which routes the type 0 config
+    /// transactions by function number. Returns the adapter of each function.
+    pub fn start_functions(devices: Vec<Box<dyn PciSimDevice + Send + Sync>>) -> Vec<PciAdapter> {
+        PciAdapterBuilder::new().start_functions(devices)
+    }
+
+    /// Start a small hierarchy of device models behind one bridge thread, each at the given
+    /// BDF. The bridge routes config requests by BDF, memory requests by the BARs of the
+    /// functions and completions by requester ID. Returns the adapter of each function, in
+    /// order.
+    pub fn start_hierarchy(
+        devices: Vec<(u16, Box<dyn PciSimDevice + Send + Sync>)>,
+    ) -> Vec<PciAdapter> {
+        PciAdapterBuilder::new().start_hierarchy(devices)
+    }
+
+    /// Start a model serving `functions` functions on a single lane. The model tells the
+    /// functions apart by the completer ID of the config requests and by the address of the
+    /// memory and IO requests.
+    pub fn start_multi_function(
+        device: Box<dyn PciSimDevice + Send + Sync>,
+        functions: usize,
+    ) -> Vec<PciAdapter> {
+        PciAdapterBuilder::new().start_multi_function(device, functions)
+    }
+}
+
+/// Builder of [`PciAdapter`]s, for when the defaults of [`PciAdapter::start`] do not fit.
+///
+/// By default the segment is 0, the bridge is 00:02.0, the device is 00:03.0, the lanes are
+/// unbounded, the completion timeout is [`DEFAULT_COMPLETION_TIMEOUT`], memory reads are split at
+/// 4096 bytes, the most a request could ask for, and the MSI window is the x86 MSI range on x86_64
+/// and empty elsewhere.
+#[derive(Debug, Clone)]
+pub struct PciAdapterBuilder {
+    segment: u16,
+    bdf: u16,
+    completer: u16,
+    lane_capacity: Option<usize>,
+    multi_lane: Option<MultiLane>,
+    backpressure: BackpressurePolicy,
+    ordering: OrderingPolicy,
+    delay: DelayModel,
+    link: Option<Link>,
+    clock: SimClock,
+    config_cache: Option<Vec<usize>>,
+    completion_timeout: Duration,
+    max_read_request: usize,
+    msi_window: Range<u64>,
+    /// Guest RAM handed to the models connecting with [`crate::shm::connect_shm_mapped`].
+    pub(crate) shared_memory: Vec<GuestRamRegion>,
+    bridge_thread_name: Option<String>,
+    device_thread_name: Option<String>,
+}
+
+impl Default for PciAdapterBuilder {
+    fn default() -> Self {
+        PciAdapterBuilder {
+            segment: 0,
+            bdf: make_bdf(0x0, 0x2, 0x0),
+            completer: make_bdf(0x0, 0x3, 0x0),
+            lane_capacity: None,
+            multi_lane: None,
+            backpressure: BackpressurePolicy::default(),
+            ordering: OrderingPolicy::default(),
+            delay: DelayModel::default(),
+            link: None,
+            clock: SimClock::host(),
+            config_cache: None,
+            completion_timeout: DEFAULT_COMPLETION_TIMEOUT,
+            max_read_request: MAX_READ_REQUEST,
+            msi_window: DEFAULT_MSI_WINDOW,
+            shared_memory: vec![],
+            bridge_thread_name: None,
+            device_thread_name: None,
+        }
+    }
+}
+
+impl PciAdapterBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// PCI segment of the bridge and the devices, which qualifies the requester IDs the
+    /// hypervisor hooks get, see [`PciAddress`].
+    pub fn segment(mut self, segment: u16) -> Self {
+        self.segment = segment;
+        self
+    }
+
+    /// Requester ID of the bridge in the requests it sends.
+    pub fn bdf(mut self, bus: u8, device: u8, function: u8) -> Self {
+        self.bdf = make_bdf(bus, device, function);
+        self
+    }
+
+    /// Completer ID of the device, the functions of a multi-function device share its bus and
+    /// device number.
+    pub fn completer(mut self, bus: u8, device: u8, function: u8) -> Self {
+        self.completer = make_bdf(bus, device, function);
+        self
+    }
+
+    /// Bound the TLPs queued on each lane, so a model flooding the bridge or falling behind
+    /// holds no more than `capacity` TLPs in memory. The models block while their upstream
+    /// lane is full, the bridge follows the [`BackpressurePolicy`].
+    pub fn lane_capacity(mut self, capacity: usize) -> Self {
+        self.lane_capacity = Some(capacity);
+        self
+    }
+
+    /// Stripe the lanes of the models over several channels, so a model moving a lot of data
+    /// by DMA is not held up by a single one, see [`MultiLane`]. The TLPs on the channels count
+    /// as taken off the lane for the [`BackpressurePolicy`] and the flow control credits.
+    pub fn multi_lane(mut self, lanes: MultiLane) -> Self {
+        self.multi_lane = Some(lanes);
+        self
+    }
+
+    /// What the bridge does when the lane of a function is full.
+    pub fn backpressure(mut self, policy: BackpressurePolicy) -> Self {
+        self.backpressure = policy;
+        self
+    }
+
+    /// What the bridge does about the order of the TLPs of the devices.
+    pub fn ordering(mut self, policy: OrderingPolicy) -> Self {
+        self.ordering = policy;
+        self
+    }
+
+    /// Hold back the TLPs crossing the bridge, to test a driver against a slow device.
+    pub fn delay(mut self, model: DelayModel) -> Self {
+        self.delay = model;
+        self
+    }
+
+    /// Limit the throughput between the bridge and each device to what `link` carries, so
+    /// large transfers take the time they take on hardware.
+    pub fn link(mut self, link: Link) -> Self {
+        self.link = Some(link);
+        self
+    }
+
+    /// The clock handed to the device models in [`LaneInfo`], e.g. a manual one so the
+    /// timers of the models only fire as a test moves it. Delays and the link keep the time of
+    /// the host.
+    pub fn clock(mut self, clock: SimClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Answer the reads of the type 0 header from a write-through cache in each adapter, but
+    /// for the `volatile` registers which the device changes on its own, e.g. the status
+    /// register at 1. Writes, [`PciAdapter::reconnect`] and [`PciAdapter::restore`] drop what
+    /// the cache holds.
+    pub fn config_cache(mut self, volatile: &[usize]) -> Self {
+        self.config_cache = Some(volatile.to_vec());
+        self
+    }
+
+    /// Time the bridge waits for a completion, for the functions whose Device Control 2 leaves
+    /// the completion timeout in its default range.
+    pub fn completion_timeout(mut self, timeout: Duration) -> Self {
+        self.completion_timeout = timeout;
+        self
+    }
+
+    /// Largest memory read request of the bridge in bytes, a power of two from 128 to 4096.
+    /// Larger reads are split into several requests whose completions are put back together.
+    /// The requests to a function with a PCI Express capability are also kept within the Max
+    /// Payload Size and Max Read Request Size the guest programmed into its Device Control.
+    pub fn max_read_request_size(mut self, size: usize) -> Self {
+        self.max_read_request = size.clamp(128, MAX_READ_REQUEST).next_power_of_two();
+        self
+    }
+
+    /// The addresses the device writes its MSIs to, which the bridge passes to the
+    /// [`MsiSink`] instead of guest memory. On aarch64 this is the GITS_TRANSLATER register of
+    /// the ITS, which the VMM places.
+    pub fn msi_window(mut self, window: Range<u64>) -> Self {
+        self.msi_window = window;
+        self
+    }
+
+    /// Hand `regions` of guest RAM to the device models which connect with
+    /// [`PciAdapterBuilder::start_shm`], so they move bulk data in and out of guest memory
+    /// directly, see [`GuestMap`].
+    pub fn share_guest_memory(mut self, regions: Vec<GuestRamRegion>) -> Self {
+        self.shared_memory = regions;
+        self
+    }
+
+    pub fn bridge_thread_name(mut self, name: &str) -> Self {
+        self.bridge_thread_name = Some(name.to_string());
+        self
+    }
+
+    /// Name of the device model threads, shared by all of them.
+    pub fn device_thread_name(mut self, name: &str) -> Self {
+        self.device_thread_name = Some(name.to_string());
+        self
+    }
+
+    pub(crate) fn lane(&self) -> (Sender<Tlp>, Receiver<Tlp>) {
+        match self.lane_capacity {
+            Some(capacity) => bounded(capacity),
+            None => unbounded(),
+        }
+    }
+
+    fn spawn<F: FnOnce() + Send + 'static>(name: &Option<String>, f: F) -> JoinHandle<()> {
+        let mut builder = std::thread::Builder::new();
+        if let Some(name) = name {
+            builder = builder.name(name.clone());
+        }
+        builder.spawn(f).expect("failed to spawn thread")
+    }
+
+    /// What a model serving `functions` learns in [`PciSimDevice::on_start`].
+    fn lane_info(&self, functions: Vec<u16>, features: DeviceFeatures) -> LaneInfo {
+        LaneInfo {
+            functions,
+            features: BRIDGE_FEATURES.intersect(&features),
+            lane_capacity: self.lane_capacity,
+            link: self.link,
+            clock: self.clock.clone(),
+        }
+    }
+
+    /// Run a device model on its own thread, which notifies `exits` when it ends. The model
+    /// runs again on the lane handed over with a reset, see [`PciSimBridge::reset`].
+    fn spawn_device(
+        &self,
+        info: LaneInfo,
+        mut device: Box<dyn PciSimDevice + Send + Sync>,
+        lane: PciLane,
+        exits: &Sender<u16>,
+    ) -> ModelThread {
+        let bdf = info.functions[0];
+        let notice = ExitNotice(bdf, exits.clone());
+        let (controls, control_rx) = unbounded::<ModelControl>();
+        let multi_lane = self.multi_lane;
+        let stripe = move |lane| match multi_lane {
+            Some(lanes) => lanes.stripe(lane),
+            None => lane,
+        };
+        let handle = Self::spawn(&self.device_thread_name, move || {
+            let _notice = notice;
+            let mut lane = stripe(lane);
+            device.on_start(&info);
+            loop {
+                device.run(&lane);
+                // The bridge hands the next lane over before it closes the lane.
+                let next = match control_rx.try_recv() {
+                    Ok(ModelControl::Reset(kind, next)) => {
+                        device.on_reset(kind);
+                        next
+                    }
+                    Ok(ModelControl::Save(function, next, sender)) => {
+                        let _ = sender.send(Ok(device.state_save(function)));
+                        next
+                    }
+                    Ok(ModelControl::Restore(function, state, next, sender)) => {
+                        let restored = device.state_restore(function, &state).map_err(|e| {
+                            error!("Failed to restore the state of {:#x}: {}", function, e);
+                            PciAdapterError::InvalidSnapshot
+                        });
+                        let _ = sender.send(restored);
+                        next
+                    }
+                    Err(_) => break,
+                };
+                lane = stripe(next);
+            }
+            device.on_stop();
+        });
+
+        ModelThread {
+            bdf,
+            handle,
+            controls,
+        }
+    }
+
+    pub fn start(self, device: Box<dyn PciSimDevice + Send + Sync>) -> PciAdapter {
+        self.start_functions(vec![device]).pop().unwrap()
+    }
+
+    /// See [`PciAdapter::start_functions`], function `n` is the completer with function
+    /// number `n`.
+    pub fn start_functions(
+        self,
+        devices: Vec<Box<dyn PciSimDevice + Send + Sync>>,
+    ) -> Vec<PciAdapter> {
+        assert!((1..=MAX_FUNCTIONS).contains(&devices.len()));
+
+        let devices = devices
+            .into_iter()
+            .enumerate()
+            .map(|(function, device)| (self.completer & !0b111 | function as u16, device))
+            .collect();
+        self.start_hierarchy(devices)
+    }
+
+    /// See [`PciAdapter::start_hierarchy`], the completer ID set on the builder is not used.
+    pub fn start_hierarchy(
+        self,
+        devices: Vec<(u16, Box<dyn PciSimDevice + Send + Sync>)>,
+    ) -> Vec<PciAdapter> {
+        assert!(!devices.is_empty());
+
+        let (upstream_tx, upstream) = self.lane();
+        let (exits_tx, exits) = unbounded();
+        let mut downstream = vec![];
+        let mut models = vec![];
+        let mut exports = vec![];
+        // The functions share the tags of the bridge, so only what all of them support is used.
+        let features = devices
+            .iter()
+            .map(|(_, device)| device.features())
+            .reduce(|all, f| all.intersect(&f))
+            .unwrap();
+        for (bdf, device) in devices {
+            let (tx, rx) = self.lane();
+            let device_lane = PciLane {
+                tx: upstream_tx.clone(),
+                rx,
+            };
+            downstream.push((bdf, tx));
+            exports.push(Exports::of(device.as_ref()));
+            let info = self.lane_info(vec![bdf], features);
+            models.push(self.spawn_device(info, device, device_lane, &exits_tx));
+        }
+        self.spawn_bridge(
+            (upstream_tx, upstream),
+            downstream,
+            models,
+            (exits_tx, exits),
+            features,
+            exports,
+        )
+    }
+
+    /// See [`PciAdapter::start_multi_function`].
+    pub fn start_multi_function(
+        self,
+        device: Box<dyn PciSimDevice + Send + Sync>,
+        functions: usize,
+    ) -> Vec<PciAdapter> {
+        assert!((1..=MAX_FUNCTIONS).contains(&functions));
+
+        let (exits_tx, exits) = unbounded();
+        let (tx, device_rx) = self.lane();
+        let (device_tx, rx) = self.lane();
+        let device_lane = PciLane {
+            tx: device_tx.clone(),
+            rx: device_rx,
+        };
+        let features = device.features();
+        let mut exports = vec![Exports::default(); functions];
+        exports[0] = Exports::of(device.as_ref());
+        let first = self.completer & !0b111;
+        let bdfs: Vec<u16> = (0..functions).map(|f| first | f as u16).collect();
+        let info = self.lane_info(bdfs.clone(), features);
+        let model = self.spawn_device(info, device, device_lane, &exits_tx);
+        let downstream = bdfs.into_iter().map(|bdf| (bdf, tx.clone())).collect();
+
+        self.spawn_bridge(
+            (device_tx, rx),
+            downstream,
+            vec![model],
+            (exits_tx, exits),
+            features,
+            exports,
+        )
+    }
+
+    fn spawn_bridge(
+        self,
+        (upstream_tx, upstream): (Sender<Tlp>, Receiver<Tlp>),
+        downstream: Vec<(u16, Sender<Tlp>)>,
+        models: Vec<ModelThread>,
+        (exits_tx, exits): (Sender<u16>, Receiver<u16>),
+        features: DeviceFeatures,
+        exports: Vec<Exports>,
+    ) -> Vec<PciAdapter> {
+        let (tx, cmd_rx) = unbounded();
+        let (events_tx, events) = unbounded();
+        let (alive_tx, alive) = bounded(0);
+        let bdfs: Vec<u16> = downstream.iter().map(|(bdf, _)| *bdf).collect();
+        let flows = downstream
+            .iter()
+            .zip(exports.iter())
+            .filter_map(|((_, tx), exports)| Some(LaneFlow::new(tx.clone(), exports.credits?)))
+            .collect();
+        let controls = models.iter().map(|m| (m.bdf, m.controls.clone())).collect();
+        let handles = models.into_iter().map(|m| (m.bdf, m.handle)).collect();
+        let mut runner = PciSimBridge {
+            handles,
+            controls,
+            exits,
+            panicked: HashSet::new(),
+            command: HashMap::new(),
+            stats: AdapterStats::default(),
+            upstream_tx: Some(upstream_tx),
+            exits_tx,
+            builder: self.clone(),
+            detaching: HashMap::new(),
+            upstream,
+            flows,
+            downstream: downstream.into_iter().collect(),
+            windows: vec![],
+            peers: None,
+            cmd_rx,
+            features: DeviceFeatures::default(),
+            tags: TagPool::new(DEFAULT_TAGS),
+            backlog: VecDeque::new(),
+            store: HashMap::new(),
+            completion_timeout: self.completion_timeout,
+            max_read_request: self.max_read_request,
+            msi_window: self.msi_window.clone(),
+            event_log: None,
+            observers: vec![],
+            msi_sink: None,
+            ptm_time: Box::new(self.clock.clone()),
+            intx: HashSet::new(),
+            intx_lines: [None; 4],
+            msix: HashMap::new(),
+            dma_memory: None,
+            dma_translator: None,
+            translation_agent: None,
+            invalidations: VecDeque::new(),
+            page_handler: None,
+            message_handler: None,
+            page_requests: HashMap::new(),
+            disconnect_policy: DisconnectPolicy::default(),
+            crs_policy: CrsPolicy::default(),
+            backpressure: self.backpressure,
+            ordering: self.ordering,
+            delay: self.delay.clone(),
+            delayed_down: DelayLine::new(),
+            delayed_up: DelayLine::new(),
+            link: self.link,
+            throttles: HashMap::new(),
+            links: HashMap::new(),
+            errors: HashMap::new(),
+            error_reporting: HashMap::new(),
+            acs: HashMap::new(),
+            payload_sizes: HashMap::new(),
+            completion_timeouts: HashMap::new(),
+            connected: true,
+            events: events_tx,
+            _alive: alive_tx,
+            bdf: self.bdf,
+        };
+
+        runner.negotiate(features);
+
+        let mut handle = Some(Self::spawn(&self.bridge_thread_name, move || {
+            runner.run();
+        }));
+
+        // The caches share the resets of the bus.
+        let config_cache = self.config_cache.as_deref().map(ConfigCache::new);
+        let bus_resets = Arc::new(AtomicU64::new(0));
+        exports
+            .into_iter()
+            .zip(bdfs.iter())
+            .map(|(exports, &bdf)| PciAdapter {
+                handle: handle.take(),
+                shared_regions: exports.shared_regions,
+                doorbells: exports.doorbells,
+                // The functions of a device share its bus and device number.
+                functions: bdfs.iter().filter(|&&other| other >> 3 == bdf >> 3).count(),
+                ..PciAdapter::new_handle(
+                    bdf,
+                    tx.clone(),
+                    events.clone(),
+                    alive.clone(),
+                    Arc::new(ReplyPool::default()),
+                    self.segment,
+                    config_cache.as_ref().map(ConfigCache::renew),
+                    &bus_resets,
+                )
+            })
+            .collect()
+    }
+}
+
+pub(crate) const BAR0_REG: usize = 4;
+const NUM_BAR_REGS: usize = 6;
+/// Expansion ROM base address register at 0x30.
+const ROM_REG: usize = 12;
+const ROM_ENABLE: u32 = 0x1;
+const ROM_ADDRESS_MASK: u32 = 0xffff_f800;
+/// Command and status register, the status bit 4 tells whether the capability list exists.
+const STATUS_REG: usize = 1;
+const STATUS_CAP_LIST: u32 = 0x10 << 16;
+/// Enables of the command register, in the lower half of [`STATUS_REG`].
+const COMMAND_IO_SPACE: u16 = 0x1;
+const COMMAND_MEMORY_SPACE: u16 = 0x2;
+const COMMAND_BUS_MASTER: u16 = 0x4;
+/// Capabilities pointer at 0x34.
+const CAP_PTR_REG: usize = 13;
+/// Upper bound of the capabilities in the 192 bytes after the header.
+const MAX_CAPS: usize = 48;
+/// Header type register, bit 7 of the header type tells whether the device is multi-function.
+const HEADER_TYPE_REG: usize = 3;
+const HEADER_TYPE_MULTI_FUNCTION: u32 = 0x80 << 16;
+
+impl PciDevice for PciAdapter {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.sync_bus_resets();
+        if reg_idx == ROM_REG && offset == 0 && !data.is_empty() {
+            self.rom_enabled = data[0] as u32 & ROM_ENABLE != 0;
+        }
+
+        // The message control of MSI-X occupies the upper half of the capability header.
+        if self.msix.is_some_and(|cap| cap.reg == reg_idx) && offset as usize + data.len() <= 4 {
+            let mut bytes = ((self.msix_control as u32) << 16).to_le_bytes();
+            bytes[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+            self.msix_control = (u32::from_le_bytes(bytes) >> 16) as u16;
+            let _ = self
+                .tx
+                .send(AdapterMessage::SetMsixControl(self.bdf, self.msix_control));
+        }
+
+        self.config_write(reg_idx, offset, data);
+
+        // The bridge signals the errors Device Control enables and holds Device Status.
+        if Some(reg_idx) == self.devctl_reg && offset as usize + data.len() <= 4 {
+            let mut bytes = [0; 4];
+            bytes[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+            let bits = u32::from_le_bytes(bytes);
+            if offset < 2 {
+                let control = self.config_read(reg_idx) as u16;
+                let _ = self
+                    .tx
+                    .send(AdapterMessage::SetErrorReporting(self.bdf, control));
+            }
+            if bits >> 16 != 0 {
+                let _ = self.tx.send(AdapterMessage::ClearErrors(self.bdf, bits));
+            }
+        }
+
+        // The bridge splits its requests by the sizes in Device Control.
+        if Some(reg_idx) == self.devctl_reg && offset < 2 {
+            self.sync_payload_sizes();
+        }
+
+        // And waits for the completions as long as Device Control 2 says.
+        if self.devctl_reg.map(|reg| reg - DEVCTL_DW + DEVCTL2_DW) == Some(reg_idx) && offset == 0 {
+            self.sync_completion_timeout();
+        }
+
+        // Retrain Link in Link Control asks for the link to be negotiated again.
+        if Some(reg_idx) == self.link_status_reg
+            && offset == 0
+            && data.first().is_some_and(|b| *b as u16 & LNKCTL_RL != 0)
+        {
+            self.train_link();
+        }
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.guest_config_read(reg_idx)
+    }
+
+    fn allocate_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
+    {
+        self.allocate_bars_with(allocator)
+    }
+
+    fn free_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        self.free_bars_with(allocator)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        if data.len() != 4 || self.virtual_function {
+            return None;
+        }
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        // Sizing writes all 1s and the guest restores the old base afterwards.
+        if value == u32::MAX {
+            return None;
+        }
+
+        let region = self.mmio_regions.iter().find(|r| {
+            r.bar_reg == reg_idx
+                || (r.type_ == PciBarRegionType::Memory64BitRegion && r.bar_reg + 1 == reg_idx)
+        })?;
+        let old_base = region.start.raw_value();
+        let new_base = if region.bar_reg == reg_idx {
+            let flags = match region.type_ {
+                PciBarRegionType::IoRegion => 0x3,
+                _ => 0xf,
+            };
+            let mask = !(region.length - 1) as u32 & !flags;
+            (old_base & !0xffff_ffff) | (value & mask) as u64
+        } else {
+            (old_base & 0xffff_ffff) | (value as u64) << 32
+        };
+        if new_base == old_base {
+            return None;
+        }
+
+        debug!(
+            "reprogram BAR reg{} from {:#x} to {:#x}",
+            region.bar_reg, old_base, new_base
+        );
+        Some(BarReprogrammingParams {
+            old_base,
+            new_base,
+            len: region.length,
+            region_type: region.type_,
+        })
+    }
+
+    fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.bar_mmio_read(base + offset, data);
+    }
+
+    fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.bar_mmio_write(base + offset, data);
+        None
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> std::result::Result<(), io::Error> {
+        let idx = match self
+            .mmio_regions
+            .iter()
+            .position(|r| r.start.raw_value() == old_base)
+        {
+            Some(idx) => idx,
+            None => return Ok(()),
+        };
+
+        let mut region = self.mmio_regions[idx];
+        region.start = GuestAddress(new_base);
+        if let (Some(slot), Some(manager)) = (region.mem_slot, self.slot_manager.as_mut()) {
+            manager.unmap_region(slot)?;
+            region.mem_slot =
+                Some(manager.map_region(new_base, region.length, region.host_addr.unwrap())?);
+        }
+
+        self.mmio_regions[idx] = region;
+        self.route_bars();
+        self.route_doorbells();
+        Ok(())
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl BusDevice for PciAdapter {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+/// The allocator of the tests: 4 KiB of IO ports on x86_64, 256 MiB of MMIO above 4 GiB and
+/// 256 MiB in the 32-bit hole.
+#[cfg(test)]
+pub(crate) fn test_allocator() -> SystemAllocator {
+    SystemAllocator::new(
+        #[cfg(target_arch = "x86_64")]
+        GuestAddress(0x1000),
+        #[cfg(target_arch = "x86_64")]
+        0x1000,
+        GuestAddress(0x1_0000_0000),
+        0x1000_0000,
+        GuestAddress(0xc000_0000),
+        0x1000_0000,
+        #[cfg(target_arch = "x86_64")]
+        vec![vm_allocator::GsiApic::new(24, 24)],
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pci::{PciCapability, PciCapabilityId, PciInterruptPin};
+
+    #[test]
+    fn byte_enable() {
+        assert_eq!(write_byte_enable(0, 4), 0x0f);
+        assert_eq!(write_byte_enable(1, 2), 0x06);
+        assert_eq!(write_byte_enable(2, 4), 0x3c);
+        assert_eq!(write_byte_enable(0, 8), 0xff);
+        assert_eq!(write_byte_enable(3, 2), 0x18);
+    }
+
+    /// A device model which never answers.
+    struct SilentDevice;
+
+    impl PciSimDevice for SilentDevice {
+        fn run(&mut self, lane: &PciLane) {
+            while lane.rx.recv().is_ok() {}
+        }
+    }
+
+    /// A device model which leaves the lane immediately.
+    struct DeadDevice;
+
+    impl PciSimDevice for DeadDevice {
+        fn run(&mut self, _lane: &PciLane) {}
+    }
+
+    /// A device model which leaves the lane after receiving the first TLP.
+    struct OneShotDevice;
+
+    impl PciSimDevice for OneShotDevice {
+        fn run(&mut self, lane: &PciLane) {
+            let _ = lane.rx.recv();
+        }
+    }
+
+    /// A device model which rejects every request as unsupported.
+    struct UnsupportedDevice;
+
+    impl PciSimDevice for UnsupportedDevice {
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                if let PacketType::Config0Read(extra) | PacketType::Config0Write(extra) =
+                    tlp.header._type
+                {
+                    let cpl = TlpBuilder::completion(CompletionExtra {
+                        requester: extra.requester,
+                        completer: extra.completer,
+                        tag: extra.tag,
+                        status: CPL_UR,
+                        bcm: false,
+                        byte_count: 4,
+                        lower_address: 0,
+                    })
+                    .build();
+                    let _ = lane.tx.send(cpl);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_request() {
+        let adapter = PciAdapter::start(Box::new(UnsupportedDevice));
+
+        assert_eq!(
+            adapter.try_config_read(0),
+            Err(PciAdapterError::Completion(CPL_UR))
+        );
+        assert_eq!(
+            adapter.try_config_write(1, 0, &[0x6]),
+            Err(PciAdapterError::Completion(CPL_UR))
+        );
+        assert_eq!(adapter.config_read(0), u32::MAX);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model which completes the given number of config reads with CRS first.
+    struct CrsDevice(u32);
+
+    impl PciSimDevice for CrsDevice {
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                if let PacketType::Config0Read(extra) = tlp.header._type {
+                    let status = if self.0 > 0 { CPL_CRS } else { CPL_SC };
+                    self.0 = self.0.saturating_sub(1);
+
+                    let cpl = TlpBuilder::completion_data(CompletionExtra {
+                        requester: extra.requester,
+                        completer: extra.completer,
+                        tag: extra.tag,
+                        status,
+                        bcm: false,
+                        byte_count: 4,
+                        lower_address: 0,
+                    })
+                    .data(vec![0x5678_1234])
+                    .build();
+                    let _ = lane.tx.send(cpl);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn crs_retry() {
+        let adapter = PciAdapter::start(Box::new(CrsDevice(5)));
+        let mut policy = CrsPolicy {
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+            max_retries: 2,
+            software_visibility: false,
+        };
+
+        adapter.set_crs_policy(policy);
+        assert_eq!(
+            adapter.try_config_read(0),
+            Err(PciAdapterError::Completion(CPL_CRS))
+        );
+
+        policy.software_visibility = true;
+        adapter.set_crs_policy(policy);
+        assert_eq!(adapter.try_config_read(0), Ok(CRS_VENDOR_ID));
+
+        policy.software_visibility = false;
+        adapter.set_crs_policy(policy);
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model with a prefetchable BAR0 backed by shared memory and optionally an
+    /// expansion ROM, whose every DW reads as the ROM signature.
+    struct SharedBarDevice {
+        config: PciConfiguration,
+        memory: Vec<u8>,
+        msix: bool,
+    }
+
+    /// MSI-X capability with 2 vectors, the table at 0 and the PBA at 0x800 of BAR0.
+    struct MsixCapability([u8; 10]);
+
+    impl PciCapability for MsixCapability {
+        fn bytes(&self) -> &[u8] {
+            &self.0
+        }
+
+        fn id(&self) -> PciCapabilityId {
+            PciCapabilityId::MsiX
+        }
+    }
+
+    impl SharedBarDevice {
+        fn new(rom: bool) -> Self {
+            let mut config = PciConfiguration::new(
+                0x1234,
+                0x5679,
+                0x0001,
+                PciClassCode::Other,
+                &PciMassStorageSubclass::MassStorage,
+                None,
+                PciHeaderType::Device,
+                0x5555,
+                0x6666,
+                None,
+            );
+            let bar = PciBarConfiguration::new(
+                0,
+                0x1000,
+                PciBarRegionType::Memory32BitRegion,
+                PciBarPrefetchable::Prefetchable,
+            );
+            config.add_pci_bar(&bar).unwrap();
+
+            if rom {
+                let bar = PciBarConfiguration::new(
+                    6,
+                    0x800,
+                    PciBarRegionType::Memory32BitRegion,
+                    PciBarPrefetchable::NotPrefetchable,
+                );
+                config.add_pci_rom_bar(&bar, 0).unwrap();
+            }
+
+            SharedBarDevice {
+                config,
+                memory: vec![0; 0x1000],
+                msix: false,
+            }
+        }
+
+        /// Add an MSI-X capability, every memory write then raises vector 1.
+        fn with_msix(mut self) -> Self {
+            let cap = MsixCapability([0x01, 0x00, 0, 0, 0, 0, 0x00, 0x08, 0, 0]);
+            self.config.add_capability(&cap).unwrap();
+            self.msix = true;
+            self
+        }
+    }
+
+    impl PciSimDevice for SharedBarDevice {
+        fn shared_regions(&self) -> Vec<SharedRegion> {
+            vec![SharedRegion {
+                bar: 0,
+                host_addr: self.memory.as_ptr() as u64,
+                size: self.memory.len(),
+            }]
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                let (extra, value) = match tlp.header._type {
+                    PacketType::Config0Read(extra) => (
+                        extra,
+                        Some(self.config.read_config_register(extra.reg as usize)),
+                    ),
+                    PacketType::Config0Write(extra) => {
+                        let data = tlp.data.unwrap()[0].to_le_bytes();
+                        self.config
+                            .write_config_register(extra.reg as usize, 0, &data);
+                        (extra, None)
+                    }
+                    PacketType::MemoryRead(MemoryExtra {
+                        requester,
+                        tag,
+                        addr,
+                    }) => {
+                        let _ = lane.tx.send(pattern_completion(&tlp, requester, tag, addr));
+                        continue;
+                    }
+                    PacketType::MemoryRead64(Memory64Extra {
+                        requester,
+                        tag,
+                        addr,
+                    }) => {
+                        let _ = lane
+                            .tx
+                            .send(pattern_completion(&tlp, requester, tag, addr as u32));
+                        continue;
+                    }
+                    PacketType::MemoryWrite(_) | PacketType::MemoryWrite64(_) if self.msix => {
+                        lane.raise_msix(make_bdf(0x0, 0x3, 0x0), 1).unwrap();
+                        continue;
+                    }
+                    _ => continue,
+                };
+
+                let cpl = CompletionExtra {
+                    requester: extra.requester,
+                    completer: extra.completer,
+                    tag: extra.tag,
+                    status: CPL_SC,
+                    bcm: false,
+                    byte_count: 4,
+                    lower_address: 0,
+                };
+                let tlp = match value {
+                    Some(value) => TlpBuilder::completion_data(cpl).data(vec![value]),
+                    None => TlpBuilder::completion(cpl),
+                };
+                let _ = lane.tx.send(tlp.build());
+            }
+        }
+    }
+
+    /// The completion of the memory read `tlp`, every DW read is 0x55aa_0000.
+    fn pattern_completion(tlp: &Tlp, requester: u16, tag: u8, addr: u32) -> Tlp {
+        TlpBuilder::completion_data(CompletionExtra {
+            requester,
+            completer: 0,
+            tag,
+            status: CPL_SC,
+            bcm: false,
+            byte_count: 0,
+            lower_address: addr as u8 & 0x7c,
+        })
+        .byte_enable(0x0f)
+        .data(vec![0x55aa_0000; tlp.header.length as usize])
+        .build()
+    }
+
+    /// Records the guest address of every mapped slot, `None` once it is unmapped.
+    #[derive(Clone, Default)]
+    struct RecordingSlots(Arc<std::sync::Mutex<Vec<Option<u64>>>>);
+
+    impl MemorySlotManager for RecordingSlots {
+        fn map_region(&mut self, gpa: u64, _size: u64, _host_addr: u64) -> io::Result<u32> {
+            let mut slots = self.0.lock().unwrap();
+            slots.push(Some(gpa));
+            Ok(slots.len() as u32 - 1)
+        }
+
+        fn unmap_region(&mut self, slot: u32) -> io::Result<()> {
+            self.0.lock().unwrap()[slot as usize] = None;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn shared_bar() {
+        let mut adapter = PciAdapter::start(Box::new(SharedBarDevice::new(false)));
+        let slots = RecordingSlots::default();
+        adapter.set_slot_manager(Box::new(slots.clone()));
+
+        let mut allocator = test_allocator();
+
+        let bars = adapter.allocate_bars(&mut allocator).unwrap();
+        assert_eq!(bars.len(), 1);
+        let base = bars[0].0.raw_value();
+        assert_eq!(*slots.0.lock().unwrap(), vec![Some(base)]);
+        assert_eq!(adapter.mmio_regions[0].mem_slot, Some(0));
+
+        adapter.move_bar(base, 0xc010_0000).unwrap();
+        assert_eq!(*slots.0.lock().unwrap(), vec![None, Some(0xc010_0000)]);
+        assert!(adapter.find_region(0xc010_0000).is_some());
+
+        adapter.free_bars(&mut allocator).unwrap();
+        assert_eq!(*slots.0.lock().unwrap(), vec![None, None]);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn bar_reprogramming() {
+        let mut adapter = PciAdapter::start(Box::new(SharedBarDevice::new(false)));
+        let mut allocator = test_allocator();
+
+        let base = adapter.allocate_bars(&mut allocator).unwrap()[0]
+            .0
+            .raw_value();
+        let bar = 0xc020_0000u32.to_le_bytes();
+        assert_eq!(
+            adapter.detect_bar_reprogramming(4, &bar),
+            Some(BarReprogrammingParams {
+                old_base: base,
+                new_base: 0xc020_0000,
+                len: 0x1000,
+                region_type: PciBarRegionType::Memory32BitRegion,
+            })
+        );
+        // Sizing, restoring the old base and writes to other registers move nothing.
+        assert_eq!(adapter.detect_bar_reprogramming(4, &[0xff; 4]), None);
+        assert_eq!(
+            adapter.detect_bar_reprogramming(4, &(base as u32 | 0x8).to_le_bytes()),
+            None
+        );
+        assert_eq!(adapter.detect_bar_reprogramming(5, &bar), None);
+        assert_eq!(adapter.detect_bar_reprogramming(4, &bar[..2]), None);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn msix() {
+        let mut adapter = PciAdapter::start(Box::new(SharedBarDevice::new(false).with_msix()));
+        let (tx, rx) = unbounded();
+        adapter.set_msi_sink(Box::new(ChannelSink(tx)));
+
+        let mut allocator = test_allocator();
+
+        let base = adapter.allocate_bars(&mut allocator).unwrap()[0]
+            .0
+            .raw_value();
+        let cap = adapter.msix.unwrap();
+        assert_eq!(cap.table_size, 2);
+
+        // Program vector 1 and enable MSI-X, the vector stays masked.
+        adapter.bar_mmio_write(base + 0x10, &0xfee0_1000u64.to_le_bytes());
+        adapter.bar_mmio_write(base + 0x18, &0x4021u32.to_le_bytes());
+        adapter.write_config_register(cap.reg, 2, &[0x01, 0x80]);
+        let mut data = [0u8; 4];
+        adapter.bar_mmio_read(base + 0x18, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x4021);
+
+        adapter.bar_mmio_write(base + 0x100, &[0x1]);
+        // The device raises the vector before it completes the following read.
+        adapter.config_read(0);
+        adapter.bar_mmio_read(base + 0x800, &mut data);
+        assert_eq!(data, [0x2, 0, 0, 0]);
+        assert!(rx.try_recv().is_err());
+
+        // Unmasking sends the pending message.
+        adapter.bar_mmio_write(base + 0x1c, &[0, 0, 0, 0]);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok((0xfee0_1000, 0x4021))
+        );
+        adapter.bar_mmio_write(base + 0x100, &[0x1]);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok((0xfee0_1000, 0x4021))
+        );
+
+        assert_eq!(
+            adapter.try_bar_mmio_read(base + 0x18, &mut data[..2]),
+            Err(PciAdapterError::InvalidSize(2))
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// Records the calls of the [`VectorSink`] of the bridge.
+    struct GroupSink(Sender<String>);
+
+    impl InterruptSink for GroupSink {
+        fn configure_group(
+            &self,
+            source: PciAddress,
+            kind: InterruptKind,
+            vectors: u16,
+        ) -> io::Result<()> {
+            let _ = self
+                .0
+                .send(format!("group {} {:?} {}", source, kind, vectors));
+            Ok(())
+        }
+
+        fn release_group(&self, source: PciAddress) -> io::Result<()> {
+            let _ = self.0.send(format!("release {}", source));
+            Ok(())
+        }
+
+        fn configure_vector(
+            &self,
+            _: PciAddress,
+            vector: u16,
+            addr: u64,
+            data: u32,
+        ) -> io::Result<()> {
+            let _ = self
+                .0
+                .send(format!("vector {} {:#x} {:#x}", vector, addr, data));
+            Ok(())
+        }
+
+        fn trigger(&self, _: PciAddress, vector: u16) -> io::Result<()> {
+            let _ = self.0.send(format!("trigger {}", vector));
+            Ok(())
+        }
+
+        fn mask(&self, _: PciAddress, vector: u16) -> io::Result<()> {
+            let _ = self.0.send(format!("mask {}", vector));
+            Ok(())
+        }
+
+        fn unmask(&self, _: PciAddress, vector: u16) -> io::Result<()> {
+            let _ = self.0.send(format!("unmask {}", vector));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interrupt_sink() {
+        let mut adapter = PciAdapter::start(Box::new(SharedBarDevice::new(false).with_msix()));
+        let (tx, rx) = unbounded();
+        adapter.set_msi_sink(Box::new(VectorSink::new(GroupSink(tx))));
+
+        let mut allocator = test_allocator();
+        let base = adapter.allocate_bars(&mut allocator).unwrap()[0]
+            .0
+            .raw_value();
+        let cap = adapter.msix.unwrap();
+        let events = |adapter: &PciAdapter| {
+            // A config read waits for the bridge to take the preceding messages.
+            adapter.config_read(0);
+            rx.try_iter().collect::<Vec<_>>()
+        };
+
+        // Enabling MSI-X sets up the group, with every vector masked after reset.
+        adapter.bar_mmio_write(base + 0x10, &0xfee0_1000u64.to_le_bytes());
+        adapter.bar_mmio_write(base + 0x18, &0x4021u32.to_le_bytes());
+        adapter.write_config_register(cap.reg, 2, &[0x01, 0x80]);
+        assert_eq!(
+            events(&adapter),
+            ["group 0000:00:03.0 Msix 2", "mask 0", "mask 1"]
+        );
+
+        // A vector raised while masked is programmed and triggered once unmasked.
+        adapter.bar_mmio_write(base + 0x100, &[0x1]);
+        adapter.bar_mmio_write(base + 0x1c, &[0, 0, 0, 0]);
+        assert_eq!(
+            events(&adapter),
+            ["unmask 1", "vector 1 0xfee01000 0x4021", "trigger 1"]
+        );
+        adapter.bar_mmio_write(base + 0x100, &[0x1]);
+        assert_eq!(events(&adapter), ["trigger 1"]);
+
+        // The function mask masks the unmasked vectors only.
+        adapter.write_config_register(cap.reg, 2, &[0x01, 0xc0]);
+        assert_eq!(events(&adapter), ["mask 1"]);
+        adapter.write_config_register(cap.reg, 2, &[0x01, 0x00]);
+        assert_eq!(events(&adapter), ["release 0000:00:03.0"]);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn expansion_rom() {
+        let mut adapter = PciAdapter::start(Box::new(SharedBarDevice::new(true)));
+        let mut allocator = test_allocator();
+
+        let bars = adapter.allocate_bars(&mut allocator).unwrap();
+        assert_eq!(bars.len(), 2);
+        let (base, size, _) = bars[1];
+        assert_eq!(size, 0x800);
+        assert_eq!(
+            adapter.read_config_register(ROM_REG),
+            base.raw_value() as u32
+        );
+
+        let mut data = [0u8; 2];
+        adapter.bar_mmio_read(base.raw_value(), &mut data);
+        assert_eq!(data, [0xff, 0xff]);
+
+        let enable = base.raw_value() as u32 | ROM_ENABLE;
+        adapter.write_config_register(ROM_REG, 0, &enable.to_le_bytes());
+        adapter.bar_mmio_read(base.raw_value(), &mut data);
+        assert_eq!(data, [0x55, 0xaa]);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model which raises an MSI at the address it holds whenever its BAR is written.
+    struct MsiDevice(u64);
+
+    impl PciSimDevice for MsiDevice {
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                if let PacketType::MemoryWrite64(_) = tlp.header._type {
+                    lane.raise_msi(make_bdf(0, 3, 0), self.0, 0x4021).unwrap();
+                }
+            }
+        }
+    }
+
+    struct ChannelSink(Sender<(u64, u32)>);
+
+    impl MsiSink for ChannelSink {
+        fn inject(&self, addr: u64, data: u32) -> io::Result<()> {
+            self.0.send((addr, data)).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn msi() {
+        let mut adapter = PciAdapter::start(Box::new(MsiDevice(0xfee0_1000)));
+        let (tx, rx) = unbounded();
+        adapter.set_msi_sink(Box::new(ChannelSink(tx)));
+
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        adapter.bar_mmio_write(0x1_0000_0000, &[0x1]);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok((0xfee0_1000, 0x4021))
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// Records the MSIs along with the DeviceID, like the ITS of aarch64.
+    struct ItsSink(Sender<(u32, u64, u32)>);
+
+    impl MsiSink for ItsSink {
+        fn inject(&self, _: u64, _: u32) -> io::Result<()> {
+            unreachable!("MSIs are injected with their source")
+        }
+
+        fn inject_from(&self, source: PciAddress, addr: u64, data: u32) -> io::Result<()> {
+            self.0.send((source.devid(), addr, data)).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn msi_window() {
+        // GITS_TRANSLATER of an ITS at 0x0808_0000, the DeviceID tells the segment.
+        let mut adapter = PciAdapterBuilder::new()
+            .segment(2)
+            .msi_window(0x0809_0040..0x0809_0044)
+            .start(Box::new(MsiDevice(0x0809_0040)));
+        let (tx, rx) = unbounded();
+        adapter.set_msi_sink(Box::new(ItsSink(tx)));
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+
+        adapter.bar_mmio_write(0x1_0000_0000, &[0x1]);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok((0x2_0018, 0x0809_0040, 0x4021))
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model which asserts INTA while the last byte written to its BAR is not 0.
+    struct IntxDevice;
+
+    impl PciSimDevice for IntxDevice {
+        fn run(&mut self, lane: &PciLane) {
+            let irq = IrqHandle::new(lane, make_bdf(0, 3, 0));
+            let config = |reg| if reg == 15 { 0x100 } else { 0 };
+            while let Ok(tlp) = lane.rx.recv() {
+                if let PacketType::MemoryWrite64(_) = tlp.header._type {
+                    let level = tlp.data.unwrap()[0] >> 24 != 0;
+                    irq.set_intx(config, level).unwrap();
+                }
+            }
+        }
+    }
+
+    struct IntxSink(Sender<(u8, bool)>);
+
+    impl MsiSink for IntxSink {
+        fn inject(&self, _: u64, _: u32) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_intx(&self, pin: u8, level: bool) -> io::Result<()> {
+            self.0.send((pin, level)).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn intx() {
+        let mut adapter = PciAdapter::start(Box::new(IntxDevice));
+        let (tx, rx) = unbounded();
+        adapter.set_msi_sink(Box::new(IntxSink(tx)));
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+
+        for byte in [1, 1, 0] {
+            adapter.bar_mmio_write(0x1_0000_0000, &[byte]);
+        }
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok((1, true)));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok((1, false)));
+        assert_eq!(adapter.stats().unwrap().errors, 0);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model which sends the INTx message whose code is the last byte written to its
+    /// BAR, whatever it sent before.
+    struct IntxMessageDevice(u16);
+
+    impl PciSimDevice for IntxMessageDevice {
+        fn on_start(&mut self, info: &LaneInfo) {
+            self.0 = info.functions[0];
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                if let PacketType::MemoryWrite64(_) = tlp.header._type {
+                    let code = (tlp.data.unwrap()[0] >> 24) as u8;
+                    let message = MessageExtra::routed(self.0, code, MessageRouting::Local);
+                    lane.tx.send(TlpBuilder::message(message).build()).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn intx_wires() {
+        let mut adapter = PciAdapter::start(Box::new(IntxMessageDevice(0)));
+        let (tx, rx) = unbounded();
+        adapter.set_msi_sink(Box::new(IntxSink(tx)));
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        let level = || rx.recv_timeout(Duration::from_secs(1));
+
+        // The wire of a function is asserted or not, one Deassert_INTx releases it.
+        for code in [MSG_ASSERT_INTA, MSG_ASSERT_INTA, MSG_DEASSERT_INTA] {
+            adapter.bar_mmio_write(0x1_0000_0000, &[code]);
+        }
+        assert_eq!(level(), Ok((1, true)));
+        assert_eq!(level(), Ok((1, false)));
+
+        // A reset function and an unplugged one no longer assert their wires.
+        adapter.bar_mmio_write(0x1_0000_0000, &[MSG_ASSERT_INTA + 1]);
+        assert_eq!(level(), Ok((2, true)));
+        assert_eq!(adapter.function_reset(), Ok(()));
+        assert_eq!(level(), Ok((2, false)));
+        adapter.bar_mmio_write(0x1_0000_0000, &[MSG_ASSERT_INTA + 2]);
+        assert_eq!(level(), Ok((3, true)));
+        assert_eq!(adapter.stats().unwrap().errors, 0);
+        assert_eq!(adapter.unplug(&mut test_allocator()), Ok(()));
+        assert_eq!(level(), Ok((3, false)));
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model on INTA which asserts it while the last byte written to its BAR is not 0.
+    struct IntxLineDevice {
+        config: ConfigSpace,
+        requester: u16,
+    }
+
+    impl IntxLineDevice {
+        fn new() -> Self {
+            let mut pci = PciConfiguration::new(
+                0x1234,
+                0x5678,
+                0x0001,
+                PciClassCode::Other,
+                &PciMassStorageSubclass::MassStorage,
+                None,
+                PciHeaderType::Device,
+                0x5555,
+                0x6666,
+                None,
+            );
+            pci.set_irq(0xff, PciInterruptPin::IntA);
+            IntxLineDevice {
+                config: ConfigSpace::new(PcieConfiguration::new(pci)),
+                requester: 0,
+            }
+        }
+    }
+
+    impl PciSimDevice for IntxLineDevice {
+        fn on_start(&mut self, info: &LaneInfo) {
+            self.requester = info.functions[0];
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            let irq = IrqHandle::new(lane, self.requester);
+            while let Ok(tlp) = lane.rx.recv() {
+                if let PacketType::MemoryWrite64(_) = tlp.header._type {
+                    let level = tlp.data.unwrap()[0] >> 24 != 0;
+                    let config = &self.config;
+                    irq.set_intx(|reg| config.register(reg), level).unwrap();
+                } else {
+                    self.config.handle(lane, &tlp);
+                }
+            }
+        }
+    }
+
+    struct IntxLineSink(Sender<(u8, u32, bool)>);
+
+    impl MsiSink for IntxLineSink {
+        fn inject(&self, _: u64, _: u32) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_intx_line(&self, pin: u8, gsi: u32, level: bool) -> io::Result<()> {
+            self.0.send((pin, gsi, level)).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn link_states() {
+        let pci = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x0001,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0x5555,
+            0x6666,
+            None,
+        );
+        let mut config = PcieConfiguration::new(pci);
+        let cap = PcieCapability::new(PciePortType::Endpoint).link(LinkSpeed::Gen3, 4);
+        let lnkctl = config.add_capability(&cap).unwrap() / 4 + 4;
+        let mut adapters = PciAdapter::start_functions(vec![
+            Box::new(ConfigSpace::new(config)),
+            Box::new(PciTestDevice::new()),
+        ]);
+        let events = adapters[0].events();
+        adapters[0].allocate_bars(&mut test_allocator()).unwrap();
+        let status = |adapter: &PciAdapter| adapter.guest_config_read(lnkctl) >> 16;
+
+        // The model reports the speed and width, the bridge whether the link is active.
+        assert_eq!(adapters[0].link_state(), Ok(LtssmState::L0));
+        assert_eq!(status(&adapters[0]), 0x43 | LNKSTA_DLLLA as u32);
+
+        // A link in L1 wakes for the next request.
+        assert_eq!(adapters[0].enter_l1(), Ok(()));
+        assert_eq!(adapters[1].link_state(), Ok(LtssmState::L1));
+        assert_eq!(adapters[1].try_config_read(0), Ok(0x5678_1234));
+        assert_eq!(adapters[0].link_state(), Ok(LtssmState::L0));
+        assert_eq!(adapters[0].retrain_link(), Ok(()));
+
+        // The functions of the device share the link that goes down.
+        assert_eq!(adapters[0].set_link_up(false), Ok(()));
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(1)),
+            Ok(AdapterEvent::LinkDown {
+                bdf: adapters[0].bdf()
+            })
+        );
+        assert_eq!(adapters[1].link_state(), Ok(LtssmState::Detect));
+        assert_eq!(
+            adapters[1].try_config_read(0),
+            Err(PciAdapterError::LinkDown)
+        );
+        assert_eq!(status(&adapters[0]), 0xffff);
+        assert_eq!(adapters[1].retrain_link(), Err(PciAdapterError::LinkDown));
+        assert_eq!(adapters[1].enter_l1(), Err(PciAdapterError::LinkDown));
+        assert_eq!(adapters[1].set_link_up(false), Ok(()));
+
+        assert_eq!(adapters[1].set_link_up(true), Ok(()));
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(1)),
+            Ok(AdapterEvent::LinkUp {
+                bdf: adapters[1].bdf()
+            })
+        );
+        assert_eq!(status(&adapters[0]), 0x43 | LNKSTA_DLLLA as u32);
+        assert_eq!(adapters[1].try_config_read(0), Ok(0x5678_1234));
+
+        adapters[0].stop();
+        for adapter in adapters {
+            adapter.join();
+        }
+    }
+
+    #[test]
+    fn link_negotiation() {
+        let pci = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x0001,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0x5555,
+            0x6666,
+            None,
+        );
+        let mut config = PcieConfiguration::new(pci);
+        let cap = PcieCapability::new(PciePortType::Endpoint).link(LinkSpeed::Gen3, 16);
+        let lnkctl = config.add_capability(&cap).unwrap() / 4 + 4;
+        let mut adapter = PciAdapterBuilder::new()
+            .link(Link::new(LinkSpeed::Gen4, 8))
+            .start(Box::new(ConfigSpace::new(config)));
+        adapter.allocate_bars(&mut test_allocator()).unwrap();
+
+        // A Gen3 x16 device behind a Gen4 x8 port trains at Gen3 x8.
+        assert_eq!(
+            adapter.negotiated_link(),
+            Ok(Some(Link::new(LinkSpeed::Gen3, 8)))
+        );
+        assert_eq!(
+            adapter.guest_config_read(lnkctl) >> 16,
+            0x83 | LNKSTA_DLLLA as u32
+        );
+
+        // The guest lowers the target speed and retrains, Retrain Link reads 0.
+        adapter.write_config_register(lnkctl + 8, 0, &[LinkSpeed::Gen1.code() as u8]);
+        adapter.write_config_register(lnkctl, 0, &[LNKCTL_RL as u8]);
+        assert_eq!(
+            adapter.negotiated_link(),
+            Ok(Some(Link::new(LinkSpeed::Gen1, 8)))
+        );
+        assert_eq!(
+            adapter.guest_config_read(lnkctl),
+            (0x81 | LNKSTA_DLLLA as u32) << 16
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn intx_line() {
+        let mut adapters = PciAdapter::start_functions(vec![
+            Box::new(IntxLineDevice::new()),
+            Box::new(IntxLineDevice::new()),
+        ]);
+        let mut allocator = test_allocator();
+
+        // Both functions are on INTA of the bridge, so they share its GSI.
+        for adapter in adapters.iter_mut() {
+            assert_eq!(adapter.intx_line(), None);
+            adapter.allocate_bars(&mut allocator).unwrap();
+            assert_eq!(adapter.intx_line(), Some(24));
+            assert_eq!(adapter.config_read(15), 0x0118);
+        }
+        assert_eq!(allocator.allocate_irq(), Some(25));
+
+        let adapter = &mut adapters[0];
+        let (tx, rx) = unbounded();
+        adapter.set_msi_sink(Box::new(IntxLineSink(tx)));
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        adapter.route_bars();
+        adapter.bar_mmio_write(0x1_0000_0000, &[1]);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok((1, 24, true)));
+
+        for adapter in adapters.iter() {
+            adapter.stop();
+        }
+        for adapter in adapters {
+            adapter.join();
+        }
+    }
+
+    /// A device model which issues a DMA write, a DMA read and a faulting DMA read whenever
+    /// its BAR is written, the completions it receives are forwarded to the test.
+    struct DmaDevice(Sender<Tlp>);
+
+    impl PciSimDevice for DmaDevice {
+        fn run(&mut self, lane: &PciLane) {
+            let requester = make_bdf(0, 3, 0);
+            while let Ok(tlp) = lane.rx.recv() {
+                match tlp.header._type {
+                    PacketType::MemoryWrite64(_) => {
+                        let write = TlpBuilder::memory_write(MemoryExtra {
+                            requester,
+                            tag: 0,
+                            addr: 0x1000,
+                        })
+                        .byte_enable(0xfe)
+                        .data(vec![0x1122_3344, 0x5566_7788])
+                        .build();
+                        let read = |tag, addr| {
+                            TlpBuilder::memory_read(MemoryExtra {
+                                requester,
+                                tag,
+                                addr,
+                            })
+                            .byte_enable(0xff)
+                            .length(2)
+                            .build()
+                        };
+
+                        lane.tx.send(write).unwrap();
+                        lane.tx.send(read(1, 0x1000)).unwrap();
+                        lane.tx.send(read(2, 0x5000)).unwrap();
+                    }
+                    _ => self.0.send(tlp).unwrap(),
+                }
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct VecMemory(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl DmaMemory for VecMemory {
+        fn read(&self, gpa: u64, data: &mut [u8]) -> io::Result<()> {
+            let gpa = gpa as usize;
+            data.copy_from_slice(&self.0.lock().unwrap()[gpa..gpa + data.len()]);
+            Ok(())
+        }
+
+        fn write(&self, gpa: u64, data: &[u8]) -> io::Result<()> {
+            let gpa = gpa as usize;
+            self.0.lock().unwrap()[gpa..gpa + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// Maps the IOVA page at 0x1000 to 0x3000.
+    struct OnePageTranslator;
+
+    impl DmaTranslator for OnePageTranslator {
+        fn translate(
+            &self,
+            _: u16,
+            iova: u64,
+            len: u64,
+            _: DmaAccess,
+        ) -> std::result::Result<u64, DmaFault> {
+            if iova >= 0x1000 && iova + len <= 0x2000 {
+                Ok(iova + 0x2000)
+            } else {
+                Err(DmaFault::Unmapped)
+            }
+        }
+    }
+
+    #[test]
+    fn dma() {
+        let (tx, rx) = unbounded();
+        let mut adapter = PciAdapter::start(Box::new(DmaDevice(tx)));
+        let memory = VecMemory(Arc::new(std::sync::Mutex::new(vec![0; 0x4000])));
+        adapter.set_dma_memory(Box::new(memory.clone()));
+        adapter.set_dma_translator(Box::new(OnePageTranslator));
+
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        adapter.bar_mmio_write(0x1_0000_0000, &[0x1]);
+
+        let cpl = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        match cpl.header._type {
+            PacketType::CompletionData(extra) => {
+                assert_eq!((extra.tag, extra.status, extra.byte_count), (1, CPL_SC, 8));
+            }
+            t => panic!("unexpected {:?}", t),
+        }
+        assert_eq!(cpl.data, Some(vec![0x0022_3344, 0x5566_7788]));
+        assert_eq!(
+            memory.0.lock().unwrap()[0x3000..0x3008],
+            [0x00, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]
+        );
+
+        let cpl = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        match cpl.header._type {
+            PacketType::Completion(extra) => assert_eq!((extra.tag, extra.status), (2, CPL_UR)),
+            t => panic!("unexpected {:?}", t),
+        }
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model which, on a write to its BAR, DMA writes 1 to 6 across the page at
+    /// 0x1000 and reads back 256 bytes around them.
+    struct DmaClientDevice(Sender<Result<Vec<u8>>>);
+
+    impl PciSimDevice for DmaClientDevice {
+        fn run(&mut self, lane: &PciLane) {
+            let mut dma = DmaHandle::new(lane, make_bdf(0, 3, 0)).max_read_request(128);
+            while let Ok(tlp) = dma.recv() {
+                if let PacketType::MemoryWrite64(_) = tlp.header._type {
+                    dma.write(0xffe, &[1, 2, 3, 4, 5, 6]).unwrap();
+                    let _ = self.0.send(dma.read(0xf81, 0x100));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dma_client() {
+        let (tx, rx) = unbounded();
+        let mut adapter = PciAdapter::start(Box::new(DmaClientDevice(tx)));
+        let memory = VecMemory(Arc::new(std::sync::Mutex::new(
+            (0..0x2000).map(|i| i as u8).collect(),
+        )));
+        adapter.set_dma_memory(Box::new(memory.clone()));
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        adapter.bar_mmio_write(0x1_0000_0000, &[0x1]);
+
+        // The read is split in 3 requests, the write in 2.
+        let data = rx.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+        let memory = memory.0.lock().unwrap();
+        assert_eq!(memory[0xffe..0x1004], [1, 2, 3, 4, 5, 6]);
+        assert_eq!(data, memory[0xf81..0x1081]);
+        let stats = adapter.stats().unwrap();
+        assert_eq!(
+            (stats.received.memory_read, stats.received.memory_write),
+            (3, 2)
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn command_register() {
+        let mut adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        let mut allocator = test_allocator();
+        let bars = adapter.allocate_bars(&mut allocator).unwrap();
+        let (memory, io) = (bars[0].0.raw_value(), bars[1].0.raw_value());
+
+        // Nothing is enforced before the command register is written.
+        let mut data = [0u8; 4];
+        assert_eq!(adapter.try_bar_mmio_read(io, &mut data), Ok(()));
+
+        adapter.write_config_register(STATUS_REG, 0, &[COMMAND_MEMORY_SPACE as u8]);
+        assert_eq!(adapter.try_bar_mmio_read(memory, &mut data), Ok(()));
+        assert_eq!(
+            adapter.try_bar_mmio_read(io, &mut data),
+            Err(PciAdapterError::Completion(CPL_UR))
+        );
+        assert_eq!(
+            adapter.try_bar_mmio_write(io, &data),
+            Err(PciAdapterError::Completion(CPL_UR))
+        );
+
+        adapter.write_config_register(STATUS_REG, 0, &[0, 0]);
+        adapter.bar_mmio_read(memory, &mut data);
+        assert_eq!(data, [0xff; 4]);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn bus_master() {
+        let (tx, rx) = unbounded();
+        let mut adapter = PciAdapter::start(Box::new(DmaDevice(tx)));
+        let memory = VecMemory(Arc::new(std::sync::Mutex::new(vec![0; 0x4000])));
+        adapter.set_dma_memory(Box::new(memory.clone()));
+
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+
+        // The model forwards the config write instead of completing it.
+        let _ = adapter.config_write_async(STATUS_REG, 0, &[COMMAND_MEMORY_SPACE as u8]);
+        let write = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(write.header._type, PacketType::Config0Write(_)));
+
+        // The DMA write is dropped and both reads end as Unsupported Requests.
+        adapter.bar_mmio_write(0x1_0000_0000, &[0x1]);
+        for tag in 1..=2 {
+            let cpl = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            match cpl.header._type {
+                PacketType::Completion(extra) => {
+                    assert_eq!((extra.tag, extra.status), (tag, CPL_UR))
+                }
+                t => panic!("unexpected {:?}", t),
+            }
+        }
+        assert!(memory.0.lock().unwrap().iter().all(|&b| b == 0));
+        assert_eq!(adapter.stats().unwrap().errors, 3);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// An ATS capable device model which asks for the translations of 2 pages whenever its
+    /// BAR is written and acknowledges invalidations, every TLP it receives is forwarded to
+    /// the test.
+    struct AtsDevice(Sender<Tlp>);
+
+    impl PciSimDevice for AtsDevice {
+        fn features(&self) -> DeviceFeatures {
+            DeviceFeatures {
+                ats: true,
+                ..DeviceFeatures::default()
+            }
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                match tlp.header._type {
+                    PacketType::MemoryWrite64(_) => {
+                        let request = TlpBuilder::memory_read(MemoryExtra {
+                            requester: make_bdf(0, 3, 0),
+                            tag: 1,
+                            addr: 0x1000,
+                        })
+                        .address_type(AddressType::TranslationRequest)
+                        .byte_enable(0xff)
+                        .length(4)
+                        .build();
+                        lane.tx.send(request).unwrap();
+                        continue;
+                    }
+                    PacketType::MessageData(ATS_INVALIDATE_REQUEST) => {
+                        let cpl =
+                            TlpBuilder::with_type(PacketType::Message(ATS_INVALIDATE_COMPLETION))
+                                .build();
+                        lane.tx.send(cpl).unwrap();
+                    }
+                    _ => (),
+                }
+                self.0.send(tlp).unwrap();
+            }
+        }
+    }
+
+    /// Translates the page at 0x1000 to 0x8000_1000 read-only.
+    struct OnePageAgent;
+
+    impl TranslationAgent for OnePageAgent {
+        fn translate(&self, _: u16, iova: u64) -> Option<AtsTranslation> {
+            if iova != 0x1000 {
+                return None;
+            }
+
+            Some(AtsTranslation {
+                addr: 0x8000_1000,
+                size: 0x1000,
+                read: true,
+                write: false,
+                untranslated_only: false,
+            })
+        }
+    }
+
+    #[test]
+    fn ats() {
+        let (tx, rx) = unbounded();
+        let mut adapter = PciAdapter::start(Box::new(AtsDevice(tx)));
+        adapter.set_translation_agent(Box::new(OnePageAgent));
+
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        adapter.bar_mmio_write(0x1_0000_0000, &[0x1]);
+
+        let cpl = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        match cpl.header._type {
+            PacketType::CompletionData(extra) => {
+                assert_eq!((extra.tag, extra.status, extra.byte_count), (1, CPL_SC, 16));
+            }
+            t => panic!("unexpected {:?}", t),
+        }
+        let data = cpl.data.unwrap();
+        assert_eq!(
+            AtsTranslation::from_entry([data[0], data[1]]).addr,
+            0x8000_1000
+        );
+        assert_eq!(
+            AtsTranslation::from_entry([data[2], data[3]]),
+            AtsTranslation::fault()
+        );
+
+        assert_eq!(adapter.invalidate_ats(0x1000, 0x2000), Ok(()));
+        let request = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(request.data, Some(vec![0, 0x800]));
+
+        let unmap = IommuEvent::Unmap {
+            iova: 0x1800,
+            size: 0x1000,
+        };
+        assert_eq!(adapter.notify_iommu(unmap), Ok(()));
+        let event = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(IommuEvent::from_message(&event), Some(unmap));
+        for range in [[0, 0x1000], [0, 0x2000]].iter() {
+            let request = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(request.data.as_deref(), Some(&range[..]));
+        }
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model which faults in 2 pages whenever its BAR is written and reports the
+    /// response code to the test.
+    struct PriDevice(Sender<u8>);
+
+    impl PciSimDevice for PriDevice {
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                if let PacketType::MemoryWrite64(_) = tlp.header._type {
+                    lane.request_pages(make_bdf(0, 3, 0), 5, &[0x1000, 0x3000], true)
+                        .unwrap();
+                    let response = lane.await_prg_response(5, |_| ()).unwrap();
+                    self.0.send(response).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Accepts every group, the pages are forwarded to the test.
+    struct ChannelPageHandler(Sender<Vec<u64>>);
+
+    impl PageRequestHandler for ChannelPageHandler {
+        fn page_request(&self, _: u16, pages: &[PageRequestExtra]) -> u8 {
+            self.0.send(pages.iter().map(|p| p.addr).collect()).unwrap();
+            PRG_SUCCESS
+        }
+    }
+
+    #[test]
+    fn page_request() {
+        let (tx, rx) = unbounded();
+        let mut adapter = PciAdapter::start(Box::new(PriDevice(tx)));
+
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        adapter.bar_mmio_write(0x1_0000_0000, &[0x1]);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok(PRG_RESPONSE_FAILURE)
+        );
+
+        let (pages_tx, pages) = unbounded();
+        adapter.set_page_request_handler(Box::new(ChannelPageHandler(pages_tx)));
+        adapter.bar_mmio_write(0x1_0000_0000, &[0x1]);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(PRG_SUCCESS));
+        assert_eq!(pages.try_recv(), Ok(vec![0x1000, 0x3000]));
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn multi_function() {
+        let mut adapters = PciAdapter::start_functions(vec![
+            Box::new(PciTestDevice::new()),
+            Box::new(UnsupportedDevice),
+        ]);
+        assert_eq!(adapters.len(), 2);
+        assert_eq!(adapters[1].function(), 1);
+
+        assert_eq!(adapters[0].try_config_read(0), Ok(0x5678_1234));
+        assert_eq!(
+            adapters[1].try_config_read(0),
+            Err(PciAdapterError::Completion(CPL_UR))
+        );
+        let header = adapters[0].read_config_register(HEADER_TYPE_REG);
+        assert_ne!(header & HEADER_TYPE_MULTI_FUNCTION, 0);
+
+        adapters[0].stop();
+        for adapter in adapters {
+            adapter.join();
+        }
+
+        let mut adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        let header = adapter.read_config_register(HEADER_TYPE_REG);
+        assert_eq!(header & HEADER_TYPE_MULTI_FUNCTION, 0);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model with the BDF it is given which answers a config read, then reads
+    /// 0x1000_0000 and hands what it receives next to the test.
+    struct PeerDevice(u16, Sender<Tlp>);
+
+    impl PciSimDevice for PeerDevice {
+        fn run(&mut self, lane: &PciLane) {
+            let requester = self.0;
+            while let Ok(tlp) = lane.rx.recv() {
+                match tlp.header._type {
+                    PacketType::Config0Read(extra) => {
+                        let cpl = TlpBuilder::completion_data(CompletionExtra {
+                            requester: extra.requester,
+                            completer: requester,
+                            tag: extra.tag,
+                            status: CPL_SC,
+                            bcm: false,
+                            byte_count: 4,
+                            lower_address: 0,
+                        })
+                        .data(vec![0xabcd_0000])
+                        .build();
+                        let read = TlpBuilder::memory_read64(Memory64Extra {
+                            requester,
+                            tag: 7,
+                            addr: 0x1000_0000,
+                        })
+                        .byte_enable(0x0f)
+                        .length(1)
+                        .build();
+
+                        lane.tx.send(cpl).unwrap();
+                        lane.tx.send(read).unwrap();
+                    }
+                    _ => self.1.send(tlp).unwrap(),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hierarchy() {
+        let (tx, rx) = unbounded();
+        let adapters = PciAdapter::start_hierarchy(vec![
+            (make_bdf(0, 3, 0), Box::new(PciTestDevice::new())),
+            (
+                make_bdf(0, 4, 0),
+                Box::new(PeerDevice(make_bdf(0, 4, 0), tx)),
+            ),
+        ]);
+        assert_eq!(adapters[1].bdf(), 0x20);
+
+        adapters[0].route_memory(vec![(0x1000_0000..0x1000_1000)]);
+        assert_eq!(adapters[0].try_config_read(0), Ok(0x5678_1234));
+        assert_eq!(adapters[1].try_config_read(0), Ok(0xabcd_0000));
+
+        // The read of the peer goes to the window of the test device, whose completion is
+        // steered back to the peer by requester ID.
+        let cpl = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        match cpl.header._type {
+            PacketType::CompletionData(extra) => {
+                assert_eq!(extra.requester, make_bdf(0, 4, 0));
+                assert_eq!(extra.tag, 7);
+            }
+            _ => panic!("unexpected TLP {:?}", cpl.header._type),
+        }
+        assert_eq!(cpl.data, Some(vec![0x1234_5678]));
+
+        adapters[0].stop();
+        for adapter in adapters {
+            adapter.join();
+        }
+    }
+
+    #[test]
+    fn acs() {
+        let (tx, rx) = unbounded();
+        let adapters = PciAdapter::start_hierarchy(vec![
+            (make_bdf(0, 3, 0), Box::new(PciTestDevice::new())),
+            (
+                make_bdf(0, 4, 0),
+                Box::new(PeerDevice(make_bdf(0, 4, 0), tx)),
+            ),
+        ]);
+        adapters[0].route_memory(vec![(0x1000_0000..0x1000_1000)]);
+        let mut port = AcsPort::new(AcsPort::REQUEST_REDIRECT | AcsPort::EGRESS_CONTROL);
+        let status = || {
+            assert_eq!(adapters[1].try_config_read(0), Ok(0xabcd_0000));
+            match rx
+                .recv_timeout(Duration::from_secs(1))
+                .unwrap()
+                .header
+                ._type
+            {
+                PacketType::CompletionData(extra) | PacketType::Completion(extra) => extra.status,
+                other => panic!("unexpected TLP {:?}", other),
+            }
+        };
+
+        // The port lets the read of the peer through until the guest enables ACS.
+        adapters[1].set_acs(&port);
+        assert_eq!(status(), CPL_SC);
+
+        // The egress to device 3 is blocked, the read is aborted by the bridge.
+        port.write(0, 2, &AcsPort::EGRESS_CONTROL.to_le_bytes());
+        port.write(1, 0, &(1u32 << 3).to_le_bytes());
+        adapters[1].set_acs(&port);
+        assert_eq!(status(), CPL_CA);
+        assert_eq!(adapters[1].stats().unwrap().errors, 1);
+
+        // Redirected to the root complex the read is for the guest memory, which is not there.
+        port.write(0, 2, &AcsPort::REQUEST_REDIRECT.to_le_bytes());
+        adapters[1].set_acs(&port);
+        assert_eq!(status(), CPL_UR);
+
+        adapters[0].stop();
+        for adapter in adapters {
+            adapter.join();
+        }
+    }
+
+    #[test]
+    fn peer_to_peer() {
+        let (tx, rx) = unbounded();
+        let requester = PciAdapter::start(Box::new(PeerDevice(make_bdf(0, 3, 0), tx)));
+        let completer = PciAdapter::start(Box::new(PciTestDevice::new()));
+        let bus = PeerBus::new();
+        requester.join_peer_bus(&bus);
+        completer.join_peer_bus(&bus);
+        let bar = 0x1000_0000..0x1000_1000;
+        completer.route_memory(vec![bar]);
+        assert_eq!(completer.try_config_read(0), Ok(0x5678_1234));
+
+        // The read crosses to the other bridge, which sends it with a tag of its own and
+        // steers the completion back with the requester ID and the tag of the device.
+        assert_eq!(requester.try_config_read(0), Ok(0xabcd_0000));
+        let cpl = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        match cpl.header._type {
+            PacketType::CompletionData(extra) => {
+                assert_eq!(extra.requester, make_bdf(0, 3, 0));
+                assert_eq!(extra.tag, 7);
+            }
+            _ => panic!("unexpected TLP {:?}", cpl.header._type),
+        }
+        assert_eq!(cpl.data, Some(vec![0x1234_5678]));
+
+        // Without the window the read is for the guest memory, which is not there.
+        completer.route_memory(vec![]);
+        assert_eq!(completer.try_config_read(0), Ok(0x5678_1234));
+        assert_eq!(requester.try_config_read(0), Ok(0xabcd_0000));
+        let cpl = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        match cpl.header._type {
+            PacketType::Completion(extra) => assert_eq!(extra.status, CPL_UR),
+            _ => panic!("unexpected TLP {:?}", cpl.header._type),
+        }
+
+        for adapter in [requester, completer] {
+            adapter.stop();
+            adapter.join();
+        }
+    }
+
+    /// A device model which answers a config read, then sends its messages and hands what it
+    /// receives next to the test along with its BDF.
+    struct MessageDevice(u16, Vec<Tlp>, Sender<(u16, Tlp)>);
+
+    impl PciSimDevice for MessageDevice {
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                match tlp.header._type {
+                    PacketType::Config0Read(extra) => {
+                        let cpl = TlpBuilder::completion_data(CompletionExtra {
+                            requester: extra.requester,
+                            completer: self.0,
+                            tag: extra.tag,
+                            status: CPL_SC,
+                            bcm: false,
+                            byte_count: 4,
+                            lower_address: 0,
+                        })
+                        .data(vec![0xabcd_0000])
+                        .build();
+                        lane.tx.send(cpl).unwrap();
+                        for message in self.1.drain(..) {
+                            lane.tx.send(message).unwrap();
+                        }
+                    }
+                    _ => self.2.send((self.0, tlp)).unwrap(),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ptm() {
+        let bdf = make_bdf(0, 3, 0);
+        let clock = SimClock::manual();
+        clock.advance(Duration::from_micros(5));
+        let guest = SimClock::manual();
+        guest.advance(Duration::from_secs(3));
+
+        for (source, master_time) in [(None, 5_000), (Some(guest), 3_000_000_000)] {
+            let (tx, rx) = unbounded();
+            let requests = vec![ptm::request(bdf), ptm::request(make_bdf(0, 9, 0))];
+            let adapter = PciAdapterBuilder::new()
+                .clock(clock.clone())
+                .start(Box::new(MessageDevice(bdf, requests, tx)));
+            if let Some(source) = source {
+                adapter.set_ptm_time_source(Box::new(source));
+            }
+
+            // The request of the device is answered, the one of a stranger is not.
+            assert_eq!(adapter.try_config_read(0), Ok(0xabcd_0000));
+            let (_, tlp) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(
+                PtmResponse::from_message(&tlp),
+                Some(PtmResponse {
+                    master_time,
+                    propagation_delay: 0,
+                })
+            );
+            assert_eq!(adapter.try_config_read(0), Ok(0xabcd_0000));
+            assert_eq!(adapter.stats().unwrap().errors, 1);
+
+            adapter.stop();
+            adapter.join();
+        }
+    }
+
+    #[test]
+    fn msix_requester() {
+        // A model raises the MSI-X vectors of its own functions only.
+        let stranger = MessageExtra::routed(
+            make_bdf(0, 9, 0),
+            MSIX_VECTOR_MESSAGE,
+            MessageRouting::RootComplex,
+        );
+        let messages = vec![TlpBuilder::message_data(stranger).data(vec![0]).build()];
+        let (tx, _rx) = unbounded();
+        let adapter = PciAdapter::start(Box::new(MessageDevice(make_bdf(0, 3, 0), messages, tx)));
+
+        assert_eq!(adapter.try_config_read(0), Ok(0xabcd_0000));
+        assert_eq!(adapter.try_config_read(0), Ok(0xabcd_0000));
+        assert_eq!(adapter.stats().unwrap().errors, 1);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    struct ChannelMessageHandler(Mutex<Sender<(MessageExtra, Option<Vec<u32>>)>>);
+
+    impl MessageHandler for ChannelMessageHandler {
+        fn message(&self, message: &MessageExtra, data: Option<&[u32]>) {
+            let data = data.map(|dw| dw.to_vec());
+            self.0.lock().unwrap().send((*message, data)).unwrap();
+        }
+    }
+
+    #[test]
+    fn messages() {
+        let (first, second) = (make_bdf(0, 3, 0), make_bdf(0, 4, 0));
+        let (tx, rx) = unbounded();
+        let upstream = vec![
+            TlpBuilder::message(MessageExtra::to_id(first, 0x7e, second)).build(),
+            TlpBuilder::message_data(MessageExtra::routed(
+                first,
+                0x7e,
+                MessageRouting::RootComplex,
+            ))
+            .data(vec![0x5a5a])
+            .build(),
+            TlpBuilder::message(MessageExtra::routed(first, 0x7e, MessageRouting::Broadcast))
+                .build(),
+            TlpBuilder::message(MessageExtra::to_address(first, 0x7f, 0x1000_0800)).build(),
+        ];
+        let adapters = PciAdapter::start_hierarchy(vec![
+            (first, Box::new(MessageDevice(first, upstream, tx.clone()))),
+            (second, Box::new(MessageDevice(second, vec![], tx))),
+        ]);
+        let (handler_tx, handler_rx) = unbounded();
+        adapters[0].set_message_handler(Box::new(ChannelMessageHandler(Mutex::new(handler_tx))));
+        adapters[1].route_memory(vec![(0x1000_0000..0x1000_1000)]);
+        let received = || {
+            let (bdf, tlp) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            match tlp.header._type {
+                PacketType::RoutedMessage(extra) | PacketType::RoutedMessageData(extra) => {
+                    (bdf, extra)
+                }
+                _ => panic!("unexpected TLP {:?}", tlp.header._type),
+            }
+        };
+
+        // Upstream, the messages go to the peer by ID and by address, to the handler when
+        // routed to the root complex, and a broadcast of a device goes nowhere.
+        assert_eq!(adapters[0].try_config_read(0), Ok(0xabcd_0000));
+        assert_eq!(
+            received(),
+            (second, MessageExtra::to_id(first, 0x7e, second))
+        );
+        assert_eq!(
+            received(),
+            (second, MessageExtra::to_address(first, 0x7f, 0x1000_0800))
+        );
+        assert_eq!(
+            handler_rx.recv_timeout(Duration::from_secs(1)),
+            Ok((
+                MessageExtra::routed(first, 0x7e, MessageRouting::RootComplex),
+                Some(vec![0x5a5a])
+            ))
+        );
+        assert_eq!(adapters[0].stats().unwrap().errors, 1);
+
+        // Downstream, the messages carry the requester ID of the bridge.
+        let bridge = make_bdf(0, 2, 0);
+        let broadcast = MessageExtra::routed(0, 0x7e, MessageRouting::Broadcast);
+        assert_eq!(adapters[0].send_message(broadcast, None), Ok(()));
+        let mut delivered = vec![received(), received()];
+        delivered.sort_by_key(|(bdf, _)| *bdf);
+        let broadcast = MessageExtra::routed(bridge, 0x7e, MessageRouting::Broadcast);
+        assert_eq!(delivered, vec![(first, broadcast), (second, broadcast)]);
+
+        let local = MessageExtra::routed(0, 0x7e, MessageRouting::Local);
+        assert_eq!(adapters[1].send_message(local, Some(vec![1])), Ok(()));
+        assert_eq!(received().0, second);
+        let by_address = MessageExtra::to_address(0, 0x7f, 0x1000_0000);
+        assert_eq!(adapters[0].send_message(by_address, None), Ok(()));
+        assert_eq!(received().0, second);
+
+        let nobody = make_bdf(0, 5, 0);
+        assert_eq!(
+            adapters[0].send_message(MessageExtra::to_id(0, 0x7e, nobody), None),
+            Err(PciAdapterError::InvalidBdf(nobody))
+        );
+        assert_eq!(
+            adapters[0].send_message(MessageExtra::to_address(0, 0x7e, 0x2000_0000), None),
+            Err(PciAdapterError::Completion(CPL_UR))
+        );
+        assert_eq!(
+            adapters[0].send_message(
+                MessageExtra::routed(0, 0x7e, MessageRouting::Gathered),
+                None
+            ),
+            Err(PciAdapterError::Unsupported)
+        );
+
+        adapters[0].stop();
+        for adapter in adapters {
+            adapter.join();
+        }
+    }
+
+    #[test]
+    fn disconnected() {
+        let adapter = PciAdapter::start(Box::new(DeadDevice));
+        let events = adapter.events();
+
+        // The device leaves on its own, which the bridge notices when its thread ends.
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(1)),
+            Ok(AdapterEvent::LaneDown { pending: 0 })
+        );
+        assert_eq!(
+            adapter.try_config_read(0),
+            Err(PciAdapterError::Disconnected)
+        );
+        assert_eq!(
+            adapter.try_config_write(1, 0, &[0x6]),
+            Err(PciAdapterError::Disconnected)
+        );
+        assert_eq!(adapter.config_read(0), u32::MAX);
+        assert_eq!(adapter.health(), Err(PciAdapterError::Disconnected));
+
+        // Requests left behind by the bridge do not wait forever either.
+        adapter.stop();
+        assert_eq!(
+            adapter.try_config_read(0),
+            Err(PciAdapterError::Disconnected)
+        );
+        adapter.join();
+    }
+
+    #[test]
+    fn model_exit() {
+        let adapters = PciAdapter::start_functions(vec![
+            Box::new(PciTestDevice::new()),
+            Box::new(OneShotDevice),
+        ]);
+        let events = adapters[0].events();
+
+        // The model of function 1 leaves without answering, only its functions are dropped.
+        assert_eq!(
+            adapters[1].try_config_read(0),
+            Err(PciAdapterError::Completion(CPL_UR))
+        );
+        assert_eq!(
+            adapters[1].try_config_read(0),
+            Err(PciAdapterError::Disconnected)
+        );
+        for _ in 0..4 {
+            assert_eq!(adapters[0].try_config_read(0), Ok(0x5678_1234));
+        }
+        assert_eq!(adapters[0].health(), Ok(()));
+        assert!(events.try_recv().is_err());
+
+        adapters[0].stop();
+        for adapter in adapters {
+            adapter.join();
+        }
+    }
+
+    #[test]
+    fn resync() {
+        let adapter = PciAdapter::start(Box::new(OneShotDevice));
+        let events = adapter.events();
+        adapter.set_disconnect_policy(DisconnectPolicy::Resync {
+            window: Duration::from_secs(10),
+        });
+
+        let (lane, device_lane) = PciLane::pair();
+        let device = std::thread::spawn(move || PciTestDevice::new().run(&device_lane));
+
+        std::thread::scope(|s| {
+            let read = s.spawn(|| adapter.try_config_read(0));
+            assert_eq!(events.recv(), Ok(AdapterEvent::LaneDown { pending: 1 }));
+            adapter.reconnect(lane, PciTestDevice::new().features());
+            assert_eq!(read.join().unwrap(), Ok(0x5678_1234));
+        });
+        assert_eq!(events.recv(), Ok(AdapterEvent::LaneUp { replayed: 1 }));
+
+        adapter.stop();
+        adapter.join();
+        device.join().unwrap();
+    }
+
+    #[test]
+    fn negotiation() {
+        let adapter = PciAdapter::start(Box::new(SilentDevice));
+        assert_eq!(adapter.features(), Ok(DeviceFeatures::default()));
+
+        let (lane, _device_lane) = PciLane::pair();
+        let features = DeviceFeatures {
+            extended_tags: true,
+            ten_bit_tags: true,
+            ats: true,
+            extra_virtual_channels: 3,
+            ..DeviceFeatures::default()
+        };
+        adapter.reconnect(lane, features);
+        let features = adapter.features().unwrap();
+        assert!(features.extended_tags);
+        assert!(!features.ten_bit_tags);
+        assert!(features.ats);
+        assert_eq!(features.extra_virtual_channels, 0);
+        assert_eq!(features.tags(), 256);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn tag_recycling() {
+        let adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+
+        for _ in 0..DEFAULT_TAGS * 2 {
+            assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        }
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn builder() {
+        let adapter = PciAdapterBuilder::new()
+            .bdf(1, 0, 0)
+            .completer(1, 2, 0)
+            .lane_capacity(4)
+            .bridge_thread_name("bridge")
+            .device_thread_name("device")
+            .start(Box::new(PciTestDevice::new()));
+        assert_eq!(adapter.bdf(), make_bdf(1, 2, 0));
+        for _ in 0..16 {
+            assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        }
+
+        adapter.stop();
+        adapter.join();
+
+        let adapter = PciAdapterBuilder::new()
+            .completion_timeout(Duration::from_millis(10))
+            .start(Box::new(SilentDevice));
+        assert_eq!(adapter.try_config_read(0), Err(PciAdapterError::Timeout));
+
+        adapter.stop();
+        adapter.join();
+
+        // The lanes handed over on a reset are striped too.
+        let adapter = PciAdapterBuilder::new()
+            .multi_lane(MultiLane::new(4).capacity(2))
+            .start(Box::new(PciTestDevice::new()));
+        for _ in 0..16 {
+            assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        }
+        adapter.hot_reset().unwrap();
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn bar_sizes() {
+        let sizes = [
+            0xfff0_000c,
+            0xffff_ffff,
+            0xffff_f000,
+            0xffff_ff01,
+            0x0000_ff01,
+            0x0000_0000,
+        ];
+        let regions = PciAdapter::bar_regions(&sizes);
+        let lengths: Vec<_> = regions.iter().map(|r| (r.bar_reg, r.length)).collect();
+        assert_eq!(
+            lengths,
+            vec![(4, 0x10_0000), (6, 0x1000), (7, 0x100), (8, 0x100)]
+        );
+
+        // Size bits reading back all zero must not overflow.
+        let regions = PciAdapter::bar_regions(&[0xc, 0, 0, 0, 0, 0]);
+        assert_eq!(regions[0].length, 0);
+    }
+
+    #[test]
+    fn read_block() {
+        assert_eq!(split_request(0x7e, 4, 128), vec![(0x7e, 2), (0x80, 2)]);
+        assert_eq!(
+            super::split_request(0x1000, 0x1000, 4096),
+            vec![(0x1000, 0x1000)]
+        );
+        assert_eq!(split_request(0x1000, 0, 4096), vec![]);
+
+        let mut adapter = PciAdapterBuilder::new()
+            .max_read_request_size(100)
+            .start(Box::new(PciTestDevice::new()));
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x10_0000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+
+        // 34 parts of 128 bytes at most, put back together in order.
+        let mut data = vec![0; 0x1006];
+        adapter
+            .try_bar_mmio_read_block(0x1_0000_007e, &mut data)
+            .unwrap();
+        let pattern = 0x1234_5678u32.to_be_bytes();
+        assert!(data
+            .iter()
+            .enumerate()
+            .all(|(i, &b)| b == pattern[(i + 2) % 4]));
+        assert_eq!(adapter.stats().unwrap().sent.memory_read, 34);
+
+        assert_eq!(
+            adapter.try_bar_mmio_read_block(0x1_000f_ff00, &mut data),
+            Err(PciAdapterError::InvalidSize(0x1006))
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn memory_header() {
+        let (tx, rx) = unbounded();
+        let mut adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        adapter.add_tlp_observer(Box::new(move |direction, tlp: &Tlp| {
+            if direction == Direction::Downstream {
+                let _ = tx.send(tlp.header._type);
+            }
+        }));
+        for (start, type_) in [
+            (0xc000_0000, PciBarRegionType::Memory32BitRegion),
+            (0x1_0000_0000, PciBarRegionType::Memory64BitRegion),
+        ] {
+            adapter.mmio_regions.push(MmioRegion {
+                start: GuestAddress(start),
+                length: 0x1000,
+                type_,
+                bar_reg: BAR0_REG,
+                mem_slot: None,
+                host_addr: None,
+                mmap_size: None,
+                slot_mapped: false,
             });
+        }
 
-            bar_reg += if is_64bit { 2 } else { 1 };
+        // Requests below 4GB have a 3DW header, the others a 4DW one.
+        let mut data = [0u8; 4];
+        adapter.bar_mmio_write(0xc000_0004, &[0x5a; 4]);
+        adapter.bar_mmio_read(0xc000_0004, &mut data);
+        assert_eq!(data, [0x5a; 4]);
+        adapter.bar_mmio_write(0x1_0000_0008, &[0xa5; 4]);
+        adapter.bar_mmio_read(0x1_0000_0008, &mut data);
+        assert_eq!(data, [0xa5; 4]);
+        let requests: Vec<PacketType> = rx.try_iter().collect();
+        assert!(matches!(
+            requests[..],
+            [
+                PacketType::MemoryWrite(MemoryExtra {
+                    addr: 0xc000_0004,
+                    ..
+                }),
+                PacketType::MemoryRead(MemoryExtra {
+                    addr: 0xc000_0004,
+                    ..
+                }),
+                PacketType::MemoryWrite64(Memory64Extra {
+                    addr: 0x1_0000_0008,
+                    ..
+                }),
+                PacketType::MemoryRead64(Memory64Extra {
+                    addr: 0x1_0000_0008,
+                    ..
+                }),
+            ]
+        ));
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A model with the PCI Express capability whose memory reads as 0xab after a delay.
+    struct PayloadDevice(ConfigSpace, Duration);
+
+    impl SimpleDevice for PayloadDevice {
+        fn on_config_read(&mut self, function: u16, reg: usize) -> u32 {
+            self.0.on_config_read(function, reg)
         }
 
-        regions
+        fn on_config_write(&mut self, function: u16, reg: usize, offset: u64, data: &[u8]) {
+            self.0.on_config_write(function, reg, offset, data)
+        }
+
+        fn on_mem_read(&mut self, _addr: u64, data: &mut [u8]) -> std::result::Result<(), u8> {
+            std::thread::sleep(self.1);
+            data.fill(0xab);
+            Ok(())
+        }
     }
 
-    pub fn join(self) {
-        self.handle.join().unwrap();
+    #[test]
+    fn payload_sizes() {
+        let pci = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x0001,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0x5555,
+            0x6666,
+            None,
+        );
+        let mut config = PcieConfiguration::new(pci);
+        let cap = PcieCapability::new(PciePortType::Endpoint).max_payload(512);
+        let devctl = config.add_capability(&cap).unwrap() / 4 + DEVCTL_DW;
+        let mut adapter = PciAdapter::start(Box::new(PayloadDevice(
+            ConfigSpace::new(config),
+            Duration::ZERO,
+        )));
+        adapter.allocate_bars(&mut test_allocator()).unwrap();
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x10_0000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        let mut data = vec![0; 0x1000];
+        let transfer = |adapter: &PciAdapter, data: &mut [u8]| {
+            let before = adapter.stats().unwrap().sent;
+            adapter
+                .try_bar_mmio_read_block(0x1_0000_0000, data)
+                .unwrap();
+            assert!(data.iter().all(|&b| b == 0xab));
+            adapter.stats().unwrap().sent.memory_read - before.memory_read
+        };
+
+        // 128 bytes of payload and 512 bytes of read request after reset, the smaller
+        // Max Payload Size bounding the reads so each completes with one TLP.
+        assert_eq!(transfer(&adapter, &mut data), 32);
+
+        // The guest raises them to 256 and 1024 bytes, until the link resets.
+        adapter.write_config_register(devctl, 0, &(1u16 << 5 | 3 << 12).to_le_bytes());
+        assert_eq!(transfer(&adapter, &mut data), 16);
+        adapter.hot_reset().unwrap();
+        assert_eq!(transfer(&adapter, &mut data), 32);
+
+        // Nor does a write carry more than the Max Payload Size.
+        let writes = |adapter: &PciAdapter, addr| {
+            let before = adapter.stats().unwrap().sent;
+            adapter.try_bar_mmio_write(addr, &[0xcd; 8]).unwrap();
+            adapter.stats().unwrap().sent.memory_write - before.memory_write
+        };
+        assert_eq!(writes(&adapter, 0x1_0000_0078), 1);
+        assert_eq!(writes(&adapter, 0x1_0000_007e), 2);
+
+        adapter.stop();
+        adapter.join();
     }
 
-    pub fn stop(&self) {
-        self.tx.send(AdapterMessage::Exit).unwrap();
+    #[test]
+    fn function_completion_timeout() {
+        let pci = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x0001,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0x5555,
+            0x6666,
+            None,
+        );
+        let mut config = PcieConfiguration::new(pci);
+        let cap = PcieCapability::new(PciePortType::Endpoint).completion_timeout(0b0010, true);
+        let devctl2 = config.add_capability(&cap).unwrap() / 4 + DEVCTL2_DW;
+        let device = PayloadDevice(ConfigSpace::new(config), Duration::from_millis(30));
+        let mut adapter = PciAdapterBuilder::new()
+            .completion_timeout(Duration::from_millis(10))
+            .start(Box::new(device));
+        adapter.allocate_bars(&mut test_allocator()).unwrap();
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        let read = |adapter: &PciAdapter| adapter.try_bar_mmio_read(0x1_0000_0000, &mut [0; 4]);
+
+        // The default range is the completion timeout of the bridge.
+        assert_eq!(read(&adapter), Err(PciAdapterError::Timeout));
+
+        // The guest disables the timeout, then picks the range of 16 ms to 55 ms.
+        adapter.write_config_register(devctl2, 0, &[0x10]);
+        assert_eq!(read(&adapter), Ok(()));
+        adapter.write_config_register(devctl2, 0, &[0x5]);
+        assert_eq!(read(&adapter), Ok(()));
+
+        // A reset brings back the default range.
+        adapter.hot_reset().unwrap();
+        assert_eq!(read(&adapter), Err(PciAdapterError::Timeout));
+
+        adapter.stop();
+        adapter.join();
     }
 
-    pub fn start(mut device: Box<dyn PciSimDevice + Send + Sync>) -> PciAdapter {
-        let (lane, device_lane) = PciLane::pair();
-        let (tx, cmd_rx) = unbounded();
-        let handle = std::thread::spawn(move || device.as_mut().run(&device_lane));
-        let mut runner = PciSimBridge {
-            handle,
-            lane,
-            cmd_rx,
-            config_tag: 0,
-            store: HashMap::new(),
-            bdf: make_bdf(0x0, 0x2, 0x0),
-        };
+    /// A device model which takes no TLP off its lane until it is told to.
+    struct StalledDevice(Receiver<()>);
 
-        let handle = std::thread::spawn(move || {
-            runner.run();
+    impl PciSimDevice for StalledDevice {
+        fn run(&mut self, lane: &PciLane) {
+            let _ = self.0.recv();
+            while lane.rx.recv().is_ok() {}
+        }
+    }
+
+    #[test]
+    fn backpressure() {
+        let (go, stalled) = bounded(1);
+        let mut adapter = PciAdapterBuilder::new()
+            .lane_capacity(2)
+            .backpressure(BackpressurePolicy::DropPosted)
+            .start(Box::new(StalledDevice(stalled)));
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
         });
 
-        PciAdapter {
-            tx,
-            handle,
-            mmio_regions: vec![],
+        for i in 0..5 {
+            adapter.bar_mmio_write(0x1_0000_0000, &[i]);
         }
+        let stats = adapter.stats().unwrap();
+        assert_eq!((stats.sent.memory_write, stats.dropped), (2, 3));
+
+        drop(go);
+        adapter.stop();
+        adapter.join();
     }
-}
 
-const BAR0_REG: usize = 4;
-const NUM_BAR_REGS: usize = 6;
+    /// A device model with little room for posted requests, which takes no TLP off its lane
+    /// until it is told to and then reports them.
+    struct CreditDevice(Receiver<()>, Sender<Tlp>);
 
-impl PciDevice for PciAdapter {
-    fn write_config_register(
-        &mut self,
-        reg_idx: usize,
-        offset: u64,
-        data: &[u8],
-    ) -> Option<Arc<Barrier>> {
-        self.config_write(reg_idx, offset, data);
-        None
+    impl PciSimDevice for CreditDevice {
+        fn flow_control(&self) -> Option<FcCredits> {
+            Some(FcCredits {
+                posted: FcCredit { header: 2, data: 0 },
+                ..FcCredits::default()
+            })
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            let _ = self.0.recv();
+            while let Ok(tlp) = lane.rx.recv() {
+                let _ = self.1.send(tlp);
+            }
+        }
     }
 
-    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
-        self.config_read(reg_idx)
+    #[test]
+    fn flow_control() {
+        let (go, stalled) = bounded(1);
+        let (tx, rx) = unbounded();
+        let mut adapter = PciAdapter::start(Box::new(CreditDevice(stalled, tx)));
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+
+        // The writes beyond the credits are held back by the bridge, not lost.
+        for i in 0..5 {
+            adapter.bar_mmio_write(0x1_0000_0000, &[i]);
+        }
+        let stats = adapter.stats().unwrap();
+        assert_eq!((stats.sent.memory_write, stats.dropped), (2, 0));
+
+        drop(go);
+        for i in 0..5 {
+            let tlp = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(tlp.data.unwrap()[0] >> 24, i);
+        }
+        assert_eq!(adapter.stats().unwrap().sent.memory_write, 5);
+
+        adapter.stop();
+        adapter.join();
     }
 
-    fn allocate_bars(
-        &mut self,
-        allocator: &mut SystemAllocator,
-    ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
-    {
-        use PciBarRegionType::*;
+    /// A device model which, once told to, DMA writes 1 and then a relaxed 2 at 0x1000 and
+    /// raises a relaxed MSI. It answers config reads with relaxed completions.
+    struct RelaxedDevice(Receiver<()>, Sender<()>);
 
-        let mut ranges = vec![];
-        let mut regions = self.scan_bar();
-        self.mmio_regions.clear();
+    impl PciSimDevice for RelaxedDevice {
+        fn run(&mut self, lane: &PciLane) {
+            let requester = make_bdf(0, 3, 0);
+            let write = |addr, data, relaxed| {
+                let mut tlp = TlpBuilder::memory_write64(Memory64Extra {
+                    requester,
+                    tag: 0,
+                    addr,
+                })
+                .byte_enable(0xf)
+                .data(vec![data])
+                .build();
+                tlp.header.relax_ordering = relaxed;
+                tlp
+            };
 
-        for region in regions.iter_mut() {
-            match region.type_ {
-                Memory64BitRegion => {
-                    region.start = allocator
-                        .allocate_mmio_addresses(None, region.length, Some(0x10))
-                        .ok_or(PciDeviceError::IoAllocationFailed(region.length))?;
-                    self.config_write_u32(
-                        region.bar_reg + 1,
-                        (region.start.raw_value() >> 32) as u32,
-                    );
-                    self.config_write_u32(region.bar_reg, region.start.raw_value() as u32);
-                }
-                Memory32BitRegion => {
-                    region.start = allocator
-                        .allocate_mmio_hole_addresses(None, region.length, Some(0x10))
-                        .ok_or(PciDeviceError::IoAllocationFailed(region.length))?;
-                    self.config_write_u32(region.bar_reg, region.start.raw_value() as u32);
-                }
-                IoRegion => {
-                    region.start = allocator
-                        .allocate_io_addresses(None, region.length, Some(0x4))
-                        .ok_or(PciDeviceError::IoAllocationFailed(region.length))?;
-                    debug!("write io addr {:#x}", region.start.raw_value());
-                    self.config_write_u32(region.bar_reg, region.start.raw_value() as u32);
+            let _ = self.0.recv();
+            lane.tx.send(write(0x1000, 1, false)).unwrap();
+            lane.tx.send(write(0x1000, 2, true)).unwrap();
+            lane.tx.send(write(MSI_DOORBELL_BASE, 0x21, true)).unwrap();
+            let _ = self.1.send(());
+
+            while let Ok(tlp) = lane.rx.recv() {
+                if let PacketType::Config0Read(extra) = tlp.header._type {
+                    let mut cpl = TlpBuilder::completion_data(CompletionExtra {
+                        requester: extra.requester,
+                        completer: extra.completer,
+                        tag: extra.tag,
+                        status: CPL_SC,
+                        bcm: false,
+                        byte_count: 4,
+                        lower_address: 0,
+                    })
+                    .byte_enable(0xf)
+                    .data(vec![0])
+                    .build();
+                    cpl.header.relax_ordering = true;
+                    let _ = lane.tx.send(cpl);
                 }
             }
+        }
+    }
 
-            debug!(
-                "allocate BAR reg{}; address: {:#x}; region_type: {}",
-                region.bar_reg,
-                region.start.raw_value(),
-                region.type_ as u8
-            );
+    #[test]
+    fn ordering() {
+        let memory = VecMemory(Arc::new(std::sync::Mutex::new(vec![0; 0x2000])));
 
-            ranges.push((region.start, region.length, region.type_));
-            self.mmio_regions.push(*region);
+        // The bridge holds still in the observer of the BAR write until the model sent its
+        // TLPs, which are then reordered together: the relaxed write goes first and is
+        // overwritten.
+        let (go_tx, go_rx) = bounded(1);
+        let (sent_tx, sent_rx) = bounded(1);
+        let mut adapter = PciAdapterBuilder::new()
+            .ordering(OrderingPolicy::Reorder)
+            .start(Box::new(RelaxedDevice(go_rx, sent_tx)));
+        adapter.set_dma_memory(Box::new(memory.clone()));
+        adapter.add_tlp_observer(Box::new(move |direction, tlp: &Tlp| {
+            if direction == Direction::Downstream
+                && matches!(tlp.header._type, PacketType::MemoryWrite64(_))
+            {
+                let _ = go_tx.send(());
+                let _ = sent_rx.recv();
+            }
+        }));
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        adapter.bar_mmio_write(0x1_0000_0000, &[0]);
+        adapter.config_read(0);
+        assert_eq!(memory.0.lock().unwrap()[0x1000..0x1004], [0, 0, 0, 1]);
+        assert_eq!(adapter.stats().unwrap().violations, 0);
+        adapter.stop();
+        adapter.join();
+
+        // In order, the relaxed MSI and completion are flagged.
+        let (go_tx, go_rx) = bounded(1);
+        let (sent_tx, sent_rx) = bounded(1);
+        let adapter = PciAdapterBuilder::new()
+            .ordering(OrderingPolicy::Strict)
+            .start(Box::new(RelaxedDevice(go_rx, sent_tx)));
+        adapter.set_dma_memory(Box::new(memory.clone()));
+        // The memory is set once the bridge answers a later command.
+        adapter.stats().unwrap();
+        go_tx.send(()).unwrap();
+        sent_rx.recv().unwrap();
+        adapter.config_read(0);
+        assert_eq!(memory.0.lock().unwrap()[0x1000..0x1004], [0, 0, 0, 2]);
+        assert_eq!(adapter.stats().unwrap().violations, 2);
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model which never answers, but reports every TLP and its own exit.
+    struct ReportingDevice(Sender<Option<Tlp>>);
+
+    impl PciSimDevice for ReportingDevice {
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                let _ = self.0.send(Some(tlp));
+            }
+            let _ = self.0.send(None);
         }
+    }
+
+    /// A device model which panics on the first TLP.
+    struct PanicDevice;
 
-        Ok(ranges)
+    impl PciSimDevice for PanicDevice {
+        fn run(&mut self, lane: &PciLane) {
+            let _ = lane.rx.recv();
+            panic!("device model bug");
+        }
     }
 
-    fn free_bars(
-        &mut self,
-        allocator: &mut SystemAllocator,
-    ) -> std::result::Result<(), PciDeviceError> {
-        for region in self.mmio_regions.iter() {
-            match region.type_ {
-                PciBarRegionType::IoRegion => {
-                    #[cfg(target_arch = "x86_64")]
-                    allocator.free_io_addresses(region.start, region.length);
-                    #[cfg(target_arch = "aarch64")]
-                    error!("I/O region is not supported");
-                }
-                PciBarRegionType::Memory32BitRegion => {
-                    allocator.free_mmio_hole_addresses(region.start, region.length);
-                }
-                PciBarRegionType::Memory64BitRegion => {
-                    allocator.free_mmio_addresses(region.start, region.length);
+    #[test]
+    fn device_panic() {
+        let mut adapters = PciAdapter::start_hierarchy(vec![
+            (make_bdf(0, 3, 0), Box::new(PanicDevice)),
+            (make_bdf(0, 4, 0), Box::new(PciTestDevice::new())),
+        ]);
+        let events = adapters[0].events();
+        assert_eq!(adapters[0].health(), Ok(()));
+
+        assert_eq!(
+            adapters[0].try_config_read(0),
+            Err(PciAdapterError::DevicePanicked)
+        );
+        assert_eq!(
+            events.recv(),
+            Ok(AdapterEvent::DevicePanicked {
+                bdf: make_bdf(0, 3, 0)
+            })
+        );
+        assert_eq!(adapters[0].health(), Err(PciAdapterError::DevicePanicked));
+        assert_eq!(
+            adapters[0].try_config_read(0),
+            Err(PciAdapterError::DevicePanicked)
+        );
+
+        // The other device of the hierarchy is not affected.
+        assert_eq!(adapters[1].health(), Ok(()));
+        assert_eq!(adapters[1].try_config_read(0), Ok(0x5678_1234));
+
+        adapters[0].stop();
+        adapters.remove(0).join();
+    }
+
+    #[test]
+    fn shutdown() {
+        let (tx, rx) = unbounded();
+        let adapter = PciAdapter::start(Box::new(ReportingDevice(tx)));
+        adapter.set_completion_timeout(Duration::from_millis(50));
+
+        std::thread::scope(|s| {
+            let read = s.spawn(|| adapter.try_config_read(0));
+            assert!(rx.recv().unwrap().is_some());
+
+            // The outstanding read still gets its completion timeout.
+            adapter.stop();
+            assert_eq!(read.join().unwrap(), Err(PciAdapterError::Timeout));
+        });
+        assert_eq!(
+            adapter.try_config_read(0),
+            Err(PciAdapterError::Disconnected)
+        );
+
+        // The device model is gone once the adapter is joined.
+        adapter.join();
+        assert!(rx.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn async_requests() {
+        let adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+
+        let reads: Vec<_> = (0..8).map(|_| adapter.config_read_async(0)).collect();
+        let write = adapter.config_write_async(1, 0, &[0x6]);
+        for read in reads {
+            assert_eq!(read.wait(), Ok(0x5678_1234));
+        }
+        assert_eq!(write.wait(), Ok(()));
+        assert_eq!(
+            adapter.config_read_async(PCIE_CONFIG_REGS).try_wait(),
+            Some(Err(PciAdapterError::InvalidRegister(PCIE_CONFIG_REGS)))
+        );
+
+        adapter.stop();
+        adapter.join();
+
+        let adapter = PciAdapterBuilder::new()
+            .completion_timeout(Duration::from_millis(50))
+            .start(Box::new(SilentDevice));
+        let read = adapter.config_read_async(0);
+        assert_eq!(read.try_wait(), None);
+        assert_eq!(read.wait_timeout(Duration::from_millis(10)), None);
+
+        adapter.stop();
+        adapter.join();
+        assert_eq!(read.wait(), Err(PciAdapterError::Timeout));
+    }
+
+    #[test]
+    fn stats() {
+        let adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        let before = adapter.stats().unwrap();
+
+        for _ in 0..4 {
+            assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        }
+        adapter.config_write(1, 0, &[0x6]);
+
+        let stats = adapter.stats().unwrap();
+        assert_eq!(stats.sent.config_read - before.sent.config_read, 4);
+        assert_eq!(stats.sent.config_write - before.sent.config_write, 1);
+        assert_eq!(stats.received.completion - before.received.completion, 5);
+        assert_eq!(stats.completions - before.completions, 5);
+        assert_eq!(stats.bytes_sent - before.bytes_sent, 4);
+        assert_eq!(stats.bytes_received - before.bytes_received, 16);
+        assert_eq!(stats.outstanding, 0);
+
+        adapter.stop();
+        adapter.join();
+
+        let adapter = PciAdapterBuilder::new()
+            .completion_timeout(Duration::from_millis(10))
+            .start(Box::new(SilentDevice));
+        assert_eq!(adapter.try_config_read(0), Err(PciAdapterError::Timeout));
+
+        let stats = adapter.stats().unwrap();
+        assert_eq!(stats.timeouts, 1);
+        assert_eq!(stats.completions, 0);
+        assert_eq!(stats.received.total(), 0);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn tlp_observer() {
+        let adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        let (tx, rx) = unbounded();
+        adapter.add_tlp_observer(Box::new(move |direction, tlp: &Tlp| {
+            let _ = tx.send((direction, tlp.header._type));
+        }));
+
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        let seen: Vec<_> = rx.try_iter().collect();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0].0, Direction::Downstream);
+        assert!(matches!(seen[0].1, PacketType::Config0Read(_)));
+        assert_eq!(seen[1].0, Direction::Upstream);
+        assert!(matches!(seen[1].1, PacketType::CompletionData(_)));
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn hotplug() {
+        let adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        let bdf = make_bdf(0, 4, 0);
+        let mut slot = HotplugSlot::new(false).device(0, 4);
+
+        let mut plugged = adapter
+            .hotplug(bdf, Box::new(PciTestDevice::new()))
+            .unwrap();
+        let event = adapter.events().recv().unwrap();
+        assert_eq!(event, AdapterEvent::Attached { bdf });
+        slot.on_event(&event);
+        assert!(slot.is_present());
+        assert_eq!(plugged.try_config_read(0), Ok(0x5678_1234));
+        assert_eq!(
+            adapter.hotplug(bdf, Box::new(PciTestDevice::new())).err(),
+            Some(PciAdapterError::InvalidBdf(bdf))
+        );
+        assert_eq!(
+            adapter
+                .hotplug(make_bdf(0, 5, 0), Box::new(UnsupportedDevice))
+                .err(),
+            Some(PciAdapterError::Unsupported)
+        );
+
+        let mut allocator = test_allocator();
+        plugged.allocate_bars(&mut allocator).unwrap();
+        plugged.unplug(&mut allocator).unwrap();
+        let event = adapter.events().recv().unwrap();
+        assert_eq!(event, AdapterEvent::Detached { bdf });
+        slot.on_event(&event);
+        assert!(!slot.is_present());
+        assert_eq!(
+            plugged.try_config_read(0),
+            Err(PciAdapterError::Disconnected)
+        );
+        assert_eq!(
+            plugged.unplug(&mut allocator),
+            Err(PciAdapterError::InvalidBdf(bdf))
+        );
+
+        // The device started with the bridge is not affected.
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        assert_eq!(adapter.health(), Ok(()));
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model whose only register, the vendor and device ID, is writable and migrates
+    /// with it.
+    #[derive(Default)]
+    struct ScratchDevice(Arc<std::sync::Mutex<u32>>);
+
+    struct ScratchState(Arc<std::sync::Mutex<u32>>);
+
+    impl DeviceState for ScratchState {
+        fn save(&self) -> Vec<u8> {
+            self.0.lock().unwrap().to_le_bytes().to_vec()
+        }
+
+        fn restore(&self, state: &[u8]) -> io::Result<()> {
+            let value = std::convert::TryInto::try_into(state)
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+            *self.0.lock().unwrap() = u32::from_le_bytes(value);
+            Ok(())
+        }
+    }
+
+    impl PciSimDevice for ScratchDevice {
+        fn state(&self) -> Option<Arc<dyn DeviceState>> {
+            Some(Arc::new(ScratchState(self.0.clone())))
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                let (extra, data) = match tlp.header._type {
+                    PacketType::Config0Read(extra) if extra.reg == 0 => {
+                        (extra, Some(vec![*self.0.lock().unwrap()]))
+                    }
+                    PacketType::Config0Write(extra) if extra.reg == 0 => {
+                        *self.0.lock().unwrap() = tlp.data.unwrap()[0];
+                        (extra, None)
+                    }
+                    PacketType::Config0Read(extra) => (extra, Some(vec![0])),
+                    PacketType::Config0Write(extra) => (extra, None),
+                    _ => continue,
+                };
+
+                let cpl = CompletionExtra {
+                    requester: extra.requester,
+                    completer: extra.completer,
+                    tag: extra.tag,
+                    status: CPL_SC,
+                    bcm: false,
+                    byte_count: 4,
+                    lower_address: 0,
+                };
+                let tlp = match data {
+                    Some(data) => TlpBuilder::completion_data(cpl).data(data).build(),
+                    None => TlpBuilder::completion(cpl).build(),
+                };
+                let _ = lane.tx.send(tlp);
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut adapter = PciAdapter::start(Box::new(ScratchDevice::default()));
+        adapter.config_write(0, 0, &[0x78, 0x56, 0x34, 0x12]);
+        adapter.rom_enabled = true;
+        let snapshot = adapter.snapshot().unwrap();
+        assert!(snapshot.pending.is_empty());
+        assert_eq!(snapshot.device, 0x1234_5678u32.to_le_bytes());
+        adapter.stop();
+        adapter.join();
+
+        let snapshot = AdapterSnapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        let mut adapter = PciAdapter::start(Box::new(ScratchDevice::default()));
+        adapter.restore(&snapshot).unwrap();
+        assert_eq!(adapter.try_config_read(0), Ok(0x1234_5678));
+        assert!(adapter.rom_enabled);
+
+        // A model without state can not take the state of another.
+        let mut other = PciAdapter::start(Box::new(PciTestDevice::new()));
+        assert_eq!(
+            other.restore(&snapshot),
+            Err(PciAdapterError::InvalidSnapshot)
+        );
+
+        other.stop();
+        other.join();
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model counting the reads of its vendor and device ID, which it answers with
+    /// the count. The count is saved by the model itself.
+    struct CountingDevice(u32);
+
+    impl PciSimDevice for CountingDevice {
+        fn state_save(&mut self, _: u16) -> Option<Vec<u8>> {
+            Some(self.0.to_le_bytes().to_vec())
+        }
+
+        fn state_restore(&mut self, _: u16, state: &[u8]) -> io::Result<()> {
+            let count = std::convert::TryInto::try_into(state)
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+            self.0 = u32::from_le_bytes(count);
+            Ok(())
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                if let PacketType::Config0Read(extra) = tlp.header._type {
+                    self.0 += (extra.reg == 0) as u32;
+                    let cpl = CompletionExtra {
+                        requester: extra.requester,
+                        completer: extra.completer,
+                        tag: extra.tag,
+                        status: CPL_SC,
+                        bcm: false,
+                        byte_count: 4,
+                        lower_address: 0,
+                    };
+                    let data = if extra.reg == 0 { self.0 } else { 0 };
+                    let _ = lane
+                        .tx
+                        .send(TlpBuilder::completion_data(cpl).data(vec![data]).build());
                 }
             }
         }
-        Ok(())
     }
 
-    fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
-        self.bar_mmio_read(base + offset, data);
+    #[test]
+    fn quiesced_snapshot() {
+        let adapter = PciAdapter::start(Box::new(CountingDevice(0)));
+        assert_eq!(adapter.try_config_read(0), Ok(1));
+        assert_eq!(adapter.try_config_read(0), Ok(2));
+        let snapshot = adapter.snapshot().unwrap();
+        assert_eq!(snapshot.device, 2u32.to_le_bytes());
+        // The model runs on after the save.
+        assert_eq!(adapter.try_config_read(0), Ok(3));
+        adapter.stop();
+        adapter.join();
+
+        let mut adapter = PciAdapter::start(Box::new(CountingDevice(0)));
+        adapter.restore(&snapshot).unwrap();
+        assert_eq!(adapter.try_config_read(0), Ok(3));
+        let mut broken = snapshot.clone();
+        broken.device.pop();
+        assert_eq!(
+            adapter.restore(&broken),
+            Err(PciAdapterError::InvalidSnapshot)
+        );
+
+        adapter.stop();
+        adapter.join();
     }
 
-    fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
-        None
+    #[test]
+    fn completion_timeout() {
+        let adapter = PciAdapter::start(Box::new(SilentDevice));
+        adapter.set_completion_timeout(Duration::from_millis(10));
+
+        assert_eq!(adapter.config_read(0), u32::MAX);
+        assert_eq!(adapter.try_config_read(0), Err(PciAdapterError::Timeout));
+        adapter.config_write(1, 0, &[0x6]);
+
+        adapter.stop();
+        adapter.join();
     }
 
-    fn as_any(&mut self) -> &mut dyn Any {
-        self
+    #[test]
+    fn config_cache() {
+        let adapter = PciAdapterBuilder::new()
+            .config_cache(&[STATUS_REG])
+            .start(Box::new(PciTestDevice::new()));
+        let sent = |adapter: &PciAdapter| adapter.stats().unwrap().sent.config_read;
+
+        assert_eq!(adapter.config_read(0), 0x5678_1234);
+        assert_eq!(adapter.config_read(0), 0x5678_1234);
+        assert_eq!(adapter.config_read_many(&[0, 2]).unwrap()[0], 0x5678_1234);
+        assert_eq!(sent(&adapter), 2);
+
+        // A write goes through and the register is read again afterwards.
+        adapter.config_write(BAR0_REG, 0, &[0xff; 4]);
+        let mask = adapter.config_read(BAR0_REG);
+        assert_eq!(adapter.config_read(BAR0_REG), mask);
+        assert_eq!(sent(&adapter), 3);
+
+        // Volatile registers and those past the header are always read from the device.
+        for reg in [STATUS_REG, STATUS_REG, 16, 16] {
+            adapter.config_read(reg);
+        }
+        assert_eq!(sent(&adapter), 7);
+
+        adapter.stop();
+        adapter.join();
     }
-}
 
-impl BusDevice for PciAdapter {
-    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
-        self.read_bar(base, offset, data)
+    #[test]
+    fn link() {
+        let mut adapter = PciAdapterBuilder::new()
+            .link(Link::new(LinkSpeed::Gen1, 1))
+            .start(Box::new(PciTestDevice::new()));
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x10_0000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: BAR0_REG,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+
+        // 512 KiB of completions take over 2 ms at 250 MB/s.
+        let start = Instant::now();
+        let mut data = vec![0; 0x8_0000];
+        adapter
+            .try_bar_mmio_read_block(0x1_0000_0000, &mut data)
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(2));
+
+        adapter.stop();
+        adapter.join();
     }
 
-    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
-        self.write_bar(base, offset, data)
+    #[test]
+    fn delay() {
+        let delay = Duration::from_millis(50);
+        let adapter = PciAdapterBuilder::new()
+            .delay(DelayModel::new().set(
+                Direction::Upstream,
+                TlpKind::Completion,
+                Delay::Fixed(delay),
+            ))
+            .start(Box::new(PciTestDevice::new()));
+
+        let start = Instant::now();
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        assert!(start.elapsed() >= delay);
+
+        // The completion comes too late.
+        adapter.set_completion_timeout(Duration::from_millis(10));
+        assert_eq!(adapter.try_config_read(0), Err(PciAdapterError::Timeout));
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device model which reports its lifecycle hooks and answers config reads with the
+    /// number of resets it went through.
+    struct LifecycleDevice {
+        resets: u32,
+        hooks: Sender<String>,
+    }
+
+    impl PciSimDevice for LifecycleDevice {
+        fn on_start(&mut self, info: &LaneInfo) {
+            let _ = self.hooks.send(format!("start {:?}", info.functions));
+        }
+
+        fn on_reset(&mut self, kind: ResetKind) {
+            self.resets += 1;
+            let _ = self.hooks.send(format!("reset {:?}", kind));
+        }
+
+        fn on_stop(&mut self) {
+            let _ = self.hooks.send("stop".to_string());
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            while let Ok(tlp) = lane.rx.recv() {
+                if let PacketType::Config0Read(extra) = tlp.header._type {
+                    let cpl = TlpBuilder::completion_data(CompletionExtra {
+                        requester: extra.requester,
+                        completer: extra.completer,
+                        tag: extra.tag,
+                        status: CPL_SC,
+                        bcm: false,
+                        byte_count: 4,
+                        lower_address: 0,
+                    })
+                    .data(vec![self.resets])
+                    .build();
+                    lane.tx.send(cpl).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lifecycle() {
+        let (tx, hooks) = unbounded();
+        let adapter = PciAdapter::start(Box::new(LifecycleDevice {
+            resets: 0,
+            hooks: tx,
+        }));
+        assert_eq!(adapter.try_config_read(0), Ok(0));
+
+        // The model runs again on a new lane after each reset.
+        adapter.function_reset().unwrap();
+        assert_eq!(adapter.try_config_read(0), Ok(1));
+        adapter.hot_reset().unwrap();
+        assert_eq!(adapter.try_config_read(0), Ok(2));
+
+        adapter.stop();
+        adapter.join();
+        let hooks: Vec<String> = hooks.try_iter().collect();
+        assert_eq!(
+            hooks,
+            vec!["start [24]", "reset FunctionLevel(24)", "reset Hot", "stop"]
+        );
+    }
+
+    #[test]
+    fn secondary_bus_reset() {
+        let (tx, hooks) = unbounded();
+        let device = |bdf| -> (u16, Box<dyn PciSimDevice + Send + Sync>) {
+            let device = LifecycleDevice {
+                resets: 0,
+                hooks: tx.clone(),
+            };
+            (bdf, Box::new(device))
+        };
+        let mut adapters = PciAdapterBuilder::new()
+            .config_cache(&[])
+            .start_hierarchy(vec![device(make_bdf(1, 0, 0)), device(make_bdf(2, 0, 0))]);
+        for adapter in adapters.iter() {
+            assert_eq!(adapter.try_config_read(0), Ok(0));
+        }
+        adapters[1].rom_enabled = true;
+        adapters[1].msix_control = 0xc003;
+        assert!(adapters[1].rom_enabled());
+
+        // Both models reset, and the other adapter does not answer from its cache.
+        adapters[0].secondary_bus_reset().unwrap();
+        for adapter in adapters.iter() {
+            assert_eq!(adapter.try_config_read(0), Ok(1));
+        }
+        let resets = hooks.try_iter().filter(|hook| hook == "reset Hot").count();
+        assert_eq!(resets, 2);
+
+        // The other adapter forgets the ROM enable and the MSI-X enable and mask too.
+        assert!(!adapters[1].rom_enabled());
+        adapters[1].sync_bus_resets();
+        assert_eq!(adapters[1].msix_control, 0x3);
+
+        for adapter in adapters.iter() {
+            adapter.stop();
+        }
+        for adapter in adapters {
+            adapter.join();
+        }
     }
 }
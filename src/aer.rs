@@ -0,0 +1,480 @@
+//! Advanced Error Reporting.
+//!
+//! A device model reports an error it detects with [`ConfigSpace::report_error`], which looks
+//! the mask and severity of the error up in the AER capability the guest programmed, see
+//! [`AerCapability`]. Like the AER capability of a real function, it latches the error in the
+//! status registers, and the first unmasked uncorrectable error in the First Error Pointer and
+//! the Header Log until the guest clears its status. An unmasked error is reported by the
+//! ERR_COR, ERR_NONFATAL or ERR_FATAL message, routed to the root complex without data and with
+//! the requester ID of the function in its header, as any model may send it.
+//!
+//! The bridge latches the message of a function into its [`AerLog`], the error detected bits
+//! of its Device Status register, which the guest reads through the adapter as if the model
+//! held them and clears by writing 1. Like the sticky registers of a real function they survive
+//! the resets the AER driver of the guest recovers the function with. The message of a
+//! requester which is not a function on a lane of the bridge is dropped. An error whose
+//! reporting the guest enabled in Device Control is signaled to the hypervisor with
+//! [`AdapterEvent::Error`], which the Root Error Status register of the root port the
+//! hypervisor emulates above the function takes.
+
+use crate::*;
+
+use crate::ltssm::PCI_CAP_ID_EXP;
+
+/// Message code of ERR_COR.
+pub(crate) const ERR_COR: u8 = 0x30;
+/// Message code of ERR_NONFATAL.
+pub(crate) const ERR_NONFATAL: u8 = 0x31;
+/// Message code of ERR_FATAL.
+pub(crate) const ERR_FATAL: u8 = 0x33;
+
+/// Capability ID of the AER extended capability.
+pub(crate) const PCI_EXT_CAP_ID_AER: u16 = 0x0001;
+/// DW of the PCI Express capability holding Device Control and, in its upper half, Device
+/// Status.
+pub(crate) const DEVCTL_DW: usize = 2;
+/// DW index into the AER body of Advanced Error Capabilities and Control, whose bits 4:0 are
+/// the First Error Pointer.
+pub(crate) const AER_CONTROL: usize = 5;
+pub(crate) const FIRST_ERROR_POINTER: u32 = 0x1f;
+/// Capabilities Pointer register.
+const CAP_PTR_REG: usize = 13;
+/// DW index into the AER body of the Uncorrectable Error Mask register.
+const AER_UNCOR_MASK: usize = 1;
+/// DW index into the AER body of the Uncorrectable Error Severity register.
+const AER_UNCOR_SEVERITY: usize = 2;
+/// DW index into the AER body of the Correctable Error Mask register.
+const AER_COR_MASK: usize = 4;
+
+/// Correctable Error Detected in the Device Status register.
+const DEVSTA_CED: u16 = 1 << 0;
+/// Non-Fatal Error Detected in the Device Status register.
+const DEVSTA_NFED: u16 = 1 << 1;
+/// Fatal Error Detected in the Device Status register.
+const DEVSTA_FED: u16 = 1 << 2;
+/// Unsupported Request Detected in the Device Status register.
+const DEVSTA_URD: u16 = 1 << 3;
+const DEVSTA_ERRORS: u16 = DEVSTA_CED | DEVSTA_NFED | DEVSTA_FED | DEVSTA_URD;
+
+/// An error the AER capability has a status bit for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AerError {
+    ReceiverError,
+    BadTlp,
+    BadDllp,
+    ReplayNumRollover,
+    ReplayTimerTimeout,
+    AdvisoryNonFatal,
+    CorrectedInternal,
+    HeaderLogOverflow,
+    DataLinkProtocol,
+    SurpriseDown,
+    PoisonedTlp,
+    FlowControlProtocol,
+    CompletionTimeout,
+    CompleterAbort,
+    UnexpectedCompletion,
+    ReceiverOverflow,
+    MalformedTlp,
+    Ecrc,
+    UnsupportedRequest,
+    AcsViolation,
+    UncorrectableInternal,
+}
+
+impl AerError {
+    /// The bit of the error in the correctable or the uncorrectable status register.
+    pub fn bit(self) -> u8 {
+        use AerError::*;
+
+        match self {
+            ReceiverError => 0,
+            BadTlp => 6,
+            BadDllp => 7,
+            ReplayNumRollover => 8,
+            ReplayTimerTimeout => 12,
+            AdvisoryNonFatal => 13,
+            CorrectedInternal => 14,
+            HeaderLogOverflow => 15,
+            DataLinkProtocol => 4,
+            SurpriseDown => 5,
+            PoisonedTlp => 12,
+            FlowControlProtocol => 13,
+            CompletionTimeout => 14,
+            CompleterAbort => 15,
+            UnexpectedCompletion => 16,
+            ReceiverOverflow => 17,
+            MalformedTlp => 18,
+            Ecrc => 19,
+            UnsupportedRequest => 20,
+            AcsViolation => 21,
+            UncorrectableInternal => 22,
+        }
+    }
+
+    /// Whether the error is corrected by the hardware.
+    pub fn is_correctable(self) -> bool {
+        use AerError::*;
+
+        matches!(
+            self,
+            ReceiverError
+                | BadTlp
+                | BadDllp
+                | ReplayNumRollover
+                | ReplayTimerTimeout
+                | AdvisoryNonFatal
+                | CorrectedInternal
+                | HeaderLogOverflow
+        )
+    }
+}
+
+/// Severity of an error, which picks the message signaling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorSeverity {
+    Correctable,
+    NonFatal,
+    Fatal,
+}
+
+impl ErrorSeverity {
+    fn code(self) -> u8 {
+        match self {
+            ErrorSeverity::Correctable => ERR_COR,
+            ErrorSeverity::NonFatal => ERR_NONFATAL,
+            ErrorSeverity::Fatal => ERR_FATAL,
+        }
+    }
+
+    pub(crate) fn from_code(code: u8) -> Option<Self> {
+        match code {
+            ERR_COR => Some(ErrorSeverity::Correctable),
+            ERR_NONFATAL => Some(ErrorSeverity::NonFatal),
+            ERR_FATAL => Some(ErrorSeverity::Fatal),
+            _ => None,
+        }
+    }
+
+    /// The error detected bit of the severity in Device Status, and its reporting enable
+    /// bit in Device Control.
+    fn device_status(self) -> u16 {
+        match self {
+            ErrorSeverity::Correctable => DEVSTA_CED,
+            ErrorSeverity::NonFatal => DEVSTA_NFED,
+            ErrorSeverity::Fatal => DEVSTA_FED,
+        }
+    }
+}
+
+/// The header of `tlp` as the header log holds it, a 3 DW header padded with 0.
+pub fn header_log(tlp: &TlpHeader) -> [u32; 4] {
+    let mut log = [0; 4];
+    if let Ok(bytes) = tlp.to_bytes() {
+        for (dw, bytes) in log.iter_mut().zip(bytes.chunks(4)) {
+            *dw = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+    }
+    log
+}
+
+/// The ERR_* message of `severity` of function `requester`.
+pub(crate) fn report(requester: u16, severity: ErrorSeverity) -> Tlp {
+    TlpBuilder::message(MessageExtra::routed(
+        requester,
+        severity.code(),
+        MessageRouting::RootComplex,
+    ))
+    .build()
+}
+
+/// The errors the bridge latched for a function, as its Device Status register holds them.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AerLog {
+    /// The error detected bits of the Device Status register.
+    pub device_status: u16,
+}
+
+impl AerLog {
+    /// Latch the error of the ERR_* message of `severity`. Returns whether the error is
+    /// signaled, as the reporting enables of Device Control `devctl` say.
+    pub(crate) fn latch(&mut self, severity: ErrorSeverity, devctl: u16) -> bool {
+        self.device_status |= severity.device_status();
+        devctl & severity.device_status() != 0
+    }
+
+    /// The guest wrote `value` to Device Control and Status, clearing the error detected bits
+    /// it wrote 1 to.
+    pub(crate) fn clear(&mut self, value: u32) {
+        self.device_status &= !((value >> 16) as u16 & DEVSTA_ERRORS);
+    }
+
+    /// Device Control and Status with the errors laid over the `value` the model holds.
+    pub(crate) fn overlay(&self, value: u32) -> u32 {
+        value | (self.device_status as u32) << 16
+    }
+}
+
+/// Where the registers controlling the errors of a function are.
+pub(crate) struct ErrorControls {
+    /// Register index of Device Control and Status.
+    pub devctl: Option<usize>,
+    /// Register index of the AER capability header.
+    pub aer: Option<usize>,
+}
+
+impl ErrorControls {
+    /// Walk both capability lists of the configuration space `read` reads from.
+    pub(crate) fn find(read: impl Fn(usize) -> u32) -> Self {
+        // Bound the walks, a broken device may link its capabilities into a loop.
+        const MAX_CAPS: usize = 48;
+
+        let mut controls = ErrorControls {
+            devctl: None,
+            aer: None,
+        };
+        let mut offset = read(CAP_PTR_REG) & 0xfc;
+        for _ in 0..MAX_CAPS {
+            if offset == 0 {
+                break;
+            }
+            let header = read((offset >> 2) as usize);
+            if header & 0xff == PCI_CAP_ID_EXP as u32 {
+                controls.devctl = Some((offset >> 2) as usize + DEVCTL_DW);
+                break;
+            }
+            offset = (header >> 8) & 0xfc;
+        }
+
+        let mut reg = PCI_CONFIG_REGS;
+        for _ in 0..MAX_CAPS {
+            let header = read(reg);
+            if header & 0xffff == PCI_EXT_CAP_ID_AER as u32 {
+                controls.aer = Some(reg);
+                break;
+            }
+            reg = (header >> 20) as usize / 4;
+            if reg < PCI_CONFIG_REGS {
+                break;
+            }
+        }
+        controls
+    }
+
+    /// The severity of `error` and whether it is masked and signaled, as the registers of
+    /// the capabilities `read` reads say.
+    pub(crate) fn classify(
+        &self,
+        read: impl Fn(usize) -> u32,
+        error: AerError,
+    ) -> (ErrorSeverity, bool, bool) {
+        let bit = 1 << error.bit();
+        let (severity, masked) = match (self.aer, error.is_correctable()) {
+            (Some(aer), true) => (
+                ErrorSeverity::Correctable,
+                read(aer + 1 + AER_COR_MASK) & bit != 0,
+            ),
+            (Some(aer), false) => {
+                let severity = if read(aer + 1 + AER_UNCOR_SEVERITY) & bit != 0 {
+                    ErrorSeverity::Fatal
+                } else {
+                    ErrorSeverity::NonFatal
+                };
+                (severity, read(aer + 1 + AER_UNCOR_MASK) & bit != 0)
+            }
+            (None, true) => (ErrorSeverity::Correctable, false),
+            (None, false) => (ErrorSeverity::NonFatal, false),
+        };
+        let enabled = self
+            .devctl
+            .is_none_or(|devctl| read(devctl) as u16 & severity.device_status() != 0);
+        (severity, masked, !masked && enabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::adapter::test_allocator;
+    use crossbeam_channel::{select, unbounded, Receiver};
+    use std::time::Duration;
+
+    /// A model with the PCI Express and AER capabilities reporting the errors it is told.
+    struct ErrorDevice {
+        config: ConfigSpace,
+        errors: Receiver<(AerError, Option<[u32; 4]>)>,
+        requester: u16,
+    }
+
+    impl PciSimDevice for ErrorDevice {
+        fn on_start(&mut self, info: &LaneInfo) {
+            self.requester = info.functions[0];
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            loop {
+                select! {
+                    recv(lane.rx) -> tlp => match tlp {
+                        Ok(tlp) => {
+                            self.config.handle(lane, &tlp);
+                        }
+                        Err(_) => break,
+                    },
+                    recv(self.errors) -> error => {
+                        let (error, header) = error.unwrap();
+                        self.config
+                            .report_error(lane, self.requester, error, header)
+                            .unwrap();
+                    },
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn recovery() {
+        let pci = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x0001,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0x5555,
+            0x6666,
+            None,
+        );
+        let mut config = PcieConfiguration::new(pci);
+        let devctl = config
+            .add_capability(&PcieCapability::new(PciePortType::Endpoint))
+            .unwrap()
+            / 4
+            + DEVCTL_DW;
+        let aer = config.add_extended(&AerCapability::new()).unwrap() / 4 + 1;
+        let (errors, rx) = unbounded();
+        let device = ErrorDevice {
+            config: ConfigSpace::new(config),
+            errors: rx,
+            requester: 0,
+        };
+        let mut adapter = PciAdapter::start(Box::new(device));
+        let events = adapter.events();
+        adapter.allocate_bars(&mut test_allocator()).unwrap();
+        let bdf = adapter.bdf();
+
+        // The AER driver of the guest enables the reporting of every severity.
+        adapter.write_config_register(devctl, 0, &[0x7]);
+        assert_eq!(adapter.error_log(), Ok(AerLog::default()));
+
+        // An Advisory Non-Fatal Error is masked by default and only latched by the function,
+        // the Bad TLP is signaled.
+        errors.send((AerError::AdvisoryNonFatal, None)).unwrap();
+        errors.send((AerError::BadTlp, None)).unwrap();
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(1)),
+            Ok(AdapterEvent::Error {
+                bdf,
+                severity: ErrorSeverity::Correctable
+            })
+        );
+        assert_eq!(
+            adapter.guest_config_read(aer + AerCapability::COR_STATUS),
+            1 << 13 | 1 << 6
+        );
+
+        // A Malformed TLP is fatal by default, its header is logged.
+        let tlp = TlpBuilder::memory_write(MemoryExtra {
+            requester: bdf,
+            tag: 0,
+            addr: 0x1000,
+        })
+        .length(1)
+        .build();
+        let header = header_log(&tlp.header);
+        assert_eq!(header[0], 0x4000_0001);
+        errors.send((AerError::MalformedTlp, Some(header))).unwrap();
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(1)),
+            Ok(AdapterEvent::Error {
+                bdf,
+                severity: ErrorSeverity::Fatal
+            })
+        );
+        assert_eq!(
+            adapter.guest_config_read(aer + AerCapability::UNCOR_STATUS),
+            1 << 18
+        );
+        assert_eq!(adapter.guest_config_read(aer + AER_CONTROL) & 0x1f, 18);
+        for (i, dw) in header.iter().enumerate() {
+            assert_eq!(
+                adapter.guest_config_read(aer + AerCapability::HEADER_LOG + i),
+                *dw
+            );
+        }
+        assert_eq!(
+            adapter.guest_config_read(devctl) >> 16,
+            (DEVSTA_CED | DEVSTA_FED) as u32
+        );
+
+        // The errors survive the reset recovering from the fatal error, the guest clears them.
+        adapter.hot_reset().unwrap();
+        assert_eq!(
+            adapter.error_log().unwrap().device_status,
+            DEVSTA_CED | DEVSTA_FED
+        );
+        adapter.write_config_register(
+            aer + AerCapability::UNCOR_STATUS,
+            0,
+            &(1u32 << 18).to_le_bytes(),
+        );
+        adapter.write_config_register(aer + AerCapability::COR_STATUS, 0, &[0xff, 0xff]);
+        adapter.write_config_register(devctl, 2, &[0xf]);
+        assert_eq!(adapter.error_log(), Ok(AerLog::default()));
+        assert_eq!(
+            adapter.guest_config_read(aer + AerCapability::UNCOR_STATUS),
+            0
+        );
+        assert_eq!(adapter.guest_config_read(aer + AER_CONTROL) & 0x1f, 0);
+        assert_eq!(
+            adapter.guest_config_read(aer + AerCapability::HEADER_LOG),
+            0
+        );
+
+        // An error of a function the guest does not enable reporting for is latched only.
+        adapter.write_config_register(devctl, 0, &[0x0]);
+        errors.send((AerError::PoisonedTlp, None)).unwrap();
+        assert!(events.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(adapter.error_log().unwrap().device_status, DEVSTA_NFED);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn latch() {
+        let mut log = AerLog::default();
+        let severity = |tlp: Tlp| match tlp.header._type {
+            PacketType::RoutedMessage(extra) => {
+                assert_eq!(extra.requester, 0x18);
+                assert_eq!(extra.routing, MessageRouting::RootComplex);
+                assert_eq!(extra.addr, 0);
+                ErrorSeverity::from_code(extra.code).unwrap()
+            }
+            _ => unreachable!(),
+        };
+
+        // The error is latched whether or not Device Control enables reporting it.
+        let poisoned = report(0x18, ErrorSeverity::NonFatal);
+        assert!(!log.latch(severity(poisoned), 0));
+        assert_eq!(log.device_status, DEVSTA_NFED);
+        let bad_tlp = report(0x18, ErrorSeverity::Correctable);
+        assert!(log.latch(severity(bad_tlp), 0x1));
+        assert_eq!(log.device_status, DEVSTA_NFED | DEVSTA_CED);
+        assert_eq!(log.overlay(0x7), 0x0003_0007);
+
+        log.clear(0xe << 16);
+        assert_eq!(log.device_status, DEVSTA_CED);
+    }
+}
@@ -0,0 +1,165 @@
+//! The guest resources the BARs and the INTx line of an adapter are placed in.
+//!
+//! [`PciAdapter::allocate_bars_with`] takes the address ranges and GSIs from an
+//! [`AddressAllocator`], so a VMM with a resource manager of its own hands it in instead of a
+//! `SystemAllocator` of the rust-vmm crates, which implements the trait as well.
+
+use crate::*;
+
+/// Allocator of the guest address ranges of the BARs and of the GSIs of the INTx lines.
+pub trait AddressAllocator {
+    /// A range of `size` bytes aligned to `align` in the MMIO space above 4 GiB, for the
+    /// 64-bit BARs.
+    fn allocate_mmio(&mut self, size: GuestUsize, align: GuestUsize) -> Option<GuestAddress>;
+
+    /// Free a range of [`AddressAllocator::allocate_mmio`].
+    fn free_mmio(&mut self, base: GuestAddress, size: GuestUsize);
+
+    /// A range of `size` bytes aligned to `align` in the MMIO hole below 4 GiB, for the 32-bit
+    /// BARs.
+    fn allocate_mmio_hole(&mut self, size: GuestUsize, align: GuestUsize) -> Option<GuestAddress>;
+
+    /// Free a range of [`AddressAllocator::allocate_mmio_hole`].
+    fn free_mmio_hole(&mut self, base: GuestAddress, size: GuestUsize);
+
+    /// A range of `size` I/O ports aligned to `align`, for the IO BARs. Only x86 has port I/O,
+    /// elsewhere the IO BARs are not placed.
+    fn allocate_io(&mut self, size: GuestUsize, align: GuestUsize) -> Option<GuestAddress>;
+
+    /// Free a range of [`AddressAllocator::allocate_io`].
+    fn free_io(&mut self, base: GuestAddress, size: GuestUsize);
+
+    /// A GSI for an INTx line. Allocators without GSIs leave the INTx pins unrouted.
+    fn allocate_irq(&mut self) -> Option<u32> {
+        None
+    }
+}
+
+impl AddressAllocator for SystemAllocator {
+    fn allocate_mmio(&mut self, size: GuestUsize, align: GuestUsize) -> Option<GuestAddress> {
+        self.allocate_mmio_addresses(None, size, Some(align))
+    }
+
+    fn free_mmio(&mut self, base: GuestAddress, size: GuestUsize) {
+        self.free_mmio_addresses(base, size)
+    }
+
+    fn allocate_mmio_hole(&mut self, size: GuestUsize, align: GuestUsize) -> Option<GuestAddress> {
+        self.allocate_mmio_hole_addresses(None, size, Some(align))
+    }
+
+    fn free_mmio_hole(&mut self, base: GuestAddress, size: GuestUsize) {
+        self.free_mmio_hole_addresses(base, size)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn allocate_io(&mut self, size: GuestUsize, align: GuestUsize) -> Option<GuestAddress> {
+        self.allocate_io_addresses(None, size, Some(align))
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn allocate_io(&mut self, _: GuestUsize, _: GuestUsize) -> Option<GuestAddress> {
+        None
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn free_io(&mut self, base: GuestAddress, size: GuestUsize) {
+        self.free_io_addresses(base, size)
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn free_io(&mut self, _: GuestAddress, _: GuestUsize) {}
+
+    fn allocate_irq(&mut self) -> Option<u32> {
+        SystemAllocator::allocate_irq(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A resource manager handing out the ranges one after the other, which records the frees.
+    #[derive(Default)]
+    struct Bump {
+        mmio: u64,
+        hole: u64,
+        io: u64,
+        freed: Vec<(GuestAddress, GuestUsize)>,
+    }
+
+    impl Bump {
+        fn next(next: &mut u64, size: GuestUsize, align: GuestUsize) -> Option<GuestAddress> {
+            let align = align.max(size.next_power_of_two());
+            let start = next.div_ceil(align) * align;
+            *next = start + size;
+            Some(GuestAddress(start))
+        }
+    }
+
+    impl AddressAllocator for Bump {
+        fn allocate_mmio(&mut self, size: GuestUsize, align: GuestUsize) -> Option<GuestAddress> {
+            Self::next(&mut self.mmio, size, align)
+        }
+
+        fn free_mmio(&mut self, base: GuestAddress, size: GuestUsize) {
+            self.freed.push((base, size));
+        }
+
+        fn allocate_mmio_hole(
+            &mut self,
+            size: GuestUsize,
+            align: GuestUsize,
+        ) -> Option<GuestAddress> {
+            Self::next(&mut self.hole, size, align)
+        }
+
+        fn free_mmio_hole(&mut self, base: GuestAddress, size: GuestUsize) {
+            self.freed.push((base, size));
+        }
+
+        fn allocate_io(&mut self, size: GuestUsize, align: GuestUsize) -> Option<GuestAddress> {
+            Self::next(&mut self.io, size, align)
+        }
+
+        fn free_io(&mut self, base: GuestAddress, size: GuestUsize) {
+            self.freed.push((base, size));
+        }
+    }
+
+    #[test]
+    fn address_allocator() {
+        let mut adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        let mut allocator = Bump {
+            mmio: 0x40_0000_0000,
+            hole: 0xc000_0000,
+            io: 0x1000,
+            ..Bump::default()
+        };
+
+        let bars = adapter.allocate_bars_with(&mut allocator).unwrap();
+        assert!(!bars.is_empty());
+        for &(start, length, type_) in bars.iter() {
+            match type_ {
+                PciBarRegionType::Memory64BitRegion => {
+                    assert!(start.raw_value() >= 0x40_0000_0000)
+                }
+                PciBarRegionType::Memory32BitRegion => {
+                    assert!((0xc000_0000..0x1_0000_0000).contains(&start.raw_value()))
+                }
+                PciBarRegionType::IoRegion => assert!(start.raw_value() < 0x1_0000),
+            }
+            assert_eq!(start.raw_value() % length, 0);
+        }
+
+        adapter.free_bars_with(&mut allocator).unwrap();
+        let placed: Vec<_> = bars
+            .iter()
+            .map(|&(start, length, _)| (start, length))
+            .collect();
+        assert_eq!(allocator.freed, placed);
+
+        adapter.stop();
+        adapter.join();
+    }
+}
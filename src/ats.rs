@@ -0,0 +1,313 @@
+//! Address Translation Services.
+//!
+//! An ATS capable device asks the translation agent for translations by memory reads with
+//! AT=TranslationRequest, which the bridge completes with one translation entry per 2 DWs of
+//! the request. Cached translations are shot down by Invalidate Request messages, which the
+//! device acknowledges with an Invalidate Completion message.
+//!
+//! Devices with the Page Request Interface ask for pages which are not resident by Page
+//! Request messages. The bridge queues them until the last request of the group arrives, hands
+//! the group to the [`PageRequestHandler`] and sends its answer back by a PRG Response message.
+//!
+//! When the guest changes the vIOMMU mappings of a device, e.g. by virtio-iommu requests or
+//! VT-d invalidations, the VMM tells the bridge about it by [`PciAdapter::notify_iommu`]. The
+//! bridge forwards each [`IommuEvent`] to the device by a vendor defined message with code
+//! [`IOMMU_EVENT_MESSAGE`], so the model can keep its translation cache in step, and shoots
+//! down the translations of an unmapped or invalidated range with Invalidate Requests before
+//! the call returns.
+
+use crate::*;
+
+/// Message code of Invalidate Request, the payload is the encoded untranslated range.
+pub const ATS_INVALIDATE_REQUEST: u8 = 0x01;
+/// Message code of Invalidate Completion.
+pub const ATS_INVALIDATE_COMPLETION: u8 = 0x02;
+
+/// Message code of the vIOMMU events forwarded to the device, see [`IommuEvent::to_message`].
+pub const IOMMU_EVENT_MESSAGE: u8 = 0x7d;
+
+/// Smallest translation unit.
+pub const ATS_PAGE_SIZE: u64 = 0x1000;
+
+const ATS_READ: u32 = 1 << 0;
+const ATS_WRITE: u32 = 1 << 1;
+const ATS_UNTRANSLATED_ONLY: u32 = 1 << 2;
+const ATS_SIZE: u32 = 1 << 11;
+
+/// A translation handed out by the translation agent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AtsTranslation {
+    /// Translated address, aligned to `size`.
+    pub addr: u64,
+    /// Size of the translated range, a power of two of at least [`ATS_PAGE_SIZE`].
+    pub size: u64,
+    pub read: bool,
+    pub write: bool,
+    /// The device must keep using untranslated addresses for the range.
+    pub untranslated_only: bool,
+}
+
+impl AtsTranslation {
+    /// The entry of a failed translation, which allows no access.
+    pub fn fault() -> Self {
+        AtsTranslation {
+            addr: 0,
+            size: ATS_PAGE_SIZE,
+            read: false,
+            write: false,
+            untranslated_only: false,
+        }
+    }
+
+    /// Encode the 2 DWs entry of a translation completion.
+    pub fn to_entry(&self) -> [u32; 2] {
+        let [high, mut low] = encode_range(self.addr, self.size);
+        if self.read {
+            low |= ATS_READ;
+        }
+        if self.write {
+            low |= ATS_WRITE;
+        }
+        if self.untranslated_only {
+            low |= ATS_UNTRANSLATED_ONLY;
+        }
+
+        [high, low]
+    }
+
+    /// Decode an entry of a translation completion.
+    pub fn from_entry(entry: [u32; 2]) -> Self {
+        let (addr, size) = decode_range(entry);
+
+        AtsTranslation {
+            addr,
+            size,
+            read: entry[1] & ATS_READ != 0,
+            write: entry[1] & ATS_WRITE != 0,
+            untranslated_only: entry[1] & ATS_UNTRANSLATED_ONLY != 0,
+        }
+    }
+}
+
+/// Hypervisor hook answering the translation requests of ATS capable devices, e.g. a vIOMMU.
+pub trait TranslationAgent: Send {
+    /// Translate the page at `iova` for `requester`, `None` if nothing is mapped.
+    fn translate(&self, requester: u16, iova: u64) -> Option<AtsTranslation>;
+}
+
+/// Hypervisor hook making the pages a device asks for by the Page Request Interface available,
+/// e.g. by faulting them in.
+pub trait PageRequestHandler: Send {
+    /// Handle a complete page request group of `requester`, returns the PRG response code.
+    fn page_request(&self, requester: u16, pages: &[PageRequestExtra]) -> u8;
+}
+
+/// A change of the vIOMMU mappings of a device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IommuEvent {
+    /// `size` bytes at `iova` are mapped to `gpa`.
+    Map {
+        iova: u64,
+        size: u64,
+        gpa: u64,
+        read: bool,
+        write: bool,
+    },
+    /// The mappings of `size` bytes at `iova` are removed.
+    Unmap { iova: u64, size: u64 },
+    /// The guest invalidated the IOTLB for `size` bytes at `iova`, the mappings may have
+    /// changed.
+    Invalidate { iova: u64, size: u64 },
+}
+
+const IOMMU_MAP: u32 = 0;
+const IOMMU_UNMAP: u32 = 1;
+const IOMMU_INVALIDATE: u32 = 2;
+const IOMMU_READ: u32 = 1 << 8;
+const IOMMU_WRITE: u32 = 1 << 9;
+
+impl IommuEvent {
+    /// The message the bridge sends to the device. The payload is a DW with the kind of the
+    /// event and the permissions of a mapping, followed by the IOVA, the size and for a
+    /// mapping the guest physical address, each as 2 DWs with the high DW first.
+    pub fn to_message(&self) -> Tlp {
+        let (kind, iova, size, gpa) = match *self {
+            IommuEvent::Map {
+                iova,
+                size,
+                gpa,
+                read,
+                write,
+            } => {
+                let mut kind = IOMMU_MAP;
+                if read {
+                    kind |= IOMMU_READ;
+                }
+                if write {
+                    kind |= IOMMU_WRITE;
+                }
+                (kind, iova, size, Some(gpa))
+            }
+            IommuEvent::Unmap { iova, size } => (IOMMU_UNMAP, iova, size, None),
+            IommuEvent::Invalidate { iova, size } => (IOMMU_INVALIDATE, iova, size, None),
+        };
+
+        let mut data = vec![
+            kind,
+            (iova >> 32) as u32,
+            iova as u32,
+            (size >> 32) as u32,
+            size as u32,
+        ];
+        if let Some(gpa) = gpa {
+            data.extend_from_slice(&[(gpa >> 32) as u32, gpa as u32]);
+        }
+        TlpBuilder::with_type(PacketType::MessageData(IOMMU_EVENT_MESSAGE))
+            .data(data)
+            .build()
+    }
+
+    /// The event a message of [`IommuEvent::to_message`] tells about, `None` for other TLPs.
+    pub fn from_message(tlp: &Tlp) -> Option<IommuEvent> {
+        if tlp.header._type != PacketType::MessageData(IOMMU_EVENT_MESSAGE) {
+            return None;
+        }
+
+        let data = tlp.data.as_deref()?;
+        let qword = |i: usize| ((data[i] as u64) << 32) | data[i + 1] as u64;
+        match (data.first()? & 0xff, data.len()) {
+            (IOMMU_MAP, 7) => Some(IommuEvent::Map {
+                iova: qword(1),
+                size: qword(3),
+                gpa: qword(5),
+                read: data[0] & IOMMU_READ != 0,
+                write: data[0] & IOMMU_WRITE != 0,
+            }),
+            (IOMMU_UNMAP, 5) => Some(IommuEvent::Unmap {
+                iova: qword(1),
+                size: qword(3),
+            }),
+            (IOMMU_INVALIDATE, 5) => Some(IommuEvent::Invalidate {
+                iova: qword(1),
+                size: qword(3),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Split `size` bytes at `addr` into the fewest naturally aligned power of two ranges of at
+/// least [`ATS_PAGE_SIZE`] covering them, the ranges a single Invalidate Request can carry.
+pub fn invalidation_ranges(addr: u64, size: u64) -> Vec<(u64, u64)> {
+    let mut ranges = vec![];
+    if size == 0 {
+        return ranges;
+    }
+
+    let end =
+        (addr as u128 + size as u128 + ATS_PAGE_SIZE as u128 - 1) & !(ATS_PAGE_SIZE as u128 - 1);
+    let mut addr = addr & !(ATS_PAGE_SIZE - 1);
+    while (addr as u128) < end {
+        let mut chunk = 1u128 << addr.trailing_zeros();
+        while addr as u128 + chunk > end {
+            chunk /= 2;
+        }
+        ranges.push((addr, chunk as u64));
+        addr = match addr.checked_add(chunk as u64) {
+            Some(addr) => addr,
+            None => break,
+        };
+    }
+    ranges
+}
+
+/// Encode an address range the ATS way: with the S bit set, the lowest 0 bit from bit 12 up
+/// tells the size of the range.
+pub fn encode_range(addr: u64, size: u64) -> [u32; 2] {
+    let range = if size > ATS_PAGE_SIZE {
+        (addr & !(size - 1)) | ((size / 2 - 1) & !(ATS_PAGE_SIZE - 1)) | ATS_SIZE as u64
+    } else {
+        addr & !(ATS_PAGE_SIZE - 1)
+    };
+
+    [(range >> 32) as u32, range as u32]
+}
+
+/// Decode an address range encoded by [`encode_range`], e.g. the payload of an Invalidate
+/// Request. Returns the address and the size of the range.
+pub fn decode_range(dw: [u32; 2]) -> (u64, u64) {
+    let range = ((dw[0] as u64) << 32) | (dw[1] & !(ATS_PAGE_SIZE as u32 - 1)) as u64;
+    if dw[1] & ATS_SIZE == 0 {
+        return (range, ATS_PAGE_SIZE);
+    }
+
+    let size = ATS_PAGE_SIZE << (range >> 12).trailing_ones().min(50);
+    (range & !(size - 1), size * 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(encode_range(0x1_2345_6000, 0x1000), [0x1, 0x2345_6000]);
+        assert_eq!(encode_range(0x1_2345_6000, 0x2000), [0x1, 0x2345_6800]);
+        assert_eq!(encode_range(0x1_2340_0000, 0x10_0000), [0x1, 0x2347_f800]);
+
+        assert_eq!(decode_range([0x1, 0x2345_6000]), (0x1_2345_6000, 0x1000));
+        assert_eq!(decode_range([0x1, 0x2345_6800]), (0x1_2345_6000, 0x2000));
+        assert_eq!(decode_range([0x1, 0x2347_f800]), (0x1_2340_0000, 0x10_0000));
+
+        let translation = AtsTranslation {
+            addr: 0x8000_0000,
+            size: 0x20_0000,
+            read: true,
+            write: false,
+            untranslated_only: false,
+        };
+        assert_eq!(translation.to_entry(), [0x0, 0x800f_f801]);
+        assert_eq!(
+            AtsTranslation::from_entry(translation.to_entry()),
+            translation
+        );
+
+        assert_eq!(
+            invalidation_ranges(0x1800, 0x4000),
+            vec![(0x1000, 0x1000), (0x2000, 0x2000), (0x4000, 0x2000)]
+        );
+        assert_eq!(
+            invalidation_ranges(0x10_0000, 0x10_0000),
+            vec![(0x10_0000, 0x10_0000)]
+        );
+        assert_eq!(invalidation_ranges(0x1000, 0), vec![]);
+    }
+
+    #[test]
+    fn iommu_events() {
+        let events = [
+            IommuEvent::Map {
+                iova: 0x1_0000_2000,
+                size: 0x3000,
+                gpa: 0x8000_2000,
+                read: true,
+                write: false,
+            },
+            IommuEvent::Unmap {
+                iova: 0x1_0000_2000,
+                size: 0x3000,
+            },
+            IommuEvent::Invalidate {
+                iova: 0x4000,
+                size: 0x1000,
+            },
+        ];
+        for event in events.iter() {
+            assert_eq!(IommuEvent::from_message(&event.to_message()), Some(*event));
+        }
+        assert_eq!(events[0].to_message().data.unwrap()[0], 0x100);
+
+        let reset = ResetKind::Hot.to_message();
+        assert_eq!(IommuEvent::from_message(&reset), None);
+    }
+}
@@ -0,0 +1,47 @@
+//! Dump the event log recorded by the bridge.
+//!
+//! usage: tlp-eventlog <log file> [last N milliseconds]
+
+use pcie_tlp::eventlog::{last_events, read_event_log};
+use std::process::exit;
+use std::time::Duration;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: {} <log file> [last N milliseconds]", args[0]);
+        exit(1);
+    }
+
+    let records = match read_event_log(&args[1]) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", args[1], e);
+            exit(1);
+        }
+    };
+
+    let records = match args.get(2).map(|ms| ms.parse::<u64>()) {
+        Some(Ok(ms)) => last_events(&records, Duration::from_millis(ms)),
+        Some(Err(e)) => {
+            eprintln!("invalid window: {}", e);
+            exit(1);
+        }
+        None => &records[..],
+    };
+
+    let last = records.last().map(|r| r.timestamp).unwrap_or(0);
+    for record in records {
+        let delta = (last - record.timestamp) as f64 / 1_000_000.0;
+        match record.tlp() {
+            Some(tlp) => println!(
+                "#{:<8} -{:>12.3}ms {:?} {:?} length {} data {:x?}",
+                record.seq, delta, record.kind, tlp.header._type, tlp.header.length, tlp.data
+            ),
+            None => println!(
+                "#{:<8} -{:>12.3}ms {:?} {:#x} {:x?}",
+                record.seq, delta, record.kind, record.arg, record.payload
+            ),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Write-through cache of the config space of a function, kept by its adapter.
+//!
+//! Hypervisors read the IDs, the command register and the like of a device over and over,
+//! each read a round trip through the bridge and the device model. The registers of the type
+//! 0 header only change when they are written, so the adapter answers their reads from a copy
+//! it keeps. A write goes to the device and drops the copy of its register, as the device
+//! decides what the register holds after it, e.g. the size mask of a BAR. Registers which the
+//! device changes on its own, e.g. the error bits of the status register, are declared
+//! volatile and always read from the device.
+//!
+//! The caches of the functions behind a bridge are renewed from each other and share the
+//! count of the resets of the bus, a secondary bus reset drops all of them at once.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Registers of the type 0 header, the only ones cached.
+const HEADER_REGS: usize = 16;
+
+pub(crate) struct ConfigCache {
+    /// Bit `n` set if register `n` is never cached.
+    volatile: u16,
+    /// Resets of the bus, shared by the caches of the functions behind the bridge.
+    bus_resets: Arc<AtomicU64>,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    values: [Option<u32>; HEADER_REGS],
+    /// Bumped by every invalidation, a read which started before one does not fill the cache.
+    generation: u64,
+    /// The bus resets the values are taken after.
+    bus_resets: u64,
+}
+
+impl ConfigCache {
+    pub fn new(volatile: &[usize]) -> Self {
+        ConfigCache {
+            volatile: volatile
+                .iter()
+                .filter(|&&reg| reg < HEADER_REGS)
+                .fold(0, |mask, reg| mask | 1 << reg),
+            bus_resets: Arc::new(AtomicU64::new(0)),
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// An empty cache with the same volatile registers, for another function on the bus.
+    pub fn renew(&self) -> Self {
+        let bus_resets = self.bus_resets.load(Ordering::SeqCst);
+        ConfigCache {
+            volatile: self.volatile,
+            bus_resets: self.bus_resets.clone(),
+            state: Mutex::new(CacheState {
+                bus_resets,
+                ..CacheState::default()
+            }),
+        }
+    }
+
+    /// The state of the cache, emptied if the bus was reset since it was last looked at.
+    fn state(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        let mut state = self.state.lock().unwrap();
+        let bus_resets = self.bus_resets.load(Ordering::SeqCst);
+        if state.bus_resets != bus_resets {
+            state.bus_resets = bus_resets;
+            state.generation += 1;
+            state.values = [None; HEADER_REGS];
+        }
+        state
+    }
+
+    fn cacheable(&self, reg: usize) -> bool {
+        reg < HEADER_REGS && self.volatile & (1 << reg) == 0
+    }
+
+    pub fn get(&self, reg: usize) -> Option<u32> {
+        if !self.cacheable(reg) {
+            return None;
+        }
+        self.state().values[reg]
+    }
+
+    /// Taken before a read is sent, to tell [`ConfigCache::fill`] whether the register could
+    /// have changed since.
+    pub fn generation(&self) -> u64 {
+        self.state().generation
+    }
+
+    /// Keep `value` read from `reg` by a read sent at `generation`.
+    pub fn fill(&self, reg: usize, generation: u64, value: u32) {
+        if !self.cacheable(reg) {
+            return;
+        }
+
+        let mut state = self.state();
+        if state.generation == generation {
+            state.values[reg] = Some(value);
+        }
+    }
+
+    /// Drop the copy of `reg`, which is written.
+    pub fn invalidate(&self, reg: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        if reg < HEADER_REGS {
+            state.values[reg] = None;
+        }
+    }
+
+    /// Drop every copy, e.g. as the device behind the adapter may have changed.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.values = [None; HEADER_REGS];
+    }
+
+    /// Drop every copy of the caches of all functions on the bus, which is reset.
+    pub fn clear_bus(&self) {
+        self.bus_resets.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache() {
+        let cache = ConfigCache::new(&[1, 20]);
+        let generation = cache.generation();
+        cache.fill(0, generation, 0x1234);
+        cache.fill(1, generation, 0x10);
+        cache.fill(20, generation, 0x1);
+        assert_eq!(cache.get(0), Some(0x1234));
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(20), None);
+
+        // A read which raced with a write keeps the cache empty.
+        let generation = cache.generation();
+        cache.invalidate(4);
+        cache.fill(4, generation, 0xffff_0000);
+        assert_eq!(cache.get(4), None);
+        assert_eq!(cache.get(0), Some(0x1234));
+
+        cache.clear();
+        assert_eq!(cache.get(0), None);
+        assert_eq!(cache.renew().volatile, cache.volatile);
+
+        // A bus reset empties the caches of the other functions too.
+        let other = cache.renew();
+        cache.fill(0, cache.generation(), 0x1234);
+        other.fill(0, other.generation(), 0x5678);
+        let generation = cache.generation();
+        other.clear_bus();
+        cache.fill(2, generation, 0x1);
+        assert_eq!(
+            (cache.get(0), cache.get(2), other.get(0)),
+            (None, None, None)
+        );
+    }
+}
@@ -0,0 +1,914 @@
+//! Builders of the capabilities of the configuration space.
+//!
+//! Each builder lays out the registers of its capability, the bytes after the capability ID
+//! and next pointer like [`PciCapability::bytes`] takes them, and knows which of their bits
+//! the guest writes. [`PcieConfiguration::add_capability`] links the capability into the list
+//! and keeps these bits writable, which [`PciConfiguration`] does not do for capabilities, so
+//! models neither compute the offsets of their capabilities nor shadow their registers.
+//!
+//! The extended capabilities from 0x100 are built the same way and added by
+//! [`PcieConfiguration::add_extended`], which also knows the status bits the guest clears by
+//! writing 1.
+
+use crate::*;
+
+use pci::{PciCapability, PciCapabilityId};
+
+/// A capability [`PcieConfiguration::add_capability`] adds to the configuration space.
+pub trait StandardCapability: PciCapability {
+    /// The bits the guest writes in each DW of the capability, starting with the DW of the
+    /// capability ID and next pointer.
+    fn writable(&self) -> Vec<u32>;
+}
+
+/// Little endian store of `value` at byte `offset` of the capability, whose first byte is at
+/// offset 2 after the capability ID and next pointer.
+fn put(bytes: &mut [u8], offset: usize, value: &[u8]) {
+    bytes[offset - 2..offset - 2 + value.len()].copy_from_slice(value);
+}
+
+/// Power Management capability, version 1.2 of the interface.
+#[derive(Debug, Clone)]
+pub struct PmCapability {
+    bytes: [u8; 6],
+}
+
+/// Power states of a function, as the PowerState field of PMCSR holds them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+impl PowerState {
+    /// The state of the PowerState field of `pmcsr`.
+    pub fn from_pmcsr(pmcsr: u32) -> Self {
+        match pmcsr & PmCapability::POWER_STATE {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+}
+
+impl PmCapability {
+    /// PowerState of PMCSR.
+    pub const POWER_STATE: u32 = 0x3;
+    /// No_Soft_Reset of PMCSR.
+    pub const NO_SOFT_RESET: u32 = 0x8;
+    /// PME_En of PMCSR.
+    pub const PME_EN: u32 = 1 << 8;
+    /// PME_Status of PMCSR, cleared by the guest writing 1.
+    pub const PME_STATUS: u32 = 1 << 15;
+
+    const PMC: usize = 0x2;
+    const PMCSR: usize = 0x4;
+
+    pub fn new() -> Self {
+        let mut bytes = [0u8; 6];
+        put(&mut bytes, Self::PMC, &0x3u16.to_le_bytes());
+        PmCapability { bytes }
+    }
+
+    fn pmc(&mut self, bits: u16) {
+        let pmc = u16::from_le_bytes([self.bytes[0], self.bytes[1]]) | bits;
+        put(&mut self.bytes, Self::PMC, &pmc.to_le_bytes());
+    }
+
+    /// Support D1.
+    pub fn d1(mut self) -> Self {
+        self.pmc(1 << 9);
+        self
+    }
+
+    /// Support D2.
+    pub fn d2(mut self) -> Self {
+        self.pmc(1 << 10);
+        self
+    }
+
+    /// The states the function signals PME from, bit 0 for D0 to bit 4 for D3cold.
+    pub fn pme(mut self, states: u8) -> Self {
+        self.pmc(((states & 0x1f) as u16) << 11);
+        self
+    }
+
+    /// The function keeps its state on the way from D3hot to D0.
+    pub fn no_soft_reset(mut self) -> Self {
+        put(
+            &mut self.bytes,
+            Self::PMCSR,
+            &(Self::NO_SOFT_RESET as u16).to_le_bytes(),
+        );
+        self
+    }
+}
+
+impl Default for PmCapability {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PciCapability for PmCapability {
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::PowerManagement
+    }
+}
+
+impl StandardCapability for PmCapability {
+    fn writable(&self) -> Vec<u32> {
+        // PowerState and PME_En of PMCSR.
+        vec![0, 0x0000_0103]
+    }
+}
+
+/// MSI capability, 32-bit without per vector masking unless told otherwise.
+#[derive(Debug, Clone)]
+pub struct MsiCapability {
+    vectors: u8,
+    address64: bool,
+    masking: bool,
+    bytes: Vec<u8>,
+}
+
+impl MsiCapability {
+    /// `vectors` is rounded up to a power of 2, at most 32.
+    pub fn new(vectors: u8) -> Self {
+        let mut cap = MsiCapability {
+            vectors: vectors.clamp(1, 32).next_power_of_two(),
+            address64: false,
+            masking: false,
+            bytes: vec![],
+        };
+        cap.layout();
+        cap
+    }
+
+    /// The guest gives a 64-bit message address.
+    pub fn address64(mut self) -> Self {
+        self.address64 = true;
+        self.layout();
+        self
+    }
+
+    /// The guest masks each vector by the mask register.
+    pub fn per_vector_masking(mut self) -> Self {
+        self.masking = true;
+        self.layout();
+        self
+    }
+
+    /// DW index of the message data, after the upper address of a 64-bit capability.
+    fn data_dw(&self) -> usize {
+        if self.address64 {
+            3
+        } else {
+            2
+        }
+    }
+
+    fn layout(&mut self) {
+        let dws = self.data_dw() + 1 + if self.masking { 2 } else { 0 };
+        // The data register is the last one without masking, it takes half of its DW.
+        let len = if self.masking { dws * 4 } else { dws * 4 - 2 } - 2;
+        let mut control = (self.vectors.trailing_zeros() as u16) << 1;
+        if self.address64 {
+            control |= 1 << 7;
+        }
+        if self.masking {
+            control |= 1 << 8;
+        }
+
+        self.bytes = vec![0; len];
+        put(&mut self.bytes, 0x2, &control.to_le_bytes());
+    }
+}
+
+impl PciCapability for MsiCapability {
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::MessageSignalledInterrupts
+    }
+}
+
+impl StandardCapability for MsiCapability {
+    fn writable(&self) -> Vec<u32> {
+        // Enable and Multiple Message Enable, the address, the data and the mask bits.
+        let mut writable = vec![0x0071_0000, 0xffff_fffc];
+        if self.address64 {
+            writable.push(0xffff_ffff);
+        }
+        writable.push(0x0000_ffff);
+        if self.masking {
+            writable.push(((1u64 << self.vectors) - 1) as u32);
+            writable.push(0);
+        }
+        writable
+    }
+}
+
+/// MSI-X capability, its table and PBA in BAR0 until told otherwise.
+#[derive(Debug, Clone)]
+pub struct MsixCapability {
+    bytes: [u8; 10],
+}
+
+impl MsixCapability {
+    const CONTROL: usize = 0x2;
+    const TABLE: usize = 0x4;
+    const PBA: usize = 0x8;
+
+    /// `vectors` from 1 to 2048.
+    pub fn new(vectors: u16) -> Self {
+        let mut bytes = [0u8; 10];
+        let size = vectors.clamp(1, 2048) - 1;
+        put(&mut bytes, Self::CONTROL, &size.to_le_bytes());
+        MsixCapability { bytes }
+    }
+
+    /// The table is at `offset`, a multiple of 8, into BAR `bar`.
+    pub fn table(mut self, bar: u8, offset: u32) -> Self {
+        put(
+            &mut self.bytes,
+            Self::TABLE,
+            &((offset & !0x7) | (bar & 0x7) as u32).to_le_bytes(),
+        );
+        self
+    }
+
+    /// The PBA is at `offset`, a multiple of 8, into BAR `bar`.
+    pub fn pba(mut self, bar: u8, offset: u32) -> Self {
+        put(
+            &mut self.bytes,
+            Self::PBA,
+            &((offset & !0x7) | (bar & 0x7) as u32).to_le_bytes(),
+        );
+        self
+    }
+}
+
+impl PciCapability for MsixCapability {
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::MsiX
+    }
+}
+
+impl StandardCapability for MsixCapability {
+    fn writable(&self) -> Vec<u32> {
+        // Enable and Function Mask.
+        vec![0xc000_0000, 0, 0]
+    }
+}
+
+/// The type of a PCI Express function, which picks the registers of its capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciePortType {
+    Endpoint = 0x0,
+    LegacyEndpoint = 0x1,
+    RootPort = 0x4,
+    UpstreamPort = 0x5,
+    DownstreamPort = 0x6,
+    RootComplexEndpoint = 0x9,
+}
+
+/// PCI Express capability, version 2, of a function with a link of one Gen1 lane and a max
+/// payload of 128 bytes until told otherwise.
+#[derive(Debug, Clone)]
+pub struct PcieCapability {
+    port: PciePortType,
+    bytes: [u8; 0x3a],
+}
+
+impl PcieCapability {
+    const CAPS: usize = 0x02;
+    const DEVCAP: usize = 0x04;
+    const DEVCTL: usize = 0x08;
+    const LNKCAP: usize = 0x0c;
+    const LNKSTA: usize = 0x12;
+    const DEVCAP2: usize = 0x24;
+    const LNKCAP2: usize = 0x2c;
+    const LNKCTL2: usize = 0x30;
+
+    const DEVCAP_FLR: u32 = 1 << 28;
+    /// Completion Timeout Disable Supported of Device Capabilities 2.
+    const DEVCAP2_CTDS: u32 = 1 << 4;
+    /// Max Read Request Size of 512 bytes, the value of Device Control after reset.
+    const DEVCTL_MRRS_512: u16 = 0x2 << 12;
+
+    pub fn new(port: PciePortType) -> Self {
+        let mut cap = PcieCapability {
+            port,
+            bytes: [0u8; 0x3a],
+        };
+        put(
+            &mut cap.bytes,
+            Self::CAPS,
+            &(0x2u16 | (port as u16) << 4).to_le_bytes(),
+        );
+        put(
+            &mut cap.bytes,
+            Self::DEVCTL,
+            &Self::DEVCTL_MRRS_512.to_le_bytes(),
+        );
+        cap.link(LinkSpeed::Gen1, 1)
+    }
+
+    fn get(&self, offset: usize) -> u32 {
+        let b = &self.bytes[offset - 2..offset + 2];
+        u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+    }
+
+    /// Largest payload the function takes, a power of 2 from 128 to 4096 bytes.
+    pub fn max_payload(mut self, bytes: usize) -> Self {
+        let encoded = (bytes.clamp(128, 4096).next_power_of_two() / 128).trailing_zeros();
+        let devcap = (self.get(Self::DEVCAP) & !0x7) | encoded;
+        put(&mut self.bytes, Self::DEVCAP, &devcap.to_le_bytes());
+        self
+    }
+
+    /// The function supports Function Level Reset.
+    pub fn flr(mut self) -> Self {
+        let devcap = self.get(Self::DEVCAP) | Self::DEVCAP_FLR;
+        put(&mut self.bytes, Self::DEVCAP, &devcap.to_le_bytes());
+        self
+    }
+
+    /// The guest programs the completion timeout of the function into Device Control 2,
+    /// picking from the `ranges` A to D, bits 0 to 3, and disabling it if `disable`.
+    pub fn completion_timeout(mut self, ranges: u8, disable: bool) -> Self {
+        let mut devcap2 = (self.get(Self::DEVCAP2) & !0x1f) | (ranges & 0xf) as u32;
+        if disable {
+            devcap2 |= Self::DEVCAP2_CTDS;
+        }
+        put(&mut self.bytes, Self::DEVCAP2, &devcap2.to_le_bytes());
+        self
+    }
+
+    /// The link runs at `speed` with `width` lanes, from 1 to 32. The link status says it is
+    /// trained at both.
+    pub fn link(mut self, speed: LinkSpeed, width: u8) -> Self {
+        let speed = speed.code();
+        let width = width.clamp(1, 32) as u32;
+        let lnkcap = (self.get(Self::LNKCAP) & !0x3ff) | width << 4 | speed;
+        put(&mut self.bytes, Self::LNKCAP, &lnkcap.to_le_bytes());
+        put(
+            &mut self.bytes,
+            Self::LNKSTA,
+            &((width << 4 | speed) as u16).to_le_bytes(),
+        );
+        // Supported Link Speeds Vector, every speed up to the one of the link.
+        let vector = ((1u32 << speed) - 1) << 1;
+        put(&mut self.bytes, Self::LNKCAP2, &vector.to_le_bytes());
+        // Target Link Speed, the fastest the link supports.
+        let lnkctl2 = (self.get(Self::LNKCTL2) & !0xf) | speed;
+        put(&mut self.bytes, Self::LNKCTL2, &lnkctl2.to_le_bytes());
+        self
+    }
+}
+
+impl PciCapability for PcieCapability {
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::PciExpress
+    }
+}
+
+impl StandardCapability for PcieCapability {
+    fn writable(&self) -> Vec<u32> {
+        let mut writable = vec![0; 15];
+        // Device Control but Initiate FLR, which the bridge handles.
+        writable[2] = 0x0000_7fff;
+        // Link Control and Link Control 2 of the functions with a link.
+        if self.port != PciePortType::RootComplexEndpoint {
+            writable[4] = 0x0000_0ffb;
+            writable[12] = 0x0000_ffff;
+        }
+        // Device Control 2, the Completion Timeout Value and Disable only if supported.
+        let devcap2 = self.get(Self::DEVCAP2);
+        writable[10] = 0x0000_ffe0;
+        if devcap2 & 0xf != 0 {
+            writable[10] |= 0xf;
+        }
+        if devcap2 & Self::DEVCAP2_CTDS != 0 {
+            writable[10] |= 0x10;
+        }
+        writable
+    }
+}
+
+/// An extended capability [`PcieConfiguration::add_extended`] adds to the configuration space.
+pub trait ExtendedCapability {
+    fn id(&self) -> u16;
+
+    fn version(&self) -> u8;
+
+    /// The registers following the capability header.
+    fn body(&self) -> Vec<u32>;
+
+    /// The bits the guest writes in each DW of the body.
+    fn writable(&self) -> Vec<u32> {
+        vec![]
+    }
+
+    /// The bits of each DW of the body the guest clears by writing 1.
+    fn clear_on_write(&self) -> Vec<u32> {
+        vec![]
+    }
+}
+
+/// Advanced Error Reporting capability of a function which is not a root port. The errors
+/// are unmasked and their severities the defaults of the specification.
+#[derive(Debug, Clone, Default)]
+pub struct AerCapability {
+    ecrc: bool,
+}
+
+impl AerCapability {
+    /// Uncorrectable errors the status, mask and severity registers have bits for.
+    pub const UNCORRECTABLE: u32 = 0x03ff_f030;
+    /// Correctable errors the status and mask registers have bits for.
+    pub const CORRECTABLE: u32 = 0x0000_f1c1;
+    /// DW index into the body of the uncorrectable error status register.
+    pub const UNCOR_STATUS: usize = 0;
+    /// DW index into the body of the correctable error status register.
+    pub const COR_STATUS: usize = 3;
+    /// DW index into the body of the first DW of the header log.
+    pub const HEADER_LOG: usize = 6;
+
+    const UNCOR_SEVERITY: u32 = 0x0046_2030;
+    const COR_MASK: u32 = 0x0000_2000;
+    /// ECRC Generation and Check Capable.
+    const CAP_ECRC: u32 = 0x0000_00a0;
+    /// ECRC Generation and Check Enable.
+    const CONTROL_ECRC: u32 = 0x0000_0140;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The function generates and checks ECRC.
+    pub fn ecrc(mut self) -> Self {
+        self.ecrc = true;
+        self
+    }
+}
+
+impl ExtendedCapability for AerCapability {
+    fn id(&self) -> u16 {
+        0x0001
+    }
+
+    fn version(&self) -> u8 {
+        2
+    }
+
+    fn body(&self) -> Vec<u32> {
+        let mut body = vec![0; 17];
+        body[2] = Self::UNCOR_SEVERITY;
+        body[4] = Self::COR_MASK;
+        if self.ecrc {
+            body[5] = Self::CAP_ECRC;
+        }
+        body
+    }
+
+    fn writable(&self) -> Vec<u32> {
+        let control = if self.ecrc { Self::CONTROL_ECRC } else { 0 };
+        vec![
+            0,
+            Self::UNCORRECTABLE,
+            Self::UNCORRECTABLE,
+            0,
+            Self::CORRECTABLE,
+            control,
+        ]
+    }
+
+    fn clear_on_write(&self) -> Vec<u32> {
+        vec![Self::UNCORRECTABLE, 0, 0, Self::CORRECTABLE]
+    }
+}
+
+/// Device Serial Number capability.
+#[derive(Debug, Clone)]
+pub struct DsnCapability {
+    serial: u64,
+}
+
+impl DsnCapability {
+    pub fn new(serial: u64) -> Self {
+        DsnCapability { serial }
+    }
+}
+
+impl ExtendedCapability for DsnCapability {
+    fn id(&self) -> u16 {
+        0x0003
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn body(&self) -> Vec<u32> {
+        vec![self.serial as u32, (self.serial >> 32) as u32]
+    }
+}
+
+/// Alternative Routing-ID Interpretation capability, which lets a device have 256 functions.
+#[derive(Debug, Clone)]
+pub struct AriCapability {
+    next_function: u8,
+}
+
+impl AriCapability {
+    /// `next_function` is the function number of the next function of the device, 0 for the
+    /// last one.
+    pub fn new(next_function: u8) -> Self {
+        AriCapability { next_function }
+    }
+}
+
+impl ExtendedCapability for AriCapability {
+    fn id(&self) -> u16 {
+        0x000e
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn body(&self) -> Vec<u32> {
+        vec![(self.next_function as u32) << 8]
+    }
+
+    fn writable(&self) -> Vec<u32> {
+        // MFVC and ACS Function Groups Enable, Function Group.
+        vec![0x0073_0000]
+    }
+}
+
+/// Address Translation Services capability, see [`crate::ats`].
+#[derive(Debug, Clone, Default)]
+pub struct AtsCapability {
+    queue_depth: u8,
+    page_aligned: bool,
+}
+
+impl AtsCapability {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Invalidate requests the function queues, from 1 to 32. 32 unless told otherwise.
+    pub fn invalidate_queue_depth(mut self, depth: u8) -> Self {
+        // 0 stands for 32.
+        self.queue_depth = depth.clamp(1, 32) & 0x1f;
+        self
+    }
+
+    /// The function only asks for translations of page aligned addresses.
+    pub fn page_aligned(mut self) -> Self {
+        self.page_aligned = true;
+        self
+    }
+}
+
+impl ExtendedCapability for AtsCapability {
+    fn id(&self) -> u16 {
+        0x000f
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn body(&self) -> Vec<u32> {
+        let mut cap = self.queue_depth as u32;
+        if self.page_aligned {
+            cap |= 1 << 5;
+        }
+        vec![cap]
+    }
+
+    fn writable(&self) -> Vec<u32> {
+        // Smallest Translation Unit and Enable.
+        vec![0x801f_0000]
+    }
+}
+
+/// Precision Time Measurement capability of a PTM Requester, see [`crate::ptm`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PtmCapability {
+    granularity: u8,
+}
+
+impl PtmCapability {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Period of the local clock of the function in ns, 0 unless told otherwise, which says
+    /// it is not known.
+    pub fn local_clock_granularity(mut self, ns: u8) -> Self {
+        self.granularity = ns;
+        self
+    }
+}
+
+impl ExtendedCapability for PtmCapability {
+    fn id(&self) -> u16 {
+        0x001f
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn body(&self) -> Vec<u32> {
+        // PTM Requester Capable.
+        vec![(self.granularity as u32) << 8 | 0x1, 0]
+    }
+
+    fn writable(&self) -> Vec<u32> {
+        // PTM Enable and Effective Granularity.
+        vec![0, 0x0000_ff01]
+    }
+}
+
+/// A BAR of the VFs of an [`SriovCapability`].
+#[derive(Debug, Clone, Copy)]
+struct VfBar {
+    index: usize,
+    size: u64,
+    /// The type and prefetchable bits of the BAR.
+    flags: u32,
+}
+
+/// Single Root I/O Virtualization capability of a physical function.
+#[derive(Debug, Clone)]
+pub struct SriovCapability {
+    total_vfs: u16,
+    first_vf: u16,
+    vf_stride: u16,
+    vf_device_id: u16,
+    bars: Vec<VfBar>,
+}
+
+impl SriovCapability {
+    /// Extended capability ID of SR-IOV.
+    pub const ID: u16 = 0x0010;
+
+    /// DW index into the body of the SR-IOV Control and Status registers.
+    pub const CONTROL: usize = 1;
+    /// DW index into the body of the InitialVFs and TotalVFs registers.
+    pub const TOTAL_VFS: usize = 2;
+    /// DW index into the body of the NumVFs register.
+    pub const NUM_VFS: usize = 3;
+    /// DW index into the body of the First VF Offset and VF Stride registers.
+    pub const VF_OFFSET: usize = 4;
+    /// DW index into the body of the VF Device ID.
+    pub const VF_DEVICE_ID: usize = 5;
+    /// DW index into the body of the System Page Size register.
+    pub const PAGE_SIZE: usize = 7;
+    /// DW index into the body of VF BAR0.
+    pub const VF_BAR0: usize = 8;
+
+    /// VF Enable.
+    pub const VF_ENABLE: u32 = 0x1;
+    /// VF Memory Space Enable.
+    pub const VF_MSE: u32 = 0x8;
+
+    const SUPPORTED_PAGE_SIZES: usize = 6;
+    /// Page sizes from 4 KB to 4 MB, in powers of 4.
+    const PAGE_SIZES: u32 = 0x553;
+
+    /// `total_vfs` VFs, the first one right after the PF with a stride of 1.
+    pub fn new(total_vfs: u16, vf_device_id: u16) -> Self {
+        SriovCapability {
+            total_vfs,
+            first_vf: 1,
+            vf_stride: 1,
+            vf_device_id,
+            bars: vec![],
+        }
+    }
+
+    /// The routing ID of the first VF is `first` after the one of the PF, the others follow
+    /// `stride` apart.
+    pub fn vf_offset(mut self, first: u16, stride: u16) -> Self {
+        self.first_vf = first;
+        self.vf_stride = stride;
+        self
+    }
+
+    /// VF BAR `index`, `size` bytes for each VF. A 64-bit BAR takes the index after it too.
+    pub fn vf_bar(
+        mut self,
+        index: usize,
+        size: u64,
+        region: PciBarRegionType,
+        prefetchable: PciBarPrefetchable,
+    ) -> Self {
+        let mut flags = prefetchable as u32;
+        if region == PciBarRegionType::Memory64BitRegion {
+            flags |= 0x4;
+        }
+        self.bars.push(VfBar {
+            index: index.min(5),
+            size: size.max(0x10).next_power_of_two(),
+            flags,
+        });
+        self
+    }
+}
+
+impl ExtendedCapability for SriovCapability {
+    fn id(&self) -> u16 {
+        Self::ID
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn body(&self) -> Vec<u32> {
+        let mut body = vec![0; 15];
+        body[Self::TOTAL_VFS] = self.total_vfs as u32 | (self.total_vfs as u32) << 16;
+        body[Self::VF_OFFSET] = self.first_vf as u32 | (self.vf_stride as u32) << 16;
+        body[Self::VF_DEVICE_ID] = (self.vf_device_id as u32) << 16;
+        body[Self::SUPPORTED_PAGE_SIZES] = Self::PAGE_SIZES;
+        body[Self::PAGE_SIZE] = 0x1;
+        for bar in &self.bars {
+            body[Self::VF_BAR0 + bar.index] = bar.flags;
+        }
+        body
+    }
+
+    fn writable(&self) -> Vec<u32> {
+        let mut writable = vec![0; 15];
+        // VF Enable, VF Migration Enable and Interrupt Enable, VF MSE, ARI Capable Hierarchy.
+        writable[Self::CONTROL] = 0x0000_001f;
+        writable[Self::NUM_VFS] = 0x0000_ffff;
+        writable[Self::PAGE_SIZE] = Self::PAGE_SIZES;
+        for bar in &self.bars {
+            let mask = !(bar.size - 1);
+            writable[Self::VF_BAR0 + bar.index] = mask as u32 & 0xffff_fff0;
+            if bar.flags & 0x4 != 0 && bar.index < 5 {
+                writable[Self::VF_BAR0 + bar.index + 1] = (mask >> 32) as u32;
+            }
+        }
+        writable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capability_list() {
+        let pci = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x0001,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0x5555,
+            0x6666,
+            None,
+        );
+        let mut config = PcieConfiguration::new(pci);
+        let pm = config.add_capability(&PmCapability::new().d1()).unwrap() / 4;
+        let msi = config
+            .add_capability(&MsiCapability::new(4).address64().per_vector_masking())
+            .unwrap()
+            / 4;
+        let msix = config
+            .add_capability(&MsixCapability::new(8).table(2, 0).pba(2, 0x800))
+            .unwrap()
+            / 4;
+        let pcie = config
+            .add_capability(&PcieCapability::new(PciePortType::Endpoint).link(LinkSpeed::Gen3, 4))
+            .unwrap()
+            / 4;
+
+        // The list is linked in order and each capability follows the previous one.
+        assert_eq!(config.read_config_register(13) & 0xfc, pm as u32 * 4);
+        let next = |reg: usize| (config.read_config_register(reg) >> 8 & 0xfc) as usize / 4;
+        assert_eq!(
+            (next(pm), next(msi), next(msix), next(pcie)),
+            (msi, msix, pcie, 0)
+        );
+        assert_eq!((msi - pm, msix - msi, pcie - msix), (2, 6, 3));
+
+        assert_eq!(
+            config.read_config_register(pm),
+            0x0203_0000 | (msi as u32 * 4) << 8 | 0x1
+        );
+        assert_eq!(config.read_config_register(msi) >> 16, 0x0184);
+        assert_eq!(config.read_config_register(msix + 1), 0x2);
+        assert_eq!(config.read_config_register(msix + 2), 0x802);
+        assert_eq!(config.read_config_register(pcie + 3), 0x43);
+        assert_eq!(config.read_config_register(pcie + 4) >> 16, 0x43);
+
+        // The guest enables 4 vectors and masks vector 2, the pending bits stay read-only.
+        config.write_config_register(msi, 2, &[0x25, 0x01]);
+        config.write_config_register(msi + 4, 0, &[0xff; 4]);
+        config.write_config_register(msi + 5, 0, &[0xff; 4]);
+        assert_eq!(config.read_config_register(msi) >> 16, 0x01a5);
+        assert_eq!(config.read_config_register(msi + 4), 0xf);
+        assert_eq!(config.read_config_register(msi + 5), 0);
+
+        config.write_config_register(msix, 2, &[0xff, 0xff]);
+        assert_eq!(config.read_config_register(msix) >> 16, 0xc007);
+
+        // Without completion timeout ranges Device Control 2 keeps the timeout at default.
+        config.write_config_register(pcie + 10, 0, &[0xff]);
+        assert_eq!(config.read_config_register(pcie + 10), 0xe0);
+    }
+
+    #[test]
+    fn extended_capability_list() {
+        let pci = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x0001,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0x5555,
+            0x6666,
+            None,
+        );
+        let mut config = PcieConfiguration::new(pci);
+        let aer = config.add_extended(&AerCapability::new()).unwrap() / 4;
+        let sriov = SriovCapability::new(4, 0x5679).vf_bar(
+            0,
+            0x4000,
+            PciBarRegionType::Memory64BitRegion,
+            PciBarPrefetchable::Prefetchable,
+        );
+        let sriov = config.add_extended(&sriov).unwrap() / 4;
+        let ats = config.add_extended(&AtsCapability::new()).unwrap() / 4;
+        let ari = config.add_extended(&AriCapability::new(0)).unwrap() / 4;
+        let dsn = config
+            .add_extended(&DsnCapability::new(0x1234_5678_9abc_def0))
+            .unwrap()
+            / 4;
+
+        assert_eq!(aer, 0x40);
+        let next = |reg: usize| (config.read_config_register(reg) >> 20) as usize / 4;
+        assert_eq!(
+            (next(aer), next(sriov), next(ats), next(ari), next(dsn)),
+            (sriov, ats, ari, dsn, 0)
+        );
+        assert_eq!(config.read_config_register(sriov) & 0xf_ffff, 0x1_0010);
+        assert_eq!(config.read_config_register(sriov + 3), 0x0004_0004);
+        assert_eq!(config.read_config_register(dsn + 2), 0x1234_5678);
+
+        // The error status bits are cleared by writing 1, the other bits stay.
+        let status = aer + 1 + AerCapability::UNCOR_STATUS;
+        config.set_extended_register(status, 0x0010_4000);
+        config.write_config_register(status, 0, &0x0000_4001u32.to_le_bytes());
+        assert_eq!(config.read_config_register(status), 0x0010_0000);
+
+        // The guest sizes and places VF BAR0, then enables 2 VFs.
+        let bar = sriov + 1 + SriovCapability::VF_BAR0;
+        config.write_config_register(bar, 0, &[0xff; 4]);
+        config.write_config_register(bar + 1, 0, &[0xff; 4]);
+        assert_eq!(config.read_config_register(bar), 0xffff_c00c);
+        assert_eq!(config.read_config_register(bar + 1), 0xffff_ffff);
+        config.write_config_register(sriov + 1 + SriovCapability::NUM_VFS, 0, &[2, 0]);
+        config.write_config_register(sriov + 1 + SriovCapability::CONTROL, 0, &[0x9]);
+        assert_eq!(
+            config.read_config_register(sriov + 1 + SriovCapability::NUM_VFS),
+            2
+        );
+        assert_eq!(
+            config.read_config_register(sriov + 1 + SriovCapability::CONTROL),
+            0x9
+        );
+    }
+}
@@ -0,0 +1,273 @@
+/*!
+Capture of the traffic of the bridge into pcapng files.
+
+[`PcapngCapture`] is a [`TlpObserver`] writing every TLP it sees in its wire format, so traces
+can be archived, attached to bug reports and inspected offline, e.g. by Wireshark.
+
+# Format
+
+The file is a single pcapng section in little endian: a Section Header Block, one Interface
+Description Block with link type `LINKTYPE_USER0` (147) and nanosecond timestamps, followed by
+one Enhanced Packet Block per TLP. The packet data is the TLP as serialized by
+[`Tlp::to_bytes`], the direction is stored in the `epb_flags` option: inbound for TLPs from
+the devices, outbound for TLPs to the devices. [`read_capture`] reads such a file back, e.g. to
+replay it as a [`Trace`].
+*/
+
+use crate::*;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x1;
+const ENHANCED_PACKET_BLOCK: u32 = 0x6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+/// `LINKTYPE_USER0`, there is no link type assigned to PCIe TLPs.
+pub const LINKTYPE_TLP: u16 = 147;
+
+const OPT_END: u16 = 0;
+const OPT_IF_TSRESOL: u16 = 9;
+const OPT_EPB_FLAGS: u16 = 2;
+const EPB_FLAGS_INBOUND: u32 = 0x1;
+const EPB_FLAGS_OUTBOUND: u32 = 0x2;
+
+/// [`TlpObserver`] writing the TLPs into a pcapng stream.
+pub struct PcapngCapture<W: Write> {
+    writer: W,
+    /// Set by the first failed write, the capture stops there.
+    failed: bool,
+}
+
+impl PcapngCapture<BufWriter<File>> {
+    /// Capture into a new file at `path`, any existing file is truncated.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        PcapngCapture::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> PcapngCapture<W> {
+    /// Start a capture on `writer` by writing the section and interface headers.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let mut shb = vec![];
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        // Version 1.0 and an unknown section length.
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut writer, SECTION_HEADER_BLOCK, &shb)?;
+
+        let mut idb = vec![];
+        idb.extend_from_slice(&LINKTYPE_TLP.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit.
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        push_option(&mut idb, OPT_IF_TSRESOL, &[9]);
+        push_option(&mut idb, OPT_END, &[]);
+        write_block(&mut writer, INTERFACE_DESCRIPTION_BLOCK, &idb)?;
+
+        Ok(PcapngCapture {
+            writer,
+            failed: false,
+        })
+    }
+
+    /// Write a TLP seen at `timestamp` nanoseconds since the UNIX epoch.
+    pub fn write_tlp(&mut self, direction: Direction, timestamp: u64, tlp: &Tlp) -> io::Result<()> {
+        let data = tlp
+            .to_bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+
+        let mut epb = vec![];
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&data);
+        pad(&mut epb);
+
+        let flags = match direction {
+            Direction::Upstream => EPB_FLAGS_INBOUND,
+            Direction::Downstream => EPB_FLAGS_OUTBOUND,
+        };
+        push_option(&mut epb, OPT_EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut epb, OPT_END, &[]);
+
+        write_block(&mut self.writer, ENHANCED_PACKET_BLOCK, &epb)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> TlpObserver for PcapngCapture<W> {
+    fn observe(&mut self, direction: Direction, tlp: &Tlp) {
+        if self.failed {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        if let Err(e) = self.write_tlp(direction, timestamp, tlp) {
+            error!("Capture of TLPs stopped: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+/// Read back the TLPs of a capture written by [`PcapngCapture`], with their direction and
+/// timestamp in nanoseconds since the UNIX epoch, in the order they were captured. Blocks other
+/// than Enhanced Packet Blocks are skipped.
+pub fn read_capture<R: Read>(mut reader: R) -> io::Result<Vec<(Direction, u64, Tlp)>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf)?;
+
+    if buf.len() < 12 || buf[0..4] != SECTION_HEADER_BLOCK.to_le_bytes() {
+        return Err(invalid("not a pcapng file"));
+    }
+    if buf[8..12] != BYTE_ORDER_MAGIC.to_le_bytes() {
+        return Err(invalid("big endian pcapng is not supported"));
+    }
+
+    let u32_at =
+        |buf: &[u8], offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+
+    let mut tlps = vec![];
+    let mut offset = 0;
+    while offset + 12 <= buf.len() {
+        let block_type = u32_at(&buf, offset);
+        let len = u32_at(&buf, offset + 4) as usize;
+        if len < 12 || !len.is_multiple_of(4) || offset + len > buf.len() {
+            return Err(invalid("truncated block"));
+        }
+        let block = &buf[offset + 8..offset + len - 4];
+        offset += len;
+
+        if block_type != ENHANCED_PACKET_BLOCK {
+            continue;
+        }
+        if block.len() < 20 {
+            return Err(invalid("truncated packet block"));
+        }
+
+        let timestamp = ((u32_at(block, 4) as u64) << 32) | u32_at(block, 8) as u64;
+        let captured = u32_at(block, 12) as usize;
+        let options = 20 + ((captured + 3) & !3);
+        if options > block.len() {
+            return Err(invalid("truncated packet block"));
+        }
+        let tlp =
+            Tlp::from_bytes(&block[20..20 + captured]).map_err(|e| invalid(&format!("{:?}", e)))?;
+
+        let mut direction = None;
+        let mut opt = options;
+        while opt + 4 <= block.len() {
+            let code = u16::from_le_bytes([block[opt], block[opt + 1]]);
+            let opt_len = u16::from_le_bytes([block[opt + 2], block[opt + 3]]) as usize;
+            if code == OPT_END || opt + 4 + opt_len > block.len() {
+                break;
+            }
+            if code == OPT_EPB_FLAGS && opt_len == 4 {
+                direction = match u32_at(block, opt + 4) & 0x3 {
+                    EPB_FLAGS_INBOUND => Some(Direction::Upstream),
+                    EPB_FLAGS_OUTBOUND => Some(Direction::Downstream),
+                    _ => None,
+                };
+            }
+            opt += 4 + ((opt_len + 3) & !3);
+        }
+
+        let direction = direction.ok_or_else(|| invalid("packet without direction"))?;
+        tlps.push((direction, timestamp, tlp));
+    }
+
+    Ok(tlps)
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+/// Write a block, whose total length is repeated after the 4 bytes aligned `body`.
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let len = (12 + body.len()) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcapng() {
+        let mut capture = PcapngCapture::new(vec![]).unwrap();
+        let headers = capture.get_ref().len();
+        assert_eq!(headers, 28 + 32);
+        assert_eq!(capture.get_ref()[8..12], BYTE_ORDER_MAGIC.to_le_bytes());
+
+        let tlp = TlpBuilder::completion_data(CompletionExtra {
+            requester: 0x10,
+            completer: 0x18,
+            tag: 1,
+            status: 0,
+            bcm: false,
+            byte_count: 4,
+            lower_address: 0,
+        })
+        .data(vec![0x1234_5678])
+        .build();
+        let wire = tlp.to_bytes().unwrap();
+        capture
+            .write_tlp(Direction::Downstream, 0x1_0000_0002, &tlp)
+            .unwrap();
+
+        let buf = capture.into_inner();
+        let epb = &buf[headers..];
+        assert_eq!(epb[0..4], ENHANCED_PACKET_BLOCK.to_le_bytes());
+        let len = u32::from_le_bytes([epb[4], epb[5], epb[6], epb[7]]) as usize;
+        assert_eq!(len, epb.len());
+        assert_eq!(epb[len - 4..], epb[4..8]);
+        assert_eq!(epb[12..16], 1u32.to_le_bytes());
+        assert_eq!(epb[16..20], 2u32.to_le_bytes());
+        assert_eq!(epb[20..24], (wire.len() as u32).to_le_bytes());
+        assert_eq!(epb[28..28 + wire.len()], wire[..]);
+        assert_eq!(
+            epb[28 + wire.len()..36 + wire.len()],
+            [2, 0, 4, 0, 2, 0, 0, 0]
+        );
+
+        let tlps = read_capture(&buf[..]).unwrap();
+        assert_eq!(tlps.len(), 1);
+        assert_eq!(tlps[0].0, Direction::Downstream);
+        assert_eq!(tlps[0].1, 0x1_0000_0002);
+        assert_eq!(tlps[0].2.to_bytes().unwrap(), wire);
+        assert!(read_capture(&buf[..headers - 4]).is_err());
+    }
+}
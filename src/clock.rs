@@ -0,0 +1,281 @@
+//! Time as seen by the device models.
+//!
+//! A model with timeouts or periodic behavior, e.g. a watchdog or a link heartbeat, reads the
+//! time from the [`SimClock`] of its [`LaneInfo`] rather than from the host. The clock follows
+//! the host by default. A manual clock only moves when [`SimClock::advance`] is called, which
+//! makes such models deterministic in tests. The model keeps its deadlines in a [`Timers`]
+//! and waits on its lane with [`SimClock::recv_until`], which returns at the next deadline
+//! in the time of the clock.
+
+use crate::*;
+
+use crossbeam_channel::{bounded, select, Receiver, RecvError, Sender};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+enum Source {
+    Host(Instant),
+    Manual {
+        now: Mutex<Duration>,
+        /// Waiters woken when the clock moves, gone once they stop waiting.
+        waiters: Mutex<Vec<Weak<Sender<()>>>>,
+    },
+}
+
+/// A clock shared by the adapter and its device models. Clones read the same time.
+#[derive(Clone)]
+pub struct SimClock {
+    source: Arc<Source>,
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        SimClock::host()
+    }
+}
+
+impl fmt::Debug for SimClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.source {
+            Source::Host(_) => write!(f, "SimClock::Host({:?})", self.now()),
+            Source::Manual { .. } => write!(f, "SimClock::Manual({:?})", self.now()),
+        }
+    }
+}
+
+impl PartialEq for SimClock {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.source, &other.source)
+    }
+}
+
+impl SimClock {
+    /// A clock following the host, starting at 0 now.
+    pub fn host() -> Self {
+        SimClock {
+            source: Arc::new(Source::Host(Instant::now())),
+        }
+    }
+
+    /// A clock at 0 which only moves by [`SimClock::advance`].
+    pub fn manual() -> Self {
+        SimClock {
+            source: Arc::new(Source::Manual {
+                now: Mutex::new(Duration::default()),
+                waiters: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    pub fn is_manual(&self) -> bool {
+        matches!(*self.source, Source::Manual { .. })
+    }
+
+    /// Time since the clock started.
+    pub fn now(&self) -> Duration {
+        match &*self.source {
+            Source::Host(start) => start.elapsed(),
+            Source::Manual { now, .. } => *now.lock().unwrap(),
+        }
+    }
+
+    /// Move a manual clock forward by `by` and wake the models waiting on it. A clock
+    /// following the host is left alone.
+    pub fn advance(&self, by: Duration) {
+        if let Source::Manual { now, waiters } = &*self.source {
+            *now.lock().unwrap() += by;
+            for waiter in waiters.lock().unwrap().drain(..) {
+                if let Some(waiter) = waiter.upgrade() {
+                    let _ = waiter.try_send(());
+                }
+            }
+        }
+    }
+
+    /// The next TLP of `rx`, or `None` once the clock reaches `deadline`. Without a deadline
+    /// it only waits for the TLP. Fails once the lane is gone.
+    pub fn recv_until(
+        &self,
+        rx: &Receiver<Tlp>,
+        deadline: Option<Duration>,
+    ) -> std::result::Result<Option<Tlp>, RecvError> {
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return rx.recv().map(Some),
+        };
+
+        match &*self.source {
+            Source::Host(start) => match rx.recv_deadline(*start + deadline) {
+                Ok(tlp) => Ok(Some(tlp)),
+                Err(e) if e.is_timeout() => Ok(None),
+                Err(_) => Err(RecvError),
+            },
+            Source::Manual { now, waiters } => {
+                let (tx, moved) = bounded(1);
+                let tx = Arc::new(tx);
+                loop {
+                    // The waiter is in place before the time is read, so an advance in
+                    // between is not missed.
+                    {
+                        let mut waiters = waiters.lock().unwrap();
+                        waiters.retain(|waiter| waiter.strong_count() > 0);
+                        waiters.push(Arc::downgrade(&tx));
+                    }
+                    if *now.lock().unwrap() >= deadline {
+                        return Ok(None);
+                    }
+                    select! {
+                        recv(rx) -> tlp => return tlp.map(Some),
+                        recv(moved) -> _ => (),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The deadlines of a model, each with what it is for.
+#[derive(Debug, Clone)]
+pub struct Timers<T> {
+    /// By deadline, then in the order they were set.
+    deadlines: BTreeMap<(Duration, u64), T>,
+    next: u64,
+}
+
+impl<T> Default for Timers<T> {
+    fn default() -> Self {
+        Timers {
+            deadlines: BTreeMap::new(),
+            next: 0,
+        }
+    }
+}
+
+impl<T: PartialEq> Timers<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fire `timer` at `deadline`.
+    pub fn set(&mut self, deadline: Duration, timer: T) {
+        self.deadlines.insert((deadline, self.next), timer);
+        self.next += 1;
+    }
+
+    /// Drop the deadlines of `timer`.
+    pub fn cancel(&mut self, timer: &T) {
+        self.deadlines.retain(|_, t| t != timer);
+    }
+
+    /// The earliest deadline.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.deadlines.keys().next().map(|&(deadline, _)| deadline)
+    }
+
+    /// Take the timers whose deadline `clock` reached, earliest first.
+    pub fn expired(&mut self, clock: &SimClock) -> Vec<T> {
+        let now = clock.now();
+        let mut expired = vec![];
+        while let Some(entry) = self.deadlines.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            expired.push(entry.remove());
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::device::dispatch;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn timers() {
+        let clock = SimClock::manual();
+        let mut timers = Timers::new();
+        timers.set(Duration::from_millis(20), "heartbeat");
+        timers.set(Duration::from_millis(10), "watchdog");
+        timers.set(Duration::from_millis(30), "watchdog");
+        assert_eq!(timers.next_deadline(), Some(Duration::from_millis(10)));
+        assert!(timers.expired(&clock).is_empty());
+
+        clock.advance(Duration::from_millis(20));
+        assert_eq!(timers.expired(&clock), ["watchdog", "heartbeat"]);
+        timers.cancel(&"watchdog");
+        assert_eq!(timers.next_deadline(), None);
+    }
+
+    /// Config register 0 reads the periods the watchdog counted.
+    struct Counter(Arc<AtomicU32>);
+
+    impl SimpleDevice for Counter {
+        fn on_config_read(&mut self, _: u16, _: usize) -> u32 {
+            self.0.load(Ordering::SeqCst)
+        }
+
+        fn on_config_write(&mut self, _: u16, _: usize, _: u64, _: &[u8]) {}
+    }
+
+    /// A watchdog which counts the periods of 10ms gone by.
+    struct Watchdog {
+        clock: SimClock,
+        counter: Counter,
+    }
+
+    impl PciSimDevice for Watchdog {
+        fn on_start(&mut self, info: &LaneInfo) {
+            self.clock = info.clock.clone();
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            let period = Duration::from_millis(10);
+            let mut timers = Timers::new();
+            timers.set(self.clock.now() + period, ());
+            let mut completer = 0;
+            loop {
+                match self.clock.recv_until(&lane.rx, timers.next_deadline()) {
+                    Ok(Some(tlp)) => dispatch(&mut self.counter, lane, &tlp, &mut completer),
+                    Ok(None) => (),
+                    Err(_) => break,
+                }
+                for () in timers.expired(&self.clock) {
+                    self.counter.0.fetch_add(1, Ordering::SeqCst);
+                    timers.set(self.clock.now() + period, ());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn manual_clock() {
+        let clock = SimClock::manual();
+        let fired = Arc::new(AtomicU32::new(0));
+        let watchdog = Watchdog {
+            clock: SimClock::host(),
+            counter: Counter(fired.clone()),
+        };
+        let adapter = PciAdapterBuilder::new()
+            .clock(clock.clone())
+            .start(Box::new(watchdog));
+
+        // However long the host takes, the watchdog only fires as the clock moves.
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(adapter.try_config_read(0), Ok(0));
+        for n in 1..=3 {
+            clock.advance(Duration::from_millis(10));
+            while fired.load(Ordering::SeqCst) < n {
+                std::thread::yield_now();
+            }
+        }
+        assert_eq!(adapter.try_config_read(0), Ok(3));
+
+        adapter.stop();
+        adapter.join();
+    }
+}
@@ -0,0 +1,628 @@
+//! Glue plugging a [`PciAdapter`] into the PCI segment of cloud-hypervisor.
+//!
+//! The adapter already is a [`PciDevice`] and a [`BusDevice`] of the cloud-hypervisor crates,
+//! what is left to the device manager is wiring it up: [`ChPciSegment::add_device`] allocates
+//! the BARs, registers them on the I/O and MMIO buses, hands the MSIs and the routed INTx line
+//! of the device to the interrupt managers and adds the device to the PCI bus. The bus then
+//! forwards the configuration space accesses of the guest to the adapter, and moves the BARs
+//! the guest reprograms through the [`DeviceRelocation`] it was created with, see
+//! [`BarRelocation`].
+//!
+//! [`ChInterruptSink`] instead gives every function an interrupt group of its own, sized and
+//! masked as the guest programs MSI-X, when plugged in through a [`VectorSink`].
+//!
+//! For live migration the adapter is [`Migratable`]: its [`Snapshottable::snapshot`] holds the
+//! bytes of the [`AdapterSnapshot`], which the device manager of the destination passes to
+//! [`ChPciSegment::restore_device`] to plug the device back in where it was.
+//!
+//! Run `cargo run --example cloud_hypervisor_demo --features cloud-hypervisor-demo` to boot a
+//! guest which finds a [`PciTestDevice`] on the segment.
+
+use crate::interrupt::MSI_VECTORS;
+use crate::*;
+
+use pci::{DeviceRelocation, PciBus, PciRootError};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::sync::{Arc, Mutex};
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceConfig, InterruptSourceGroup,
+    LegacyIrqGroupConfig, MsiIrqGroupConfig, MsiIrqSourceConfig,
+};
+use vm_device::Bus;
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+
+/// Why a device could not be added to a [`ChPciSegment`].
+#[derive(Debug)]
+pub enum SegmentError {
+    /// No address range is left for a BAR.
+    Bars(PciDeviceError),
+    /// The PCI bus has no slot left, or refused the device or its BARs.
+    Bus(PciRootError),
+    /// An interrupt manager could not create the interrupts of the device.
+    Interrupts(io::Error),
+    /// The snapshot does not fit the device.
+    Restore(PciAdapterError),
+}
+
+/// [`MsiSink`] signaling through the interrupt groups of cloud-hypervisor.
+///
+/// The groups have a fixed number of vectors, each programmed with a message, while the device
+/// tells the message with every MSI. The messages are routed to the vectors as they show up and
+/// reuse the vectors round robin once all of them are taken.
+///
+/// A legacy group signals an edge, so the INTx line is triggered when the pin is asserted and
+/// deasserting it is left to the guest acknowledging the interrupt.
+pub struct InterruptGroupSink {
+    msi: Arc<Box<dyn InterruptSourceGroup>>,
+    vectors: usize,
+    devid: u32,
+    routes: Mutex<Routes>,
+    intx: Option<Arc<Box<dyn InterruptSourceGroup>>>,
+}
+
+/// The messages routed to the vectors of an MSI group and the vector to reuse next.
+#[derive(Default)]
+struct Routes {
+    messages: Vec<(u64, u32)>,
+    next: usize,
+}
+
+impl InterruptGroupSink {
+    /// A sink of `vectors` vectors of `msi`, which tells the messages with the device ID
+    /// `devid`.
+    pub fn new(msi: Arc<Box<dyn InterruptSourceGroup>>, vectors: usize, devid: u32) -> Self {
+        InterruptGroupSink {
+            msi,
+            vectors,
+            devid,
+            routes: Mutex::new(Routes::default()),
+            intx: None,
+        }
+    }
+
+    /// Signal the INTx line through the legacy group `intx`.
+    pub fn intx(mut self, intx: Arc<Box<dyn InterruptSourceGroup>>) -> Self {
+        self.intx = Some(intx);
+        self
+    }
+
+    /// The vector programmed with the message, routing it first if needed.
+    fn route(&self, addr: u64, data: u32) -> io::Result<InterruptIndex> {
+        let mut routes = self.routes.lock().unwrap();
+        if let Some(index) = routes.messages.iter().position(|&m| m == (addr, data)) {
+            return Ok(index as InterruptIndex);
+        }
+
+        let index = if routes.messages.len() < self.vectors {
+            routes.messages.len()
+        } else {
+            routes.next
+        };
+
+        debug!("route MSI {:#x}:{:#x} to vector {}", addr, data, index);
+        let config = MsiIrqSourceConfig {
+            high_addr: (addr >> 32) as u32,
+            low_addr: addr as u32,
+            data,
+            devid: self.devid,
+        };
+        self.msi.update(
+            index as InterruptIndex,
+            InterruptSourceConfig::MsiIrq(config),
+        )?;
+
+        if index == routes.messages.len() {
+            routes.messages.push((addr, data));
+        } else {
+            routes.messages[index] = (addr, data);
+            routes.next = (index + 1) % self.vectors;
+        }
+        Ok(index as InterruptIndex)
+    }
+}
+
+impl MsiSink for InterruptGroupSink {
+    fn inject(&self, addr: u64, data: u32) -> io::Result<()> {
+        let index = self.route(addr, data)?;
+        self.msi.trigger(index)
+    }
+
+    fn set_intx_line(&self, pin: u8, _: u32, level: bool) -> io::Result<()> {
+        match self.intx.as_ref() {
+            Some(intx) if level => intx.trigger(0),
+            Some(_) => Ok(()),
+            None => self.set_intx(pin, level),
+        }
+    }
+}
+
+/// [`InterruptSink`] creating an interrupt group of the MSI interrupt manager of
+/// cloud-hypervisor for each function, to be plugged in through a [`VectorSink`]. The vectors
+/// are told with the [`PciAddress::devid`] of their function.
+pub struct ChInterruptSink {
+    manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+    groups: Mutex<HashMap<PciAddress, Arc<Box<dyn InterruptSourceGroup>>>>,
+}
+
+impl ChInterruptSink {
+    pub fn new(manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>) -> Self {
+        ChInterruptSink {
+            manager,
+            groups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn group(&self, source: PciAddress) -> io::Result<Arc<Box<dyn InterruptSourceGroup>>> {
+        self.groups
+            .lock()
+            .unwrap()
+            .get(&source)
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no interrupt group for {}", source),
+                )
+            })
+    }
+}
+
+impl InterruptSink for ChInterruptSink {
+    fn configure_group(
+        &self,
+        source: PciAddress,
+        _: InterruptKind,
+        vectors: u16,
+    ) -> io::Result<()> {
+        self.release_group(source)?;
+        let group = self.manager.create_group(MsiIrqGroupConfig {
+            base: 0,
+            count: vectors as InterruptIndex,
+        })?;
+        group.enable()?;
+        self.groups.lock().unwrap().insert(source, group);
+        Ok(())
+    }
+
+    fn release_group(&self, source: PciAddress) -> io::Result<()> {
+        let group = self.groups.lock().unwrap().remove(&source);
+        match group {
+            Some(group) => {
+                group.disable()?;
+                self.manager.destroy_group(group)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn configure_vector(
+        &self,
+        source: PciAddress,
+        vector: u16,
+        addr: u64,
+        data: u32,
+    ) -> io::Result<()> {
+        let config = MsiIrqSourceConfig {
+            high_addr: (addr >> 32) as u32,
+            low_addr: addr as u32,
+            data,
+            devid: source.devid(),
+        };
+        self.group(source)?.update(
+            vector as InterruptIndex,
+            InterruptSourceConfig::MsiIrq(config),
+        )
+    }
+
+    fn trigger(&self, source: PciAddress, vector: u16) -> io::Result<()> {
+        self.group(source)?.trigger(vector as InterruptIndex)
+    }
+
+    fn mask(&self, source: PciAddress, vector: u16) -> io::Result<()> {
+        self.group(source)?.mask(vector as InterruptIndex)
+    }
+
+    fn unmask(&self, source: PciAddress, vector: u16) -> io::Result<()> {
+        self.group(source)?.unmask(vector as InterruptIndex)
+    }
+}
+
+/// [`DeviceRelocation`] of a VMM built from the cloud-hypervisor crates without its device
+/// manager: a BAR the guest reprograms moves on its bus, then in the device.
+pub struct BarRelocation {
+    io_bus: Arc<Bus>,
+    mmio_bus: Arc<Bus>,
+}
+
+impl BarRelocation {
+    pub fn new(io_bus: Arc<Bus>, mmio_bus: Arc<Bus>) -> Self {
+        BarRelocation { io_bus, mmio_bus }
+    }
+}
+
+impl DeviceRelocation for BarRelocation {
+    fn move_bar(
+        &self,
+        old_base: u64,
+        new_base: u64,
+        len: u64,
+        pci_dev: &mut dyn PciDevice,
+        region_type: PciBarRegionType,
+    ) -> io::Result<()> {
+        let bus = match region_type {
+            PciBarRegionType::IoRegion => &self.io_bus,
+            _ => &self.mmio_bus,
+        };
+        bus.update_range(old_base, len, new_base, len)
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        pci_dev.move_bar(old_base, new_base)
+    }
+}
+
+/// The parts of the cloud-hypervisor device manager a [`PciAdapter`] is plugged into.
+#[derive(Clone)]
+pub struct ChPciSegment {
+    id: u16,
+    pci_bus: Arc<Mutex<PciBus>>,
+    allocator: Arc<Mutex<SystemAllocator>>,
+    io_bus: Arc<Bus>,
+    mmio_bus: Arc<Bus>,
+    msi_interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+    legacy_interrupt_manager: Option<Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>>,
+}
+
+impl ChPciSegment {
+    pub fn new(
+        pci_bus: Arc<Mutex<PciBus>>,
+        allocator: Arc<Mutex<SystemAllocator>>,
+        io_bus: Arc<Bus>,
+        mmio_bus: Arc<Bus>,
+        msi_interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+    ) -> Self {
+        ChPciSegment {
+            id: 0,
+            pci_bus,
+            allocator,
+            io_bus,
+            mmio_bus,
+            msi_interrupt_manager,
+            legacy_interrupt_manager: None,
+        }
+    }
+
+    /// The ID of the segment, 0 by default. The MSIs of the devices carry it in the upper half
+    /// of their DeviceID, as cloud-hypervisor does.
+    pub fn id(mut self, id: u16) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Signal the INTx lines of the devices through `manager`, without it only MSIs are
+    /// signaled.
+    pub fn legacy_interrupts(
+        mut self,
+        manager: Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
+    ) -> Self {
+        self.legacy_interrupt_manager = Some(manager);
+        self
+    }
+
+    /// Plug `adapter` into the next free slot of the bus. Returns the BDF of the device on the
+    /// segment, which is independent of the BDF the bridge uses on the lane, and the adapter
+    /// as the bus shares it.
+    pub fn add_device(
+        &self,
+        mut adapter: PciAdapter,
+    ) -> std::result::Result<(u32, Arc<Mutex<PciAdapter>>), SegmentError> {
+        let mut pci_bus = self.pci_bus.lock().unwrap();
+        let bdf = pci_bus.next_device_id().map_err(SegmentError::Bus)? << 3;
+
+        let mut allocator = self.allocator.lock().unwrap();
+        let bars = adapter
+            .allocate_bars(&mut allocator)
+            .map_err(SegmentError::Bars)?;
+        let devid = PciAddress {
+            segment: self.id,
+            bdf: bdf as u16,
+        }
+        .devid();
+        let sink = match self.interrupts(&adapter, devid) {
+            Ok(sink) => sink,
+            Err(e) => {
+                let _ = adapter.free_bars(&mut allocator);
+                return Err(SegmentError::Interrupts(e));
+            }
+        };
+        adapter.set_msi_sink(Box::new(sink));
+
+        debug!("add device {:#x} with BARs {:x?}", bdf, bars);
+        let adapter = self.plug(&mut pci_bus, bdf, adapter, bars)?;
+        Ok((bdf, adapter))
+    }
+
+    /// Plug `adapter` of a freshly started model back into slot `bdf` of the bus, with the
+    /// state of `snapshot` taken on the source, see [`PciAdapter::restore`]. The BARs stay where
+    /// the guest put them, the device manager reserves their ranges in its allocator itself.
+    pub fn restore_device(
+        &self,
+        bdf: u32,
+        mut adapter: PciAdapter,
+        snapshot: &AdapterSnapshot,
+    ) -> std::result::Result<Arc<Mutex<PciAdapter>>, SegmentError> {
+        adapter.restore(snapshot).map_err(SegmentError::Restore)?;
+        let devid = PciAddress {
+            segment: self.id,
+            bdf: bdf as u16,
+        }
+        .devid();
+        let sink = self
+            .interrupts(&adapter, devid)
+            .map_err(SegmentError::Interrupts)?;
+        adapter.set_msi_sink(Box::new(sink));
+
+        let bars = snapshot
+            .bars
+            .iter()
+            .map(|bar| (GuestAddress(bar.start), bar.length, bar.type_))
+            .collect();
+        debug!("restore device {:#x} with BARs {:x?}", bdf, bars);
+        let mut pci_bus = self.pci_bus.lock().unwrap();
+        self.plug(&mut pci_bus, bdf, adapter, bars)
+    }
+
+    /// Add `adapter` to the bus at `bdf` and register its BARs on the I/O and MMIO buses.
+    fn plug(
+        &self,
+        pci_bus: &mut PciBus,
+        bdf: u32,
+        adapter: PciAdapter,
+        bars: Vec<(GuestAddress, GuestUsize, PciBarRegionType)>,
+    ) -> std::result::Result<Arc<Mutex<PciAdapter>>, SegmentError> {
+        let adapter = Arc::new(Mutex::new(adapter));
+        pci_bus
+            .add_device(bdf, adapter.clone())
+            .map_err(SegmentError::Bus)?;
+        pci_bus
+            .register_mapping(adapter.clone(), &self.io_bus, &self.mmio_bus, bars)
+            .map_err(SegmentError::Bus)?;
+        Ok(adapter)
+    }
+
+    /// Create the interrupt groups of the device of DeviceID `devid`, whose BARs are allocated.
+    fn interrupts(&self, adapter: &PciAdapter, devid: u32) -> io::Result<InterruptGroupSink> {
+        let vectors = adapter
+            .msix
+            .map_or(MSI_VECTORS as usize, |cap| cap.table_size);
+        let msi = self.msi_interrupt_manager.create_group(MsiIrqGroupConfig {
+            base: 0,
+            count: vectors as InterruptIndex,
+        })?;
+        msi.enable()?;
+        let sink = InterruptGroupSink::new(msi, vectors, devid);
+
+        match (adapter.intx_line(), self.legacy_interrupt_manager.as_ref()) {
+            (Some(irq), Some(manager)) => {
+                let intx = manager.create_group(LegacyIrqGroupConfig { irq })?;
+                intx.enable()?;
+                Ok(sink.intx(intx))
+            }
+            _ => Ok(sink),
+        }
+    }
+}
+
+/// The bridge quiesces the model while it takes the snapshot, so pausing the adapter is left to
+/// pausing the guest.
+impl Pausable for PciAdapter {}
+
+impl Snapshottable for PciAdapter {
+    fn id(&self) -> String {
+        format!("pcie-tlp-{}", self.address())
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let snapshot =
+            PciAdapter::snapshot(self).map_err(|e| MigratableError::Snapshot(e.into()))?;
+        Snapshot::new_from_state(&snapshot.to_bytes())
+    }
+}
+
+impl Transportable for PciAdapter {}
+
+impl Migratable for PciAdapter {}
+
+impl TryFrom<&Snapshot> for AdapterSnapshot {
+    type Error = MigratableError;
+
+    fn try_from(snapshot: &Snapshot) -> std::result::Result<Self, MigratableError> {
+        let bytes: Vec<u8> = snapshot.to_state()?;
+        AdapterSnapshot::from_bytes(&bytes).map_err(|e| MigratableError::Restore(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::test_allocator;
+
+    use pci::{PciConfigIo, PciRoot};
+    use std::sync::mpsc::{channel, Sender};
+
+    /// Group recording the vectors it triggers with their messages.
+    struct RecordingGroup {
+        configs: Mutex<Vec<Option<MsiIrqSourceConfig>>>,
+        triggers: Mutex<Sender<(InterruptIndex, u64, u32)>>,
+    }
+
+    impl InterruptSourceGroup for RecordingGroup {
+        fn trigger(&self, index: InterruptIndex) -> io::Result<()> {
+            let config = self.configs.lock().unwrap()[index as usize].unwrap();
+            let addr = (config.high_addr as u64) << 32 | config.low_addr as u64;
+            let _ = self
+                .triggers
+                .lock()
+                .unwrap()
+                .send((index, addr, config.data));
+            Ok(())
+        }
+
+        fn update(&self, index: InterruptIndex, config: InterruptSourceConfig) -> io::Result<()> {
+            if let InterruptSourceConfig::MsiIrq(config) = config {
+                self.configs.lock().unwrap()[index as usize] = Some(config);
+            }
+            Ok(())
+        }
+    }
+
+    struct RecordingManager(Mutex<Sender<(InterruptIndex, u64, u32)>>);
+
+    impl InterruptManager for RecordingManager {
+        type GroupConfig = MsiIrqGroupConfig;
+
+        fn create_group(
+            &self,
+            config: MsiIrqGroupConfig,
+        ) -> io::Result<Arc<Box<dyn InterruptSourceGroup>>> {
+            Ok(Arc::new(Box::new(RecordingGroup {
+                configs: Mutex::new(vec![None; config.count as usize]),
+                triggers: Mutex::new(self.0.lock().unwrap().clone()),
+            })))
+        }
+
+        fn destroy_group(&self, _: Arc<Box<dyn InterruptSourceGroup>>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A segment with the configuration mechanism at 0xcf8 on its I/O bus, whose MSIs are
+    /// recorded into `tx`.
+    fn new_segment(tx: Sender<(InterruptIndex, u64, u32)>) -> (ChPciSegment, Arc<Bus>, Arc<Bus>) {
+        let io_bus = Arc::new(Bus::new());
+        let mmio_bus = Arc::new(Bus::new());
+        let relocation = Arc::new(BarRelocation::new(io_bus.clone(), mmio_bus.clone()));
+        let pci_bus = Arc::new(Mutex::new(PciBus::new(PciRoot::new(None), relocation)));
+        io_bus
+            .insert(
+                Arc::new(Mutex::new(PciConfigIo::new(pci_bus.clone()))),
+                0xcf8,
+                8,
+            )
+            .unwrap();
+        let segment = ChPciSegment::new(
+            pci_bus,
+            Arc::new(Mutex::new(test_allocator())),
+            io_bus.clone(),
+            mmio_bus.clone(),
+            Arc::new(RecordingManager(Mutex::new(tx))),
+        );
+        (segment, io_bus, mmio_bus)
+    }
+
+    #[test]
+    fn segment() {
+        let (tx, rx) = channel();
+        let (segment, io_bus, mmio_bus) = new_segment(tx);
+
+        let adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        let (bdf, adapter) = segment.add_device(adapter).unwrap();
+        assert_eq!(bdf, 1 << 3);
+
+        // The guest finds the device through the configuration mechanism.
+        let config_read = |reg: u32| {
+            let address = 0x8000_0000 | bdf << 8 | reg << 2;
+            io_bus.write(0xcf8, &address.to_le_bytes()).unwrap();
+            let mut data = [0; 4];
+            io_bus.read(0xcfc, &mut data).unwrap();
+            u32::from_le_bytes(data)
+        };
+        let ids = adapter.lock().unwrap().config_read(0);
+        assert_eq!(config_read(0), ids);
+        let bar = (config_read(5) as u64) << 32 | (config_read(4) & !0xf) as u64;
+        let mut data = [0; 4];
+        mmio_bus.read(bar, &mut data).unwrap();
+
+        // A BAR the guest moves is found at its new base.
+        let address = 0x8000_0000 | bdf << 8 | 4 << 2;
+        io_bus.write(0xcf8, &address.to_le_bytes()).unwrap();
+        io_bus.write(0xcfc, &0xc080_0000u32.to_le_bytes()).unwrap();
+        let moved = bar & !0xffff_ffff | 0xc080_0000;
+        assert_eq!(config_read(4) & !0xf, 0xc080_0000);
+        assert!(mmio_bus.read(bar, &mut [0; 4]).is_err());
+        let mut moved_data = [0; 4];
+        mmio_bus.read(moved, &mut moved_data).unwrap();
+        assert_eq!(moved_data, data);
+
+        // Messages are routed to the vectors as they show up.
+        let sink = InterruptGroupSink::new(
+            segment
+                .msi_interrupt_manager
+                .create_group(MsiIrqGroupConfig { base: 0, count: 2 })
+                .unwrap(),
+            2,
+            bdf,
+        );
+        for (addr, data) in [
+            (0xfee0_0000, 0x21),
+            (0xfee0_1000, 0x22),
+            (0xfee0_0000, 0x21),
+        ] {
+            sink.inject(addr, data).unwrap();
+        }
+        sink.inject(0xfee0_2000, 0x23).unwrap();
+        let triggers: Vec<_> = rx.try_iter().collect();
+        assert_eq!(
+            triggers,
+            vec![
+                (0, 0xfee0_0000, 0x21),
+                (1, 0xfee0_1000, 0x22),
+                (0, 0xfee0_0000, 0x21),
+                (0, 0xfee0_2000, 0x23)
+            ]
+        );
+
+        // Each function gets a group of its own once it enables MSI-X.
+        let sink = VectorSink::new(ChInterruptSink::new(segment.msi_interrupt_manager.clone()));
+        let source = PciAddress::new(0, 0, 1, 0);
+        assert!(sink.inject_vector(source, 1, 0xfee0_1000, 0x4021).is_err());
+        sink.enable_msix(source, 2).unwrap();
+        sink.inject_vector(source, 1, 0xfee0_1000, 0x4021).unwrap();
+        assert_eq!(rx.try_recv(), Ok((1, 0xfee0_1000, 0x4021)));
+        sink.disable_msix(source).unwrap();
+        assert!(sink.sink().trigger(source, 1).is_err());
+
+        // The buses keep the adapter, the bridge goes down with the test.
+        adapter.lock().unwrap().stop();
+    }
+
+    #[test]
+    fn migration() {
+        let (tx, _rx) = channel();
+        let (source, _, source_mmio) = new_segment(tx.clone());
+        let (bdf, adapter) = source
+            .add_device(PciAdapter::start(Box::new(PciTestDevice::new())))
+            .unwrap();
+        let snapshot = Snapshottable::snapshot(&mut *adapter.lock().unwrap()).unwrap();
+        let bar = adapter.lock().unwrap().mmio_regions[0].start.raw_value();
+        let mut data = [0; 4];
+        source_mmio.read(bar, &mut data).unwrap();
+        adapter.lock().unwrap().stop();
+
+        // The destination finds the device in the same slot with the same BARs.
+        let (destination, _, mmio_bus) = new_segment(tx);
+        let snapshot = AdapterSnapshot::try_from(&snapshot).unwrap();
+        let adapter = destination
+            .restore_device(
+                bdf,
+                PciAdapter::start(Box::new(PciTestDevice::new())),
+                &snapshot,
+            )
+            .unwrap();
+        let mut restored = [0; 4];
+        mmio_bus.read(bar, &mut restored).unwrap();
+        assert_eq!(restored, data);
+
+        adapter.lock().unwrap().stop();
+    }
+}
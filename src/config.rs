@@ -0,0 +1,764 @@
+//! Device side store of the 4 KB PCIe configuration space.
+
+use crate::*;
+
+use crate::adapter::Result;
+use crate::aer::{report, ErrorControls, AER_CONTROL, FIRST_ERROR_POINTER};
+use crate::device::{dispatch, fail};
+use crate::interrupt::PM_PME;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Number of DW registers of the conventional PCI configuration space.
+pub const PCI_CONFIG_REGS: usize = 64;
+/// Number of DW registers of the PCIe configuration space.
+pub const PCIE_CONFIG_REGS: usize = 1024;
+
+/// The PCIe configuration space of a device model.
+///
+/// The first 256 bytes are served by [`PciConfiguration`], the extended space from 0x100 holds
+/// a linked list of extended capabilities. Without any extended capability the header at 0x100
+/// reads 0, which terminates the list as the specification requires.
+pub struct PcieConfiguration {
+    pci: PciConfiguration,
+    /// The writable bits of the capabilities added by [`PcieConfiguration::add_capability`],
+    /// which [`PciConfiguration`] keeps read-only, and the guest's copy of them.
+    cap_regs: Vec<u32>,
+    cap_writable: Vec<u32>,
+    regs: Vec<u32>,
+    writable: Vec<u32>,
+    /// Bits of the extended registers the guest clears by writing 1.
+    clear_on_write: Vec<u32>,
+    /// Register index of the last extended capability header.
+    last_cap: Option<usize>,
+    /// Register index where the next extended capability goes.
+    next_cap: usize,
+}
+
+impl PcieConfiguration {
+    pub fn new(pci: PciConfiguration) -> Self {
+        let len = PCIE_CONFIG_REGS - PCI_CONFIG_REGS;
+
+        PcieConfiguration {
+            pci,
+            cap_regs: vec![0; PCI_CONFIG_REGS],
+            cap_writable: vec![0; PCI_CONFIG_REGS],
+            regs: vec![0; len],
+            writable: vec![0; len],
+            clear_on_write: vec![0; len],
+            last_cap: None,
+            next_cap: PCI_CONFIG_REGS,
+        }
+    }
+
+    /// The conventional part of the configuration space, e.g. to add BARs.
+    pub fn pci(&mut self) -> &mut PciConfiguration {
+        &mut self.pci
+    }
+
+    /// Append a standard capability to the capability list, with the bits its builder says
+    /// the guest writes. Returns the byte offset of the capability.
+    pub fn add_capability(
+        &mut self,
+        cap: &dyn StandardCapability,
+    ) -> std::result::Result<usize, ConfigError> {
+        let offset = self.pci.add_capability(cap)?;
+        let reg = offset / 4;
+        for (i, mask) in cap.writable().into_iter().enumerate() {
+            if mask != 0 && reg + i < PCI_CONFIG_REGS {
+                self.cap_regs[reg + i] = self.pci.read_config_register(reg + i);
+                self.cap_writable[reg + i] = mask;
+            }
+        }
+        Ok(offset)
+    }
+
+    /// Append an extended capability with the given ID and version. `body` follows the
+    /// capability header and is read-only unless [`PcieConfiguration::set_writable`] says
+    /// otherwise. Returns the byte offset of the capability.
+    pub fn add_extended_capability(
+        &mut self,
+        id: u16,
+        version: u8,
+        body: &[u32],
+    ) -> std::result::Result<usize, ConfigError> {
+        let reg = self.next_cap;
+        let len = 1 + body.len();
+        if reg + len > PCIE_CONFIG_REGS {
+            return Err(ConfigError::CapabilitySpaceFull(len * 4));
+        }
+
+        let idx = reg - PCI_CONFIG_REGS;
+        self.regs[idx] = id as u32 | ((version as u32 & 0xf) << 16);
+        self.regs[idx + 1..idx + len].copy_from_slice(body);
+
+        // Link the new capability through the next pointer of the previous one.
+        if let Some(last) = self.last_cap {
+            let last = last - PCI_CONFIG_REGS;
+            self.regs[last] = (self.regs[last] & 0x000f_ffff) | (((reg * 4) as u32) << 20);
+        }
+
+        self.last_cap = Some(reg);
+        self.next_cap = reg + len;
+        Ok(reg * 4)
+    }
+
+    /// Append an extended capability laid out by its builder, with the bits the builder says
+    /// the guest writes or clears. Returns the byte offset of the capability.
+    pub fn add_extended(
+        &mut self,
+        cap: &dyn ExtendedCapability,
+    ) -> std::result::Result<usize, ConfigError> {
+        let offset = self.add_extended_capability(cap.id(), cap.version(), &cap.body())?;
+        let body = offset / 4 + 1;
+        for (i, mask) in cap.writable().into_iter().enumerate() {
+            self.set_writable(body + i, mask);
+        }
+        for (i, mask) in cap.clear_on_write().into_iter().enumerate() {
+            self.set_clear_on_write(body + i, mask);
+        }
+        Ok(offset)
+    }
+
+    /// Allow the guest to change the bits of `mask` in an extended register.
+    pub fn set_writable(&mut self, reg_idx: usize, mask: u32) {
+        if (PCI_CONFIG_REGS..PCIE_CONFIG_REGS).contains(&reg_idx) {
+            self.writable[reg_idx - PCI_CONFIG_REGS] = mask;
+        }
+    }
+
+    /// Let the guest clear the bits of `mask` in an extended register by writing 1 to them,
+    /// like status bits are.
+    pub fn set_clear_on_write(&mut self, reg_idx: usize, mask: u32) {
+        if (PCI_CONFIG_REGS..PCIE_CONFIG_REGS).contains(&reg_idx) {
+            self.clear_on_write[reg_idx - PCI_CONFIG_REGS] = mask;
+        }
+    }
+
+    /// Set an extended register as the device does, whatever bits the guest may write, e.g.
+    /// to latch an error in a status register.
+    pub fn set_extended_register(&mut self, reg_idx: usize, value: u32) {
+        if (PCI_CONFIG_REGS..PCIE_CONFIG_REGS).contains(&reg_idx) {
+            self.regs[reg_idx - PCI_CONFIG_REGS] = value;
+        }
+    }
+
+    pub fn read_config_register(&self, reg_idx: usize) -> u32 {
+        match reg_idx {
+            r if r < PCI_CONFIG_REGS => {
+                let mask = self.cap_writable[r];
+                (self.pci.read_config_register(r) & !mask) | (self.cap_regs[r] & mask)
+            }
+            r if r < PCIE_CONFIG_REGS => self.regs[r - PCI_CONFIG_REGS],
+            _ => u32::MAX,
+        }
+    }
+
+    pub fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        if reg_idx < PCI_CONFIG_REGS && self.cap_writable[reg_idx] == 0 {
+            self.pci.write_config_register(reg_idx, offset, data);
+            return;
+        }
+
+        let offset = offset as usize;
+        if reg_idx >= PCIE_CONFIG_REGS || offset + data.len() > 4 {
+            return;
+        }
+
+        let mut mask = 0u32;
+        let mut value = 0u32;
+        for (i, b) in data.iter().enumerate() {
+            mask |= 0xff << ((offset + i) * 8);
+            value |= (*b as u32) << ((offset + i) * 8);
+        }
+
+        let (reg, writable, clear) = if reg_idx < PCI_CONFIG_REGS {
+            (&mut self.cap_regs[reg_idx], self.cap_writable[reg_idx], 0)
+        } else {
+            let idx = reg_idx - PCI_CONFIG_REGS;
+            (
+                &mut self.regs[idx],
+                self.writable[idx],
+                self.clear_on_write[idx],
+            )
+        };
+        let cleared = value & mask & clear;
+        let mask = mask & writable;
+        *reg = ((*reg & !mask) | (value & mask)) & !cleared;
+    }
+}
+
+/// Called with the value of a register, returns what the guest reads.
+pub type ReadHook = Box<dyn FnMut(u32) -> u32 + Send + Sync>;
+/// Called with the value of a register before and after the guest wrote it.
+pub type WriteHook = Box<dyn FnMut(u32, u32) + Send + Sync>;
+
+/// The configuration space of a function with the register semantics a model adds to what
+/// [`PcieConfiguration`] keeps.
+///
+/// A register is made read-only or write-1-to-clear bit by bit, and hooks see the reads and
+/// writes of the guest, e.g. to start a reset or to return a live status. The registers
+/// [`PcieConfiguration`] keeps read-only, such as the status register, are held by the
+/// wrapper once [`ConfigSpace::emulate`] says which bits the guest writes. Models delegate
+/// their config requests by [`ConfigSpace::handle`], or from the callbacks of a
+/// [`SimpleDevice`] by [`ConfigSpace::read_config_register`] and
+/// [`ConfigSpace::write_config_register`].
+///
+/// With the Power Management capability of [`ConfigSpace::add_pm`], [`ConfigSpace::handle`]
+/// also keeps the memory and IO requests from the model while the function is not in D0.
+pub struct ConfigSpace {
+    config: PcieConfiguration,
+    /// The registers held by the wrapper, with the bits the guest writes.
+    emulated: HashMap<usize, (u32, u32)>,
+    read_only: HashMap<usize, u32>,
+    clear_on_write: HashMap<usize, u32>,
+    read_hooks: HashMap<usize, ReadHook>,
+    write_hooks: HashMap<usize, WriteHook>,
+    pm: Option<PowerManagement>,
+    /// Completer ID taken from the config requests, for the requests completed by the wrapper.
+    completer: u16,
+    /// The register index of the AER body and the status bit of the error of the First Error
+    /// Pointer and the header log, see [`ConfigSpace::report_error`].
+    logged_error: Option<(usize, u32)>,
+}
+
+/// The Power Management capability of a [`ConfigSpace`].
+struct PowerManagement {
+    /// Register index of PMCSR.
+    reg: usize,
+    /// The Power Management Capabilities register.
+    pmc: u16,
+    /// Config requests complete with CRS for `recovery` after D3hot to D0, on `clock`.
+    recovery: Option<(SimClock, Duration)>,
+    ready_at: Option<Duration>,
+}
+
+impl PowerManagement {
+    fn supports(&self, state: PowerState) -> bool {
+        match state {
+            PowerState::D1 => self.pmc & (1 << 9) != 0,
+            PowerState::D2 => self.pmc & (1 << 10) != 0,
+            _ => true,
+        }
+    }
+
+    fn recovering(&self) -> bool {
+        match (&self.recovery, self.ready_at) {
+            (Some((clock, _)), Some(ready_at)) => clock.now() < ready_at,
+            _ => false,
+        }
+    }
+}
+
+impl ConfigSpace {
+    pub fn new(config: PcieConfiguration) -> Self {
+        ConfigSpace {
+            config,
+            emulated: HashMap::new(),
+            read_only: HashMap::new(),
+            clear_on_write: HashMap::new(),
+            read_hooks: HashMap::new(),
+            write_hooks: HashMap::new(),
+            pm: None,
+            completer: 0,
+            logged_error: None,
+        }
+    }
+
+    /// The wrapped configuration space, e.g. to add capabilities.
+    pub fn config(&mut self) -> &mut PcieConfiguration {
+        &mut self.config
+    }
+
+    /// Hold register `reg` in the wrapper, starting at `value`, with the bits of `writable`
+    /// written by the guest.
+    pub fn emulate(&mut self, reg: usize, value: u32, writable: u32) {
+        self.emulated.insert(reg, (value, writable));
+    }
+
+    /// Keep the bits of `mask` in register `reg` from being written by the guest.
+    pub fn set_read_only(&mut self, reg: usize, mask: u32) {
+        self.read_only.insert(reg, mask);
+    }
+
+    /// Let the guest clear the bits of `mask` in register `reg` by writing 1 to them. Writing
+    /// 0 leaves them alone.
+    pub fn set_clear_on_write(&mut self, reg: usize, mask: u32) {
+        self.clear_on_write.insert(reg, mask);
+    }
+
+    pub fn on_read(&mut self, reg: usize, hook: ReadHook) {
+        self.read_hooks.insert(reg, hook);
+    }
+
+    pub fn on_write(&mut self, reg: usize, hook: WriteHook) {
+        self.write_hooks.insert(reg, hook);
+    }
+
+    /// Add the Power Management capability, whose PMCSR the wrapper holds. The guest moves the
+    /// function between the states `cap` supports and clears PME_Status by writing 1, writes
+    /// of an unsupported state are dropped. Returns the byte offset of the capability.
+    pub fn add_pm(&mut self, cap: &PmCapability) -> std::result::Result<usize, ConfigError> {
+        let offset = self.config.add_capability(cap)?;
+        let reg = offset / 4 + 1;
+        let writable = PmCapability::POWER_STATE | PmCapability::PME_EN | PmCapability::PME_STATUS;
+        self.emulate(reg, self.config.read_config_register(reg), writable);
+        self.set_clear_on_write(reg, PmCapability::PME_STATUS);
+        self.pm = Some(PowerManagement {
+            reg,
+            pmc: (self.config.read_config_register(offset / 4) >> 16) as u16,
+            recovery: None,
+            ready_at: None,
+        });
+        Ok(offset)
+    }
+
+    /// Complete config requests with CRS for `time` on `clock` after the function goes from
+    /// D3hot to D0 and resets itself, i.e. without No_Soft_Reset.
+    pub fn set_pm_recovery(&mut self, clock: SimClock, time: Duration) {
+        if let Some(pm) = self.pm.as_mut() {
+            pm.recovery = Some((clock, time));
+        }
+    }
+
+    /// The power state of the function, D0 without the Power Management capability.
+    pub fn power_state(&self) -> PowerState {
+        match self.pm.as_ref() {
+            Some(pm) => PowerState::from_pmcsr(self.register(pm.reg)),
+            None => PowerState::D0,
+        }
+    }
+
+    /// Signal a PME from the function at `requester`, if it supports PME in its power state.
+    /// PME_Status is set, and the PM_PME message is sent while the guest set PME_En. Returns
+    /// whether the message is sent.
+    pub fn pme(&mut self, lane: &PciLane, requester: u16) -> Result<bool> {
+        let (reg, pmc) = match self.pm.as_ref() {
+            Some(pm) => (pm.reg, pm.pmc),
+            None => return Ok(false),
+        };
+        let pmcsr = self.register(reg);
+        if pmc & (1 << (11 + (pmcsr & PmCapability::POWER_STATE))) == 0 {
+            return Ok(false);
+        }
+
+        self.set_register(reg, pmcsr | PmCapability::PME_STATUS);
+        if pmcsr & PmCapability::PME_EN == 0 {
+            return Ok(false);
+        }
+        let tlp = TlpBuilder::message(MessageExtra::routed(
+            requester,
+            PM_PME,
+            MessageRouting::RootComplex,
+        ))
+        .build();
+        lane.tx
+            .send(tlp)
+            .map_err(|_| PciAdapterError::Disconnected)?;
+        Ok(true)
+    }
+
+    /// Report `error` of the function at `requester`, with the header of the TLP in error for
+    /// the header log, see [`header_log`]. The mask and severity of the error are those the
+    /// guest programmed into the AER capability, an uncorrectable error is non-fatal without
+    /// one. The error is latched in the status registers of the AER capability, the first
+    /// unmasked uncorrectable error also in the First Error Pointer and the header log until
+    /// the guest clears its status. An unmasked error is sent to the bridge as an ERR_*
+    /// message, returns whether Device Control enables signaling it to the root complex, see
+    /// [`crate::aer`].
+    pub fn report_error(
+        &mut self,
+        lane: &PciLane,
+        requester: u16,
+        error: AerError,
+        header: Option<[u32; 4]>,
+    ) -> Result<bool> {
+        let read = |reg| self.register(reg);
+        let controls = ErrorControls::find(read);
+        let (severity, masked, signaled) = controls.classify(read, error);
+        if let Some(aer) = controls.aer {
+            let body = aer + 1;
+            let bit = 1 << error.bit();
+            let status = match severity {
+                ErrorSeverity::Correctable => body + AerCapability::COR_STATUS,
+                _ => body + AerCapability::UNCOR_STATUS,
+            };
+            self.set_register(status, self.register(status) | bit);
+            let first = severity != ErrorSeverity::Correctable && !masked;
+            if first && self.logged_error.is_none() {
+                let control = self.register(body + AER_CONTROL) & !FIRST_ERROR_POINTER;
+                self.set_register(body + AER_CONTROL, control | error.bit() as u32);
+                for (i, dw) in header.unwrap_or_default().iter().enumerate() {
+                    self.set_register(body + AerCapability::HEADER_LOG + i, *dw);
+                }
+                self.logged_error = Some((body, bit));
+            }
+        }
+        if masked {
+            return Ok(false);
+        }
+        lane.tx
+            .send(report(requester, severity))
+            .map_err(|_| PciAdapterError::Disconnected)?;
+        Ok(signaled)
+    }
+
+    /// Set register `reg` as the device does, whatever bits the guest may write, e.g. to
+    /// latch an error into a status register.
+    pub fn set_register(&mut self, reg: usize, value: u32) {
+        match self.emulated.get_mut(&reg) {
+            Some((held, _)) => *held = value,
+            None if reg >= PCI_CONFIG_REGS => self.config.set_extended_register(reg, value),
+            None => self
+                .config
+                .write_config_register(reg, 0, &value.to_le_bytes()),
+        }
+    }
+
+    /// The value of register `reg` as the device sees it, without the read hooks.
+    pub fn register(&self, reg: usize) -> u32 {
+        match self.emulated.get(&reg) {
+            Some(&(value, _)) => value,
+            None => self.config.read_config_register(reg),
+        }
+    }
+
+    pub fn read_config_register(&mut self, reg: usize) -> u32 {
+        let value = self.register(reg);
+        match self.read_hooks.get_mut(&reg) {
+            Some(hook) => hook(value),
+            None => value,
+        }
+    }
+
+    /// Write `data` at byte `offset` of register `reg`.
+    pub fn write_config_register(&mut self, reg: usize, offset: u64, data: &[u8]) {
+        let offset = offset as usize;
+        if offset + data.len() > 4 {
+            return;
+        }
+        let (mut mask, mut bits) = (0u32, 0u32);
+        for (i, b) in data.iter().enumerate() {
+            mask |= 0xff << ((offset + i) * 8);
+            bits |= (*b as u32) << ((offset + i) * 8);
+        }
+
+        // Clearing the status of the first error frees the First Error Pointer and the header
+        // log.
+        if let Some((body, bit)) = self.logged_error {
+            if reg == body + AerCapability::UNCOR_STATUS && bits & bit != 0 {
+                let control = self.register(body + AER_CONTROL) & !FIRST_ERROR_POINTER;
+                self.set_register(body + AER_CONTROL, control);
+                for i in 0..4 {
+                    self.set_register(body + AerCapability::HEADER_LOG + i, 0);
+                }
+                self.logged_error = None;
+            }
+        }
+
+        let old = self.register(reg);
+        let clear = self.clear_on_write.get(&reg).copied().unwrap_or(0);
+        let mut keep = self.read_only.get(&reg).copied().unwrap_or(0) | clear | !mask;
+        if let Some(&(_, writable)) = self.emulated.get(&reg) {
+            keep |= !writable;
+        }
+        let mut value = ((old & keep) | (bits & !keep)) & !(bits & mask & clear);
+        if let Some(pm) = self.pm.as_mut().filter(|pm| pm.reg == reg) {
+            let (from, to) = (PowerState::from_pmcsr(old), PowerState::from_pmcsr(value));
+            if !pm.supports(to) {
+                value = (value & !PmCapability::POWER_STATE) | (old & PmCapability::POWER_STATE);
+            } else if from == PowerState::D3Hot
+                && to == PowerState::D0
+                && old & PmCapability::NO_SOFT_RESET == 0
+            {
+                pm.ready_at = pm
+                    .recovery
+                    .as_ref()
+                    .map(|(clock, time)| clock.now() + *time);
+            }
+        }
+
+        match self.emulated.get_mut(&reg) {
+            Some((held, _)) => *held = value,
+            // The bits the wrapper decides on are written as a whole register.
+            None if keep != !mask => {
+                self.config
+                    .write_config_register(reg, 0, &value.to_le_bytes())
+            }
+            None => self.config.write_config_register(reg, offset as u64, data),
+        }
+
+        let new = self.register(reg);
+        if let Some(hook) = self.write_hooks.get_mut(&reg) {
+            hook(old, new);
+        }
+    }
+
+    /// Answer `tlp` if it is a config request, returns whether it is one. Out of D0 the
+    /// memory and IO requests are taken as well, the non-posted ones complete with UR.
+    pub fn handle(&mut self, lane: &PciLane, tlp: &Tlp) -> bool {
+        use PacketType::*;
+
+        match tlp.header._type {
+            Config0Read(extra) | Config0Write(extra) | Config1Read(extra) | Config1Write(extra) => {
+                self.completer = extra.completer;
+                if self.pm.as_ref().is_some_and(PowerManagement::recovering) {
+                    fail(lane, tlp, extra.completer, CPL_CRS);
+                } else {
+                    let mut completer = extra.completer;
+                    dispatch(self, lane, tlp, &mut completer);
+                }
+                true
+            }
+            MemoryRead(_) | MemoryRead64(_) | MemoryWrite(_) | MemoryWrite64(_) | IoRead(_)
+            | IoWrite(_)
+                if self.power_state() != PowerState::D0 =>
+            {
+                fail(lane, tlp, self.completer, CPL_UR);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl SimpleDevice for ConfigSpace {
+    fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+        self.read_config_register(reg)
+    }
+
+    fn on_config_write(&mut self, _: u16, reg: usize, offset: u64, data: &[u8]) {
+        self.write_config_register(reg, offset, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crossbeam_channel::{select, unbounded, Receiver};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    #[test]
+    fn extended_capabilities() {
+        let pci = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x0001,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0x5555,
+            0x6666,
+            None,
+        );
+        let mut config = PcieConfiguration::new(pci);
+        assert_eq!(config.read_config_register(0x40), 0);
+
+        assert_eq!(
+            config.add_extended_capability(0x3, 1, &[1, 2]).unwrap(),
+            0x100
+        );
+        assert_eq!(config.add_extended_capability(0xb, 1, &[0]).unwrap(), 0x10c);
+        assert_eq!(config.read_config_register(0x40), 0x10c1_0003);
+        assert_eq!(config.read_config_register(0x43), 0x0001_000b);
+
+        config.write_config_register(0x44, 0, &[0xff; 4]);
+        assert_eq!(config.read_config_register(0x44), 0);
+        config.set_writable(0x44, 0xffff_0000);
+        config.write_config_register(0x44, 2, &[0x34, 0x12]);
+        assert_eq!(config.read_config_register(0x44), 0x1234_0000);
+
+        assert_eq!(config.read_config_register(0), 0x5678_1234);
+        assert_eq!(config.read_config_register(PCIE_CONFIG_REGS), u32::MAX);
+    }
+
+    #[test]
+    fn config_space() {
+        let pci = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x0001,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0x5555,
+            0x6666,
+            None,
+        );
+        let mut config = ConfigSpace::new(PcieConfiguration::new(pci));
+
+        // Command and status, with a detected parity error latched.
+        config.emulate(1, 0x8010_0000, 0x0000_0547);
+        config.set_clear_on_write(1, 0xf900_0000);
+        let written = Arc::new(Mutex::new(vec![]));
+        let log = written.clone();
+        config.on_write(
+            1,
+            Box::new(move |old, new| log.lock().unwrap().push((old, new))),
+        );
+        config.write_config_register(1, 0, &0x8000_0006u32.to_le_bytes());
+        assert_eq!(config.read_config_register(1), 0x0010_0006);
+        config.write_config_register(1, 0, &[0x02]);
+        assert_eq!(config.read_config_register(1), 0x0010_0002);
+        assert_eq!(
+            *written.lock().unwrap(),
+            [(0x8010_0000, 0x0010_0006), (0x0010_0006, 0x0010_0002)]
+        );
+
+        // The interrupt line is writable in the header, but not in this model.
+        config.set_read_only(0xf, 0xff);
+        config.write_config_register(0xf, 0, &[0x0b]);
+        assert_eq!(config.read_config_register(0xf) & 0xff, 0);
+
+        config.on_read(0, Box::new(|value| value | 0xffff));
+        assert_eq!(config.read_config_register(0), 0x5678_ffff);
+        assert_eq!(config.register(0), 0x5678_1234);
+    }
+
+    /// Memory of a model which reads 0xab.
+    struct Pattern;
+
+    impl SimpleDevice for Pattern {
+        fn on_config_read(&mut self, _: u16, _: usize) -> u32 {
+            0
+        }
+
+        fn on_config_write(&mut self, _: u16, _: usize, _: u64, _: &[u8]) {}
+
+        fn on_mem_read(&mut self, _: u64, data: &mut [u8]) -> std::result::Result<(), u8> {
+            data.fill(0xab);
+            Ok(())
+        }
+    }
+
+    /// Poll `done` until it holds, failing the test after a second.
+    fn wait_for(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+        }
+    }
+
+    /// A model with the PM capability at 0x40 signaling PME of the requester it is told.
+    struct PmDevice {
+        config: ConfigSpace,
+        memory: Pattern,
+        pme: Receiver<u16>,
+    }
+
+    impl PciSimDevice for PmDevice {
+        fn on_start(&mut self, info: &LaneInfo) {
+            self.config
+                .set_pm_recovery(info.clock.clone(), Duration::from_millis(10));
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            let mut completer = 0;
+            loop {
+                select! {
+                    recv(lane.rx) -> tlp => match tlp {
+                        Ok(tlp) => {
+                            if !self.config.handle(lane, &tlp) {
+                                dispatch(&mut self.memory, lane, &tlp, &mut completer);
+                            }
+                        }
+                        Err(_) => break,
+                    },
+                    recv(self.pme) -> requester => {
+                        self.config.pme(lane, requester.unwrap()).unwrap();
+                    },
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn power_management() {
+        let pci = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x0001,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0x5555,
+            0x6666,
+            None,
+        );
+        let mut config = ConfigSpace::new(PcieConfiguration::new(pci));
+        // PME from D0 and D3hot.
+        let cap = PmCapability::new().pme(0b01001);
+        assert_eq!(config.add_pm(&cap).unwrap(), 0x40);
+        let (pme, rx) = unbounded();
+        let device = PmDevice {
+            config,
+            memory: Pattern,
+            pme: rx,
+        };
+
+        let clock = SimClock::manual();
+        let mut adapter = PciAdapterBuilder::new()
+            .clock(clock.clone())
+            .start(Box::new(device));
+        adapter.set_crs_policy(CrsPolicy {
+            software_visibility: true,
+            ..CrsPolicy::default()
+        });
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_0000_0000),
+            length: 0x1000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: 4,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        let events = adapter.events();
+        let mut data = [0u8; 4];
+        adapter.try_bar_mmio_read(0x1_0000_0000, &mut data).unwrap();
+        assert_eq!(data, [0xab; 4]);
+
+        // D1 is not supported, the write is dropped.
+        adapter.try_config_write(0x11, 0, &[0x1]).unwrap();
+        assert_eq!(adapter.try_config_read(0x11), Ok(0));
+        adapter.try_config_write(0x11, 0, &[0x3]).unwrap();
+        assert_eq!(
+            adapter.try_bar_mmio_read(0x1_0000_0000, &mut data),
+            Err(PciAdapterError::Completion(CPL_UR))
+        );
+
+        // Without PME_En only PME_Status is set.
+        pme.send(adapter.bdf()).unwrap();
+        wait_for(|| adapter.try_config_read(0x11) == Ok(0x8003));
+        adapter
+            .try_config_write(0x11, 0, &0x8103u16.to_le_bytes())
+            .unwrap();
+        assert_eq!(adapter.try_config_read(0x11), Ok(0x0103));
+        pme.send(adapter.bdf()).unwrap();
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(1)),
+            Ok(AdapterEvent::Pme { bdf: adapter.bdf() })
+        );
+
+        // A PME in the name of a function on no lane of the bridge is dropped.
+        pme.send(make_bdf(0, 9, 0)).unwrap();
+        wait_for(|| adapter.stats().unwrap().errors > 0);
+        assert!(events.try_recv().is_err());
+
+        // The function resets itself on the way to D0 and is not ready for 10ms.
+        adapter.try_config_write(0x11, 0, &[0x0]).unwrap();
+        assert_eq!(adapter.try_config_read(0), Ok(0xffff_0001));
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        adapter.try_bar_mmio_read(0x1_0000_0000, &mut data).unwrap();
+
+        adapter.stop();
+        adapter.join();
+    }
+}
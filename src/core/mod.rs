@@ -0,0 +1,570 @@
+/*!
+Pure representation of PCIe transaction layer packets.
+
+Everything inside this module only depends on `core` and `alloc`, thus it could be reused by
+firmware or embedded test benches which speak the same packet definitions as the adapter. The
+module is always available, while the adapter and device models require the `std` feature.
+*/
+
+mod parser;
+mod serializer;
+
+pub use parser::{parse_tlp, CodecError};
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// Byte 0 bits 7:5
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Fmt {
+    Dw3NoData = 0b00 << 5,
+    Dw4NoData = 0b01 << 5,
+    Dw3 = 0b10 << 5,
+    Dw4 = 0b11 << 5,
+    Prefix = 0b100 << 5,
+}
+
+impl TryFrom<u8> for Fmt {
+    type Error = ();
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0b00 => Ok(Fmt::Dw3NoData),
+            0b01 => Ok(Fmt::Dw4NoData),
+            0b10 => Ok(Fmt::Dw3),
+            0b11 => Ok(Fmt::Dw4),
+            0b100 => Ok(Fmt::Prefix),
+            _ => Err(()),
+        }
+    }
+}
+
+// After a glance of others' implementations of PCIe TLP simulation, I found that
+// the FMT & TYPE could uniquely identify a type of packet. That reminds me to
+// redesign the representation of packet thoroughly.
+
+/// Packet specific data of config space related PCIe transactions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfigExtra {
+    pub requester: u16,
+    pub completer: u16,
+    pub tag: u8,
+    pub reg: u16,
+}
+
+/// Packet specific data of 32bit memory PCIe transactions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryExtra {
+    pub requester: u16,
+    pub tag: u8,
+    pub addr: u32,
+}
+
+/// Packet psecific data of 64bit memory PCIe transactions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Memory64Extra {
+    pub requester: u16,
+    pub tag: u8,
+    pub addr: u64,
+}
+/// Completion status: Successful Completion.
+pub const CPL_SC: u8 = 0b000;
+/// Completion status: Unsupported Request.
+pub const CPL_UR: u8 = 0b001;
+/// Completion status: Configuration Request Retry Status.
+pub const CPL_CRS: u8 = 0b010;
+/// Completion status: Completer Abort.
+pub const CPL_CA: u8 = 0b100;
+
+/// Message code of Page Request.
+pub const MSG_PAGE_REQUEST: u8 = 0b0000_0100;
+/// Message code of PRG Response.
+pub const MSG_PRG_RESPONSE: u8 = 0b0000_0101;
+/// Message code of Assert_INTA, Assert_INTB to Assert_INTD follow it.
+pub const MSG_ASSERT_INTA: u8 = 0b0010_0000;
+/// Message code of Deassert_INTA, Deassert_INTB to Deassert_INTD follow it.
+pub const MSG_DEASSERT_INTA: u8 = 0b0010_0100;
+
+/// PRG response code: all pages of the group are available.
+pub const PRG_SUCCESS: u8 = 0b0000;
+/// PRG response code: some page of the group could not be made available.
+pub const PRG_INVALID_REQUEST: u8 = 0b0001;
+/// PRG response code: the page request interface of the device should be disabled.
+pub const PRG_RESPONSE_FAILURE: u8 = 0b1111;
+
+/// Packet specific data of Page Request messages, which are routed to the root complex.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageRequestExtra {
+    pub requester: u16,
+    /// Page address, the lower 12 bits are 0.
+    pub addr: u64,
+    /// Page request group index, 9 bits.
+    pub prg_index: u16,
+    /// The last request of the group.
+    pub last: bool,
+    pub write: bool,
+    pub read: bool,
+}
+
+/// Packet specific data of PRG Response messages, which are routed by ID.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrgResponseExtra {
+    pub requester: u16,
+    pub destination: u16,
+    /// Page request group index, 9 bits.
+    pub prg_index: u16,
+    /// Response code, 4 bits.
+    pub response: u8,
+}
+
+/// Routing of a message, the r\[2:0\] bits of its TYPE field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageRouting {
+    /// Routed to the root complex.
+    RootComplex = 0b000,
+    /// Routed by the address of the message.
+    Address = 0b001,
+    /// Routed by the BDF in the upper 16 bits of the address field.
+    Id = 0b010,
+    /// Broadcast from the root complex to every function below it.
+    Broadcast = 0b011,
+    /// Terminated at the receiver.
+    Local = 0b100,
+    /// Gathered and routed to the root complex.
+    Gathered = 0b101,
+}
+
+impl MessageRouting {
+    /// The routing of the r\[2:0\] bits, `None` for the reserved ones.
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        use MessageRouting::*;
+
+        match bits & 0b111 {
+            0b000 => Some(RootComplex),
+            0b001 => Some(Address),
+            0b010 => Some(Id),
+            0b011 => Some(Broadcast),
+            0b100 => Some(Local),
+            0b101 => Some(Gathered),
+            _ => None,
+        }
+    }
+}
+
+/// Packet specific data of messages which are not terminated at the receiver, whose routing is
+/// kept along with the address field, DW2 and DW3 of the header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MessageExtra {
+    pub requester: u16,
+    pub code: u8,
+    pub routing: MessageRouting,
+    /// The address of an address routed message, the destination BDF in the upper 16 bits for
+    /// ID routing, message specific otherwise.
+    pub addr: u64,
+}
+
+impl MessageExtra {
+    /// A message routed by ID to `destination`.
+    pub fn to_id(requester: u16, code: u8, destination: u16) -> Self {
+        MessageExtra {
+            requester,
+            code,
+            routing: MessageRouting::Id,
+            addr: (destination as u64) << 48,
+        }
+    }
+
+    /// A message routed by address to `addr`.
+    pub fn to_address(requester: u16, code: u8, addr: u64) -> Self {
+        MessageExtra {
+            requester,
+            code,
+            routing: MessageRouting::Address,
+            addr,
+        }
+    }
+
+    /// A message of `routing` without address or destination, e.g. a broadcast.
+    pub fn routed(requester: u16, code: u8, routing: MessageRouting) -> Self {
+        MessageExtra {
+            requester,
+            code,
+            routing,
+            addr: 0,
+        }
+    }
+
+    /// The BDF an ID routed message goes to.
+    pub fn destination(&self) -> u16 {
+        (self.addr >> 48) as u16
+    }
+}
+
+/// Packet specific data of completion PCIe transactions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompletionExtra {
+    pub requester: u16,
+    pub completer: u16,
+    pub tag: u8,
+    pub status: u8,
+    pub bcm: bool,
+    pub byte_count: u16,
+    pub lower_address: u8,
+}
+
+/// The type of PCIe transaction, tightly coupled with TYPE\[4:0\] and FMT\[2:0\]
+/// fields in the header.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PacketType {
+    MemoryRead(MemoryExtra),
+    MemoryRead64(Memory64Extra),
+    MemoryReadLock,
+    MemoryReadLock64,
+    MemoryWrite(MemoryExtra),
+    MemoryWrite64(Memory64Extra),
+    IoRead(MemoryExtra),
+    IoWrite(MemoryExtra),
+    Config0Read(ConfigExtra),
+    Config0Write(ConfigExtra),
+    Config1Read(ConfigExtra),
+    Config1Write(ConfigExtra),
+    /// Message without payload, carries the message code.
+    Message(u8),
+    /// Message with payload, carries the message code.
+    MessageData(u8),
+    /// Message without payload routed other than to the receiver.
+    RoutedMessage(MessageExtra),
+    /// Message with payload routed other than to the receiver.
+    RoutedMessageData(MessageExtra),
+    PageRequest(PageRequestExtra),
+    PrgResponse(PrgResponseExtra),
+    Completion(CompletionExtra),
+    CompletionData(CompletionExtra),
+    CompletionLocked(CompletionExtra),
+    CompletionLockedData(CompletionExtra),
+    FetchAddAtomic,
+    SwapAtomic,
+    CasAtomic,
+    LocalPrefix(u8),
+    EndToEndPrefix(u8),
+    Unknown,
+}
+
+/// Traffica class of PCIe packet. Byte 1 bits 6:4 of the header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrafficClass {
+    TC0 = 0b000,
+    TC1,
+    TC2,
+    TC3,
+    TC4,
+    TC5,
+    TC6,
+    TC7,
+}
+
+impl From<u8> for TrafficClass {
+    fn from(value: u8) -> Self {
+        use TrafficClass::*;
+
+        match value & 0b111 {
+            0 => TC0,
+            1 => TC1,
+            2 => TC2,
+            3 => TC3,
+            4 => TC4,
+            5 => TC5,
+            6 => TC6,
+            _ => TC7,
+        }
+    }
+}
+
+/// The address type field inside the PCIe transacton headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressType {
+    Default = 0b00,
+    TranslationRequest,
+    Translated,
+    Reserved,
+}
+
+impl From<u8> for AddressType {
+    fn from(value: u8) -> Self {
+        match value & 0b11 {
+            0 => AddressType::Default,
+            1 => AddressType::TranslationRequest,
+            2 => AddressType::Translated,
+            _ => AddressType::Reserved,
+        }
+    }
+}
+
+/// Literally a in memory representation of PCIe transaction headers.
+#[derive(Debug, Clone, Copy)]
+pub struct TlpHeader {
+    pub _type: PacketType,
+    pub trafic_class: TrafficClass,
+    pub address_type: AddressType,
+
+    /// Attr\[1\]
+    pub relax_ordering: bool,
+    /// Attr\[0\]
+    pub no_snoop: bool,
+    /// Attr\[2\]
+    pub id_ordering: bool,
+
+    pub poisoned_data: bool,
+    pub tlp_digest: bool,
+    pub processing_hint: bool,
+
+    // The upper 4 bits is the last DW, and the lower 4 bits are the first DW.
+    pub byte_enable: u8,
+    pub length: u16,
+}
+
+/// Requester ID in the upper 16 bits and tag in the lower 8 bits, matching a completion to the
+/// request it answers.
+pub type TransactionId = u32;
+
+/// Basic abstraction of a TLP packet without CRC checksum attached.
+#[derive(Debug, Clone)]
+pub struct Tlp {
+    pub header: TlpHeader,
+    pub data: Option<Vec<u32>>,
+}
+
+impl TlpHeader {
+    /// Transaction of a configuration or I/O request or of a completion, `None` for other packets.
+    pub fn transaction_id(&self) -> Option<TransactionId> {
+        use PacketType::*;
+
+        match self._type {
+            Config0Read(extra) | Config0Write(extra) => {
+                Some(extra.tag as u32 | ((extra.requester as u32) << 16))
+            }
+            IoRead(extra) | IoWrite(extra) => {
+                Some(extra.tag as u32 | ((extra.requester as u32) << 16))
+            }
+            CompletionData(extra) | Completion(extra) => {
+                Some(extra.tag as u32 | ((extra.requester as u32) << 16))
+            }
+            _ => None,
+        }
+    }
+
+    /// Tag of a request or completion, `None` for packets without a tag.
+    pub fn tag(&self) -> Option<u8> {
+        use PacketType::*;
+
+        match self._type {
+            MemoryRead(e) | MemoryWrite(e) | IoRead(e) | IoWrite(e) => Some(e.tag),
+            MemoryRead64(e) | MemoryWrite64(e) => Some(e.tag),
+            Config0Read(e) | Config0Write(e) | Config1Read(e) | Config1Write(e) => Some(e.tag),
+            Completion(e) | CompletionData(e) | CompletionLocked(e) | CompletionLockedData(e) => {
+                Some(e.tag)
+            }
+            _ => None,
+        }
+    }
+
+    /// Change the tag of a request or completion, packets without a tag are left alone.
+    pub fn set_tag(&mut self, tag: u8) {
+        use PacketType::*;
+
+        match &mut self._type {
+            MemoryRead(e) | MemoryWrite(e) | IoRead(e) | IoWrite(e) => e.tag = tag,
+            MemoryRead64(e) | MemoryWrite64(e) => e.tag = tag,
+            Config0Read(e) | Config0Write(e) | Config1Read(e) | Config1Write(e) => e.tag = tag,
+            Completion(e) | CompletionData(e) | CompletionLocked(e) | CompletionLockedData(e) => {
+                e.tag = tag
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Default for Tlp {
+    fn default() -> Self {
+        Tlp {
+            header: TlpHeader::default(),
+            data: None,
+        }
+    }
+}
+
+impl Default for TlpHeader {
+    fn default() -> Self {
+        TlpHeader {
+            _type: PacketType::Unknown,
+            trafic_class: TrafficClass::TC0,
+            address_type: AddressType::Default,
+            relax_ordering: false,
+            no_snoop: false,
+            id_ordering: false,
+            poisoned_data: false,
+            processing_hint: false,
+            tlp_digest: false,
+            byte_enable: 0,
+            length: 0,
+        }
+    }
+}
+/// Convenient builder of [`Tlp`].
+#[derive(Debug)]
+pub struct TlpBuilder(Tlp);
+
+impl TlpBuilder {
+    pub fn with_type(ptype: PacketType) -> Self {
+        TlpBuilder(Tlp::default()).r#type(ptype)
+    }
+
+    pub fn memory_read(extra: MemoryExtra) -> Self {
+        Self::with_type(PacketType::MemoryRead(extra))
+    }
+
+    pub fn memory_read64(extra: Memory64Extra) -> Self {
+        Self::with_type(PacketType::MemoryRead64(extra))
+    }
+
+    pub fn memory_write(extra: MemoryExtra) -> Self {
+        Self::with_type(PacketType::MemoryWrite(extra))
+    }
+
+    pub fn memory_write64(extra: Memory64Extra) -> Self {
+        Self::with_type(PacketType::MemoryWrite64(extra))
+    }
+
+    pub fn io_read(extra: MemoryExtra) -> Self {
+        Self::with_type(PacketType::IoRead(extra)).length(1)
+    }
+
+    pub fn io_write(extra: MemoryExtra) -> Self {
+        Self::with_type(PacketType::IoWrite(extra)).length(1)
+    }
+
+    pub fn config0_read(extra: ConfigExtra) -> Self {
+        Self::with_type(PacketType::Config0Read(extra))
+    }
+
+    pub fn config0_write(extra: ConfigExtra) -> Self {
+        Self::with_type(PacketType::Config0Write(extra)).length(1)
+    }
+
+    pub fn page_request(extra: PageRequestExtra) -> Self {
+        Self::with_type(PacketType::PageRequest(extra))
+    }
+
+    pub fn prg_response(extra: PrgResponseExtra) -> Self {
+        Self::with_type(PacketType::PrgResponse(extra))
+    }
+
+    pub fn message(extra: MessageExtra) -> Self {
+        Self::with_type(PacketType::RoutedMessage(extra))
+    }
+
+    pub fn message_data(extra: MessageExtra) -> Self {
+        Self::with_type(PacketType::RoutedMessageData(extra))
+    }
+
+    pub fn completion(extra: CompletionExtra) -> Self {
+        Self::with_type(PacketType::Completion(extra))
+    }
+
+    pub fn completion_data(extra: CompletionExtra) -> Self {
+        Self::with_type(PacketType::CompletionData(extra))
+    }
+
+    fn r#type(mut self, _type: PacketType) -> Self {
+        self.0.header._type = _type;
+        self
+    }
+
+    pub fn length(mut self, len: u16) -> Self {
+        self.0.header.length = len;
+        self
+    }
+
+    pub fn data(mut self, data: Vec<u32>) -> Self {
+        let len = data.len();
+        self.0.data = Some(data);
+        self.length(len as u16)
+    }
+
+    pub fn address_type(mut self, at: AddressType) -> Self {
+        self.0.header.address_type = at;
+        self
+    }
+
+    pub fn byte_enable(mut self, be: u8) -> Self {
+        self.0.header.byte_enable = be;
+        self
+    }
+
+    pub fn build(self) -> Tlp {
+        self.0
+    }
+}
+
+impl Tlp {
+    /// Check whether a TLP is valid according to the PCIe specification, only configuration and
+    /// I/O requests are checked so far and any other packet is reported as invalid.
+    pub fn is_valid(&self) -> bool {
+        use PacketType::*;
+
+        let header = self.header;
+
+        // DW BE rule check
+        if (header.length == 1 && header.byte_enable & 0xf == 0)
+            | (header.length == 1 && header.byte_enable & 0xf0 != 0)
+            | (header.length > 1 && header.byte_enable & 0xf0 == 0)
+        {
+            return false;
+        }
+
+        match header._type {
+            Config0Read(_) | Config0Write(_) | Config1Read(_) | Config1Write(_) | IoRead(_)
+            | IoWrite(_) => {
+                // PCIe 3.0 specification 2.2.5 and 2.2.7
+                if header.trafic_class != TrafficClass::TC0
+                    || header.no_snoop
+                    || header.relax_ordering
+                    || header.length != 0b00001
+                {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn unhandled_packets() {
+        let tlp = TlpBuilder::memory_write(MemoryExtra {
+            requester: 0x0010,
+            tag: 1,
+            addr: 0x1000,
+        })
+        .data(vec![0])
+        .build();
+        assert_eq!(tlp.header.transaction_id(), None);
+        assert!(!tlp.is_valid());
+
+        let tlp = TlpBuilder::config0_read(ConfigExtra {
+            requester: 0x0010,
+            completer: 0x0100,
+            tag: 1,
+            reg: 0,
+        })
+        .build();
+        assert_eq!(tlp.header.transaction_id(), Some(0x0010_0001));
+    }
+}
@@ -0,0 +1,387 @@
+use super::*;
+use nom::error::{ErrorKind, ParseError};
+use nom::multi::count;
+use nom::number::complete::{be_u16, be_u32, be_u64, be_u8};
+use nom::Err::Failure;
+use nom::IResult;
+
+/// Errors reported by the TLP wire codec.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodecError {
+    /// The buffer ends before the whole packet.
+    Incomplete,
+    /// Reserved FMT and TYPE combination, carries byte 0 of the header.
+    InvalidHeader(u8),
+    /// The packet type can not be represented on the wire yet.
+    Unsupported,
+}
+
+impl<I> ParseError<I> for CodecError {
+    fn from_error_kind(_: I, _: ErrorKind) -> Self {
+        CodecError::Incomplete
+    }
+
+    fn append(_: I, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+pub(crate) const MEMORY: u8 = 0b00000;
+pub(crate) const MEMORY_LOCK: u8 = 0b00001;
+pub(crate) const IO: u8 = 0b00010;
+pub(crate) const CONFIG0: u8 = 0b00100;
+pub(crate) const CONFIG1: u8 = 0b00101;
+pub(crate) const COMPLETION: u8 = 0b01010;
+pub(crate) const COMPLETION_LOCKED: u8 = 0b01011;
+pub(crate) const FETCH_ADD: u8 = 0b01100;
+pub(crate) const SWAP: u8 = 0b01101;
+pub(crate) const CAS: u8 = 0b01110;
+/// Messages use TYPE 10rrr, the lower 3 bits are the routing subfield.
+pub(crate) const MESSAGE: u8 = 0b10000;
+
+/// The fixed first DW shared by all kinds of headers.
+struct FirstDw {
+    fmt: Fmt,
+    r#type: u8,
+    header: TlpHeader,
+}
+
+fn first_dw(i: &[u8]) -> IResult<&[u8], FirstDw, CodecError> {
+    let (i, b0) = be_u8(i)?;
+    let (i, b1) = be_u8(i)?;
+    let (i, b2) = be_u8(i)?;
+    let (i, b3) = be_u8(i)?;
+
+    let fmt = Fmt::try_from(b0 >> 5).map_err(|_| Failure(CodecError::InvalidHeader(b0)))?;
+
+    let header = TlpHeader {
+        trafic_class: TrafficClass::from(b1 >> 4),
+        id_ordering: b1 & 0b100 != 0,
+        processing_hint: b1 & 0b1 != 0,
+        tlp_digest: b2 & 0b1000_0000 != 0,
+        poisoned_data: b2 & 0b100_0000 != 0,
+        relax_ordering: b2 & 0b10_0000 != 0,
+        no_snoop: b2 & 0b1_0000 != 0,
+        address_type: AddressType::from(b2 >> 2),
+        length: (((b2 & 0b11) as u16) << 8) | b3 as u16,
+        ..Default::default()
+    };
+
+    Ok((
+        i,
+        FirstDw {
+            fmt,
+            r#type: b0 & 0x1f,
+            header,
+        },
+    ))
+}
+
+/// Parse the address part of memory request headers, the lower 2 bits are PH and dropped here.
+fn address(fmt: Fmt) -> impl Fn(&[u8]) -> IResult<&[u8], u64, CodecError> {
+    move |i| match fmt {
+        Fmt::Dw4 | Fmt::Dw4NoData => {
+            let (i, hi) = be_u32(i)?;
+            let (i, lo) = be_u32(i)?;
+            Ok((i, ((hi as u64) << 32) | (lo & !0b11) as u64))
+        }
+        _ => {
+            let (i, lo) = be_u32(i)?;
+            Ok((i, (lo & !0b11) as u64))
+        }
+    }
+}
+
+/// Parse a single TLP from the beginning of the buffer, which should be produced by
+/// [`Tlp::to_bytes`]. The ECRC digest is never attached in our simulation.
+pub fn parse_tlp(i: &[u8]) -> IResult<&[u8], Tlp, CodecError> {
+    use PacketType::*;
+
+    let (i, first) = first_dw(i)?;
+    let FirstDw {
+        fmt,
+        r#type,
+        mut header,
+    } = first;
+    let invalid = || Failure(CodecError::InvalidHeader(fmt as u8 | r#type));
+    let with_data = matches!(fmt, Fmt::Dw3 | Fmt::Dw4);
+
+    // TLP prefixes are not modeled yet.
+    if fmt == Fmt::Prefix {
+        return Err(Failure(CodecError::Unsupported));
+    }
+
+    let (i, _type) = match r#type {
+        MEMORY | IO | CONFIG0 | CONFIG1 => {
+            let (i, requester) = be_u16(i)?;
+            let (i, tag) = be_u8(i)?;
+            let (i, byte_enable) = be_u8(i)?;
+            header.byte_enable = byte_enable;
+
+            if r#type == IO {
+                let (i, addr) = address(fmt)(i)?;
+                let extra = MemoryExtra {
+                    requester,
+                    tag,
+                    addr: addr as u32,
+                };
+
+                let t = match fmt {
+                    Fmt::Dw3NoData => IoRead(extra),
+                    Fmt::Dw3 => IoWrite(extra),
+                    _ => return Err(invalid()),
+                };
+                (i, t)
+            } else if r#type == MEMORY {
+                let (i, addr) = address(fmt)(i)?;
+                let extra = MemoryExtra {
+                    requester,
+                    tag,
+                    addr: addr as u32,
+                };
+                let extra64 = Memory64Extra {
+                    requester,
+                    tag,
+                    addr,
+                };
+
+                let t = match fmt {
+                    Fmt::Dw3NoData => MemoryRead(extra),
+                    Fmt::Dw4NoData => MemoryRead64(extra64),
+                    Fmt::Dw3 => MemoryWrite(extra),
+                    _ => MemoryWrite64(extra64),
+                };
+                (i, t)
+            } else {
+                let (i, completer) = be_u16(i)?;
+                let (i, ext_reg) = be_u8(i)?;
+                let (i, reg) = be_u8(i)?;
+                let extra = ConfigExtra {
+                    requester,
+                    completer,
+                    tag,
+                    reg: (((ext_reg & 0xf) as u16) << 6) | (reg >> 2) as u16,
+                };
+
+                let t = match (r#type, fmt) {
+                    (CONFIG0, Fmt::Dw3NoData) => Config0Read(extra),
+                    (CONFIG0, Fmt::Dw3) => Config0Write(extra),
+                    (CONFIG1, Fmt::Dw3NoData) => Config1Read(extra),
+                    (CONFIG1, Fmt::Dw3) => Config1Write(extra),
+                    _ => return Err(invalid()),
+                };
+                (i, t)
+            }
+        }
+        COMPLETION | COMPLETION_LOCKED => {
+            let (i, completer) = be_u16(i)?;
+            let (i, b6) = be_u8(i)?;
+            let (i, b7) = be_u8(i)?;
+            let (i, requester) = be_u16(i)?;
+            let (i, tag) = be_u8(i)?;
+            let (i, lower_address) = be_u8(i)?;
+            let extra = CompletionExtra {
+                requester,
+                completer,
+                tag,
+                status: b6 >> 5,
+                bcm: b6 & 0b1_0000 != 0,
+                byte_count: (((b6 & 0xf) as u16) << 8) | b7 as u16,
+                lower_address: lower_address & 0x7f,
+            };
+
+            let t = match (r#type, fmt) {
+                (COMPLETION, Fmt::Dw3NoData) => Completion(extra),
+                (COMPLETION, Fmt::Dw3) => CompletionData(extra),
+                (COMPLETION_LOCKED, Fmt::Dw3NoData) => CompletionLocked(extra),
+                (COMPLETION_LOCKED, Fmt::Dw3) => CompletionLockedData(extra),
+                _ => return Err(invalid()),
+            };
+            (i, t)
+        }
+        t if t & 0b11000 == MESSAGE => {
+            // The tag is not tracked, neither are the routing subfield and DW2/DW3 of the
+            // messages terminated at the receiver. INTx messages keep their requester, the
+            // receiver tracks the virtual wire of each function.
+            let routing = MessageRouting::from_bits(t).ok_or_else(invalid)?;
+            let (i, requester) = be_u16(i)?;
+            let (i, _tag) = be_u8(i)?;
+            let (i, code) = be_u8(i)?;
+            let (i, upper) = be_u64(i)?;
+            let intx = (MSG_ASSERT_INTA..MSG_DEASSERT_INTA + 4).contains(&code);
+
+            let t = match (fmt, code) {
+                (Fmt::Dw4NoData, MSG_PAGE_REQUEST) => PageRequest(PageRequestExtra {
+                    requester,
+                    addr: upper & !0xfff,
+                    prg_index: ((upper >> 3) & 0x1ff) as u16,
+                    last: upper & 0b100 != 0,
+                    write: upper & 0b10 != 0,
+                    read: upper & 0b1 != 0,
+                }),
+                (Fmt::Dw4NoData, MSG_PRG_RESPONSE) => PrgResponse(PrgResponseExtra {
+                    requester,
+                    destination: (upper >> 48) as u16,
+                    prg_index: ((upper >> 32) & 0x1ff) as u16,
+                    response: ((upper >> 44) & 0xf) as u8,
+                }),
+                (Fmt::Dw4NoData, _) if routing == MessageRouting::Local && !intx => Message(code),
+                (Fmt::Dw4, _) if routing == MessageRouting::Local => MessageData(code),
+                (Fmt::Dw4NoData, _) => RoutedMessage(MessageExtra {
+                    requester,
+                    code,
+                    routing,
+                    addr: upper,
+                }),
+                (Fmt::Dw4, _) => RoutedMessageData(MessageExtra {
+                    requester,
+                    code,
+                    routing,
+                    addr: upper,
+                }),
+                _ => return Err(invalid()),
+            };
+            (i, t)
+        }
+        MEMORY_LOCK | FETCH_ADD | SWAP | CAS => {
+            return Err(Failure(CodecError::Unsupported));
+        }
+        _ => return Err(invalid()),
+    };
+
+    // Length of 0 means 1024 DW for packets which carry or request a payload.
+    if header.length == 0 && (with_data || matches!(_type, MemoryRead(_) | MemoryRead64(_))) {
+        header.length = 1024;
+    }
+    header._type = _type;
+
+    let (i, data) = if with_data {
+        let (i, data) = count(be_u32, header.length as usize)(i)?;
+        (i, Some(data))
+    } else {
+        (i, None)
+    };
+
+    Ok((i, Tlp { header, data }))
+}
+
+impl Tlp {
+    /// Decode a TLP from the buffer, trailing bytes are ignored.
+    pub fn from_bytes(buf: &[u8]) -> Result<Tlp, CodecError> {
+        match parse_tlp(buf) {
+            Ok((_, tlp)) => Ok(tlp),
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(e),
+            Err(nom::Err::Incomplete(_)) => Err(CodecError::Incomplete),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn head() {
+        let data = &[0b00000100u8, 0b0, 0b0, 0b1];
+        assert_eq!(Tlp::from_bytes(data).unwrap_err(), CodecError::Incomplete);
+
+        let data = &[0b10100100u8, 0b0, 0b0, 0b0];
+        assert_eq!(
+            Tlp::from_bytes(data).unwrap_err(),
+            CodecError::InvalidHeader(0b10100100)
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let packets = vec![
+            TlpBuilder::config0_read(ConfigExtra {
+                requester: 0x0010,
+                completer: 0x0018,
+                tag: 3,
+                reg: 0x3ff,
+            })
+            .length(1)
+            .byte_enable(0xf)
+            .build(),
+            TlpBuilder::config0_write(ConfigExtra {
+                requester: 0x0010,
+                completer: 0x0018,
+                tag: 4,
+                reg: 4,
+            })
+            .byte_enable(0x3)
+            .data(vec![0xdead_beef])
+            .build(),
+            TlpBuilder::memory_read64(Memory64Extra {
+                requester: 0x0010,
+                tag: 5,
+                addr: 0x1_7000_0004,
+            })
+            .byte_enable(0xfe)
+            .length(1024)
+            .build(),
+            TlpBuilder::io_write(MemoryExtra {
+                requester: 0x0010,
+                tag: 6,
+                addr: 0xc040,
+            })
+            .byte_enable(0xc)
+            .data(vec![0x0000_5a5a])
+            .build(),
+            TlpBuilder::page_request(PageRequestExtra {
+                requester: 0x0018,
+                addr: 0x7f12_3456_7000,
+                prg_index: 0x1a5,
+                last: true,
+                write: false,
+                read: true,
+            })
+            .build(),
+            TlpBuilder::prg_response(PrgResponseExtra {
+                requester: 0x0010,
+                destination: 0x0018,
+                prg_index: 0x1a5,
+                response: PRG_INVALID_REQUEST,
+            })
+            .build(),
+            TlpBuilder::completion_data(CompletionExtra {
+                requester: 0x0010,
+                completer: 0x0018,
+                tag: 5,
+                status: 0,
+                bcm: false,
+                byte_count: 0x800,
+                lower_address: 0x44,
+            })
+            .data(vec![0x1234_5678, 0x9abc_def0])
+            .build(),
+            TlpBuilder::message(MessageExtra::to_id(0x0018, 0x7e, 0x0020)).build(),
+            TlpBuilder::message_data(MessageExtra::to_address(0x0018, 0x7e, 0x1_2345_6780))
+                .data(vec![0xcafe_f00d])
+                .build(),
+            TlpBuilder::message(MessageExtra::routed(
+                0x0010,
+                0x7e,
+                MessageRouting::Broadcast,
+            ))
+            .build(),
+            TlpBuilder::message(MessageExtra::routed(
+                0x0018,
+                MSG_ASSERT_INTA + 1,
+                MessageRouting::Local,
+            ))
+            .build(),
+        ];
+
+        for tlp in packets {
+            let buf = tlp.to_bytes().unwrap();
+            let (rest, parsed) = parse_tlp(&buf).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(parsed.header._type, tlp.header._type);
+            assert_eq!(parsed.header.length, tlp.header.length);
+            assert_eq!(parsed.data, tlp.data);
+        }
+    }
+}
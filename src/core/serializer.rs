@@ -0,0 +1,143 @@
+use super::parser::*;
+use super::*;
+
+impl TlpHeader {
+    /// FMT and TYPE fields of byte 0 for the packet type of this header.
+    fn fmt_type(&self) -> Result<(Fmt, u8), CodecError> {
+        use PacketType::*;
+
+        let fmt_type = match self._type {
+            MemoryRead(_) => (Fmt::Dw3NoData, MEMORY),
+            MemoryRead64(_) => (Fmt::Dw4NoData, MEMORY),
+            MemoryWrite(_) => (Fmt::Dw3, MEMORY),
+            MemoryWrite64(_) => (Fmt::Dw4, MEMORY),
+            IoRead(_) => (Fmt::Dw3NoData, IO),
+            IoWrite(_) => (Fmt::Dw3, IO),
+            Config0Read(_) => (Fmt::Dw3NoData, CONFIG0),
+            Config0Write(_) => (Fmt::Dw3, CONFIG0),
+            Config1Read(_) => (Fmt::Dw3NoData, CONFIG1),
+            Config1Write(_) => (Fmt::Dw3, CONFIG1),
+            // Local - terminate at receiver routing.
+            Message(_) => (Fmt::Dw4NoData, MESSAGE | 0b100),
+            MessageData(_) => (Fmt::Dw4, MESSAGE | 0b100),
+            RoutedMessage(extra) => (Fmt::Dw4NoData, MESSAGE | extra.routing as u8),
+            RoutedMessageData(extra) => (Fmt::Dw4, MESSAGE | extra.routing as u8),
+            // Routed to root complex.
+            PageRequest(_) => (Fmt::Dw4NoData, MESSAGE),
+            // Routed by ID.
+            PrgResponse(_) => (Fmt::Dw4NoData, MESSAGE | 0b010),
+            Completion(_) => (Fmt::Dw3NoData, COMPLETION),
+            CompletionData(_) => (Fmt::Dw3, COMPLETION),
+            CompletionLocked(_) => (Fmt::Dw3NoData, COMPLETION_LOCKED),
+            CompletionLockedData(_) => (Fmt::Dw3, COMPLETION_LOCKED),
+            _ => return Err(CodecError::Unsupported),
+        };
+
+        Ok(fmt_type)
+    }
+
+    /// Serialize the header into its wire format, which is 3 or 4 DW in big endian.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CodecError> {
+        use PacketType::*;
+
+        let (fmt, r#type) = self.fmt_type()?;
+        let len = match fmt {
+            Fmt::Dw3 | Fmt::Dw3NoData => 12,
+            _ => 16,
+        };
+
+        let mut header = alloc::vec![0; len];
+
+        // let's construct the fixed part of header
+        header[0] = fmt as u8 | r#type;
+        header[1] = (self.processing_hint as u8)
+            | ((self.id_ordering as u8) << 2)
+            | ((self.trafic_class as u8) << 4);
+        header[2] = ((self.length >> 8) & 0b11) as u8
+            | ((self.address_type as u8) << 2)
+            | ((self.no_snoop as u8) << 4)
+            | ((self.relax_ordering as u8) << 5)
+            | ((self.poisoned_data as u8) << 6)
+            | ((self.tlp_digest as u8) << 7);
+        header[3] = self.length as u8;
+
+        match self._type {
+            MemoryRead(extra) | MemoryWrite(extra) | IoRead(extra) | IoWrite(extra) => {
+                header[4..6].copy_from_slice(&extra.requester.to_be_bytes());
+                header[6] = extra.tag;
+                header[7] = self.byte_enable;
+                header[8..12].copy_from_slice(&(extra.addr & !0b11).to_be_bytes());
+            }
+            MemoryRead64(extra) | MemoryWrite64(extra) => {
+                header[4..6].copy_from_slice(&extra.requester.to_be_bytes());
+                header[6] = extra.tag;
+                header[7] = self.byte_enable;
+                header[8..16].copy_from_slice(&(extra.addr & !0b11).to_be_bytes());
+            }
+            Config0Read(extra) | Config0Write(extra) | Config1Read(extra) | Config1Write(extra) => {
+                header[4..6].copy_from_slice(&extra.requester.to_be_bytes());
+                header[6] = extra.tag;
+                header[7] = self.byte_enable;
+                header[8..10].copy_from_slice(&extra.completer.to_be_bytes());
+                header[10] = ((extra.reg >> 6) & 0xf) as u8;
+                header[11] = ((extra.reg & 0x3f) as u8) << 2;
+            }
+            Message(code) | MessageData(code) => {
+                header[7] = code;
+            }
+            RoutedMessage(extra) | RoutedMessageData(extra) => {
+                header[4..6].copy_from_slice(&extra.requester.to_be_bytes());
+                header[7] = extra.code;
+                header[8..16].copy_from_slice(&extra.addr.to_be_bytes());
+            }
+            PageRequest(extra) => {
+                header[4..6].copy_from_slice(&extra.requester.to_be_bytes());
+                header[7] = MSG_PAGE_REQUEST;
+                let upper = (extra.addr & !0xfff)
+                    | ((extra.prg_index as u64 & 0x1ff) << 3)
+                    | ((extra.last as u64) << 2)
+                    | ((extra.write as u64) << 1)
+                    | extra.read as u64;
+                header[8..16].copy_from_slice(&upper.to_be_bytes());
+            }
+            PrgResponse(extra) => {
+                header[4..6].copy_from_slice(&extra.requester.to_be_bytes());
+                header[7] = MSG_PRG_RESPONSE;
+                header[8..10].copy_from_slice(&extra.destination.to_be_bytes());
+                let index = ((extra.response as u16 & 0xf) << 12) | (extra.prg_index & 0x1ff);
+                header[10..12].copy_from_slice(&index.to_be_bytes());
+            }
+            Completion(extra)
+            | CompletionData(extra)
+            | CompletionLocked(extra)
+            | CompletionLockedData(extra) => {
+                header[4..6].copy_from_slice(&extra.completer.to_be_bytes());
+                header[6] = (extra.status << 5)
+                    | ((extra.bcm as u8) << 4)
+                    | ((extra.byte_count >> 8) & 0xf) as u8;
+                header[7] = extra.byte_count as u8;
+                header[8..10].copy_from_slice(&extra.requester.to_be_bytes());
+                header[10] = extra.tag;
+                header[11] = extra.lower_address & 0x7f;
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(header)
+    }
+}
+
+impl Tlp {
+    /// Serialize the whole packet, the payload follows the header as big endian DWs.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CodecError> {
+        let mut buf = self.header.to_bytes()?;
+
+        if let Some(data) = &self.data {
+            for dw in data {
+                buf.extend_from_slice(&dw.to_be_bytes());
+            }
+        }
+
+        Ok(buf)
+    }
+}
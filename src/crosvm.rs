@@ -0,0 +1,350 @@
+//! Shim mounting a [`PciAdapter`] in crosvm.
+//!
+//! crosvm has PCI device traits of its own. [`CrosvmPciAdapter`] implements them on top of the
+//! adapter, so the device models run behind the same bridge as in cloud-hypervisor, and the
+//! blanket implementation of crosvm makes it a `BusDevice` of the MMIO bus.
+//!
+//! crosvm maps the BARs of its PCI devices on the MMIO bus only, so the I/O BARs of a model are
+//! left at 0. The MSIs of the device go to the [`MsiSink`] the shim is built with, e.g. a
+//! [`KvmMsiSink`](crate::KvmMsiSink) on the VM of crosvm, while the INTx line crosvm assigns is
+//! triggered through its event when the pin is asserted.
+//!
+//! The sandbox of crosvm forks every device into a jailed process of its own, which loses the
+//! threads of the bridge, so the shim needs crosvm to run with `--disable-sandbox`.
+
+use crate::*;
+
+use crate::adapter::BAR0_REG;
+use crate::interrupt::INTERRUPT_REG;
+use base::{AsRawDescriptor, Event, RawDescriptor};
+use devices::pci as crosvm_pci;
+use resources::{Alloc, MmioType};
+use std::io;
+
+/// Prefetchable bit of a memory BAR.
+const BAR_PREFETCHABLE: u32 = 0x8;
+
+/// [`MsiSink`] of the shim, which passes the MSIs on and triggers the INTx event of crosvm.
+struct EventSink {
+    msi: Option<Box<dyn MsiSink>>,
+    intx: Option<Event>,
+}
+
+impl MsiSink for EventSink {
+    fn inject(&self, addr: u64, data: u32) -> io::Result<()> {
+        match self.msi.as_ref() {
+            Some(msi) => msi.inject(addr, data),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "MSI is not routed",
+            )),
+        }
+    }
+
+    fn inject_from(&self, source: PciAddress, addr: u64, data: u32) -> io::Result<()> {
+        match self.msi.as_ref() {
+            Some(msi) => msi.inject_from(source, addr, data),
+            None => self.inject(addr, data),
+        }
+    }
+
+    fn inject_vector(
+        &self,
+        source: PciAddress,
+        vector: u16,
+        addr: u64,
+        data: u32,
+    ) -> io::Result<()> {
+        match self.msi.as_ref() {
+            Some(msi) => msi.inject_vector(source, vector, addr, data),
+            None => self.inject(addr, data),
+        }
+    }
+
+    fn enable_msix(&self, source: PciAddress, vectors: u16) -> io::Result<()> {
+        match self.msi.as_ref() {
+            Some(msi) => msi.enable_msix(source, vectors),
+            None => Ok(()),
+        }
+    }
+
+    fn disable_msix(&self, source: PciAddress) -> io::Result<()> {
+        match self.msi.as_ref() {
+            Some(msi) => msi.disable_msix(source),
+            None => Ok(()),
+        }
+    }
+
+    fn mask_vector(&self, source: PciAddress, vector: u16, masked: bool) -> io::Result<()> {
+        match self.msi.as_ref() {
+            Some(msi) => msi.mask_vector(source, vector, masked),
+            None => Ok(()),
+        }
+    }
+
+    fn set_intx(&self, pin: u8, level: bool) -> io::Result<()> {
+        match self.intx.as_ref() {
+            Some(intx) if level => intx
+                .write(1)
+                .map_err(|e| io::Error::other(format!("{:?}", e))),
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("INTx pin {} is not routed", pin),
+            )),
+        }
+    }
+}
+
+/// A [`PciAdapter`] as a PCI device of crosvm.
+pub struct CrosvmPciAdapter {
+    adapter: PciAdapter,
+    address: Option<crosvm_pci::PciAddress>,
+    /// The interrupts handed to the adapter once its BARs are allocated, after crosvm assigned
+    /// the INTx line.
+    msi: Option<Box<dyn MsiSink>>,
+    intx: Option<Event>,
+    descriptors: Vec<RawDescriptor>,
+}
+
+impl CrosvmPciAdapter {
+    pub fn new(adapter: PciAdapter) -> Self {
+        CrosvmPciAdapter {
+            adapter,
+            address: None,
+            msi: None,
+            intx: None,
+            descriptors: vec![],
+        }
+    }
+
+    /// Inject the MSIs of the device through `sink`, without it MSIs are dropped.
+    pub fn msi_sink(mut self, sink: Box<dyn MsiSink>) -> Self {
+        self.msi = Some(sink);
+        self
+    }
+
+    pub fn adapter(&self) -> &PciAdapter {
+        &self.adapter
+    }
+
+    /// The adapter, e.g. to stop and join the bridge once crosvm is done with the device.
+    pub fn into_adapter(self) -> PciAdapter {
+        self.adapter
+    }
+}
+
+impl crosvm_pci::PciDevice for CrosvmPciAdapter {
+    fn debug_label(&self) -> String {
+        "pcie-tlp".to_string()
+    }
+
+    fn allocate_address(
+        &mut self,
+        resources: &mut resources::SystemAllocator,
+    ) -> crosvm_pci::Result<crosvm_pci::PciAddress> {
+        if self.address.is_none() {
+            if let Some(Alloc::PciBar { bus, dev, func, .. }) =
+                resources.allocate_pci(0, self.debug_label())
+            {
+                self.address = Some(crosvm_pci::PciAddress { bus, dev, func });
+            }
+        }
+        self.address.ok_or(crosvm_pci::Error::PciAllocationFailed)
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.descriptors.clone()
+    }
+
+    fn assign_irq(
+        &mut self,
+        irq_evt: &Event,
+        irq_resample_evt: &Event,
+        irq_num: u32,
+        irq_pin: crosvm_pci::PciInterruptPin,
+    ) {
+        let intx = match irq_evt.try_clone() {
+            Ok(intx) => intx,
+            Err(e) => {
+                error!("Failed to clone the event of IRQ {}: {:?}", irq_num, e);
+                return;
+            }
+        };
+
+        debug!("route INTx pin {:?} to IRQ {}", irq_pin, irq_num);
+        self.descriptors = vec![
+            intx.as_raw_descriptor(),
+            irq_resample_evt.as_raw_descriptor(),
+        ];
+        self.intx = Some(intx);
+        self.adapter
+            .config_write(INTERRUPT_REG, 0, &[irq_num as u8]);
+    }
+
+    fn allocate_io_bars(
+        &mut self,
+        resources: &mut resources::SystemAllocator,
+    ) -> crosvm_pci::Result<Vec<(u64, u64)>> {
+        let address = self.allocate_address(resources)?;
+        let label = self.debug_label();
+        let mut failure = None;
+
+        let regions = self
+            .adapter
+            .place_bars(|region| {
+                let mmio = match region.type_ {
+                    PciBarRegionType::Memory32BitRegion => MmioType::Low,
+                    PciBarRegionType::Memory64BitRegion => MmioType::High,
+                    PciBarRegionType::IoRegion => {
+                        debug!("leave I/O BAR reg{} unassigned", region.bar_reg);
+                        return Some(GuestAddress(0));
+                    }
+                };
+                let alloc = Alloc::PciBar {
+                    bus: address.bus,
+                    dev: address.dev,
+                    func: address.func,
+                    bar: (region.bar_reg - BAR0_REG) as u8,
+                };
+                resources
+                    .mmio_allocator(mmio)
+                    .allocate_with_align(region.length, alloc, label.clone(), region.length)
+                    .map(GuestAddress)
+                    .map_err(|e| failure = Some(e))
+                    .ok()
+            })
+            .map_err(|size| crosvm_pci::Error::IoAllocationFailed(size, failure.unwrap()))?;
+
+        self.adapter.set_msi_sink(Box::new(EventSink {
+            msi: self.msi.take(),
+            intx: self.intx.take(),
+        }));
+        Ok(regions
+            .iter()
+            .filter(|r| r.type_ != PciBarRegionType::IoRegion)
+            .map(|r| (r.start.raw_value(), r.length))
+            .collect())
+    }
+
+    fn get_bar_configuration(&self, bar_num: usize) -> Option<crosvm_pci::PciBarConfiguration> {
+        let region = self
+            .adapter
+            .mmio_regions
+            .iter()
+            .find(|r| r.bar_reg == BAR0_REG + bar_num)?;
+        let region_type = match region.type_ {
+            PciBarRegionType::Memory32BitRegion => crosvm_pci::PciBarRegionType::Memory32BitRegion,
+            PciBarRegionType::Memory64BitRegion => crosvm_pci::PciBarRegionType::Memory64BitRegion,
+            PciBarRegionType::IoRegion => crosvm_pci::PciBarRegionType::IoRegion,
+        };
+        let prefetchable = if region.type_ != PciBarRegionType::IoRegion
+            && self.adapter.config_read(region.bar_reg) & BAR_PREFETCHABLE != 0
+        {
+            crosvm_pci::PciBarPrefetchable::Prefetchable
+        } else {
+            crosvm_pci::PciBarPrefetchable::NotPrefetchable
+        };
+
+        Some(
+            crosvm_pci::PciBarConfiguration::new(bar_num, region.length, region_type, prefetchable)
+                .set_address(region.start.raw_value()),
+        )
+    }
+
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        self.adapter.guest_config_read(reg_idx)
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        PciDevice::write_config_register(&mut self.adapter, reg_idx, offset, data);
+    }
+
+    fn read_bar(&mut self, addr: u64, data: &mut [u8]) {
+        self.adapter.bar_mmio_read(addr, data);
+    }
+
+    fn write_bar(&mut self, addr: u64, data: &[u8]) {
+        self.adapter.bar_mmio_write(addr, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crossbeam_channel::{unbounded, Sender};
+    use crosvm_pci::PciDevice as _;
+    use devices::{BusAccessInfo, BusDevice};
+    use std::time::Duration;
+
+    struct ChannelSink(Sender<(u64, u32)>);
+
+    impl MsiSink for ChannelSink {
+        fn inject(&self, addr: u64, data: u32) -> io::Result<()> {
+            let _ = self.0.send((addr, data));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn crosvm_device() {
+        let mut resources = resources::SystemAllocator::builder()
+            .add_io_addresses(0x1000, 0x1000)
+            .add_low_mmio_addresses(0xc000_0000, 0x1000_0000)
+            .add_high_mmio_addresses(0x1_0000_0000, 0x1000_0000)
+            .create_allocator(5)
+            .unwrap();
+        let (tx, rx) = unbounded();
+        let mut device = CrosvmPciAdapter::new(PciAdapter::start(Box::new(PciTestDevice::new())))
+            .msi_sink(Box::new(ChannelSink(tx)));
+
+        let address = device.allocate_address(&mut resources).unwrap();
+        assert_eq!(device.allocate_address(&mut resources).unwrap(), address);
+        let irq = Event::new().unwrap();
+        let resample = Event::new().unwrap();
+        device.assign_irq(&irq, &resample, 5, crosvm_pci::PciInterruptPin::IntA);
+        assert_eq!(device.keep_rds().len(), 2);
+        assert_eq!(device.read_config_register(15) & 0xff, 5);
+
+        // The memory BARs are placed, the I/O BAR is left alone.
+        let bars = device.allocate_io_bars(&mut resources).unwrap();
+        assert_eq!(bars.len(), 2);
+        let bar0 = device.get_bar_configuration(0).unwrap();
+        assert!(bar0.is_64bit_memory());
+        assert_eq!((bar0.address(), bar0.size()), bars[0]);
+        assert_eq!(device.read_config_register(4) & !0xf, bars[0].0 as u32);
+        assert_eq!(device.read_config_register(6) & !0x3, 0);
+
+        // Accesses of the MMIO bus reach the model.
+        let info = |address| BusAccessInfo {
+            offset: 0,
+            address,
+            id: 0,
+        };
+        let mut data = [0; 4];
+        BusDevice::read(&mut device, info(bars[0].0), &mut data);
+        assert_eq!(data, [0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(
+            device.read_config_register(0),
+            device.adapter().config_read(0)
+        );
+
+        // An MSI-X vector of the model goes to the sink.
+        let irq_bar = device.get_bar_configuration(4).unwrap().address();
+        let cap = (device.read_config_register(13) & 0xfc) as usize >> 2;
+        BusDevice::write(&mut device, info(irq_bar), &0xfee0_0000u64.to_le_bytes());
+        BusDevice::write(&mut device, info(irq_bar + 0x8), &0x4021u32.to_le_bytes());
+        BusDevice::write(&mut device, info(irq_bar + 0xc), &0u32.to_le_bytes());
+        device.write_config_register(cap, 2, &[0x03, 0x80]);
+        BusDevice::write(&mut device, info(irq_bar + 0x100), &0u32.to_le_bytes());
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok((0xfee0_0000, 0x4021))
+        );
+
+        let adapter = device.into_adapter();
+        adapter.stop();
+        adapter.join();
+    }
+}
@@ -0,0 +1,211 @@
+//! Latency injected into the TLPs crossing the bridge.
+//!
+//! A device model answers as fast as its thread runs, so the completion timeouts and polling
+//! loops of a guest driver are never exercised. A [`DelayModel`] installed with
+//! [`PciAdapterBuilder::delay`] holds back the TLPs crossing the bridge by direction and type,
+//! for a fixed time, a random time within bounds or whatever a callback decides. The bridge
+//! keeps running meanwhile, and the TLPs to and from a device keep their order: a TLP is never
+//! delivered before the one sent ahead of it on the same link.
+
+use crate::*;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long a TLP is held back.
+#[derive(Clone)]
+pub enum Delay {
+    Fixed(Duration),
+    /// Uniformly distributed between the bounds, both included.
+    Uniform(Duration, Duration),
+    /// Decided for each TLP by the callback, which runs on the bridge thread.
+    Callback(Arc<dyn Fn(&Tlp) -> Duration + Send + Sync>),
+}
+
+impl fmt::Debug for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Delay::Fixed(delay) => write!(f, "Fixed({:?})", delay),
+            Delay::Uniform(min, max) => write!(f, "Uniform({:?}, {:?})", min, max),
+            Delay::Callback(_) => write!(f, "Callback"),
+        }
+    }
+}
+
+/// The delays of the TLPs by direction and type, the TLPs without one are not delayed.
+#[derive(Debug, Clone)]
+pub struct DelayModel {
+    delays: HashMap<(Direction, TlpKind), Delay>,
+    /// State of the xorshift generator of the uniform delays.
+    rng: u64,
+}
+
+impl Default for DelayModel {
+    fn default() -> Self {
+        DelayModel {
+            delays: HashMap::new(),
+            rng: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+}
+
+impl DelayModel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delay the TLPs of `kind` going in `direction`.
+    pub fn set(mut self, direction: Direction, kind: TlpKind, delay: Delay) -> Self {
+        self.delays.insert((direction, kind), delay);
+        self
+    }
+
+    /// Delay every TLP going in `direction`.
+    pub fn set_all(mut self, direction: Direction, delay: Delay) -> Self {
+        for kind in TlpKind::ALL.iter() {
+            self.delays.insert((direction, *kind), delay.clone());
+        }
+        self
+    }
+
+    /// Seed the random uniform delays, to reproduce a run.
+    pub fn seed(mut self, seed: u64) -> Self {
+        // Xorshift never leaves 0.
+        self.rng = seed.max(1);
+        self
+    }
+
+    /// The delay of `tlp` going in `direction`.
+    pub fn delay_of(&mut self, direction: Direction, tlp: &Tlp) -> Duration {
+        match self.delays.get(&(direction, TlpKind::of(tlp))) {
+            None => Duration::ZERO,
+            Some(Delay::Fixed(delay)) => *delay,
+            Some(Delay::Uniform(min, max)) => {
+                let (min, max) = (*min, *max);
+                let span = max.saturating_sub(min).as_nanos() as u64;
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 7;
+                self.rng ^= self.rng << 17;
+                min + Duration::from_nanos(self.rng % span.saturating_add(1))
+            }
+            Some(Delay::Callback(callback)) => callback(tlp),
+        }
+    }
+}
+
+/// TLPs held back in one direction, each lane keeping the order they are sent in.
+pub(crate) struct DelayLine<T> {
+    /// Items by due time, then by the order they are pushed.
+    queue: BTreeMap<(Instant, u64), (u16, T)>,
+    /// Due time of the last item held of each lane and how many are held.
+    lanes: HashMap<u16, (Instant, usize)>,
+    pushed: u64,
+}
+
+impl<T> DelayLine<T> {
+    pub fn new() -> Self {
+        DelayLine {
+            queue: BTreeMap::new(),
+            lanes: HashMap::new(),
+            pushed: 0,
+        }
+    }
+
+    /// Hold back `item` on `lane` for `delay`, and at least until the items before it on the
+    /// lane are due. Returns the item if it goes through at once.
+    pub fn push(&mut self, lane: u16, delay: Duration, item: T) -> Option<T> {
+        let now = Instant::now();
+        let due = match self.lanes.get_mut(&lane) {
+            None if delay == Duration::ZERO => return Some(item),
+            None => {
+                self.lanes.insert(lane, (now + delay, 1));
+                now + delay
+            }
+            Some((last, held)) => {
+                *last = (now + delay).max(*last);
+                *held += 1;
+                *last
+            }
+        };
+
+        self.pushed += 1;
+        self.queue.insert((due, self.pushed), (lane, item));
+        None
+    }
+
+    /// The first item if it is due at `now`.
+    pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+        let key = *self.queue.keys().next().filter(|(due, _)| *due <= now)?;
+        let (lane, item) = self.queue.remove(&key).unwrap();
+        if let Some((_, held)) = self.lanes.get_mut(&lane) {
+            *held -= 1;
+            if *held == 0 {
+                self.lanes.remove(&lane);
+            }
+        }
+        Some(item)
+    }
+
+    /// When the first item is due.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.keys().next().map(|(due, _)| *due)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.lanes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays() {
+        let read = TlpBuilder::config0_read(ConfigExtra {
+            requester: 0x10,
+            completer: 0x18,
+            tag: 0,
+            reg: 0,
+        })
+        .byte_enable(0xf)
+        .length(1)
+        .build();
+
+        let min = Duration::from_micros(100);
+        let max = Duration::from_micros(200);
+        let mut model = DelayModel::new()
+            .set(
+                Direction::Downstream,
+                TlpKind::ConfigRead,
+                Delay::Uniform(min, max),
+            )
+            .set_all(Direction::Upstream, Delay::Fixed(min))
+            .seed(7);
+        let delays: Vec<_> = (0..64)
+            .map(|_| model.delay_of(Direction::Downstream, &read))
+            .collect();
+        assert!(delays.iter().all(|d| (min..=max).contains(d)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+        assert_eq!(model.delay_of(Direction::Upstream, &read), min);
+
+        // A TLP without delay waits for the delayed one before it on its lane only.
+        let mut line = DelayLine::new();
+        assert_eq!(line.push(0, Duration::ZERO, 1), Some(1));
+        assert_eq!(line.push(0, Duration::from_millis(10), 2), None);
+        assert_eq!(line.push(0, Duration::ZERO, 3), None);
+        assert_eq!(line.push(8, Duration::ZERO, 4), Some(4));
+        assert_eq!(line.pop_due(Instant::now()), None);
+        let due = line.next_due().unwrap();
+        assert_eq!(line.pop_due(due), Some(2));
+        assert_eq!(line.pop_due(due), Some(3));
+        assert!(line.is_empty());
+    }
+}
@@ -8,6 +8,11 @@
 
 use crate::*;
 
+use crate::adapter::BAR0_REG;
+use crate::dma::enabled_bytes;
+use std::io;
+use std::sync::Arc;
+
 /// The simulated PCIe transaction layer device model.
 ///
 /// The device model simply receives PCIe transactions and handle them conform to PCIe specification.
@@ -19,11 +24,464 @@ pub trait PciSimDevice {
     ///
     /// * `lane` - full-duplexed PCIe lane to communicate with bridge thread.
     fn run(&mut self, lane: &PciLane);
+
+    /// Optional features advertised to the bridge when the device is attached. The bridge only
+    /// uses the features supported by both sides, see [`PciAdapter::features`].
+    fn features(&self) -> DeviceFeatures {
+        DeviceFeatures::default()
+    }
+
+    /// Host memory backing the prefetchable BARs of the device. The adapter maps it into the
+    /// guest instead of sending TLPs for accesses to these BARs.
+    fn shared_regions(&self) -> Vec<SharedRegion> {
+        vec![]
+    }
+
+    /// Doorbell registers in the BARs of the device. The writes to them reach the model as
+    /// kicks instead of TLPs, see [`crate::doorbell`].
+    fn doorbells(&self) -> Option<Doorbells> {
+        None
+    }
+
+    /// Internal state of the model saved by [`PciAdapter::snapshot`] and loaded by
+    /// [`PciAdapter::restore`]. Models without one migrate with their BARs and MSI-X table
+    /// only.
+    fn state(&self) -> Option<Arc<dyn DeviceState>> {
+        None
+    }
+
+    /// Save the state of function `function` for [`PciAdapter::snapshot`]. Called on the
+    /// thread of the model between two runs, so the model is quiesced: it took the TLPs sent
+    /// before and sees none until it runs again. By default the [`PciSimDevice::state`] is
+    /// saved.
+    fn state_save(&mut self, _function: u16) -> Option<Vec<u8>> {
+        self.state().map(|state| state.save())
+    }
+
+    /// Load a state saved by [`PciSimDevice::state_save`] into function `function` for
+    /// [`PciAdapter::restore`], quiesced like the save. By default the state is loaded into the
+    /// [`PciSimDevice::state`], models without one only take an empty state.
+    fn state_restore(&mut self, _function: u16, state: &[u8]) -> io::Result<()> {
+        match self.state() {
+            Some(saved) => saved.restore(state),
+            None if state.is_empty() => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "device model has no state",
+            )),
+        }
+    }
+
+    /// Credits of the receive buffers of the model, which the bridge respects when it sends
+    /// TLPs to the model. `None` lets the bridge send as much as the lane takes, see
+    /// [`crate::flow`].
+    fn flow_control(&self) -> Option<FcCredits> {
+        None
+    }
+
+    /// Called on the thread of the model before the first [`PciSimDevice::run`], to allocate
+    /// what the model needs for the lane it is plugged in at.
+    fn on_start(&mut self, _info: &LaneInfo) {}
+
+    /// Called once the model took the TLPs sent before the reset and its
+    /// [`PciSimDevice::run`] returned. The model puts the functions reset back into their
+    /// initial state, then runs again on a new lane, see [`PciAdapter::function_reset`].
+    fn on_reset(&mut self, _kind: ResetKind) {}
+
+    /// Called when the model runs for the last time, to flush its state before the thread
+    /// ends.
+    fn on_stop(&mut self) {}
+}
+
+/// A device model which answers the requests of the bridge one access at a time.
+///
+/// Every `SimpleDevice` is a [`PciSimDevice`] whose `run` takes the requests off the lane,
+/// hands each one to the callback of its kind with the bytes its byte enables select and
+/// answers the non-posted ones with a completion. A callback failing with a completion status,
+/// e.g. [`CPL_UR`], completes the request with that status.
+pub trait SimpleDevice {
+    /// Read config register `reg` of function `function`.
+    fn on_config_read(&mut self, function: u16, reg: usize) -> u32;
+
+    /// Write `data` at byte `offset` of config register `reg` of function `function`, like
+    /// [`PciConfiguration::write_config_register`] takes it.
+    fn on_config_write(&mut self, function: u16, reg: usize, offset: u64, data: &[u8]);
+
+    /// Read `data.len()` bytes of memory space at `addr`.
+    fn on_mem_read(&mut self, _addr: u64, _data: &mut [u8]) -> std::result::Result<(), u8> {
+        Err(CPL_UR)
+    }
+
+    /// Write `data` to memory space at `addr`. Memory writes are posted, so whatever goes
+    /// wrong is not reported.
+    fn on_mem_write(&mut self, _addr: u64, _data: &[u8]) {}
+
+    /// Read `data.len()` bytes of IO space at `addr`.
+    fn on_io_read(&mut self, _addr: u32, _data: &mut [u8]) -> std::result::Result<(), u8> {
+        Err(CPL_UR)
+    }
+
+    /// Write `data` to IO space at `addr`.
+    fn on_io_write(&mut self, _addr: u32, _data: &[u8]) -> std::result::Result<(), u8> {
+        Err(CPL_UR)
+    }
+}
+
+impl<T: SimpleDevice> PciSimDevice for T {
+    fn run(&mut self, lane: &PciLane) {
+        // The completer ID of memory and IO completions is taken from the config requests,
+        // like a real device captures its bus number.
+        let mut completer = 0;
+        while let Ok(tlp) = lane.rx.recv() {
+            dispatch(self, lane, &tlp, &mut completer);
+        }
+    }
+}
+
+/// Hand `tlp` to the callback of its kind of `device` and answer it if it is non-posted.
+/// `completer` is the ID of the device, which the config requests update.
+pub(crate) fn dispatch<T: SimpleDevice>(
+    device: &mut T,
+    lane: &PciLane,
+    tlp: &Tlp,
+    completer: &mut u16,
+) {
+    use PacketType::*;
+
+    let be = tlp.header.byte_enable;
+    let cpl = |requester, completer, tag| CompletionExtra {
+        requester,
+        completer,
+        tag,
+        status: CPL_SC,
+        bcm: false,
+        byte_count: 4,
+        lower_address: 0,
+    };
+
+    match tlp.header._type {
+        Config0Read(extra) => {
+            *completer = extra.completer;
+            let value = device.on_config_read(extra.completer, extra.reg as usize);
+            let cpl = cpl(extra.requester, extra.completer, extra.tag);
+            reply(lane, cpl, Ok(Some(vec![value])), be);
+        }
+        Config0Write(extra) => {
+            *completer = extra.completer;
+            if let Some(&value) = tlp.data.as_ref().and_then(|dw| dw.first()) {
+                let offset = be.trailing_zeros();
+                let len = (8 - be.leading_zeros()).saturating_sub(offset) as usize;
+                let data = &u32::to_le_bytes(value >> (offset * 8))[..len.min(4)];
+                device.on_config_write(extra.completer, extra.reg as usize, offset as u64, data);
+            }
+            let cpl = cpl(extra.requester, extra.completer, extra.tag);
+            reply(lane, cpl, Ok(None), be);
+        }
+        // Type 1 config requests are for bridges, an endpoint does not support them.
+        Config1Read(extra) | Config1Write(extra) => {
+            let cpl = cpl(extra.requester, extra.completer, extra.tag);
+            reply(lane, cpl, Err(CPL_UR), be);
+        }
+        MemoryRead(MemoryExtra {
+            requester,
+            tag,
+            addr,
+        }) => {
+            let cpl = cpl(requester, *completer, tag);
+            memory_read(device, lane, cpl, addr as u64, tlp);
+        }
+        MemoryRead64(Memory64Extra {
+            requester,
+            tag,
+            addr,
+        }) => {
+            let cpl = cpl(requester, *completer, tag);
+            memory_read(device, lane, cpl, addr, tlp);
+        }
+        MemoryWrite(MemoryExtra { addr, .. }) => memory_write(device, addr as u64, tlp),
+        MemoryWrite64(Memory64Extra { addr, .. }) => memory_write(device, addr, tlp),
+        IoRead(extra) => {
+            let (first, last) = enabled_span(1, be);
+            let mut bytes = [0u8; 4];
+            let result = device
+                .on_io_read(extra.addr + first as u32, &mut bytes[first..last])
+                .map(|()| Some(vec![u32::from_be_bytes(bytes)]));
+            reply(
+                lane,
+                cpl(extra.requester, *completer, extra.tag),
+                result,
+                be,
+            );
+        }
+        IoWrite(extra) => {
+            let (first, last) = enabled_span(1, be);
+            let bytes = tlp.data.as_ref().map_or(0, |dw| dw[0]).to_be_bytes();
+            let result = device
+                .on_io_write(extra.addr + first as u32, &bytes[first..last])
+                .map(|()| None);
+            reply(
+                lane,
+                cpl(extra.requester, *completer, extra.tag),
+                result,
+                be,
+            );
+        }
+        _ => (),
+    }
+}
+
+/// Complete `tlp` with UR if it is non-posted, for a request no function of the model takes.
+pub(crate) fn reject(lane: &PciLane, tlp: &Tlp, completer: u16) {
+    fail(lane, tlp, completer, CPL_UR);
+}
+
+/// Complete `tlp` with `status` if it is non-posted.
+pub(crate) fn fail(lane: &PciLane, tlp: &Tlp, completer: u16, status: u8) {
+    let (requester, tag) = match non_posted(tlp) {
+        Some(id) => id,
+        None => return,
+    };
+    let cpl = CompletionExtra {
+        requester,
+        completer,
+        tag,
+        status: CPL_SC,
+        bcm: false,
+        byte_count: 4,
+        lower_address: 0,
+    };
+    reply(lane, cpl, Err(status), tlp.header.byte_enable);
+}
+
+/// Requester ID and tag of `tlp` if it is a non-posted request a device model completes.
+pub(crate) fn non_posted(tlp: &Tlp) -> Option<(u16, u8)> {
+    use PacketType::*;
+
+    match tlp.header._type {
+        Config0Read(extra) | Config0Write(extra) | Config1Read(extra) | Config1Write(extra) => {
+            Some((extra.requester, extra.tag))
+        }
+        MemoryRead(extra) | IoRead(extra) | IoWrite(extra) => Some((extra.requester, extra.tag)),
+        MemoryRead64(extra) => Some((extra.requester, extra.tag)),
+        _ => None,
+    }
+}
+
+/// Hand the bytes a memory read enables to [`SimpleDevice::on_mem_read`] and complete it.
+fn memory_read<T: SimpleDevice>(
+    device: &mut T,
+    lane: &PciLane,
+    mut cpl: CompletionExtra,
+    addr: u64,
+    tlp: &Tlp,
+) {
+    let length = match tlp.header.length {
+        0 => 1024,
+        len => len as usize,
+    };
+    let (first, last) = enabled_span(length, tlp.header.byte_enable);
+    let mut bytes = vec![0u8; length * 4];
+    let result = device
+        .on_mem_read(addr + first as u64, &mut bytes[first..last])
+        .map(|()| {
+            Some(
+                bytes
+                    .chunks(4)
+                    .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect(),
+            )
+        });
+
+    // A byte count of 0 stands for 4096 bytes.
+    cpl.byte_count = ((last - first) & 0xfff) as u16;
+    cpl.lower_address = ((addr as usize + first) & 0x7f) as u8;
+    reply(lane, cpl, result, tlp.header.byte_enable);
+}
+
+/// Hand each run of bytes a memory write enables to [`SimpleDevice::on_mem_write`].
+fn memory_write<T: SimpleDevice>(device: &mut T, addr: u64, tlp: &Tlp) {
+    let data = match tlp.data.as_ref() {
+        Some(data) => data,
+        None => return,
+    };
+    let bytes: Vec<u8> = data.iter().flat_map(|dw| dw.to_be_bytes()).collect();
+    let enabled = enabled_bytes(data.len(), tlp.header.byte_enable);
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if !enabled[i] {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        while i < bytes.len() && enabled[i] {
+            i += 1;
+        }
+        device.on_mem_write(addr + start as u64, &bytes[start..i]);
+    }
+}
+
+/// The first and one past the last byte enabled of a `length` DW payload.
+fn enabled_span(length: usize, byte_enable: u8) -> (usize, usize) {
+    let enabled = enabled_bytes(length, byte_enable);
+    let first = enabled.iter().position(|&b| b).unwrap_or(0);
+    let last = enabled.iter().rposition(|&b| b).map_or(first, |i| i + 1);
+    (first, last)
+}
+
+/// Answer a request of the bridge with the data read, or with the status it failed with.
+fn reply(
+    lane: &PciLane,
+    mut cpl: CompletionExtra,
+    result: std::result::Result<Option<Vec<u32>>, u8>,
+    byte_enable: u8,
+) {
+    let tlp = match result {
+        Ok(Some(data)) => TlpBuilder::completion_data(cpl)
+            .byte_enable(byte_enable)
+            .data(data)
+            .build(),
+        Ok(None) => TlpBuilder::completion(cpl).build(),
+        Err(status) => {
+            cpl.status = status;
+            TlpBuilder::completion(cpl).build()
+        }
+    };
+    let _ = lane.tx.send(tlp);
+}
+
+/// Where a device model is plugged in, given to [`PciSimDevice::on_start`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LaneInfo {
+    /// The functions the model serves, the first one being the BDF of the model.
+    pub functions: Vec<u16>,
+    /// The features the bridge uses with the model, see [`PciAdapter::features`].
+    pub features: DeviceFeatures,
+    /// The most TLPs each direction of the lane holds, `None` if it is unbounded.
+    pub lane_capacity: Option<usize>,
+    /// The link the bridge simulates between itself and the model, if any.
+    pub link: Option<Link>,
+    /// The clock the model reads the time from, see [`crate::clock`].
+    pub clock: SimClock,
+}
+
+/// The kind of a reset of a device model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetKind {
+    /// Function Level Reset of the function at the BDF, the other functions of the model keep
+    /// their state.
+    FunctionLevel(u16),
+    /// Hot reset of the link, which resets every function of the model.
+    Hot,
+}
+
+/// Code of the vendor defined message which tells a model in another process about a reset,
+/// see [`ResetKind::to_message`].
+pub const RESET_MESSAGE: u8 = 0x7e;
+
+impl ResetKind {
+    /// The message a transport sends on the lane of a model in another process, whose
+    /// [`PciSimDevice::on_reset`] it stands in for. It is sent after the TLPs the model takes
+    /// before the reset. The payload is the BDF of a Function Level Reset, or all 1s for a
+    /// hot reset.
+    pub fn to_message(&self) -> Tlp {
+        let dw = match self {
+            ResetKind::FunctionLevel(bdf) => *bdf as u32,
+            ResetKind::Hot => u32::MAX,
+        };
+        TlpBuilder::with_type(PacketType::MessageData(RESET_MESSAGE))
+            .data(vec![dw])
+            .build()
+    }
+
+    /// The reset a message of [`ResetKind::to_message`] tells about, `None` for other TLPs.
+    pub fn from_message(tlp: &Tlp) -> Option<ResetKind> {
+        match (tlp.header._type, tlp.data.as_deref()) {
+            (PacketType::MessageData(RESET_MESSAGE), Some(&[u32::MAX])) => Some(ResetKind::Hot),
+            (PacketType::MessageData(RESET_MESSAGE), Some(&[bdf])) => {
+                Some(ResetKind::FunctionLevel(bdf as u16))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Optional PCIe features of a device or the bridge.
+///
+/// The bridge and the device negotiate them at attach time and the bridge sticks to the common
+/// subset, so a device never sees a request it did not ask for. A remote transport carries the
+/// features of its peer in the handshake and passes them to [`PciAdapter::reconnect`].
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DeviceFeatures {
+    /// 8-bit tags, otherwise only 32 tags are outstanding at the same time.
+    pub extended_tags: bool,
+    /// 10-bit tags.
+    pub ten_bit_tags: bool,
+    /// Address Translation Services.
+    pub ats: bool,
+    /// Integrity and Data Encryption.
+    pub ide: bool,
+    /// AtomicOp requests: FetchAdd, Swap and CAS.
+    pub atomics: bool,
+    /// Number of virtual channels besides VC0.
+    pub extra_virtual_channels: u8,
+}
+
+impl DeviceFeatures {
+    /// The features supported by both sides.
+    pub fn intersect(&self, other: &DeviceFeatures) -> DeviceFeatures {
+        DeviceFeatures {
+            extended_tags: self.extended_tags && other.extended_tags,
+            ten_bit_tags: self.ten_bit_tags && other.ten_bit_tags,
+            ats: self.ats && other.ats,
+            ide: self.ide && other.ide,
+            atomics: self.atomics && other.atomics,
+            extra_virtual_channels: self
+                .extra_virtual_channels
+                .min(other.extra_virtual_channels),
+        }
+    }
+
+    /// Number of tags which could be outstanding at the same time.
+    pub fn tags(&self) -> usize {
+        if self.ten_bit_tags {
+            1024
+        } else if self.extended_tags {
+            256
+        } else {
+            32
+        }
+    }
 }
 
+/// Size of the memory BAR of [`PciTestDevice`].
+const TEST_BAR_SIZE: usize = 0x100000;
+/// What the memory of the BAR of [`PciTestDevice`] holds before it is written, and what its
+/// IO BAR always reads.
+const TEST_PATTERN: u32 = 0x12345678;
+/// The BAR of [`PciTestDevice`] holding its MSI-X table, PBA and interrupt register.
+const TEST_IRQ_BAR: usize = 4;
+const TEST_IRQ_BAR_SIZE: u64 = 0x1000;
+const TEST_MSIX_VECTORS: u16 = 4;
+const TEST_MSIX_TABLE: u32 = 0x0;
+const TEST_MSIX_PBA: u32 = 0x800;
+/// Offset of the register in the interrupt BAR which raises the MSI-X vector written to it.
+const TEST_IRQ_REG: u64 = 0x100;
+
 /// A simple PCIe transaction level simulated device for test purpose.
+///
+/// BAR0 is 1 MB of memory, which starts filled with [`TEST_PATTERN`] in memory byte order,
+/// so the device serves as a scratchpad for the read and write paths of the adapter.
+///
+/// BAR4 is 4 KB holding the table of the 4 MSI-X vectors at 0 and their PBA at 0x800, both
+/// emulated by the adapter. Writing a vector number to the register at 0x100 makes the device
+/// raise that vector, so tests drive the whole interrupt path without a guest.
 pub struct PciTestDevice {
-    config: PciConfiguration,
+    config: ConfigSpace,
+    memory: Vec<u8>,
+    /// The BDF the device raises its interrupts as, known once it is started.
+    bdf: u16,
 }
 
 impl PciTestDevice {
@@ -43,7 +501,7 @@ impl PciTestDevice {
 
         let bar = PciBarConfiguration::new(
             0,
-            0x100000,
+            TEST_BAR_SIZE as u64,
             PciBarRegionType::Memory64BitRegion,
             PciBarPrefetchable::NotPrefetchable,
         );
@@ -59,101 +517,236 @@ impl PciTestDevice {
 
         config.add_pci_bar(&bar).unwrap();
 
-        PciTestDevice { config }
+        let bar = PciBarConfiguration::new(
+            TEST_IRQ_BAR,
+            TEST_IRQ_BAR_SIZE,
+            PciBarRegionType::Memory32BitRegion,
+            PciBarPrefetchable::NotPrefetchable,
+        );
+
+        config.add_pci_bar(&bar).unwrap();
+
+        let mut config = PcieConfiguration::new(config);
+        let msix = MsixCapability::new(TEST_MSIX_VECTORS)
+            .table(TEST_IRQ_BAR as u8, TEST_MSIX_TABLE)
+            .pba(TEST_IRQ_BAR as u8, TEST_MSIX_PBA);
+        config.add_capability(&msix).unwrap();
+
+        // Device Serial Number extended capability.
+        config
+            .add_extended(&DsnCapability::new(0x1234_5678_9abc_def0))
+            .unwrap();
+
+        PciTestDevice {
+            config: ConfigSpace::new(config),
+            memory: TEST_PATTERN
+                .to_be_bytes()
+                .iter()
+                .copied()
+                .cycle()
+                .take(TEST_BAR_SIZE)
+                .collect(),
+            bdf: 0,
+        }
+    }
+}
+
+impl PciTestDevice {
+    /// The offset of `addr` into the interrupt BAR, if it falls in there.
+    fn irq_offset(&self, addr: u64) -> Option<u64> {
+        let base = (self.config.register(BAR0_REG + TEST_IRQ_BAR) & 0xffff_fff0) as u64;
+        if base == 0 || !(base..base + TEST_IRQ_BAR_SIZE).contains(&addr) {
+            return None;
+        }
+        Some(addr - base)
+    }
+
+    /// `length` DWs of the BAR memory at `addr`. The BAR is aligned to its size, so the
+    /// offset into it is in the low bits of the address.
+    fn read_memory(&self, addr: u64, length: usize) -> Vec<u32> {
+        (0..length)
+            .map(|i| {
+                let at = (addr as usize + i * 4) & (TEST_BAR_SIZE - 1) & !0b11;
+                let b = &self.memory[at..at + 4];
+                u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+            })
+            .collect()
+    }
+
+    /// The completion of the memory read `trans` of `addr`.
+    fn complete_read(&self, trans: &Tlp, requester: u16, tag: u8, addr: u64) -> Tlp {
+        let lower_address = (addr as u8 & 0b1111100)
+            | ((trans.header.byte_enable & 0xf).trailing_zeros() as u8 % 4);
+
+        let byte_enable = if trans.header.length == 1 { 0x0f } else { 0xff };
+        let length = match trans.header.length {
+            0 => 1024,
+            len => len as usize,
+        };
+
+        TlpBuilder::completion_data(CompletionExtra {
+            requester,
+            completer: 0,
+            tag,
+            bcm: false,
+            byte_count: 0,
+            status: 0,
+            lower_address,
+        })
+        .byte_enable(byte_enable)
+        .data(match self.irq_offset(addr) {
+            // The interrupt register reads as 0, the adapter answers for the MSI-X structures.
+            Some(_) => vec![0; length],
+            None => self.read_memory(addr, length),
+        })
+        .build()
+    }
+
+    /// Take the memory write `trans` of `addr`: raise the vector written to the interrupt
+    /// register, or write the BAR memory.
+    fn memory_write(&mut self, lane: &PciLane, addr: u64, trans: &Tlp) {
+        match self.irq_offset(addr) {
+            Some(TEST_IRQ_REG) => {
+                let enabled = enabled_bytes(1, trans.header.byte_enable);
+                let dw = trans.data.as_ref().and_then(|data| data.first().copied());
+                let mut bytes = dw.unwrap_or(0).to_be_bytes();
+                for (byte, enabled) in bytes.iter_mut().zip(enabled) {
+                    if !enabled {
+                        *byte = 0;
+                    }
+                }
+
+                let vector = u32::from_le_bytes(bytes) as u16;
+                debug!("test device raises MSI-X vector {}", vector);
+                lane.raise_msix(self.bdf, vector).unwrap();
+            }
+            Some(_) => (),
+            None => self.write_memory(addr, trans),
+        }
+    }
+
+    /// Write the bytes the memory write `trans` enables to the BAR memory at `addr`.
+    fn write_memory(&mut self, addr: u64, trans: &Tlp) {
+        let data = match trans.data.as_ref() {
+            Some(data) => data,
+            None => return,
+        };
+        let enabled = enabled_bytes(data.len(), trans.header.byte_enable);
+        let bytes = data.iter().flat_map(|dw| dw.to_be_bytes());
+        for (i, byte) in bytes.enumerate().filter(|(i, _)| enabled[*i]) {
+            self.memory[(addr as usize + i) & (TEST_BAR_SIZE - 1)] = byte;
+        }
     }
 }
 
 impl PciSimDevice for PciTestDevice {
+    fn features(&self) -> DeviceFeatures {
+        DeviceFeatures {
+            extended_tags: true,
+            ..DeviceFeatures::default()
+        }
+    }
+
+    fn on_start(&mut self, info: &LaneInfo) {
+        self.bdf = info.functions[0];
+    }
+
     fn run(&mut self, lane: &PciLane) {
         use PacketType::*;
 
         while let Ok(trans) = lane.rx.recv() {
             match trans.header._type {
-                IoRead => {
-                    let h = self.config.read_config_register(0);
-                    println!("{:#x}", h);
-                }
-
-                IoWrite => {}
-
-                Config0Read(extra) => {
-                    let value = self.config.read_config_register(extra.reg as usize);
-
+                // The IO region reads the pattern the memory region starts with.
+                IoRead(extra) => {
                     let tlp = TlpBuilder::completion_data(CompletionExtra {
                         requester: extra.requester,
-                        completer: extra.completer,
+                        completer: 0,
                         tag: extra.tag,
                         bcm: false,
                         byte_count: 4,
-                        status: 0,
+                        status: CPL_SC,
                         lower_address: 0,
                     })
-                    .data(vec![value])
+                    .data(vec![TEST_PATTERN])
                     .build();
 
                     lane.tx.send(tlp).unwrap();
                 }
 
-                Config0Write(extra) => {
-                    let value = trans.data.unwrap()[0];
-                    let be = trans.header.byte_enable;
-                    let offset = be.trailing_zeros() as u64;
-                    let len = (8 - be.leading_zeros() - offset as u32) as usize;
-                    let data = &u32::to_le_bytes(value >> offset)[0..len];
-
-                    self.config
-                        .write_config_register(extra.reg as usize, offset, data);
-
-                    let tlp = TlpBuilder::completion_data(CompletionExtra {
+                IoWrite(extra) => {
+                    let tlp = TlpBuilder::completion(CompletionExtra {
                         requester: extra.requester,
-                        completer: extra.completer,
+                        completer: 0,
                         tag: extra.tag,
                         bcm: false,
                         byte_count: 4,
-                        status: 0,
+                        status: CPL_SC,
                         lower_address: 0,
                     })
-                    .data(vec![value])
                     .build();
 
                     lane.tx.send(tlp).unwrap();
                 }
 
-                // Ignore type 1 configuration transaction since it is for PCI bridge
-                Config1Read(_) | Config1Write(_) => (),
-
-                MemoryRead64(extra) => {
-                    let lower_address = (extra.addr as u8 & 0b1111100)
-                        | ((trans.header.byte_enable & 0xf).trailing_zeros() as u8 % 4);
-
-                    let byte_enable = if trans.header.length == 1 { 0x0f } else { 0xff };
+                Config0Read(_) | Config0Write(_) | Config1Read(_) | Config1Write(_) => {
+                    self.config.handle(lane, &trans);
+                }
 
-                    let tlp = TlpBuilder::completion_data(CompletionExtra {
-                        requester: extra.requester,
-                        completer: 0,
-                        tag: extra.tag,
-                        bcm: false,
-                        byte_count: 0,
-                        status: 0,
-                        lower_address,
-                    })
-                    .byte_enable(byte_enable)
-                    .data(vec![0x12345678; trans.header.length as usize])
-                    .build();
+                // The 3DW and 4DW requests only differ in the width of the address.
+                MemoryRead(MemoryExtra {
+                    requester,
+                    tag,
+                    addr,
+                }) => {
+                    lane.tx
+                        .send(self.complete_read(&trans, requester, tag, addr as u64))
+                        .unwrap();
+                }
+                MemoryRead64(Memory64Extra {
+                    requester,
+                    tag,
+                    addr,
+                }) => {
+                    lane.tx
+                        .send(self.complete_read(&trans, requester, tag, addr))
+                        .unwrap();
+                }
 
-                    lane.tx.send(tlp).unwrap();
+                MemoryWrite(MemoryExtra { addr, .. }) => {
+                    self.memory_write(lane, addr as u64, &trans)
                 }
-                _ => unimplemented!(),
+                MemoryWrite64(Memory64Extra { addr, .. }) => self.memory_write(lane, addr, &trans),
+                // Anything else, e.g. a message, is not something the device takes.
+                _ if non_posted(&trans).is_some() => reject(lane, &trans, self.bdf),
+                _ => debug!("test device drops {:?}", trans.header._type),
             }
         }
     }
 }
 
+/// A model which only has a device and vendor ID, for the tests of the transports.
+#[cfg(test)]
+pub(crate) struct Ids(pub u32);
+
+#[cfg(test)]
+impl SimpleDevice for Ids {
+    fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+        if reg == 0 {
+            self.0
+        } else {
+            0
+        }
+    }
+
+    fn on_config_write(&mut self, _: u16, _: usize, _: u64, _: &[u8]) {}
+}
+
 #[cfg(test)]
 mod tests {
     use pci::PciDevice;
 
     use super::*;
+    use crate::adapter::test_allocator;
 
     #[test]
     fn common() {
@@ -167,6 +760,47 @@ mod tests {
         adapter.join();
     }
 
+    #[test]
+    fn serial_number() {
+        let adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+
+        // The capability heads the extended configuration space.
+        assert_eq!(adapter.config_read(0x40), 0x0001_0003);
+        assert_eq!(
+            adapter.try_config_read(PCIE_CONFIG_REGS),
+            Err(PciAdapterError::InvalidRegister(PCIE_CONFIG_REGS))
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn ecam() {
+        let adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+
+        // Two bytes in the middle of the serial number.
+        let mut serial = [0u8; 2];
+        adapter.try_ecam_read(0x106, &mut serial).unwrap();
+        assert_eq!(serial, [0xbc, 0x9a]);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn config_read_many() {
+        let adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+
+        assert_eq!(
+            adapter.config_read_many(&[0, 0x40]),
+            Ok(vec![0x5678_1234, 0x0001_0003])
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
+
     #[test]
     fn bar() {
         let device = PciTestDevice::new();
@@ -210,4 +844,271 @@ mod tests {
         adapter.stop();
         adapter.join();
     }
+
+    /// Route BAR0 of the test device at 0x1_7000_0000.
+    fn map_bar0(adapter: &mut PciAdapter) {
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0x1_7000_0000),
+            length: 0x100000,
+            type_: PciBarRegionType::Memory64BitRegion,
+            bar_reg: 0,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+    }
+
+    #[test]
+    fn bar_memory() {
+        let mut adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        map_bar0(&mut adapter);
+
+        // The memory keeps what is written.
+        adapter.bar_mmio_write(0x1_7000_0002, &[0xaa, 0xbb, 0xcc, 0xdd]);
+        let mut data = [0u8; 8];
+        adapter.bar_mmio_read(0x1_7000_0000, &mut data);
+        assert_eq!(data, [0x12, 0x34, 0xaa, 0xbb, 0xcc, 0xdd, 0x56, 0x78]);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn bar_mmio_read_many() {
+        let mut adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        map_bar0(&mut adapter);
+        adapter.bar_mmio_write(0x1_7000_0002, &[0xaa, 0xbb]);
+
+        let (mut first, mut second) = ([0u8; 2], [0u8; 4]);
+        adapter
+            .bar_mmio_read_many(&mut [
+                (0x1_7000_0001, &mut first[..]),
+                (0x1_7000_0010, &mut second[..]),
+            ])
+            .unwrap();
+        assert_eq!(first, [0x34, 0xaa]);
+        assert_eq!(second, [0x12, 0x34, 0x56, 0x78]);
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn io_bar() {
+        let mut adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0xc000),
+            length: 0x100,
+            type_: PciBarRegionType::IoRegion,
+            bar_reg: 6,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+
+        // IO reads and writes of a DW at most, which stay within it.
+        let mut data = [0u8; 2];
+        adapter.bar_mmio_read(0xc002, &mut data);
+        assert_eq!(data, [0x56, 0x78]);
+        assert_eq!(adapter.try_bar_mmio_write(0xc001, &[0x1]), Ok(()));
+        assert_eq!(
+            adapter.try_bar_mmio_write(0xc003, &[0x1, 0x2]),
+            Err(PciAdapterError::InvalidSize(2))
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    #[test]
+    fn unexpected_tlp() {
+        let (host, dev) = PciLane::pair();
+        let device = std::thread::spawn(move || PciTestDevice::new().run(&dev));
+
+        // A message is dropped, the device keeps answering.
+        host.tx.send(ResetKind::Hot.to_message()).unwrap();
+        let read = TlpBuilder::io_read(MemoryExtra {
+            requester: 0x10,
+            tag: 2,
+            addr: 0xc000,
+        })
+        .byte_enable(0xf)
+        .build();
+        host.tx.send(read).unwrap();
+
+        let completion = host.rx.recv().unwrap();
+        assert_eq!(completion.data, Some(vec![TEST_PATTERN]));
+
+        drop(host);
+        device.join().unwrap();
+    }
+
+    #[test]
+    fn memory32() {
+        let (host, dev) = PciLane::pair();
+        let device = std::thread::spawn(move || PciTestDevice::new().run(&dev));
+
+        let extra = MemoryExtra {
+            requester: 0x10,
+            tag: 1,
+            addr: 0x7000_0004,
+        };
+        let write = TlpBuilder::memory_write(extra)
+            .byte_enable(0xf)
+            .data(vec![0xaabb_ccdd])
+            .build();
+        let read = TlpBuilder::memory_read(extra)
+            .byte_enable(0xff)
+            .length(2)
+            .build();
+        host.tx.send(write).unwrap();
+        host.tx.send(read).unwrap();
+
+        let completion = host.rx.recv().unwrap();
+        match completion.header._type {
+            PacketType::CompletionData(extra) => {
+                assert_eq!((extra.requester, extra.tag), (0x10, 1));
+                assert_eq!(extra.lower_address, 0x04);
+            }
+            _ => panic!("unexpected {:?}", completion.header._type),
+        }
+        assert_eq!(completion.data, Some(vec![0xaabb_ccdd, TEST_PATTERN]));
+
+        drop(host);
+        device.join().unwrap();
+    }
+
+    struct ChannelSink(crossbeam_channel::Sender<(u64, u32)>);
+
+    impl MsiSink for ChannelSink {
+        fn inject(&self, addr: u64, data: u32) -> std::io::Result<()> {
+            self.0.send((addr, data)).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn msix() {
+        let mut adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        let (tx, rx) = crossbeam_channel::unbounded();
+        adapter.set_msi_sink(Box::new(ChannelSink(tx)));
+
+        let mut allocator = test_allocator();
+
+        let bars = adapter.allocate_bars(&mut allocator).unwrap();
+        let base = bars
+            .iter()
+            .find(|(_, size, _)| *size == TEST_IRQ_BAR_SIZE)
+            .unwrap()
+            .0
+            .raw_value();
+        // The MSI-X capability is the only one.
+        let cap = (adapter.config_read(13) & 0xfc) as usize >> 2;
+        assert_eq!(adapter.config_read(cap) & 0x7ff_00ff, 0x3_0011);
+
+        // Program and unmask vector 2, then enable MSI-X.
+        adapter.bar_mmio_write(base + 0x20, &0xfee0_2000u64.to_le_bytes());
+        adapter.bar_mmio_write(base + 0x28, &0x4022u32.to_le_bytes());
+        adapter.bar_mmio_write(base + 0x2c, &0u32.to_le_bytes());
+        adapter.write_config_register(cap, 2, &[0x03, 0x80]);
+
+        adapter.bar_mmio_write(base + TEST_IRQ_REG, &2u32.to_le_bytes());
+        assert_eq!(
+            rx.recv_timeout(std::time::Duration::from_secs(1)),
+            Ok((0xfee0_2000, 0x4022))
+        );
+
+        // A masked vector is left pending.
+        adapter.bar_mmio_write(base + TEST_IRQ_REG, &1u32.to_le_bytes());
+        let mut data = [0u8; 4];
+        adapter.bar_mmio_read(base + TEST_IRQ_REG, &mut data);
+        assert_eq!(data, [0; 4]);
+        adapter.bar_mmio_read(base + TEST_MSIX_PBA as u64, &mut data);
+        assert_eq!(data, [0x2, 0, 0, 0]);
+        assert!(rx.try_recv().is_err());
+
+        adapter.stop();
+        adapter.join();
+    }
+
+    /// A device with a config space of 64 registers and 256 bytes of memory at any address.
+    struct ScratchDevice {
+        config: [u32; PCI_CONFIG_REGS],
+        memory: [u8; 0x100],
+    }
+
+    impl SimpleDevice for ScratchDevice {
+        fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+            self.config.get(reg).copied().unwrap_or(0)
+        }
+
+        fn on_config_write(&mut self, _: u16, reg: usize, offset: u64, data: &[u8]) {
+            if let Some(value) = self.config.get_mut(reg) {
+                let mut bytes = value.to_le_bytes();
+                bytes[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+                *value = u32::from_le_bytes(bytes);
+            }
+        }
+
+        fn on_mem_read(&mut self, addr: u64, data: &mut [u8]) -> std::result::Result<(), u8> {
+            let start = addr as usize & 0xff;
+            data.copy_from_slice(&self.memory[start..start + data.len()]);
+            Ok(())
+        }
+
+        fn on_mem_write(&mut self, addr: u64, data: &[u8]) {
+            let start = addr as usize & 0xff;
+            self.memory[start..start + data.len()].copy_from_slice(data);
+        }
+    }
+
+    #[test]
+    fn simple() {
+        let mut config = [0; PCI_CONFIG_REGS];
+        config[0] = 0x5678_1234;
+        let device = ScratchDevice {
+            config,
+            memory: [0; 0x100],
+        };
+        let mut adapter = PciAdapter::start(Box::new(device));
+
+        assert_eq!(adapter.config_read(0), 0x5678_1234);
+        adapter.config_write(1, 2, &[0xaa]);
+        assert_eq!(adapter.config_read(1), 0x00aa_0000);
+
+        for (start, type_, bar_reg) in [
+            (0x1_0000_0000, PciBarRegionType::Memory64BitRegion, 0),
+            (0xc000, PciBarRegionType::IoRegion, 2),
+        ] {
+            adapter.mmio_regions.push(MmioRegion {
+                start: GuestAddress(start),
+                length: 0x100,
+                type_,
+                bar_reg,
+                mem_slot: None,
+                host_addr: None,
+                mmap_size: None,
+                slot_mapped: false,
+            });
+        }
+
+        adapter.bar_mmio_write(0x1_0000_0001, &[1, 2, 3]);
+        let mut data = [0xff; 8];
+        adapter.bar_mmio_read(0x1_0000_0000, &mut data);
+        assert_eq!(data, [0, 1, 2, 3, 0, 0, 0, 0]);
+        let mut data = [0; 2];
+        adapter.bar_mmio_read(0x1_0000_0002, &mut data);
+        assert_eq!(data, [2, 3]);
+
+        // The IO space is not implemented.
+        assert_eq!(
+            adapter.try_bar_mmio_read(0xc000, &mut data),
+            Err(PciAdapterError::Completion(CPL_UR))
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
 }
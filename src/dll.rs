@@ -0,0 +1,690 @@
+//! Simulation of the data link layer.
+//!
+//! Lanes carry TLPs as they are, the way a link does once its data link layer made it
+//! reliable. A model which will sit behind a real data link layer is tested against one by
+//! wrapping it in a [`DataLinkDevice`]: the TLPs between the bridge and the model then cross a
+//! pair of [`DataLink`]s, the Downstream Port of the bridge and the Upstream Port of the model.
+//!
+//! Each end brings the link up with FC Init, advertising its credits by InitFC1 and InitFC2
+//! DLLPs, and holds back the TLPs until the link is DL_Active. The transmitter numbers the TLPs
+//! and keeps them in its replay buffer until the receiver acknowledges them with an Ack DLLP.
+//! A TLP which arrives with a bad LCRC or out of sequence is answered with a Nak, on which the
+//! transmitter replays every unacknowledged TLP, and so does the replay timer when the Acks stop
+//! coming. The [`DataLinkControl`] of the wrapper spoils or loses TLPs on the wire to exercise
+//! those paths, and reports what the link did.
+
+use crate::*;
+
+use crossbeam_channel::{at, never, select};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Sequence numbers are 12 bits.
+const SEQ_MASK: u16 = 0xfff;
+/// The transmitter holds back TLPs while half of the sequence numbers are unacknowledged.
+const MAX_UNACKNOWLEDGED: usize = 2048;
+/// Time without Ack after which the transmitter replays its buffer. Lanes have no symbol time,
+/// so this is far above the value of the spec.
+const REPLAY_TIMEOUT: Duration = Duration::from_millis(20);
+/// Replays of the same TLPs after which the link retrains.
+const REPLAY_NUM_ROLLOVER: u8 = 4;
+
+const FC_CLASSES: [FcClass; 3] = [FcClass::Posted, FcClass::NonPosted, FcClass::Completion];
+
+/// A data link layer packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dllp {
+    /// The TLPs up to the sequence number arrived.
+    Ack(u16),
+    /// The TLPs up to the sequence number arrived, the following ones must be replayed.
+    Nak(u16),
+    InitFc1(FcClass, FcCredit),
+    InitFc2(FcClass, FcCredit),
+    UpdateFc(FcClass, FcCredit),
+}
+
+/// What crosses the wire between two [`DataLink`]s.
+#[derive(Debug, Clone)]
+pub enum Frame {
+    /// A TLP with its sequence number, `corrupted` when its LCRC does not check.
+    Tlp {
+        seq: u16,
+        tlp: Tlp,
+        corrupted: bool,
+    },
+    Dllp(Dllp),
+}
+
+/// State of the data link control and management state machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum DlState {
+    /// The physical layer is down.
+    #[default]
+    Inactive,
+    /// Sending InitFC1 until the credits of every class of the other end are known.
+    FcInit1,
+    /// Sending InitFC2 until the other end is known to have the credits of this one.
+    FcInit2,
+    /// TLPs flow.
+    Active,
+}
+
+/// What one end of a link did since it came up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DataLinkStats {
+    /// TLPs sent for the first time.
+    pub sent: u64,
+    /// TLPs sent again from the replay buffer.
+    pub replayed: u64,
+    /// Naks received.
+    pub naks: u64,
+    /// Replays started by the replay timer.
+    pub timeouts: u64,
+    /// Times REPLAY_NUM rolled over, on which a real link retrains.
+    pub retrains: u64,
+}
+
+/// Whether sequence number `a` comes before `b`.
+fn before(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) & SEQ_MASK < MAX_UNACKNOWLEDGED as u16
+}
+
+fn next_seq(seq: u16) -> u16 {
+    seq.wrapping_add(1) & SEQ_MASK
+}
+
+fn class_of(credits: &FcCredits, class: FcClass) -> FcCredit {
+    match class {
+        FcClass::Posted => credits.posted,
+        FcClass::NonPosted => credits.non_posted,
+        FcClass::Completion => credits.completion,
+    }
+}
+
+/// One end of a link: the transmitter and receiver of its data link layer. TLPs go in by
+/// [`DataLink::transmit`] and the frames to put on the wire come out of [`DataLink::frames`],
+/// the frames of the other end go in by [`DataLink::receive`], which hands out the TLPs they
+/// deliver.
+#[derive(Debug, Default)]
+pub struct DataLink {
+    state: DlState,
+    advertised: FcCredits,
+    /// Credits of the other end, by class, as it advertises them.
+    remote: [Option<FcCredit>; 3],
+    /// Whether the other end sent InitFC2, which ends FC Init once the credits are known.
+    remote_fc2: bool,
+    next_transmit_seq: u16,
+    replay: VecDeque<(u16, Tlp)>,
+    replay_num: u8,
+    replay_deadline: Option<Instant>,
+    next_rcv_seq: u16,
+    nak_scheduled: bool,
+    queued: VecDeque<Tlp>,
+    outbox: VecDeque<Frame>,
+    stats: DataLinkStats,
+}
+
+impl DataLink {
+    /// An end advertising `credits` at FC Init, 0 for infinite ones.
+    pub fn new(credits: FcCredits) -> Self {
+        DataLink {
+            advertised: credits,
+            ..Default::default()
+        }
+    }
+
+    /// The physical layer is up, start FC Init.
+    pub fn start(&mut self) {
+        if self.state != DlState::Inactive {
+            return;
+        }
+        self.state = DlState::FcInit1;
+        for class in FC_CLASSES {
+            let credit = class_of(&self.advertised, class);
+            self.outbox
+                .push_back(Frame::Dllp(Dllp::InitFc1(class, credit)));
+        }
+        self.advance();
+    }
+
+    pub fn state(&self) -> DlState {
+        self.state
+    }
+
+    /// The credits the other end advertised, once FC Init learned them all.
+    pub fn credits(&self) -> Option<FcCredits> {
+        Some(FcCredits {
+            posted: self.remote[0]?,
+            non_posted: self.remote[1]?,
+            completion: self.remote[2]?,
+        })
+    }
+
+    pub fn stats(&self) -> DataLinkStats {
+        self.stats
+    }
+
+    /// When the replay timer expires, if it runs.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.replay_deadline
+    }
+
+    /// Send `tlp`, once the link is DL_Active.
+    pub fn transmit(&mut self, tlp: Tlp) {
+        self.queued.push_back(tlp);
+        self.flush();
+    }
+
+    /// The frames to put on the wire, in order.
+    pub fn frames(&mut self) -> impl Iterator<Item = Frame> + '_ {
+        self.outbox.drain(..)
+    }
+
+    /// Take a frame of the other end, returns the TLP it delivers if any.
+    pub fn receive(&mut self, frame: Frame) -> Option<Tlp> {
+        match frame {
+            Frame::Dllp(dllp) => {
+                self.dllp(dllp);
+                None
+            }
+            Frame::Tlp { .. } if self.state < DlState::FcInit2 => None,
+            Frame::Tlp {
+                seq,
+                tlp,
+                corrupted,
+            } => {
+                // A TLP tells FC Init is over on the other end.
+                self.activate();
+                if corrupted {
+                    self.nak();
+                    None
+                } else if seq == self.next_rcv_seq {
+                    self.next_rcv_seq = next_seq(seq);
+                    self.nak_scheduled = false;
+                    self.outbox.push_back(Frame::Dllp(Dllp::Ack(seq)));
+                    Some(tlp)
+                } else if before(seq, self.next_rcv_seq) {
+                    // A duplicate of a replay, acknowledged again and dropped.
+                    let last = self.next_rcv_seq.wrapping_sub(1) & SEQ_MASK;
+                    self.outbox.push_back(Frame::Dllp(Dllp::Ack(last)));
+                    None
+                } else {
+                    self.nak();
+                    None
+                }
+            }
+        }
+    }
+
+    /// Replay the buffer if the replay timer expired at `now`.
+    pub fn tick(&mut self, now: Instant) {
+        if self.replay_deadline.is_some_and(|deadline| deadline <= now) {
+            self.stats.timeouts += 1;
+            self.replay();
+        }
+    }
+
+    fn dllp(&mut self, dllp: Dllp) {
+        match dllp {
+            Dllp::InitFc1(class, credit) | Dllp::InitFc2(class, credit) => {
+                if self.state == DlState::Active {
+                    return;
+                }
+                let index = FC_CLASSES.iter().position(|&c| c == class).unwrap();
+                self.remote[index].get_or_insert(credit);
+                self.remote_fc2 |= matches!(dllp, Dllp::InitFc2(..));
+                self.advance();
+            }
+            Dllp::UpdateFc(..) => self.activate(),
+            Dllp::Ack(seq) if self.state == DlState::Active => {
+                self.purge(seq);
+                self.flush();
+            }
+            Dllp::Nak(seq) if self.state == DlState::Active => {
+                self.stats.naks += 1;
+                self.purge(seq);
+                self.replay();
+                self.flush();
+            }
+            Dllp::Ack(_) | Dllp::Nak(_) => (),
+        }
+    }
+
+    /// Move on with FC Init as far as what the other end sent allows.
+    fn advance(&mut self) {
+        if self.state == DlState::FcInit1 && self.credits().is_some() {
+            self.state = DlState::FcInit2;
+            for class in FC_CLASSES {
+                let credit = class_of(&self.advertised, class);
+                self.outbox
+                    .push_back(Frame::Dllp(Dllp::InitFc2(class, credit)));
+            }
+        }
+        if self.state == DlState::FcInit2 && self.remote_fc2 {
+            self.activate();
+        }
+    }
+
+    fn activate(&mut self) {
+        if self.state == DlState::FcInit2 {
+            self.state = DlState::Active;
+            self.flush();
+        }
+    }
+
+    /// Send the queued TLPs the replay buffer has room for.
+    fn flush(&mut self) {
+        while self.state == DlState::Active && self.replay.len() < MAX_UNACKNOWLEDGED {
+            let tlp = match self.queued.pop_front() {
+                Some(tlp) => tlp,
+                None => break,
+            };
+            let seq = self.next_transmit_seq;
+            self.next_transmit_seq = next_seq(seq);
+            self.replay.push_back((seq, tlp.clone()));
+            self.outbox.push_back(Frame::Tlp {
+                seq,
+                tlp,
+                corrupted: false,
+            });
+            self.stats.sent += 1;
+            self.replay_deadline
+                .get_or_insert_with(|| Instant::now() + REPLAY_TIMEOUT);
+        }
+    }
+
+    /// Drop the TLPs acknowledged up to `seq` from the replay buffer.
+    fn purge(&mut self, seq: u16) {
+        let mut progress = false;
+        while let Some(&(first, _)) = self.replay.front() {
+            if first != seq && !before(first, seq) {
+                break;
+            }
+            self.replay.pop_front();
+            progress = true;
+        }
+        if progress {
+            self.replay_num = 0;
+            self.replay_deadline =
+                (!self.replay.is_empty()).then(|| Instant::now() + REPLAY_TIMEOUT);
+        }
+    }
+
+    fn replay(&mut self) {
+        if self.replay.is_empty() {
+            self.replay_deadline = None;
+            return;
+        }
+        self.replay_num += 1;
+        if self.replay_num == REPLAY_NUM_ROLLOVER {
+            self.stats.retrains += 1;
+            self.replay_num = 0;
+        }
+        for (seq, tlp) in &self.replay {
+            self.outbox.push_back(Frame::Tlp {
+                seq: *seq,
+                tlp: tlp.clone(),
+                corrupted: false,
+            });
+        }
+        self.stats.replayed += self.replay.len() as u64;
+        self.replay_deadline = Some(Instant::now() + REPLAY_TIMEOUT);
+    }
+
+    fn nak(&mut self) {
+        if !self.nak_scheduled {
+            self.nak_scheduled = true;
+            let last = self.next_rcv_seq.wrapping_sub(1) & SEQ_MASK;
+            self.outbox.push_back(Frame::Dllp(Dllp::Nak(last)));
+        }
+    }
+}
+
+/// What the wire does to a TLP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkError {
+    /// The TLP arrives with a bad LCRC.
+    Corrupt,
+    /// The TLP never arrives.
+    Lost,
+}
+
+#[derive(Debug, Default)]
+struct Wire {
+    /// Errors with the number of TLPs they still take, downstream and upstream.
+    errors: [VecDeque<(LinkError, usize)>; 2],
+    /// Stats of the Downstream and the Upstream Port.
+    stats: [DataLinkStats; 2],
+    state: DlState,
+    credits: Option<FcCredits>,
+}
+
+fn index(direction: Direction) -> usize {
+    match direction {
+        Direction::Downstream => 0,
+        Direction::Upstream => 1,
+    }
+}
+
+/// The link of a [`DataLinkDevice`], shared by the test and the thread of the model.
+#[derive(Debug, Clone, Default)]
+pub struct DataLinkControl {
+    wire: Arc<Mutex<Wire>>,
+}
+
+impl DataLinkControl {
+    /// Hit the next `count` TLPs sent in `direction` with `error`, once the errors injected
+    /// before are used up. Replays count too.
+    pub fn inject(&self, direction: Direction, error: LinkError, count: usize) {
+        if count > 0 {
+            let mut wire = self.wire.lock().unwrap();
+            wire.errors[index(direction)].push_back((error, count));
+        }
+    }
+
+    /// Drop the errors not used yet.
+    pub fn clear(&self) {
+        let mut wire = self.wire.lock().unwrap();
+        wire.errors.iter_mut().for_each(VecDeque::clear);
+    }
+
+    /// What the transmitter of `direction` did, the Downstream Port for downstream.
+    pub fn stats(&self, direction: Direction) -> DataLinkStats {
+        self.wire.lock().unwrap().stats[index(direction)]
+    }
+
+    /// State of the Downstream Port.
+    pub fn state(&self) -> DlState {
+        self.wire.lock().unwrap().state
+    }
+
+    /// The credits of the model as the Downstream Port learned them at FC Init.
+    pub fn credits(&self) -> Option<FcCredits> {
+        self.wire.lock().unwrap().credits
+    }
+
+    /// Put `frame` on the wire in `direction`, `None` if it is lost.
+    fn carry(&self, direction: Direction, frame: Frame) -> Option<Frame> {
+        let (seq, tlp) = match frame {
+            Frame::Tlp { seq, tlp, .. } => (seq, tlp),
+            dllp => return Some(dllp),
+        };
+
+        let mut wire = self.wire.lock().unwrap();
+        let errors = &mut wire.errors[index(direction)];
+        let error = errors.front_mut().map(|(error, count)| {
+            *count -= 1;
+            *error
+        });
+        if errors.front().is_some_and(|(_, count)| *count == 0) {
+            errors.pop_front();
+        }
+        match error {
+            Some(LinkError::Lost) => {
+                debug!("TLP {} lost {:?}", seq, direction);
+                None
+            }
+            error => Some(Frame::Tlp {
+                seq,
+                tlp,
+                corrupted: error == Some(LinkError::Corrupt),
+            }),
+        }
+    }
+
+    fn publish(&self, port: &DataLink, device: &DataLink) {
+        let mut wire = self.wire.lock().unwrap();
+        wire.stats = [port.stats(), device.stats()];
+        wire.state = port.state();
+        wire.credits = port.credits();
+    }
+}
+
+/// A device model behind a simulated data link layer, see [`crate::dll`]. The link comes up
+/// each time the model runs, i.e. again after a reset.
+pub struct DataLinkDevice<D> {
+    device: D,
+    control: DataLinkControl,
+}
+
+impl<D: PciSimDevice + Send> DataLinkDevice<D> {
+    pub fn new(device: D) -> Self {
+        DataLinkDevice {
+            device,
+            control: DataLinkControl::default(),
+        }
+    }
+
+    /// The control of the link, which stays with the test once the model is started.
+    pub fn control(&self) -> DataLinkControl {
+        self.control.clone()
+    }
+}
+
+/// Move the frames of both ends across the wire until they are quiet, then hand the TLPs they
+/// delivered to the bridge and to the model. The stats are published first, so whoever a TLP
+/// wakes up sees what the link did to deliver it.
+fn exchange(
+    control: &DataLinkControl,
+    port: &mut DataLink,
+    device: &mut DataLink,
+    lane: &PciLane,
+    model: &PciLane,
+) {
+    let (mut to_model, mut to_bridge) = (vec![], vec![]);
+    loop {
+        let down: Vec<Frame> = port.frames().collect();
+        let up: Vec<Frame> = device.frames().collect();
+        if down.is_empty() && up.is_empty() {
+            break;
+        }
+        for frame in down {
+            let frame = control.carry(Direction::Downstream, frame);
+            to_model.extend(frame.and_then(|frame| device.receive(frame)));
+        }
+        for frame in up {
+            let frame = control.carry(Direction::Upstream, frame);
+            to_bridge.extend(frame.and_then(|frame| port.receive(frame)));
+        }
+    }
+    control.publish(port, device);
+
+    for tlp in to_model {
+        let _ = model.tx.send(tlp);
+    }
+    for tlp in to_bridge {
+        let _ = lane.tx.send(tlp);
+    }
+}
+
+impl<D: PciSimDevice + Send> PciSimDevice for DataLinkDevice<D> {
+    fn run(&mut self, lane: &PciLane) {
+        let (model, inner) = PciLane::pair();
+        let mut port = DataLink::new(FcCredits::default());
+        let mut upstream = DataLink::new(self.device.flow_control().unwrap_or_default());
+        let DataLinkDevice { device, control } = self;
+
+        port.start();
+        upstream.start();
+        std::thread::scope(|scope| {
+            // The model runs on a lane of its own, which ends with the lane of the bridge.
+            scope.spawn(move || device.run(&inner));
+            loop {
+                exchange(control, &mut port, &mut upstream, lane, &model);
+                let deadline = port.deadline().into_iter().chain(upstream.deadline()).min();
+                let timer = deadline.map_or_else(never, at);
+                select! {
+                    recv(lane.rx) -> tlp => match tlp {
+                        Ok(tlp) => port.transmit(tlp),
+                        Err(_) => break,
+                    },
+                    recv(model.rx) -> tlp => match tlp {
+                        Ok(tlp) => upstream.transmit(tlp),
+                        Err(_) => break,
+                    },
+                    recv(timer) -> _ => {
+                        let now = Instant::now();
+                        port.tick(now);
+                        upstream.tick(now);
+                    }
+                }
+            }
+            drop(model);
+        });
+    }
+
+    fn features(&self) -> DeviceFeatures {
+        self.device.features()
+    }
+
+    fn shared_regions(&self) -> Vec<SharedRegion> {
+        self.device.shared_regions()
+    }
+
+    fn doorbells(&self) -> Option<Doorbells> {
+        self.device.doorbells()
+    }
+
+    fn state(&self) -> Option<Arc<dyn DeviceState>> {
+        self.device.state()
+    }
+
+    fn flow_control(&self) -> Option<FcCredits> {
+        self.device.flow_control()
+    }
+
+    fn on_start(&mut self, info: &LaneInfo) {
+        self.device.on_start(info);
+    }
+
+    fn on_reset(&mut self, kind: ResetKind) {
+        self.device.on_reset(kind);
+    }
+
+    fn on_stop(&mut self) {
+        self.device.on_stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Config register 0 reads the device and vendor ID, the others read 0.
+    struct Ids;
+
+    impl SimpleDevice for Ids {
+        fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+            if reg == 0 {
+                0x5678_1234
+            } else {
+                0
+            }
+        }
+
+        fn on_config_write(&mut self, _: u16, _: usize, _: u64, _: &[u8]) {}
+    }
+
+    fn write(addr: u64) -> Tlp {
+        TlpBuilder::memory_write64(Memory64Extra {
+            requester: 0x10,
+            tag: 0,
+            addr,
+        })
+        .data(vec![0])
+        .build()
+    }
+
+    /// Hand the frames of `from` to `to`, returns the TLPs delivered.
+    fn cross(from: &mut DataLink, to: &mut DataLink) -> Vec<Tlp> {
+        let frames: Vec<Frame> = from.frames().collect();
+        frames.into_iter().filter_map(|f| to.receive(f)).collect()
+    }
+
+    #[test]
+    fn fc_init() {
+        let credits = FcCredits {
+            posted: FcCredit {
+                header: 8,
+                data: 64,
+            },
+            ..Default::default()
+        };
+        let (mut port, mut device) = (DataLink::new(FcCredits::default()), DataLink::new(credits));
+
+        // TLPs wait for DL_Active.
+        port.transmit(write(0x1000));
+        port.start();
+        assert_eq!(port.state(), DlState::FcInit1);
+        assert!(cross(&mut port, &mut device).is_empty());
+        assert_eq!(device.state(), DlState::Inactive);
+
+        device.start();
+        assert_eq!(device.state(), DlState::FcInit2);
+        assert!(cross(&mut device, &mut port).is_empty());
+        assert_eq!(port.state(), DlState::Active);
+        assert_eq!(port.credits(), Some(credits));
+
+        let tlps = cross(&mut port, &mut device);
+        assert_eq!(device.state(), DlState::Active);
+        assert_eq!(tlps.len(), 1);
+        assert_eq!(device.credits(), Some(FcCredits::default()));
+
+        // Acks empty the replay buffer and stop the timer.
+        assert!(port.deadline().is_some());
+        cross(&mut device, &mut port);
+        assert_eq!(port.deadline(), None);
+
+        // A duplicate is acknowledged again and dropped.
+        let seq = (0..SEQ_MASK + 2).fold(0, |seq, _| next_seq(seq));
+        assert_eq!(seq, 1);
+        assert!(before(SEQ_MASK, 0));
+        let replayed = Frame::Tlp {
+            seq: 0,
+            tlp: write(0x1000),
+            corrupted: false,
+        };
+        assert!(device.receive(replayed).is_none());
+        assert!(matches!(
+            device.frames().collect::<Vec<_>>()[..],
+            [Frame::Dllp(Dllp::Ack(0))]
+        ));
+    }
+
+    #[test]
+    fn replay() {
+        let device = DataLinkDevice::new(Ids);
+        let control = device.control();
+        let adapter = PciAdapter::start(Box::new(device));
+
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        assert_eq!(control.state(), DlState::Active);
+        assert_eq!(control.credits(), Some(FcCredits::default()));
+
+        // A corrupted request is Naked and replayed.
+        control.inject(Direction::Downstream, LinkError::Corrupt, 1);
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        let stats = control.stats(Direction::Downstream);
+        assert_eq!((stats.sent, stats.naks, stats.replayed), (2, 1, 1));
+
+        // A lost completion is only replayed when the replay timer expires.
+        control.inject(Direction::Upstream, LinkError::Lost, 1);
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        let stats = control.stats(Direction::Upstream);
+        assert_eq!((stats.sent, stats.timeouts, stats.replayed), (3, 1, 1));
+
+        // While a Nak is scheduled, only the replay timer recovers, and REPLAY_NUM rolls over
+        // when the same TLP keeps failing.
+        control.inject(Direction::Downstream, LinkError::Corrupt, 4);
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        let stats = control.stats(Direction::Downstream);
+        assert_eq!(
+            (stats.naks, stats.replayed, stats.timeouts, stats.retrains),
+            (2, 5, 3, 1)
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
+}
@@ -0,0 +1,398 @@
+//! DMA of the device model into guest memory.
+//!
+//! Memory requests issued by the device, other than MSIs, are serviced by the bridge through
+//! a [`DmaMemory`]. When a [`DmaTranslator`] is set, e.g. by a vIOMMU, every address is treated
+//! as an IOVA and translated first. Reads which fail translation are completed with UR, writes
+//! are posted and thus dropped.
+//!
+//! On the device side, a [`DmaHandle`] saves the models from building the memory requests and
+//! matching their completions by hand.
+
+use crate::*;
+
+use crate::adapter::{split_request, write_byte_enable, Result, MAX_READ_REQUEST};
+use crate::tag::TagPool;
+use crossbeam_channel::{RecvError, RecvTimeoutError};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+use vm_memory::{Bytes, GuestAddressSpace, GuestMemory, GuestMemoryAtomic};
+
+/// Bytes a memory request never crosses a multiple of.
+const PAGE_SIZE: usize = 4096;
+/// Tags of a requester without extended tags.
+const DEVICE_TAGS: usize = 32;
+
+/// Direction of a DMA access, as seen from the device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DmaAccess {
+    Read,
+    Write,
+}
+
+/// Why a DMA access is refused by a [`DmaTranslator`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DmaFault {
+    /// Nothing is mapped at the IOVA for the requester.
+    Unmapped,
+    /// The mapping does not allow the access.
+    Permission,
+}
+
+impl fmt::Display for DmaFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DmaFault::Unmapped => write!(f, "unmapped IOVA"),
+            DmaFault::Permission => write!(f, "access not permitted"),
+        }
+    }
+}
+
+/// Hypervisor hook translating the IOVAs of device DMA into guest physical addresses.
+pub trait DmaTranslator: Send {
+    /// Translate an access of `len` bytes at `iova` by `requester`. The translated range must
+    /// be contiguous in the guest physical address space, fault otherwise.
+    fn translate(
+        &self,
+        requester: u16,
+        iova: u64,
+        len: u64,
+        access: DmaAccess,
+    ) -> std::result::Result<u64, DmaFault>;
+}
+
+/// Hypervisor hook giving the bridge access to guest memory.
+pub trait DmaMemory: Send {
+    fn read(&self, gpa: u64, data: &mut [u8]) -> io::Result<()>;
+
+    fn write(&self, gpa: u64, data: &[u8]) -> io::Result<()>;
+}
+
+/// Guest memory the VMM may hot-add to or resize while the device does DMA. Every access takes
+/// the memory map current at the time, and the map it took stays valid until the access is
+/// done, so accesses of a request spanning several of them may see different maps.
+impl<M: GuestMemory + Send + Sync> DmaMemory for GuestMemoryAtomic<M> {
+    fn read(&self, gpa: u64, data: &mut [u8]) -> io::Result<()> {
+        self.memory()
+            .read_slice(data, GuestAddress(gpa))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn write(&self, gpa: u64, data: &[u8]) -> io::Result<()> {
+        self.memory()
+            .write_slice(data, GuestAddress(gpa))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+/// Whether each byte of a `length` DW payload is enabled by the first and last DW byte
+/// enables. The last DW byte enables only apply to payloads of more than one DW.
+pub(crate) fn enabled_bytes(length: usize, byte_enable: u8) -> Vec<bool> {
+    let last = if length > 1 {
+        byte_enable >> 4
+    } else {
+        byte_enable & 0xf
+    };
+
+    (0..length * 4)
+        .map(|i| {
+            let be = match i / 4 {
+                0 => byte_enable & 0xf,
+                dw if dw == length - 1 => last,
+                _ => 0xf,
+            };
+            be & (1 << (i % 4)) != 0
+        })
+        .collect()
+}
+
+/// The DMA of a device model, issued on its lane: the handle builds the memory requests,
+/// hands out their tags and waits for the completions of the reads.
+///
+/// The completions come down the lane with the requests of the host, so a model using a handle
+/// takes its TLPs by [`DmaHandle::recv`], which also returns those arriving while a read waits.
+/// The handle owns the tags of the requester, the model must not issue reads of its own.
+pub struct DmaHandle<'a> {
+    lane: &'a PciLane,
+    requester: u16,
+    tags: TagPool,
+    timeout: Duration,
+    max_read_request: usize,
+    /// TLPs which arrived while a read waited for its completions.
+    deferred: VecDeque<Tlp>,
+    /// Tags of the reads which timed out, kept until their completions arrive.
+    abandoned: HashSet<u8>,
+}
+
+impl<'a> DmaHandle<'a> {
+    /// DMA of the function `requester` on `lane`, with 32 tags and the default completion
+    /// timeout.
+    pub fn new(lane: &'a PciLane, requester: u16) -> Self {
+        DmaHandle {
+            lane,
+            requester,
+            tags: TagPool::new(DEVICE_TAGS),
+            timeout: DEFAULT_COMPLETION_TIMEOUT,
+            max_read_request: MAX_READ_REQUEST,
+            deferred: VecDeque::new(),
+            abandoned: HashSet::new(),
+        }
+    }
+
+    /// Number of reads outstanding at the same time, e.g. 256 with the extended tags of
+    /// [`LaneInfo::features`].
+    pub fn tags(mut self, tags: usize) -> Self {
+        self.tags = TagPool::new(tags.clamp(1, 256));
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Largest read request in bytes, a power of 2 from 128 to 4096.
+    pub fn max_read_request(mut self, size: usize) -> Self {
+        self.max_read_request = size.clamp(128, MAX_READ_REQUEST).next_power_of_two();
+        self
+    }
+
+    /// The next TLP of the lane which is not a completion of the handle.
+    pub fn recv(&mut self) -> std::result::Result<Tlp, RecvError> {
+        if let Some(tlp) = self.deferred.pop_front() {
+            return Ok(tlp);
+        }
+
+        loop {
+            let tlp = self.lane.rx.recv()?;
+            if !self.stale(&tlp) {
+                return Ok(tlp);
+            }
+        }
+    }
+
+    /// Like [`DmaHandle::recv`], but gives up after `timeout`, e.g. for a model which also
+    /// polls a backend of its own.
+    pub fn recv_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> std::result::Result<Tlp, RecvTimeoutError> {
+        if let Some(tlp) = self.deferred.pop_front() {
+            return Ok(tlp);
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let tlp = self.lane.rx.recv_deadline(deadline)?;
+            if !self.stale(&tlp) {
+                return Ok(tlp);
+            }
+        }
+    }
+
+    /// Whether `tlp` completes a read which timed out, whose tag is free again.
+    fn stale(&mut self, tlp: &Tlp) -> bool {
+        match tlp.header._type {
+            PacketType::Completion(extra) | PacketType::CompletionData(extra)
+                if extra.requester == self.requester && self.abandoned.remove(&extra.tag) =>
+            {
+                self.tags.free(extra.tag);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Read `len` bytes at `addr`, in as many requests as it takes. The requests are
+    /// outstanding at the same time as far as the tags allow.
+    pub fn read(&mut self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        let parts = split_request(addr, len, self.max_read_request);
+        let deadline = Instant::now() + self.timeout;
+        let mut data = vec![0u8; len];
+        // Offset in `data` and length of the part of each tag.
+        let mut pending: HashMap<u8, (usize, usize)> = HashMap::new();
+        let mut result = Ok(());
+        let mut next = 0;
+
+        loop {
+            // Nothing more is sent once a part failed.
+            while next < parts.len() && result.is_ok() {
+                let tag = match self.tags.alloc() {
+                    Some(tag) => tag,
+                    None => break,
+                };
+                let (start, size) = parts[next];
+                self.send(self.read_request(tag, start, size))?;
+                pending.insert(tag, ((start - addr) as usize, size));
+                next += 1;
+            }
+            if pending.is_empty() {
+                break;
+            }
+
+            let tlp = match self.lane.rx.recv_deadline(deadline) {
+                Ok(tlp) => tlp,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.abandoned.extend(pending.keys());
+                    return Err(PciAdapterError::Timeout);
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(PciAdapterError::Disconnected),
+            };
+            if self.stale(&tlp) {
+                continue;
+            }
+            let extra = match tlp.header._type {
+                PacketType::Completion(extra) | PacketType::CompletionData(extra)
+                    if extra.requester == self.requester && pending.contains_key(&extra.tag) =>
+                {
+                    extra
+                }
+                _ => {
+                    self.deferred.push_back(tlp);
+                    continue;
+                }
+            };
+
+            let (offset, size) = pending.remove(&extra.tag).unwrap();
+            self.tags.free(extra.tag);
+            let part = match (extra.status, tlp.data.as_ref()) {
+                // The data starts with the DW of the lower address.
+                (CPL_SC, Some(dw))
+                    if dw.len() * 4 >= (extra.lower_address & 0b11) as usize + size =>
+                {
+                    let skip = (extra.lower_address & 0b11) as usize;
+                    Ok(dw
+                        .iter()
+                        .flat_map(|dw| dw.to_be_bytes())
+                        .skip(skip)
+                        .take(size)
+                        .collect::<Vec<u8>>())
+                }
+                (CPL_SC, _) => Err(PciAdapterError::MalformedCompletion),
+                (status, _) => Err(PciAdapterError::Completion(status)),
+            };
+            match part {
+                Ok(bytes) => data[offset..offset + size].copy_from_slice(&bytes),
+                Err(err) => {
+                    error!(
+                        "DMA read of {} bytes at {:#x} by {:#x} failed: {}",
+                        len, addr, self.requester, err
+                    );
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+
+        result.map(|()| data)
+    }
+
+    /// Write `data` at `addr`. Writes are posted, nothing tells whether they went through.
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<()> {
+        for (start, size) in split_request(addr, data.len(), PAGE_SIZE) {
+            let offset = (start & 0b11) as usize;
+            let at = (start - addr) as usize;
+            let mut bytes = vec![0u8; (offset + size + 3) & !0b11];
+            bytes[offset..offset + size].copy_from_slice(&data[at..at + size]);
+            let dw = bytes
+                .chunks(4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+
+            let builder = if start >> 32 == 0 {
+                TlpBuilder::memory_write(MemoryExtra {
+                    requester: self.requester,
+                    tag: 0,
+                    addr: start as u32 & !0b11,
+                })
+            } else {
+                TlpBuilder::memory_write64(Memory64Extra {
+                    requester: self.requester,
+                    tag: 0,
+                    addr: start & !0b11,
+                })
+            };
+            let tlp = builder
+                .byte_enable(write_byte_enable(offset, size))
+                .data(dw)
+                .build();
+            self.send(tlp)?;
+        }
+        Ok(())
+    }
+
+    fn read_request(&self, tag: u8, addr: u64, len: usize) -> Tlp {
+        let offset = (addr & 0b11) as usize;
+        let builder = if addr >> 32 == 0 {
+            TlpBuilder::memory_read(MemoryExtra {
+                requester: self.requester,
+                tag,
+                addr: addr as u32 & !0b11,
+            })
+        } else {
+            TlpBuilder::memory_read64(Memory64Extra {
+                requester: self.requester,
+                tag,
+                addr: addr & !0b11,
+            })
+        };
+
+        // A length of 0 stands for 1024 DWs.
+        builder
+            .byte_enable(write_byte_enable(offset, len))
+            .length((offset + len).div_ceil(4) as u16 & 0x3ff)
+            .build()
+    }
+
+    fn send(&self, tlp: Tlp) -> Result<()> {
+        self.lane
+            .tx
+            .send(tlp)
+            .map_err(|_| PciAdapterError::Disconnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_enables() {
+        assert_eq!(enabled_bytes(1, 0x05), [true, false, true, false]);
+        assert_eq!(
+            enabled_bytes(2, 0x3e),
+            [false, true, true, true, true, true, false, false]
+        );
+        assert_eq!(enabled_bytes(3, 0xfc).iter().filter(|&&b| b).count(), 10);
+    }
+
+    #[test]
+    fn hotplugged_memory() {
+        use std::sync::Arc;
+        use vm_memory::{GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+
+        let memory = GuestMemoryAtomic::new(
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap(),
+        );
+        let dma: Box<dyn DmaMemory> = Box::new(memory.clone());
+        dma.write(0x10, &[1, 2, 3, 4]).unwrap();
+        let mut data = [0; 4];
+        dma.read(0x10, &mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert!(dma.read(0x10_0000, &mut data).is_err());
+
+        // The VMM adds RAM, the bridge sees it on its next access.
+        let region =
+            GuestRegionMmap::new(MmapRegion::new(0x1000).unwrap(), GuestAddress(0x10_0000))
+                .unwrap();
+        let grown = memory.memory().insert_region(Arc::new(region)).unwrap();
+        memory.lock().unwrap().replace(grown);
+        dma.write(0x10_0ffc, &[5, 6, 7, 8]).unwrap();
+        dma.read(0x10_0ffc, &mut data).unwrap();
+        assert_eq!(data, [5, 6, 7, 8]);
+        dma.read(0x10, &mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+    }
+}
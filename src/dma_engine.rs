@@ -0,0 +1,407 @@
+//! A scatter-gather DMA engine for device models to embed.
+//!
+//! The engine walks a chain of descriptors in guest memory, moves the data of each one between
+//! guest memory and the memory of the device through a [`DmaHandle`], writes the status back
+//! into the descriptor and raises an MSI-X vector when the chain is done. A model maps the
+//! registers of the engine somewhere into a BAR, forwards their accesses to
+//! [`DmaEngine::read_register`] and [`DmaEngine::write_register`], and calls
+//! [`DmaEngine::poll`] from its run loop.
+//!
+//! A descriptor is 32 bytes, little endian:
+//!
+//! | Offset | Field |
+//! |--------|-------|
+//! | 0x00 | Source address |
+//! | 0x08 | Destination address |
+//! | 0x10 | Length in bytes |
+//! | 0x14 | Control, see `DESC_*`, with the status written back into the upper bits |
+//! | 0x18 | Address of the next descriptor, 0 ends the chain |
+//!
+//! The registers, accessed as 32-bit words:
+//!
+//! | Offset | Register |
+//! |--------|----------|
+//! | 0x00 | Address of the first descriptor, low |
+//! | 0x04 | Address of the first descriptor, high |
+//! | 0x08 | Control, see `CONTROL_*` |
+//! | 0x0c | Status, see `STATUS_*`, write 1 to clear |
+//! | 0x10 | Descriptors completed since reset (RO) |
+
+use crate::*;
+
+use crate::adapter::Result;
+
+pub const DESC_SIZE: usize = 32;
+/// The source is in the memory of the device.
+pub const DESC_SRC_LOCAL: u32 = 1 << 0;
+/// The destination is in the memory of the device.
+pub const DESC_DST_LOCAL: u32 = 1 << 1;
+/// Raise the interrupt once the descriptor is done, besides at the end of the chain.
+pub const DESC_INTERRUPT: u32 = 1 << 2;
+/// Written back when the data of the descriptor is moved.
+pub const DESC_DONE: u32 = 1 << 31;
+/// Written back with [`DESC_DONE`] when the descriptor failed, which stops the chain.
+pub const DESC_ERROR: u32 = 1 << 30;
+
+pub const REG_DESC_LO: u64 = 0x00;
+pub const REG_DESC_HI: u64 = 0x04;
+pub const REG_CONTROL: u64 = 0x08;
+pub const REG_STATUS: u64 = 0x0c;
+pub const REG_COMPLETED: u64 = 0x10;
+/// Bytes of the registers.
+pub const REGS_SIZE: u64 = 0x20;
+
+/// Start the chain at the descriptor address, cleared by the engine when it takes it.
+pub const CONTROL_START: u32 = 1 << 0;
+/// Raise the interrupt when the chain is done.
+pub const CONTROL_INTERRUPT: u32 = 1 << 1;
+
+/// A chain is running.
+pub const STATUS_BUSY: u32 = 1 << 0;
+pub const STATUS_DONE: u32 = 1 << 1;
+pub const STATUS_ERROR: u32 = 1 << 2;
+
+/// Most descriptors of a chain, so a chain looping back on itself ends.
+const MAX_CHAIN: u64 = 1 << 16;
+
+/// The memory of the device the engine moves data from and to.
+pub trait LocalMemory {
+    /// Read `data.len()` bytes at `addr`, false if they are out of range.
+    fn read(&mut self, addr: u64, data: &mut [u8]) -> bool;
+
+    /// Write `data` at `addr`, false if it is out of range.
+    fn write(&mut self, addr: u64, data: &[u8]) -> bool;
+}
+
+impl LocalMemory for Vec<u8> {
+    fn read(&mut self, addr: u64, data: &mut [u8]) -> bool {
+        match self.get(addr as usize..addr as usize + data.len()) {
+            Some(bytes) => {
+                data.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> bool {
+        match self.get_mut(addr as usize..addr as usize + data.len()) {
+            Some(bytes) => {
+                bytes.copy_from_slice(data);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A descriptor of the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Descriptor {
+    pub src: u64,
+    pub dst: u64,
+    pub len: u32,
+    pub control: u32,
+    pub next: u64,
+}
+
+impl Descriptor {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let u64_at = |at: usize| {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&bytes[at..at + 8]);
+            u64::from_le_bytes(b)
+        };
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        Descriptor {
+            src: u64_at(0x00),
+            dst: u64_at(0x08),
+            len: u32_at(0x10),
+            control: u32_at(0x14),
+            next: u64_at(0x18),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; DESC_SIZE] {
+        let mut bytes = [0u8; DESC_SIZE];
+        bytes[0x00..0x08].copy_from_slice(&self.src.to_le_bytes());
+        bytes[0x08..0x10].copy_from_slice(&self.dst.to_le_bytes());
+        bytes[0x10..0x14].copy_from_slice(&self.len.to_le_bytes());
+        bytes[0x14..0x18].copy_from_slice(&self.control.to_le_bytes());
+        bytes[0x18..0x20].copy_from_slice(&self.next.to_le_bytes());
+        bytes
+    }
+}
+
+/// A DMA engine with its registers.
+#[derive(Debug, Default)]
+pub struct DmaEngine {
+    desc: u64,
+    control: u32,
+    status: u32,
+    completed: u32,
+    /// The MSI-X vector of the interrupt, none raises nothing.
+    vector: Option<u16>,
+}
+
+impl DmaEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Raise MSI-X `vector` for the interrupts of the engine. A model with another kind of
+    /// interrupt looks at the return of [`DmaEngine::poll`] instead.
+    pub fn msix_vector(mut self, vector: u16) -> Self {
+        self.vector = Some(vector);
+        self
+    }
+
+    /// Back to the state at reset, with the vector kept.
+    pub fn reset(&mut self) {
+        *self = DmaEngine {
+            vector: self.vector,
+            ..Default::default()
+        };
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    pub fn read_register(&self, offset: u64) -> u32 {
+        match offset {
+            REG_DESC_LO => self.desc as u32,
+            REG_DESC_HI => (self.desc >> 32) as u32,
+            REG_CONTROL => self.control,
+            REG_STATUS => self.status,
+            REG_COMPLETED => self.completed,
+            _ => 0,
+        }
+    }
+
+    pub fn write_register(&mut self, offset: u64, value: u32) {
+        match offset {
+            REG_DESC_LO => self.desc = (self.desc & !0xffff_ffff) | value as u64,
+            REG_DESC_HI => self.desc = (self.desc & 0xffff_ffff) | (value as u64) << 32,
+            REG_CONTROL => self.control = value,
+            REG_STATUS => self.status &= !(value & (STATUS_DONE | STATUS_ERROR)),
+            _ => (),
+        }
+    }
+
+    /// Run the chain the driver started, if any. Returns whether an interrupt is due, which
+    /// is already raised if the engine has a vector. Only losing the lane fails.
+    pub fn poll(
+        &mut self,
+        dma: &mut DmaHandle,
+        irq: &IrqHandle,
+        local: &mut dyn LocalMemory,
+    ) -> Result<bool> {
+        if self.control & CONTROL_START == 0 {
+            return Ok(false);
+        }
+        self.control &= !CONTROL_START;
+        self.status = (self.status | STATUS_BUSY) & !(STATUS_DONE | STATUS_ERROR);
+
+        let result = self.run_chain(dma, local);
+        self.status &= !STATUS_BUSY;
+        let interrupt = match result {
+            Ok((failed, interrupt)) => {
+                self.status |= if failed {
+                    STATUS_DONE | STATUS_ERROR
+                } else {
+                    STATUS_DONE
+                };
+                interrupt || self.control & CONTROL_INTERRUPT != 0
+            }
+            Err(PciAdapterError::Disconnected) => return Err(PciAdapterError::Disconnected),
+            Err(err) => {
+                error!("DMA engine failed to fetch a descriptor: {}", err);
+                self.status |= STATUS_DONE | STATUS_ERROR;
+                self.control & CONTROL_INTERRUPT != 0
+            }
+        };
+
+        if let (true, Some(vector)) = (interrupt, self.vector) {
+            irq.raise_msix(vector)?;
+        }
+        Ok(interrupt)
+    }
+
+    /// Walk the chain, returns whether a descriptor failed and whether one asked for an
+    /// interrupt. Fails if a descriptor could not be fetched or written back.
+    fn run_chain(
+        &mut self,
+        dma: &mut DmaHandle,
+        local: &mut dyn LocalMemory,
+    ) -> Result<(bool, bool)> {
+        let mut addr = self.desc;
+        let mut interrupt = false;
+
+        for _ in 0..MAX_CHAIN {
+            if addr == 0 {
+                return Ok((false, interrupt));
+            }
+            let mut desc = Descriptor::from_bytes(&dma.read(addr, DESC_SIZE)?);
+            let moved = self.transfer(dma, local, &desc);
+            if let Err(PciAdapterError::Disconnected) = moved {
+                return Err(PciAdapterError::Disconnected);
+            }
+
+            desc.control |= DESC_DONE;
+            if moved.is_err() {
+                desc.control |= DESC_ERROR;
+            }
+            dma.write(addr + 0x14, &desc.control.to_le_bytes())?;
+            self.completed = self.completed.wrapping_add(1);
+            interrupt |= desc.control & DESC_INTERRUPT != 0;
+            if moved.is_err() {
+                return Ok((true, interrupt));
+            }
+            addr = desc.next;
+        }
+
+        error!("DMA engine chain at {:#x} does not end", self.desc);
+        Ok((true, interrupt))
+    }
+
+    /// Move the data of `desc`.
+    fn transfer(
+        &self,
+        dma: &mut DmaHandle,
+        local: &mut dyn LocalMemory,
+        desc: &Descriptor,
+    ) -> Result<()> {
+        let mut data = vec![0u8; desc.len as usize];
+        if desc.control & DESC_SRC_LOCAL != 0 {
+            if !local.read(desc.src, &mut data) {
+                return Err(PciAdapterError::InvalidAddress(desc.src));
+            }
+        } else {
+            data = dma.read(desc.src, data.len())?;
+        }
+
+        if desc.control & DESC_DST_LOCAL != 0 {
+            if !local.write(desc.dst, &data) {
+                return Err(PciAdapterError::InvalidAddress(desc.dst));
+            }
+            Ok(())
+        } else {
+            dma.write(desc.dst, &data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crossbeam_channel::{unbounded, Receiver, Sender};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct VecMemory(Arc<Mutex<Vec<u8>>>);
+
+    impl DmaMemory for VecMemory {
+        fn read(&self, gpa: u64, data: &mut [u8]) -> io::Result<()> {
+            let gpa = gpa as usize;
+            data.copy_from_slice(&self.0.lock().unwrap()[gpa..gpa + data.len()]);
+            Ok(())
+        }
+
+        fn write(&self, gpa: u64, data: &[u8]) -> io::Result<()> {
+            let gpa = gpa as usize;
+            self.0.lock().unwrap()[gpa..gpa + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// A model which runs the chain at 0x1000 once told to, then sends its memory and the
+    /// registers.
+    struct Copier(Receiver<()>, Sender<(Vec<u8>, u32, u32, bool)>, u16);
+
+    impl PciSimDevice for Copier {
+        fn on_start(&mut self, info: &LaneInfo) {
+            self.2 = info.functions[0];
+        }
+
+        fn run(&mut self, lane: &PciLane) {
+            let mut dma = DmaHandle::new(lane, self.2);
+            let irq = IrqHandle::new(lane, self.2);
+            let mut local = vec![0u8; 0x100];
+            let mut engine = DmaEngine::new();
+            self.0.recv().unwrap();
+
+            engine.write_register(REG_DESC_LO, 0x1000);
+            engine.write_register(REG_CONTROL, CONTROL_START);
+            let interrupt = engine.poll(&mut dma, &irq, &mut local).unwrap();
+            let status = engine.read_register(REG_STATUS);
+            let completed = engine.read_register(REG_COMPLETED);
+            self.1.send((local, status, completed, interrupt)).unwrap();
+            while lane.rx.recv().is_ok() {}
+        }
+    }
+
+    #[test]
+    fn chain() {
+        let mut ram = vec![0u8; 0x4000];
+        ram[0x2000..0x2010].copy_from_slice(&[0xaa; 16]);
+        let descs = [
+            // Guest memory to the device, then back to the guest elsewhere.
+            Descriptor {
+                src: 0x2000,
+                dst: 0x40,
+                len: 16,
+                control: DESC_DST_LOCAL,
+                next: 0x1020,
+            },
+            Descriptor {
+                src: 0x40,
+                dst: 0x3000,
+                len: 16,
+                control: DESC_SRC_LOCAL | DESC_INTERRUPT,
+                next: 0x1040,
+            },
+            // Beyond the memory of the device, which fails the chain.
+            Descriptor {
+                src: 0x2000,
+                dst: 0x1000,
+                len: 16,
+                control: DESC_DST_LOCAL,
+                next: 0,
+            },
+        ];
+        for (i, desc) in descs.iter().enumerate() {
+            ram[0x1000 + i * DESC_SIZE..0x1000 + (i + 1) * DESC_SIZE]
+                .copy_from_slice(&desc.to_bytes());
+        }
+        let memory = VecMemory(Arc::new(Mutex::new(ram)));
+
+        let (start, started) = unbounded();
+        let (tx, rx) = unbounded();
+        let adapter = PciAdapter::start(Box::new(Copier(started, tx, 0)));
+        adapter.set_dma_memory(Box::new(memory.clone()));
+        // The bridge takes the memory before the model starts.
+        adapter.stats().unwrap();
+        start.send(()).unwrap();
+        let (local, status, completed, interrupt) = rx.recv().unwrap();
+
+        assert_eq!(local[0x40..0x50], [0xaa; 16]);
+        assert_eq!(status, STATUS_DONE | STATUS_ERROR);
+        assert_eq!(completed, 3);
+        assert!(interrupt);
+
+        let ram = memory.0.lock().unwrap();
+        assert_eq!(ram[0x3000..0x3010], [0xaa; 16]);
+        let control = |i: usize| Descriptor::from_bytes(&ram[0x1000 + i * DESC_SIZE..]).control;
+        assert_eq!(control(0), DESC_DST_LOCAL | DESC_DONE);
+        assert_eq!(control(2), DESC_DST_LOCAL | DESC_DONE | DESC_ERROR);
+        drop(ram);
+
+        adapter.stop();
+        adapter.join();
+    }
+}
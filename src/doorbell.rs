@@ -0,0 +1,295 @@
+//! Doorbell registers passed to the device model as kicks.
+//!
+//! Drivers kick the queues of a device by writing to doorbell registers in a BAR. Taking each
+//! kick through a VM exit and a memory write TLP is a lot of work for what is merely a
+//! notification. A device model marks these registers with [`Doorbells`], and the writes to
+//! them reach the model as kicks on a channel of their own. A kick carries the index of the
+//! doorbell, not the data written.
+//!
+//! With an [`IoEventManager`] set on the adapter, e.g. a [`VmIoEventManager`], the adapter
+//! registers an ioeventfd at each doorbell, so the guest kicks the model without exiting to the
+//! hypervisor. Kicks of an ioeventfd coalesce when the guest rings the doorbell again before the
+//! first kick is taken. Without a manager, [`PciAdapter::bar_mmio_write`] turns the writes to a
+//! doorbell into kicks.
+//!
+//! The bridge passes each kick on after the BAR writes it took before. A model which takes the
+//! TLPs on its lane before acting on a kick therefore sees the writes the driver issued before
+//! ringing the doorbell.
+
+use crate::*;
+
+use crate::hypervisor::{eventfd, signal};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// A doorbell register of a device model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Doorbell {
+    /// Index of the memory BAR holding the register, 0 to 5.
+    pub bar: usize,
+    /// Offset of the register in the BAR.
+    pub offset: u64,
+}
+
+/// The doorbells a device model exports, see [`PciSimDevice::doorbells`].
+#[derive(Debug, Clone)]
+pub struct Doorbells {
+    registers: Vec<Doorbell>,
+    kicks: Sender<usize>,
+}
+
+impl Doorbells {
+    /// The doorbells at `registers` and the channel the model takes its kicks from. Each kick is
+    /// the index of its doorbell in `registers`.
+    pub fn new(registers: Vec<Doorbell>) -> (Doorbells, Receiver<usize>) {
+        let (kicks, rx) = unbounded();
+        (Doorbells { registers, kicks }, rx)
+    }
+
+    pub fn registers(&self) -> &[Doorbell] {
+        &self.registers
+    }
+
+    /// The doorbell at `offset` of BAR `bar`, if any.
+    pub(crate) fn find(&self, bar: usize, offset: u64) -> Option<usize> {
+        self.registers
+            .iter()
+            .position(|r| r.bar == bar && r.offset == offset)
+    }
+
+    pub(crate) fn kicks(&self) -> Sender<usize> {
+        self.kicks.clone()
+    }
+}
+
+/// Hypervisor hook to signal an eventfd on guest writes to an address.
+pub trait IoEventManager: Send + Sync {
+    /// Signal the eventfd `event` on every guest write to the guest physical address `gpa`.
+    fn register(&mut self, gpa: u64, event: RawFd) -> io::Result<()>;
+
+    /// Remove a registration of [`IoEventManager::register`].
+    fn unregister(&mut self, gpa: u64, event: RawFd) -> io::Result<()>;
+}
+
+/// [`IoEventManager`] registering ioeventfds with the VM of a [`Hypervisor`].
+pub struct VmIoEventManager<H> {
+    vm: Arc<H>,
+}
+
+/// [`VmIoEventManager`] registering KVM ioeventfds.
+#[cfg(feature = "kvm")]
+pub type KvmIoEventManager = VmIoEventManager<kvm_ioctls::VmFd>;
+
+/// [`VmIoEventManager`] registering mshv ioeventfds.
+#[cfg(feature = "mshv")]
+pub type MshvIoEventManager = VmIoEventManager<mshv_ioctls::VmFd>;
+
+impl<H: Hypervisor> VmIoEventManager<H> {
+    pub fn new(vm: Arc<H>) -> Self {
+        VmIoEventManager { vm }
+    }
+}
+
+impl<H: Hypervisor> IoEventManager for VmIoEventManager<H> {
+    fn register(&mut self, gpa: u64, event: RawFd) -> io::Result<()> {
+        self.vm.register_ioevent(gpa, event)
+    }
+
+    fn unregister(&mut self, gpa: u64, event: RawFd) -> io::Result<()> {
+        self.vm.unregister_ioevent(gpa, event)
+    }
+}
+
+/// Take the count of an eventfd, whether it was signaled.
+fn drain(fd: RawFd) -> bool {
+    let mut count = 0u64;
+    let n = unsafe { libc::read(fd, &mut count as *mut u64 as *mut libc::c_void, 8) };
+    n == 8
+}
+
+/// The eventfds of the doorbells of a function and the thread passing their kicks on.
+pub(crate) struct DoorbellEvents {
+    events: Vec<RawFd>,
+    /// The guest physical address each eventfd is registered at.
+    registered: Vec<Option<u64>>,
+    /// Signaled to stop the thread.
+    exit: RawFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DoorbellEvents {
+    /// One eventfd for each of `count` doorbells, `kick` is called with the index of the
+    /// doorbell signaled.
+    pub(crate) fn new(count: usize, kick: impl Fn(usize) + Send + 'static) -> io::Result<Self> {
+        let mut this = DoorbellEvents {
+            events: vec![],
+            registered: vec![None; count],
+            exit: eventfd()?,
+            thread: None,
+        };
+        for _ in 0..count {
+            this.events.push(eventfd()?);
+        }
+
+        let mut fds: Vec<_> = this
+            .events
+            .iter()
+            .chain(std::iter::once(&this.exit))
+            .map(|&fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let thread = std::thread::Builder::new()
+            .name("pcie-doorbell".to_string())
+            .spawn(move || loop {
+                if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    error!("Failed to poll doorbells: {}", e);
+                    return;
+                }
+                if fds[count].revents != 0 {
+                    return;
+                }
+                for (index, fd) in fds[..count].iter().enumerate() {
+                    if fd.revents != 0 && drain(fd.fd) {
+                        kick(index);
+                    }
+                }
+            })?;
+        this.thread = Some(thread);
+        Ok(this)
+    }
+
+    /// Signal the eventfd of doorbell `index` on guest writes to `gpa`.
+    pub(crate) fn register(&mut self, manager: &mut dyn IoEventManager, index: usize, gpa: u64) {
+        match manager.register(gpa, self.events[index]) {
+            Ok(()) => self.registered[index] = Some(gpa),
+            Err(e) => error!("Failed to register doorbell {} at {:#x}: {}", index, gpa, e),
+        }
+    }
+
+    /// Remove the registrations of all doorbells.
+    pub(crate) fn unregister_all(&mut self, manager: &mut dyn IoEventManager) {
+        for (index, gpa) in self.registered.iter_mut().enumerate() {
+            if let Some(gpa) = gpa.take() {
+                if let Err(e) = manager.unregister(gpa, self.events[index]) {
+                    error!(
+                        "Failed to unregister doorbell {} at {:#x}: {}",
+                        index, gpa, e
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Drop for DoorbellEvents {
+    fn drop(&mut self) {
+        let _ = signal(self.exit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        for &fd in self.events.iter().chain(std::iter::once(&self.exit)) {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Records the registrations and lets the test play the guest writing to them.
+    #[derive(Clone, Default)]
+    struct Events(Arc<Mutex<HashMap<u64, RawFd>>>);
+
+    impl Events {
+        fn ring(&self, gpa: u64) {
+            signal(self.0.lock().unwrap()[&gpa]).unwrap();
+        }
+    }
+
+    impl IoEventManager for Events {
+        fn register(&mut self, gpa: u64, event: RawFd) -> io::Result<()> {
+            self.0.lock().unwrap().insert(gpa, event);
+            Ok(())
+        }
+
+        fn unregister(&mut self, gpa: u64, _: RawFd) -> io::Result<()> {
+            self.0.lock().unwrap().remove(&gpa);
+            Ok(())
+        }
+    }
+
+    /// [`PciTestDevice`] with a doorbell at offset 0x40 of BAR0.
+    struct Kicked(PciTestDevice, Doorbells);
+
+    impl PciSimDevice for Kicked {
+        fn run(&mut self, lane: &PciLane) {
+            self.0.run(lane)
+        }
+
+        fn doorbells(&self) -> Option<Doorbells> {
+            Some(self.1.clone())
+        }
+    }
+
+    #[test]
+    fn doorbells() {
+        let (doorbells, kicks) = Doorbells::new(vec![Doorbell {
+            bar: 0,
+            offset: 0x40,
+        }]);
+        let mut adapter = PciAdapter::start(Box::new(Kicked(PciTestDevice::new(), doorbells)));
+        let events = Events::default();
+        adapter.set_ioevent_manager(Box::new(events.clone()));
+
+        let mut next = 0xc000_0000;
+        adapter
+            .place_bars(|region| {
+                let start = GuestAddress(next);
+                next += region.length.max(0x1000);
+                Some(start)
+            })
+            .unwrap();
+        let bar0 = adapter.mmio_regions[0].start.raw_value();
+        assert_eq!(
+            events.0.lock().unwrap().keys().copied().collect::<Vec<_>>(),
+            vec![bar0 + 0x40]
+        );
+
+        // The ioeventfd kicks the model.
+        events.ring(bar0 + 0x40);
+        assert_eq!(kicks.recv_timeout(Duration::from_secs(1)), Ok(0));
+
+        // Writes the hypervisor still passes on kick the model as well, other writes do not.
+        adapter.bar_mmio_write(bar0 + 0x40, &1u32.to_le_bytes());
+        adapter.bar_mmio_write(bar0 + 0x44, &1u32.to_le_bytes());
+        assert_eq!(kicks.recv_timeout(Duration::from_secs(1)), Ok(0));
+        assert!(kicks.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // The doorbell follows BAR0.
+        PciDevice::move_bar(&mut adapter, bar0, 0xd000_0000).unwrap();
+        assert_eq!(
+            events.0.lock().unwrap().keys().copied().collect::<Vec<_>>(),
+            vec![0xd000_0040]
+        );
+        events.ring(0xd000_0040);
+        assert_eq!(kicks.recv_timeout(Duration::from_secs(1)), Ok(0));
+
+        adapter.stop();
+        adapter.join();
+    }
+}
@@ -0,0 +1,232 @@
+//! The DPI-C end of a lane, for an RTL simulator running a device model.
+//!
+//! With the `dpi` feature the crate exports C functions for the testbench of an endpoint IP,
+//! e.g. in Verilator, which links the crate as a static library. The testbench connects to a
+//! bridge started by [`PciAdapterBuilder::start_unix`] and moves the beats of
+//! [`crate::rtl`] on every clock. The SystemVerilog imports of a 256-bit interface are:
+//!
+//! - `function chandle pcie_tlp_dpi_open(string path, int unsigned width)`
+//! - `function int pcie_tlp_dpi_put(chandle lane, bit [255:0] data, int unsigned keep,
+//!   bit last)`
+//! - `function int pcie_tlp_dpi_get(chandle lane, output bit [255:0] data,
+//!   output int unsigned keep, output bit last)`
+//! - `function void pcie_tlp_dpi_close(chandle lane)`
+//!
+//! A packed `bit [32 * width - 1:0]` is passed as `width` u32s with bits 31:0 first, which is
+//! the DW order of a beat.
+
+use crate::*;
+
+use crate::rtl::MAX_BEAT_DWS;
+use crossbeam_channel::TryRecvError;
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+
+/// The lane of a testbench, behind the `chandle` of the imports.
+pub struct DpiLane {
+    lane: PciLane,
+    width: usize,
+    assembler: BeatAssembler,
+    /// Beats to the endpoint not taken yet.
+    beats: VecDeque<Beat>,
+}
+
+impl DpiLane {
+    /// Connect to the bridge listening on the Unix domain socket at `path`, for an interface
+    /// of `width` DWs.
+    pub fn connect(path: &str, width: usize) -> std::io::Result<DpiLane> {
+        if !(1..=MAX_BEAT_DWS).contains(&width) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "unsupported beat width",
+            ));
+        }
+        let (lane, _) = connect_unix(path, DeviceFeatures::default())?;
+        Ok(DpiLane {
+            lane,
+            width,
+            assembler: BeatAssembler::new(),
+            beats: VecDeque::new(),
+        })
+    }
+
+    /// Take a beat of the endpoint, returns false once the bridge is gone.
+    pub fn put(&mut self, beat: &Beat) -> bool {
+        match self.assembler.push(beat) {
+            Some(Ok(tlp)) => self.lane.tx.send(tlp).is_ok(),
+            Some(Err(e)) => {
+                error!("Malformed TLP from the simulator: {:?}", e);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// The next beat to the endpoint, if any. Fails once the bridge is gone.
+    pub fn get(&mut self) -> Result<Option<Beat>, TryRecvError> {
+        while self.beats.is_empty() {
+            let tlp = match self.lane.rx.try_recv() {
+                Ok(tlp) => tlp,
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            match Beat::split(&tlp, self.width) {
+                Ok(beats) => self.beats.extend(beats),
+                Err(e) => error!("{:?} dropped: {:?}", tlp.header._type, e),
+            }
+        }
+        Ok(self.beats.pop_front())
+    }
+}
+
+/// Connect to the bridge listening at `path`, see [`DpiLane::connect`]. Returns NULL on
+/// failure.
+///
+/// # Safety
+///
+/// `path` is a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pcie_tlp_dpi_open(path: *const c_char, width: u32) -> *mut DpiLane {
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    match DpiLane::connect(path, width as usize) {
+        Ok(lane) => Box::into_raw(Box::new(lane)),
+        Err(e) => {
+            error!("Failed to connect to the bridge at {}: {}", path, e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Pass a beat of the endpoint, returns 0, or -1 once the bridge is gone.
+///
+/// # Safety
+///
+/// `lane` comes from [`pcie_tlp_dpi_open`] and `data` holds the DWs of its width.
+#[no_mangle]
+pub unsafe extern "C" fn pcie_tlp_dpi_put(
+    lane: *mut DpiLane,
+    data: *const u32,
+    keep: u32,
+    last: u8,
+) -> c_int {
+    let lane = &mut *lane;
+    let beat = Beat {
+        data: std::slice::from_raw_parts(data, lane.width).to_vec(),
+        keep,
+        last: last != 0,
+    };
+    if lane.put(&beat) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Take the next beat to the endpoint, returns 1 if there is one, 0 if not and -1 once the
+/// bridge is gone.
+///
+/// # Safety
+///
+/// `lane` comes from [`pcie_tlp_dpi_open`], `data` has room for the DWs of its width.
+#[no_mangle]
+pub unsafe extern "C" fn pcie_tlp_dpi_get(
+    lane: *mut DpiLane,
+    data: *mut u32,
+    keep: *mut u32,
+    last: *mut u8,
+) -> c_int {
+    let lane = &mut *lane;
+    match lane.get() {
+        Ok(Some(beat)) => {
+            std::ptr::copy_nonoverlapping(beat.data.as_ptr(), data, lane.width);
+            *keep = beat.keep;
+            *last = beat.last as u8;
+            1
+        }
+        Ok(None) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Disconnect from the bridge.
+///
+/// # Safety
+///
+/// `lane` comes from [`pcie_tlp_dpi_open`] and is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn pcie_tlp_dpi_close(lane: *mut DpiLane) {
+    if !lane.is_null() {
+        drop(Box::from_raw(lane));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::device::{dispatch, Ids};
+    use std::ffi::CString;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn dpi_lane() {
+        let path = std::env::temp_dir().join(format!("pcie-tlp-dpi-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // The testbench of an endpoint of 4 DWs answers one config read.
+        let testbench = {
+            let path = CString::new(path.to_str().unwrap()).unwrap();
+            thread::spawn(move || unsafe {
+                let lane = loop {
+                    let lane = pcie_tlp_dpi_open(path.as_ptr(), 4);
+                    if !lane.is_null() {
+                        break lane;
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                };
+
+                let (endpoint, model) = PciLane::pair();
+                let mut assembler = BeatAssembler::new();
+                let (mut data, mut keep, mut last) = ([0u32; 4], 0, 0);
+                let tlp = loop {
+                    match pcie_tlp_dpi_get(lane, data.as_mut_ptr(), &mut keep, &mut last) {
+                        1 => {
+                            let beat = Beat {
+                                data: data.to_vec(),
+                                keep,
+                                last: last != 0,
+                            };
+                            if let Some(tlp) = assembler.push(&beat) {
+                                break tlp.unwrap();
+                            }
+                        }
+                        0 => thread::sleep(Duration::from_millis(1)),
+                        _ => panic!("bridge gone"),
+                    }
+                };
+
+                dispatch(&mut Ids(0x5678_1234), &model, &tlp, &mut 0);
+                let cpl = endpoint.rx.recv().unwrap();
+                for beat in Beat::split(&cpl, 4).unwrap() {
+                    let put =
+                        pcie_tlp_dpi_put(lane, beat.data.as_ptr(), beat.keep, beat.last as u8);
+                    assert_eq!(put, 0);
+                }
+                lane as usize
+            })
+        };
+
+        let adapter = PciAdapter::start_unix(&path).unwrap();
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+
+        adapter.stop();
+        adapter.join();
+        unsafe { pcie_tlp_dpi_close(testbench.join().unwrap() as *mut DpiLane) };
+        let _ = std::fs::remove_file(&path);
+    }
+}
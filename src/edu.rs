@@ -0,0 +1,421 @@
+//! Model of the `edu` educational device of QEMU.
+//!
+//! The device is what the guest side of many PCI driver tutorials is written against, so a
+//! guest booted with [`PciEduDevice`] behind an adapter runs an existing edu driver as an end to
+//! end test of the adapter. BAR0 is 1 MB of registers, laid out as QEMU documents them in
+//! `docs/specs/edu.rst`:
+//!
+//! | Offset | Register |
+//! |--------|----------|
+//! | 0x00 | Identification, `0x010000ed` (RO) |
+//! | 0x04 | Liveness check, reads the inverse of what is written |
+//! | 0x08 | Factorial of the value written |
+//! | 0x20 | Status, 0x80 raises an interrupt after each factorial |
+//! | 0x24 | Interrupt status (RO) |
+//! | 0x60 | Interrupt raise, sets the bits written in the interrupt status (WO) |
+//! | 0x64 | Interrupt acknowledge, clears the bits written (WO) |
+//! | 0x80 | DMA source address |
+//! | 0x88 | DMA destination address |
+//! | 0x90 | DMA transfer count |
+//! | 0x98 | DMA command: 0x1 starts, 0x2 transfers to RAM, 0x4 raises interrupt 0x100 after |
+//!
+//! The 4 KB DMA buffer of the device is at 0x40000 in the addresses of the DMA engine. The
+//! interrupt is an MSI once the guest enables it and INTA otherwise. The factorial and the
+//! DMA are done as their register is written, so the busy bits never read set.
+
+use crate::*;
+
+use crate::adapter::Result;
+use crate::device::dispatch;
+use pci::PciInterruptPin;
+use std::ops::Range;
+
+const EDU_VENDOR_ID: u16 = 0x1234;
+const EDU_DEVICE_ID: u16 = 0x11e8;
+const EDU_REVISION: u8 = 0x10;
+/// Version 1.0 in the identification register.
+const EDU_ID: u32 = 0x0100_00ed;
+const EDU_BAR_SIZE: u64 = 0x100000;
+
+const REG_ID: u64 = 0x00;
+const REG_LIVENESS: u64 = 0x04;
+const REG_FACTORIAL: u64 = 0x08;
+const REG_STATUS: u64 = 0x20;
+const REG_IRQ_STATUS: u64 = 0x24;
+const REG_IRQ_RAISE: u64 = 0x60;
+const REG_IRQ_ACK: u64 = 0x64;
+const REG_DMA_SRC: u64 = 0x80;
+const REG_DMA_DST: u64 = 0x88;
+const REG_DMA_COUNT: u64 = 0x90;
+const REG_DMA_CMD: u64 = 0x98;
+
+const STATUS_IRQ_FACTORIAL: u32 = 0x80;
+/// Interrupt status bit of a finished factorial.
+const IRQ_FACTORIAL: u32 = 0x1;
+/// Interrupt status bit of a finished DMA.
+const IRQ_DMA: u32 = 0x100;
+
+const DMA_START: u64 = 0x1;
+const DMA_TO_RAM: u64 = 0x2;
+const DMA_IRQ: u64 = 0x4;
+/// Where the DMA buffer is in the addresses of the DMA engine.
+const DMA_BUFFER: u64 = 0x40000;
+const DMA_BUFFER_SIZE: usize = 0x1000;
+/// The device only drives the low 28 bits of a DMA address.
+const DMA_MASK: u64 = (1 << 28) - 1;
+
+const MSI_ENABLE: u32 = 1 << 16;
+
+/// The QEMU edu device, see the [module documentation](crate::edu).
+pub struct PciEduDevice {
+    regs: EduRegisters,
+    bdf: u16,
+}
+
+impl PciEduDevice {
+    pub fn new() -> Self {
+        PciEduDevice {
+            regs: EduRegisters::new(),
+            bdf: 0,
+        }
+    }
+
+    /// Run the DMA the command register asks for.
+    fn run_dma(&mut self, dma: &mut DmaHandle) {
+        let regs = &mut self.regs;
+        let count = regs.dma_count as usize;
+        let result = if regs.dma_cmd & DMA_TO_RAM == 0 {
+            regs.buffer_range(regs.dma_dst, count).map(|range| {
+                dma.read(regs.dma_src & DMA_MASK, count)
+                    .map(|data| regs.buffer[range].copy_from_slice(&data))
+            })
+        } else {
+            regs.buffer_range(regs.dma_src, count)
+                .map(|range| dma.write(regs.dma_dst & DMA_MASK, &regs.buffer[range]))
+        };
+
+        match result {
+            Some(Ok(())) => (),
+            Some(Err(err)) => error!("edu DMA of {} bytes failed: {}", count, err),
+            None => error!(
+                "edu DMA of {} bytes from {:#x} to {:#x} is out of the buffer",
+                count, regs.dma_src, regs.dma_dst
+            ),
+        }
+
+        regs.dma_cmd &= !DMA_START;
+        if regs.dma_cmd & DMA_IRQ != 0 {
+            regs.raise_irq(IRQ_DMA);
+        }
+    }
+
+    /// Send the interrupts raised since the last call: an MSI for each raise while MSI is
+    /// enabled, the level of INTA otherwise.
+    fn update_irq(&mut self, irq: &IrqHandle) -> Result<()> {
+        let raised = std::mem::take(&mut self.regs.raised);
+        let regs = &self.regs;
+        let config = |reg| regs.config.read_config_register(reg);
+        if config(regs.msi_reg) & MSI_ENABLE != 0 {
+            if raised {
+                irq.raise_msi(config, 0)?;
+            }
+        } else {
+            irq.set_intx(config, regs.irq_status != 0)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for PciEduDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PciSimDevice for PciEduDevice {
+    fn on_start(&mut self, info: &LaneInfo) {
+        self.bdf = info.functions[0];
+    }
+
+    fn on_reset(&mut self, _kind: ResetKind) {
+        self.regs = EduRegisters::new();
+    }
+
+    fn run(&mut self, lane: &PciLane) {
+        let mut dma = DmaHandle::new(lane, self.bdf);
+        let irq = IrqHandle::new(lane, self.bdf);
+        let mut completer = self.bdf;
+
+        while let Ok(tlp) = dma.recv() {
+            dispatch(&mut self.regs, lane, &tlp, &mut completer);
+
+            if self.regs.dma_cmd & DMA_START != 0 {
+                self.run_dma(&mut dma);
+            }
+            if let Err(err) = self.update_irq(&irq) {
+                error!("edu interrupt failed: {}", err);
+            }
+        }
+    }
+}
+
+/// The config space and BAR0 of the device, which answer the requests of the bridge. Actions
+/// needing the lane are left to [`PciEduDevice::run`].
+struct EduRegisters {
+    config: PcieConfiguration,
+    /// Register index of the MSI capability.
+    msi_reg: usize,
+    liveness: u32,
+    factorial: u32,
+    status: u32,
+    irq_status: u32,
+    /// Whether an interrupt is raised which is not sent yet.
+    raised: bool,
+    dma_src: u64,
+    dma_dst: u64,
+    dma_count: u64,
+    dma_cmd: u64,
+    buffer: Vec<u8>,
+}
+
+impl EduRegisters {
+    fn new() -> Self {
+        let mut config = PciConfiguration::new(
+            EDU_VENDOR_ID,
+            EDU_DEVICE_ID,
+            EDU_REVISION,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0x1af4,
+            0x1100,
+            None,
+        );
+
+        let bar = PciBarConfiguration::new(
+            0,
+            EDU_BAR_SIZE,
+            PciBarRegionType::Memory32BitRegion,
+            PciBarPrefetchable::NotPrefetchable,
+        );
+        config.add_pci_bar(&bar).unwrap();
+        config.set_irq(0, PciInterruptPin::IntA);
+
+        let mut config = PcieConfiguration::new(config);
+        let msi = MsiCapability::new(1).address64();
+        let msi_reg = config.add_capability(&msi).unwrap() / 4;
+
+        EduRegisters {
+            config,
+            msi_reg,
+            liveness: 0,
+            factorial: 1,
+            status: 0,
+            irq_status: 0,
+            raised: false,
+            dma_src: 0,
+            dma_dst: 0,
+            dma_count: 0,
+            dma_cmd: 0,
+            buffer: vec![0; DMA_BUFFER_SIZE],
+        }
+    }
+
+    fn raise_irq(&mut self, bits: u32) {
+        self.irq_status |= bits;
+        self.raised = self.irq_status != 0;
+    }
+
+    /// The part of the DMA buffer at `addr` of the DMA engine, if `len` bytes fit there.
+    fn buffer_range(&self, addr: u64, len: usize) -> Option<Range<usize>> {
+        let start = addr.checked_sub(DMA_BUFFER)? as usize;
+        let end = start.checked_add(len)?;
+        if end > DMA_BUFFER_SIZE {
+            return None;
+        }
+        Some(start..end)
+    }
+
+    /// The offset of `addr` into BAR0. The BAR is aligned to its size.
+    fn offset(addr: u64) -> u64 {
+        addr & (EDU_BAR_SIZE - 1)
+    }
+
+    /// Whether an access of `len` bytes to `offset` is one the device takes: 4 bytes, or 8
+    /// bytes to the DMA registers.
+    fn valid(offset: u64, len: usize) -> bool {
+        len == 4 || (len == 8 && offset >= REG_DMA_SRC)
+    }
+}
+
+impl SimpleDevice for EduRegisters {
+    fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+        self.config.read_config_register(reg)
+    }
+
+    fn on_config_write(&mut self, _: u16, reg: usize, offset: u64, data: &[u8]) {
+        self.config.write_config_register(reg, offset, data);
+    }
+
+    fn on_mem_read(&mut self, addr: u64, data: &mut [u8]) -> std::result::Result<(), u8> {
+        let offset = Self::offset(addr);
+        let value = if !Self::valid(offset, data.len()) {
+            u64::MAX
+        } else {
+            match offset {
+                REG_ID => EDU_ID as u64,
+                REG_LIVENESS => self.liveness as u64,
+                REG_FACTORIAL => self.factorial as u64,
+                REG_STATUS => self.status as u64,
+                REG_IRQ_STATUS => self.irq_status as u64,
+                REG_DMA_SRC => self.dma_src,
+                REG_DMA_DST => self.dma_dst,
+                REG_DMA_COUNT => self.dma_count,
+                REG_DMA_CMD => self.dma_cmd,
+                _ => u64::MAX,
+            }
+        };
+
+        let len = data.len().min(8);
+        data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+        Ok(())
+    }
+
+    fn on_mem_write(&mut self, addr: u64, data: &[u8]) {
+        let offset = Self::offset(addr);
+        if !Self::valid(offset, data.len()) {
+            debug!(
+                "edu ignores a write of {} bytes at {:#x}",
+                data.len(),
+                offset
+            );
+            return;
+        }
+        let mut bytes = [0u8; 8];
+        bytes[..data.len()].copy_from_slice(data);
+        let value = u64::from_le_bytes(bytes);
+
+        match offset {
+            REG_LIVENESS => self.liveness = !(value as u32),
+            REG_FACTORIAL => {
+                self.factorial = (1..=value as u32).fold(1u32, |f, n| f.wrapping_mul(n));
+                if self.status & STATUS_IRQ_FACTORIAL != 0 {
+                    self.raise_irq(IRQ_FACTORIAL);
+                }
+            }
+            REG_STATUS => {
+                self.status =
+                    (self.status & !STATUS_IRQ_FACTORIAL) | (value as u32 & STATUS_IRQ_FACTORIAL)
+            }
+            REG_IRQ_RAISE => self.raise_irq(value as u32),
+            REG_IRQ_ACK => self.irq_status &= !(value as u32),
+            REG_DMA_SRC => self.dma_src = value,
+            REG_DMA_DST => self.dma_dst = value,
+            REG_DMA_COUNT => self.dma_count = value,
+            REG_DMA_CMD if value & DMA_START != 0 => self.dma_cmd = value,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::adapter::BAR0_REG;
+    use crossbeam_channel::{unbounded, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct VecMemory(Arc<Mutex<Vec<u8>>>);
+
+    impl DmaMemory for VecMemory {
+        fn read(&self, gpa: u64, data: &mut [u8]) -> std::io::Result<()> {
+            let gpa = gpa as usize;
+            data.copy_from_slice(&self.0.lock().unwrap()[gpa..gpa + data.len()]);
+            Ok(())
+        }
+
+        fn write(&self, gpa: u64, data: &[u8]) -> std::io::Result<()> {
+            let gpa = gpa as usize;
+            self.0.lock().unwrap()[gpa..gpa + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    struct ChannelSink(Sender<(u64, u32)>);
+
+    impl MsiSink for ChannelSink {
+        fn inject(&self, addr: u64, data: u32) -> std::io::Result<()> {
+            self.0.send((addr, data)).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn edu() {
+        let mut adapter = PciAdapter::start(Box::new(PciEduDevice::new()));
+        let (tx, rx) = unbounded();
+        adapter.set_msi_sink(Box::new(ChannelSink(tx)));
+        let mut ram = vec![0u8; 0x2000];
+        ram[0x1000..0x1010].copy_from_slice(&[0x5a; 16]);
+        let memory = VecMemory(Arc::new(Mutex::new(ram)));
+        adapter.set_dma_memory(Box::new(memory.clone()));
+
+        assert_eq!(adapter.config_read(0), 0x11e8_1234);
+        adapter.write_config_register(BAR0_REG, 0, &0xc000_0000u32.to_le_bytes());
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0xc000_0000),
+            length: EDU_BAR_SIZE,
+            type_: PciBarRegionType::Memory32BitRegion,
+            bar_reg: 0,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        let read = |adapter: &mut PciAdapter, offset: u64| {
+            let mut data = [0u8; 4];
+            adapter.bar_mmio_read(0xc000_0000 + offset, &mut data);
+            u32::from_le_bytes(data)
+        };
+        let write = |adapter: &mut PciAdapter, offset: u64, value: u64| {
+            adapter.bar_mmio_write(0xc000_0000 + offset, &value.to_le_bytes());
+        };
+
+        assert_eq!(read(&mut adapter, REG_ID), EDU_ID);
+        adapter.bar_mmio_write(0xc000_0000 + REG_LIVENESS, &0x1234u32.to_le_bytes());
+        assert_eq!(read(&mut adapter, REG_LIVENESS), !0x1234);
+        adapter.bar_mmio_write(0xc000_0000 + REG_FACTORIAL, &5u32.to_le_bytes());
+        assert_eq!(read(&mut adapter, REG_FACTORIAL), 120);
+
+        // Copy 16 bytes through the DMA buffer and back with an interrupt.
+        write(&mut adapter, REG_DMA_SRC, 0x1000);
+        write(&mut adapter, REG_DMA_DST, DMA_BUFFER);
+        write(&mut adapter, REG_DMA_COUNT, 16);
+        write(&mut adapter, REG_DMA_CMD, DMA_START);
+        write(&mut adapter, REG_DMA_SRC, DMA_BUFFER);
+        write(&mut adapter, REG_DMA_DST, 0x1100);
+        write(&mut adapter, REG_DMA_CMD, DMA_START | DMA_TO_RAM | DMA_IRQ);
+        assert_eq!(read(&mut adapter, REG_IRQ_STATUS), IRQ_DMA);
+        assert_eq!(memory.0.lock().unwrap()[0x1100..0x1110], [0x5a; 16]);
+        adapter.bar_mmio_write(0xc000_0000 + REG_IRQ_ACK, &IRQ_DMA.to_le_bytes());
+
+        // Raise the interrupt as an MSI once the guest enables it.
+        let cap = (adapter.config_read(13) & 0xfc) as usize >> 2;
+        adapter.write_config_register(cap + 1, 0, &0xfee0_1000u32.to_le_bytes());
+        adapter.write_config_register(cap + 3, 0, &0x4021u32.to_le_bytes());
+        adapter.write_config_register(cap, 2, &[0x81, 0x00]);
+        adapter.bar_mmio_write(0xc000_0000 + REG_IRQ_RAISE, &0x2u32.to_le_bytes());
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok((0xfee0_1000, 0x4021))
+        );
+        assert_eq!(read(&mut adapter, REG_IRQ_STATUS), 0x2);
+
+        adapter.stop();
+        adapter.join();
+    }
+}
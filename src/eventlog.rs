@@ -0,0 +1,348 @@
+/*!
+Machine readable event log of the bridge for post-mortem analysis.
+
+The bridge appends fixed size records into a ring which lives in a file mapped with
+`MAP_SHARED`. Every store goes to the page cache directly, so the log survives a crash of the
+hypervisor process and the last moments of device activity could be reconstructed by the
+`tlp-eventlog` tool afterwards.
+
+# Format
+
+All integers are little endian. The file starts with a 64 bytes header:
+
+| offset | size | field                                    |
+|--------|------|------------------------------------------|
+| 0      | 8    | magic `PCIETLPL`                         |
+| 8      | 4    | version, currently 1                     |
+| 12     | 4    | record size, currently 64                |
+| 16     | 4    | capacity of the ring in records          |
+| 24     | 8    | sequence number of the next record       |
+
+Followed by `capacity` records of 64 bytes. Record with sequence number `seq` (starting from
+1) is stored in slot `(seq - 1) % capacity`:
+
+| offset | size | field                                                   |
+|--------|------|---------------------------------------------------------|
+| 0      | 8    | sequence number, 0 marks an empty or torn record        |
+| 8      | 8    | timestamp in nanoseconds since the UNIX epoch           |
+| 16     | 1    | [`EventKind`]                                           |
+| 18     | 2    | valid bytes of the payload                              |
+| 20     | 4    | argument, TLP length in bytes or the transaction ID     |
+| 24     | 40   | payload, the (truncated) wire format of the TLP         |
+
+The sequence number of a record is cleared before and written after the rest of the record,
+thus a record torn by a crash is simply skipped by the reader.
+*/
+
+use crate::*;
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"PCIETLPL";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 64;
+const RECORD_SIZE: usize = 64;
+const PAYLOAD_SIZE: usize = RECORD_SIZE - 24;
+
+/// The kind of a recorded event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    /// A TLP sent from the bridge to the device.
+    TlpSent,
+    /// A TLP received by the bridge from the device.
+    TlpReceived,
+    /// A non-posted transaction did not complete in time.
+    Timeout,
+    /// The device left the lane.
+    Disconnected,
+    /// A transaction completed with a non-successful status.
+    CompletionError,
+    /// The bridge started.
+    Started,
+    /// The bridge stopped.
+    Stopped,
+    /// A new lane is attached, the argument is the number of replayed transactions.
+    Reconnected,
+    /// A DMA of the device failed translation, the argument is the requester and the payload
+    /// the IOVA.
+    DmaFault,
+    /// The thread of a device model panicked, the argument is the BDF of the model.
+    DevicePanicked,
+    Unknown(u8),
+}
+
+impl From<u8> for EventKind {
+    fn from(value: u8) -> Self {
+        use EventKind::*;
+
+        match value {
+            1 => TlpSent,
+            2 => TlpReceived,
+            3 => Timeout,
+            4 => Disconnected,
+            5 => CompletionError,
+            6 => Started,
+            7 => Stopped,
+            8 => Reconnected,
+            9 => DmaFault,
+            10 => DevicePanicked,
+            v => Unknown(v),
+        }
+    }
+}
+
+impl From<EventKind> for u8 {
+    fn from(kind: EventKind) -> u8 {
+        use EventKind::*;
+
+        match kind {
+            TlpSent => 1,
+            TlpReceived => 2,
+            Timeout => 3,
+            Disconnected => 4,
+            CompletionError => 5,
+            Started => 6,
+            Stopped => 7,
+            Reconnected => 8,
+            DmaFault => 9,
+            DevicePanicked => 10,
+            Unknown(v) => v,
+        }
+    }
+}
+
+/// A single record recovered from the log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventRecord {
+    pub seq: u64,
+    /// Nanoseconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub kind: EventKind,
+    pub arg: u32,
+    pub payload: Vec<u8>,
+}
+
+impl EventRecord {
+    /// Decode the TLP carried by the record if it is not truncated.
+    pub fn tlp(&self) -> Option<Tlp> {
+        match self.kind {
+            EventKind::TlpSent | EventKind::TlpReceived => Tlp::from_bytes(&self.payload).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Writer side of the event log, owned by the bridge thread.
+#[derive(Debug)]
+pub struct EventLog {
+    _file: File,
+    map: *mut u8,
+    capacity: u64,
+    next: u64,
+}
+
+// The mapping is exclusively owned by the log.
+unsafe impl Send for EventLog {}
+
+impl EventLog {
+    /// Create the log file at `path` with room for `capacity` records, any existing file is
+    /// truncated.
+    pub fn create<P: AsRef<Path>>(path: P, capacity: u32) -> io::Result<EventLog> {
+        if capacity == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "zero capacity"));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let len = HEADER_SIZE + RECORD_SIZE * capacity as usize;
+        file.set_len(len as u64)?;
+
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut log = EventLog {
+            _file: file,
+            map: map as *mut u8,
+            capacity: capacity as u64,
+            next: 1,
+        };
+
+        let header = log.slice(0, HEADER_SIZE);
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(RECORD_SIZE as u32).to_le_bytes());
+        header[16..20].copy_from_slice(&capacity.to_le_bytes());
+        header[24..32].copy_from_slice(&1u64.to_le_bytes());
+
+        Ok(log)
+    }
+
+    fn len(&self) -> usize {
+        HEADER_SIZE + RECORD_SIZE * self.capacity as usize
+    }
+
+    fn slice(&mut self, offset: usize, len: usize) -> &mut [u8] {
+        assert!(offset + len <= self.len());
+        unsafe { std::slice::from_raw_parts_mut(self.map.add(offset), len) }
+    }
+
+    /// Append a record, overwriting the oldest one when the ring is full.
+    pub fn append(&mut self, kind: EventKind, arg: u32, payload: &[u8]) {
+        let seq = self.next;
+        let slot = ((seq - 1) % self.capacity) as usize;
+        let record = self.slice(HEADER_SIZE + slot * RECORD_SIZE, RECORD_SIZE);
+        let len = payload.len().min(PAYLOAD_SIZE);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        record[0..8].copy_from_slice(&0u64.to_le_bytes());
+        record[8..16].copy_from_slice(&timestamp.to_le_bytes());
+        record[16] = kind.into();
+        record[17] = 0;
+        record[18..20].copy_from_slice(&(len as u16).to_le_bytes());
+        record[20..24].copy_from_slice(&arg.to_le_bytes());
+        record[24..24 + len].copy_from_slice(&payload[..len]);
+        record[24 + len..].fill(0);
+        std::sync::atomic::fence(std::sync::atomic::Ordering::Release);
+        record[0..8].copy_from_slice(&seq.to_le_bytes());
+
+        self.next += 1;
+        let next = self.next.to_le_bytes();
+        self.slice(24, 8).copy_from_slice(&next);
+    }
+
+    /// Append a TLP crossing the bridge.
+    pub fn append_tlp(&mut self, kind: EventKind, tlp: &Tlp) {
+        match tlp.to_bytes() {
+            Ok(buf) => self.append(kind, buf.len() as u32, &buf),
+            Err(_) => self.append(kind, 0, &[]),
+        }
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.len());
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Recover all valid records from a log file, ordered by sequence number.
+pub fn read_event_log<P: AsRef<Path>>(path: P) -> io::Result<Vec<EventRecord>> {
+    let buf = std::fs::read(path)?;
+
+    if buf.len() < HEADER_SIZE || &buf[0..8] != MAGIC {
+        return Err(invalid("not an event log"));
+    }
+
+    let u32_at = |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+
+    if u32_at(8) != VERSION || u32_at(12) as usize != RECORD_SIZE {
+        return Err(invalid("unsupported event log version"));
+    }
+
+    let capacity = u32_at(16) as usize;
+    if buf.len() < HEADER_SIZE + capacity * RECORD_SIZE {
+        return Err(invalid("truncated event log"));
+    }
+
+    let mut records: Vec<EventRecord> = (0..capacity)
+        .map(|slot| HEADER_SIZE + slot * RECORD_SIZE)
+        .filter(|&offset| u64_at(offset) != 0)
+        .map(|offset| {
+            let len = (u16::from_le_bytes([buf[offset + 18], buf[offset + 19]]) as usize)
+                .min(PAYLOAD_SIZE);
+            EventRecord {
+                seq: u64_at(offset),
+                timestamp: u64_at(offset + 8),
+                kind: EventKind::from(buf[offset + 16]),
+                arg: u32_at(offset + 20),
+                payload: buf[offset + 24..offset + 24 + len].to_vec(),
+            }
+        })
+        .collect();
+
+    records.sort_by_key(|r| r.seq);
+    Ok(records)
+}
+
+/// Keep only the records within `window` before the last one.
+pub fn last_events(records: &[EventRecord], window: Duration) -> &[EventRecord] {
+    let last = match records.last() {
+        Some(r) => r.timestamp,
+        None => return records,
+    };
+    let start = last.saturating_sub(window.as_nanos() as u64);
+    let idx = records
+        .iter()
+        .position(|r| r.timestamp >= start)
+        .unwrap_or(records.len());
+    &records[idx..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring() {
+        let path = std::env::temp_dir().join(format!("pcie-tlp-eventlog-{}", std::process::id()));
+        let tlp = TlpBuilder::completion_data(CompletionExtra {
+            requester: 0x10,
+            completer: 0x18,
+            tag: 1,
+            status: 0,
+            bcm: false,
+            byte_count: 4,
+            lower_address: 0,
+        })
+        .data(vec![0x1234_5678])
+        .build();
+
+        let mut log = EventLog::create(&path, 4).unwrap();
+        log.append(EventKind::Started, 0, &[]);
+        for id in 0..3 {
+            log.append(EventKind::Timeout, id, &[]);
+        }
+        log.append_tlp(EventKind::TlpReceived, &tlp);
+        drop(log);
+
+        let records = read_event_log(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].seq, 2);
+        assert_eq!(records[0].kind, EventKind::Timeout);
+        assert_eq!(records[2].arg, 2);
+        assert_eq!(records[3].tlp().unwrap().data, tlp.data);
+        assert_eq!(last_events(&records, Duration::from_secs(60)).len(), 4);
+    }
+}
@@ -0,0 +1,283 @@
+//! Error injection on device models.
+//!
+//! A [`FaultyDevice`] wraps a device model and sits on its lane. While the
+//! [`DeviceFaultInjector`] of the wrapper holds faults, the non-posted requests of the bridge
+//! are answered with them instead of the way the model would, so tests reach the error paths
+//! of the adapter and of the guest drivers with any model. Posted requests and the requests
+//! of the model itself pass untouched.
+
+use crate::*;
+
+use crate::device::{fail, non_posted};
+use crossbeam_channel::select;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// How a request is answered instead of by the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Completed with Completer Abort, the model does not see the request.
+    Abort,
+    /// Completed with Unsupported Request, the model does not see the request.
+    Unsupported,
+    /// The model completes the request, with the data of its completion poisoned.
+    Poisoned,
+    /// The model completes the request, but the Length field of its completion disagrees with
+    /// the payload.
+    Malformed,
+    /// Never completed, the model does not see the request.
+    Silence,
+}
+
+/// The faults of a [`FaultyDevice`], shared by the test and the thread of the model.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFaultInjector {
+    /// Each fault with the number of requests it still takes.
+    faults: Arc<Mutex<VecDeque<(Fault, usize)>>>,
+}
+
+impl DeviceFaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer the next `count` non-posted requests with `fault`, once the faults injected
+    /// before are used up.
+    pub fn inject(&self, fault: Fault, count: usize) {
+        if count > 0 {
+            self.faults.lock().unwrap().push_back((fault, count));
+        }
+    }
+
+    /// Drop the faults not used yet, the model answers all requests again.
+    pub fn clear(&self) {
+        self.faults.lock().unwrap().clear();
+    }
+
+    /// Number of requests still to be faulted.
+    pub fn pending(&self) -> usize {
+        self.faults.lock().unwrap().iter().map(|(_, n)| n).sum()
+    }
+
+    fn take(&self) -> Option<Fault> {
+        let mut faults = self.faults.lock().unwrap();
+        let (fault, count) = faults.front_mut()?;
+        let fault = *fault;
+        *count -= 1;
+        if *count == 0 {
+            faults.pop_front();
+        }
+        Some(fault)
+    }
+}
+
+/// A device model answering requests with the faults of its [`DeviceFaultInjector`].
+pub struct FaultyDevice<D> {
+    device: D,
+    injector: DeviceFaultInjector,
+    completer: u16,
+}
+
+impl<D: PciSimDevice + Send> FaultyDevice<D> {
+    pub fn new(device: D) -> Self {
+        FaultyDevice {
+            device,
+            injector: DeviceFaultInjector::new(),
+            completer: 0,
+        }
+    }
+
+    /// The injector of the model, which stays with the test once the model is started.
+    pub fn injector(&self) -> DeviceFaultInjector {
+        self.injector.clone()
+    }
+}
+
+/// Pass a request of the bridge to the model unless a fault takes it. The faults applied to
+/// the completion are kept by transaction ID.
+fn request(
+    injector: &DeviceFaultInjector,
+    completer: u16,
+    tlp: Tlp,
+    lane: &PciLane,
+    model: &PciLane,
+    faulted: &mut HashMap<u32, Fault>,
+) {
+    let (requester, tag) = match non_posted(&tlp) {
+        Some(id) => id,
+        None => {
+            let _ = model.tx.send(tlp);
+            return;
+        }
+    };
+
+    match injector.take() {
+        Some(Fault::Abort) => fail(lane, &tlp, completer, CPL_CA),
+        Some(Fault::Unsupported) => fail(lane, &tlp, completer, CPL_UR),
+        Some(Fault::Silence) => debug!("drop {:?}", tlp.header._type),
+        Some(fault) => {
+            faulted.insert(tag as u32 | (requester as u32) << 16, fault);
+            let _ = model.tx.send(tlp);
+        }
+        None => {
+            let _ = model.tx.send(tlp);
+        }
+    }
+}
+
+/// Spoil the completion of a faulted request.
+fn spoil(tlp: &mut Tlp, fault: Fault) {
+    match fault {
+        Fault::Poisoned => tlp.header.poisoned_data = true,
+        Fault::Malformed => match tlp.data.as_mut() {
+            Some(_) => tlp.header.length = (tlp.header.length + 1) & 0x3ff,
+            None => tlp.data = Some(vec![0]),
+        },
+        _ => (),
+    }
+}
+
+impl<D: PciSimDevice + Send> PciSimDevice for FaultyDevice<D> {
+    fn run(&mut self, lane: &PciLane) {
+        let (model, inner) = PciLane::pair();
+        let FaultyDevice {
+            device,
+            injector,
+            completer,
+        } = self;
+        let mut faulted = HashMap::new();
+
+        std::thread::scope(|scope| {
+            // The model runs on a lane of its own, which ends with the lane of the bridge.
+            scope.spawn(move || device.run(&inner));
+            loop {
+                select! {
+                    recv(lane.rx) -> tlp => match tlp {
+                        Ok(tlp) => request(injector, *completer, tlp, lane, &model, &mut faulted),
+                        Err(_) => break,
+                    },
+                    recv(model.rx) -> tlp => match tlp {
+                        Ok(mut tlp) => {
+                            if let PacketType::Completion(_) | PacketType::CompletionData(_) =
+                                tlp.header._type
+                            {
+                                let fault = tlp
+                                    .header
+                                    .transaction_id()
+                                    .and_then(|trans_id| faulted.remove(&trans_id));
+                                if let Some(fault) = fault {
+                                    spoil(&mut tlp, fault);
+                                }
+                            }
+                            let _ = lane.tx.send(tlp);
+                        }
+                        Err(_) => break,
+                    },
+                }
+            }
+            drop(model);
+        });
+    }
+
+    fn features(&self) -> DeviceFeatures {
+        self.device.features()
+    }
+
+    fn shared_regions(&self) -> Vec<SharedRegion> {
+        self.device.shared_regions()
+    }
+
+    fn doorbells(&self) -> Option<Doorbells> {
+        self.device.doorbells()
+    }
+
+    fn state(&self) -> Option<Arc<dyn DeviceState>> {
+        self.device.state()
+    }
+
+    fn flow_control(&self) -> Option<FcCredits> {
+        self.device.flow_control()
+    }
+
+    fn on_start(&mut self, info: &LaneInfo) {
+        self.completer = info.functions[0];
+        self.device.on_start(info);
+    }
+
+    fn on_reset(&mut self, kind: ResetKind) {
+        self.device.on_reset(kind);
+    }
+
+    fn on_stop(&mut self) {
+        self.device.on_stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    /// Config register 0 reads the device and vendor ID, the others read 0.
+    struct Ids;
+
+    impl SimpleDevice for Ids {
+        fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+            if reg == 0 {
+                0x5678_1234
+            } else {
+                0
+            }
+        }
+
+        fn on_config_write(&mut self, _: u16, _: usize, _: u64, _: &[u8]) {}
+    }
+
+    #[test]
+    fn faults() {
+        let device = FaultyDevice::new(Ids);
+        let injector = device.injector();
+        let adapter = PciAdapterBuilder::new()
+            .completion_timeout(Duration::from_millis(100))
+            .start(Box::new(device));
+
+        injector.inject(Fault::Abort, 1);
+        injector.inject(Fault::Unsupported, 1);
+        injector.inject(Fault::Poisoned, 1);
+        injector.inject(Fault::Malformed, 2);
+        injector.inject(Fault::Silence, 1);
+        assert_eq!(injector.pending(), 6);
+
+        assert_eq!(
+            adapter.try_config_read(0),
+            Err(PciAdapterError::Completion(CPL_CA))
+        );
+        assert_eq!(
+            adapter.try_config_read(0),
+            Err(PciAdapterError::Completion(CPL_UR))
+        );
+        assert_eq!(
+            adapter.try_config_read(0),
+            Err(PciAdapterError::PoisonedCompletion)
+        );
+        assert_eq!(
+            adapter.try_config_read(0),
+            Err(PciAdapterError::MalformedCompletion)
+        );
+        assert_eq!(
+            adapter.try_config_write(1, 0, &[0x6]),
+            Err(PciAdapterError::MalformedCompletion)
+        );
+        assert_eq!(adapter.try_config_read(0), Err(PciAdapterError::Timeout));
+        assert_eq!(injector.pending(), 0);
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+
+        injector.inject(Fault::Abort, 3);
+        injector.clear();
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+
+        adapter.stop();
+        adapter.join();
+    }
+}
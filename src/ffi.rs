@@ -0,0 +1,517 @@
+//! A C API for device models written in C or C++, e.g. SystemC or in-house simulators.
+//!
+//! With the `ffi` feature the crate exports the functions declared in `include/pcie_tlp.h`.
+//! A model passes its callbacks in a [`DeviceOps`], which [`FfiDevice`] plugs into the
+//! adapter like any other [`PciSimDevice`]. The model sees its lane through an opaque
+//! `pcie_tlp_lane` handle and exchanges TLPs as a [`TlpDesc`] followed by the DWs of the
+//! payload. A simulator running in its own process connects with [`pcie_tlp_lane_connect`]
+//! instead.
+//!
+//! All functions return 0 on success, [`PCIE_TLP_GONE`] once the other end of the lane is
+//! gone, [`PCIE_TLP_INVALID`] for a TLP which can not be sent and [`PCIE_TLP_OVERFLOW`] for a
+//! TLP received with more payload than the caller has room for.
+
+use crate::*;
+
+use crossbeam_channel::RecvTimeoutError;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::time::Duration;
+
+pub const PCIE_TLP_GONE: c_int = -1;
+pub const PCIE_TLP_INVALID: c_int = -2;
+pub const PCIE_TLP_OVERFLOW: c_int = -3;
+
+/// Kinds of [`TlpDesc`]. Memory requests above 4GB use the 64-bit format on the wire.
+pub const PCIE_TLP_MEM_READ: u8 = 0;
+pub const PCIE_TLP_MEM_WRITE: u8 = 1;
+pub const PCIE_TLP_IO_READ: u8 = 2;
+pub const PCIE_TLP_IO_WRITE: u8 = 3;
+pub const PCIE_TLP_CFG_READ: u8 = 4;
+pub const PCIE_TLP_CFG_WRITE: u8 = 5;
+pub const PCIE_TLP_CPL: u8 = 6;
+pub const PCIE_TLP_CPL_DATA: u8 = 7;
+pub const PCIE_TLP_MSG: u8 = 8;
+pub const PCIE_TLP_MSG_DATA: u8 = 9;
+/// A TLP the C API has no fields for, only ever received.
+pub const PCIE_TLP_OTHER: u8 = 0xff;
+
+/// Bits of [`TlpDesc::attr`].
+pub const PCIE_TLP_ATTR_NO_SNOOP: u8 = 1 << 0;
+pub const PCIE_TLP_ATTR_RELAXED_ORDERING: u8 = 1 << 1;
+pub const PCIE_TLP_ATTR_ID_ORDERING: u8 = 1 << 2;
+pub const PCIE_TLP_ATTR_POISONED: u8 = 1 << 3;
+
+/// The header of a TLP as seen by C, `struct pcie_tlp_desc`. Fields which do not apply to
+/// the kind are 0. The layout only ever grows into `reserved`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TlpDesc {
+    pub kind: u8,
+    pub tag: u8,
+    pub requester: u16,
+    /// Completer of configuration requests and completions.
+    pub completer: u16,
+    /// DW index of configuration requests.
+    pub reg: u16,
+    /// Address of memory and I/O requests.
+    pub address: u64,
+    /// Length in DWs.
+    pub length: u16,
+    /// First DW byte enables in the lower 4 bits, last DW ones in the upper 4 bits.
+    pub byte_enable: u8,
+    /// Status of completions.
+    pub status: u8,
+    /// Byte count of completions.
+    pub byte_count: u16,
+    /// Lower address of completions.
+    pub lower_address: u8,
+    /// Code of messages.
+    pub message: u8,
+    pub attr: u8,
+    pub reserved: [u8; 7],
+}
+
+impl TlpDesc {
+    fn from_tlp(tlp: &Tlp) -> TlpDesc {
+        let header = &tlp.header;
+        let mut desc = TlpDesc {
+            length: header.length,
+            byte_enable: header.byte_enable,
+            ..Default::default()
+        };
+        let mut memory = |kind, requester, tag, address| {
+            desc.kind = kind;
+            desc.requester = requester;
+            desc.tag = tag;
+            desc.address = address;
+        };
+        match header._type {
+            PacketType::MemoryRead(e) => {
+                memory(PCIE_TLP_MEM_READ, e.requester, e.tag, e.addr as u64)
+            }
+            PacketType::MemoryRead64(e) => memory(PCIE_TLP_MEM_READ, e.requester, e.tag, e.addr),
+            PacketType::MemoryWrite(e) => {
+                memory(PCIE_TLP_MEM_WRITE, e.requester, e.tag, e.addr as u64)
+            }
+            PacketType::MemoryWrite64(e) => memory(PCIE_TLP_MEM_WRITE, e.requester, e.tag, e.addr),
+            PacketType::IoRead(e) => memory(PCIE_TLP_IO_READ, e.requester, e.tag, e.addr as u64),
+            PacketType::IoWrite(e) => memory(PCIE_TLP_IO_WRITE, e.requester, e.tag, e.addr as u64),
+            PacketType::Config0Read(e)
+            | PacketType::Config1Read(e)
+            | PacketType::Config0Write(e)
+            | PacketType::Config1Write(e) => {
+                desc.kind = match header._type {
+                    PacketType::Config0Read(_) | PacketType::Config1Read(_) => PCIE_TLP_CFG_READ,
+                    _ => PCIE_TLP_CFG_WRITE,
+                };
+                desc.requester = e.requester;
+                desc.completer = e.completer;
+                desc.tag = e.tag;
+                desc.reg = e.reg;
+            }
+            PacketType::Completion(e) | PacketType::CompletionData(e) => {
+                desc.kind = match header._type {
+                    PacketType::Completion(_) => PCIE_TLP_CPL,
+                    _ => PCIE_TLP_CPL_DATA,
+                };
+                desc.requester = e.requester;
+                desc.completer = e.completer;
+                desc.tag = e.tag;
+                desc.status = e.status;
+                desc.byte_count = e.byte_count;
+                desc.lower_address = e.lower_address;
+            }
+            PacketType::Message(code) => {
+                desc.kind = PCIE_TLP_MSG;
+                desc.message = code;
+            }
+            PacketType::MessageData(code) => {
+                desc.kind = PCIE_TLP_MSG_DATA;
+                desc.message = code;
+            }
+            _ => desc.kind = PCIE_TLP_OTHER,
+        }
+        for (set, bit) in [
+            (header.no_snoop, PCIE_TLP_ATTR_NO_SNOOP),
+            (header.relax_ordering, PCIE_TLP_ATTR_RELAXED_ORDERING),
+            (header.id_ordering, PCIE_TLP_ATTR_ID_ORDERING),
+            (header.poisoned_data, PCIE_TLP_ATTR_POISONED),
+        ] {
+            if set {
+                desc.attr |= bit;
+            }
+        }
+        desc
+    }
+
+    fn build(&self, data: &[u32]) -> Option<Tlp> {
+        let memory = MemoryExtra {
+            requester: self.requester,
+            tag: self.tag,
+            addr: self.address as u32,
+        };
+        let memory64 = Memory64Extra {
+            requester: self.requester,
+            tag: self.tag,
+            addr: self.address,
+        };
+        let config = ConfigExtra {
+            requester: self.requester,
+            completer: self.completer,
+            tag: self.tag,
+            reg: self.reg,
+        };
+        let completion = CompletionExtra {
+            requester: self.requester,
+            completer: self.completer,
+            tag: self.tag,
+            status: self.status,
+            bcm: false,
+            byte_count: self.byte_count,
+            lower_address: self.lower_address,
+        };
+        let above_4g = self.address >> 32 != 0;
+        let builder = match self.kind {
+            PCIE_TLP_MEM_READ if above_4g => TlpBuilder::memory_read64(memory64),
+            PCIE_TLP_MEM_READ => TlpBuilder::memory_read(memory),
+            PCIE_TLP_MEM_WRITE if above_4g => TlpBuilder::memory_write64(memory64),
+            PCIE_TLP_MEM_WRITE => TlpBuilder::memory_write(memory),
+            PCIE_TLP_IO_READ if !above_4g => TlpBuilder::io_read(memory),
+            PCIE_TLP_IO_WRITE if !above_4g => TlpBuilder::io_write(memory),
+            PCIE_TLP_CFG_READ => TlpBuilder::config0_read(config),
+            PCIE_TLP_CFG_WRITE => TlpBuilder::config0_write(config),
+            PCIE_TLP_CPL => TlpBuilder::completion(completion),
+            PCIE_TLP_CPL_DATA => TlpBuilder::completion_data(completion),
+            // INTx messages carry the requester, the bridge keeps the wire of each function.
+            PCIE_TLP_MSG if (MSG_ASSERT_INTA..MSG_DEASSERT_INTA + 4).contains(&self.message) => {
+                let message =
+                    MessageExtra::routed(self.requester, self.message, MessageRouting::Local);
+                TlpBuilder::message(message)
+            }
+            PCIE_TLP_MSG => TlpBuilder::with_type(PacketType::Message(self.message)),
+            PCIE_TLP_MSG_DATA => TlpBuilder::with_type(PacketType::MessageData(self.message)),
+            _ => return None,
+        };
+        let mut builder = builder.length(self.length).byte_enable(self.byte_enable);
+        if !data.is_empty() {
+            builder = builder.data(data.to_vec());
+        }
+        let mut tlp = builder.build();
+        tlp.header.no_snoop = self.attr & PCIE_TLP_ATTR_NO_SNOOP != 0;
+        tlp.header.relax_ordering = self.attr & PCIE_TLP_ATTR_RELAXED_ORDERING != 0;
+        tlp.header.id_ordering = self.attr & PCIE_TLP_ATTR_ID_ORDERING != 0;
+        tlp.header.poisoned_data = self.attr & PCIE_TLP_ATTR_POISONED != 0;
+        Some(tlp)
+    }
+}
+
+/// The callbacks of a device model, `struct pcie_tlp_device_ops`.
+///
+/// `run` serves the lane until the lane is gone, like [`PciSimDevice::run`]. `function` of
+/// `on_reset` is the BDF of a Function Level Reset, or -1 for a hot reset. `release` is called
+/// once the adapter is done with the model.
+#[repr(C)]
+pub struct DeviceOps {
+    pub ctx: *mut c_void,
+    pub run: unsafe extern "C" fn(ctx: *mut c_void, lane: *const PciLane),
+    pub on_start:
+        Option<unsafe extern "C" fn(ctx: *mut c_void, functions: *const u16, count: usize)>,
+    pub on_reset: Option<unsafe extern "C" fn(ctx: *mut c_void, function: i32)>,
+    pub release: Option<unsafe extern "C" fn(ctx: *mut c_void)>,
+}
+
+/// A device model behind the C API.
+pub struct FfiDevice {
+    ops: DeviceOps,
+}
+
+// The callbacks are only ever called from the thread the adapter runs the model on, one at a
+// time, as promised by `FfiDevice::new`.
+unsafe impl Send for FfiDevice {}
+unsafe impl Sync for FfiDevice {}
+
+impl FfiDevice {
+    /// Wrap the callbacks of a C model.
+    ///
+    /// # Safety
+    ///
+    /// The callbacks are valid until `release` and may be called from a thread other than the
+    /// caller's.
+    pub unsafe fn new(ops: DeviceOps) -> FfiDevice {
+        FfiDevice { ops }
+    }
+}
+
+impl PciSimDevice for FfiDevice {
+    fn run(&mut self, lane: &PciLane) {
+        unsafe { (self.ops.run)(self.ops.ctx, lane) }
+    }
+
+    fn on_start(&mut self, info: &LaneInfo) {
+        if let Some(on_start) = self.ops.on_start {
+            unsafe { on_start(self.ops.ctx, info.functions.as_ptr(), info.functions.len()) }
+        }
+    }
+
+    fn on_reset(&mut self, kind: ResetKind) {
+        if let Some(on_reset) = self.ops.on_reset {
+            let function = match kind {
+                ResetKind::FunctionLevel(bdf) => bdf as i32,
+                ResetKind::Hot => -1,
+            };
+            unsafe { on_reset(self.ops.ctx, function) }
+        }
+    }
+}
+
+impl Drop for FfiDevice {
+    fn drop(&mut self) {
+        if let Some(release) = self.ops.release {
+            unsafe { release(self.ops.ctx) }
+        }
+    }
+}
+
+/// Send a TLP with `count` DWs of payload at `data`, which may be NULL if there are none.
+///
+/// # Safety
+///
+/// `lane` is a live lane handle, `desc` is valid and `data` holds `count` DWs.
+#[no_mangle]
+pub unsafe extern "C" fn pcie_tlp_send(
+    lane: *const PciLane,
+    desc: *const TlpDesc,
+    data: *const u32,
+    count: usize,
+) -> c_int {
+    let data = if count == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, count)
+    };
+    let tlp = match (*desc).build(data) {
+        Some(tlp) => tlp,
+        None => return PCIE_TLP_INVALID,
+    };
+    match (*lane).tx.send(tlp) {
+        Ok(()) => 0,
+        Err(_) => PCIE_TLP_GONE,
+    }
+}
+
+/// Receive a TLP, waiting `timeout_us` microseconds at most, or forever if it is negative.
+/// Returns 1 if there is one, with its payload in `data`, and 0 if there is none yet. The
+/// `length` of `desc` tells the DWs of the payload. A payload of more than `capacity` DWs
+/// returns [`PCIE_TLP_OVERFLOW`] with only the first `capacity` DWs in `data`, the TLP is
+/// consumed all the same.
+///
+/// # Safety
+///
+/// `lane` is a live lane handle, `desc` is writable and `data` has room for `capacity` DWs.
+#[no_mangle]
+pub unsafe extern "C" fn pcie_tlp_recv(
+    lane: *const PciLane,
+    desc: *mut TlpDesc,
+    data: *mut u32,
+    capacity: usize,
+    timeout_us: i64,
+) -> c_int {
+    let rx = &(*lane).rx;
+    let tlp = if timeout_us < 0 {
+        match rx.recv() {
+            Ok(tlp) => tlp,
+            Err(_) => return PCIE_TLP_GONE,
+        }
+    } else {
+        match rx.recv_timeout(Duration::from_micros(timeout_us as u64)) {
+            Ok(tlp) => tlp,
+            Err(RecvTimeoutError::Timeout) => return 0,
+            Err(RecvTimeoutError::Disconnected) => return PCIE_TLP_GONE,
+        }
+    };
+    *desc = TlpDesc::from_tlp(&tlp);
+    let payload = tlp.data.as_deref().unwrap_or(&[]);
+    let count = payload.len().min(capacity);
+    if count > 0 {
+        std::ptr::copy_nonoverlapping(payload.as_ptr(), data, count);
+    }
+    if payload.len() > capacity {
+        return PCIE_TLP_OVERFLOW;
+    }
+    1
+}
+
+/// Raise an MSI, see [`PciLane::raise_msi`].
+///
+/// # Safety
+///
+/// `lane` is a live lane handle.
+#[no_mangle]
+pub unsafe extern "C" fn pcie_tlp_raise_msi(
+    lane: *const PciLane,
+    requester: u16,
+    addr: u64,
+    data: u32,
+) -> c_int {
+    match (*lane).raise_msi(requester, addr, data) {
+        Ok(()) => 0,
+        Err(_) => PCIE_TLP_GONE,
+    }
+}
+
+/// Connect to the bridge listening on the Unix domain socket at `path`, for a simulator in
+/// its own process. Returns NULL on failure.
+///
+/// # Safety
+///
+/// `path` is a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pcie_tlp_lane_connect(path: *const c_char) -> *mut PciLane {
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    match connect_unix(path, DeviceFeatures::default()) {
+        Ok((lane, _)) => Box::into_raw(Box::new(lane)),
+        Err(e) => {
+            error!("Failed to connect to the bridge at {}: {}", path, e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Disconnect a lane from [`pcie_tlp_lane_connect`]. The lane passed to `run` is owned by the
+/// adapter and never closed by the model.
+///
+/// # Safety
+///
+/// `lane` comes from [`pcie_tlp_lane_connect`] and is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn pcie_tlp_lane_close(lane: *mut PciLane) {
+    if !lane.is_null() {
+        drop(Box::from_raw(lane));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A C style model answering config reads of register 0 with its IDs.
+    unsafe extern "C" fn run(ctx: *mut c_void, lane: *const PciLane) {
+        let ids = *(ctx as *const u32);
+        let (mut desc, mut data) = (TlpDesc::default(), [0u32; 4]);
+        while pcie_tlp_recv(lane, &mut desc, data.as_mut_ptr(), data.len(), -1) == 1 {
+            if desc.kind != PCIE_TLP_CFG_READ && desc.kind != PCIE_TLP_CFG_WRITE {
+                continue;
+            }
+            let value = if desc.reg == 0 { ids } else { 0 };
+            let cpl = TlpDesc {
+                kind: if desc.kind == PCIE_TLP_CFG_READ {
+                    PCIE_TLP_CPL_DATA
+                } else {
+                    PCIE_TLP_CPL
+                },
+                requester: desc.requester,
+                completer: desc.completer,
+                tag: desc.tag,
+                length: (desc.kind == PCIE_TLP_CFG_READ) as u16,
+                byte_count: 4,
+                ..Default::default()
+            };
+            let count = cpl.length as usize;
+            if pcie_tlp_send(lane, &cpl, &value, count) != 0 {
+                break;
+            }
+        }
+    }
+
+    static RELEASED: AtomicBool = AtomicBool::new(false);
+
+    unsafe extern "C" fn release(_: *mut c_void) {
+        RELEASED.store(true, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(std::mem::size_of::<TlpDesc>(), 32);
+        assert_eq!(std::mem::align_of::<TlpDesc>(), 8);
+    }
+
+    #[test]
+    fn desc() {
+        let desc = TlpDesc {
+            kind: PCIE_TLP_MEM_WRITE,
+            requester: 0x0100,
+            tag: 3,
+            address: 0x1_0000_0000,
+            length: 1,
+            byte_enable: 0x0f,
+            attr: PCIE_TLP_ATTR_NO_SNOOP,
+            ..Default::default()
+        };
+        let tlp = desc.build(&[0x1234]).unwrap();
+        assert!(matches!(tlp.header._type, PacketType::MemoryWrite64(_)));
+        let back = TlpDesc::from_tlp(&tlp);
+        assert_eq!(back.kind, PCIE_TLP_MEM_WRITE);
+        assert_eq!(back.address, desc.address);
+        assert_eq!(back.attr, PCIE_TLP_ATTR_NO_SNOOP);
+
+        let io = TlpDesc {
+            kind: PCIE_TLP_IO_READ,
+            address: 0x1_0000_0000,
+            ..Default::default()
+        };
+        assert!(io.build(&[]).is_none());
+    }
+
+    #[test]
+    fn recv_overflow() {
+        let (lane, peer) = PciLane::pair();
+        let (mut desc, mut data) = (TlpDesc::default(), [0u32; 2]);
+        let write = |len| {
+            TlpBuilder::memory_write(MemoryExtra {
+                requester: 0,
+                tag: 0,
+                addr: 0x1000,
+            })
+            .data(vec![0x1234; len])
+            .build()
+        };
+
+        peer.tx.send(write(2)).unwrap();
+        let ret = unsafe { pcie_tlp_recv(&lane, &mut desc, data.as_mut_ptr(), 2, 0) };
+        assert_eq!((ret, desc.length), (1, 2));
+
+        peer.tx.send(write(3)).unwrap();
+        let ret = unsafe { pcie_tlp_recv(&lane, &mut desc, data.as_mut_ptr(), 2, 0) };
+        assert_eq!((ret, desc.length), (PCIE_TLP_OVERFLOW, 3));
+        assert_eq!(data, [0x1234; 2]);
+
+        // No payload and no room for one.
+        peer.tx.send(write(0)).unwrap();
+        let ret = unsafe { pcie_tlp_recv(&lane, &mut desc, std::ptr::null_mut(), 0, 0) };
+        assert_eq!(ret, 1);
+    }
+
+    #[test]
+    fn ffi_device() {
+        let ids = Box::new(0x5678_1234u32);
+        let ops = DeviceOps {
+            ctx: &*ids as *const u32 as *mut c_void,
+            run,
+            on_start: None,
+            on_reset: None,
+            release: Some(release),
+        };
+        let adapter = PciAdapter::start(Box::new(unsafe { FfiDevice::new(ops) }));
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+
+        adapter.stop();
+        adapter.join();
+        assert!(RELEASED.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,273 @@
+//! Credit based flow control of the lanes.
+//!
+//! A PCIe receiver advertises how many TLPs its buffers hold at link up (FC init), in header
+//! and data credits for each of the posted, non-posted and completion classes. A transmitter
+//! only sends a TLP if the receiver has the credits for it, the receiver gives them back with
+//! UpdateFC DLLPs as it empties its buffers.
+//!
+//! Lanes are unbounded channels unless told otherwise, so a device model never sees the back
+//! pressure of a real link. A model which wants to be tested against it advertises its
+//! credits by [`PciSimDevice::flow_control`]: the bridge then holds back the TLPs for which
+//! the model has no credits left. The TLPs queued on the lane stand for the receive buffers,
+//! the model returns their credits by taking them off the lane.
+
+use crate::*;
+
+use crate::ordering::passing;
+use crossbeam_channel::Sender;
+use std::collections::VecDeque;
+
+/// Flow control class of a TLP, which picks the credits it consumes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FcClass {
+    Posted,
+    NonPosted,
+    Completion,
+}
+
+impl FcClass {
+    pub fn of(tlp: &Tlp) -> FcClass {
+        use PacketType::*;
+
+        match tlp.header._type {
+            MemoryWrite(_) | MemoryWrite64(_) | Message(_) | MessageData(_) | RoutedMessage(_)
+            | RoutedMessageData(_) | PageRequest(_) | PrgResponse(_) => FcClass::Posted,
+            Completion(_) | CompletionData(_) | CompletionLocked(_) | CompletionLockedData(_) => {
+                FcClass::Completion
+            }
+            _ => FcClass::NonPosted,
+        }
+    }
+}
+
+/// Header credits and data credits of 4 DWs each of one class. 0 advertises infinite credits,
+/// like the spec does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FcCredit {
+    pub header: u32,
+    pub data: u32,
+}
+
+/// Credits advertised by a receiver at FC init.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FcCredits {
+    pub posted: FcCredit,
+    pub non_posted: FcCredit,
+    pub completion: FcCredit,
+}
+
+impl FcCredits {
+    fn class(&self, class: FcClass) -> &FcCredit {
+        match class {
+            FcClass::Posted => &self.posted,
+            FcClass::NonPosted => &self.non_posted,
+            FcClass::Completion => &self.completion,
+        }
+    }
+
+    fn class_mut(&mut self, class: FcClass) -> &mut FcCredit {
+        match class {
+            FcClass::Posted => &mut self.posted,
+            FcClass::NonPosted => &mut self.non_posted,
+            FcClass::Completion => &mut self.completion,
+        }
+    }
+}
+
+/// The header and data credits a TLP consumes.
+pub fn credits_of(tlp: &Tlp) -> FcCredit {
+    let dw = tlp.data.as_ref().map_or(0, |data| data.len() as u32);
+    FcCredit {
+        header: 1,
+        data: dw.div_ceil(4),
+    }
+}
+
+/// The transmitter side of the flow control of a link direction: the credits advertised by the
+/// receiver and those consumed by the TLPs sent since.
+#[derive(Debug, Clone, Default)]
+pub struct FlowControl {
+    limit: FcCredits,
+    consumed: FcCredits,
+}
+
+impl FlowControl {
+    /// FC init with the credits advertised by the receiver.
+    pub fn new(limit: FcCredits) -> Self {
+        FlowControl {
+            limit,
+            consumed: FcCredits::default(),
+        }
+    }
+
+    /// Whether the receiver has the credits for `tlp`. A TLP larger than all the data credits
+    /// of its class is let through once nothing else is in flight, it could never pass
+    /// otherwise.
+    pub fn can_send(&self, tlp: &Tlp) -> bool {
+        let class = FcClass::of(tlp);
+        let need = credits_of(tlp);
+        let limit = self.limit.class(class);
+        let consumed = self.consumed.class(class);
+
+        let fits = |limit: u32, consumed: u32, need: u32| {
+            limit == 0 || consumed + need <= limit || (consumed == 0 && need > limit)
+        };
+        fits(limit.header, consumed.header, need.header)
+            && fits(limit.data, consumed.data, need.data)
+    }
+
+    /// Consume the credits of `tlp`, which is sent.
+    pub fn consume(&mut self, tlp: &Tlp) {
+        let need = credits_of(tlp);
+        let consumed = self.consumed.class_mut(FcClass::of(tlp));
+        consumed.header += need.header;
+        consumed.data += need.data;
+    }
+
+    /// UpdateFC: the receiver freed `credit` of `class`.
+    pub fn update(&mut self, class: FcClass, credit: FcCredit) {
+        let consumed = self.consumed.class_mut(class);
+        consumed.header = consumed.header.saturating_sub(credit.header);
+        consumed.data = consumed.data.saturating_sub(credit.data);
+    }
+
+    /// The credits of each class which are not consumed, 0 for infinite ones.
+    pub fn available(&self) -> FcCredits {
+        let left = |class| {
+            let (limit, consumed): (&FcCredit, &FcCredit) =
+                (self.limit.class(class), self.consumed.class(class));
+            FcCredit {
+                header: limit.header.saturating_sub(consumed.header),
+                data: limit.data.saturating_sub(consumed.data),
+            }
+        };
+
+        FcCredits {
+            posted: left(FcClass::Posted),
+            non_posted: left(FcClass::NonPosted),
+            completion: left(FcClass::Completion),
+        }
+    }
+}
+
+/// Flow control of the lane to a device model, kept by the bridge.
+pub(crate) struct LaneFlow {
+    pub lane: Sender<Tlp>,
+    fc: FlowControl,
+    /// Credits of the TLPs on the lane, oldest first.
+    queued: VecDeque<(FcClass, FcCredit)>,
+    /// TLPs waiting for credits, in the order they are sent.
+    pub held: VecDeque<Tlp>,
+}
+
+impl LaneFlow {
+    pub fn new(lane: Sender<Tlp>, credits: FcCredits) -> Self {
+        LaneFlow {
+            lane,
+            fc: FlowControl::new(credits),
+            queued: VecDeque::new(),
+            held: VecDeque::new(),
+        }
+    }
+
+    /// Move to the new lane of a model which is reset, with the credits of FC init. The held
+    /// TLPs go on the new lane.
+    pub fn reset(&mut self, lane: Sender<Tlp>) {
+        self.lane = lane;
+        self.fc = FlowControl::new(self.fc.limit);
+        self.queued.clear();
+    }
+
+    /// Give back the credits of the TLPs the model took off the lane. The bridge is the only
+    /// sender on the lane, so these are the oldest ones.
+    fn drain(&mut self) {
+        while self.queued.len() > self.lane.len() {
+            let (class, credit) = self.queued.pop_front().unwrap();
+            self.fc.update(class, credit);
+        }
+    }
+
+    /// Consume the credits of `tlp` if it could go on the lane now. TLPs only pass the ones
+    /// held before them if the ordering rules require it, e.g. posted requests pass the
+    /// non-posted ones which wait for credits.
+    pub fn admit(&mut self, tlp: &Tlp) -> bool {
+        self.drain();
+        let passes = self
+            .held
+            .iter()
+            .all(|held| passing(tlp, held) == Passing::Must);
+        if !passes || !self.fc.can_send(tlp) {
+            return false;
+        }
+
+        self.fc.consume(tlp);
+        self.queued.push_back((FcClass::of(tlp), credits_of(tlp)));
+        true
+    }
+
+    /// The first held TLP there are credits for, if it may pass the ones held before it.
+    pub fn release(&mut self) -> Option<Tlp> {
+        self.drain();
+        let index = (0..self.held.len()).find(|&i| {
+            self.fc.can_send(&self.held[i])
+                && self
+                    .held
+                    .range(..i)
+                    .all(|held| passing(&self.held[i], held) == Passing::Must)
+        })?;
+
+        let tlp = self.held.remove(index).unwrap();
+        self.fc.consume(&tlp);
+        self.queued.push_back((FcClass::of(&tlp), credits_of(&tlp)));
+        Some(tlp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credits() {
+        let write = |dw: usize| {
+            TlpBuilder::memory_write64(Memory64Extra {
+                requester: 0x10,
+                tag: 0,
+                addr: 0x1000,
+            })
+            .byte_enable(0xff)
+            .data(vec![0; dw])
+            .build()
+        };
+        let read = TlpBuilder::memory_read64(Memory64Extra {
+            requester: 0x10,
+            tag: 1,
+            addr: 0x1000,
+        })
+        .byte_enable(0xff)
+        .length(2)
+        .build();
+        assert_eq!(FcClass::of(&write(1)), FcClass::Posted);
+        assert_eq!(FcClass::of(&read), FcClass::NonPosted);
+        assert_eq!(credits_of(&write(5)), FcCredit { header: 1, data: 2 });
+
+        let mut fc = FlowControl::new(FcCredits {
+            posted: FcCredit { header: 2, data: 2 },
+            ..FcCredits::default()
+        });
+        assert!(fc.can_send(&write(8)));
+        fc.consume(&write(8));
+        assert!(!fc.can_send(&write(1)));
+        // The non-posted credits are infinite.
+        assert!(fc.can_send(&read));
+
+        fc.update(FcClass::Posted, FcCredit { header: 1, data: 1 });
+        assert!(fc.can_send(&write(4)));
+        assert!(!fc.can_send(&write(5)));
+        assert_eq!(fc.available().posted, FcCredit { header: 2, data: 1 });
+
+        // A write larger than the buffer waits until the buffer is empty.
+        fc.update(FcClass::Posted, FcCredit { header: 1, data: 1 });
+        assert!(fc.can_send(&write(16)));
+    }
+}
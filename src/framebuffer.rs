@@ -0,0 +1,448 @@
+//! Model of a framebuffer, a graphics card with on-board memory.
+//!
+//! BAR0 is 64 MB of video memory, a 64-bit prefetchable BAR the model exports as a
+//! [`SharedRegion`], so the adapter maps it into the guest when it has a slot manager and the
+//! guest draws without any TLP. Without one, the accesses go through the model like those of
+//! any other BAR. BAR2 is a 4 KB register bank to set the mode:
+//!
+//! | Offset | Register |
+//! |--------|----------|
+//! | 0x00 | Identification, `0x00fb0001` (RO) |
+//! | 0x04 | Size of the video memory in bytes (RO) |
+//! | 0x08 | Width in pixels |
+//! | 0x0c | Height in pixels |
+//! | 0x10 | Bits per pixel, 8, 16, 24 or 32 |
+//! | 0x14 | Bytes from a line to the next, the line rounded up to 4 bytes (RO) |
+//! | 0x18 | Offset of the frame in the video memory |
+//! | 0x1c | Enable, bit 0 scans the frame out |
+//!
+//! The mode registers take effect when the guest writes the enable register with bit 0 set,
+//! which it reads back clear if the frame does not fit in the video memory. The host side of
+//! the display reads the frame scanned out by a [`Scanout`].
+
+use crate::*;
+
+use crate::adapter::BAR0_REG;
+use crate::device::dispatch;
+use pci::PciDisplaySubclass;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+const FB_VENDOR_ID: u16 = 0x1234;
+const FB_DEVICE_ID: u16 = 0x10fb;
+const FB_ID: u32 = 0x00fb_0001;
+const VRAM_BAR: usize = 0;
+const VRAM_SIZE: usize = 64 << 20;
+const REGS_BAR: usize = 2;
+const REGS_BAR_SIZE: u64 = 0x1000;
+const MAX_LINES: u32 = 8192;
+
+const REG_ID: u64 = 0x00;
+const REG_VRAM_SIZE: u64 = 0x04;
+const REG_WIDTH: u64 = 0x08;
+const REG_HEIGHT: u64 = 0x0c;
+const REG_BPP: u64 = 0x10;
+const REG_STRIDE: u64 = 0x14;
+const REG_OFFSET: u64 = 0x18;
+const REG_ENABLE: u64 = 0x1c;
+
+const ENABLE_SCANOUT: u32 = 0x1;
+
+/// A mode of the framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FbMode {
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u32,
+    /// Bytes from a line to the next.
+    pub stride: u32,
+    /// Offset of the frame in the video memory.
+    pub offset: u32,
+}
+
+impl FbMode {
+    /// Bytes of the pixels of a line, without the padding.
+    pub fn line(&self) -> usize {
+        (self.width * self.bpp / 8) as usize
+    }
+
+    /// Whether the mode is one the device scans out from `vram` bytes of memory.
+    fn valid(&self, vram: usize) -> bool {
+        matches!(self.bpp, 8 | 16 | 24 | 32)
+            && (1..=MAX_LINES).contains(&self.width)
+            && (1..=MAX_LINES).contains(&self.height)
+            && self.offset as usize + self.stride as usize * self.height as usize <= vram
+    }
+}
+
+/// Anonymous host memory of the video memory, shared by the model, the guest once it is mapped
+/// and the [`Scanout`].
+struct Vram {
+    addr: *mut u8,
+    size: usize,
+}
+
+// The memory is only accessed by copies, racing ones tear the frame at worst.
+unsafe impl Send for Vram {}
+unsafe impl Sync for Vram {}
+
+impl Vram {
+    fn new(size: usize) -> io::Result<Self> {
+        // The pages are only backed once they are touched.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Vram {
+            addr: addr as *mut u8,
+            size,
+        })
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        assert!(offset + data.len() <= self.size);
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.addr.add(offset), data.as_mut_ptr(), data.len())
+        }
+    }
+
+    fn write(&self, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= self.size);
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), self.addr.add(offset), data.len()) }
+    }
+}
+
+impl Drop for Vram {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+/// The display side of a [`PciFramebufferDevice`], which reads the frame the guest draws.
+#[derive(Clone)]
+pub struct Scanout {
+    vram: Arc<Vram>,
+    mode: Arc<Mutex<Option<FbMode>>>,
+}
+
+impl Scanout {
+    /// The mode scanned out, `None` while the guest does not enable the scanout.
+    pub fn mode(&self) -> Option<FbMode> {
+        *self.mode.lock().unwrap()
+    }
+
+    /// A copy of the frame scanned out, its lines one after the other without the padding of
+    /// the stride.
+    pub fn frame(&self) -> Option<(FbMode, Vec<u8>)> {
+        let mode = self.mode()?;
+        let line = mode.line();
+        let mut frame = vec![0u8; line * mode.height as usize];
+        for (y, pixels) in frame.chunks_mut(line).enumerate() {
+            self.vram
+                .read(mode.offset as usize + y * mode.stride as usize, pixels);
+        }
+        Some((mode, frame))
+    }
+}
+
+/// The framebuffer, see the [module documentation](crate::framebuffer).
+pub struct PciFramebufferDevice {
+    regs: FbRegisters,
+}
+
+impl PciFramebufferDevice {
+    /// Fails if the video memory cannot be allocated.
+    pub fn new() -> io::Result<Self> {
+        let vram = Arc::new(Vram::new(VRAM_SIZE)?);
+        Ok(PciFramebufferDevice {
+            regs: FbRegisters::new(vram, Arc::new(Mutex::new(None))),
+        })
+    }
+
+    /// The display side of the device, to be taken before the device is started.
+    pub fn scanout(&self) -> Scanout {
+        Scanout {
+            vram: self.regs.vram.clone(),
+            mode: self.regs.scanout.clone(),
+        }
+    }
+}
+
+impl PciSimDevice for PciFramebufferDevice {
+    fn shared_regions(&self) -> Vec<SharedRegion> {
+        vec![SharedRegion {
+            bar: VRAM_BAR,
+            host_addr: self.regs.vram.addr as u64,
+            size: self.regs.vram.size,
+        }]
+    }
+
+    fn on_reset(&mut self, _kind: ResetKind) {
+        // The video memory keeps its content, like the memory chips of a card do.
+        *self.regs.scanout.lock().unwrap() = None;
+        self.regs = FbRegisters::new(self.regs.vram.clone(), self.regs.scanout.clone());
+    }
+
+    fn run(&mut self, lane: &PciLane) {
+        let mut completer = 0;
+        while let Ok(tlp) = lane.rx.recv() {
+            dispatch(&mut self.regs, lane, &tlp, &mut completer);
+        }
+    }
+}
+
+/// The config space and the BARs of the framebuffer.
+struct FbRegisters {
+    config: PcieConfiguration,
+    vram: Arc<Vram>,
+    /// The mode the guest programs, which takes effect when it enables the scanout.
+    mode: FbMode,
+    enable: u32,
+    scanout: Arc<Mutex<Option<FbMode>>>,
+}
+
+impl FbRegisters {
+    fn new(vram: Arc<Vram>, scanout: Arc<Mutex<Option<FbMode>>>) -> Self {
+        let mut config = PciConfiguration::new(
+            FB_VENDOR_ID,
+            FB_DEVICE_ID,
+            0,
+            PciClassCode::DisplayController,
+            &PciDisplaySubclass::Other,
+            None,
+            PciHeaderType::Device,
+            0x1af4,
+            0x1100,
+            None,
+        );
+
+        let bar = PciBarConfiguration::new(
+            VRAM_BAR,
+            vram.size as u64,
+            PciBarRegionType::Memory64BitRegion,
+            PciBarPrefetchable::Prefetchable,
+        );
+        config.add_pci_bar(&bar).unwrap();
+        let bar = PciBarConfiguration::new(
+            REGS_BAR,
+            REGS_BAR_SIZE,
+            PciBarRegionType::Memory32BitRegion,
+            PciBarPrefetchable::NotPrefetchable,
+        );
+        config.add_pci_bar(&bar).unwrap();
+
+        FbRegisters {
+            config: PcieConfiguration::new(config),
+            vram,
+            mode: FbMode::default(),
+            enable: 0,
+            scanout,
+        }
+    }
+
+    /// The offset of `addr` into the register bank, if it is in BAR2.
+    fn regs_offset(&self, addr: u64) -> Option<u64> {
+        let base = (self.config.read_config_register(BAR0_REG + REGS_BAR) & 0xffff_fff0) as u64;
+        if base == 0 || !(base..base + REGS_BAR_SIZE).contains(&addr) {
+            return None;
+        }
+        Some(addr - base)
+    }
+
+    /// The offset of `addr` into the video memory. The BAR is aligned to its size.
+    fn vram_offset(&self, addr: u64) -> usize {
+        (addr & (self.vram.size as u64 - 1)) as usize
+    }
+
+    fn read(&self, reg: u64) -> u32 {
+        match reg {
+            REG_ID => FB_ID,
+            REG_VRAM_SIZE => self.vram.size as u32,
+            REG_WIDTH => self.mode.width,
+            REG_HEIGHT => self.mode.height,
+            REG_BPP => self.mode.bpp,
+            REG_STRIDE => self.mode.stride,
+            REG_OFFSET => self.mode.offset,
+            REG_ENABLE => self.enable,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, reg: u64, value: u32) {
+        match reg {
+            REG_WIDTH => self.mode.width = value,
+            REG_HEIGHT => self.mode.height = value,
+            REG_BPP => self.mode.bpp = value,
+            REG_OFFSET => self.mode.offset = value,
+            REG_ENABLE => {
+                self.enable = value & ENABLE_SCANOUT;
+                let scanout = if self.enable == 0 {
+                    None
+                } else if self.mode.valid(self.vram.size) {
+                    Some(self.mode)
+                } else {
+                    debug!("Framebuffer refuses the mode {:?}", self.mode);
+                    self.enable = 0;
+                    None
+                };
+                *self.scanout.lock().unwrap() = scanout;
+            }
+            _ => (),
+        }
+        self.mode.stride = ((self.mode.line() + 3) & !3) as u32;
+    }
+}
+
+impl SimpleDevice for FbRegisters {
+    fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+        self.config.read_config_register(reg)
+    }
+
+    fn on_config_write(&mut self, _: u16, reg: usize, offset: u64, data: &[u8]) {
+        self.config.write_config_register(reg, offset, data);
+    }
+
+    fn on_mem_read(&mut self, addr: u64, data: &mut [u8]) -> std::result::Result<(), u8> {
+        match self.regs_offset(addr) {
+            Some(offset) => {
+                let value = self.read(offset & !0b11).to_le_bytes();
+                let start = (offset & 0b11) as usize;
+                let len = data.len().min(4 - start);
+                data[..len].copy_from_slice(&value[start..start + len]);
+            }
+            None => self.vram.read(self.vram_offset(addr), data),
+        }
+        Ok(())
+    }
+
+    fn on_mem_write(&mut self, addr: u64, data: &[u8]) {
+        match self.regs_offset(addr) {
+            Some(offset) if data.len() == 4 && offset & 0b11 == 0 => self.write(
+                offset,
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            ),
+            Some(offset) => debug!(
+                "Framebuffer ignores a write of {} bytes at {:#x}",
+                data.len(),
+                offset
+            ),
+            None => self.vram.write(self.vram_offset(addr), data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::test_allocator;
+
+    /// Records the guest address and host memory of every mapped slot.
+    #[derive(Clone, Default)]
+    struct RecordingSlots(Arc<Mutex<Vec<(u64, u64)>>>);
+
+    impl MemorySlotManager for RecordingSlots {
+        fn map_region(&mut self, gpa: u64, _size: u64, host_addr: u64) -> io::Result<u32> {
+            let mut slots = self.0.lock().unwrap();
+            slots.push((gpa, host_addr));
+            Ok(slots.len() as u32 - 1)
+        }
+
+        fn unmap_region(&mut self, _slot: u32) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn framebuffer() {
+        let device = PciFramebufferDevice::new().unwrap();
+        let scanout = device.scanout();
+        let host_addr = device.shared_regions()[0].host_addr;
+        let mut adapter = PciAdapter::start(Box::new(device));
+        let slots = RecordingSlots::default();
+        adapter.set_slot_manager(Box::new(slots.clone()));
+
+        let mut allocator = test_allocator();
+        let bars = adapter.allocate_bars(&mut allocator).unwrap();
+        let vram = bars
+            .iter()
+            .find(|bar| bar.1 == VRAM_SIZE as u64)
+            .unwrap()
+            .0
+            .raw_value();
+        let regs = bars
+            .iter()
+            .find(|bar| bar.1 == REGS_BAR_SIZE)
+            .unwrap()
+            .0
+            .raw_value();
+        assert_eq!(*slots.0.lock().unwrap(), vec![(vram, host_addr)]);
+
+        let read = |adapter: &mut PciAdapter, reg: u64| {
+            let mut data = [0u8; 4];
+            adapter.bar_mmio_read(regs + reg, &mut data);
+            u32::from_le_bytes(data)
+        };
+        let write = |adapter: &mut PciAdapter, reg: u64, value: u32| {
+            adapter.bar_mmio_write(regs + reg, &value.to_le_bytes());
+        };
+        assert_eq!(read(&mut adapter, REG_ID), FB_ID);
+        assert_eq!(read(&mut adapter, REG_VRAM_SIZE), VRAM_SIZE as u32);
+
+        // A frame larger than the video memory is refused.
+        write(&mut adapter, REG_WIDTH, 8192);
+        write(&mut adapter, REG_HEIGHT, 8192);
+        write(&mut adapter, REG_BPP, 32);
+        write(&mut adapter, REG_ENABLE, ENABLE_SCANOUT);
+        assert_eq!(read(&mut adapter, REG_ENABLE), 0);
+        assert_eq!(scanout.mode(), None);
+
+        write(&mut adapter, REG_WIDTH, 3);
+        write(&mut adapter, REG_HEIGHT, 2);
+        write(&mut adapter, REG_BPP, 24);
+        write(&mut adapter, REG_OFFSET, 0x100);
+        write(&mut adapter, REG_ENABLE, ENABLE_SCANOUT);
+        assert_eq!(read(&mut adapter, REG_STRIDE), 12);
+        assert_eq!(
+            scanout.mode(),
+            Some(FbMode {
+                width: 3,
+                height: 2,
+                bpp: 24,
+                stride: 12,
+                offset: 0x100,
+            })
+        );
+
+        // The first line is drawn through the slot, the second one by TLPs.
+        let line: Vec<u8> = (0..9).collect();
+        unsafe {
+            std::ptr::copy_nonoverlapping(line.as_ptr(), (host_addr + 0x100) as *mut u8, 9);
+        }
+        adapter.bar_mmio_write(vram + 0x10c, &[0xa0, 0xa1, 0xa2, 0xa3]);
+        adapter.bar_mmio_write(vram + 0x110, &[0xa4, 0xa5, 0xa6, 0xa7, 0xa8]);
+        let mut data = [0u8; 4];
+        adapter.bar_mmio_read(vram + 0x104, &mut data);
+        assert_eq!(data, [4, 5, 6, 7]);
+
+        let (_, frame) = scanout.frame().unwrap();
+        assert_eq!(
+            frame,
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8]
+        );
+
+        adapter.stop();
+        adapter.join();
+    }
+}
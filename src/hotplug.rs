@@ -0,0 +1,331 @@
+//! Native PCIe hot-plug of the simulated devices.
+//!
+//! [`PciAdapter::hotplug`] and [`PciAdapter::unplug`] attach and detach device models behind a
+//! running bridge. The guest learns about it from the slot the device sits in: the hypervisor
+//! keeps a [`HotplugSlot`] in the PCI Express capability of the downstream port above the
+//! device, hands it the events of the adapter, see [`HotplugSlot::on_event`], and signals the
+//! hot-plug interrupt of the port when asked to.
+//!
+//! The slot has an attention button by default, see [`HotplugSlot::press_attention_button`].
+//! Linux answers a press by blinking the power indicator and, 5 seconds later, unbinding the
+//! driver and turning the slot off if it has a power controller. The hypervisor then unplugs
+//! the device once [`HotplugSlot::is_powered`] is false, which empties the slot as the
+//! [`AdapterEvent::Detached`] events come.
+
+use crate::*;
+
+/// Byte offset of the Slot Capabilities register in the PCI Express capability.
+pub const PCI_EXP_SLTCAP: usize = 0x14;
+/// Byte offset of the Slot Control register in the PCI Express capability, the Slot Status
+/// register follows at 0x1a.
+pub const PCI_EXP_SLTCTL: usize = 0x18;
+
+const SLTCTL_ABPE: u16 = 1 << 0;
+const SLTCTL_PDCE: u16 = 1 << 3;
+const SLTCTL_CCIE: u16 = 1 << 4;
+const SLTCTL_HPIE: u16 = 1 << 5;
+const SLTCTL_AIC_SHIFT: u16 = 6;
+const SLTCTL_PIC_SHIFT: u16 = 8;
+const SLTCTL_PCC: u16 = 1 << 10;
+const SLTCTL_DLLSCE: u16 = 1 << 12;
+
+const SLTSTA_ABP: u16 = 1 << 0;
+const SLTSTA_PDC: u16 = 1 << 3;
+const SLTSTA_CC: u16 = 1 << 4;
+const SLTSTA_PDS: u16 = 1 << 6;
+const SLTSTA_DLLSC: u16 = 1 << 8;
+/// Status bits which are cleared by writing 1.
+const SLTSTA_RW1C: u16 = 0x011f;
+
+/// Physical Slot Number in bits 31:19 of Slot Capabilities.
+const SLTCAP_PSN_SHIFT: u32 = 19;
+
+/// Slot Capabilities, Slot Control and Slot Status registers of a hot-plug capable slot.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HotplugSlot {
+    capabilities: u32,
+    control: u16,
+    status: u16,
+    /// Bus and device number of the device the slot holds.
+    link: Option<u16>,
+    /// The functions of the device which are attached, one bit each.
+    functions: u8,
+}
+
+impl HotplugSlot {
+    /// Attention Button Present.
+    pub const ATTENTION_BUTTON: u32 = 1 << 0;
+    /// Power Controller Present.
+    pub const POWER_CONTROLLER: u32 = 1 << 1;
+    /// Attention Indicator Present.
+    pub const ATTENTION_INDICATOR: u32 = 1 << 3;
+    /// Power Indicator Present.
+    pub const POWER_INDICATOR: u32 = 1 << 4;
+    /// Hot-Plug Surprise.
+    pub const SURPRISE: u32 = 1 << 5;
+    /// Hot-Plug Capable, always set.
+    pub const HOT_PLUG_CAPABLE: u32 = 1 << 6;
+    /// No Command Completed Support: the slot does not signal Command Completed.
+    pub const NO_COMMAND_COMPLETED: u32 = 1 << 18;
+
+    const FEATURES: u32 = 0x4007f;
+    const DEFAULT_CAPABILITIES: u32 = Self::ATTENTION_BUTTON
+        | Self::ATTENTION_INDICATOR
+        | Self::POWER_INDICATOR
+        | Self::HOT_PLUG_CAPABLE
+        | Self::NO_COMMAND_COMPLETED;
+
+    /// A slot which is empty or holds a device, with an attention button and indicators but
+    /// no power controller.
+    pub fn new(present: bool) -> Self {
+        HotplugSlot {
+            capabilities: Self::DEFAULT_CAPABILITIES,
+            control: 0,
+            status: if present { SLTSTA_PDS } else { 0 },
+            link: None,
+            functions: 0,
+        }
+    }
+
+    /// The features of the slot from the Slot Capabilities bits above, it is hot-plug
+    /// capable whatever they say.
+    pub fn capabilities(mut self, capabilities: u32) -> Self {
+        self.capabilities = (self.capabilities & !Self::FEATURES)
+            | (capabilities & Self::FEATURES)
+            | Self::HOT_PLUG_CAPABLE;
+        self
+    }
+
+    /// Physical Slot Number of the slot, 13 bits.
+    pub fn slot_number(mut self, number: u16) -> Self {
+        self.capabilities = (self.capabilities & ((1 << SLTCAP_PSN_SHIFT) - 1))
+            | (number as u32 & 0x1fff) << SLTCAP_PSN_SHIFT;
+        self
+    }
+
+    /// The slot holds the device `device` on `bus`, whose functions [`HotplugSlot::on_event`]
+    /// follows.
+    pub fn device(mut self, bus: u8, device: u8) -> Self {
+        self.link = Some((bus as u16) << 8 | ((device & 0x1f) as u16) << 3);
+        self
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.status & SLTSTA_PDS != 0
+    }
+
+    /// A device is plugged into or removed from the slot, which latches the presence detect
+    /// and data link layer state changes. Returns whether the hot-plug interrupt should be
+    /// signaled.
+    pub fn set_present(&mut self, present: bool) -> bool {
+        if present == self.is_present() {
+            return false;
+        }
+
+        self.status ^= SLTSTA_PDS;
+        self.status |= SLTSTA_PDC | SLTSTA_DLLSC;
+        self.interrupt_pending()
+    }
+
+    /// Whether the power controller keeps the slot on, always true without one.
+    pub fn is_powered(&self) -> bool {
+        self.capabilities & Self::POWER_CONTROLLER == 0 || self.control & SLTCTL_PCC == 0
+    }
+
+    /// The Attention Indicator Control: 1 on, 2 blinking, 3 off.
+    pub fn attention_indicator(&self) -> u8 {
+        (self.control >> SLTCTL_AIC_SHIFT) as u8 & 0b11
+    }
+
+    /// The Power Indicator Control: 1 on, 2 blinking, 3 off.
+    pub fn power_indicator(&self) -> u8 {
+        (self.control >> SLTCTL_PIC_SHIFT) as u8 & 0b11
+    }
+
+    /// The user pushes the attention button of the slot to ask for the device to be added or
+    /// removed. Returns whether the hot-plug interrupt should be signaled, never without an
+    /// attention button.
+    pub fn press_attention_button(&mut self) -> bool {
+        if self.capabilities & Self::ATTENTION_BUTTON == 0 {
+            return false;
+        }
+
+        self.status |= SLTSTA_ABP;
+        self.interrupt_pending()
+    }
+
+    /// Follow `event` of the adapter: the slot is occupied while a function of its device is
+    /// attached and latches a data link layer state change as the link of the device goes
+    /// down or up. Events of other devices are ignored. Returns whether the hot-plug
+    /// interrupt should be signaled.
+    pub fn on_event(&mut self, event: &AdapterEvent) -> bool {
+        let holds = |bdf: &u16| self.link == Some(bdf & !0b111);
+
+        match event {
+            AdapterEvent::Attached { bdf } if holds(bdf) => {
+                self.functions |= 1 << (bdf & 0b111);
+                self.set_present(true)
+            }
+            AdapterEvent::Detached { bdf } if holds(bdf) => {
+                self.functions &= !(1 << (bdf & 0b111));
+                self.set_present(self.functions != 0)
+            }
+            AdapterEvent::LinkDown { bdf } | AdapterEvent::LinkUp { bdf } if holds(bdf) => {
+                self.status |= SLTSTA_DLLSC;
+                self.interrupt_pending()
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether an enabled event is latched while hot-plug interrupts are enabled.
+    pub fn interrupt_pending(&self) -> bool {
+        let mut enabled = 0;
+        if self.control & SLTCTL_ABPE != 0 {
+            enabled |= SLTSTA_ABP;
+        }
+        if self.control & SLTCTL_CCIE != 0 {
+            enabled |= SLTSTA_CC;
+        }
+        if self.control & SLTCTL_PDCE != 0 {
+            enabled |= SLTSTA_PDC;
+        }
+        if self.control & SLTCTL_DLLSCE != 0 {
+            enabled |= SLTSTA_DLLSC;
+        }
+
+        self.control & SLTCTL_HPIE != 0 && self.status & enabled != 0
+    }
+
+    /// The Slot Capabilities register at [`PCI_EXP_SLTCAP`].
+    pub fn read_capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// The DW at [`PCI_EXP_SLTCTL`], Slot Control in the lower and Slot Status in the upper
+    /// half.
+    pub fn read(&self) -> u32 {
+        self.control as u32 | (self.status as u32) << 16
+    }
+
+    /// Write `data` at byte `offset` into the DW at [`PCI_EXP_SLTCTL`]. The change bits of the
+    /// status are cleared by writing 1. A write to Slot Control completes at once, which
+    /// latches Command Completed unless the slot has [`HotplugSlot::NO_COMMAND_COMPLETED`].
+    /// Returns whether the hot-plug interrupt should be signaled, e.g. as an event latched
+    /// before is enabled.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> bool {
+        let offset = offset as usize;
+        if offset + data.len() > 4 {
+            return self.interrupt_pending();
+        }
+
+        let mut mask = 0u32;
+        let mut value = 0u32;
+        for (i, b) in data.iter().enumerate() {
+            mask |= 0xff << ((offset + i) * 8);
+            value |= (*b as u32) << ((offset + i) * 8);
+        }
+
+        let control_mask = mask as u16;
+        self.control = (self.control & !control_mask) | (value as u16 & control_mask);
+        self.status &= !((value >> 16) as u16 & (mask >> 16) as u16 & SLTSTA_RW1C);
+        if control_mask != 0 && self.capabilities & Self::NO_COMMAND_COMPLETED == 0 {
+            self.status |= SLTSTA_CC;
+        }
+
+        self.interrupt_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slot() {
+        let mut slot = HotplugSlot::new(false);
+        assert!(!slot.is_present());
+
+        // Interrupts are disabled, the change is latched anyway.
+        assert!(!slot.set_present(true));
+        assert_eq!(slot.read() >> 16, 0x148);
+        assert!(!slot.set_present(true));
+
+        // Enabling the interrupt with the change latched asks for it.
+        assert!(slot.write(0, &(SLTCTL_HPIE | SLTCTL_PDCE).to_le_bytes()));
+        assert!(!slot.write(2, &SLTSTA_PDC.to_le_bytes()));
+        assert_eq!(slot.read(), 0x0140_0028);
+
+        assert!(slot.set_present(false));
+        assert_eq!(slot.read() >> 16, 0x108);
+        assert!(!slot.write(2, &[0xff, 0xff]));
+        assert_eq!(slot.read() >> 16, 0);
+    }
+
+    #[test]
+    fn capabilities() {
+        let slot = HotplugSlot::new(false).slot_number(5);
+        assert_eq!(slot.read_capabilities(), 5 << 19 | 0x4_0059);
+        assert!(slot.is_powered());
+
+        let mut slot = HotplugSlot::new(true)
+            .capabilities(HotplugSlot::POWER_CONTROLLER)
+            .slot_number(0x2003);
+        assert_eq!(slot.read_capabilities(), 3 << 19 | 0x42);
+        assert!(!slot.press_attention_button());
+        assert_eq!(slot.read() >> 16, 0x40);
+
+        // Every write to Slot Control completes a command, turning the slot off is one.
+        assert!(slot.write(0, &(SLTCTL_HPIE | SLTCTL_CCIE).to_le_bytes()));
+        assert!(!slot.write(2, &SLTSTA_CC.to_le_bytes()));
+        assert!(slot.write(1, &[0x07]));
+        assert!(!slot.is_powered());
+        assert_eq!((slot.attention_indicator(), slot.power_indicator()), (0, 3));
+    }
+
+    #[test]
+    fn attention_button() {
+        let mut slot = HotplugSlot::new(true);
+        assert!(!slot.press_attention_button());
+        assert_eq!(slot.read() >> 16, 0x41);
+
+        assert!(slot.write(0, &(SLTCTL_HPIE | SLTCTL_ABPE).to_le_bytes()));
+        assert!(!slot.write(2, &SLTSTA_ABP.to_le_bytes()));
+        assert!(slot.press_attention_button());
+
+        // The guest blinks the power indicator, there is no command to complete.
+        slot.write(2, &SLTSTA_ABP.to_le_bytes());
+        assert!(!slot.write(1, &[0x02]));
+        assert_eq!(slot.power_indicator(), 2);
+    }
+
+    #[test]
+    fn events() {
+        let bdf = make_bdf(0, 4, 1);
+        let mut slot = HotplugSlot::new(false).device(0, 4);
+        slot.write(0, &(SLTCTL_HPIE | SLTCTL_PDCE).to_le_bytes());
+
+        assert!(!slot.on_event(&AdapterEvent::Attached {
+            bdf: make_bdf(0, 5, 0)
+        }));
+        assert!(slot.on_event(&AdapterEvent::Attached { bdf }));
+        assert!(slot.is_present());
+        slot.write(2, &SLTSTA_PDC.to_le_bytes());
+        assert!(!slot.on_event(&AdapterEvent::Attached {
+            bdf: make_bdf(0, 4, 0)
+        }));
+
+        // The slot is empty once all the functions are gone.
+        assert!(!slot.on_event(&AdapterEvent::Detached { bdf }));
+        assert!(slot.is_present());
+        assert!(slot.on_event(&AdapterEvent::Detached {
+            bdf: make_bdf(0, 4, 0)
+        }));
+        assert_eq!(slot.read() >> 16, 0x108);
+
+        // Data link layer state changes are latched, but not enabled.
+        slot.write(2, &[0xff, 0xff]);
+        assert!(!slot.on_event(&AdapterEvent::LinkDown { bdf }));
+        assert_eq!(slot.read() >> 16, 0x100);
+    }
+}
@@ -0,0 +1,459 @@
+//! The VM primitives the integration layer is built on.
+//!
+//! Slot mapped BARs, doorbells and irqfd delivered interrupts all come down to a handful of
+//! operations on the VM: mapping host memory, signaling eventfds on guest writes and injecting
+//! interrupts. [`Hypervisor`] abstracts them, so [`VmSlotManager`], [`VmIoEventManager`],
+//! [`VmMsiSink`] and [`VmIrqfdSink`] work with any hypervisor implementing it. The `kvm`
+//! feature implements it for the VM of `kvm-ioctls`, the `mshv` feature for the VM of
+//! `mshv-ioctls`, which serves the mshv builds of cloud-hypervisor.
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+/// A GSI of the VM and the MSI it is routed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiRoute {
+    pub gsi: u32,
+    pub addr: u64,
+    pub data: u32,
+    /// Requester ID of the function, the DeviceID the ITS of aarch64 translates the MSI with.
+    pub devid: u32,
+}
+
+/// Operations on a VM the adapter needs from the hypervisor.
+pub trait Hypervisor: Send + Sync {
+    /// Map `size` bytes of host memory at `host_addr` to the guest physical address `gpa`
+    /// through `slot`.
+    fn map_memory(&self, slot: u32, gpa: u64, size: u64, host_addr: u64) -> io::Result<()>;
+
+    /// Remove the mapping [`Hypervisor::map_memory`] made with the same arguments.
+    fn unmap_memory(&self, slot: u32, gpa: u64, size: u64, host_addr: u64) -> io::Result<()>;
+
+    /// Signal the eventfd `event` on every guest write to the guest physical address `gpa`.
+    fn register_ioevent(&self, gpa: u64, event: RawFd) -> io::Result<()>;
+
+    /// Remove a registration of [`Hypervisor::register_ioevent`].
+    fn unregister_ioevent(&self, gpa: u64, event: RawFd) -> io::Result<()>;
+
+    /// Inject the interrupt routed to `gsi` whenever the eventfd `event` is signaled.
+    fn register_irqfd(&self, event: RawFd, gsi: u32) -> io::Result<()>;
+
+    /// Remove a registration of [`Hypervisor::register_irqfd`].
+    fn unregister_irqfd(&self, event: RawFd, gsi: u32) -> io::Result<()>;
+
+    /// Replace the MSI routes of the VM with `routes`.
+    fn set_msi_routing(&self, routes: &[MsiRoute]) -> io::Result<()>;
+
+    /// Inject the MSI of message `data` at `addr`, sent by the function of requester ID
+    /// `devid` if known. Interrupt controllers which translate MSIs by their sender, e.g. the
+    /// ITS of aarch64, need it.
+    fn signal_msi(&self, addr: u64, data: u32, devid: Option<u32>) -> io::Result<()>;
+
+    /// Set the level of the interrupt line `gsi`, an SPI on aarch64. Hypervisors without
+    /// interrupt controller in the kernel refuse it.
+    fn set_irq_line(&self, gsi: u32, level: bool) -> io::Result<()> {
+        let _ = (gsi, level);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "interrupt lines are not emulated by the hypervisor",
+        ))
+    }
+}
+
+/// A new eventfd, owned by the caller.
+pub(crate) fn eventfd() -> io::Result<RawFd> {
+    match unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(fd),
+    }
+}
+
+/// Add 1 to the count of the eventfd `fd`.
+pub(crate) fn signal(fd: RawFd) -> io::Result<()> {
+    let one = 1u64;
+    match unsafe { libc::write(fd, &one as *const u64 as *const libc::c_void, 8) } {
+        8 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The `EventFd` of the hypervisor crates for an eventfd which stays owned by the caller.
+#[cfg(any(feature = "kvm", feature = "mshv"))]
+fn borrow_eventfd(fd: RawFd) -> std::mem::ManuallyDrop<vmm_sys_util::eventfd::EventFd> {
+    use std::os::unix::io::FromRawFd;
+
+    std::mem::ManuallyDrop::new(unsafe { vmm_sys_util::eventfd::EventFd::from_raw_fd(fd) })
+}
+
+/// KVM VMs, which need an in-kernel irqchip for irqfds, MSI routes and interrupt lines. On
+/// x86_64 the routing table keeps the default routes of the irqchip, so replacing the MSI
+/// routes does not disconnect the pins of the PIC and IOAPIC. On aarch64 the MSIs carry their
+/// DeviceID for the ITS, and the interrupt lines are SPIs of the GIC, which need no routes.
+#[cfg(feature = "kvm")]
+impl Hypervisor for kvm_ioctls::VmFd {
+    fn map_memory(&self, slot: u32, gpa: u64, size: u64, host_addr: u64) -> io::Result<()> {
+        let region = kvm_bindings::kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: gpa,
+            memory_size: size,
+            userspace_addr: host_addr,
+            flags: 0,
+        };
+
+        // The caller of map_memory guarantees the memory outlives the mapping.
+        unsafe { self.set_user_memory_region(region) }
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn unmap_memory(&self, slot: u32, _: u64, _: u64, _: u64) -> io::Result<()> {
+        // A slot of zero size is deleted by KVM.
+        self.map_memory(slot, 0, 0, 0)
+    }
+
+    fn register_ioevent(&self, gpa: u64, event: RawFd) -> io::Result<()> {
+        kvm_ioctls::VmFd::register_ioevent(
+            self,
+            &borrow_eventfd(event),
+            &kvm_ioctls::IoEventAddress::Mmio(gpa),
+            kvm_ioctls::NoDatamatch,
+        )
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn unregister_ioevent(&self, gpa: u64, event: RawFd) -> io::Result<()> {
+        kvm_ioctls::VmFd::unregister_ioevent(
+            self,
+            &borrow_eventfd(event),
+            &kvm_ioctls::IoEventAddress::Mmio(gpa),
+            kvm_ioctls::NoDatamatch,
+        )
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn register_irqfd(&self, event: RawFd, gsi: u32) -> io::Result<()> {
+        kvm_ioctls::VmFd::register_irqfd(self, &borrow_eventfd(event), gsi)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn unregister_irqfd(&self, event: RawFd, gsi: u32) -> io::Result<()> {
+        kvm_ioctls::VmFd::unregister_irqfd(self, &borrow_eventfd(event), gsi)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn set_msi_routing(&self, routes: &[MsiRoute]) -> io::Result<()> {
+        use kvm_bindings::*;
+
+        #[cfg(target_arch = "x86_64")]
+        let irqchip = |gsi, chip, pin| {
+            let mut entry = kvm_irq_routing_entry {
+                gsi,
+                type_: KVM_IRQ_ROUTING_IRQCHIP,
+                ..Default::default()
+            };
+            entry.u.irqchip = kvm_irq_routing_irqchip { irqchip: chip, pin };
+            entry
+        };
+        // The default routes of KVM: the 16 legacy IRQs on the PICs, all 24 on the IOAPIC.
+        #[cfg(target_arch = "x86_64")]
+        let mut entries: Vec<_> = (0..16)
+            .filter(|&gsi| gsi != 2)
+            .map(|gsi| match gsi {
+                0..=7 => irqchip(gsi, KVM_IRQCHIP_PIC_MASTER, gsi),
+                _ => irqchip(gsi, KVM_IRQCHIP_PIC_SLAVE, gsi - 8),
+            })
+            .chain((0..24).map(|gsi| irqchip(gsi, KVM_IRQCHIP_IOAPIC, gsi)))
+            .collect();
+        #[cfg(not(target_arch = "x86_64"))]
+        let mut entries = vec![];
+        for route in routes {
+            let mut entry = kvm_irq_routing_entry {
+                gsi: route.gsi,
+                type_: KVM_IRQ_ROUTING_MSI,
+                ..Default::default()
+            };
+            entry.u.msi = kvm_irq_routing_msi {
+                address_lo: route.addr as u32,
+                address_hi: (route.addr >> 32) as u32,
+                data: route.data,
+                ..Default::default()
+            };
+            #[cfg(target_arch = "aarch64")]
+            {
+                entry.flags = KVM_MSI_VALID_DEVID;
+                entry.u.msi.__bindgen_anon_1.devid = route.devid;
+            }
+            entries.push(entry);
+        }
+
+        let routing = KvmIrqRouting::from_entries(&entries)
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        self.set_gsi_routing(&routing)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn signal_msi(&self, addr: u64, data: u32, devid: Option<u32>) -> io::Result<()> {
+        #[allow(unused_mut)]
+        let mut msi = kvm_bindings::kvm_msi {
+            address_lo: addr as u32,
+            address_hi: (addr >> 32) as u32,
+            data,
+            ..Default::default()
+        };
+        #[cfg(target_arch = "aarch64")]
+        if let Some(devid) = devid {
+            msi.flags = kvm_bindings::KVM_MSI_VALID_DEVID;
+            msi.devid = devid;
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        let _ = devid;
+
+        kvm_ioctls::VmFd::signal_msi(self, msi)
+            .map(|_| ())
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn set_irq_line(&self, gsi: u32, level: bool) -> io::Result<()> {
+        // KVM_IRQ_LINE takes the type of the interrupt on aarch64, the lines are SPIs.
+        #[cfg(target_arch = "aarch64")]
+        let gsi = kvm_bindings::KVM_ARM_IRQ_TYPE_SPI << kvm_bindings::KVM_ARM_IRQ_TYPE_SHIFT | gsi;
+        kvm_ioctls::VmFd::set_irq_line(self, gsi, level)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+}
+
+/// mshv VMs. The memory slots are only bookkeeping of the adapter, mshv identifies a mapping
+/// by its addresses. MSIs are injected as virtual interrupts decoded from the x86 message, and
+/// the interrupt controllers are emulated by the VMM, so there are no interrupt lines.
+#[cfg(feature = "mshv")]
+impl Hypervisor for mshv_ioctls::VmFd {
+    fn map_memory(&self, _: u32, gpa: u64, size: u64, host_addr: u64) -> io::Result<()> {
+        self.map_user_memory(mshv_region(gpa, size, host_addr))
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn unmap_memory(&self, _: u32, gpa: u64, size: u64, host_addr: u64) -> io::Result<()> {
+        self.unmap_user_memory(mshv_region(gpa, size, host_addr))
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn register_ioevent(&self, gpa: u64, event: RawFd) -> io::Result<()> {
+        mshv_ioctls::VmFd::register_ioevent(
+            self,
+            &borrow_eventfd(event),
+            &mshv_ioctls::IoEventAddress::Mmio(gpa),
+            mshv_ioctls::NoDatamatch,
+        )
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn unregister_ioevent(&self, gpa: u64, event: RawFd) -> io::Result<()> {
+        mshv_ioctls::VmFd::unregister_ioevent(
+            self,
+            &borrow_eventfd(event),
+            &mshv_ioctls::IoEventAddress::Mmio(gpa),
+            mshv_ioctls::NoDatamatch,
+        )
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn register_irqfd(&self, event: RawFd, gsi: u32) -> io::Result<()> {
+        mshv_ioctls::VmFd::register_irqfd(self, &borrow_eventfd(event), gsi)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn unregister_irqfd(&self, event: RawFd, gsi: u32) -> io::Result<()> {
+        mshv_ioctls::VmFd::unregister_irqfd(self, &borrow_eventfd(event), gsi)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn set_msi_routing(&self, routes: &[MsiRoute]) -> io::Result<()> {
+        use mshv_bindings::{mshv_user_irq_entry, mshv_user_irq_table};
+
+        // The table is followed by its entries, allocated as whole tables to keep it aligned.
+        let size = std::mem::size_of::<mshv_user_irq_table>();
+        let entries = routes.len() * std::mem::size_of::<mshv_user_irq_entry>();
+        let mut table: Vec<_> = (0..1 + entries.div_ceil(size))
+            .map(|_| mshv_user_irq_table::default())
+            .collect();
+        table[0].nr = routes.len() as u32;
+        let slice = unsafe { table[0].entries.as_mut_slice(routes.len()) };
+        for (entry, route) in slice.iter_mut().zip(routes) {
+            *entry = mshv_user_irq_entry {
+                gsi: route.gsi,
+                address_lo: route.addr as u32,
+                address_hi: (route.addr >> 32) as u32,
+                data: route.data,
+            };
+        }
+
+        mshv_ioctls::VmFd::set_msi_routing(self, &table[0])
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn signal_msi(&self, addr: u64, data: u32, _: Option<u32>) -> io::Result<()> {
+        // The delivery modes of the message are the interrupt types of the hypervisor.
+        let request = mshv_ioctls::InterruptRequest {
+            interrupt_type: (data >> 8) & 0x7,
+            apic_id: (addr >> 12) & 0xff,
+            vector: data & 0xff,
+            level_triggered: data & (1 << 15) != 0,
+            logical_destination_mode: addr & (1 << 2) != 0,
+            long_mode: false,
+        };
+
+        self.request_virtual_interrupt(&request)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn signal_msi(&self, _: u64, _: u32, _: Option<u32>) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "MSIs are only injected through irqfds",
+        ))
+    }
+}
+
+#[cfg(feature = "mshv")]
+fn mshv_region(gpa: u64, size: u64, host_addr: u64) -> mshv_bindings::mshv_user_mem_region {
+    use mshv_bindings::{MSHV_SET_MEM_BIT_EXECUTABLE, MSHV_SET_MEM_BIT_WRITABLE};
+
+    mshv_bindings::mshv_user_mem_region {
+        size,
+        guest_pfn: gpa >> 12,
+        userspace_addr: host_addr,
+        flags: (1 << MSHV_SET_MEM_BIT_WRITABLE | 1 << MSHV_SET_MEM_BIT_EXECUTABLE) as u8,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    use std::sync::{Arc, Mutex};
+
+    /// Records what the integration layer asks of the VM.
+    #[derive(Default)]
+    struct Recorder {
+        memory: Mutex<Vec<(u32, u64, u64, u64)>>,
+        irqfds: Mutex<Vec<(RawFd, u32)>>,
+        routes: Mutex<Vec<MsiRoute>>,
+        msis: Mutex<Vec<(u64, u32, Option<u32>)>>,
+    }
+
+    impl Hypervisor for Recorder {
+        fn map_memory(&self, slot: u32, gpa: u64, size: u64, host_addr: u64) -> io::Result<()> {
+            self.memory
+                .lock()
+                .unwrap()
+                .push((slot, gpa, size, host_addr));
+            Ok(())
+        }
+
+        fn unmap_memory(&self, slot: u32, gpa: u64, size: u64, host_addr: u64) -> io::Result<()> {
+            let mut memory = self.memory.lock().unwrap();
+            let index = memory
+                .iter()
+                .position(|&m| m == (slot, gpa, size, host_addr))
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            memory.remove(index);
+            Ok(())
+        }
+
+        fn register_ioevent(&self, _: u64, _: RawFd) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn unregister_ioevent(&self, _: u64, _: RawFd) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn register_irqfd(&self, event: RawFd, gsi: u32) -> io::Result<()> {
+            self.irqfds.lock().unwrap().push((event, gsi));
+            Ok(())
+        }
+
+        fn unregister_irqfd(&self, event: RawFd, gsi: u32) -> io::Result<()> {
+            self.irqfds.lock().unwrap().retain(|&i| i != (event, gsi));
+            Ok(())
+        }
+
+        fn set_msi_routing(&self, routes: &[MsiRoute]) -> io::Result<()> {
+            let mut routes = routes.to_vec();
+            routes.sort_by_key(|r| r.gsi);
+            *self.routes.lock().unwrap() = routes;
+            Ok(())
+        }
+
+        fn signal_msi(&self, addr: u64, data: u32, devid: Option<u32>) -> io::Result<()> {
+            self.msis.lock().unwrap().push((addr, data, devid));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn hypervisor() {
+        let vm = Arc::new(Recorder::default());
+
+        // Slots are reused, unmapping hands the mapping back to the hypervisor.
+        let mut slots = VmSlotManager::new(vm.clone(), 4);
+        assert_eq!(slots.map_region(0x1000, 0x1000, 0x7f00_0000).unwrap(), 4);
+        assert_eq!(slots.map_region(0x4000, 0x2000, 0x7f10_0000).unwrap(), 5);
+        slots.unmap_region(4).unwrap();
+        assert!(slots.unmap_region(4).is_err());
+        assert_eq!(slots.map_region(0x8000, 0x1000, 0x7f20_0000).unwrap(), 4);
+        assert_eq!(
+            *vm.memory.lock().unwrap(),
+            vec![
+                (5, 0x4000, 0x2000, 0x7f10_0000),
+                (4, 0x8000, 0x1000, 0x7f20_0000)
+            ]
+        );
+
+        // Each vector gets an irqfd, whose GSI follows the message of the vector.
+        let sink = VmIrqfdSink::new(vm.clone(), 24..26);
+        let function = PciAddress::new(0, 0, 3, 0);
+        sink.inject_vector(function, 0, 0xfee0_0000, 0x4021)
+            .unwrap();
+        sink.inject_vector(function, 0, 0xfee0_0000, 0x4021)
+            .unwrap();
+        sink.inject_vector(function, 1, 0xfee0_0000, 0x4022)
+            .unwrap();
+        sink.inject_vector(function, 0, 0xfee0_1000, 0x4021)
+            .unwrap();
+        // The same BDF on segment 1 is another function.
+        let other = PciAddress::new(1, 0, 3, 0);
+        sink.inject_vector(other, 0, 0xfee0_0000, 0x4023).unwrap();
+        let route = |gsi, addr, data| MsiRoute {
+            gsi,
+            addr,
+            data,
+            devid: 0x18,
+        };
+        assert_eq!(
+            *vm.routes.lock().unwrap(),
+            vec![
+                route(24, 0xfee0_1000, 0x4021),
+                route(25, 0xfee0_0000, 0x4022)
+            ]
+        );
+        // Out of GSIs, the MSI of 0001:00:03.0 is signaled along with its DeviceID.
+        assert_eq!(
+            *vm.msis.lock().unwrap(),
+            vec![(0xfee0_0000, 0x4023, Some(0x1_0018))]
+        );
+
+        // The count of the irqfd of vector 0 holds both raises.
+        let (event, gsi) = vm.irqfds.lock().unwrap()[0];
+        assert_eq!(gsi, 24);
+        let mut count = 0u64;
+        unsafe { libc::read(event, &mut count as *mut u64 as *mut libc::c_void, 8) };
+        assert_eq!(count, 3);
+
+        drop(sink);
+        assert!(vm.irqfds.lock().unwrap().is_empty());
+    }
+}
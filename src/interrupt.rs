@@ -0,0 +1,1034 @@
+//! Interrupt delivery from the simulated device to the guest.
+//!
+//! A device raises an MSI by a memory write TLP to the MSI doorbell range, just like a real
+//! device. The bridge recognizes such writes and hands the address and data over to the
+//! [`MsiSink`] of the hypervisor, along with the requester ID of the function, which the ITS of
+//! aarch64 takes as the DeviceID of the MSI.
+//!
+//! MSI-X is emulated by the adapter the way VFIO does it: the guest accesses to the MSI-X table
+//! and PBA never reach the device, which raises vectors by number with a vendor defined
+//! message. The bridge looks the message of the vector up and delivers it unless it is masked.
+//!
+//! Legacy INTx is signaled by the Assert_INTx and Deassert_INTx messages, which carry the
+//! requester ID of the function. The bridge keeps the virtual wire of each function, ORs the
+//! wires together and hands the level of each pin to the [`MsiSink`].
+//!
+//! A device model raises its interrupts through an [`IrqHandle`], which follows what the guest
+//! programmed into the configuration space of the model.
+
+use crate::adapter::Result;
+use crate::hypervisor::{eventfd, signal};
+use crate::{
+    Hypervisor, MessageExtra, MessageRouting, MsiRoute, MsixSnapshot, PciAdapterError, PciAddress,
+    PciLane, TlpBuilder, MSG_ASSERT_INTA, MSG_DEASSERT_INTA,
+};
+
+use log::{debug, error};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+/// Start of the x86 MSI doorbell range.
+pub const MSI_DOORBELL_BASE: u64 = 0xfee0_0000;
+/// Size of the x86 MSI doorbell range.
+pub const MSI_DOORBELL_SIZE: u64 = 0x10_0000;
+
+/// The addresses the device writes its MSIs to unless the VMM says otherwise: the x86 MSI
+/// range on x86_64. Other platforms have no fixed doorbell, e.g. the GITS_TRANSLATER register
+/// of the ITS of aarch64 sits where the VMM placed the ITS, see
+/// [`crate::PciAdapterBuilder::msi_window`].
+#[cfg(target_arch = "x86_64")]
+pub(crate) const DEFAULT_MSI_WINDOW: Range<u64> =
+    MSI_DOORBELL_BASE..MSI_DOORBELL_BASE + MSI_DOORBELL_SIZE;
+#[cfg(not(target_arch = "x86_64"))]
+pub(crate) const DEFAULT_MSI_WINDOW: Range<u64> = 0..0;
+
+/// Hypervisor hook injecting MSIs into the guest.
+pub trait MsiSink: Send {
+    /// Inject the MSI the device wrote `data` to `addr` for.
+    fn inject(&self, addr: u64, data: u32) -> io::Result<()>;
+
+    /// Inject the MSI function `source` wrote `data` to `addr` for. The address of the
+    /// function is the DeviceID the ITS of aarch64 translates the MSI with, see
+    /// [`PciAddress::devid`], sinks which do not need it inject the message.
+    fn inject_from(&self, source: PciAddress, addr: u64, data: u32) -> io::Result<()> {
+        let _ = source;
+        self.inject(addr, data)
+    }
+
+    /// Inject MSI-X vector `vector` of function `source`, which the guest programmed with
+    /// `addr` and `data`. Sinks which do not tell the vectors apart inject the message.
+    fn inject_vector(
+        &self,
+        source: PciAddress,
+        vector: u16,
+        addr: u64,
+        data: u32,
+    ) -> io::Result<()> {
+        let _ = vector;
+        self.inject_from(source, addr, data)
+    }
+
+    /// MSI-X of function `source` is enabled with `vectors` vectors, all of them unmasked
+    /// unless [`MsiSink::mask_vector`] follows.
+    fn enable_msix(&self, source: PciAddress, vectors: u16) -> io::Result<()> {
+        let _ = (source, vectors);
+        Ok(())
+    }
+
+    /// MSI-X of function `source` is disabled.
+    fn disable_msix(&self, source: PciAddress) -> io::Result<()> {
+        let _ = source;
+        Ok(())
+    }
+
+    /// MSI-X vector `vector` of function `source` is masked or unmasked by the guest. The
+    /// bridge holds back the vectors raised while masked, so sinks may ignore it.
+    fn mask_vector(&self, source: PciAddress, vector: u16, masked: bool) -> io::Result<()> {
+        let _ = (source, vector, masked);
+        Ok(())
+    }
+
+    /// Set the level of INTx pin `pin` of the bridge, 1 for INTA to 4 for INTD. Sinks which do
+    /// not route INTx refuse it.
+    fn set_intx(&self, pin: u8, level: bool) -> io::Result<()> {
+        let _ = (pin, level);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "INTx is not routed",
+        ))
+    }
+
+    /// Set the level of INTx pin `pin`, which the adapter routed to `gsi` and reported to the
+    /// guest in the Interrupt Line register, see [`crate::PciAdapter::intx_line`]. Sinks which
+    /// only know the pins leave it to [`MsiSink::set_intx`].
+    fn set_intx_line(&self, pin: u8, gsi: u32, level: bool) -> io::Result<()> {
+        let _ = gsi;
+        self.set_intx(pin, level)
+    }
+}
+
+/// [`MsiSink`] signaling MSIs and the routed INTx lines through the VM of a [`Hypervisor`].
+pub struct VmMsiSink<H> {
+    vm: Arc<H>,
+}
+
+/// [`VmMsiSink`] signaling MSIs through `KVM_SIGNAL_MSI` and the routed INTx lines through
+/// `KVM_IRQ_LINE`, the VM needs an in-kernel irqchip. On aarch64 the MSIs carry the segment and
+/// BDF of the function as DeviceID for the ITS.
+#[cfg(feature = "kvm")]
+pub type KvmMsiSink = VmMsiSink<kvm_ioctls::VmFd>;
+
+/// [`VmMsiSink`] injecting MSIs as virtual interrupts of mshv. INTx is left to the interrupt
+/// controllers of the VMM.
+#[cfg(feature = "mshv")]
+pub type MshvMsiSink = VmMsiSink<mshv_ioctls::VmFd>;
+
+impl<H: Hypervisor> VmMsiSink<H> {
+    pub fn new(vm: Arc<H>) -> Self {
+        VmMsiSink { vm }
+    }
+}
+
+impl<H: Hypervisor> MsiSink for VmMsiSink<H> {
+    fn inject(&self, addr: u64, data: u32) -> io::Result<()> {
+        self.vm.signal_msi(addr, data, None)
+    }
+
+    fn inject_from(&self, source: PciAddress, addr: u64, data: u32) -> io::Result<()> {
+        self.vm.signal_msi(addr, data, Some(source.devid()))
+    }
+
+    fn set_intx_line(&self, _: u8, gsi: u32, level: bool) -> io::Result<()> {
+        self.vm.set_irq_line(gsi, level)
+    }
+}
+
+/// [`MsiSink`] delivering each MSI-X vector through an irqfd of its own. The bridge signals
+/// the eventfd of a vector and the hypervisor injects the message routed to its GSI, so the
+/// interrupts of the device bypass the VMM. MSIs and INTx go through a [`VmMsiSink`].
+///
+/// A vector is routed when it is first raised and again when the guest changed its message.
+/// Setting the MSI routes replaces those of the VMM, so the sink needs GSIs the VMM does not
+/// route itself. Once they are used up, the vectors left are injected as MSIs.
+pub struct VmIrqfdSink<H: Hypervisor> {
+    vm: Arc<H>,
+    gsis: Range<u32>,
+    vectors: Mutex<HashMap<(PciAddress, u16), IrqfdVector>>,
+    fallback: VmMsiSink<H>,
+}
+
+/// [`VmIrqfdSink`] registering KVM irqfds.
+#[cfg(feature = "kvm")]
+pub type KvmIrqfdSink = VmIrqfdSink<kvm_ioctls::VmFd>;
+
+/// [`VmIrqfdSink`] registering mshv irqfds.
+#[cfg(feature = "mshv")]
+pub type MshvIrqfdSink = VmIrqfdSink<mshv_ioctls::VmFd>;
+
+/// The GSI of an MSI-X vector, its irqfd and the message it is routed to.
+struct IrqfdVector {
+    gsi: u32,
+    event: RawFd,
+    message: (u64, u32),
+}
+
+impl<H: Hypervisor> VmIrqfdSink<H> {
+    /// Route the vectors to the GSIs in `gsis`, which the VMM does not use itself.
+    pub fn new(vm: Arc<H>, gsis: Range<u32>) -> Self {
+        VmIrqfdSink {
+            fallback: VmMsiSink::new(vm.clone()),
+            vm,
+            gsis,
+            vectors: Default::default(),
+        }
+    }
+
+    fn set_routing(&self, vectors: &HashMap<(PciAddress, u16), IrqfdVector>) -> io::Result<()> {
+        let routes: Vec<_> = vectors
+            .iter()
+            .map(|(&(source, _), vector)| MsiRoute {
+                gsi: vector.gsi,
+                addr: vector.message.0,
+                data: vector.message.1,
+                devid: source.devid(),
+            })
+            .collect();
+        self.vm.set_msi_routing(&routes)
+    }
+}
+
+impl<H: Hypervisor> MsiSink for VmIrqfdSink<H> {
+    fn inject(&self, addr: u64, data: u32) -> io::Result<()> {
+        self.fallback.inject(addr, data)
+    }
+
+    fn inject_from(&self, source: PciAddress, addr: u64, data: u32) -> io::Result<()> {
+        self.fallback.inject_from(source, addr, data)
+    }
+
+    fn inject_vector(
+        &self,
+        source: PciAddress,
+        vector: u16,
+        addr: u64,
+        data: u32,
+    ) -> io::Result<()> {
+        let mut vectors = self.vectors.lock().unwrap();
+        if let Some(routed) = vectors.get(&(source, vector)) {
+            if routed.message == (addr, data) {
+                return signal(routed.event);
+            }
+        }
+
+        match vectors.get_mut(&(source, vector)) {
+            Some(routed) => {
+                routed.message = (addr, data);
+                self.set_routing(&vectors)?;
+            }
+            None => {
+                let gsi = self.gsis.start + vectors.len() as u32;
+                if gsi >= self.gsis.end {
+                    return self.fallback.inject_from(source, addr, data);
+                }
+
+                let event = eventfd()?;
+                vectors.insert(
+                    (source, vector),
+                    IrqfdVector {
+                        gsi,
+                        event,
+                        message: (addr, data),
+                    },
+                );
+                let registered = self
+                    .set_routing(&vectors)
+                    .and_then(|_| self.vm.register_irqfd(event, gsi));
+                if let Err(e) = registered {
+                    vectors.remove(&(source, vector));
+                    unsafe { libc::close(event) };
+                    return Err(e);
+                }
+                debug!("route MSI-X vector {} of {} to GSI {}", vector, source, gsi);
+            }
+        }
+
+        signal(vectors[&(source, vector)].event)
+    }
+
+    fn set_intx_line(&self, pin: u8, gsi: u32, level: bool) -> io::Result<()> {
+        self.fallback.set_intx_line(pin, gsi, level)
+    }
+}
+
+impl<H: Hypervisor> Drop for VmIrqfdSink<H> {
+    fn drop(&mut self) {
+        for vector in self.vectors.get_mut().unwrap().values() {
+            if let Err(e) = self.vm.unregister_irqfd(vector.event, vector.gsi) {
+                error!(
+                    "Failed to unregister the irqfd of GSI {}: {}",
+                    vector.gsi, e
+                );
+            }
+            unsafe { libc::close(vector.event) };
+        }
+    }
+}
+
+/// Vectors of the group of a function raising MSIs, the most MSI offers.
+pub(crate) const MSI_VECTORS: u16 = 32;
+
+/// The interrupts a group of vectors stands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptKind {
+    Msi,
+    Msix,
+}
+
+/// Hypervisor hook injecting the interrupts of the functions the way VMMs usually manage
+/// them: each function has a group of vectors, each vector is programmed with a message and
+/// triggered by its index. [`VectorSink`] passes the MSIs of the bridge on to it.
+pub trait InterruptSink: Send {
+    /// Set up the group of `vectors` vectors of function `source`, replacing the group it had.
+    /// The vectors are unmasked and not programmed yet.
+    fn configure_group(
+        &self,
+        source: PciAddress,
+        kind: InterruptKind,
+        vectors: u16,
+    ) -> io::Result<()>;
+
+    /// Tear down the group of function `source`.
+    fn release_group(&self, source: PciAddress) -> io::Result<()>;
+
+    /// Program vector `vector` of the group of `source` with the message of `data` at `addr`.
+    fn configure_vector(
+        &self,
+        source: PciAddress,
+        vector: u16,
+        addr: u64,
+        data: u32,
+    ) -> io::Result<()>;
+
+    /// Inject the message vector `vector` of the group of `source` is programmed with.
+    fn trigger(&self, source: PciAddress, vector: u16) -> io::Result<()>;
+
+    fn mask(&self, source: PciAddress, vector: u16) -> io::Result<()> {
+        let _ = (source, vector);
+        Ok(())
+    }
+
+    fn unmask(&self, source: PciAddress, vector: u16) -> io::Result<()> {
+        let _ = (source, vector);
+        Ok(())
+    }
+}
+
+/// [`MsiSink`] injecting through an [`InterruptSink`].
+///
+/// MSI-X vectors map to the vectors of the group one to one, the group is set up when the
+/// guest enables MSI-X. MSIs come with their message only, so their messages are routed to
+/// the vectors of an MSI group as they show up, reusing the vectors round robin once all of
+/// them are taken.
+pub struct VectorSink<S> {
+    sink: S,
+    groups: Mutex<HashMap<PciAddress, VectorGroup>>,
+}
+
+/// The messages programmed into the vectors of a group.
+struct VectorGroup {
+    kind: InterruptKind,
+    messages: Vec<Option<(u64, u32)>>,
+    /// The MSI vector to reuse next.
+    next: usize,
+}
+
+impl<S: InterruptSink> VectorSink<S> {
+    pub fn new(sink: S) -> Self {
+        VectorSink {
+            sink,
+            groups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    /// The group of `source` of `kind`, set up with `vectors` vectors if it has none.
+    fn group<'a>(
+        &self,
+        groups: &'a mut HashMap<PciAddress, VectorGroup>,
+        source: PciAddress,
+        kind: InterruptKind,
+        vectors: u16,
+    ) -> io::Result<&'a mut VectorGroup> {
+        if groups.get(&source).map(|group| group.kind) != Some(kind) {
+            self.sink.configure_group(source, kind, vectors)?;
+            let group = VectorGroup {
+                kind,
+                messages: vec![None; vectors as usize],
+                next: 0,
+            };
+            groups.insert(source, group);
+        }
+        Ok(groups.get_mut(&source).unwrap())
+    }
+
+    /// Trigger `vector` of `group`, programming it with the message first if needed.
+    fn trigger(
+        &self,
+        group: &mut VectorGroup,
+        source: PciAddress,
+        vector: usize,
+        message: (u64, u32),
+    ) -> io::Result<()> {
+        if group.messages[vector] != Some(message) {
+            debug!(
+                "route MSI {:#x?} of {} to vector {}",
+                message, source, vector
+            );
+            self.sink
+                .configure_vector(source, vector as u16, message.0, message.1)?;
+            group.messages[vector] = Some(message);
+        }
+        self.sink.trigger(source, vector as u16)
+    }
+}
+
+impl<S: InterruptSink> MsiSink for VectorSink<S> {
+    fn inject(&self, addr: u64, data: u32) -> io::Result<()> {
+        self.inject_from(PciAddress::default(), addr, data)
+    }
+
+    fn inject_from(&self, source: PciAddress, addr: u64, data: u32) -> io::Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = self.group(&mut groups, source, InterruptKind::Msi, MSI_VECTORS)?;
+        let vector = match group.messages.iter().position(|&m| m == Some((addr, data))) {
+            Some(vector) => vector,
+            None => match group.messages.iter().position(Option::is_none) {
+                Some(vector) => vector,
+                None => {
+                    let vector = group.next;
+                    group.next = (vector + 1) % group.messages.len();
+                    vector
+                }
+            },
+        };
+        self.trigger(group, source, vector, (addr, data))
+    }
+
+    fn inject_vector(
+        &self,
+        source: PciAddress,
+        vector: u16,
+        addr: u64,
+        data: u32,
+    ) -> io::Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        match groups.get_mut(&source) {
+            Some(group)
+                if group.kind == InterruptKind::Msix
+                    && (vector as usize) < group.messages.len() =>
+            {
+                self.trigger(group, source, vector as usize, (addr, data))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("MSI-X vector {} of {} is not enabled", vector, source),
+            )),
+        }
+    }
+
+    fn enable_msix(&self, source: PciAddress, vectors: u16) -> io::Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        groups.remove(&source);
+        self.group(&mut groups, source, InterruptKind::Msix, vectors)
+            .map(|_| ())
+    }
+
+    fn disable_msix(&self, source: PciAddress) -> io::Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        if groups.remove(&source).is_some() {
+            self.sink.release_group(source)?;
+        }
+        Ok(())
+    }
+
+    fn mask_vector(&self, source: PciAddress, vector: u16, masked: bool) -> io::Result<()> {
+        if masked {
+            self.sink.mask(source, vector)
+        } else {
+            self.sink.unmask(source, vector)
+        }
+    }
+}
+
+/// [`InterruptSink`] calling back with the function and the message of every interrupt it
+/// injects, e.g. to tell a test which interrupts a device raised.
+pub struct CallbackSink<F> {
+    callback: F,
+    messages: Mutex<HashMap<(PciAddress, u16), (u64, u32)>>,
+}
+
+impl<F: Fn(PciAddress, u64, u32) + Send> CallbackSink<F> {
+    pub fn new(callback: F) -> Self {
+        CallbackSink {
+            callback,
+            messages: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<F: Fn(PciAddress, u64, u32) + Send> InterruptSink for CallbackSink<F> {
+    fn configure_group(&self, source: PciAddress, _: InterruptKind, _: u16) -> io::Result<()> {
+        self.release_group(source)
+    }
+
+    fn release_group(&self, source: PciAddress) -> io::Result<()> {
+        self.messages
+            .lock()
+            .unwrap()
+            .retain(|&(function, _), _| function != source);
+        Ok(())
+    }
+
+    fn configure_vector(
+        &self,
+        source: PciAddress,
+        vector: u16,
+        addr: u64,
+        data: u32,
+    ) -> io::Result<()> {
+        self.messages
+            .lock()
+            .unwrap()
+            .insert((source, vector), (addr, data));
+        Ok(())
+    }
+
+    fn trigger(&self, source: PciAddress, vector: u16) -> io::Result<()> {
+        let message = self
+            .messages
+            .lock()
+            .unwrap()
+            .get(&(source, vector))
+            .copied();
+        match message {
+            Some((addr, data)) => {
+                (self.callback)(source, addr, data);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("vector {} of {} is not programmed", vector, source),
+            )),
+        }
+    }
+}
+
+/// Capability ID of MSI-X.
+pub(crate) const PCI_CAP_ID_MSIX: u8 = 0x11;
+/// Capability ID of MSI.
+const PCI_CAP_ID_MSI: u8 = 0x05;
+/// Vendor defined type 1 message routed to the root complex a device model sends to raise an
+/// MSI-X vector of the function of its requester ID, the payload is the vector number.
+/// Receivers which do not know the message discard it silently.
+pub(crate) const MSIX_VECTOR_MESSAGE: u8 = 0x7f;
+/// Message code of PM_PME, a message without data routed to the root complex.
+pub(crate) const PM_PME: u8 = 0x18;
+
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_TABLE_SIZE_MASK: u32 = 0x7ff;
+const MSIX_BIR_MASK: u32 = 0x7;
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_VECTOR_MASKED: u32 = 0x1;
+
+/// The structure an MSI-X access hits and the offset into it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MsixStructure {
+    Table(u64),
+    Pba(u64),
+}
+
+/// Location of the MSI-X table and PBA, decoded from the MSI-X capability of the device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct MsixCap {
+    /// Register index of the capability header.
+    pub reg: usize,
+    pub table_size: usize,
+    pub table_bar: usize,
+    pub table_offset: u64,
+    pub pba_bar: usize,
+    pub pba_offset: u64,
+}
+
+impl MsixCap {
+    /// Decode the three DWs of the capability at register `reg`.
+    pub fn new(reg: usize, dw: [u32; 3]) -> Self {
+        MsixCap {
+            reg,
+            table_size: ((dw[0] >> 16) & MSIX_TABLE_SIZE_MASK) as usize + 1,
+            table_bar: (dw[1] & MSIX_BIR_MASK) as usize,
+            table_offset: (dw[1] & !MSIX_BIR_MASK) as u64,
+            pba_bar: (dw[2] & MSIX_BIR_MASK) as usize,
+            pba_offset: (dw[2] & !MSIX_BIR_MASK) as u64,
+        }
+    }
+
+    /// Find the structure `offset` into BAR `bar` belongs to, if any.
+    pub fn locate(&self, bar: usize, offset: u64) -> Option<MsixStructure> {
+        let table_len = (self.table_size * MSIX_ENTRY_SIZE) as u64;
+        // One pending bit per vector, in QWs.
+        let pba_len = (self.table_size.div_ceil(64) * 8) as u64;
+
+        if bar == self.table_bar
+            && (self.table_offset..self.table_offset + table_len).contains(&offset)
+        {
+            Some(MsixStructure::Table(offset - self.table_offset))
+        } else if bar == self.pba_bar
+            && (self.pba_offset..self.pba_offset + pba_len).contains(&offset)
+        {
+            Some(MsixStructure::Pba(offset - self.pba_offset))
+        } else {
+            None
+        }
+    }
+}
+
+/// The guest programmed MSI-X table and the pending bits of a device.
+///
+/// The bridge keeps them on behalf of the device model, which raises vectors by number and
+/// never sees the messages. A vector raised while masked becomes pending and is sent once
+/// it is unmasked, as the specification requires.
+#[derive(Debug)]
+pub(crate) struct MsixTable {
+    /// Message address low, message address high, message data and vector control.
+    entries: Vec<[u32; 4]>,
+    pending: Vec<bool>,
+    control: u16,
+}
+
+impl MsixTable {
+    pub fn new(size: usize, control: u16) -> Self {
+        MsixTable {
+            // Every vector is masked after reset.
+            entries: vec![[0, 0, 0, MSIX_VECTOR_MASKED]; size],
+            pending: vec![false; size],
+            control,
+        }
+    }
+
+    pub fn save(&self) -> MsixSnapshot {
+        MsixSnapshot {
+            control: self.control,
+            entries: self.entries.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+
+    pub fn restore(snapshot: &MsixSnapshot) -> Self {
+        let mut pending = snapshot.pending.clone();
+        pending.resize(snapshot.entries.len(), false);
+
+        MsixTable {
+            entries: snapshot.entries.clone(),
+            pending,
+            control: snapshot.control,
+        }
+    }
+
+    /// Whether each vector is masked, `None` while MSI-X is disabled.
+    pub fn masks(&self) -> Option<Vec<bool>> {
+        if self.control & MSIX_ENABLE == 0 {
+            return None;
+        }
+        Some((0..self.entries.len()).map(|v| self.is_masked(v)).collect())
+    }
+
+    fn is_masked(&self, vector: usize) -> bool {
+        self.control & MSIX_FUNCTION_MASK != 0 || self.entries[vector][3] & MSIX_VECTOR_MASKED != 0
+    }
+
+    fn message(&self, vector: usize) -> (u64, u32) {
+        let entry = self.entries[vector];
+        (((entry[1] as u64) << 32) | entry[0] as u64, entry[2])
+    }
+
+    pub fn read(&self, structure: MsixStructure, data: &mut [u8]) {
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = match structure {
+                MsixStructure::Table(offset) => {
+                    let offset = offset as usize + i;
+                    self.entries
+                        .get(offset / MSIX_ENTRY_SIZE)
+                        .map(|entry| {
+                            entry[(offset % MSIX_ENTRY_SIZE) / 4].to_le_bytes()[offset % 4]
+                        })
+                        .unwrap_or(0)
+                }
+                MsixStructure::Pba(offset) => {
+                    let first = (offset as usize + i) * 8;
+                    (0..8)
+                        .filter(|bit| self.pending.get(first + bit) == Some(&true))
+                        .fold(0, |b, bit| b | 1 << bit)
+                }
+            };
+        }
+    }
+
+    /// Update the table, the PBA is read-only. Returns the pending vectors unmasked by the
+    /// write with their messages.
+    pub fn write(&mut self, structure: MsixStructure, data: &[u8]) -> Vec<(usize, u64, u32)> {
+        let offset = match structure {
+            MsixStructure::Table(offset) => offset as usize,
+            MsixStructure::Pba(_) => return vec![],
+        };
+
+        for (i, b) in data.iter().enumerate() {
+            let offset = offset + i;
+            if let Some(entry) = self.entries.get_mut(offset / MSIX_ENTRY_SIZE) {
+                let dw = &mut entry[(offset % MSIX_ENTRY_SIZE) / 4];
+                let mut bytes = dw.to_le_bytes();
+                bytes[offset % 4] = *b;
+                *dw = u32::from_le_bytes(bytes);
+            }
+        }
+
+        self.flush()
+    }
+
+    /// Back to the state after reset: MSI-X disabled and every vector masked.
+    pub fn reset(&mut self) {
+        *self = MsixTable::new(
+            self.entries.len(),
+            self.control & MSIX_TABLE_SIZE_MASK as u16,
+        );
+    }
+
+    /// Update the message control of the capability, returns the pending vectors unmasked by
+    /// the change with their messages.
+    pub fn set_control(&mut self, control: u16) -> Vec<(usize, u64, u32)> {
+        self.control = control;
+        self.flush()
+    }
+
+    /// Raise `vector`. Returns its message unless the vector is masked, then it becomes
+    /// pending instead. Nothing is sent while MSI-X is disabled.
+    pub fn trigger(&mut self, vector: usize) -> Option<(u64, u32)> {
+        if vector >= self.entries.len() || self.control & MSIX_ENABLE == 0 {
+            return None;
+        }
+
+        if self.is_masked(vector) {
+            self.pending[vector] = true;
+            return None;
+        }
+
+        Some(self.message(vector))
+    }
+
+    /// Take the pending vectors which are no longer masked with their messages.
+    fn flush(&mut self) -> Vec<(usize, u64, u32)> {
+        let mut messages = vec![];
+        if self.control & MSIX_ENABLE == 0 {
+            return messages;
+        }
+
+        for vector in 0..self.entries.len() {
+            if self.pending[vector] && !self.is_masked(vector) {
+                self.pending[vector] = false;
+                let (addr, data) = self.message(vector);
+                messages.push((vector, addr, data));
+            }
+        }
+
+        messages
+    }
+}
+
+/// Command and status register.
+const COMMAND_REG: usize = 1;
+/// Capabilities pointer at 0x34.
+const CAP_PTR_REG: usize = 13;
+/// Interrupt line and pin at 0x3c.
+pub(crate) const INTERRUPT_REG: usize = 15;
+
+const MSI_ENABLE: u32 = 0x1;
+const MSI_64BIT: u32 = 1 << 7;
+const MSI_PER_VECTOR_MASK: u32 = 1 << 8;
+/// Interrupt Disable of the command register.
+const COMMAND_INTX_DISABLE: u32 = 1 << 10;
+/// Capabilities List of the status register.
+const STATUS_CAP_LIST: u32 = 0x10 << 16;
+/// Upper bound of the capabilities in the 192 bytes after the header.
+const MAX_CAPS: usize = 48;
+
+/// The interrupts of a function of a device model, raised the way the guest programmed them
+/// into its configuration space. The configuration space belongs to the model, so it is read
+/// by the `config` callback taking the register index, e.g. backed by
+/// [`PcieConfiguration::read_config_register`](crate::PcieConfiguration).
+pub struct IrqHandle<'a> {
+    lane: &'a PciLane,
+    requester: u16,
+    /// Whether the INTx pin is asserted, the messages are only sent when it changes.
+    asserted: Cell<bool>,
+}
+
+impl<'a> IrqHandle<'a> {
+    pub fn new(lane: &'a PciLane, requester: u16) -> Self {
+        IrqHandle {
+            lane,
+            requester,
+            asserted: Cell::new(false),
+        }
+    }
+
+    /// Raise MSI `vector` as the MSI capability says. Returns whether the MSI is sent, which it
+    /// is not while MSI is disabled, the vector is not enabled or it is masked.
+    pub fn raise_msi(&self, config: impl Fn(usize) -> u32, vector: u8) -> Result<bool> {
+        let reg = match find_capability(&config, PCI_CAP_ID_MSI) {
+            Some(reg) => reg,
+            None => return Ok(false),
+        };
+        let control = config(reg) >> 16;
+        // The data and mask registers come after the upper address of a 64-bit capability.
+        let (addr, data_reg) = if control & MSI_64BIT != 0 {
+            (
+                config(reg + 1) as u64 | (config(reg + 2) as u64) << 32,
+                reg + 3,
+            )
+        } else {
+            (config(reg + 1) as u64, reg + 2)
+        };
+
+        let vectors = 1u32 << ((control >> 4) & 0x7).min(5);
+        let masked = control & MSI_PER_VECTOR_MASK != 0 && config(data_reg + 1) >> vector & 1 != 0;
+        if control & MSI_ENABLE == 0 || vector as u32 >= vectors || masked {
+            return Ok(false);
+        }
+
+        // The function sets the low bits of the data to the vector.
+        let data = (config(data_reg) & 0xffff & !(vectors - 1)) | vector as u32;
+        self.lane
+            .raise_msi(self.requester, addr, data)
+            .map_err(|_| PciAdapterError::Disconnected)?;
+        Ok(true)
+    }
+
+    /// Raise MSI-X `vector`, the bridge knows the table the guest programmed.
+    pub fn raise_msix(&self, vector: u16) -> Result<()> {
+        self.lane
+            .raise_msix(self.requester, vector)
+            .map_err(|_| PciAdapterError::Disconnected)
+    }
+
+    /// Assert or deassert the INTx pin of the Interrupt Pin register. The pin stays
+    /// deasserted while Interrupt Disable is set in the command register. Returns whether a
+    /// message is sent, which it is only when the pin changes.
+    pub fn set_intx(&self, config: impl Fn(usize) -> u32, level: bool) -> Result<bool> {
+        let pin = (config(INTERRUPT_REG) >> 8) as u8;
+        if !(1..=4).contains(&pin) {
+            return Ok(false);
+        }
+
+        let level = level && config(COMMAND_REG) & COMMAND_INTX_DISABLE == 0;
+        if level == self.asserted.get() {
+            return Ok(false);
+        }
+
+        let base = if level {
+            MSG_ASSERT_INTA
+        } else {
+            MSG_DEASSERT_INTA
+        };
+        let message = MessageExtra::routed(self.requester, base + pin - 1, MessageRouting::Local);
+        self.lane
+            .tx
+            .send(TlpBuilder::message(message).build())
+            .map_err(|_| PciAdapterError::Disconnected)?;
+        self.asserted.set(level);
+        Ok(true)
+    }
+}
+
+/// Register index of the capability `id` in the capability list read by `config`.
+fn find_capability(config: &impl Fn(usize) -> u32, id: u8) -> Option<usize> {
+    if config(COMMAND_REG) & STATUS_CAP_LIST == 0 {
+        return None;
+    }
+
+    let mut offset = config(CAP_PTR_REG) & 0xfc;
+    for _ in 0..MAX_CAPS {
+        if offset == 0 {
+            break;
+        }
+        let header = config(offset as usize / 4);
+        if header as u8 == id {
+            return Some(offset as usize / 4);
+        }
+        offset = (header >> 8) & 0xfc;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PacketType, Tlp};
+
+    #[test]
+    fn msix_table() {
+        let cap = MsixCap::new(0x10, [0x0001_0011, 0x0000_0000, 0x0000_0800]);
+        assert_eq!(cap.table_size, 2);
+        assert_eq!(cap.locate(0, 0x1c), Some(MsixStructure::Table(0x1c)));
+        assert_eq!(cap.locate(0, 0x20), None);
+        assert_eq!(cap.locate(0, 0x804), Some(MsixStructure::Pba(0x4)));
+        assert_eq!(cap.locate(1, 0), None);
+
+        let mut table = MsixTable::new(cap.table_size, 0);
+        let entry = [0x00, 0x10, 0xe0, 0xfe, 0, 0, 0, 0, 0x21, 0x40, 0, 0];
+        assert!(table.write(MsixStructure::Table(0x10), &entry).is_empty());
+        assert_eq!(table.trigger(1), None);
+
+        // Masked vectors become pending until they are unmasked.
+        assert!(table.set_control(MSIX_ENABLE).is_empty());
+        assert_eq!(table.trigger(1), None);
+        let mut pba = [0u8; 8];
+        table.read(MsixStructure::Pba(0), &mut pba);
+        assert_eq!(pba, [0x2, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(
+            table.write(MsixStructure::Table(0x1c), &[0, 0, 0, 0]),
+            vec![(1, 0xfee0_1000, 0x4021)]
+        );
+        table.read(MsixStructure::Pba(0), &mut pba);
+        assert_eq!(pba, [0; 8]);
+        assert_eq!(table.trigger(1), Some((0xfee0_1000, 0x4021)));
+        assert_eq!(table.trigger(2), None);
+
+        let mut data = [0u8; 4];
+        table.read(MsixStructure::Table(0x18), &mut data);
+        assert_eq!(data, [0x21, 0x40, 0, 0]);
+
+        assert!(table
+            .set_control(MSIX_ENABLE | MSIX_FUNCTION_MASK)
+            .is_empty());
+        assert_eq!(table.trigger(1), None);
+        assert_eq!(
+            table.set_control(MSIX_ENABLE),
+            vec![(1, 0xfee0_1000, 0x4021)]
+        );
+    }
+
+    #[cfg(feature = "kvm")]
+    #[test]
+    fn irqfd_sink() {
+        let vm = std::sync::Arc::new(kvm_ioctls::Kvm::new().unwrap().create_vm().unwrap());
+        vm.create_irq_chip().unwrap();
+        let sink = KvmIrqfdSink::new(vm, 24..26);
+        let function = PciAddress::new(0, 0, 3, 0);
+        let gsi = |vector| sink.vectors.lock().unwrap()[&(function, vector)].gsi;
+
+        sink.inject_vector(function, 0, 0xfee0_0000, 0x4021)
+            .unwrap();
+        sink.inject_vector(function, 1, 0xfee0_0000, 0x4022)
+            .unwrap();
+        assert_eq!((gsi(0), gsi(1)), (24, 25));
+
+        // A new message keeps the GSI of the vector, vectors beyond the GSIs are signaled.
+        sink.inject_vector(function, 0, 0xfee0_1000, 0x4021)
+            .unwrap();
+        assert_eq!(gsi(0), 24);
+        assert_eq!(
+            sink.vectors.lock().unwrap()[&(function, 0)].message,
+            (0xfee0_1000, 0x4021)
+        );
+        // Without a vCPU the MSI reaches no APIC, which KVM_SIGNAL_MSI reports.
+        let _ = sink.inject_vector(PciAddress::new(0, 0, 4, 0), 0, 0xfee0_0000, 0x4023);
+        assert_eq!(sink.vectors.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn irq_handle() {
+        // A 64-bit MSI capability at 0x50 with 2 vectors enabled, vector 1 masked, and INTA.
+        let mut regs = [0u32; 64];
+        regs[COMMAND_REG] = STATUS_CAP_LIST;
+        regs[CAP_PTR_REG] = 0x50;
+        regs[INTERRUPT_REG] = 0x100;
+        regs[0x14] = 0x0191_0005;
+        regs[0x15..0x19].copy_from_slice(&[0xfee0_1000, 0, 0x4020, 0b10]);
+
+        let (device, bridge) = PciLane::pair();
+        let irq = IrqHandle::new(&device, 0x18);
+        assert_eq!(irq.raise_msi(|reg| regs[reg], 0), Ok(true));
+        let tlp = bridge.rx.try_recv().unwrap();
+        assert!(matches!(tlp.header._type, PacketType::MemoryWrite(e) if e.addr == 0xfee0_1000));
+        assert_eq!(
+            tlp.data,
+            Some(vec![u32::from_be_bytes(0x4020u32.to_le_bytes())])
+        );
+        assert_eq!(irq.raise_msi(|reg| regs[reg], 1), Ok(false));
+        assert_eq!(irq.raise_msi(|reg| regs[reg], 2), Ok(false));
+
+        let code = |tlp: Tlp| match tlp.header._type {
+            PacketType::RoutedMessage(MessageExtra {
+                requester: 0x18,
+                code,
+                routing: MessageRouting::Local,
+                ..
+            }) => code,
+            t => panic!("unexpected {:?}", t),
+        };
+        assert_eq!(irq.set_intx(|reg| regs[reg], true), Ok(true));
+        assert_eq!(irq.set_intx(|reg| regs[reg], true), Ok(false));
+        assert_eq!(code(bridge.rx.try_recv().unwrap()), MSG_ASSERT_INTA);
+
+        // Interrupt Disable deasserts the pin.
+        regs[COMMAND_REG] |= COMMAND_INTX_DISABLE;
+        assert_eq!(irq.set_intx(|reg| regs[reg], true), Ok(true));
+        assert_eq!(code(bridge.rx.try_recv().unwrap()), MSG_DEASSERT_INTA);
+        assert!(bridge.rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn vector_sink() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let sink = VectorSink::new(CallbackSink::new(move |source, addr, data| {
+            tx.send((source, addr, data)).unwrap()
+        }));
+        let first = PciAddress::new(0, 0, 3, 0);
+        let second = PciAddress::new(1, 0, 3, 0);
+
+        // MSIs of each function get a group of their own.
+        sink.inject_from(first, 0xfee0_0000, 0x21).unwrap();
+        sink.inject_from(second, 0xfee0_0000, 0x21).unwrap();
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            [(first, 0xfee0_0000, 0x21), (second, 0xfee0_0000, 0x21)]
+        );
+        for data in 0x22..0x22 + MSI_VECTORS as u32 {
+            sink.inject_from(first, 0xfee0_0000, data).unwrap();
+        }
+        let groups = sink.groups.lock().unwrap();
+        // The last message took the vector of the first one.
+        assert_eq!(groups[&first].messages[0], Some((0xfee0_0000, 0x41)));
+        assert_eq!(groups[&second].messages[1], None);
+        drop(groups);
+        rx.try_iter().count();
+
+        // MSI-X vectors are programmed on demand and only exist while enabled.
+        assert!(sink.inject_vector(first, 1, 0xfee0_1000, 0x4021).is_err());
+        sink.enable_msix(first, 2).unwrap();
+        sink.inject_vector(first, 1, 0xfee0_1000, 0x4021).unwrap();
+        assert!(sink.inject_vector(first, 2, 0xfee0_1000, 0x4022).is_err());
+        assert_eq!(rx.try_recv(), Ok((first, 0xfee0_1000, 0x4021)));
+        sink.disable_msix(first).unwrap();
+        assert!(sink.inject_vector(first, 1, 0xfee0_1000, 0x4021).is_err());
+        assert!(sink.sink().trigger(first, 1).is_err());
+    }
+}
@@ -12,9 +12,10 @@ implementation. Make it easy to port the device between different rust-vmm
 hypervisor.
 
 2. The simulated devices should run in their own simulation threads for better
-isolation. Currently we should just consider one PCIe lane support and we will
-expore multiple PCIe lanes eventually. A PCIe lane is simply a pair of stream
-of PCIe transaction in our simulation.
+isolation. A PCIe lane is simply a pair of stream of PCIe transaction in our
+simulation. A device is reached through one lane by default, see
+[`multilane::MultiLane`] and [`PciAdapterBuilder::multi_lane`] to stripe its
+traffic over several.
 
 3. We should handle PCIe bridging logic in another separated thread. Basically,
 our PciAdapter should run inside its own thread. And rely on message passing
@@ -29,8 +30,8 @@ In my mind model, there should be three components to enable our simulation.
 * A handle to enable the hypervisor make requests to the device model.
 
 There should be another layer of abstraction of PCIe lane which is the channel
-between bridge and device model. Currently, only software based device model is
-considered. But RTL based simulation model is also possible.
+between bridge and device model. Besides software based device models, RTL based
+simulation models plug in through [`rtl`].
 
 There are basically three roles in the crate: hypervisor, [`PciAdapter`] and
 [`PciSimDevice`]. Conceptually, the bridge and simulated device both running inside
@@ -115,309 +116,297 @@ BAR region of the simulated device:
   hypervisor and device model and bypass the transaction simulation system. We can
   further provide the ability to change the guest physical address this region mapped
   in the hypervisor by moving the memory slot registered in the KVM virtual machine.
+
+[`PciFramebufferDevice`] is a model of such a graphics card, with its video memory shared and
+its mode set through a register bank.
+
+# Crate features
+
+The `std` feature is enabled by default and pulls in the adapter, the device models and the
+rust-vmm dependencies. Without it, only the [`core`](crate::core) module is built, which is
+`no_std` and only needs an allocator. That allows firmware or embedded test benches to share
+the packet definitions, builder, parser and serializer with the hypervisor side.
+
+The hypervisor hooks of the adapter, for slot mapped BARs, doorbells and interrupts, are
+implemented on top of the [`Hypervisor`] trait by [`VmSlotManager`], [`VmIoEventManager`],
+[`VmMsiSink`] and [`VmIrqfdSink`]. The `kvm` feature implements the trait for the VMs of KVM and
+adds the aliases [`KvmSlotManager`], [`KvmIoEventManager`], [`KvmMsiSink`] and [`KvmIrqfdSink`].
+The `mshv` feature does the same for the Microsoft Hypervisor with [`MshvSlotManager`],
+[`MshvIoEventManager`], [`MshvMsiSink`] and [`MshvIrqfdSink`], e.g. for the mshv builds of
+cloud-hypervisor.
+
+On aarch64 the IO BARs stay unassigned and the VMM points [`PciAdapterBuilder::msi_window`] at
+the GITS_TRANSLATER register of its ITS. The sinks get the MSIs along with the requester ID of
+their function, which KVM passes on to the ITS as DeviceID.
+
+VMMs which manage interrupts as groups of vectors plug in an [`InterruptSink`] through
+[`VectorSink`] instead, the bridge tells it when the guest enables MSI-X or masks vectors.
+
+The `virtio` feature adds [`VirtioBlkDevice`], a virtio block device model which drives its
+virtqueues by DMA like real hardware.
+
+The `cloud-hypervisor` feature adds [`ChPciSegment`], which plugs an adapter into the PCI
+segment of cloud-hypervisor, see [`cloud_hypervisor`].
+
+The `crosvm` feature adds [`CrosvmPciAdapter`], which mounts an adapter in crosvm through its
+own PCI device traits, see [`crosvm`].
+
+The `ffi` feature exports a C API, declared in `include/pcie_tlp.h`, so device models written in
+C or C++ plug in as [`FfiDevice`], see [`ffi`].
 */
 
-mod adapter;
-mod device;
-// mod parser;
+#![cfg_attr(not(feature = "std"), no_std)]
 
-pub use adapter::{MmioRegion, PciAdapter, PciLane};
-pub use device::{PciSimDevice, PciTestDevice};
+extern crate alloc;
 
+#[cfg(feature = "std")]
+mod acs;
+#[cfg(feature = "std")]
+mod adapter;
+#[cfg(feature = "std")]
+pub mod aer;
+#[cfg(feature = "std")]
+mod allocator;
+#[cfg(feature = "std")]
+pub mod ats;
+#[cfg(feature = "std")]
+mod cache;
+#[cfg(feature = "std")]
+pub mod capability;
+#[cfg(feature = "std")]
+pub mod capture;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "cloud-hypervisor")]
+pub mod cloud_hypervisor;
+#[cfg(feature = "std")]
+mod config;
+pub mod core;
+#[cfg(feature = "crosvm")]
+pub mod crosvm;
+#[cfg(feature = "std")]
+pub mod delay;
+#[cfg(feature = "std")]
+mod device;
+#[cfg(feature = "std")]
+pub mod dll;
+#[cfg(feature = "std")]
+mod dma;
+#[cfg(feature = "std")]
+pub mod dma_engine;
+#[cfg(feature = "std")]
+pub mod doorbell;
+#[cfg(feature = "dpi")]
+pub mod dpi;
+#[cfg(feature = "std")]
+pub mod edu;
+#[cfg(feature = "std")]
+pub mod eventlog;
+#[cfg(feature = "std")]
+pub mod fault;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod flow;
+#[cfg(feature = "std")]
+pub mod framebuffer;
+#[cfg(feature = "std")]
+mod hotplug;
+#[cfg(feature = "std")]
+mod hypervisor;
+#[cfg(feature = "std")]
+mod interrupt;
+#[cfg(feature = "std")]
+pub mod link;
+#[cfg(feature = "std")]
+pub mod ltssm;
+#[cfg(feature = "std")]
+mod memslot;
+#[cfg(feature = "std")]
+pub mod message;
+#[cfg(feature = "std")]
+pub mod multilane;
+#[cfg(feature = "std")]
+pub mod nic;
+#[cfg(feature = "std")]
+pub mod ordering;
+#[cfg(feature = "std")]
+pub mod ptm;
+#[cfg(feature = "std")]
+pub mod remote;
+#[cfg(feature = "std")]
+mod replay;
+#[cfg(feature = "std")]
+mod reply;
+#[cfg(feature = "std")]
+pub mod root_complex;
+#[cfg(feature = "std")]
+pub mod rtl;
+#[cfg(feature = "std")]
+pub mod segment;
+#[cfg(feature = "std")]
+pub mod shm;
+#[cfg(feature = "std")]
+pub mod snapshot;
+#[cfg(feature = "std")]
+pub mod sriov;
+#[cfg(feature = "std")]
+mod stats;
+#[cfg(feature = "std")]
+mod tag;
+#[cfg(feature = "std")]
+pub mod tlm;
+#[cfg(feature = "std")]
+mod trace;
+#[cfg(feature = "std")]
+pub mod vfio_user;
+#[cfg(feature = "virtio")]
+pub mod virtio;
+
+pub use self::core::*;
+#[cfg(feature = "std")]
+pub use acs::{AcsPort, PCI_ACS_CAP, PCI_EXT_CAP_ID_ACS};
+#[cfg(feature = "std")]
+pub use adapter::{
+    make_bdf, AdapterEvent, BackpressurePolicy, CrsPolicy, DisconnectPolicy, MmioRegion,
+    PciAdapter, PciAdapterBuilder, PciAdapterError, PciLane, PeerBus, PendingRequest,
+    DEFAULT_COMPLETION_TIMEOUT,
+};
+#[cfg(feature = "std")]
+pub use aer::{header_log, AerError, AerLog, ErrorSeverity};
+#[cfg(feature = "std")]
+pub use allocator::AddressAllocator;
+#[cfg(feature = "std")]
+pub use ats::{AtsTranslation, IommuEvent, PageRequestHandler, TranslationAgent};
+#[cfg(feature = "std")]
+pub use capability::{
+    AerCapability, AriCapability, AtsCapability, DsnCapability, ExtendedCapability, MsiCapability,
+    MsixCapability, PcieCapability, PciePortType, PmCapability, PowerState, PtmCapability,
+    SriovCapability, StandardCapability,
+};
+#[cfg(feature = "std")]
+pub use capture::{read_capture, PcapngCapture};
+#[cfg(feature = "std")]
+pub use clock::{SimClock, Timers};
+#[cfg(feature = "cloud-hypervisor")]
+pub use cloud_hypervisor::{
+    BarRelocation, ChInterruptSink, ChPciSegment, InterruptGroupSink, SegmentError,
+};
+#[cfg(feature = "std")]
+pub use config::{ConfigSpace, PcieConfiguration, PCIE_CONFIG_REGS, PCI_CONFIG_REGS};
+#[cfg(feature = "crosvm")]
+pub use crosvm::CrosvmPciAdapter;
+#[cfg(feature = "std")]
+pub use delay::{Delay, DelayModel};
+#[cfg(feature = "std")]
+pub use device::{
+    DeviceFeatures, LaneInfo, PciSimDevice, PciTestDevice, ResetKind, SimpleDevice, RESET_MESSAGE,
+};
+#[cfg(feature = "std")]
+pub use dll::{
+    DataLink, DataLinkControl, DataLinkDevice, DataLinkStats, DlState, Dllp, Frame, LinkError,
+};
+#[cfg(feature = "std")]
+pub use dma::{DmaAccess, DmaFault, DmaHandle, DmaMemory, DmaTranslator};
+#[cfg(feature = "std")]
+pub use dma_engine::{Descriptor, DmaEngine, LocalMemory};
+#[cfg(feature = "kvm")]
+pub use doorbell::KvmIoEventManager;
+#[cfg(feature = "mshv")]
+pub use doorbell::MshvIoEventManager;
+#[cfg(feature = "std")]
+pub use doorbell::{Doorbell, Doorbells, IoEventManager, VmIoEventManager};
+#[cfg(feature = "std")]
+pub use edu::PciEduDevice;
+#[cfg(feature = "std")]
+pub use eventlog::{EventKind, EventLog};
+#[cfg(feature = "std")]
+pub use fault::{DeviceFaultInjector, Fault, FaultyDevice};
+#[cfg(feature = "ffi")]
+pub use ffi::{DeviceOps, FfiDevice, TlpDesc};
+#[cfg(feature = "std")]
+pub use flow::{FcClass, FcCredit, FcCredits, FlowControl};
+#[cfg(feature = "std")]
+pub use framebuffer::{FbMode, PciFramebufferDevice, Scanout};
+#[cfg(feature = "std")]
+pub use hotplug::{HotplugSlot, PCI_EXP_SLTCAP, PCI_EXP_SLTCTL};
+#[cfg(feature = "std")]
+pub use hypervisor::{Hypervisor, MsiRoute};
+#[cfg(feature = "std")]
+pub use interrupt::{
+    CallbackSink, InterruptKind, InterruptSink, IrqHandle, MsiSink, VectorSink, VmIrqfdSink,
+    VmMsiSink, MSI_DOORBELL_BASE, MSI_DOORBELL_SIZE,
+};
+#[cfg(feature = "kvm")]
+pub use interrupt::{KvmIrqfdSink, KvmMsiSink};
+#[cfg(feature = "mshv")]
+pub use interrupt::{MshvIrqfdSink, MshvMsiSink};
+#[cfg(feature = "std")]
+pub use link::{Link, LinkSpeed};
+#[cfg(feature = "std")]
+pub use ltssm::LtssmState;
+#[cfg(feature = "kvm")]
+pub use memslot::KvmSlotManager;
+#[cfg(feature = "mshv")]
+pub use memslot::MshvSlotManager;
+#[cfg(feature = "std")]
+pub use memslot::{MemorySlotManager, SharedRegion, VmSlotManager};
+#[cfg(feature = "std")]
+pub use message::MessageHandler;
+#[cfg(feature = "std")]
+pub use multilane::MultiLane;
+#[cfg(feature = "std")]
+pub use nic::{Loopback, NetBackend, PciNicDevice, Tap};
+#[cfg(feature = "std")]
+pub use ordering::{OrderingPolicy, Passing};
+#[cfg(feature = "std")]
+pub use ptm::{PtmResponse, PtmTimeSource};
+#[cfg(feature = "std")]
+pub use remote::{connect_stream, connect_tcp, connect_unix, LaneListener, LaneStream};
+#[cfg(feature = "std")]
+pub use replay::{ReplayDevice, ReplayError, Trace};
+#[cfg(feature = "std")]
+pub use root_complex::{
+    BridgeWindows, EnumerationError, FunctionKind, RootComplex, Topology, TopologyFunction,
+};
+#[cfg(feature = "std")]
+pub use rtl::{Beat, BeatAssembler, RtlFifoDevice};
+#[cfg(feature = "std")]
+pub use segment::{EcamError, PciAddress, PciSegments};
+#[cfg(feature = "std")]
+pub use shm::{connect_shm, connect_shm_mapped, DirectDma, GuestMap, GuestRamRegion};
+#[cfg(feature = "std")]
+pub use snapshot::{AdapterSnapshot, BarSnapshot, DeviceState, MsixSnapshot};
+#[cfg(feature = "std")]
+pub use sriov::PciSriovDevice;
+#[cfg(feature = "std")]
+pub use stats::{AdapterStats, TlpCounts, TlpKind};
+#[cfg(feature = "std")]
+pub use tag::{TagTracker, Tracked};
+#[cfg(feature = "std")]
+pub use tlm::{GenericPayload, TlmDevice, TlmInitiator, TlmTarget};
+#[cfg(feature = "std")]
+pub use trace::{Direction, TlpObserver};
+#[cfg(feature = "std")]
+pub use vfio_user::VfioUserDevice;
+#[cfg(feature = "virtio")]
+pub use virtio::VirtioBlkDevice;
+
+#[cfg(feature = "std")]
 use log::{debug, error};
-use std::convert::TryFrom;
 
+#[cfg(feature = "std")]
 use pci::{
-    PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciClassCode, PciConfiguration,
-    PciDevice, PciDeviceError, PciHeaderType, PciMassStorageSubclass,
+    BarReprogrammingParams, ConfigError, PciBarConfiguration, PciBarPrefetchable, PciBarRegionType,
+    PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
+    PciMassStorageSubclass,
 };
+#[cfg(feature = "std")]
 use vm_device::BusDevice;
+#[cfg(feature = "std")]
 use vm_memory::Address;
 
+#[cfg(feature = "std")]
 use vm_allocator::SystemAllocator;
+#[cfg(feature = "std")]
 use vm_memory::{GuestAddress, GuestUsize};
-
-/// Byte 0 bits 7:5
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum Fmt {
-    Dw3NoData = 0b00 << 5,
-    Dw4NoData = 0b01 << 5,
-    Dw3 = 0b10 << 5,
-    Dw4 = 0b11 << 5,
-    Prefix = 0b100 << 5,
-}
-
-impl TryFrom<u8> for Fmt {
-    type Error = ();
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0b00 => Ok(Fmt::Dw3NoData),
-            0b01 => Ok(Fmt::Dw4NoData),
-            0b10 => Ok(Fmt::Dw3),
-            0b11 => Ok(Fmt::Dw4),
-            0b100 => Ok(Fmt::Prefix),
-            _ => Err(()),
-        }
-    }
-}
-
-// After a glance of others' implementations of PCIe TLP simulation, I found that
-// the FMT & TYPE could uniquely identify a type of packet. That reminds me to
-// redesign the representation of packet thoroughly.
-
-/// Packet specific data of config space related PCIe transactions.
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct ConfigExtra {
-    requester: u16,
-    completer: u16,
-    tag: u8,
-    reg: u16,
-}
-
-/// Packet specific data of 32bit memory PCIe transactions.
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct MemoryExtra {
-    requester: u16,
-    tag: u8,
-    addr: u32,
-}
-
-/// Packet psecific data of 64bit memory PCIe transactions.
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Memory64Extra {
-    requester: u16,
-    tag: u8,
-    addr: u64,
-}
-/// Packet specific data of completion PCIe transactions.
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct CompletionExtra {
-    requester: u16,
-    completer: u16,
-    tag: u8,
-    status: u8,
-    bcm: bool,
-    byte_count: u16,
-    lower_address: u8,
-}
-
-/// The type of PCIe transaction, tightly coupled with TYPE\[4:0\] and FMT\[2:0\]
-/// fields in the header.
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub enum PacketType {
-    MemoryRead(MemoryExtra),
-    MemoryRead64(Memory64Extra),
-    MemoryReadLock,
-    MemoryReadLock64,
-    MemoryWrite(MemoryExtra),
-    MemoryWrite64(Memory64Extra),
-    IoRead,
-    IoWrite,
-    Config0Read(ConfigExtra),
-    Config0Write(ConfigExtra),
-    Config1Read(ConfigExtra),
-    Config1Write(ConfigExtra),
-    Message(u8),
-    MessageData(u8),
-    Completion(CompletionExtra),
-    CompletionData(CompletionExtra),
-    CompletionLocked(CompletionExtra),
-    CompletionLockedData(CompletionExtra),
-    FetchAddAtomic,
-    SwapAtomic,
-    CasAtomic,
-    LocalPrefix(u8),
-    EndToEndPrefix(u8),
-    Unknown,
-}
-
-/// Traffica class of PCIe packet. Byte 1 bits 6:4 of the header.
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum TrafficClass {
-    TC0 = 0b000,
-    TC1,
-    TC2,
-    TC3,
-    TC4,
-    TC5,
-    TC6,
-    TC7,
-}
-
-/// The address type field inside the PCIe transacton headers.
-#[derive(Debug, Clone, Copy)]
-pub enum AddressType {
-    Default = 0b00,
-    TranslationRequest,
-    Translated,
-    Reserved,
-}
-
-/// Literally a in memory representation of PCIe transaction headers.
-#[derive(Debug, Clone, Copy)]
-pub struct TlpHeader {
-    _type: PacketType,
-    trafic_class: TrafficClass,
-    address_type: AddressType,
-
-    /// Attr\[1\]
-    relax_ordering: bool,
-    /// Attr\[0\]
-    no_snoop: bool,
-    /// Attr\[2\]
-    id_ordering: bool,
-
-    poisoned_data: bool,
-    tlp_digest: bool,
-    processing_hint: bool,
-
-    // The upper 4 bits is the last DW, and the lower 4 bits are the first DW.
-    byte_enable: u8,
-    length: u16,
-}
-
-/// Basic abstraction of a TLP packet without CRC checksum attached.
-#[derive(Debug, Clone)]
-pub struct Tlp {
-    pub header: TlpHeader,
-    pub data: Option<Vec<u32>>,
-}
-
-impl TlpHeader {
-    fn transaction_id(&self) -> u32 {
-        use PacketType::*;
-
-        match self._type {
-            Config0Read(extra) | Config0Write(extra) => {
-                extra.tag as u32 | ((extra.requester as u32) << 16)
-            }
-            CompletionData(extra) | Completion(extra) => {
-                extra.tag as u32 | ((extra.requester as u32) << 16)
-            }
-            _ => unimplemented!(),
-        }
-    }
-}
-
-impl Default for Tlp {
-    fn default() -> Self {
-        Tlp {
-            header: TlpHeader::default(),
-            data: None,
-        }
-    }
-}
-
-impl Default for TlpHeader {
-    fn default() -> Self {
-        TlpHeader {
-            _type: PacketType::Unknown,
-            trafic_class: TrafficClass::TC0,
-            address_type: AddressType::Default,
-            relax_ordering: false,
-            no_snoop: false,
-            id_ordering: false,
-            poisoned_data: false,
-            processing_hint: false,
-            tlp_digest: false,
-            byte_enable: 0,
-            length: 0,
-        }
-    }
-}
-/// Convenient builder of [`Tlp`].
-#[derive(Debug)]
-pub struct TlpBuilder(Tlp);
-
-impl TlpBuilder {
-    pub fn with_type(ptype: PacketType) -> Self {
-        TlpBuilder(Tlp::default()).r#type(ptype)
-    }
-
-    pub fn memory_read(extra: MemoryExtra) -> Self {
-        Self::with_type(PacketType::MemoryRead(extra))
-    }
-
-    pub fn memory_read64(extra: Memory64Extra) -> Self {
-        Self::with_type(PacketType::MemoryRead64(extra))
-    }
-
-    pub fn io_read() -> Self {
-        Self::with_type(PacketType::IoRead)
-    }
-
-    pub fn io_write() -> Self {
-        Self::with_type(PacketType::IoWrite)
-    }
-
-    pub fn config0_read(extra: ConfigExtra) -> Self {
-        Self::with_type(PacketType::Config0Read(extra))
-    }
-
-    pub fn config0_write(extra: ConfigExtra) -> Self {
-        Self::with_type(PacketType::Config0Write(extra)).length(1)
-    }
-
-    pub fn completion_data(extra: CompletionExtra) -> Self {
-        Self::with_type(PacketType::CompletionData(extra))
-    }
-
-    fn r#type(mut self, _type: PacketType) -> Self {
-        self.0.header._type = _type;
-        self
-    }
-
-    pub fn length(mut self, len: u16) -> Self {
-        self.0.header.length = len;
-        self
-    }
-
-    pub fn data(mut self, data: Vec<u32>) -> Self {
-        let len = data.len();
-        self.0.data = Some(data);
-        self.length(len as u16)
-    }
-
-    pub fn byte_enable(mut self, be: u8) -> Self {
-        self.0.header.byte_enable = be;
-        self
-    }
-
-    pub fn build(self) -> Tlp {
-        self.0
-    }
-}
-
-impl Tlp {
-    /// Check whether a TLP is valid according to the PCIe specification.
-    pub fn is_valid(&self) -> bool {
-        use PacketType::*;
-
-        let header = self.header;
-
-        // DW BE rule check
-        if (header.length == 1 && header.byte_enable & 0xf == 0)
-            | (header.length == 1 && header.byte_enable & 0xf0 != 0)
-            | (header.length > 1 && header.byte_enable & 0xf0 == 0)
-        {
-            return false;
-        }
-
-        match header._type {
-            Config0Read(extra) | Config0Write(extra) | Config1Read(extra) | Config1Write(extra) => {
-                // PCIe 3.0 specification 2.2.7
-                if header.trafic_class != TrafficClass::TC0
-                    || header.no_snoop
-                    || header.relax_ordering
-                    || header.length != 0b00001
-                {
-                    return false;
-                }
-            }
-            _ => unimplemented!(),
-        }
-
-        true
-    }
-}
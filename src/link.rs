@@ -0,0 +1,168 @@
+//! Bandwidth of the simulated links.
+//!
+//! Lanes move TLPs as fast as the threads run, so a DMA of megabytes is over at once. A
+//! [`Link`] set with [`PciAdapterBuilder::link`] gives each device a link of a real speed and
+//! width: the bridge meters the TLPs crossing it with a token bucket for each direction of the
+//! link of a device, and holds back the TLPs the link could not have carried yet, the way the
+//! [`DelayModel`] does.
+
+use crate::*;
+
+use std::time::{Duration, Instant};
+
+/// Bytes of a TLP on the wire besides the TLP itself: the framing, sequence number and LCRC.
+const FRAMING_BYTES: u64 = 8;
+/// Bytes a link carries at once after it was idle, a TLP with the largest payload.
+const BURST_BYTES: u64 = 4096 + 16 + FRAMING_BYTES;
+
+/// Generation of a PCIe link, which sets the transfer rate of its lanes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LinkSpeed {
+    /// 2.5 GT/s with 8b/10b encoding.
+    Gen1,
+    /// 5 GT/s with 8b/10b encoding.
+    Gen2,
+    /// 8 GT/s with 128b/130b encoding.
+    Gen3,
+    /// 16 GT/s with 128b/130b encoding.
+    Gen4,
+    /// 32 GT/s with 128b/130b encoding.
+    Gen5,
+}
+
+impl LinkSpeed {
+    /// The speed of `code` in the Link Capabilities, Status and Control 2 registers.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            1 => Some(LinkSpeed::Gen1),
+            2 => Some(LinkSpeed::Gen2),
+            3 => Some(LinkSpeed::Gen3),
+            4 => Some(LinkSpeed::Gen4),
+            5 => Some(LinkSpeed::Gen5),
+            _ => None,
+        }
+    }
+
+    /// The code of the speed in the Link Capabilities, Status and Control 2 registers.
+    pub fn code(self) -> u32 {
+        self as u32 + 1
+    }
+
+    /// Bytes per second a single lane carries after encoding.
+    pub fn lane_bandwidth(self) -> u64 {
+        let (rate, payload, encoded) = match self {
+            LinkSpeed::Gen1 => (2_500_000_000u64, 8, 10),
+            LinkSpeed::Gen2 => (5_000_000_000, 8, 10),
+            LinkSpeed::Gen3 => (8_000_000_000, 128, 130),
+            LinkSpeed::Gen4 => (16_000_000_000, 128, 130),
+            LinkSpeed::Gen5 => (32_000_000_000, 128, 130),
+        };
+        rate * payload / encoded / 8
+    }
+}
+
+/// Speed and width of the link of a device, e.g. Gen3 x4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Link {
+    pub speed: LinkSpeed,
+    /// Number of lanes, from 1 to 32.
+    pub width: u8,
+}
+
+impl Link {
+    pub fn new(speed: LinkSpeed, width: u8) -> Self {
+        Link {
+            speed,
+            width: width.clamp(1, 32),
+        }
+    }
+
+    /// The link two ends train at, the fastest speed and widest width both support.
+    pub fn negotiate(&self, other: &Link) -> Link {
+        Link::new(self.speed.min(other.speed), self.width.min(other.width))
+    }
+
+    /// Bytes per second the link carries in each direction.
+    pub fn bandwidth(&self) -> u64 {
+        self.speed.lane_bandwidth() * self.width as u64
+    }
+}
+
+/// Bytes `tlp` takes on the wire.
+pub fn wire_len(tlp: &Tlp) -> u64 {
+    let header = match tlp.header._type {
+        PacketType::MemoryRead64(_) | PacketType::MemoryWrite64(_) => 16,
+        _ => 12,
+    };
+    header + tlp.data.as_ref().map_or(0, |data| data.len() as u64 * 4) + FRAMING_BYTES
+}
+
+/// Token bucket metering one direction of a link.
+pub(crate) struct Throttle {
+    rate: u64,
+    /// Bytes the link could carry at `at`, below 0 while it is behind.
+    tokens: i64,
+    at: Instant,
+}
+
+impl Throttle {
+    pub fn new(link: Link) -> Self {
+        Throttle {
+            rate: link.bandwidth(),
+            tokens: BURST_BYTES as i64,
+            at: Instant::now(),
+        }
+    }
+
+    /// Meter `tlp`: how long until the link is done carrying it and the TLPs before it.
+    pub fn delay_of(&mut self, tlp: &Tlp) -> Duration {
+        self.delay_at(tlp, Instant::now())
+    }
+
+    fn delay_at(&mut self, tlp: &Tlp, now: Instant) -> Duration {
+        let refill = now.duration_since(self.at).as_nanos() * self.rate as u128 / 1_000_000_000;
+        self.tokens = (self.tokens as i128 + refill as i128).min(BURST_BYTES as i128) as i64;
+        self.at = now;
+
+        self.tokens -= wire_len(tlp) as i64;
+        if self.tokens >= 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((-self.tokens) as u64 * 1_000_000_000 / self.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle() {
+        let link = Link::new(LinkSpeed::Gen3, 4);
+        assert_eq!(link.bandwidth(), 3_938_461_536);
+        assert_eq!(Link::new(LinkSpeed::Gen1, 0).bandwidth(), 250_000_000);
+
+        // 1 MiB in writes of 256 bytes takes about 290 us at Gen3 x4.
+        let write = TlpBuilder::memory_write64(Memory64Extra {
+            requester: 0x18,
+            tag: 0,
+            addr: 0x1000,
+        })
+        .byte_enable(0xff)
+        .data(vec![0; 64])
+        .build();
+        assert_eq!(wire_len(&write), 280);
+
+        let mut throttle = Throttle::new(link);
+        let now = throttle.at;
+        let delay = (0..4096).map(|_| throttle.delay_at(&write, now)).last();
+        assert_eq!(delay, Some(Duration::from_nanos(290_153)));
+
+        // The link catches up while it is idle.
+        let later = now + Duration::from_micros(100);
+        assert_eq!(
+            throttle.delay_at(&write, later),
+            Duration::from_nanos(190_225)
+        );
+    }
+}
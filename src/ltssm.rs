@@ -0,0 +1,203 @@
+//! Training and power states of the links.
+//!
+//! The bridge keeps a coarse LTSSM for the link of each device, whose functions share it. The
+//! links are trained, from Detect through Polling and Configuration to L0, when the bridge
+//! starts and when a device is plugged in. The hypervisor takes a link down and brings it up
+//! again with [`PciAdapter::set_link_up`], e.g. to test how a driver copes with a surprise
+//! down, retrains it through Recovery with [`PciAdapter::retrain_link`] and parks it in L1 with
+//! [`PciAdapter::enter_l1`], where it stays until the next TLP on the link wakes it through
+//! Recovery. The states are passed at once, lanes have nothing to train.
+//!
+//! A link trains at the fastest speed and widest width both its ends support: the port, whose
+//! [`Link`] is set with [`PciAdapterBuilder::link`], and the device, as it advertises in the
+//! Link Capabilities register, no faster than the Target Link Speed in Link Control 2. The
+//! adapter negotiates once it finds the PCI Express capability of its function and again when
+//! the guest sets Retrain Link, and the bridge meters the TLPs at the negotiated rate.
+//!
+//! While a link is down the bridge fails the requests to its functions with
+//! [`PciAdapterError::LinkDown`] and drops every other TLP in both directions. The guest sees
+//! the state in the Link Status register of the PCI Express capability of the functions: Link
+//! Training while the link trains and Data Link Layer Link Active while it is up.
+
+use crate::*;
+
+/// Capability ID of the PCI Express capability.
+pub(crate) const PCI_CAP_ID_EXP: u8 = 0x10;
+/// DW of the PCI Express capability holding Link Control and, in its upper half, Link Status.
+pub(crate) const LNKCTL_DW: usize = 4;
+/// Link Training in the Link Status register.
+pub(crate) const LNKSTA_LT: u16 = 1 << 11;
+/// Data Link Layer Link Active in the Link Status register.
+pub(crate) const LNKSTA_DLLLA: u16 = 1 << 13;
+/// Current Link Speed and Negotiated Link Width in the Link Status register.
+pub(crate) const LNKSTA_LINK: u16 = 0x3ff;
+/// Retrain Link in the Link Control register, which always reads 0.
+pub(crate) const LNKCTL_RL: u16 = 1 << 5;
+/// DW of the PCI Express capability holding Link Control 2, with the Target Link Speed in
+/// bits 3:0.
+pub(crate) const LNKCTL2_DW: usize = 12;
+
+/// State of the LTSSM of a link, with the substates left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LtssmState {
+    /// No device is detected, the link is down.
+    Detect,
+    Polling,
+    Configuration,
+    /// The link is up and TLPs flow.
+    L0,
+    /// The link is retrained, e.g. to change its speed or on the way out of L1.
+    Recovery,
+    /// The link sleeps until a TLP is sent on it.
+    L1,
+}
+
+impl LtssmState {
+    /// Whether the data link layer is up, i.e. the link carries TLPs once it is in L0.
+    pub fn is_up(self) -> bool {
+        matches!(self, LtssmState::L0 | LtssmState::Recovery | LtssmState::L1)
+    }
+
+    /// The Link Training and Data Link Layer Link Active bits of the Link Status register, and
+    /// the Current Link Speed and Negotiated Link Width of `link` once it is negotiated.
+    pub(crate) fn link_status(self, link: Option<Link>) -> u16 {
+        let training = match self {
+            LtssmState::Polling | LtssmState::Configuration | LtssmState::Recovery => LNKSTA_LT,
+            _ => 0,
+        };
+        let active = if self.is_up() { LNKSTA_DLLLA } else { 0 };
+        let link = link.map_or(0, |link| (link.width as u32) << 4 | link.speed.code());
+        training | active | link as u16
+    }
+}
+
+/// The LTSSM of the link of a device, trained from the start.
+#[derive(Debug, Clone)]
+pub(crate) struct Ltssm {
+    /// The bus and device number the link leads to.
+    link: u16,
+    state: LtssmState,
+    negotiated: Option<Link>,
+}
+
+impl Ltssm {
+    pub(crate) fn new(link: u16) -> Self {
+        Ltssm {
+            link,
+            state: LtssmState::L0,
+            negotiated: None,
+        }
+    }
+
+    pub(crate) fn state(&self) -> LtssmState {
+        self.state
+    }
+
+    /// The speed and width the link trained at, once negotiated.
+    pub(crate) fn negotiated(&self) -> Option<Link> {
+        self.negotiated
+    }
+
+    fn step(&mut self, state: LtssmState) {
+        debug!("Link {:#x}: {:?} -> {:?}", self.link, self.state, state);
+        self.state = state;
+    }
+
+    /// Bring the link up or down. Returns whether it changed.
+    pub(crate) fn set_up(&mut self, up: bool) -> bool {
+        if up == self.state.is_up() {
+            return false;
+        }
+
+        if up {
+            self.step(LtssmState::Polling);
+            self.step(LtssmState::Configuration);
+            self.step(LtssmState::L0);
+        } else {
+            self.step(LtssmState::Detect);
+        }
+        true
+    }
+
+    /// Retrain the link through Recovery, which also takes it out of L1. Returns false if the
+    /// link is down.
+    pub(crate) fn recover(&mut self) -> bool {
+        if !self.state.is_up() {
+            return false;
+        }
+
+        self.step(LtssmState::Recovery);
+        self.step(LtssmState::L0);
+        true
+    }
+
+    /// Retrain the link through Recovery at the speed and width of `link`. Returns false if
+    /// the link is down.
+    pub(crate) fn negotiate(&mut self, link: Link) -> bool {
+        if !self.recover() {
+            return false;
+        }
+
+        debug!("Link {:#x} trained at {:?}", self.link, link);
+        self.negotiated = Some(link);
+        true
+    }
+
+    /// Park the link in L1. Returns false if the link is down.
+    pub(crate) fn enter_l1(&mut self) -> bool {
+        if !self.state.is_up() {
+            return false;
+        }
+
+        if self.state != LtssmState::L1 {
+            self.step(LtssmState::L1);
+        }
+        true
+    }
+
+    /// Wake the link for a TLP to cross it. Returns whether it carries TLPs.
+    pub(crate) fn wake(&mut self) -> bool {
+        if self.state == LtssmState::L1 {
+            self.recover();
+        }
+        self.state.is_up()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states() {
+        let mut ltssm = Ltssm::new(0x18);
+        assert_eq!(ltssm.state(), LtssmState::L0);
+        assert_eq!(ltssm.state().link_status(None), LNKSTA_DLLLA);
+        assert!(!ltssm.set_up(true));
+
+        assert!(ltssm.enter_l1());
+        assert_eq!(ltssm.state(), LtssmState::L1);
+        assert!(ltssm.wake());
+        assert_eq!(ltssm.state(), LtssmState::L0);
+
+        assert!(ltssm.set_up(false));
+        assert_eq!(ltssm.state(), LtssmState::Detect);
+        assert_eq!(ltssm.state().link_status(None), 0);
+        assert!(!ltssm.wake());
+        assert!(!ltssm.recover());
+        assert!(!ltssm.enter_l1());
+
+        assert!(!ltssm.negotiate(Link::new(LinkSpeed::Gen2, 4)));
+        assert!(ltssm.set_up(true));
+        assert_eq!(ltssm.state(), LtssmState::L0);
+        assert_eq!(
+            LtssmState::Recovery.link_status(None),
+            LNKSTA_LT | LNKSTA_DLLLA
+        );
+
+        let link = Link::new(LinkSpeed::Gen3, 8).negotiate(&Link::new(LinkSpeed::Gen4, 4));
+        assert!(ltssm.negotiate(link));
+        assert_eq!(ltssm.negotiated(), Some(Link::new(LinkSpeed::Gen3, 4)));
+        assert_eq!(ltssm.state().link_status(Some(link)), LNKSTA_DLLLA | 0x43);
+    }
+}
@@ -0,0 +1,101 @@
+//! Shared memory backing of BAR regions.
+//!
+//! A device model may export host memory for a prefetchable BAR, e.g. the on-board memory of a
+//! graphics card. The adapter maps it into the guest physical address space through a
+//! [`MemorySlotManager`] at the address the BAR is allocated to, so guest accesses bypass the
+//! transaction layer entirely. The slot follows the BAR when the guest moves it and is removed
+//! when the BAR is freed.
+
+use crate::Hypervisor;
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+/// Host memory exported by a device model to back one of its BARs.
+///
+/// The memory must stay mapped as long as the adapter of the device is alive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SharedRegion {
+    /// Index of the BAR, 0 to 5.
+    pub bar: usize,
+    /// Host virtual address of the memory.
+    pub host_addr: u64,
+    /// Size of the memory in bytes, at least the size of the BAR.
+    pub size: usize,
+}
+
+/// Hypervisor hook to map host memory into the guest physical address space.
+pub trait MemorySlotManager: Send + Sync {
+    /// Map `size` bytes of host memory at `host_addr` to the guest physical address `gpa`.
+    /// Returns the slot identifying the mapping.
+    fn map_region(&mut self, gpa: u64, size: u64, host_addr: u64) -> io::Result<u32>;
+
+    /// Remove a mapping created by [`MemorySlotManager::map_region`].
+    fn unmap_region(&mut self, slot: u32) -> io::Result<()>;
+}
+
+/// [`MemorySlotManager`] mapping the regions into the VM of a [`Hypervisor`].
+pub struct VmSlotManager<H> {
+    vm: Arc<H>,
+    next_slot: u32,
+    free_slots: Vec<u32>,
+    /// Guest physical address, size and host address of the mapping of each slot.
+    regions: HashMap<u32, (u64, u64, u64)>,
+}
+
+/// [`VmSlotManager`] registering KVM user memory slots.
+#[cfg(feature = "kvm")]
+pub type KvmSlotManager = VmSlotManager<kvm_ioctls::VmFd>;
+
+/// [`VmSlotManager`] mapping user memory into mshv VMs.
+#[cfg(feature = "mshv")]
+pub type MshvSlotManager = VmSlotManager<mshv_ioctls::VmFd>;
+
+impl<H: Hypervisor> VmSlotManager<H> {
+    /// `first_slot` is the first memory slot which is not used by the hypervisor itself.
+    pub fn new(vm: Arc<H>, first_slot: u32) -> Self {
+        VmSlotManager {
+            vm,
+            next_slot: first_slot,
+            free_slots: vec![],
+            regions: HashMap::new(),
+        }
+    }
+}
+
+impl<H: Hypervisor> MemorySlotManager for VmSlotManager<H> {
+    fn map_region(&mut self, gpa: u64, size: u64, host_addr: u64) -> io::Result<u32> {
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None => {
+                self.next_slot += 1;
+                self.next_slot - 1
+            }
+        };
+
+        match self.vm.map_memory(slot, gpa, size, host_addr) {
+            Ok(()) => {
+                self.regions.insert(slot, (gpa, size, host_addr));
+                Ok(slot)
+            }
+            Err(e) => {
+                self.free_slots.push(slot);
+                Err(e)
+            }
+        }
+    }
+
+    fn unmap_region(&mut self, slot: u32) -> io::Result<()> {
+        let (gpa, size, host_addr) = self.regions.get(&slot).copied().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("memory slot {} is not mapped", slot),
+            )
+        })?;
+        self.vm.unmap_memory(slot, gpa, size, host_addr)?;
+        self.regions.remove(&slot);
+        self.free_slots.push(slot);
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+//! Routing of messages.
+//!
+//! The bridge routes the messages which are not terminated at the receiver by their routing
+//! subfield, see [`MessageRouting`]. A message routed by ID goes to the function with the BDF,
+//! one routed by address to the function whose memory window contains the address, and a
+//! broadcast of the adapter, see [`PciAdapter::send_message`], to every function of the bridge.
+//!
+//! Upstream, the messages of the functions routed to the root complex, gathered, routed by
+//! address outside of the windows or by ID to the bridge itself end at the bridge, which hands
+//! them to the [`MessageHandler`]. Broadcasts only go downstream, and a message for a BDF the
+//! bridge does not serve goes nowhere. Both count as errors.
+
+use crate::*;
+
+/// Hypervisor hook taking the messages the functions route to the root complex, e.g. vendor
+/// defined messages.
+pub trait MessageHandler: Send {
+    fn message(&self, message: &MessageExtra, data: Option<&[u32]>);
+}
+
+/// Where the bridge delivers a message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Destination {
+    Function(u16),
+    /// Every function of the bridge.
+    All,
+    RootComplex,
+    /// Nowhere, the message breaks the routing rules.
+    Unroutable,
+}
+
+/// The functions and windows of a bridge, which [`Destination::of`] routes by.
+pub(crate) struct Routes<'a> {
+    /// The requester ID of the bridge.
+    pub bridge: u16,
+    pub functions: &'a dyn Fn(u16) -> bool,
+    pub windows: &'a dyn Fn(u64) -> Option<u16>,
+}
+
+impl Destination {
+    /// Where `message` goes, `upstream` from a function or downstream from the adapter of the
+    /// function at `local`.
+    pub(crate) fn of(message: &MessageExtra, upstream: bool, local: u16, routes: &Routes) -> Self {
+        use MessageRouting::*;
+
+        let to_bridge = |upstream| {
+            if upstream {
+                Destination::RootComplex
+            } else {
+                Destination::Unroutable
+            }
+        };
+        match message.routing {
+            Id => {
+                let destination = message.destination();
+                if (routes.functions)(destination) {
+                    Destination::Function(destination)
+                } else if destination == routes.bridge {
+                    to_bridge(upstream)
+                } else {
+                    Destination::Unroutable
+                }
+            }
+            Address => match (routes.windows)(message.addr) {
+                Some(bdf) => Destination::Function(bdf),
+                None => to_bridge(upstream),
+            },
+            Broadcast if upstream => Destination::Unroutable,
+            Broadcast => Destination::All,
+            Local if !upstream => Destination::Function(local),
+            Local | RootComplex | Gathered => to_bridge(upstream),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routing() {
+        let functions = |bdf| bdf == 0x18 || bdf == 0x20;
+        let windows = |addr| (0x1000..0x2000).contains(&addr).then_some(0x20);
+        let routes = Routes {
+            bridge: 0,
+            functions: &functions,
+            windows: &windows,
+        };
+        let of = |message, upstream| Destination::of(&message, upstream, 0x18, &routes);
+
+        assert_eq!(
+            of(MessageExtra::to_id(0x18, 0x7e, 0x20), true),
+            Destination::Function(0x20)
+        );
+        assert_eq!(
+            of(MessageExtra::to_id(0x18, 0x7e, 0), true),
+            Destination::RootComplex
+        );
+        assert_eq!(
+            of(MessageExtra::to_id(0, 0x7e, 0x28), false),
+            Destination::Unroutable
+        );
+        assert_eq!(
+            of(MessageExtra::to_address(0x18, 0x7e, 0x1800), true),
+            Destination::Function(0x20)
+        );
+        assert_eq!(
+            of(MessageExtra::to_address(0x18, 0x7e, 0x2800), true),
+            Destination::RootComplex
+        );
+        assert_eq!(
+            of(MessageExtra::to_address(0, 0x7e, 0x2800), false),
+            Destination::Unroutable
+        );
+
+        let broadcast = MessageExtra::routed(0, 0x7e, MessageRouting::Broadcast);
+        assert_eq!(of(broadcast, false), Destination::All);
+        assert_eq!(of(broadcast, true), Destination::Unroutable);
+        let local = MessageExtra::routed(0, 0x7e, MessageRouting::Local);
+        assert_eq!(of(local, false), Destination::Function(0x18));
+        let gathered = MessageExtra::routed(0x20, 0x7e, MessageRouting::Gathered);
+        assert_eq!(of(gathered, true), Destination::RootComplex);
+        assert_eq!(of(gathered, false), Destination::Unroutable);
+    }
+}
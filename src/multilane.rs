@@ -0,0 +1,259 @@
+//! Striping the traffic of a lane over several channels.
+//!
+//! A lane is one channel each way, so a model moving a lot of data by DMA waits for the one
+//! thread taking its TLPs off the channel. [`MultiLane::stripe`] carries a lane over several
+//! channels instead, each one with a worker thread of its own on either side of the link:
+//!
+//! * A distributor takes the TLPs off the lane, numbers them and puts each one on the least
+//!   loaded channel, the next channel in turn if they are all alike.
+//! * The worker of each channel hands its TLPs to the other side as they come.
+//! * A collector puts the TLPs back on the lane. A TLP goes before the ones sent earlier which
+//!   are still on their way only if the ordering rules let it pass all of them, see
+//!   [`crate::ordering::passing`], otherwise it waits for them. Completions pass the read
+//!   requests held up on a busy channel, writes never pass each other unless relaxed.
+//!
+//! Both directions of the lane are striped, closing either end of the lane closes the other
+//! once the TLPs on the way are delivered. [`PciAdapterBuilder::multi_lane`] stripes the lanes of
+//! all the models of an adapter.
+
+use crate::*;
+
+use crate::ordering::passing;
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// The most channels a lane is striped over, as many as the lanes of a x32 link.
+const MAX_LANES: usize = 32;
+
+/// The headers of the TLPs on their way by sequence number, kept by the distributor for the
+/// collector to check the order against.
+type InFlight = Arc<Mutex<BTreeMap<u64, Tlp>>>;
+
+/// How to stripe a lane, see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultiLane {
+    lanes: usize,
+    capacity: Option<usize>,
+}
+
+impl MultiLane {
+    /// Stripe over `lanes` channels, from 1 to 32.
+    pub fn new(lanes: usize) -> Self {
+        MultiLane {
+            lanes: lanes.clamp(1, MAX_LANES),
+            capacity: None,
+        }
+    }
+
+    /// Bound the TLPs queued on each channel, the distributor blocks while all of them are
+    /// full.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    pub fn lanes(&self) -> usize {
+        self.lanes
+    }
+
+    /// Carry `lane` over the channels: the TLPs sent on the returned lane come out of `lane`
+    /// on the other side of the link and the TLPs received on `lane` come out of the returned
+    /// one.
+    pub fn stripe(&self, lane: PciLane) -> PciLane {
+        let (upstream, upstream_rx) = self.channel();
+        let (downstream_tx, downstream) = self.channel();
+        self.spawn(upstream_rx, lane.tx);
+        self.spawn(lane.rx, downstream_tx);
+
+        PciLane {
+            tx: upstream,
+            rx: downstream,
+        }
+    }
+
+    fn channel<T>(&self) -> (Sender<T>, Receiver<T>) {
+        match self.capacity {
+            Some(capacity) => bounded(capacity),
+            None => unbounded(),
+        }
+    }
+
+    /// Stripe one direction, from `from` to `to`.
+    fn spawn(&self, from: Receiver<Tlp>, to: Sender<Tlp>) {
+        let in_flight = InFlight::default();
+        let (arrivals_tx, arrivals) = unbounded();
+
+        let stripes: Vec<_> = (0..self.lanes)
+            .map(|_| {
+                let (tx, rx) = self.channel::<(u64, Tlp)>();
+                let arrivals = arrivals_tx.clone();
+                spawn_worker(move || {
+                    for tlp in rx.iter() {
+                        if arrivals.send(tlp).is_err() {
+                            break;
+                        }
+                    }
+                });
+                tx
+            })
+            .collect();
+        drop(arrivals_tx);
+
+        let distributed = in_flight.clone();
+        spawn_worker(move || distribute(from, stripes, distributed));
+        spawn_worker(move || collect(arrivals, to, in_flight));
+    }
+}
+
+fn spawn_worker<F: FnOnce() + Send + 'static>(f: F) {
+    std::thread::Builder::new()
+        .name("tlp-stripe".to_string())
+        .spawn(f)
+        .expect("failed to spawn thread");
+}
+
+fn distribute(from: Receiver<Tlp>, stripes: Vec<Sender<(u64, Tlp)>>, in_flight: InFlight) {
+    for (seq, tlp) in (0u64..).zip(from.iter()) {
+        let header = Tlp {
+            header: tlp.header,
+            data: None,
+        };
+        in_flight.lock().unwrap().insert(seq, header);
+
+        let lanes = stripes.len();
+        let stripe = (0..lanes)
+            .map(|i| &stripes[(seq as usize + i) % lanes])
+            .min_by_key(|stripe| stripe.len())
+            .unwrap();
+        if stripe.send((seq, tlp)).is_err() {
+            break;
+        }
+    }
+}
+
+fn collect(arrivals: Receiver<(u64, Tlp)>, to: Sender<Tlp>, in_flight: InFlight) {
+    let mut held = BTreeMap::new();
+    for (seq, tlp) in arrivals.iter() {
+        held.insert(seq, tlp);
+        loop {
+            let ready = {
+                let mut in_flight = in_flight.lock().unwrap();
+                match next_ready(&held, &in_flight) {
+                    Some(seq) => {
+                        in_flight.remove(&seq);
+                        seq
+                    }
+                    None => break,
+                }
+            };
+            if to.send(held.remove(&ready).unwrap()).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// The first of the `held` TLPs which may pass all the TLPs sent before it and still
+/// `in_flight`.
+fn next_ready(held: &BTreeMap<u64, Tlp>, in_flight: &BTreeMap<u64, Tlp>) -> Option<u64> {
+    held.iter()
+        .find(|(seq, tlp)| {
+            in_flight
+                .range(..**seq)
+                .all(|(_, earlier)| passing(tlp, earlier) != Passing::Never)
+        })
+        .map(|(seq, _)| *seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(addr: u64, relaxed: bool) -> Tlp {
+        let mut tlp = TlpBuilder::memory_write64(Memory64Extra {
+            requester: 0x10,
+            tag: 0,
+            addr,
+        })
+        .byte_enable(0xf)
+        .data(vec![0])
+        .build();
+        tlp.header.relax_ordering = relaxed;
+        tlp
+    }
+
+    fn read(tag: u8) -> Tlp {
+        TlpBuilder::memory_read64(Memory64Extra {
+            requester: 0x10,
+            tag,
+            addr: 0x1000,
+        })
+        .byte_enable(0xf)
+        .build()
+    }
+
+    fn completion(tag: u8) -> Tlp {
+        TlpBuilder::completion_data(CompletionExtra {
+            requester: 0x10,
+            completer: 0x18,
+            tag,
+            status: CPL_SC,
+            bcm: false,
+            byte_count: 4,
+            lower_address: 0,
+        })
+        .byte_enable(0xf)
+        .data(vec![0])
+        .build()
+    }
+
+    #[test]
+    fn order() {
+        let ready = |tlps: Vec<Tlp>, arrived: u64| {
+            let in_flight: BTreeMap<_, _> = (0u64..).zip(tlps).collect();
+            let held = in_flight
+                .iter()
+                .filter(|(seq, _)| **seq >= arrived)
+                .map(|(seq, tlp)| (*seq, tlp.clone()))
+                .collect();
+            next_ready(&held, &in_flight)
+        };
+
+        // A completion passes a read held up on another channel but not a write, a write
+        // waits for the write before it unless relaxed.
+        assert_eq!(ready(vec![read(0), completion(1)], 1), Some(1));
+        assert_eq!(ready(vec![write(0, false), write(4, false)], 1), None);
+        assert_eq!(ready(vec![write(0, false), write(4, true)], 1), Some(1));
+        assert_eq!(ready(vec![write(0, false), read(0)], 1), None);
+        assert_eq!(
+            ready(vec![read(0), write(0, false), completion(1)], 2),
+            None
+        );
+        assert_eq!(ready(vec![write(0, false), completion(1)], 0), Some(0));
+    }
+
+    #[test]
+    fn stripe() {
+        let (bridge, model) = PciLane::pair();
+        let model = MultiLane::new(4).capacity(2).stripe(model);
+        assert_eq!(MultiLane::new(64).lanes(), 32);
+
+        for i in 0..64 {
+            model.tx.send(write(i * 4, false)).unwrap();
+            bridge.tx.send(write(i * 4, false)).unwrap();
+        }
+        for i in 0..64 {
+            let addr = |tlp: Tlp| match tlp.header._type {
+                PacketType::MemoryWrite64(extra) => extra.addr,
+                _ => unreachable!(),
+            };
+            assert_eq!(addr(bridge.rx.recv().unwrap()), i * 4);
+            assert_eq!(addr(model.rx.recv().unwrap()), i * 4);
+        }
+
+        // Closing one end closes the other.
+        drop(model);
+        assert!(bridge.rx.recv().is_err());
+    }
+}
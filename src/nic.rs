@@ -0,0 +1,689 @@
+//! Model of a NIC in the style of the Intel 8254x (e1000).
+//!
+//! The model keeps the part of the e1000 register set a driver needs to move frames: one RX and
+//! one TX ring of legacy descriptors, fetched and written back by DMA through the bridge, and
+//! the interrupt cause registers. The guest queues descriptors by writing the tail registers,
+//! which are the doorbells of the rings. Frames go out and come in through a [`NetBackend`],
+//! a host tap device by [`Tap`] or the port wired to itself by [`Loopback`], which keeps a
+//! steady flow of DMA through the bridge without any host setup.
+//!
+//! BAR0 is 128 KB of registers, accessed as 32-bit words:
+//!
+//! | Offset | Register |
+//! |--------|----------|
+//! | 0x0000 | CTRL, bit 26 resets the device |
+//! | 0x0008 | STATUS, link up and full duplex (RO) |
+//! | 0x00c0 | ICR, interrupt causes, cleared by reading |
+//! | 0x00c8 | ICS, sets the causes written |
+//! | 0x00d0 | IMS, unmasks the causes written |
+//! | 0x00d8 | IMC, masks the causes written |
+//! | 0x0100 | RCTL, bit 1 enables the receiver |
+//! | 0x0400 | TCTL, bit 1 enables the transmitter |
+//! | 0x2800 | RDBAL, RDBAH, RDLEN, RDH and RDT at 0x2800, 0x2804, 0x2808, 0x2810 and 0x2818 |
+//! | 0x3800 | TDBAL, TDBAH, TDLEN, TDH and TDT at 0x3800, 0x3804, 0x3808, 0x3810 and 0x3818 |
+//! | 0x5400 | RAL0 and RAH0, the MAC address |
+//!
+//! Receive buffers are 2 KB, the size RCTL selects at reset. The interrupt is INTA.
+
+use crate::*;
+
+use crate::adapter::Result;
+use crate::device::dispatch;
+use crossbeam_channel::RecvTimeoutError;
+use pci::{PciInterruptPin, PciNetworkControllerSubclass};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+const NIC_VENDOR_ID: u16 = 0x1234;
+const NIC_DEVICE_ID: u16 = 0x10d3;
+const NIC_BAR_SIZE: u64 = 0x20000;
+/// How long the model waits for a TLP before it polls the backend again.
+const NIC_POLL: Duration = Duration::from_millis(1);
+
+const REG_CTRL: u64 = 0x0000;
+const REG_STATUS: u64 = 0x0008;
+const REG_ICR: u64 = 0x00c0;
+const REG_ICS: u64 = 0x00c8;
+const REG_IMS: u64 = 0x00d0;
+const REG_IMC: u64 = 0x00d8;
+const REG_RCTL: u64 = 0x0100;
+const REG_TCTL: u64 = 0x0400;
+const REG_RDBAL: u64 = 0x2800;
+const REG_TDBAL: u64 = 0x3800;
+const REG_RAL: u64 = 0x5400;
+const REG_RAH: u64 = 0x5404;
+
+/// Offsets of the registers of a ring from its base address low register.
+const RING_BAL: u64 = 0x00;
+const RING_BAH: u64 = 0x04;
+const RING_LEN: u64 = 0x08;
+const RING_HEAD: u64 = 0x10;
+const RING_TAIL: u64 = 0x18;
+
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_FD: u32 = 0x1;
+const STATUS_LU: u32 = 0x2;
+const RCTL_EN: u32 = 0x2;
+const TCTL_EN: u32 = 0x2;
+const RAH_AV: u32 = 1 << 31;
+
+/// Transmit descriptor written back.
+const ICR_TXDW: u32 = 0x01;
+/// Link status change.
+const ICR_LSC: u32 = 0x04;
+/// Receive timer, raised as soon as a frame is received.
+const ICR_RXT0: u32 = 0x80;
+
+const DESC_SIZE: u64 = 16;
+const TXD_CMD_EOP: u8 = 0x01;
+const TXD_CMD_RS: u8 = 0x08;
+const TXD_STAT_DD: u8 = 0x01;
+const RXD_STAT_DD: u8 = 0x01;
+const RXD_STAT_EOP: u8 = 0x02;
+const RX_BUFFER_SIZE: usize = 2048;
+/// Largest frame the backends hand over, a jumbo frame of the tap device.
+const MAX_FRAME: usize = 65536;
+
+const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// Where the frames of a [`PciNicDevice`] go to and come from.
+pub trait NetBackend: Send + Sync {
+    /// Send the Ethernet frame `frame`.
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// The next frame received, `None` if there is none yet. Never blocks.
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// A port wired to itself: every frame sent is received back.
+#[derive(Default)]
+pub struct Loopback {
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl NetBackend for Loopback {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.frames.push_back(frame.to_vec());
+        Ok(())
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.frames.pop_front())
+    }
+}
+
+/// A tap device of the host, which bridges the model to a host network.
+pub struct Tap {
+    file: File,
+}
+
+impl Tap {
+    /// Attach to the tap device `name`, created if it does not exist. Takes CAP_NET_ADMIN
+    /// unless the device is already owned by the user.
+    pub fn open(name: &str) -> io::Result<Self> {
+        const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tap device name too long",
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/net/tun")?;
+
+        // struct ifreq: the name, then ifr_flags in the union.
+        let mut ifreq = [0u8; 40];
+        ifreq[..name.len()].copy_from_slice(name.as_bytes());
+        let flags = (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short;
+        ifreq[libc::IFNAMSIZ..libc::IFNAMSIZ + 2].copy_from_slice(&flags.to_ne_bytes());
+        // Safe as the ioctl only touches the ifreq, which outlives it.
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, ifreq.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Tap { file })
+    }
+}
+
+impl NetBackend for Tap {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        // Each write is one frame, so it is not split.
+        let n = self.file.write(frame)?;
+        if n != frame.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "frame truncated by the tap device",
+            ));
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut frame = vec![0u8; MAX_FRAME];
+        match self.file.read(&mut frame) {
+            Ok(n) => {
+                frame.truncate(n);
+                Ok(Some(frame))
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// A descriptor ring as its registers describe it. The device owns the descriptors from the
+/// head up to the one before the tail.
+#[derive(Debug, Clone, Copy, Default)]
+struct Ring {
+    base: u64,
+    len: u32,
+    head: u32,
+    tail: u32,
+}
+
+impl Ring {
+    fn count(&self) -> u32 {
+        (self.len / DESC_SIZE as u32).max(1)
+    }
+
+    /// Descriptors the device owns.
+    fn owned(&self) -> u32 {
+        let count = self.count();
+        (self.tail % count + count - self.head % count) % count
+    }
+
+    fn desc(&self, index: u32) -> u64 {
+        self.base + (index % self.count()) as u64 * DESC_SIZE
+    }
+
+    /// Hand the descriptor at the head back to the driver.
+    fn advance(&mut self) {
+        self.head = (self.head + 1) % self.count();
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        match offset {
+            RING_BAL => self.base as u32,
+            RING_BAH => (self.base >> 32) as u32,
+            RING_LEN => self.len,
+            RING_HEAD => self.head,
+            RING_TAIL => self.tail,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u32) {
+        match offset {
+            RING_BAL => self.base = (self.base & !0xffff_ffff) | (value & !0xf) as u64,
+            RING_BAH => self.base = (self.base & 0xffff_ffff) | (value as u64) << 32,
+            RING_LEN => self.len = value & 0x000f_ff80,
+            RING_HEAD => self.head = value & 0xffff,
+            RING_TAIL => self.tail = value & 0xffff,
+            _ => (),
+        }
+    }
+}
+
+/// The e1000-like NIC, see the [module documentation](crate::nic).
+pub struct PciNicDevice {
+    regs: NicRegisters,
+    backend: Box<dyn NetBackend>,
+    bdf: u16,
+    /// The descriptors of a frame sent so far, until the one with EOP.
+    tx_frame: Vec<u8>,
+    /// A frame received while the RX ring had no room for it.
+    rx_frame: Option<Vec<u8>>,
+}
+
+impl PciNicDevice {
+    pub fn new(backend: Box<dyn NetBackend>) -> Self {
+        PciNicDevice {
+            regs: NicRegisters::new(DEFAULT_MAC),
+            backend,
+            bdf: 0,
+            tx_frame: Vec::new(),
+            rx_frame: None,
+        }
+    }
+
+    /// The MAC address in RAL0 and RAH0 at reset, 52:54:00:12:34:56 by default.
+    pub fn mac(mut self, mac: [u8; 6]) -> Self {
+        self.regs = NicRegisters::new(mac);
+        self
+    }
+
+    /// Send the frames of the descriptors the driver queued on the TX ring.
+    fn transmit(&mut self, dma: &mut DmaHandle) -> Result<()> {
+        let regs = &mut self.regs;
+        let mut written_back = false;
+
+        while regs.tctl & TCTL_EN != 0 && regs.tx.owned() > 0 {
+            let addr = regs.tx.desc(regs.tx.head);
+            let desc = dma.read(addr, DESC_SIZE as usize)?;
+            let buffer = u64::from_le_bytes([
+                desc[0], desc[1], desc[2], desc[3], desc[4], desc[5], desc[6], desc[7],
+            ]);
+            let len = u16::from_le_bytes([desc[8], desc[9]]) as usize;
+            let cmd = desc[11];
+
+            if len > 0 {
+                self.tx_frame.extend(dma.read(buffer, len)?);
+            }
+            if cmd & TXD_CMD_RS != 0 {
+                dma.write(addr + 12, &[TXD_STAT_DD])?;
+                written_back = true;
+            }
+            regs.tx.advance();
+
+            if cmd & TXD_CMD_EOP != 0 {
+                if let Err(err) = self.backend.send(&self.tx_frame) {
+                    error!("NIC failed to send a frame: {}", err);
+                }
+                self.tx_frame.clear();
+            }
+        }
+
+        if written_back {
+            regs.raise(ICR_TXDW);
+        }
+        Ok(())
+    }
+
+    /// Move the frames of the backend into the buffers of the RX ring, as far as there is
+    /// room for them.
+    fn receive(&mut self, dma: &mut DmaHandle) -> Result<()> {
+        let regs = &mut self.regs;
+
+        while regs.rctl & RCTL_EN != 0 {
+            let frame = match self.rx_frame.take() {
+                Some(frame) => frame,
+                None => match self.backend.recv() {
+                    Ok(Some(frame)) if !frame.is_empty() => frame,
+                    Ok(Some(_)) => continue,
+                    Ok(None) => break,
+                    Err(err) => {
+                        error!("NIC failed to receive a frame: {}", err);
+                        break;
+                    }
+                },
+            };
+            let needed = frame.len().div_ceil(RX_BUFFER_SIZE) as u32;
+            if regs.rx.owned() < needed {
+                self.rx_frame = Some(frame);
+                break;
+            }
+
+            let chunks = frame.chunks(RX_BUFFER_SIZE);
+            let last = chunks.len() - 1;
+            for (i, chunk) in chunks.enumerate() {
+                let addr = regs.rx.desc(regs.rx.head);
+                let desc = dma.read(addr, 8)?;
+                let buffer = u64::from_le_bytes([
+                    desc[0], desc[1], desc[2], desc[3], desc[4], desc[5], desc[6], desc[7],
+                ]);
+                dma.write(buffer, chunk)?;
+
+                // Length, checksum, status, errors and special.
+                let mut status = RXD_STAT_DD;
+                if i == last {
+                    status |= RXD_STAT_EOP;
+                }
+                let mut writeback = [0u8; 8];
+                writeback[..2].copy_from_slice(&(chunk.len() as u16).to_le_bytes());
+                writeback[4] = status;
+                dma.write(addr + 8, &writeback)?;
+                regs.rx.advance();
+            }
+            regs.raise(ICR_RXT0);
+        }
+        Ok(())
+    }
+}
+
+impl PciSimDevice for PciNicDevice {
+    fn on_start(&mut self, info: &LaneInfo) {
+        self.bdf = info.functions[0];
+    }
+
+    fn on_reset(&mut self, _kind: ResetKind) {
+        self.regs = NicRegisters::new(self.regs.reset_mac);
+        self.tx_frame.clear();
+        self.rx_frame = None;
+    }
+
+    fn run(&mut self, lane: &PciLane) {
+        let mut dma = DmaHandle::new(lane, self.bdf);
+        let irq = IrqHandle::new(lane, self.bdf);
+        let mut completer = self.bdf;
+
+        loop {
+            match dma.recv_timeout(NIC_POLL) {
+                Ok(tlp) => dispatch(&mut self.regs, lane, &tlp, &mut completer),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if std::mem::take(&mut self.regs.was_reset) {
+                self.tx_frame.clear();
+                self.rx_frame = None;
+            }
+            if let Err(err) = self.transmit(&mut dma) {
+                error!("NIC transmit failed: {}", err);
+            }
+            if let Err(err) = self.receive(&mut dma) {
+                error!("NIC receive failed: {}", err);
+            }
+
+            let regs = &self.regs;
+            let level = regs.icr & regs.ims != 0;
+            if let Err(err) = irq.set_intx(|reg| regs.config.read_config_register(reg), level) {
+                error!("NIC interrupt failed: {}", err);
+            }
+        }
+    }
+}
+
+/// The config space and BAR0 of the NIC. Moving frames needs the lane and is left to
+/// [`PciNicDevice::run`].
+struct NicRegisters {
+    config: PcieConfiguration,
+    /// The MAC address the device comes out of reset with.
+    reset_mac: [u8; 6],
+    mac: [u8; 6],
+    ctrl: u32,
+    icr: u32,
+    ims: u32,
+    rctl: u32,
+    tctl: u32,
+    rx: Ring,
+    tx: Ring,
+    /// Whether the driver reset the device by CTRL since the last look.
+    was_reset: bool,
+}
+
+impl NicRegisters {
+    fn new(mac: [u8; 6]) -> Self {
+        let mut config = PciConfiguration::new(
+            NIC_VENDOR_ID,
+            NIC_DEVICE_ID,
+            0,
+            PciClassCode::NetworkController,
+            &PciNetworkControllerSubclass::EthernetController,
+            None,
+            PciHeaderType::Device,
+            0x1af4,
+            0x1100,
+            None,
+        );
+
+        let bar = PciBarConfiguration::new(
+            0,
+            NIC_BAR_SIZE,
+            PciBarRegionType::Memory32BitRegion,
+            PciBarPrefetchable::NotPrefetchable,
+        );
+        config.add_pci_bar(&bar).unwrap();
+        config.set_irq(0, PciInterruptPin::IntA);
+
+        NicRegisters {
+            config: PcieConfiguration::new(config),
+            reset_mac: mac,
+            mac,
+            ctrl: 0,
+            // The link comes up with the device.
+            icr: ICR_LSC,
+            ims: 0,
+            rctl: 0,
+            tctl: 0,
+            rx: Ring::default(),
+            tx: Ring::default(),
+            was_reset: false,
+        }
+    }
+
+    fn raise(&mut self, causes: u32) {
+        self.icr |= causes;
+    }
+
+    /// Reset the registers but the config space, as CTRL.RST does.
+    fn reset(&mut self) {
+        let mut fresh = NicRegisters::new(self.reset_mac);
+        std::mem::swap(&mut fresh.config, &mut self.config);
+        fresh.was_reset = true;
+        *self = fresh;
+    }
+
+    fn read(&mut self, reg: u64) -> u32 {
+        match reg {
+            REG_CTRL => self.ctrl,
+            REG_STATUS => STATUS_LU | STATUS_FD,
+            REG_ICR => std::mem::take(&mut self.icr),
+            REG_IMS => self.ims,
+            REG_RCTL => self.rctl,
+            REG_TCTL => self.tctl,
+            REG_RAL => u32::from_le_bytes([self.mac[0], self.mac[1], self.mac[2], self.mac[3]]),
+            REG_RAH => u16::from_le_bytes([self.mac[4], self.mac[5]]) as u32 | RAH_AV,
+            _ if (REG_RDBAL..REG_RDBAL + 0x20).contains(&reg) => self.rx.read(reg - REG_RDBAL),
+            _ if (REG_TDBAL..REG_TDBAL + 0x20).contains(&reg) => self.tx.read(reg - REG_TDBAL),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, reg: u64, value: u32) {
+        match reg {
+            REG_CTRL if value & CTRL_RST != 0 => self.reset(),
+            REG_CTRL => self.ctrl = value,
+            REG_ICR => self.icr &= !value,
+            REG_ICS => self.raise(value),
+            REG_IMS => self.ims |= value,
+            REG_IMC => self.ims &= !value,
+            REG_RCTL => self.rctl = value,
+            REG_TCTL => self.tctl = value,
+            REG_RAL => self.mac[..4].copy_from_slice(&value.to_le_bytes()),
+            REG_RAH => self.mac[4..].copy_from_slice(&value.to_le_bytes()[..2]),
+            _ if (REG_RDBAL..REG_RDBAL + 0x20).contains(&reg) => {
+                self.rx.write(reg - REG_RDBAL, value)
+            }
+            _ if (REG_TDBAL..REG_TDBAL + 0x20).contains(&reg) => {
+                self.tx.write(reg - REG_TDBAL, value)
+            }
+            _ => (),
+        }
+    }
+}
+
+impl SimpleDevice for NicRegisters {
+    fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+        self.config.read_config_register(reg)
+    }
+
+    fn on_config_write(&mut self, _: u16, reg: usize, offset: u64, data: &[u8]) {
+        self.config.write_config_register(reg, offset, data);
+    }
+
+    fn on_mem_read(&mut self, addr: u64, data: &mut [u8]) -> std::result::Result<(), u8> {
+        // The BAR is aligned to its size.
+        let offset = addr & (NIC_BAR_SIZE - 1);
+        let value = self.read(offset & !0b11).to_le_bytes();
+        let start = (offset & 0b11) as usize;
+        let len = data.len().min(4 - start);
+        data[..len].copy_from_slice(&value[start..start + len]);
+        Ok(())
+    }
+
+    fn on_mem_write(&mut self, addr: u64, data: &[u8]) {
+        let offset = addr & (NIC_BAR_SIZE - 1);
+        if data.len() != 4 || offset & 0b11 != 0 {
+            debug!(
+                "NIC ignores a write of {} bytes at {:#x}",
+                data.len(),
+                offset
+            );
+            return;
+        }
+        self.write(
+            offset,
+            u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::adapter::BAR0_REG;
+    use crossbeam_channel::{unbounded, Sender};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct VecMemory(Arc<Mutex<Vec<u8>>>);
+
+    impl DmaMemory for VecMemory {
+        fn read(&self, gpa: u64, data: &mut [u8]) -> std::io::Result<()> {
+            let gpa = gpa as usize;
+            data.copy_from_slice(&self.0.lock().unwrap()[gpa..gpa + data.len()]);
+            Ok(())
+        }
+
+        fn write(&self, gpa: u64, data: &[u8]) -> std::io::Result<()> {
+            let gpa = gpa as usize;
+            self.0.lock().unwrap()[gpa..gpa + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    struct IntxSink(Sender<(u8, bool)>);
+
+    impl MsiSink for IntxSink {
+        fn inject(&self, _: u64, _: u32) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn set_intx(&self, pin: u8, level: bool) -> std::io::Result<()> {
+            self.0.send((pin, level)).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        const TX_RING: usize = 0x1000;
+        const RX_RING: usize = 0x2000;
+        const TX_BUFFERS: usize = 0x10000;
+        const RX_BUFFERS: usize = 0x20000;
+        const DESCS: usize = 8;
+
+        let mut adapter = PciAdapter::start(Box::new(PciNicDevice::new(Box::new(Loopback::new()))));
+        let (tx, rx) = unbounded();
+        adapter.set_msi_sink(Box::new(IntxSink(tx)));
+        let mut ram = vec![0u8; 0x40000];
+        for i in 0..DESCS {
+            let desc = RX_RING + i * DESC_SIZE as usize;
+            let buffer = (RX_BUFFERS + i * RX_BUFFER_SIZE) as u64;
+            ram[desc..desc + 8].copy_from_slice(&buffer.to_le_bytes());
+        }
+        let memory = VecMemory(Arc::new(Mutex::new(ram)));
+        adapter.set_dma_memory(Box::new(memory.clone()));
+
+        assert_eq!(adapter.config_read(0), 0x10d3_1234);
+        adapter.write_config_register(BAR0_REG, 0, &0xc000_0000u32.to_le_bytes());
+        adapter.mmio_regions.push(MmioRegion {
+            start: GuestAddress(0xc000_0000),
+            length: NIC_BAR_SIZE,
+            type_: PciBarRegionType::Memory32BitRegion,
+            bar_reg: 0,
+            mem_slot: None,
+            host_addr: None,
+            mmap_size: None,
+            slot_mapped: false,
+        });
+        let read = |adapter: &mut PciAdapter, reg: u64| {
+            let mut data = [0u8; 4];
+            adapter.bar_mmio_read(0xc000_0000 + reg, &mut data);
+            u32::from_le_bytes(data)
+        };
+        let write = |adapter: &mut PciAdapter, reg: u64, value: u32| {
+            adapter.bar_mmio_write(0xc000_0000 + reg, &value.to_le_bytes());
+        };
+
+        assert_eq!(read(&mut adapter, REG_RAH), 0x5634 | RAH_AV);
+        assert_eq!(read(&mut adapter, REG_ICR), ICR_LSC);
+        assert_eq!(read(&mut adapter, REG_ICR), 0);
+
+        let ring_size = (DESCS as u64 * DESC_SIZE) as u32;
+        write(&mut adapter, REG_RDBAL + RING_BAL, RX_RING as u32);
+        write(&mut adapter, REG_RDBAL + RING_LEN, ring_size);
+        write(&mut adapter, REG_RDBAL + RING_TAIL, DESCS as u32 - 1);
+        write(&mut adapter, REG_TDBAL + RING_BAL, TX_RING as u32);
+        write(&mut adapter, REG_TDBAL + RING_LEN, ring_size);
+        write(&mut adapter, REG_IMS, ICR_RXT0);
+        write(&mut adapter, REG_RCTL, RCTL_EN);
+        write(&mut adapter, REG_TCTL, TCTL_EN);
+
+        // Send frames of 3 KB, in 2 descriptors each, until the RX ring is full.
+        let frames = (DESCS - 1) / 2;
+        for n in 0..frames {
+            let frame: Vec<u8> = (0..3000).map(|i| (i + n) as u8).collect();
+            let mut ram = memory.0.lock().unwrap();
+            ram[TX_BUFFERS + n * 4096..TX_BUFFERS + n * 4096 + 3000].copy_from_slice(&frame);
+            for (i, (offset, len, cmd)) in [(0, 1000, 0), (1000, 2000, TXD_CMD_EOP | TXD_CMD_RS)]
+                .iter()
+                .enumerate()
+            {
+                let desc = TX_RING + (n * 2 + i) * DESC_SIZE as usize;
+                let buffer = (TX_BUFFERS + n * 4096 + offset) as u64;
+                ram[desc..desc + 8].copy_from_slice(&buffer.to_le_bytes());
+                ram[desc + 8..desc + 10].copy_from_slice(&(*len as u16).to_le_bytes());
+                ram[desc + 11] = *cmd;
+                ram[desc + 12] = 0;
+            }
+        }
+        write(&mut adapter, REG_TDBAL + RING_TAIL, frames as u32 * 2);
+
+        // The register reads are served after the doorbell is, frames and all.
+        assert_eq!(read(&mut adapter, REG_TDBAL + RING_HEAD), frames as u32 * 2);
+        assert_eq!(read(&mut adapter, REG_RDBAL + RING_HEAD), frames as u32 * 2);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok((1, true)));
+        assert_eq!(read(&mut adapter, REG_ICR), ICR_TXDW | ICR_RXT0);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok((1, false)));
+
+        let ram = memory.0.lock().unwrap();
+        for n in 0..frames {
+            assert_eq!(ram[TX_RING + (n * 2 + 1) * 16 + 12], TXD_STAT_DD);
+            let mut frame = Vec::new();
+            for i in n * 2..n * 2 + 2 {
+                let desc = RX_RING + i * 16;
+                let len = u16::from_le_bytes([ram[desc + 8], ram[desc + 9]]) as usize;
+                let status = if i % 2 == 1 {
+                    RXD_STAT_DD | RXD_STAT_EOP
+                } else {
+                    RXD_STAT_DD
+                };
+                assert_eq!(ram[desc + 12], status);
+                let buffer = RX_BUFFERS + i * RX_BUFFER_SIZE;
+                frame.extend_from_slice(&ram[buffer..buffer + len]);
+            }
+            assert_eq!(frame, (0..3000).map(|i| (i + n) as u8).collect::<Vec<u8>>());
+        }
+        drop(ram);
+
+        adapter.stop();
+        adapter.join();
+    }
+}
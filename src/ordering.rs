@@ -0,0 +1,201 @@
+//! Ordering rules of the transactions, after table 2-39 of the PCIe 3.0 specification.
+//!
+//! A lane is a FIFO, the strongest ordering there is. A real fabric reorders the TLPs wherever
+//! the rules allow it: posted requests pass non-posted ones to avoid deadlocks, a TLP with the
+//! Relaxed Ordering attribute may pass the posted requests before it and one with ID-based
+//! Ordering may pass those of other requesters. A device model only ever run on a lane could
+//! rely on an order it is not promised, e.g. answer a read with a relaxed completion before
+//! the DMA writes the driver expects to see first.
+//!
+//! [`OrderingPolicy::Reorder`] makes the bridge take the TLPs of the models in the most
+//! reordered way the rules allow, [`OrderingPolicy::Strict`] flags the TLPs of the models
+//! which break the rules of the ordering attributes.
+
+use crate::*;
+
+/// Whether a TLP passes a TLP sent before it on the way to the same receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Passing {
+    /// The TLP must be able to pass, or the fabric could deadlock.
+    Must,
+    /// The TLP is permitted to pass but does not have to.
+    May,
+    /// The TLP must stay behind.
+    Never,
+}
+
+/// What the bridge does about the order of the TLPs of the device models.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OrderingPolicy {
+    /// Take the TLPs in the order they are sent.
+    #[default]
+    InOrder,
+    /// Take the TLPs found together on the upstream lane in the most reordered way the rules
+    /// allow, to exercise the assumptions of the models and of their drivers.
+    Reorder,
+    /// Take the TLPs in order, but count those breaking the rules of the ordering attributes
+    /// in [`AdapterStats::violations`].
+    Strict,
+}
+
+/// The requester of a TLP, or the completer of a completion, which is what ID-based Ordering
+/// goes by. `None` for the TLPs which carry no ID.
+pub(crate) fn id_of(tlp: &Tlp) -> Option<u16> {
+    use PacketType::*;
+
+    match tlp.header._type {
+        MemoryRead(e) | MemoryWrite(e) | IoRead(e) | IoWrite(e) => Some(e.requester),
+        MemoryRead64(e) | MemoryWrite64(e) => Some(e.requester),
+        Config0Read(e) | Config0Write(e) | Config1Read(e) | Config1Write(e) => Some(e.requester),
+        RoutedMessage(e) | RoutedMessageData(e) => Some(e.requester),
+        PageRequest(e) => Some(e.requester),
+        PrgResponse(e) => Some(e.requester),
+        Completion(e) | CompletionData(e) | CompletionLocked(e) | CompletionLockedData(e) => {
+            Some(e.completer)
+        }
+        _ => None,
+    }
+}
+
+/// Requester and tag of a completion.
+fn transaction_of(tlp: &Tlp) -> Option<(u16, u8)> {
+    use PacketType::*;
+
+    match tlp.header._type {
+        Completion(e) | CompletionData(e) | CompletionLocked(e) | CompletionLockedData(e) => {
+            Some((e.requester, e.tag))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `later` passes `earlier`, which is sent before it.
+pub fn passing(later: &Tlp, earlier: &Tlp) -> Passing {
+    use FcClass::*;
+
+    let relaxed = later.header.relax_ordering;
+    let id_ordered = later.header.id_ordering
+        && matches!((id_of(later), id_of(earlier)), (Some(a), Some(b)) if a != b);
+
+    match (FcClass::of(later), FcClass::of(earlier)) {
+        // A2, D2: only relaxed or ID-ordered TLPs pass posted requests.
+        (Posted, Posted) | (Completion, Posted) if relaxed || id_ordered => Passing::May,
+        // B2, C2: the Relaxed Ordering attribute does not apply to non-posted requests.
+        (NonPosted, Posted) if id_ordered => Passing::May,
+        (_, Posted) => Passing::Never,
+        // A3, A4, D3, D4: the deadlock avoidance rules.
+        (Posted, NonPosted) | (Completion, NonPosted) => Passing::Must,
+        // D5b: the completions of a request stay in order.
+        (Completion, Completion) if transaction_of(later) == transaction_of(earlier) => {
+            Passing::Never
+        }
+        _ => Passing::May,
+    }
+}
+
+/// Reorder TLPs sent in the order of `tlps`: each one goes before as many earlier ones as it
+/// is allowed to pass.
+pub fn reorder(tlps: Vec<Tlp>) -> Vec<Tlp> {
+    let mut ordered: Vec<Tlp> = Vec::with_capacity(tlps.len());
+    for tlp in tlps {
+        let at = ordered
+            .iter()
+            .rposition(|earlier| passing(&tlp, earlier) == Passing::Never)
+            .map_or(0, |i| i + 1);
+        ordered.insert(at, tlp);
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(requester: u16, relaxed: bool) -> Tlp {
+        let mut tlp = TlpBuilder::memory_write64(Memory64Extra {
+            requester,
+            tag: 0,
+            addr: 0x1000,
+        })
+        .byte_enable(0xf)
+        .data(vec![0])
+        .build();
+        tlp.header.relax_ordering = relaxed;
+        tlp
+    }
+
+    fn completion(tag: u8, relaxed: bool) -> Tlp {
+        let mut tlp = TlpBuilder::completion_data(CompletionExtra {
+            requester: 0x10,
+            completer: 0x18,
+            tag,
+            status: CPL_SC,
+            bcm: false,
+            byte_count: 4,
+            lower_address: 0,
+        })
+        .byte_enable(0xf)
+        .data(vec![0])
+        .build();
+        tlp.header.relax_ordering = relaxed;
+        tlp
+    }
+
+    #[test]
+    fn rules() {
+        let read = TlpBuilder::memory_read64(Memory64Extra {
+            requester: 0x18,
+            tag: 1,
+            addr: 0x1000,
+        })
+        .byte_enable(0xf)
+        .length(1)
+        .build();
+
+        assert_eq!(
+            passing(&write(0x18, false), &write(0x18, false)),
+            Passing::Never
+        );
+        assert_eq!(
+            passing(&write(0x18, true), &write(0x18, false)),
+            Passing::May
+        );
+        assert_eq!(passing(&write(0x18, false), &read), Passing::Must);
+        assert_eq!(
+            passing(&completion(0, false), &write(0x18, false)),
+            Passing::Never
+        );
+        assert_eq!(
+            passing(&completion(0, true), &write(0x18, false)),
+            Passing::May
+        );
+        assert_eq!(
+            passing(&completion(0, false), &completion(0, false)),
+            Passing::Never
+        );
+        assert_eq!(
+            passing(&completion(1, false), &completion(0, false)),
+            Passing::May
+        );
+
+        // Relaxed Ordering does not let a read pass a write, ID-based Ordering does.
+        let mut relaxed = read.clone();
+        relaxed.header.relax_ordering = true;
+        assert_eq!(passing(&relaxed, &write(0x18, false)), Passing::Never);
+        let mut id_ordered = read;
+        id_ordered.header.id_ordering = true;
+        assert_eq!(passing(&id_ordered, &write(0x18, false)), Passing::Never);
+        assert_eq!(passing(&id_ordered, &write(0x20, false)), Passing::May);
+
+        // The relaxed completion goes before everything, the other one stays behind the
+        // first write but lets the second one pass.
+        let ordered = reorder(vec![
+            write(0x18, false),
+            completion(0, false),
+            write(0x18, false),
+            completion(1, true),
+        ]);
+        let tags: Vec<_> = ordered.iter().map(transaction_of).collect();
+        assert_eq!(tags, vec![Some((0x10, 1)), None, None, Some((0x10, 0))]);
+    }
+}
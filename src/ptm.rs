@@ -0,0 +1,114 @@
+//! Precision Time Measurement.
+//!
+//! A device with PTM enabled in its [`PtmCapability`] asks the root complex for the time by a
+//! PTM Request message, see [`request`]. The bridge stands in for the root complex: it takes
+//! the PTM Master Time from its [`PtmTimeSource`], the clock of the adapter unless
+//! [`PciAdapter::set_ptm_time_source`] says otherwise, and answers by a PTM ResponseD message,
+//! see [`PtmResponse`]. The model relates the master time to its local clock with the times it
+//! sent the request and got the response, see [`PtmResponse::master_time_at`], e.g. to run a
+//! PTP hardware clock of a NIC.
+//!
+//! The messages are local ones, so they carry the requester ID in the upper half of the first
+//! DW of the request, and the master time and the propagation delay in the payload of the
+//! response.
+
+use crate::*;
+
+/// Message code of PTM Request.
+pub const PTM_REQUEST: u8 = 0x52;
+/// Message code of PTM Response and PTM ResponseD.
+pub const PTM_RESPONSE: u8 = 0x53;
+
+/// The source of the PTM Master Time the bridge hands out.
+pub trait PtmTimeSource: Send {
+    /// The master time in ns.
+    fn now(&self) -> u64;
+}
+
+impl PtmTimeSource for SimClock {
+    fn now(&self) -> u64 {
+        SimClock::now(self).as_nanos() as u64
+    }
+}
+
+/// The PTM Request of the function `requester`.
+pub fn request(requester: u16) -> Tlp {
+    TlpBuilder::with_type(PacketType::MessageData(PTM_REQUEST))
+        .data(vec![(requester as u32) << 16])
+        .build()
+}
+
+/// The requester of a message of [`request`], `None` for other TLPs.
+pub(crate) fn requester_of(tlp: &Tlp) -> Option<u16> {
+    match (tlp.header._type, tlp.data.as_deref()) {
+        (PacketType::MessageData(PTM_REQUEST), Some(&[dw])) => Some((dw >> 16) as u16),
+        _ => None,
+    }
+}
+
+/// What a PTM ResponseD tells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtmResponse {
+    /// The master time in ns when the request arrived.
+    pub master_time: u64,
+    /// The time in ns from the arrival of the request to the departure of the response.
+    pub propagation_delay: u32,
+}
+
+impl PtmResponse {
+    pub fn to_message(&self) -> Tlp {
+        TlpBuilder::with_type(PacketType::MessageData(PTM_RESPONSE))
+            .data(vec![
+                (self.master_time >> 32) as u32,
+                self.master_time as u32,
+                self.propagation_delay,
+            ])
+            .build()
+    }
+
+    /// The response a message of [`PtmResponse::to_message`] tells about, `None` for other
+    /// TLPs.
+    pub fn from_message(tlp: &Tlp) -> Option<PtmResponse> {
+        match (tlp.header._type, tlp.data.as_deref()) {
+            (PacketType::MessageData(PTM_RESPONSE), Some(&[high, low, delay])) => {
+                Some(PtmResponse {
+                    master_time: (high as u64) << 32 | low as u64,
+                    propagation_delay: delay,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The master time at the local time `t4` the response arrived, for the request sent at
+    /// the local time `t1`. The link delay is taken as half of the round trip the bridge did
+    /// not spend on the request.
+    pub fn master_time_at(&self, t1: u64, t4: u64) -> u64 {
+        let delay = self.propagation_delay as u64;
+        let link = t4.saturating_sub(t1).saturating_sub(delay) / 2;
+        self.master_time + delay + link
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        let tlp = request(0x18);
+        assert_eq!(requester_of(&tlp), Some(0x18));
+        assert_eq!(PtmResponse::from_message(&tlp), None);
+
+        let response = PtmResponse {
+            master_time: 0x1_0000_1000,
+            propagation_delay: 200,
+        };
+        let tlp = response.to_message();
+        assert_eq!(PtmResponse::from_message(&tlp), Some(response));
+        assert_eq!(requester_of(&tlp), None);
+
+        // 1000 ns round trip, 200 of them in the bridge, 400 on the link each way.
+        assert_eq!(response.master_time_at(5000, 6000), 0x1_0000_1000 + 600);
+    }
+}
@@ -0,0 +1,613 @@
+//! Device models in another process or on another host, reached through a socket.
+//!
+//! [`PciAdapterBuilder::start_unix`] listens on a Unix domain socket and starts the bridge once
+//! a device model connected to it, the model itself calls [`connect_unix`] and runs on the
+//! lane it returns. [`PciAdapterBuilder::start_tcp`] and [`connect_tcp`] do the same over TCP,
+//! e.g. for a model running in an RTL simulator on another machine. The bridge keeps
+//! listening: when the model goes away the lane goes down, see [`DisconnectPolicy`], and the
+//! next model which connects is attached in its place, like [`PciAdapter::reconnect`] does.
+//!
+//! Other streams, e.g. TLS on top of TCP, plug in through [`LaneStream`] and [`LaneListener`],
+//! with [`PciAdapterBuilder::start_listener`] and [`connect_stream`]. A model on the same host
+//! moving a lot of data had better use [`crate::shm`], which only sets up over the socket.
+//!
+//! # Wire format
+//!
+//! The stream is a sequence of frames, each one a little endian u32 length followed by that
+//! many bytes. Both peers open with a hello frame, then every frame is a TLP in its wire format,
+//! see [`Tlp::from_bytes`]. A model written in another language only needs to implement this.
+//!
+//! | size | hello field                                                              |
+//! |------|--------------------------------------------------------------------------|
+//! | 4    | magic `PTLP`                                                             |
+//! | 2    | version, currently 1                                                     |
+//! | 2    | reserved, 0                                                              |
+//! | 4    | features: bit 0 extended tags, 1 10-bit tags, 2 ATS, 3 IDE, 4 AtomicOps  |
+//! |      | and bits 15:8 the number of virtual channels besides VC0                 |
+//!
+//! The features of the model are those of [`PciSimDevice::features`], the bridge answers with
+//! the features it implements and uses the common subset.
+//!
+//! The model learns about its resets from a message with code [`RESET_MESSAGE`], see
+//! [`ResetKind::from_message`].
+
+use crate::*;
+
+use crate::adapter::{Reconnector, BRIDGE_FEATURES};
+use crossbeam_channel::{bounded, select, Receiver, Sender};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+const MAGIC: &[u8; 4] = b"PTLP";
+const VERSION: u16 = 1;
+const HELLO_LEN: usize = 12;
+/// The largest TLP: a 4 DW header, 1024 DW of data and a few prefixes.
+pub(crate) const MAX_FRAME: usize = 4096 + 32;
+/// How often the listener checks whether the bridge is still running.
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+
+/// A connected stream which carries a lane. The lane reads and writes the stream from two
+/// threads, through two handles of the stream.
+pub trait LaneStream: Read + Write + Send + Sync + Sized + 'static {
+    /// Another handle of the same stream.
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Close the stream, which ends a read blocked on the other handle.
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl LaneStream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl LaneStream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// Where the bridge takes the connections of device models.
+pub trait LaneListener: Send + 'static {
+    type Stream: LaneStream;
+
+    /// Take the next connection, without blocking: fail with [`io::ErrorKind::WouldBlock`]
+    /// if no peer is waiting. The stream returned is blocking.
+    fn accept(&self) -> io::Result<Self::Stream>;
+}
+
+/// Must be non-blocking, like [`PciAdapterBuilder::start_unix`] sets it.
+impl LaneListener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> io::Result<UnixStream> {
+        let (stream, _) = UnixListener::accept(self)?;
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+}
+
+/// Must be non-blocking, like [`PciAdapterBuilder::start_tcp`] sets it.
+impl LaneListener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        let (stream, _) = TcpListener::accept(self)?;
+        stream.set_nonblocking(false)?;
+        // A TLP is a small message waiting for its answer.
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn write_frame<S: Write>(stream: &mut S, payload: &[u8]) -> io::Result<()> {
+    let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}
+
+pub(crate) fn read_frame<S: Read>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(invalid("frame too long"));
+    }
+
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Write `data` to `stream` with `fds` attached.
+pub(crate) fn send_with_fds(stream: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let fds_len = mem::size_of_val(fds) as u32;
+    let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+    // u64 keeps the control buffer aligned for the cmsghdr.
+    let mut control = vec![0u64; space.div_ceil(8)];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        // SAFETY: the control buffer holds one header with room for the fds.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                fds.len(),
+            );
+        }
+    }
+
+    let sent = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The fds went with the first byte, the rest is plain data.
+    (&*stream).write_all(&data[sent as usize..])
+}
+
+/// Read `buf.len()` bytes of `stream`, returns the fds attached to them.
+pub(crate) fn recv_with_fds(stream: &UnixStream, buf: &mut [u8]) -> io::Result<Vec<File>> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = [0u64; 16];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if received == 0 && !buf.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut fds = vec![];
+    // SAFETY: the kernel filled the control buffer with complete headers.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(File::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    (&*stream).read_exact(&mut buf[received as usize..])?;
+    Ok(fds)
+}
+
+/// A non-blocking eventfd, e.g. a doorbell of the peer.
+pub(crate) fn eventfd() -> io::Result<File> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the fd is new and owned by nobody else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn features_to_bits(features: &DeviceFeatures) -> u32 {
+    features.extended_tags as u32
+        | (features.ten_bit_tags as u32) << 1
+        | (features.ats as u32) << 2
+        | (features.ide as u32) << 3
+        | (features.atomics as u32) << 4
+        | (features.extra_virtual_channels as u32) << 8
+}
+
+fn features_from_bits(bits: u32) -> DeviceFeatures {
+    DeviceFeatures {
+        extended_tags: bits & 0x1 != 0,
+        ten_bit_tags: bits & 0x2 != 0,
+        ats: bits & 0x4 != 0,
+        ide: bits & 0x8 != 0,
+        atomics: bits & 0x10 != 0,
+        extra_virtual_channels: (bits >> 8) as u8,
+    }
+}
+
+/// Send our hello with `features` and take the one of the peer, returns the features of the
+/// peer.
+pub(crate) fn handshake<S: LaneStream>(
+    stream: &mut S,
+    features: &DeviceFeatures,
+) -> io::Result<DeviceFeatures> {
+    let mut hello = MAGIC.to_vec();
+    hello.extend_from_slice(&VERSION.to_le_bytes());
+    hello.extend_from_slice(&[0, 0]);
+    hello.extend_from_slice(&features_to_bits(features).to_le_bytes());
+    write_frame(stream, &hello)?;
+
+    let hello = read_frame(stream)?;
+    if hello.len() != HELLO_LEN || &hello[..4] != MAGIC {
+        return Err(invalid("not a TLP lane"));
+    }
+    if u16::from_le_bytes([hello[4], hello[5]]) != VERSION {
+        return Err(invalid("unsupported lane version"));
+    }
+    let bits = u32::from_le_bytes([hello[8], hello[9], hello[10], hello[11]]);
+    Ok(features_from_bits(bits))
+}
+
+/// Put the TLPs read from `stream` on `tx` until the stream or the lane is closed.
+fn read_tlps<S: LaneStream>(mut stream: S, tx: Sender<Tlp>) {
+    loop {
+        let frame = match read_frame(&mut stream) {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                error!("Failed to read a TLP from the socket: {}", e);
+                break;
+            }
+        };
+        let tlp = match Tlp::from_bytes(&frame) {
+            Ok(tlp) => tlp,
+            Err(e) => {
+                error!("Malformed TLP from the socket: {:?}", e);
+                break;
+            }
+        };
+        if tx.send(tlp).is_err() {
+            break;
+        }
+    }
+    debug!("Socket lane closed");
+}
+
+/// One end of a lane carried by a socket: the TLPs of the lane it runs on go out on the
+/// socket, the TLPs from the socket come in on the lane.
+///
+/// As a device model it stands for the model at the other end of the socket, whose lane ends
+/// when the socket is closed.
+struct SocketLane<S> {
+    stream: S,
+    features: DeviceFeatures,
+    /// Disconnected once the reader of the socket ends.
+    closed: Option<Receiver<()>>,
+}
+
+impl<S: LaneStream> SocketLane<S> {
+    fn new(stream: S, features: DeviceFeatures) -> Self {
+        SocketLane {
+            stream,
+            features,
+            closed: None,
+        }
+    }
+
+    /// Start the reader of the socket, which outlives the runs on the lanes of resets.
+    fn start_reader(&mut self, tx: &Sender<Tlp>) -> io::Result<Receiver<()>> {
+        let stream = self.stream.try_clone()?;
+        let tx = tx.clone();
+        let (closed_tx, closed) = bounded(0);
+        std::thread::Builder::new()
+            .name("tlp-socket".to_string())
+            .spawn(move || {
+                let _closed = closed_tx;
+                read_tlps(stream, tx);
+            })?;
+        Ok(closed)
+    }
+}
+
+impl<S: LaneStream> PciSimDevice for SocketLane<S> {
+    fn features(&self) -> DeviceFeatures {
+        self.features
+    }
+
+    fn run(&mut self, lane: &PciLane) {
+        let closed = match self.closed.clone() {
+            Some(closed) => closed,
+            None => match self.start_reader(&lane.tx) {
+                Ok(closed) => self.closed.insert(closed).clone(),
+                Err(e) => {
+                    error!("Failed to start the socket reader: {}", e);
+                    return;
+                }
+            },
+        };
+
+        loop {
+            select! {
+                recv(lane.rx) -> tlp => {
+                    let tlp = match tlp {
+                        Ok(tlp) => tlp,
+                        Err(_) => break,
+                    };
+                    match tlp.to_bytes() {
+                        Ok(bytes) => {
+                            if let Err(e) = write_frame(&mut self.stream, &bytes) {
+                                error!("Failed to write a TLP to the socket: {}", e);
+                                break;
+                            }
+                        }
+                        Err(e) => error!("{:?} dropped: {:?}", tlp.header._type, e),
+                    }
+                },
+                recv(closed) -> _ => break,
+            }
+        }
+    }
+
+    fn on_reset(&mut self, kind: ResetKind) {
+        let sent = kind
+            .to_message()
+            .to_bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+            .and_then(|bytes| write_frame(&mut self.stream, &bytes));
+        if let Err(e) = sent {
+            error!("Failed to send the {:?} reset to the socket: {}", kind, e);
+        }
+    }
+
+    fn on_stop(&mut self) {
+        // The reader ends and the peer sees the lane closed.
+        let _ = self.stream.shutdown();
+    }
+}
+
+/// Carry `lane` over `stream` on a thread of its own, until either of them is closed.
+fn spawn_lane<S: LaneStream>(stream: S, features: DeviceFeatures, lane: PciLane) -> io::Result<()> {
+    std::thread::Builder::new()
+        .name("tlp-socket".to_string())
+        .spawn(move || {
+            let mut socket = SocketLane::new(stream, features);
+            socket.run(&lane);
+            socket.on_stop();
+        })?;
+    Ok(())
+}
+
+/// Wait for the next device model which connects to `listener` while `alive` holds and
+/// shake hands with it.
+pub(crate) fn accept<L: LaneListener>(
+    listener: &L,
+    alive: impl Fn() -> bool,
+) -> io::Result<Option<(L::Stream, DeviceFeatures)>> {
+    while alive() {
+        match listener.accept() {
+            Ok(mut stream) => match handshake(&mut stream, &BRIDGE_FEATURES) {
+                Ok(features) => return Ok(Some((stream, features))),
+                Err(e) => error!("Handshake with a device model failed: {}", e),
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_POLL),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+/// Attach the models which connect to `listener` to the bridge until the bridge is gone,
+/// `carry` carries the lane of each model over its stream.
+pub(crate) fn serve_reconnects<L, F>(listener: L, reconnector: Reconnector, carry: F)
+where
+    L: LaneListener,
+    F: Fn(L::Stream, DeviceFeatures, PciLane) -> io::Result<()>,
+{
+    loop {
+        let (stream, features) = match accept(&listener, || reconnector.alive()) {
+            Ok(Some(peer)) => peer,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Failed to accept a device model: {}", e);
+                return;
+            }
+        };
+
+        let (lane, peer) = PciLane::pair();
+        if let Err(e) = carry(stream, features, peer) {
+            error!("Failed to carry the lane of a device model: {}", e);
+            continue;
+        }
+        debug!("Device model reconnected");
+        if !reconnector.reconnect(lane, features) {
+            return;
+        }
+    }
+}
+
+impl PciAdapterBuilder {
+    /// Start the bridge with the device model which connects first to `listener`, see
+    /// [`crate::remote`]. Blocks until the model is connected. The models which connect later
+    /// replace it on the lane, the config cache of the adapter is not cleared when they do.
+    pub fn start_listener<L: LaneListener>(self, listener: L) -> io::Result<PciAdapter> {
+        let (stream, features) = accept(&listener, || true)?.unwrap();
+
+        let adapter = self.start(Box::new(SocketLane::new(stream, features)));
+        let reconnector = adapter.reconnector();
+        std::thread::Builder::new()
+            .name("tlp-listener".to_string())
+            .spawn(move || serve_reconnects(listener, reconnector, spawn_lane))?;
+        Ok(adapter)
+    }
+
+    /// See [`PciAdapterBuilder::start_listener`], listening on the Unix domain socket at
+    /// `path`.
+    pub fn start_unix<P: AsRef<Path>>(self, path: P) -> io::Result<PciAdapter> {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        self.start_listener(listener)
+    }
+
+    /// See [`PciAdapterBuilder::start_listener`], listening on TCP at `addr`.
+    pub fn start_tcp<A: ToSocketAddrs>(self, addr: A) -> io::Result<PciAdapter> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        self.start_listener(listener)
+    }
+}
+
+impl PciAdapter {
+    /// See [`PciAdapterBuilder::start_unix`].
+    pub fn start_unix<P: AsRef<Path>>(path: P) -> io::Result<PciAdapter> {
+        PciAdapterBuilder::new().start_unix(path)
+    }
+
+    /// See [`PciAdapterBuilder::start_tcp`].
+    pub fn start_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<PciAdapter> {
+        PciAdapterBuilder::new().start_tcp(addr)
+    }
+}
+
+/// Connect a device model advertising `features` to the bridge at the other end of `stream`.
+/// Returns the lane the model runs on and the features of the bridge. The connection is
+/// closed once the model drops the lane.
+pub fn connect_stream<S: LaneStream>(
+    mut stream: S,
+    features: DeviceFeatures,
+) -> io::Result<(PciLane, DeviceFeatures)> {
+    let bridge = handshake(&mut stream, &features)?;
+    let (lane, peer) = PciLane::pair();
+    spawn_lane(stream, bridge, peer)?;
+    Ok((lane, bridge))
+}
+
+/// See [`connect_stream`], for the bridge listening on the Unix domain socket at `path`.
+pub fn connect_unix<P: AsRef<Path>>(
+    path: P,
+    features: DeviceFeatures,
+) -> io::Result<(PciLane, DeviceFeatures)> {
+    connect_stream(UnixStream::connect(path)?, features)
+}
+
+/// See [`connect_stream`], for the bridge listening on TCP at `addr`.
+pub fn connect_tcp<A: ToSocketAddrs>(
+    addr: A,
+    features: DeviceFeatures,
+) -> io::Result<(PciLane, DeviceFeatures)> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    connect_stream(stream, features)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::device::{dispatch, Ids};
+    use std::thread;
+
+    /// Connect to the bridge at `path` once it is listening.
+    fn connect(path: &Path) -> PciLane {
+        loop {
+            match connect_unix(path, DeviceFeatures::default()) {
+                Ok((lane, bridge)) => {
+                    assert_eq!(bridge, BRIDGE_FEATURES);
+                    return lane;
+                }
+                Err(_) => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+
+    #[test]
+    fn unix_lane() {
+        let path = std::env::temp_dir().join(format!("pcie-tlp-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // The first model answers one request and goes away.
+        let first = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let lane = connect(&path);
+                let tlp = lane.rx.recv().unwrap();
+                dispatch(&mut Ids(0x5678_1234), &lane, &tlp, &mut 0);
+            })
+        };
+        let adapter = PciAdapter::start_unix(&path).unwrap();
+        let events = adapter.events();
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        first.join().unwrap();
+        assert_eq!(events.recv(), Ok(AdapterEvent::LaneDown { pending: 0 }));
+
+        // The next model takes its place.
+        let second = {
+            let path = path.clone();
+            thread::spawn(move || Ids(0x9abc_1234).run(&connect(&path)))
+        };
+        assert_eq!(events.recv(), Ok(AdapterEvent::LaneUp { replayed: 0 }));
+        assert_eq!(adapter.try_config_read(0), Ok(0x9abc_1234));
+
+        adapter.stop();
+        adapter.join();
+        second.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn tcp_lane() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let model = thread::spawn(move || {
+            let (lane, _) = connect_tcp(addr, DeviceFeatures::default()).unwrap();
+            Ids(0x5678_1234).run(&lane);
+        });
+        let adapter = PciAdapterBuilder::new().start_listener(listener).unwrap();
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+
+        adapter.stop();
+        adapter.join();
+        model.join().unwrap();
+    }
+
+    #[test]
+    fn reset_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let model = thread::spawn(move || {
+            let (lane, _) = connect_tcp(addr, DeviceFeatures::default()).unwrap();
+            let tlp = lane.rx.recv().unwrap();
+            ResetKind::from_message(&tlp)
+        });
+        let adapter = PciAdapterBuilder::new().start_listener(listener).unwrap();
+        adapter.hot_reset().unwrap();
+        assert_eq!(model.join().unwrap(), Some(ResetKind::Hot));
+
+        adapter.stop();
+        adapter.join();
+    }
+}
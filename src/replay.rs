@@ -0,0 +1,244 @@
+//! Replay of recorded TLP exchanges.
+//!
+//! A [`PcapngCapture`] installed on the bridge records the ordered exchange between the bridge
+//! and a device model. The [`Trace`] read back from it could stand in for either side: a
+//! [`ReplayDevice`] answers the requests of the bridge from the trace, while
+//! [`Trace::replay_host`] drives a device model with the requests of the trace and checks its
+//! answers, e.g. as a regression test of the model.
+//!
+//! Tags are picked by the requester at runtime, so requests are compared and completions are
+//! matched regardless of their tags.
+
+use crate::*;
+
+use crossbeam_channel::RecvTimeoutError;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// Why a replay diverged from its trace, with the index of the TLP in the trace.
+#[derive(Debug)]
+pub enum ReplayError {
+    /// The TLP is not the one recorded.
+    Mismatch {
+        index: usize,
+        expected: Box<Tlp>,
+        actual: Box<Tlp>,
+    },
+    /// The recorded TLP did not arrive in time.
+    Timeout(usize),
+    /// The other side left the lane before the recorded TLP arrived.
+    Disconnected(usize),
+}
+
+/// An ordered exchange of TLPs between the bridge and a device.
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    pub tlps: Vec<(Direction, Tlp)>,
+}
+
+impl Trace {
+    /// Read a trace from a capture written by [`PcapngCapture`].
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Trace> {
+        let tlps = read_capture(File::open(path)?)?;
+
+        Ok(Trace {
+            tlps: tlps
+                .into_iter()
+                .map(|(direction, _, tlp)| (direction, tlp))
+                .collect(),
+        })
+    }
+
+    /// Play the bridge: run `device` on a lane, send it the downstream TLPs of the trace and
+    /// check its upstream TLPs against the trace, waiting up to `timeout` for each of them.
+    pub fn replay_host(
+        &self,
+        mut device: Box<dyn PciSimDevice + Send>,
+        timeout: Duration,
+    ) -> std::result::Result<(), ReplayError> {
+        let (lane, device_lane) = PciLane::pair();
+        let handle = thread::spawn(move || device.run(&device_lane));
+
+        let result = self.drive(&lane, timeout);
+        drop(lane);
+        let _ = handle.join();
+        result
+    }
+
+    fn drive(&self, lane: &PciLane, timeout: Duration) -> std::result::Result<(), ReplayError> {
+        for (index, (direction, tlp)) in self.tlps.iter().enumerate() {
+            match direction {
+                Direction::Downstream => {
+                    lane.tx
+                        .send(tlp.clone())
+                        .map_err(|_| ReplayError::Disconnected(index))?;
+                }
+                Direction::Upstream => {
+                    let actual = lane.rx.recv_timeout(timeout).map_err(|e| match e {
+                        RecvTimeoutError::Timeout => ReplayError::Timeout(index),
+                        RecvTimeoutError::Disconnected => ReplayError::Disconnected(index),
+                    })?;
+                    if !same_tlp(tlp, &actual) {
+                        return Err(ReplayError::Mismatch {
+                            index,
+                            expected: Box::new(tlp.clone()),
+                            actual: Box::new(actual),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Device model answering the bridge from a [`Trace`].
+///
+/// Each request of the bridge must match the next downstream TLP of the trace, the upstream
+/// TLPs following it are sent back with the tags of the live request. The model leaves the
+/// lane when the bridge diverges from the trace or the trace ends.
+pub struct ReplayDevice {
+    trace: Trace,
+    features: DeviceFeatures,
+}
+
+impl ReplayDevice {
+    /// Replay `trace`, advertising `features` like the recorded device did.
+    pub fn new(trace: Trace, features: DeviceFeatures) -> Self {
+        ReplayDevice { trace, features }
+    }
+}
+
+impl PciSimDevice for ReplayDevice {
+    fn features(&self) -> DeviceFeatures {
+        self.features
+    }
+
+    fn run(&mut self, lane: &PciLane) {
+        // Live tags by the recorded requester and tag of the requests of the bridge.
+        let mut tags = HashMap::new();
+
+        for (index, (direction, recorded)) in self.trace.tlps.iter().enumerate() {
+            match direction {
+                Direction::Downstream => {
+                    let tlp = match lane.rx.recv() {
+                        Ok(tlp) => tlp,
+                        Err(_) => return,
+                    };
+                    if !same_tlp(recorded, &tlp) {
+                        error!(
+                            "Replay diverged at TLP {}: expected {:?}, got {:?}",
+                            index, recorded.header._type, tlp.header._type
+                        );
+                        return;
+                    }
+                    if let (Some(from), Some(to)) = (id_of(recorded), id_of(&tlp)) {
+                        tags.insert(from, to.1);
+                    }
+                }
+                Direction::Upstream => {
+                    let mut tlp = recorded.clone();
+                    if is_completion(&tlp) {
+                        if let Some(tag) = id_of(&tlp).and_then(|id| tags.remove(&id)) {
+                            tlp.header.set_tag(tag);
+                        }
+                    }
+                    if lane.tx.send(tlp).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn is_completion(tlp: &Tlp) -> bool {
+    use PacketType::*;
+
+    matches!(
+        tlp.header._type,
+        Completion(_) | CompletionData(_) | CompletionLocked(_) | CompletionLockedData(_)
+    )
+}
+
+/// Requester and tag of a request or completion.
+fn id_of(tlp: &Tlp) -> Option<(u16, u8)> {
+    use PacketType::*;
+
+    match tlp.header._type {
+        MemoryRead(e) | MemoryWrite(e) | IoRead(e) | IoWrite(e) => Some((e.requester, e.tag)),
+        MemoryRead64(e) | MemoryWrite64(e) => Some((e.requester, e.tag)),
+        Config0Read(e) | Config0Write(e) | Config1Read(e) | Config1Write(e) => {
+            Some((e.requester, e.tag))
+        }
+        Completion(e) | CompletionData(e) | CompletionLocked(e) | CompletionLockedData(e) => {
+            Some((e.requester, e.tag))
+        }
+        _ => None,
+    }
+}
+
+/// Whether two TLPs are the same but for their tags.
+fn same_tlp(a: &Tlp, b: &Tlp) -> bool {
+    let (mut a, mut b) = (a.clone(), b.clone());
+    a.header.set_tag(0);
+    b.header.set_tag(0);
+
+    match (a.to_bytes(), b.to_bytes()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(adapter: &PciAdapter) -> Vec<u32> {
+        adapter.config_write(1, 0, &[0x6]);
+        (0..4).map(|reg| adapter.config_read(reg)).collect()
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let path =
+            std::env::temp_dir().join(format!("pcie-tlp-replay-{}.pcapng", std::process::id()));
+
+        let adapter = PciAdapter::start(Box::new(PciTestDevice::new()));
+        adapter.add_tlp_observer(Box::new(PcapngCapture::create(&path).unwrap()));
+        let recorded = exercise(&adapter);
+        adapter.stop();
+        adapter.join();
+
+        let trace = Trace::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(trace.tlps.len(), 10);
+
+        // The trace stands in for the device...
+        let device = ReplayDevice::new(trace.clone(), PciTestDevice::new().features());
+        let adapter = PciAdapter::start(Box::new(device));
+        assert_eq!(exercise(&adapter), recorded);
+        adapter.stop();
+        adapter.join();
+
+        // ...and for the bridge.
+        let timeout = Duration::from_secs(1);
+        trace
+            .replay_host(Box::new(PciTestDevice::new()), timeout)
+            .unwrap();
+
+        let mut diverged = trace.clone();
+        if let Some(data) = diverged.tlps[3].1.data.as_mut() {
+            data[0] ^= 1;
+        }
+        assert!(matches!(
+            diverged.replay_host(Box::new(PciTestDevice::new()), timeout),
+            Err(ReplayError::Mismatch { index: 3, .. })
+        ));
+    }
+}
@@ -0,0 +1,90 @@
+//! Reply channels of the requests of an adapter, reused from one request to the next.
+//!
+//! Every request of [`PciAdapter`] waits for its answer on a channel of its own. Creating the
+//! channel costs allocations on the path of every VCPU exit, so the channels of the answered
+//! requests are kept for the next requests of the same type. A channel whose request is still
+//! in flight is never reused, as its answer may still arrive.
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Channels kept by type of the answer, beyond that they are freed.
+const MAX_FREE: usize = 64;
+
+/// Both ends of a reply channel, which carries a single answer at a time.
+pub(crate) struct Reply<T> {
+    pub tx: Sender<T>,
+    pub rx: Receiver<T>,
+}
+
+impl<T> Reply<T> {
+    pub fn new() -> Self {
+        let (tx, rx) = bounded(1);
+        Reply { tx, rx }
+    }
+}
+
+/// Free reply channels by the type of their answer.
+#[derive(Default)]
+pub(crate) struct ReplyPool {
+    free: Mutex<HashMap<TypeId, Vec<Box<dyn Any + Send>>>>,
+}
+
+impl ReplyPool {
+    /// Take a free channel, or create one if there is none.
+    pub fn take<T: Send + 'static>(&self) -> Box<Reply<T>> {
+        let reply = self
+            .free
+            .lock()
+            .unwrap()
+            .get_mut(&TypeId::of::<T>())
+            .and_then(Vec::pop);
+
+        match reply.map(|reply| reply.downcast::<Reply<T>>()) {
+            Some(Ok(reply)) => reply,
+            _ => Box::new(Reply::new()),
+        }
+    }
+
+    /// Give back a channel whose answer is taken.
+    pub fn put<T: Send + 'static>(&self, reply: Box<Reply<T>>) {
+        if !reply.rx.is_empty() {
+            return;
+        }
+
+        let mut free = self.free.lock().unwrap();
+        let list = free.entry(TypeId::of::<T>()).or_default();
+        if list.len() < MAX_FREE {
+            list.push(reply);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = ReplyPool::default();
+        let reply = pool.take::<u32>();
+        reply.tx.send(1).unwrap();
+        assert_eq!(reply.rx.recv(), Ok(1));
+        let ptr = &*reply as *const Reply<u32>;
+        pool.put(reply);
+
+        // The channel comes back for the same type only.
+        let other = pool.take::<u64>();
+        let reply = pool.take::<u32>();
+        assert_eq!(&*reply as *const Reply<u32>, ptr);
+        assert!(reply.rx.is_empty());
+        pool.put(other);
+
+        // A channel holding an answer is not reused.
+        reply.tx.send(2).unwrap();
+        pool.put(reply);
+        assert!(pool.free.lock().unwrap()[&TypeId::of::<u32>()].is_empty());
+    }
+}
@@ -0,0 +1,723 @@
+//! Firmware-style enumeration of the simulated hierarchy.
+//!
+//! [`RootComplex::enumerate`] walks a segment of [`PciSegments`] the way the firmware of a
+//! platform does before the OS boots, so a VMM needs no probe logic of its own against raw
+//! config accesses. It scans the buses from the first bus of the segment on, gives each bridge
+//! the next free bus number as its secondary bus and scans the bus behind it, depth first. A
+//! function thereby sits behind a bridge when it is attached at the bus the bridge gets.
+//!
+//! Once the hierarchy is known the BARs are sized and placed: the BARs of the functions on the
+//! first bus come from the [`AddressAllocator`] directly, each bridge on it gets its windows
+//! from the allocator in one piece and the BARs and windows behind it are packed into them.
+//! 32-bit memory BARs go to the memory window, 64-bit ones to the prefetchable window, which is
+//! the only one above 4 GiB, and IO BARs to the IO window. The BARs of the bridges themselves
+//! are left unassigned. The result is a [`Topology`] of the functions found.
+
+use crate::*;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Range, RangeInclusive};
+use std::sync::{Arc, Mutex};
+
+const HEADER_TYPE_REG: usize = 3;
+const HEADER_TYPE_MULTI_FUNCTION: u32 = 0x80 << 16;
+/// Header type of a PCI-to-PCI bridge.
+const HEADER_TYPE_BRIDGE: u32 = 0x01;
+/// Primary, secondary and subordinate bus number of a bridge.
+const BUS_NUMBERS_REG: usize = 6;
+const IO_WINDOW_REG: usize = 7;
+const MEMORY_WINDOW_REG: usize = 8;
+const PREFETCH_WINDOW_REG: usize = 9;
+const PREFETCH_BASE_UPPER_REG: usize = 10;
+const PREFETCH_LIMIT_UPPER_REG: usize = 11;
+/// Granularity of the memory windows of a bridge.
+const MEMORY_WINDOW_ALIGN: u64 = 0x10_0000;
+/// Granularity of the IO window of a bridge.
+const IO_WINDOW_ALIGN: u64 = 0x1000;
+
+/// Why [`RootComplex::enumerate`] gave up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnumerationError {
+    /// The segment is not added to the [`PciSegments`].
+    InvalidSegment(u16),
+    /// The ECAM window of the segment has no bus left for the secondary bus of the bridge.
+    OutOfBuses(PciAddress),
+    /// The allocator has no room for a BAR of the function or a window of the bridge, of the
+    /// given length.
+    OutOfSpace(PciAddress, GuestUsize),
+    /// A config write to the function failed.
+    Config(PciAddress, PciAdapterError),
+}
+
+impl fmt::Display for EnumerationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use EnumerationError::*;
+
+        match self {
+            InvalidSegment(segment) => write!(f, "invalid segment {:#x}", segment),
+            OutOfBuses(address) => write!(f, "no bus left behind {}", address),
+            OutOfSpace(address, length) => {
+                write!(f, "no room for {:#x} bytes of {}", length, address)
+            }
+            Config(address, e) => write!(f, "config write to {} failed: {}", address, e),
+        }
+    }
+}
+
+impl std::error::Error for EnumerationError {}
+
+/// The windows of a bridge, the ranges of addresses it forwards to its secondary bus.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BridgeWindows {
+    pub io: Option<Range<u64>>,
+    pub memory: Option<Range<u64>>,
+    pub prefetchable: Option<Range<u64>>,
+}
+
+/// What [`RootComplex::enumerate`] made of a function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FunctionKind {
+    /// An endpoint with its BARs as placed.
+    Endpoint(Vec<(GuestAddress, GuestUsize, PciBarRegionType)>),
+    Bridge {
+        secondary: u8,
+        subordinate: u8,
+        windows: BridgeWindows,
+    },
+}
+
+/// A function found by [`RootComplex::enumerate`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopologyFunction {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// Class code, subclass and programming interface.
+    pub class: u32,
+    pub kind: FunctionKind,
+}
+
+/// The functions of a segment in the order of the enumeration, each bridge followed by the
+/// functions behind it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topology {
+    pub segment: u16,
+    pub functions: Vec<TopologyFunction>,
+}
+
+impl Topology {
+    pub fn function(&self, address: PciAddress) -> Option<&TopologyFunction> {
+        self.functions.iter().find(|f| f.address == address)
+    }
+
+    /// The functions on the secondary bus of the bridge at `bridge`, or on the first bus of the
+    /// segment for `None`.
+    pub fn children(&self, bridge: Option<PciAddress>) -> Vec<&TopologyFunction> {
+        let bus = match bridge.and_then(|bridge| self.function(bridge)) {
+            Some(TopologyFunction {
+                kind: FunctionKind::Bridge { secondary, .. },
+                ..
+            }) => *secondary,
+            Some(_) => return vec![],
+            None => match self.functions.first() {
+                Some(first) => first.address.bus(),
+                None => return vec![],
+            },
+        };
+        self.functions
+            .iter()
+            .filter(|f| f.address.bus() == bus)
+            .collect()
+    }
+}
+
+/// The three windows of a bridge.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WindowKind {
+    Io,
+    Memory,
+    Prefetchable,
+}
+
+const WINDOW_KINDS: [WindowKind; 3] =
+    [WindowKind::Io, WindowKind::Memory, WindowKind::Prefetchable];
+
+impl WindowKind {
+    fn of(type_: PciBarRegionType) -> Self {
+        match type_ {
+            PciBarRegionType::IoRegion => WindowKind::Io,
+            PciBarRegionType::Memory32BitRegion => WindowKind::Memory,
+            PciBarRegionType::Memory64BitRegion => WindowKind::Prefetchable,
+        }
+    }
+
+    fn align(&self) -> u64 {
+        match self {
+            WindowKind::Io => IO_WINDOW_ALIGN,
+            _ => MEMORY_WINDOW_ALIGN,
+        }
+    }
+}
+
+/// A function found by the scan, with the BARs it asks for.
+struct Node {
+    address: PciAddress,
+    adapter: Arc<Mutex<PciAdapter>>,
+    id: u32,
+    class: u32,
+    kind: NodeKind,
+}
+
+enum NodeKind {
+    Endpoint(Vec<MmioRegion>),
+    Bridge {
+        secondary: u8,
+        subordinate: u8,
+        children: Vec<Node>,
+        /// Length and alignment of each window, `None` for a closed one.
+        needs: [Option<(u64, u64)>; 3],
+    },
+}
+
+/// What is packed into a window of a bridge: the BAR of a child endpoint by its register or
+/// the window of a child bridge, the child by index.
+#[derive(Debug, Clone, Copy)]
+enum Item {
+    Bar(usize, usize),
+    Window(usize),
+}
+
+/// Where the BARs and windows found by the scan are placed.
+#[derive(Default)]
+struct Layout {
+    bars: HashMap<(PciAddress, usize), GuestAddress>,
+    windows: HashMap<PciAddress, BridgeWindows>,
+}
+
+/// Enumerates the segments of a [`PciSegments`] like the firmware of a platform.
+pub struct RootComplex<'a> {
+    segments: &'a PciSegments,
+}
+
+impl<'a> RootComplex<'a> {
+    pub fn new(segments: &'a PciSegments) -> Self {
+        RootComplex { segments }
+    }
+
+    /// Enumerate `segment`: assign the bus numbers of the bridges, place the BARs and the
+    /// windows of the bridges with `allocator` and route the INTx pins of the endpoints to its
+    /// GSIs.
+    pub fn enumerate(
+        &self,
+        segment: u16,
+        allocator: &mut dyn AddressAllocator,
+    ) -> std::result::Result<Topology, EnumerationError> {
+        let buses = self
+            .segments
+            .buses(segment)
+            .ok_or(EnumerationError::InvalidSegment(segment))?;
+        let mut next_bus = *buses.start() as u16 + 1;
+        let nodes = self.scan(segment, *buses.start(), &buses, &mut next_bus)?;
+
+        let mut layout = Layout::default();
+        for node in nodes.iter() {
+            if let NodeKind::Bridge { needs, .. } = &node.kind {
+                let mut bases = [None; 3];
+                for (kind, need) in WINDOW_KINDS.iter().zip(needs.iter()) {
+                    if let Some((length, align)) = *need {
+                        let base = match kind {
+                            WindowKind::Io => allocator.allocate_io(length, align),
+                            WindowKind::Memory => allocator.allocate_mmio_hole(length, align),
+                            WindowKind::Prefetchable => allocator.allocate_mmio(length, align),
+                        };
+                        let base =
+                            base.ok_or(EnumerationError::OutOfSpace(node.address, length))?;
+                        bases[*kind as usize] = Some(base.raw_value());
+                    }
+                }
+                Self::lay_out(node, bases, &mut layout);
+            }
+        }
+
+        let mut functions = vec![];
+        for node in nodes.iter() {
+            Self::program(node, &layout, allocator, &mut functions)?;
+        }
+        Ok(Topology { segment, functions })
+    }
+
+    /// Probe the functions on `bus` and, depth first, behind the bridges among them.
+    fn scan(
+        &self,
+        segment: u16,
+        bus: u8,
+        buses: &RangeInclusive<u8>,
+        next_bus: &mut u16,
+    ) -> std::result::Result<Vec<Node>, EnumerationError> {
+        let mut nodes = vec![];
+        for device in 0..32 {
+            for function in 0..8 {
+                let address = PciAddress::new(segment, bus, device, function);
+                let adapter = match self.segments.adapter(address) {
+                    Some(adapter) => adapter,
+                    None if function == 0 => break,
+                    None => continue,
+                };
+
+                let (id, class, header) = {
+                    let adapter = adapter.lock().unwrap();
+                    (
+                        adapter.guest_config_read(0),
+                        adapter.guest_config_read(2) >> 8,
+                        adapter.guest_config_read(HEADER_TYPE_REG),
+                    )
+                };
+                if id & 0xffff == 0xffff {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+
+                let kind = if (header >> 16) & 0x7f == HEADER_TYPE_BRIDGE {
+                    if *next_bus > *buses.end() as u16 {
+                        return Err(EnumerationError::OutOfBuses(address));
+                    }
+                    let secondary = *next_bus as u8;
+                    *next_bus += 1;
+                    let children = self.scan(segment, secondary, buses, next_bus)?;
+                    let needs = [
+                        Self::need(&children, WindowKind::Io),
+                        Self::need(&children, WindowKind::Memory),
+                        Self::need(&children, WindowKind::Prefetchable),
+                    ];
+                    NodeKind::Bridge {
+                        secondary,
+                        subordinate: (*next_bus - 1) as u8,
+                        children,
+                        needs,
+                    }
+                } else {
+                    let mut regions = adapter.lock().unwrap().scan_bar();
+                    // Only x86 has port I/O, elsewhere the IO BARs stay unassigned.
+                    regions.retain(|r| {
+                        cfg!(target_arch = "x86_64") || r.type_ != PciBarRegionType::IoRegion
+                    });
+                    NodeKind::Endpoint(regions)
+                };
+
+                nodes.push(Node {
+                    address,
+                    adapter,
+                    id,
+                    class,
+                    kind,
+                });
+                if function == 0 && header & HEADER_TYPE_MULTI_FUNCTION == 0 {
+                    break;
+                }
+            }
+        }
+        Ok(nodes)
+    }
+
+    /// The BARs and windows of `children` of a kind of window, as the key, length and
+    /// alignment of each, largest alignment first as they are packed.
+    fn items(children: &[Node], kind: WindowKind) -> Vec<(Item, u64, u64)> {
+        let mut items = vec![];
+        for (index, child) in children.iter().enumerate() {
+            match &child.kind {
+                NodeKind::Endpoint(regions) => items.extend(
+                    regions
+                        .iter()
+                        .filter(|r| WindowKind::of(r.type_) == kind)
+                        .map(|r| (Item::Bar(index, r.bar_reg), r.length, r.length)),
+                ),
+                NodeKind::Bridge { needs, .. } => {
+                    if let Some((length, align)) = needs[kind as usize] {
+                        items.push((Item::Window(index), length, align));
+                    }
+                }
+            }
+        }
+        items.sort_by_key(|&(_, _, align)| std::cmp::Reverse(align));
+        items
+    }
+
+    /// Length and alignment of the window of a bridge with `children` behind it.
+    fn need(children: &[Node], kind: WindowKind) -> Option<(u64, u64)> {
+        let items = Self::items(children, kind);
+        let align = items.first()?.2.max(kind.align());
+        let mut end: u64 = 0;
+        for (_, length, align) in items {
+            end = end.div_ceil(align) * align + length;
+        }
+        Some((end.div_ceil(kind.align()) * kind.align(), align))
+    }
+
+    /// Place the windows of the bridge `node` at `bases` and pack what is behind them.
+    fn lay_out(node: &Node, bases: [Option<u64>; 3], layout: &mut Layout) {
+        let (children, needs) = match &node.kind {
+            NodeKind::Bridge {
+                children, needs, ..
+            } => (children, needs),
+            NodeKind::Endpoint(_) => return,
+        };
+
+        let mut windows = BridgeWindows::default();
+        let mut child_bases = vec![[None; 3]; children.len()];
+        for kind in WINDOW_KINDS.iter() {
+            let (base, length) = match (bases[*kind as usize], needs[*kind as usize]) {
+                (Some(base), Some((length, _))) => (base, length),
+                _ => continue,
+            };
+            let window = Some(base..base + length);
+            match kind {
+                WindowKind::Io => windows.io = window,
+                WindowKind::Memory => windows.memory = window,
+                WindowKind::Prefetchable => windows.prefetchable = window,
+            }
+
+            let mut next = base;
+            for (item, length, align) in Self::items(children, *kind) {
+                let start = next.div_ceil(align) * align;
+                next = start + length;
+                match item {
+                    Item::Bar(index, reg) => {
+                        let address = children[index].address;
+                        layout.bars.insert((address, reg), GuestAddress(start));
+                    }
+                    Item::Window(index) => child_bases[index][*kind as usize] = Some(start),
+                }
+            }
+        }
+
+        layout.windows.insert(node.address, windows);
+        for (child, bases) in children.iter().zip(child_bases) {
+            Self::lay_out(child, bases, layout);
+        }
+    }
+
+    /// Write the bus numbers and windows of the bridges and the BARs of the endpoints, as
+    /// laid out. The BARs of the functions on the first bus, which are not laid out, come
+    /// from `allocator`.
+    fn program(
+        node: &Node,
+        layout: &Layout,
+        allocator: &mut dyn AddressAllocator,
+        functions: &mut Vec<TopologyFunction>,
+    ) -> std::result::Result<(), EnumerationError> {
+        let mut adapter = node.adapter.lock().unwrap();
+        let kind = match &node.kind {
+            NodeKind::Endpoint(_) => {
+                let regions = adapter
+                    .place_bars(
+                        |region| match layout.bars.get(&(node.address, region.bar_reg)) {
+                            Some(&start) => Some(start),
+                            None => match region.type_ {
+                                PciBarRegionType::Memory64BitRegion => {
+                                    allocator.allocate_mmio(region.length, region.length)
+                                }
+                                PciBarRegionType::Memory32BitRegion => {
+                                    allocator.allocate_mmio_hole(region.length, region.length)
+                                }
+                                PciBarRegionType::IoRegion => {
+                                    allocator.allocate_io(region.length, region.length)
+                                }
+                            },
+                        },
+                    )
+                    .map_err(|length| EnumerationError::OutOfSpace(node.address, length))?;
+                adapter.route_intx(allocator);
+                FunctionKind::Endpoint(
+                    regions
+                        .iter()
+                        .map(|r| (r.start, r.length, r.type_))
+                        .collect(),
+                )
+            }
+            NodeKind::Bridge {
+                secondary,
+                subordinate,
+                ..
+            } => {
+                let windows = layout
+                    .windows
+                    .get(&node.address)
+                    .cloned()
+                    .unwrap_or_default();
+                let primary = node.address.bus() as u32;
+                let latency = adapter.guest_config_read(BUS_NUMBERS_REG) & 0xff00_0000;
+                let regs = [
+                    (
+                        BUS_NUMBERS_REG,
+                        latency | (*subordinate as u32) << 16 | (*secondary as u32) << 8 | primary,
+                    ),
+                    (IO_WINDOW_REG, Self::encode_io(&windows.io)),
+                    (MEMORY_WINDOW_REG, Self::encode_memory(&windows.memory)),
+                    (
+                        PREFETCH_WINDOW_REG,
+                        Self::encode_memory(&windows.prefetchable),
+                    ),
+                    (
+                        PREFETCH_BASE_UPPER_REG,
+                        windows
+                            .prefetchable
+                            .as_ref()
+                            .map_or(0, |w| (w.start >> 32) as u32),
+                    ),
+                    (
+                        PREFETCH_LIMIT_UPPER_REG,
+                        windows
+                            .prefetchable
+                            .as_ref()
+                            .map_or(0, |w| ((w.end - 1) >> 32) as u32),
+                    ),
+                ];
+                for (reg, value) in regs.iter() {
+                    adapter
+                        .try_config_write(*reg, 0, &value.to_le_bytes())
+                        .map_err(|e| EnumerationError::Config(node.address, e))?;
+                }
+
+                FunctionKind::Bridge {
+                    secondary: *secondary,
+                    subordinate: *subordinate,
+                    windows,
+                }
+            }
+        };
+        drop(adapter);
+
+        functions.push(TopologyFunction {
+            address: node.address,
+            vendor_id: node.id as u16,
+            device_id: (node.id >> 16) as u16,
+            class: node.class,
+            kind,
+        });
+        if let NodeKind::Bridge { children, .. } = &node.kind {
+            for child in children.iter() {
+                Self::program(child, layout, allocator, functions)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The I/O Base and I/O Limit registers of a window, a base above the limit closes it.
+    fn encode_io(window: &Option<Range<u64>>) -> u32 {
+        match window {
+            Some(window) => ((window.start >> 8) & 0xf0 | (window.end - 1) & 0xf000) as u32,
+            None => 0xf0,
+        }
+    }
+
+    /// The Memory Base and Memory Limit registers of a window, a base above the limit closes
+    /// it. The prefetchable ones take the upper 32 bits in registers of their own.
+    fn encode_memory(window: &Option<Range<u64>>) -> u32 {
+        match window {
+            Some(window) => {
+                ((window.start >> 16) & 0xfff0 | ((window.end - 1) & 0xfff0_0000)) as u32
+            }
+            None => 0xfff0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pci::PciSubclass;
+
+    /// Subclass of a PCI-to-PCI bridge.
+    struct PciToPciBridge;
+
+    impl PciSubclass for PciToPciBridge {
+        fn get_register_value(&self) -> u8 {
+            0x04
+        }
+    }
+
+    /// A bridge model whose bus number and window registers hold what the firmware writes.
+    fn bridge() -> ConfigSpace {
+        let pci = PciConfiguration::new(
+            0x1234,
+            0x0b00,
+            0,
+            PciClassCode::BridgeDevice,
+            &PciToPciBridge,
+            None,
+            PciHeaderType::Bridge,
+            0,
+            0,
+            None,
+        );
+        let mut config = ConfigSpace::new(PcieConfiguration::new(pci));
+        config.emulate(BUS_NUMBERS_REG, 0, 0x00ff_ffff);
+        config.emulate(IO_WINDOW_REG, 0, 0xf0f0);
+        config.emulate(MEMORY_WINDOW_REG, 0, 0xfff0_fff0);
+        config.emulate(PREFETCH_WINDOW_REG, 0, 0xfff0_fff0);
+        config.emulate(PREFETCH_BASE_UPPER_REG, 0, u32::MAX);
+        config.emulate(PREFETCH_LIMIT_UPPER_REG, 0, u32::MAX);
+        config
+    }
+
+    /// Hands out the ranges one after the other.
+    struct Ascending([u64; 3]);
+
+    impl Ascending {
+        fn next(&mut self, space: usize, size: u64, align: u64) -> Option<GuestAddress> {
+            let start = self.0[space].div_ceil(align) * align;
+            self.0[space] = start + size;
+            Some(GuestAddress(start))
+        }
+    }
+
+    impl AddressAllocator for Ascending {
+        fn allocate_mmio(&mut self, size: GuestUsize, align: GuestUsize) -> Option<GuestAddress> {
+            self.next(0, size, align)
+        }
+
+        fn free_mmio(&mut self, _: GuestAddress, _: GuestUsize) {}
+
+        fn allocate_mmio_hole(
+            &mut self,
+            size: GuestUsize,
+            align: GuestUsize,
+        ) -> Option<GuestAddress> {
+            self.next(1, size, align)
+        }
+
+        fn free_mmio_hole(&mut self, _: GuestAddress, _: GuestUsize) {}
+
+        fn allocate_io(&mut self, size: GuestUsize, align: GuestUsize) -> Option<GuestAddress> {
+            self.next(2, size, align)
+        }
+
+        fn free_io(&mut self, _: GuestAddress, _: GuestUsize) {}
+    }
+
+    #[test]
+    fn enumerate() {
+        let mut segments = PciSegments::new();
+        segments.add_segment(0, 0xe000_0000, 0..=0xff).unwrap();
+
+        // An endpoint and a bridge on bus 0, behind it an endpoint and another bridge with an
+        // endpoint behind it. The endpoint on bus 7 is behind no bridge.
+        let mut adapters = vec![];
+        let layout: [(u8, u8, bool); 6] = [
+            (0, 1, false),
+            (0, 2, true),
+            (1, 0, false),
+            (1, 1, true),
+            (2, 0, false),
+            (7, 0, false),
+        ];
+        for &(bus, device, is_bridge) in layout.iter() {
+            let builder = PciAdapterBuilder::new().completer(bus, device, 0);
+            let adapter = if is_bridge {
+                builder.start(Box::new(bridge()))
+            } else {
+                builder.start(Box::new(PciTestDevice::new()))
+            };
+            let adapter = Arc::new(Mutex::new(adapter));
+            segments.attach(adapter.clone()).unwrap();
+            adapters.push(adapter);
+        }
+
+        let mut allocator = Ascending([0x40_0000_0000, 0xc000_0000, 0x1000]);
+        let topology = RootComplex::new(&segments)
+            .enumerate(0, &mut allocator)
+            .unwrap();
+        let addresses: Vec<_> = topology.functions.iter().map(|f| f.address).collect();
+        assert_eq!(
+            addresses,
+            vec![
+                PciAddress::new(0, 0, 1, 0),
+                PciAddress::new(0, 0, 2, 0),
+                PciAddress::new(0, 1, 0, 0),
+                PciAddress::new(0, 1, 1, 0),
+                PciAddress::new(0, 2, 0, 0),
+            ]
+        );
+        assert_eq!(
+            topology.children(Some(PciAddress::new(0, 0, 2, 0))).len(),
+            2
+        );
+        let function = topology.function(PciAddress::new(0, 0, 2, 0)).unwrap();
+        assert_eq!((function.device_id, function.class), (0x0b00, 0x06_0400));
+
+        let windows = |bus, device| match &topology
+            .function(PciAddress::new(0, bus, device, 0))
+            .unwrap()
+            .kind
+        {
+            FunctionKind::Bridge {
+                secondary,
+                subordinate,
+                windows,
+            } => (*secondary, *subordinate, windows.clone()),
+            kind => panic!("no bridge: {:?}", kind),
+        };
+        let bars = |bus, device| match &topology
+            .function(PciAddress::new(0, bus, device, 0))
+            .unwrap()
+            .kind
+        {
+            FunctionKind::Endpoint(bars) => bars.clone(),
+            kind => panic!("no endpoint: {:?}", kind),
+        };
+        let (secondary, subordinate, outer) = windows(0, 2);
+        assert_eq!((secondary, subordinate), (1, 2));
+        let (secondary, subordinate, inner) = windows(1, 1);
+        assert_eq!((secondary, subordinate), (2, 2));
+
+        // Every BAR behind a bridge is in the window of its kind, of each bridge on the way.
+        let window_of = |windows: &BridgeWindows, type_| match type_ {
+            PciBarRegionType::IoRegion => windows.io.clone(),
+            PciBarRegionType::Memory32BitRegion => windows.memory.clone(),
+            PciBarRegionType::Memory64BitRegion => windows.prefetchable.clone(),
+        };
+        for (bus, bridges) in [(1, vec![&outer]), (2, vec![&outer, &inner])] {
+            for (start, length, type_) in bars(bus, 0) {
+                let range = start.raw_value()..start.raw_value() + length;
+                for windows in bridges.iter() {
+                    let window = window_of(windows, type_).unwrap();
+                    assert!(window.start <= range.start && range.end <= window.end);
+                }
+            }
+        }
+        let prefetchable = outer.prefetchable.clone().unwrap();
+        assert_eq!(prefetchable.start % MEMORY_WINDOW_ALIGN, 0);
+        assert!(prefetchable.start >= 0x40_0000_0000);
+        for (start, _, _) in bars(0, 1) {
+            assert!(window_of(&outer, PciBarRegionType::Memory32BitRegion)
+                .into_iter()
+                .chain(outer.prefetchable.clone())
+                .all(|window| !window.contains(&start.raw_value())));
+        }
+
+        // The registers of the bridges tell the same.
+        let register =
+            |index: usize, reg| adapters[index].lock().unwrap().read_config_register(reg);
+        assert_eq!(register(1, BUS_NUMBERS_REG), 0x02_01_00);
+        assert_eq!(register(3, BUS_NUMBERS_REG), 0x02_02_01);
+        assert_eq!(
+            register(1, MEMORY_WINDOW_REG),
+            RootComplex::encode_memory(&outer.memory)
+        );
+        assert_eq!(
+            register(1, PREFETCH_BASE_UPPER_REG),
+            (prefetchable.start >> 32) as u32
+        );
+
+        drop(segments);
+        for adapter in adapters {
+            let adapter = Arc::try_unwrap(adapter).ok().unwrap().into_inner().unwrap();
+            adapter.stop();
+            adapter.join();
+        }
+    }
+}
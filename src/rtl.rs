@@ -0,0 +1,369 @@
+//! Device models simulated at the register transfer level, e.g. a PCIe endpoint IP in
+//! Verilator.
+//!
+//! The TLP interface of an endpoint IP moves a TLP in beats of a few DWs, like an AXI4-Stream
+//! interface: DW `i` of a beat is bits `32 * i + 31 : 32 * i` of its data, bit `i` of the keep
+//! mask says whether the DW is valid and the last beat of a TLP has `last` set. A DW holds 4
+//! bytes of the wire format of the TLP with the first one in bits 31:24, the way the header
+//! fields are drawn in the specification. [`Beat::split`] and [`BeatAssembler`] convert TLPs
+//! to and from beats.
+//!
+//! The simulator exchanges the beats with the bridge either way:
+//!
+//! - through a pair of FIFOs with [`RtlFifoDevice`]: the testbench reads the beats to the
+//!   endpoint from one FIFO and writes those of the endpoint to the other, as lines of text
+//!   read and written with `$fscanf(fd, "%h %h %h\n", tdata, tkeep, tlast)`, see
+//!   [`Beat::to_line`],
+//! - through DPI-C with the `dpi` feature, see the `dpi` module.
+
+use crate::*;
+
+use crossbeam_channel::{bounded, select, Receiver, Sender};
+use std::ffi::CString;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// The widest beat, which the keep mask covers.
+pub const MAX_BEAT_DWS: usize = 32;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A beat of the TLP interface of an endpoint, see [`crate::rtl`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Beat {
+    /// The DWs of the beat, as wide as the interface.
+    pub data: Vec<u32>,
+    /// Bit `i` is set if DW `i` is valid.
+    pub keep: u32,
+    /// Whether the beat ends the TLP.
+    pub last: bool,
+}
+
+impl Beat {
+    /// Split `tlp` into beats of `width` DWs, 1 to [`MAX_BEAT_DWS`].
+    pub fn split(tlp: &Tlp, width: usize) -> Result<Vec<Beat>, CodecError> {
+        assert!((1..=MAX_BEAT_DWS).contains(&width));
+        let dws: Vec<u32> = tlp
+            .to_bytes()?
+            .chunks(4)
+            .map(|dw| u32::from_be_bytes([dw[0], dw[1], dw[2], dw[3]]))
+            .collect();
+
+        let beats = dws.len().div_ceil(width);
+        Ok(dws
+            .chunks(width)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut data = chunk.to_vec();
+                data.resize(width, 0);
+                Beat {
+                    data,
+                    keep: ((1u64 << chunk.len()) - 1) as u32,
+                    last: i + 1 == beats,
+                }
+            })
+            .collect())
+    }
+
+    /// The beat as a line of text: the data in hex with the last DW first, the keep mask in
+    /// hex and `last` as 0 or 1, separated by spaces.
+    pub fn to_line(&self) -> String {
+        let mut line = String::with_capacity(self.data.len() * 8 + 16);
+        for dw in self.data.iter().rev() {
+            let _ = write!(line, "{:08x}", dw);
+        }
+        let _ = write!(line, " {:x} {}", self.keep, self.last as u8);
+        line
+    }
+
+    /// Parse a line of [`Beat::to_line`] of a beat of `width` DWs, the leading zeros of the
+    /// data may be missing.
+    pub fn from_line(line: &str, width: usize) -> io::Result<Beat> {
+        let mut fields = line.split_whitespace();
+        let (data, keep, last) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(data), Some(keep), Some(last)) => (data, keep, last),
+            _ => return Err(invalid("incomplete beat")),
+        };
+        let hex = |s: &str| u32::from_str_radix(s, 16).map_err(|_| invalid("bad beat hex"));
+
+        let excess = data.len().saturating_sub(width * 8);
+        if !data.is_ascii() || data[..excess].bytes().any(|d| d != b'0') {
+            return Err(invalid("beat wider than the interface"));
+        }
+        let mut dws = vec![0u32; width];
+        for (i, dw) in dws.iter_mut().enumerate() {
+            let end = data.len().saturating_sub(i * 8);
+            let start = end.saturating_sub(8);
+            if start == end {
+                break;
+            }
+            *dw = hex(&data[start..end])?;
+        }
+
+        Ok(Beat {
+            data: dws,
+            keep: hex(keep)?,
+            last: match last {
+                "0" => false,
+                "1" => true,
+                _ => return Err(invalid("bad beat last")),
+            },
+        })
+    }
+}
+
+/// Collects the beats of an endpoint into TLPs.
+#[derive(Debug, Default)]
+pub struct BeatAssembler {
+    dws: Vec<u32>,
+}
+
+impl BeatAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take the valid DWs of `beat`, returns the TLP `beat` ends, if it does.
+    pub fn push(&mut self, beat: &Beat) -> Option<Result<Tlp, CodecError>> {
+        let valid = beat
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i < MAX_BEAT_DWS && beat.keep >> i & 1 != 0);
+        self.dws.extend(valid.map(|(_, dw)| *dw));
+        if !beat.last {
+            return None;
+        }
+
+        let bytes: Vec<u8> = self.dws.drain(..).flat_map(|dw| dw.to_be_bytes()).collect();
+        Some(Tlp::from_bytes(&bytes))
+    }
+}
+
+/// Create the FIFO at `path` unless it exists.
+fn mkfifo(path: &Path) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Put the TLPs of the beats read from `fifo` on `tx` until the simulator closes the FIFO or
+/// the lane is closed. A malformed TLP is dropped.
+fn read_beats(fifo: File, width: usize, tx: Sender<Tlp>) {
+    let mut assembler = BeatAssembler::new();
+    for line in BufReader::new(fifo).lines() {
+        let beat = match line.and_then(|line| Beat::from_line(&line, width)) {
+            Ok(beat) => beat,
+            Err(e) => {
+                error!("Failed to read a beat from the simulator: {}", e);
+                break;
+            }
+        };
+        let tlp = match assembler.push(&beat) {
+            Some(Ok(tlp)) => tlp,
+            Some(Err(e)) => {
+                error!("Malformed TLP from the simulator: {:?}", e);
+                continue;
+            }
+            None => continue,
+        };
+        if tx.send(tlp).is_err() {
+            break;
+        }
+    }
+    debug!("Simulator closed its FIFO");
+}
+
+/// A device model simulated by an RTL simulator, which exchanges beats with the bridge
+/// through a pair of FIFOs, see [`crate::rtl`].
+pub struct RtlFifoDevice {
+    to_rtl: BufWriter<File>,
+    from_rtl: Option<File>,
+    width: usize,
+    /// Disconnected once the reader of the FIFO ends.
+    closed: Option<Receiver<()>>,
+}
+
+impl RtlFifoDevice {
+    /// Open the FIFO of the beats to the endpoint at `to_rtl` and the one of the beats of the
+    /// endpoint at `from_rtl`, creating those which do not exist, for an interface of `width`
+    /// DWs. Blocks until the simulator opened both, which it does in the same order.
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(
+        to_rtl: P,
+        from_rtl: Q,
+        width: usize,
+    ) -> io::Result<Self> {
+        if !(1..=MAX_BEAT_DWS).contains(&width) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unsupported beat width",
+            ));
+        }
+        mkfifo(to_rtl.as_ref())?;
+        mkfifo(from_rtl.as_ref())?;
+
+        let to_rtl = OpenOptions::new().write(true).open(to_rtl)?;
+        let from_rtl = File::open(from_rtl)?;
+        Ok(RtlFifoDevice {
+            to_rtl: BufWriter::new(to_rtl),
+            from_rtl: Some(from_rtl),
+            width,
+            closed: None,
+        })
+    }
+
+    /// Start the reader of the FIFO, which outlives the runs on the lanes of resets.
+    fn start_reader(&mut self, tx: &Sender<Tlp>) -> io::Result<Receiver<()>> {
+        let fifo = match self.from_rtl.take() {
+            Some(fifo) => fifo,
+            None => return Err(io::ErrorKind::NotConnected.into()),
+        };
+        let (width, tx) = (self.width, tx.clone());
+        let (closed_tx, closed) = bounded(0);
+        std::thread::Builder::new()
+            .name("tlp-rtl".to_string())
+            .spawn(move || {
+                let _closed = closed_tx;
+                read_beats(fifo, width, tx);
+            })?;
+        Ok(closed)
+    }
+
+    fn write_tlp(&mut self, tlp: &Tlp) -> io::Result<()> {
+        let beats = Beat::split(tlp, self.width).map_err(|e| invalid(&format!("{:?}", e)))?;
+        for beat in beats {
+            writeln!(self.to_rtl, "{}", beat.to_line())?;
+        }
+        self.to_rtl.flush()
+    }
+}
+
+impl PciSimDevice for RtlFifoDevice {
+    fn run(&mut self, lane: &PciLane) {
+        let closed = match self.closed.clone() {
+            Some(closed) => closed,
+            None => match self.start_reader(&lane.tx) {
+                Ok(closed) => self.closed.insert(closed).clone(),
+                Err(e) => {
+                    error!("Failed to start the simulator reader: {}", e);
+                    return;
+                }
+            },
+        };
+
+        loop {
+            select! {
+                recv(lane.rx) -> tlp => {
+                    let tlp = match tlp {
+                        Ok(tlp) => tlp,
+                        Err(_) => break,
+                    };
+                    if let Err(e) = self.write_tlp(&tlp) {
+                        error!("Failed to write {:?} to the simulator: {}", tlp.header._type, e);
+                        if e.kind() != io::ErrorKind::InvalidData {
+                            break;
+                        }
+                    }
+                },
+                recv(closed) -> _ => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::device::{dispatch, Ids};
+    use std::thread;
+
+    #[test]
+    fn beats() {
+        let tlp = TlpBuilder::memory_write64(Memory64Extra {
+            requester: 0x0100,
+            tag: 3,
+            addr: 0x1_0000_1000,
+        })
+        .byte_enable(0xf)
+        .data(vec![0x1111_1111, 0x2222_2222, 0x3333_3333])
+        .build();
+
+        // 4 DWs of header and 3 of data.
+        let beats = Beat::split(&tlp, 4).unwrap();
+        assert_eq!(beats.len(), 2);
+        assert_eq!((beats[0].keep, beats[0].last), (0xf, false));
+        assert_eq!((beats[1].keep, beats[1].last), (0x7, true));
+        assert_eq!(beats[1].data, [0x1111_1111, 0x2222_2222, 0x3333_3333, 0]);
+        assert_eq!(beats[1].to_line(), "00000000333333332222222211111111 7 1");
+
+        let mut assembler = BeatAssembler::new();
+        let line = beats[0].to_line();
+        assert_eq!(Beat::from_line(&line, 4).unwrap(), beats[0]);
+        assert!(assembler.push(&beats[0]).is_none());
+        // The testbench may print the data without its leading zeros.
+        let beat = Beat::from_line("333333332222222211111111 7 1", 4).unwrap();
+        assert_eq!(beat, beats[1]);
+        let tlp = tlp.to_bytes().unwrap();
+        let assembled = assembler.push(&beat).unwrap().unwrap();
+        assert_eq!(assembled.to_bytes().unwrap(), tlp);
+
+        assert!(Beat::from_line("1 00000000333333332222222211111111 7 1", 4).is_err());
+        assert!(Beat::from_line("0 1", 4).is_err());
+        assert!(Beat::from_line("0 1 2", 4).is_err());
+    }
+
+    #[test]
+    fn fifo_pair() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let to_rtl = dir.join(format!("pcie-tlp-{}.to-rtl", id));
+        let from_rtl = dir.join(format!("pcie-tlp-{}.from-rtl", id));
+        let _ = std::fs::remove_file(&to_rtl);
+        let _ = std::fs::remove_file(&from_rtl);
+        mkfifo(&to_rtl).unwrap();
+        mkfifo(&from_rtl).unwrap();
+
+        // The testbench answers config reads through an endpoint of 2 DWs.
+        let testbench = {
+            let (to_rtl, from_rtl) = (to_rtl.clone(), from_rtl.clone());
+            thread::spawn(move || {
+                let input = BufReader::new(File::open(&to_rtl).unwrap());
+                let mut output = OpenOptions::new().write(true).open(&from_rtl).unwrap();
+                let (endpoint, model) = PciLane::pair();
+                let mut assembler = BeatAssembler::new();
+                for line in input.lines() {
+                    let beat = Beat::from_line(&line.unwrap(), 2).unwrap();
+                    if let Some(tlp) = assembler.push(&beat) {
+                        dispatch(&mut Ids(0x5678_1234), &model, &tlp.unwrap(), &mut 0);
+                        let cpl = endpoint.rx.recv().unwrap();
+                        for beat in Beat::split(&cpl, 2).unwrap() {
+                            writeln!(output, "{}", beat.to_line()).unwrap();
+                        }
+                    }
+                }
+            })
+        };
+
+        let device = RtlFifoDevice::open(&to_rtl, &from_rtl, 2).unwrap();
+        let adapter = PciAdapter::start(Box::new(device));
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        assert_eq!(adapter.try_config_read(1), Ok(0));
+
+        adapter.stop();
+        adapter.join();
+        testbench.join().unwrap();
+        let _ = std::fs::remove_file(&to_rtl);
+        let _ = std::fs::remove_file(&from_rtl);
+    }
+}
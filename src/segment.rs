@@ -0,0 +1,342 @@
+//! PCI segments, also known as domains.
+//!
+//! A segment is a hierarchy of its own with 256 buses, so the same BDF exists once in every
+//! segment. The bridge of an adapter on segment `n`, see [`PciAdapterBuilder::segment`],
+//! qualifies the requester IDs it hands to the hypervisor hooks with the segment as a
+//! [`PciAddress`], e.g. the DeviceID of an MSI on aarch64 is the segment in the upper and the
+//! BDF in the lower half. Adapters on different segments thereby share a sink without mixing up
+//! their functions.
+//!
+//! Each segment has an ECAM window of its own, where the configuration space of a function sits
+//! at the offset given by its bus, device and function number. [`PciSegments`] routes the
+//! accesses to the windows to the adapters attached at the addresses.
+
+use crate::*;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Barrier, Mutex};
+
+/// Bytes of ECAM space of one bus.
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+/// The segment, bus, device and function number of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bdf: u16,
+}
+
+impl PciAddress {
+    pub fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            segment,
+            bdf: make_bdf(bus, device, function),
+        }
+    }
+
+    pub fn bus(&self) -> u8 {
+        (self.bdf >> 8) as u8
+    }
+
+    pub fn device(&self) -> u8 {
+        ((self.bdf >> 3) & 0x1f) as u8
+    }
+
+    pub fn function(&self) -> u8 {
+        (self.bdf & 0b111) as u8
+    }
+
+    /// The segment in the upper and the BDF in the lower half, which is unique across the
+    /// segments, e.g. the DeviceID of the ITS of aarch64.
+    pub fn devid(&self) -> u32 {
+        (self.segment as u32) << 16 | self.bdf as u32
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment,
+            self.bus(),
+            self.device(),
+            self.function()
+        )
+    }
+}
+
+/// Why [`PciSegments`] refused a segment or an adapter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EcamError {
+    /// The segment is not added, or is added already.
+    InvalidSegment(u16),
+    /// The ECAM window overlaps the window of the segment.
+    Overlap(u16),
+    /// The bus of the address is not decoded by the ECAM window of its segment.
+    BusOutOfRange(PciAddress),
+    /// Another adapter is attached at the address.
+    AddressInUse(PciAddress),
+}
+
+impl fmt::Display for EcamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use EcamError::*;
+
+        match self {
+            InvalidSegment(segment) => write!(f, "invalid segment {:#x}", segment),
+            Overlap(segment) => write!(f, "ECAM window overlaps segment {:#x}", segment),
+            BusOutOfRange(address) => write!(f, "bus of {} out of the ECAM window", address),
+            AddressInUse(address) => write!(f, "{} is in use", address),
+        }
+    }
+}
+
+impl std::error::Error for EcamError {}
+
+/// The ECAM window of a segment.
+#[derive(Debug, Clone)]
+struct Ecam {
+    /// Guest physical address of the configuration space of bus 0.
+    base: u64,
+    buses: RangeInclusive<u8>,
+}
+
+impl Ecam {
+    /// The guest physical addresses the window decodes.
+    fn window(&self) -> RangeInclusive<u64> {
+        self.base + *self.buses.start() as u64 * ECAM_BUS_SIZE
+            ..=self.base + (*self.buses.end() as u64 + 1) * ECAM_BUS_SIZE - 1
+    }
+}
+
+/// Routes the configuration accesses of the guest to the ECAM windows of the segments to the
+/// adapters attached at the addresses. Functions which are not attached read all 1s, as the
+/// root complex of a real platform answers them.
+#[derive(Default)]
+pub struct PciSegments {
+    ecams: BTreeMap<u16, Ecam>,
+    adapters: BTreeMap<PciAddress, Arc<Mutex<PciAdapter>>>,
+}
+
+impl PciSegments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `segment`, whose ECAM window decodes `buses` and puts bus 0 at `base`, as the MCFG
+    /// table of ACPI describes it.
+    pub fn add_segment(
+        &mut self,
+        segment: u16,
+        base: u64,
+        buses: RangeInclusive<u8>,
+    ) -> std::result::Result<(), EcamError> {
+        if self.ecams.contains_key(&segment) {
+            return Err(EcamError::InvalidSegment(segment));
+        }
+        let ecam = Ecam { base, buses };
+        let window = ecam.window();
+        for (&other, ecam) in self.ecams.iter() {
+            let other_window = ecam.window();
+            if window.start() <= other_window.end() && other_window.start() <= window.end() {
+                return Err(EcamError::Overlap(other));
+            }
+        }
+
+        debug!("add segment {:#x} with ECAM window {:#x?}", segment, window);
+        self.ecams.insert(segment, ecam);
+        Ok(())
+    }
+
+    /// Guest physical address of the ECAM window of `segment`, bus 0 included.
+    pub fn ecam_base(&self, segment: u16) -> Option<u64> {
+        self.ecams.get(&segment).map(|ecam| ecam.base)
+    }
+
+    /// The buses the ECAM window of `segment` decodes.
+    pub(crate) fn buses(&self, segment: u16) -> Option<RangeInclusive<u8>> {
+        self.ecams.get(&segment).map(|ecam| ecam.buses.clone())
+    }
+
+    /// Attach `adapter` at its address, see [`PciAdapter::address`]. Returns the address.
+    pub fn attach(
+        &mut self,
+        adapter: Arc<Mutex<PciAdapter>>,
+    ) -> std::result::Result<PciAddress, EcamError> {
+        let address = adapter.lock().unwrap().address();
+        let ecam = self
+            .ecams
+            .get(&address.segment)
+            .ok_or(EcamError::InvalidSegment(address.segment))?;
+        if !ecam.buses.contains(&address.bus()) {
+            return Err(EcamError::BusOutOfRange(address));
+        }
+        if self.adapters.contains_key(&address) {
+            return Err(EcamError::AddressInUse(address));
+        }
+
+        self.adapters.insert(address, adapter);
+        Ok(address)
+    }
+
+    /// Detach the adapter at `address`, if any.
+    pub fn detach(&mut self, address: PciAddress) -> Option<Arc<Mutex<PciAdapter>>> {
+        self.adapters.remove(&address)
+    }
+
+    pub fn adapter(&self, address: PciAddress) -> Option<Arc<Mutex<PciAdapter>>> {
+        self.adapters.get(&address).cloned()
+    }
+
+    /// The function and the offset in its configuration space a guest physical address of an
+    /// ECAM window stands for.
+    pub fn decode(&self, gpa: u64) -> Option<(PciAddress, u64)> {
+        let (&segment, ecam) = self
+            .ecams
+            .iter()
+            .find(|(_, ecam)| ecam.window().contains(&gpa))?;
+        let offset = gpa - ecam.base;
+        let address = PciAddress {
+            segment,
+            bdf: (offset >> 12) as u16,
+        };
+        Some((address, offset & 0xfff))
+    }
+
+    /// Read the ECAM space at `gpa`, false if no ECAM window decodes it.
+    pub fn read(&self, gpa: u64, data: &mut [u8]) -> bool {
+        let (address, offset) = match self.decode(gpa) {
+            Some(decoded) => decoded,
+            None => return false,
+        };
+
+        data.fill(0xff);
+        if let Some(adapter) = self.adapters.get(&address) {
+            if let Err(e) = adapter.lock().unwrap().try_ecam_read(offset, data) {
+                error!(
+                    "Failed to read config space of {} at {:#x}: {}",
+                    address, offset, e
+                );
+                data.fill(0xff);
+            }
+        }
+        true
+    }
+
+    /// Write the ECAM space at `gpa`, false if no ECAM window decodes it. Writes to functions
+    /// which are not attached are dropped.
+    pub fn write(&self, gpa: u64, data: &[u8]) -> bool {
+        let (address, offset) = match self.decode(gpa) {
+            Some(decoded) => decoded,
+            None => return false,
+        };
+
+        if let Some(adapter) = self.adapters.get(&address) {
+            if let Err(e) = adapter.lock().unwrap().try_ecam_write(offset, data) {
+                error!(
+                    "Failed to write config space of {} at {:#x}: {}",
+                    address, offset, e
+                );
+            }
+        }
+        true
+    }
+}
+
+/// The ECAM windows on the MMIO bus of the VMM, each registered with its guest physical
+/// address as base.
+impl BusDevice for PciSegments {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        if !PciSegments::read(self, base + offset, data) {
+            data.fill(0xff);
+        }
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        PciSegments::write(self, base + offset, data);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments() {
+        let address = PciAddress::new(1, 0x40, 3, 1);
+        assert_eq!(address.to_string(), "0001:40:03.1");
+        assert_eq!(address.devid(), 0x1_4019);
+
+        let mut segments = PciSegments::new();
+        segments.add_segment(0, 0xe000_0000, 0..=0xff).unwrap();
+        assert_eq!(
+            segments.add_segment(1, 0xef00_0000, 0..=0x0f),
+            Err(EcamError::Overlap(0))
+        );
+        segments
+            .add_segment(1, 0x30_0000_0000, 0x40..=0x4f)
+            .unwrap();
+        assert_eq!(segments.ecam_base(1), Some(0x30_0000_0000));
+
+        // The same BDF on both segments.
+        let on = |segment| {
+            let adapter = PciAdapterBuilder::new()
+                .segment(segment)
+                .completer(0x40, 3, 0)
+                .start(Box::new(PciTestDevice::new()));
+            Arc::new(Mutex::new(adapter))
+        };
+        let first = on(0);
+        let second = on(1);
+        assert_eq!(
+            segments.attach(first.clone()).unwrap(),
+            PciAddress::new(0, 0x40, 3, 0)
+        );
+        assert_eq!(
+            segments.attach(second.clone()).unwrap(),
+            PciAddress::new(1, 0x40, 3, 0)
+        );
+        assert_eq!(
+            segments.attach(on(1)),
+            Err(EcamError::AddressInUse(PciAddress::new(1, 0x40, 3, 0)))
+        );
+        assert_eq!(
+            segments.attach(Arc::new(Mutex::new(
+                PciAdapterBuilder::new()
+                    .segment(2)
+                    .start(Box::new(PciTestDevice::new()))
+            ))),
+            Err(EcamError::InvalidSegment(2))
+        );
+
+        // Only the function at the address answers, with its vendor ID.
+        let ecam = |segment: u64, bus: u64, device: u64, function: u64| {
+            segment + (bus << 20 | device << 15 | function << 12)
+        };
+        let mut data = [0u8; 2];
+        assert!(segments.read(ecam(0x30_0000_0000, 0x40, 3, 0), &mut data));
+        assert_eq!(u16::from_le_bytes(data), 0x1234);
+        assert!(segments.read(ecam(0x30_0000_0000, 0x40, 4, 0), &mut data));
+        assert_eq!(data, [0xff, 0xff]);
+        assert!(!segments.read(ecam(0x30_0000_0000, 0x50, 3, 0), &mut data));
+
+        // A write to the command register reaches the adapter of its segment only.
+        assert!(segments.write(ecam(0xe000_0000, 0x40, 3, 0) + 4, &[0x2]));
+        let command = |adapter: &Arc<Mutex<PciAdapter>>| {
+            adapter.lock().unwrap().read_config_register(1) & 0xffff
+        };
+        assert_eq!(command(&first), 0x2);
+        assert_eq!(command(&second), 0);
+
+        drop(segments);
+        for adapter in [first, second] {
+            let adapter = Arc::try_unwrap(adapter).ok().unwrap().into_inner().unwrap();
+            adapter.stop();
+            adapter.join();
+        }
+    }
+}
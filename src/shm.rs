@@ -0,0 +1,973 @@
+//! Device models in another process, reached through shared memory.
+//!
+//! [`PciAdapterBuilder::start_shm`] listens on a Unix domain socket like
+//! [`PciAdapterBuilder::start_unix`], but once the device model connected with [`connect_shm`]
+//! and the peers shook hands, the TLPs no longer go through the socket: the bridge hands the
+//! model a memfd holding one ring per direction and an eventfd doorbell per ring, and each
+//! TLP is a copy into the ring. A sleeping peer is woken by its doorbell, a busy one finds the
+//! TLPs without a system call, which suits DMA heavy models. The socket stays open, the lane
+//! goes down when it is closed.
+//!
+//! # Layout
+//!
+//! The hello frames are those of [`crate::remote`], then the bridge sends a frame holding the
+//! u32 size of the data of each ring, a power of 2, together with three fds: the memfd, the
+//! doorbell of the model and the doorbell of the bridge. The memfd holds the ring of the
+//! bridge to the model followed by the ring of the model to the bridge, each one a 128 bytes
+//! header followed by its data:
+//!
+//! | offset | size | ring header field                                         |
+//! |--------|------|-----------------------------------------------------------|
+//! | 0      | 4    | producer index, bytes ever written                        |
+//! | 64     | 4    | consumer index, bytes ever read                           |
+//! | 68     | 4    | 1 while the consumer sleeps on its doorbell, 0 otherwise  |
+//!
+//! The indexes wrap around at 2^32 and the byte at index `i` is at `i % size` of the data.
+//! Every frame is a little endian u32 length followed by the TLP in its wire format and
+//! padded to 4 bytes, a frame wraps around the end of the data. The producer rings the
+//! doorbell after a frame only if the consumer sleeps. When the ring is full the producer
+//! polls until the consumer made room.
+//!
+//! Then the bridge shares the guest RAM of [`PciAdapterBuilder::share_guest_memory`]: a frame
+//! holding the u32 number of regions, followed by a frame per region holding its u64 guest
+//! physical address, size and offset in its file together with the fd of the file.
+//!
+//! # Direct DMA
+//!
+//! Copying bulk data through TLPs of at most 4 KB costs a frame per request on both rings. A
+//! model connecting with [`connect_shm_mapped`] maps the shared guest RAM into its own address
+//! space instead and gets a [`GuestMap`], which translates guest physical addresses to host
+//! virtual ones. [`DirectDma`] copies the large, DW aligned accesses from and to the mapping
+//! and leaves the small or unaligned ones, and those outside the shared RAM, to the TLPs of a
+//! [`DmaHandle`].
+//!
+//! The direct accesses bypass the bridge: they are done before the next TLP of the model is
+//! sent, e.g. the MSI telling the guest about them, but they are not ordered against the
+//! posted writes sent before them, neither translated by a [`DmaTranslator`] nor seen by the
+//! observers and the event log. Models behind a vIOMMU stick to the TLPs.
+
+use crate::*;
+
+use crate::adapter::Result;
+use crate::remote::{
+    accept, eventfd, handshake, recv_with_fds, send_with_fds, serve_reconnects, MAX_FRAME,
+};
+use crossbeam_channel::{bounded, select, Receiver, Sender, TryRecvError};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use vm_memory::{GuestMemory, GuestMemoryRegion};
+
+const RING_HEADER: usize = 128;
+const PRODUCER: usize = 0;
+const CONSUMER: usize = 64;
+const WAITING: usize = 68;
+/// Size of the data of each ring.
+const RING_SIZE: usize = 256 << 10;
+/// The smallest ring holds the largest frame.
+const MIN_RING_SIZE: usize = 8 << 10;
+/// How often the producer looks for room in a full ring.
+const RING_FULL_POLL: Duration = Duration::from_micros(50);
+/// Most regions of guest RAM the bridge shares.
+const MAX_SHARED_REGIONS: usize = 64;
+/// Bytes of the frame describing a region of guest RAM.
+const REGION_FRAME: usize = 24;
+/// Smallest access [`DirectDma`] copies directly by default.
+const DIRECT_THRESHOLD: usize = 4096;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Bytes of the frame of a TLP of `len` bytes.
+fn frame_len(len: usize) -> usize {
+    4 + len.div_ceil(4) * 4
+}
+
+/// The memfd of a lane, mapped.
+struct ShmRegion {
+    file: File,
+    map: *mut u8,
+    len: usize,
+}
+
+// The rings synchronize their users through their indexes.
+unsafe impl Send for ShmRegion {}
+unsafe impl Sync for ShmRegion {}
+
+impl ShmRegion {
+    /// Create a region of two rings with `size` bytes of data each.
+    fn create(size: usize) -> io::Result<ShmRegion> {
+        let fd = unsafe {
+            libc::memfd_create(
+                b"pcie-tlp-lane\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the fd is new and owned by nobody else.
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len((2 * (RING_HEADER + size)) as u64)?;
+        // The peer must not shrink the memory under the mapping of the bridge.
+        let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        ShmRegion::map(file, size)
+    }
+
+    /// Map the region in `file`, of two rings with `size` bytes of data each.
+    fn map(file: File, size: usize) -> io::Result<ShmRegion> {
+        let len = 2 * (RING_HEADER + size);
+        if file.metadata()?.len() < len as u64 {
+            return Err(invalid("shared memory too small"));
+        }
+
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ShmRegion {
+            file,
+            map: map as *mut u8,
+            len,
+        })
+    }
+}
+
+impl Drop for ShmRegion {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// One direction of a lane, used by one producer and one consumer.
+#[derive(Clone)]
+struct Ring {
+    region: Arc<ShmRegion>,
+    /// Offset of the header in the region.
+    offset: usize,
+    size: usize,
+}
+
+impl Ring {
+    /// Ring `index` of `region`, with `size` bytes of data.
+    fn new(region: &Arc<ShmRegion>, index: usize, size: usize) -> Ring {
+        Ring {
+            region: region.clone(),
+            offset: index * (RING_HEADER + size),
+            size,
+        }
+    }
+
+    fn index(&self, field: usize) -> &AtomicU32 {
+        // SAFETY: the field is aligned and within the mapping, which lives as long as the ring.
+        unsafe { &*(self.region.map.add(self.offset + field) as *const AtomicU32) }
+    }
+
+    /// Copy `data` to the data of the ring at index `at`, wrapping around its end.
+    fn copy_in(&self, at: u32, data: &[u8]) {
+        let start = at as usize % self.size;
+        let first = data.len().min(self.size - start);
+        // SAFETY: both parts are within the data, which only the producer writes.
+        unsafe {
+            let base = self.region.map.add(self.offset + RING_HEADER);
+            std::ptr::copy_nonoverlapping(data.as_ptr(), base.add(start), first);
+            std::ptr::copy_nonoverlapping(data[first..].as_ptr(), base, data.len() - first);
+        }
+    }
+
+    /// Copy the data of the ring at index `at` to `data`, wrapping around its end.
+    fn copy_out(&self, at: u32, data: &mut [u8]) {
+        let start = at as usize % self.size;
+        let first = data.len().min(self.size - start);
+        // SAFETY: both parts are within the data, which the producer does not write before
+        // the consumer index passed them.
+        unsafe {
+            let base = self.region.map.add(self.offset + RING_HEADER);
+            std::ptr::copy_nonoverlapping(base.add(start), data.as_mut_ptr(), first);
+            let rest = data.len() - first;
+            std::ptr::copy_nonoverlapping(base, data[first..].as_mut_ptr(), rest);
+        }
+    }
+
+    /// Write a frame of `payload`, returns false if there is no room for it.
+    fn push(&self, payload: &[u8]) -> bool {
+        let len = frame_len(payload.len());
+        let tail = self.index(PRODUCER).load(Ordering::Relaxed);
+        let head = self.index(CONSUMER).load(Ordering::Acquire);
+        if tail.wrapping_sub(head) as usize + len > self.size {
+            return false;
+        }
+
+        self.copy_in(tail, &(payload.len() as u32).to_le_bytes());
+        self.copy_in(tail.wrapping_add(4), payload);
+        self.index(PRODUCER)
+            .store(tail.wrapping_add(len as u32), Ordering::SeqCst);
+        true
+    }
+
+    /// Take the next frame, if any. Fails if the peer wrote something which is no frame.
+    fn pop(&self) -> io::Result<Option<Vec<u8>>> {
+        let head = self.index(CONSUMER).load(Ordering::Relaxed);
+        let tail = self.index(PRODUCER).load(Ordering::Acquire);
+        let available = tail.wrapping_sub(head) as usize;
+        if available == 0 {
+            return Ok(None);
+        }
+        if available < 4 || available > self.size {
+            return Err(invalid("corrupt ring indexes"));
+        }
+
+        let mut len = [0u8; 4];
+        self.copy_out(head, &mut len);
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME || frame_len(len) > available {
+            return Err(invalid("corrupt ring frame"));
+        }
+
+        let mut payload = vec![0u8; len];
+        self.copy_out(head.wrapping_add(4), &mut payload);
+        self.index(CONSUMER)
+            .store(head.wrapping_add(frame_len(len) as u32), Ordering::Release);
+        Ok(Some(payload))
+    }
+
+    /// Tell the producer the consumer is about to sleep, returns false if a frame came in
+    /// meanwhile and it should not.
+    fn sleep(&self) -> bool {
+        self.index(WAITING).store(1, Ordering::SeqCst);
+        let tail = self.index(PRODUCER).load(Ordering::SeqCst);
+        if tail != self.index(CONSUMER).load(Ordering::Relaxed) {
+            self.wake();
+            return false;
+        }
+        true
+    }
+
+    fn wake(&self) {
+        self.index(WAITING).store(0, Ordering::SeqCst);
+    }
+
+    /// Whether the producer rings the doorbell after a frame.
+    fn sleeping(&self) -> bool {
+        fence(Ordering::SeqCst);
+        self.index(WAITING).load(Ordering::SeqCst) != 0
+    }
+}
+
+fn ring_doorbell(mut doorbell: &File) {
+    if let Err(e) = doorbell.write_all(&1u64.to_le_bytes()) {
+        error!("Failed to ring the doorbell: {}", e);
+    }
+}
+
+/// Sleep until the doorbell of `ring` rings, returns false once the peer hung up.
+fn wait(ring: &Ring, mut doorbell: &File, socket: &UnixStream) -> bool {
+    if !ring.sleep() {
+        return true;
+    }
+
+    let mut fds = [
+        libc::pollfd {
+            fd: doorbell.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+    ring.wake();
+    if ret < 0 {
+        return io::Error::last_os_error().kind() == io::ErrorKind::Interrupted;
+    }
+    // Nothing is sent on the socket after the setup, it is only readable once closed.
+    if fds[1].revents != 0 {
+        return false;
+    }
+    let _ = doorbell.read(&mut [0u8; 8]);
+    true
+}
+
+/// Put the TLPs of `ring` on `tx` until the peer hung up and the ring is empty, or the lane
+/// is closed.
+fn read_ring(ring: Ring, doorbell: File, socket: UnixStream, tx: Sender<Tlp>) {
+    let mut open = true;
+    loop {
+        let frame = match ring.pop() {
+            Ok(Some(frame)) => frame,
+            Ok(None) if open => {
+                open = wait(&ring, &doorbell, &socket);
+                continue;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("Failed to read a TLP from shared memory: {}", e);
+                break;
+            }
+        };
+        let tlp = match Tlp::from_bytes(&frame) {
+            Ok(tlp) => tlp,
+            Err(e) => {
+                error!("Malformed TLP from shared memory: {:?}", e);
+                break;
+            }
+        };
+        if tx.send(tlp).is_err() {
+            break;
+        }
+    }
+    debug!("Shared memory lane closed");
+}
+
+/// One end of a lane carried by shared memory, see [`crate::shm`].
+///
+/// As a device model it stands for the model at the other end, whose lane ends when the
+/// socket is closed.
+struct ShmLane {
+    socket: UnixStream,
+    features: DeviceFeatures,
+    outbound: Ring,
+    inbound: Ring,
+    /// Doorbells of the peer and of this end.
+    peer_doorbell: File,
+    doorbell: File,
+    /// Disconnected once the reader of the inbound ring ends.
+    closed: Option<Receiver<()>>,
+}
+
+impl ShmLane {
+    /// Create the shared memory of a lane and hand it to the model at the other end of
+    /// `socket`, after the handshake.
+    fn offer(
+        socket: UnixStream,
+        features: DeviceFeatures,
+        shared: &[GuestRamRegion],
+    ) -> io::Result<ShmLane> {
+        let region = Arc::new(ShmRegion::create(RING_SIZE)?);
+        let (model, bridge) = (eventfd()?, eventfd()?);
+        let mut setup = 4u32.to_le_bytes().to_vec();
+        setup.extend_from_slice(&(RING_SIZE as u32).to_le_bytes());
+        let fds = [
+            region.file.as_raw_fd(),
+            model.as_raw_fd(),
+            bridge.as_raw_fd(),
+        ];
+        send_with_fds(&socket, &setup, &fds)?;
+        share(&socket, shared)?;
+
+        Ok(ShmLane {
+            socket,
+            features,
+            outbound: Ring::new(&region, 0, RING_SIZE),
+            inbound: Ring::new(&region, 1, RING_SIZE),
+            peer_doorbell: model,
+            doorbell: bridge,
+            closed: None,
+        })
+    }
+
+    /// Take the shared memory the bridge at the other end of `socket` hands over, after the
+    /// handshake. The guest RAM it shares follows, see [`GuestMap::take`].
+    fn take(socket: &UnixStream, features: DeviceFeatures) -> io::Result<ShmLane> {
+        let mut setup = [0u8; 8];
+        let mut fds = recv_with_fds(socket, &mut setup)?.into_iter();
+        let (region, model, bridge) = match (fds.next(), fds.next(), fds.next()) {
+            (Some(region), Some(model), Some(bridge)) => (region, model, bridge),
+            _ => return Err(invalid("missing shared memory fds")),
+        };
+        let size = u32::from_le_bytes([setup[4], setup[5], setup[6], setup[7]]) as usize;
+        if setup[..4] != 4u32.to_le_bytes() || size < MIN_RING_SIZE || !size.is_power_of_two() {
+            return Err(invalid("bad shared memory setup"));
+        }
+
+        let region = Arc::new(ShmRegion::map(region, size)?);
+        Ok(ShmLane {
+            socket: socket.try_clone()?,
+            features,
+            outbound: Ring::new(&region, 1, size),
+            inbound: Ring::new(&region, 0, size),
+            peer_doorbell: bridge,
+            doorbell: model,
+            closed: None,
+        })
+    }
+
+    /// Start the reader of the inbound ring, which outlives the runs on the lanes of resets.
+    fn start_reader(&mut self, tx: &Sender<Tlp>) -> io::Result<Receiver<()>> {
+        let ring = self.inbound.clone();
+        let doorbell = self.doorbell.try_clone()?;
+        let socket = self.socket.try_clone()?;
+        let tx = tx.clone();
+        let (closed_tx, closed) = bounded(0);
+        std::thread::Builder::new()
+            .name("tlp-shm".to_string())
+            .spawn(move || {
+                let _closed = closed_tx;
+                read_ring(ring, doorbell, socket, tx);
+            })?;
+        Ok(closed)
+    }
+
+    /// Write a frame to the outbound ring, waiting for room while the lane is open.
+    fn send(&self, payload: &[u8], closed: &Receiver<()>) -> bool {
+        while !self.outbound.push(payload) {
+            if let Err(TryRecvError::Disconnected) = closed.try_recv() {
+                return false;
+            }
+            std::thread::sleep(RING_FULL_POLL);
+        }
+        if self.outbound.sleeping() {
+            ring_doorbell(&self.peer_doorbell);
+        }
+        true
+    }
+}
+
+impl PciSimDevice for ShmLane {
+    fn features(&self) -> DeviceFeatures {
+        self.features
+    }
+
+    fn run(&mut self, lane: &PciLane) {
+        let closed = match self.closed.clone() {
+            Some(closed) => closed,
+            None => match self.start_reader(&lane.tx) {
+                Ok(closed) => self.closed.insert(closed).clone(),
+                Err(e) => {
+                    error!("Failed to start the shared memory reader: {}", e);
+                    return;
+                }
+            },
+        };
+
+        loop {
+            select! {
+                recv(lane.rx) -> tlp => {
+                    let tlp = match tlp {
+                        Ok(tlp) => tlp,
+                        Err(_) => break,
+                    };
+                    match tlp.to_bytes() {
+                        Ok(bytes) => {
+                            if !self.send(&bytes, &closed) {
+                                break;
+                            }
+                        }
+                        Err(e) => error!("{:?} dropped: {:?}", tlp.header._type, e),
+                    }
+                },
+                recv(closed) -> _ => break,
+            }
+        }
+    }
+
+    fn on_reset(&mut self, kind: ResetKind) {
+        let closed = match self.closed.clone() {
+            Some(closed) => closed,
+            None => return,
+        };
+        match kind.to_message().to_bytes() {
+            Ok(bytes) => {
+                self.send(&bytes, &closed);
+            }
+            Err(e) => error!("{:?} reset dropped: {:?}", kind, e),
+        }
+    }
+
+    fn on_stop(&mut self) {
+        // The readers of both ends see the socket closed.
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
+
+/// Carry `lane` over `shm` on a thread of its own, until either of them is closed.
+fn spawn_lane(mut shm: ShmLane, lane: PciLane) -> io::Result<()> {
+    std::thread::Builder::new()
+        .name("tlp-shm".to_string())
+        .spawn(move || {
+            shm.run(&lane);
+            shm.on_stop();
+        })?;
+    Ok(())
+}
+
+impl PciAdapterBuilder {
+    /// Start the bridge with the device model which connects first to the Unix domain socket
+    /// at `path`, carrying its lane over shared memory, see [`crate::shm`]. Blocks until the
+    /// model is connected. The models which connect later replace it on the lane, like with
+    /// [`PciAdapterBuilder::start_listener`].
+    pub fn start_shm<P: AsRef<Path>>(self, path: P) -> io::Result<PciAdapter> {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        let (socket, features) = accept(&listener, || true)?.unwrap();
+
+        let shared = self.shared_memory.clone();
+        let adapter = self.start(Box::new(ShmLane::offer(socket, features, &shared)?));
+        let reconnector = adapter.reconnector();
+        std::thread::Builder::new()
+            .name("tlp-listener".to_string())
+            .spawn(move || {
+                serve_reconnects(listener, reconnector, |socket, features, lane| {
+                    spawn_lane(ShmLane::offer(socket, features, &shared)?, lane)
+                })
+            })?;
+        Ok(adapter)
+    }
+}
+
+impl PciAdapter {
+    /// See [`PciAdapterBuilder::start_shm`].
+    pub fn start_shm<P: AsRef<Path>>(path: P) -> io::Result<PciAdapter> {
+        PciAdapterBuilder::new().start_shm(path)
+    }
+}
+
+/// Connect a device model advertising `features` to the bridge listening on the Unix domain
+/// socket at `path` with [`PciAdapterBuilder::start_shm`]. Returns the lane the model runs on
+/// and the features of the bridge, like [`connect_unix`]. The connection is closed once the
+/// model drops the lane.
+pub fn connect_shm<P: AsRef<Path>>(
+    path: P,
+    features: DeviceFeatures,
+) -> io::Result<(PciLane, DeviceFeatures)> {
+    connect_shm_mapped(path, features).map(|(lane, bridge, _)| (lane, bridge))
+}
+
+/// Like [`connect_shm`], but also maps the guest RAM the bridge shares, see
+/// [`crate::shm#direct-dma`].
+pub fn connect_shm_mapped<P: AsRef<Path>>(
+    path: P,
+    features: DeviceFeatures,
+) -> io::Result<(PciLane, DeviceFeatures, GuestMap)> {
+    let mut socket = UnixStream::connect(path)?;
+    let bridge = handshake(&mut socket, &features)?;
+    let shm = ShmLane::take(&socket, bridge)?;
+    let map = GuestMap::take(&socket)?;
+    let (lane, peer) = PciLane::pair();
+    spawn_lane(shm, peer)?;
+    Ok((lane, bridge, map))
+}
+
+/// A range of guest RAM backed by a file, e.g. the memfd of a memory region of the VMM, which
+/// the bridge shares with the device models, see [`PciAdapterBuilder::share_guest_memory`].
+#[derive(Debug, Clone)]
+pub struct GuestRamRegion {
+    pub gpa: u64,
+    pub size: u64,
+    pub file: Arc<File>,
+    /// Offset of the region in the file, a multiple of the page size.
+    pub offset: u64,
+}
+
+impl GuestRamRegion {
+    /// The regions of `memory` which are backed by files. Anonymous memory cannot be shared,
+    /// the models reach it through TLPs only.
+    pub fn from_memory<M: GuestMemory>(memory: &M) -> io::Result<Vec<GuestRamRegion>> {
+        let mut regions = vec![];
+        for region in memory.iter() {
+            if let Some(file_offset) = region.file_offset() {
+                regions.push(GuestRamRegion {
+                    gpa: region.start_addr().raw_value(),
+                    size: region.len(),
+                    file: Arc::new(file_offset.file().try_clone()?),
+                    offset: file_offset.start(),
+                });
+            }
+        }
+        Ok(regions)
+    }
+}
+
+/// Send the regions of guest RAM to the model at the other end of `socket`.
+fn share(socket: &UnixStream, regions: &[GuestRamRegion]) -> io::Result<()> {
+    if regions.len() > MAX_SHARED_REGIONS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("more than {} regions of guest RAM", MAX_SHARED_REGIONS),
+        ));
+    }
+    send_with_fds(socket, &(regions.len() as u32).to_le_bytes(), &[])?;
+    for region in regions {
+        let mut frame = region.gpa.to_le_bytes().to_vec();
+        frame.extend_from_slice(&region.size.to_le_bytes());
+        frame.extend_from_slice(&region.offset.to_le_bytes());
+        send_with_fds(socket, &frame, &[region.file.as_raw_fd()])?;
+    }
+    Ok(())
+}
+
+/// A region of guest RAM mapped by the model.
+struct MappedRegion {
+    gpa: u64,
+    size: u64,
+    map: *mut u8,
+}
+
+/// The guest RAM the bridge shares, mapped into the address space of the device model, see
+/// [`crate::shm#direct-dma`].
+#[derive(Default)]
+pub struct GuestMap {
+    regions: Vec<MappedRegion>,
+}
+
+// The guest and the bridge access the memory concurrently anyway, like a DMA engine does.
+unsafe impl Send for GuestMap {}
+unsafe impl Sync for GuestMap {}
+
+impl GuestMap {
+    /// Map the regions of guest RAM the bridge at the other end of `socket` shares.
+    fn take(socket: &UnixStream) -> io::Result<GuestMap> {
+        let mut count = [0u8; 4];
+        recv_with_fds(socket, &mut count)?;
+        let count = u32::from_le_bytes(count) as usize;
+        if count > MAX_SHARED_REGIONS {
+            return Err(invalid("too many regions of guest RAM"));
+        }
+
+        let mut map = GuestMap::default();
+        for _ in 0..count {
+            let mut frame = [0u8; REGION_FRAME];
+            let file = match recv_with_fds(socket, &mut frame)?.into_iter().next() {
+                Some(file) => file,
+                None => return Err(invalid("missing guest RAM fd")),
+            };
+            let u64_at = |at: usize| {
+                let mut b = [0u8; 8];
+                b.copy_from_slice(&frame[at..at + 8]);
+                u64::from_le_bytes(b)
+            };
+            map.add(u64_at(0), u64_at(8), &file, u64_at(16))?;
+        }
+        Ok(map)
+    }
+
+    /// Map `size` bytes at `offset` of `file` as the guest RAM at `gpa`.
+    fn add(&mut self, gpa: u64, size: u64, file: &File, offset: u64) -> io::Result<()> {
+        if size == 0 || gpa.checked_add(size).is_none() || offset.checked_add(size).is_none() {
+            return Err(invalid("bad region of guest RAM"));
+        }
+        if file.metadata()?.len() < offset + size {
+            return Err(invalid("guest RAM beyond its file"));
+        }
+
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                offset as libc::off_t,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        debug!("map guest RAM {:#x}+{:#x}", gpa, size);
+        self.regions.push(MappedRegion {
+            gpa,
+            size,
+            map: map as *mut u8,
+        });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Host virtual address of the `len` bytes of guest RAM at `gpa`, `None` unless one
+    /// region holds all of them.
+    pub fn translate(&self, gpa: u64, len: usize) -> Option<*mut u8> {
+        let end = gpa.checked_add(len as u64)?;
+        self.regions
+            .iter()
+            .find(|region| gpa >= region.gpa && end <= region.gpa + region.size)
+            .map(|region| unsafe { region.map.add((gpa - region.gpa) as usize) })
+    }
+
+    /// Copy the guest RAM at `gpa` into `data`, false unless one region holds all of it.
+    pub fn read(&self, gpa: u64, data: &mut [u8]) -> bool {
+        match self.translate(gpa, data.len()) {
+            Some(hva) => {
+                // SAFETY: the mapping holds the range and never overlaps `data`.
+                unsafe { std::ptr::copy_nonoverlapping(hva, data.as_mut_ptr(), data.len()) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Copy `data` into the guest RAM at `gpa`, false unless one region holds all of it.
+    pub fn write(&self, gpa: u64, data: &[u8]) -> bool {
+        match self.translate(gpa, data.len()) {
+            Some(hva) => {
+                // SAFETY: the mapping holds the range and never overlaps `data`.
+                unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), hva, data.len()) };
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for GuestMap {
+    fn drop(&mut self) {
+        for region in self.regions.iter() {
+            unsafe { libc::munmap(region.map as *mut libc::c_void, region.size as usize) };
+        }
+    }
+}
+
+/// DMA of a model copying the large accesses directly from and to the guest RAM of a
+/// [`GuestMap`], and sending the others as TLPs through its [`DmaHandle`].
+pub struct DirectDma<'a> {
+    dma: DmaHandle<'a>,
+    map: &'a GuestMap,
+    threshold: usize,
+}
+
+impl<'a> DirectDma<'a> {
+    /// Accesses of 4 KB and more go directly to guest RAM.
+    pub fn new(dma: DmaHandle<'a>, map: &'a GuestMap) -> Self {
+        DirectDma {
+            dma,
+            map,
+            threshold: DIRECT_THRESHOLD,
+        }
+    }
+
+    /// Smallest access in bytes which goes directly to guest RAM.
+    pub fn threshold(mut self, bytes: usize) -> Self {
+        self.threshold = bytes;
+        self
+    }
+
+    /// The handle sending the TLPs, e.g. to receive the requests of the bridge.
+    pub fn handle(&mut self) -> &mut DmaHandle<'a> {
+        &mut self.dma
+    }
+
+    /// Whether the access of `len` bytes at `addr` goes directly to guest RAM.
+    fn direct(&self, addr: u64, len: usize) -> bool {
+        len >= self.threshold
+            && addr & 0b11 == 0
+            && len & 0b11 == 0
+            && self.map.translate(addr, len).is_some()
+    }
+
+    /// Read `len` bytes at `addr`.
+    pub fn read(&mut self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        if self.direct(addr, len) {
+            let mut data = vec![0u8; len];
+            self.map.read(addr, &mut data);
+            return Ok(data);
+        }
+        self.dma.read(addr, len)
+    }
+
+    /// Write `data` at `addr`.
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<()> {
+        if self.direct(addr, data.len()) {
+            self.map.write(addr, data);
+            return Ok(());
+        }
+        self.dma.write(addr, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::adapter::BRIDGE_FEATURES;
+    use crate::device::{dispatch, Ids};
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[derive(Clone)]
+    struct VecMemory(Arc<Mutex<Vec<u8>>>);
+
+    impl DmaMemory for VecMemory {
+        fn read(&self, gpa: u64, data: &mut [u8]) -> io::Result<()> {
+            let gpa = gpa as usize;
+            data.copy_from_slice(&self.0.lock().unwrap()[gpa..gpa + data.len()]);
+            Ok(())
+        }
+
+        fn write(&self, gpa: u64, data: &[u8]) -> io::Result<()> {
+            let gpa = gpa as usize;
+            self.0.lock().unwrap()[gpa..gpa + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ring() {
+        let region = Arc::new(ShmRegion::create(MIN_RING_SIZE).unwrap());
+        let ring = Ring::new(&region, 1, MIN_RING_SIZE);
+
+        // Frames of 4 KB wrap around the 8 KB of data.
+        for i in 0..5u8 {
+            assert!(ring.push(&[i; 4093]));
+            assert!(!ring.push(&[i; 4093]));
+            assert_eq!(ring.pop().unwrap(), Some(vec![i; 4093]));
+            assert_eq!(ring.pop().unwrap(), None);
+        }
+
+        assert!(ring.sleep());
+        assert!(ring.sleeping());
+        ring.wake();
+        assert!(ring.push(&[1]));
+        assert!(!ring.sleep());
+        assert!(!ring.sleeping());
+
+        // The producer index of the peer is not to be trusted.
+        ring.index(PRODUCER).fetch_add(0x10_0000, Ordering::SeqCst);
+        assert!(ring.pop().is_err());
+    }
+
+    #[test]
+    fn shm_lane() {
+        let path = std::env::temp_dir().join(format!("pcie-tlp-shm-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // The model copies 64 KB at 0 to 0x10000 a few times, so the rings wrap around, then
+        // answers once more.
+        let model = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let (lane, bridge) = loop {
+                    match connect_shm(&path, DeviceFeatures::default()) {
+                        Ok(peer) => break peer,
+                        Err(_) => thread::sleep(Duration::from_millis(1)),
+                    }
+                };
+                assert_eq!(bridge, BRIDGE_FEATURES);
+
+                let mut bdf = 0;
+                let tlp = lane.rx.recv().unwrap();
+                dispatch(&mut Ids(0x5678_1234), &lane, &tlp, &mut bdf);
+                let mut dma = DmaHandle::new(&lane, bdf);
+                for _ in 0..8 {
+                    let data = dma.read(0, 0x10000).unwrap();
+                    dma.write(0x10000, &data).unwrap();
+                }
+                let tlp = dma.recv().unwrap();
+                dispatch(&mut Ids(0x5678_1234), &lane, &tlp, &mut bdf);
+            })
+        };
+
+        let adapter = PciAdapter::start_shm(&path).unwrap();
+        let memory = VecMemory(Arc::new(Mutex::new(vec![0; 0x20000])));
+        let pattern: Vec<u8> = (0..0x10000).map(|i| (i * 7) as u8).collect();
+        memory.write(0, &pattern).unwrap();
+        adapter.set_dma_memory(Box::new(memory.clone()));
+
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        // The writes came before the completion.
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        let mut data = vec![0; 0x10000];
+        memory.read(0x10000, &mut data).unwrap();
+        assert_eq!(data, pattern);
+
+        model.join().unwrap();
+        adapter.stop();
+        adapter.join();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn direct_dma() {
+        use vm_memory::{Bytes, FileOffset, GuestMemoryMmap};
+
+        let path = std::env::temp_dir().join(format!("pcie-tlp-dma-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // The model copies 16 KB at 0 to 0x4000 directly, and writes 3 bytes through TLPs.
+        let model = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let (lane, _, map) = loop {
+                    match connect_shm_mapped(&path, DeviceFeatures::default()) {
+                        Ok(peer) => break peer,
+                        Err(_) => thread::sleep(Duration::from_millis(1)),
+                    }
+                };
+                assert!(map.translate(0x7000, 0x1000).is_some());
+                assert!(map.translate(0x7000, 0x1001).is_none());
+
+                let mut bdf = 0;
+                let tlp = lane.rx.recv().unwrap();
+                dispatch(&mut Ids(0x5678_1234), &lane, &tlp, &mut bdf);
+                let mut dma = DirectDma::new(DmaHandle::new(&lane, bdf), &map);
+                let data = dma.read(0, 0x4000).unwrap();
+                dma.write(0x4000, &data).unwrap();
+                dma.write(0x1, &[0xaa; 3]).unwrap();
+                let tlp = dma.handle().recv().unwrap();
+                dispatch(&mut Ids(0x5678_1234), &lane, &tlp, &mut bdf);
+            })
+        };
+
+        let fd = unsafe { libc::memfd_create(b"guest\0".as_ptr() as *const _, 0) };
+        assert!(fd >= 0);
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(0x8000).unwrap();
+        let guest = GuestMemoryMmap::from_ranges_with_files(&[(
+            GuestAddress(0),
+            0x8000,
+            Some(FileOffset::new(file, 0)),
+        )])
+        .unwrap();
+        let pattern: Vec<u8> = (0..0x4000).map(|i| (i * 7) as u8).collect();
+        guest.write_slice(&pattern, GuestAddress(0)).unwrap();
+
+        let adapter = PciAdapterBuilder::new()
+            .share_guest_memory(GuestRamRegion::from_memory(&guest).unwrap())
+            .start_shm(&path)
+            .unwrap();
+        // The TLPs land somewhere else than the shared guest RAM.
+        let memory = VecMemory(Arc::new(Mutex::new(vec![0; 0x10])));
+        adapter.set_dma_memory(Box::new(memory.clone()));
+
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        assert_eq!(adapter.try_config_read(0), Ok(0x5678_1234));
+        let mut data = vec![0; 0x4000];
+        guest.read_slice(&mut data, GuestAddress(0x4000)).unwrap();
+        assert_eq!(data, pattern);
+        let mut head = [0u8; 4];
+        guest.read_slice(&mut head, GuestAddress(0)).unwrap();
+        assert_eq!(head, pattern[..4]);
+        memory.read(0, &mut head).unwrap();
+        assert_eq!(head, [0, 0xaa, 0xaa, 0xaa]);
+
+        model.join().unwrap();
+        adapter.stop();
+        adapter.join();
+        let _ = std::fs::remove_file(&path);
+    }
+}
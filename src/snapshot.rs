@@ -0,0 +1,283 @@
+/*!
+Snapshot and restore of a function for live migration.
+
+[`PciAdapter::snapshot`] collects what the adapter, the bridge and the device model know about
+a function: the programmed BARs, the MSI-X table kept by the bridge, the transactions still
+outstanding and the internal state of the model, which the bridge quiesces the model for and
+gets from [`PciSimDevice::state_save`]. [`PciAdapter::restore`] brings it back into the adapter
+of a freshly started model on the destination, where the outstanding requests are sent again.
+
+# Format
+
+[`AdapterSnapshot::to_bytes`] encodes the snapshot with little endian integers:
+
+| size | field                                                                    |
+|------|--------------------------------------------------------------------------|
+| 8    | magic `PCIETLPS`                                                         |
+| 4    | version, currently 1                                                     |
+| 2    | BDF                                                                      |
+| 1    | whether the expansion ROM is enabled                                     |
+| 4    | number of BARs, each BAR register, type, slot mapped, start and length   |
+| 1    | whether MSI-X is present, then control, size, entries and pending bits   |
+| 4    | number of outstanding requests, each as length and wire format          |
+| 4    | length of the device state, followed by the state                       |
+*/
+
+use crate::*;
+
+use std::convert::TryInto;
+use std::io;
+
+const MAGIC: &[u8; 8] = b"PCIETLPS";
+const VERSION: u32 = 1;
+/// Upper bound of the MSI-X table size.
+const MSIX_MAX_VECTORS: usize = 2048;
+
+/// Internal state of a device model which moves with it, shared by the model through
+/// [`PciSimDevice::state`], e.g. with the threads of its backend. The default
+/// [`PciSimDevice::state_save`] saves it while the model is quiesced, the other users of the
+/// state take care of the locking.
+pub trait DeviceState: Send + Sync {
+    fn save(&self) -> Vec<u8>;
+
+    /// Load a state saved by [`DeviceState::save`], before the model sees any request.
+    fn restore(&self, state: &[u8]) -> io::Result<()>;
+}
+
+/// A programmed BAR of a function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BarSnapshot {
+    pub bar_reg: usize,
+    pub type_: PciBarRegionType,
+    pub start: u64,
+    pub length: u64,
+    /// The BAR is backed by shared memory mapped into the guest.
+    pub slot_mapped: bool,
+}
+
+/// The MSI-X table and pending bits kept by the bridge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MsixSnapshot {
+    pub control: u16,
+    /// Message address low, message address high, message data and vector control.
+    pub entries: Vec<[u32; 4]>,
+    pub pending: Vec<bool>,
+}
+
+/// Everything needed to bring a function back on another host.
+#[derive(Debug, Clone)]
+pub struct AdapterSnapshot {
+    pub bdf: u16,
+    pub bars: Vec<BarSnapshot>,
+    pub rom_enabled: bool,
+    pub msix: Option<MsixSnapshot>,
+    /// Requests of the bridge which are not completed yet, in the order they were issued.
+    pub pending: Vec<Tlp>,
+    pub device: Vec<u8>,
+}
+
+impl AdapterSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&self.bdf.to_le_bytes());
+        buf.push(self.rom_enabled as u8);
+
+        buf.extend_from_slice(&(self.bars.len() as u32).to_le_bytes());
+        for bar in self.bars.iter() {
+            buf.push(bar.bar_reg as u8);
+            buf.push(bar.type_ as u8);
+            buf.push(bar.slot_mapped as u8);
+            buf.extend_from_slice(&bar.start.to_le_bytes());
+            buf.extend_from_slice(&bar.length.to_le_bytes());
+        }
+
+        match &self.msix {
+            Some(msix) => {
+                buf.push(1);
+                buf.extend_from_slice(&msix.control.to_le_bytes());
+                buf.extend_from_slice(&(msix.entries.len() as u32).to_le_bytes());
+                for entry in msix.entries.iter() {
+                    for dw in entry {
+                        buf.extend_from_slice(&dw.to_le_bytes());
+                    }
+                }
+                buf.extend(
+                    (0..msix.entries.len())
+                        .map(|i| msix.pending.get(i) == Some(&true))
+                        .map(u8::from),
+                );
+            }
+            None => buf.push(0),
+        }
+
+        let pending: Vec<Vec<u8>> = self
+            .pending
+            .iter()
+            .filter_map(|tlp| tlp.to_bytes().ok())
+            .collect();
+        buf.extend_from_slice(&(pending.len() as u32).to_le_bytes());
+        for tlp in pending {
+            buf.extend_from_slice(&(tlp.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&tlp);
+        }
+
+        buf.extend_from_slice(&(self.device.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.device);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> io::Result<AdapterSnapshot> {
+        let mut r = Reader { buf, pos: 0 };
+        if r.take(8)? != MAGIC {
+            return Err(invalid("not an adapter snapshot"));
+        }
+        if r.u32()? != VERSION {
+            return Err(invalid("unsupported snapshot version"));
+        }
+
+        let bdf = r.u16()?;
+        let rom_enabled = r.u8()? != 0;
+
+        let mut bars = vec![];
+        for _ in 0..r.u32()? {
+            let bar_reg = r.u8()? as usize;
+            let type_ = match r.u8()? {
+                0 => PciBarRegionType::Memory32BitRegion,
+                1 => PciBarRegionType::IoRegion,
+                4 => PciBarRegionType::Memory64BitRegion,
+                _ => return Err(invalid("unknown BAR type")),
+            };
+            bars.push(BarSnapshot {
+                bar_reg,
+                type_,
+                slot_mapped: r.u8()? != 0,
+                start: r.u64()?,
+                length: r.u64()?,
+            });
+        }
+
+        let msix = match r.u8()? {
+            0 => None,
+            _ => {
+                let control = r.u16()?;
+                let size = r.u32()? as usize;
+                let mut entries = Vec::with_capacity(size.min(MSIX_MAX_VECTORS));
+                for _ in 0..size {
+                    entries.push([r.u32()?, r.u32()?, r.u32()?, r.u32()?]);
+                }
+                let pending = r.take(size)?.iter().map(|&p| p != 0).collect();
+                Some(MsixSnapshot {
+                    control,
+                    entries,
+                    pending,
+                })
+            }
+        };
+
+        let mut pending = vec![];
+        for _ in 0..r.u32()? {
+            let len = r.u32()? as usize;
+            let tlp = Tlp::from_bytes(r.take(len)?).map_err(|_| invalid("malformed request"))?;
+            pending.push(tlp);
+        }
+
+        let len = r.u32()? as usize;
+        let device = r.take(len)?.to_vec();
+
+        Ok(AdapterSnapshot {
+            bdf,
+            bars,
+            rom_enabled,
+            msix,
+            pending,
+            device,
+        })
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.buf.len())
+            .ok_or_else(|| invalid("truncated snapshot"))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        let snapshot = AdapterSnapshot {
+            bdf: 0x18,
+            bars: vec![BarSnapshot {
+                bar_reg: 4,
+                type_: PciBarRegionType::Memory64BitRegion,
+                start: 0x1_0000_0000,
+                length: 0x10_0000,
+                slot_mapped: false,
+            }],
+            rom_enabled: true,
+            msix: Some(MsixSnapshot {
+                control: 0x8001,
+                entries: vec![[0xfee0_0000, 0, 0x21, 0], [0, 0, 0, 1]],
+                pending: vec![false, true],
+            }),
+            pending: vec![TlpBuilder::config0_read(ConfigExtra {
+                requester: 0x10,
+                completer: 0x18,
+                tag: 3,
+                reg: 1,
+            })
+            .byte_enable(0xf)
+            .length(1)
+            .build()],
+            device: vec![1, 2, 3],
+        };
+
+        let bytes = snapshot.to_bytes();
+        let decoded = AdapterSnapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.bdf, snapshot.bdf);
+        assert_eq!(decoded.bars, snapshot.bars);
+        assert!(decoded.rom_enabled);
+        assert_eq!(decoded.msix, snapshot.msix);
+        assert_eq!(decoded.pending.len(), 1);
+        assert_eq!(decoded.pending[0].header.tag(), Some(3));
+        assert_eq!(decoded.device, snapshot.device);
+
+        assert!(AdapterSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(AdapterSnapshot::from_bytes(b"PCIETLPL").is_err());
+    }
+}
@@ -0,0 +1,364 @@
+//! SR-IOV physical functions and the virtual functions they enable.
+//!
+//! [`PciSriovDevice`] serves a PF model which has an [`SriovCapability`] together with the VFs
+//! the guest enables through it, all on the lane of the PF. When VF Enable is set, the model
+//! creates a context for each of the NumVFs VFs, at the routing IDs First VF Offset and VF
+//! Stride give, and drops them when VF Enable is cleared. Config requests go to the function
+//! at their completer ID. While VF MSE is set, the VF BARs of the PF hold the BARs of all VFs
+//! back to back and memory requests within them go to the VF whose BAR they hit, the others
+//! go to the PF.
+//!
+//! The host side is [`PciAdapter::enable_vfs`], which does what a PF driver does and returns
+//! an adapter for each VF, for the hypervisor to assign like any other function.
+
+use crate::*;
+
+use crate::device::{dispatch, reject};
+
+/// Most extended capabilities walked to find the SR-IOV capability.
+const MAX_EXTENDED_CAPS: usize = 48;
+
+/// An SR-IOV capable device model: the PF model and the VF contexts it spawns.
+pub struct PciSriovDevice<P, V> {
+    pf: P,
+    new_vf: Box<dyn FnMut(u16) -> V + Send + Sync>,
+    /// Routing ID and context of each enabled VF.
+    vfs: Vec<(u16, V)>,
+    enabled: bool,
+    /// Base address and size for one VF of each VF BAR, while VF MSE is set.
+    bars: Vec<(u64, u64)>,
+    bdf: u16,
+    completer: u16,
+}
+
+impl<P: SimpleDevice, V: SimpleDevice> PciSriovDevice<P, V> {
+    /// Serve `pf`, whose config space has an [`SriovCapability`]. `new_vf` creates the context
+    /// of the VF at the routing ID it is given, whenever the VF is enabled or reset.
+    pub fn new<F>(pf: P, new_vf: F) -> Self
+    where
+        F: FnMut(u16) -> V + Send + Sync + 'static,
+    {
+        PciSriovDevice {
+            pf,
+            new_vf: Box::new(new_vf),
+            vfs: vec![],
+            enabled: false,
+            bars: vec![],
+            bdf: 0,
+            completer: 0,
+        }
+    }
+
+    fn read(&mut self, reg: usize) -> u32 {
+        self.pf.on_config_read(self.bdf, reg)
+    }
+
+    fn write(&mut self, reg: usize, value: u32) {
+        self.pf
+            .on_config_write(self.bdf, reg, 0, &value.to_le_bytes());
+    }
+
+    /// Walk the extended capability list of the PF for the SR-IOV capability.
+    fn find_sriov(&mut self) -> Option<usize> {
+        let mut reg = PCI_CONFIG_REGS;
+        for _ in 0..MAX_EXTENDED_CAPS {
+            let header = self.read(reg);
+            if header & 0xffff == SriovCapability::ID as u32 {
+                return Some(reg);
+            }
+
+            reg = (header >> 20) as usize / 4;
+            if reg < PCI_CONFIG_REGS {
+                break;
+            }
+        }
+        None
+    }
+
+    /// Spawn or drop the VFs after a write to the SR-IOV capability at `sriov`, and place their
+    /// BARs.
+    fn update(&mut self, sriov: usize) {
+        let reg = |body| sriov + 1 + body;
+        let control = self.read(reg(SriovCapability::CONTROL));
+        let enabled = control & SriovCapability::VF_ENABLE != 0;
+
+        if enabled && !self.enabled {
+            let num_vfs = self.read(reg(SriovCapability::NUM_VFS)) & 0xffff;
+            let offsets = self.read(reg(SriovCapability::VF_OFFSET));
+            let (first, stride) = (offsets & 0xffff, offsets >> 16);
+            let pf = self.bdf;
+            let new_vf = &mut self.new_vf;
+            self.vfs = (0..num_vfs)
+                .map(|i| {
+                    let bdf = pf.wrapping_add((first + i * stride) as u16);
+                    (bdf, new_vf(bdf))
+                })
+                .collect();
+            debug!("{} VFs of {:#x} enabled", num_vfs, pf);
+        } else if !enabled && self.enabled {
+            self.vfs.clear();
+            debug!("VFs of {:#x} disabled", self.bdf);
+        }
+        self.enabled = enabled;
+
+        self.bars.clear();
+        if enabled && control & SriovCapability::VF_MSE != 0 {
+            self.place_bars(reg(SriovCapability::VF_BAR0));
+        }
+    }
+
+    /// Find the VF BARs starting at `bar0`. The size of a VF BAR is what it reads after
+    /// writing all 1s, like software sizes it.
+    fn place_bars(&mut self, bar0: usize) {
+        let mut i = 0;
+        while i < 6 {
+            let reg = bar0 + i;
+            let low = self.read(reg);
+            self.write(reg, u32::MAX);
+            let mask = self.read(reg);
+            self.write(reg, low);
+
+            let is_64bit = mask & 0x6 == 0x4 && i < 5;
+            let (base, size) = if is_64bit {
+                let high = self.read(reg + 1);
+                self.write(reg + 1, u32::MAX);
+                let mask_high = self.read(reg + 1);
+                self.write(reg + 1, high);
+                let mask = (mask_high as u64) << 32 | (mask & 0xffff_fff0) as u64;
+                let base = (high as u64) << 32 | (low & 0xffff_fff0) as u64;
+                (base, (!mask).wrapping_add(1))
+            } else {
+                let size = (!(mask & 0xffff_fff0)).wrapping_add(1) as u64;
+                ((low & 0xffff_fff0) as u64, size)
+            };
+
+            // An unimplemented VF BAR reads 0, an unplaced one has no base.
+            if size != 0 && base != 0 {
+                self.bars.push((base, size));
+            }
+            i += if is_64bit { 2 } else { 1 };
+        }
+    }
+
+    /// The VF whose BAR holds `addr`, if any.
+    fn vf_at(&self, addr: u64) -> Option<usize> {
+        let vfs = self.vfs.len() as u64;
+        self.bars.iter().find_map(|&(base, size)| {
+            let index = addr.checked_sub(base)? / size;
+            if index < vfs {
+                Some(index as usize)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Hand `tlp` to the function it is for.
+    fn handle(&mut self, lane: &PciLane, tlp: &Tlp, sriov: Option<usize>) {
+        use PacketType::*;
+
+        let vf = match tlp.header._type {
+            Config0Read(extra) | Config0Write(extra) if extra.completer != self.bdf => {
+                match self.vfs.iter().position(|(bdf, _)| *bdf == extra.completer) {
+                    Some(vf) => Some(vf),
+                    // The VF is disabled, nothing answers at its routing ID.
+                    None => return reject(lane, tlp, extra.completer),
+                }
+            }
+            MemoryRead(extra) | MemoryWrite(extra) => self.vf_at(extra.addr as u64),
+            MemoryRead64(extra) | MemoryWrite64(extra) => self.vf_at(extra.addr),
+            _ => None,
+        };
+
+        if let Some(vf) = vf {
+            let (bdf, vf) = &mut self.vfs[vf];
+            let mut completer = *bdf;
+            dispatch(vf, lane, tlp, &mut completer);
+            return;
+        }
+
+        dispatch(&mut self.pf, lane, tlp, &mut self.completer);
+        if let (Config0Write(extra), Some(sriov)) = (tlp.header._type, sriov) {
+            if (sriov + 1..sriov + 16).contains(&(extra.reg as usize)) {
+                self.update(sriov);
+            }
+        }
+    }
+}
+
+impl<P, V> PciSimDevice for PciSriovDevice<P, V>
+where
+    P: SimpleDevice,
+    V: SimpleDevice,
+{
+    fn on_start(&mut self, info: &LaneInfo) {
+        self.bdf = info.functions[0];
+        self.completer = self.bdf;
+    }
+
+    /// A reset of the PF or a hot reset puts every VF back to its initial state, an FLR of a VF
+    /// only that VF.
+    fn on_reset(&mut self, kind: ResetKind) {
+        let pf = self.bdf;
+        let new_vf = &mut self.new_vf;
+        for (bdf, vf) in self.vfs.iter_mut() {
+            let reset = match kind {
+                ResetKind::Hot => true,
+                ResetKind::FunctionLevel(function) => function == pf || function == *bdf,
+            };
+            if reset {
+                *vf = new_vf(*bdf);
+            }
+        }
+    }
+
+    fn run(&mut self, lane: &PciLane) {
+        let sriov = self.find_sriov();
+        if sriov.is_none() {
+            error!("PF {:#x} has no SR-IOV capability", self.bdf);
+        }
+
+        while let Ok(tlp) = lane.rx.recv() {
+            self.handle(lane, &tlp, sriov);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::test_allocator;
+
+    use pci::PciMassStorageSubclass;
+
+    const VF_DEVICE_ID: u16 = 0x5679;
+
+    fn pci_config(device_id: u16) -> PciConfiguration {
+        PciConfiguration::new(
+            0x1234,
+            device_id,
+            0,
+            PciClassCode::Other,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0,
+            0,
+            None,
+        )
+    }
+
+    struct Pf(PcieConfiguration);
+
+    impl SimpleDevice for Pf {
+        fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+            self.0.read_config_register(reg)
+        }
+
+        fn on_config_write(&mut self, _: u16, reg: usize, offset: u64, data: &[u8]) {
+            self.0.write_config_register(reg, offset, data);
+        }
+    }
+
+    /// A VF whose BAR reads its routing ID and a scratch register.
+    struct Vf {
+        config: PciConfiguration,
+        bdf: u16,
+        scratch: u32,
+    }
+
+    impl SimpleDevice for Vf {
+        fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+            self.config.read_config_register(reg)
+        }
+
+        fn on_config_write(&mut self, _: u16, reg: usize, offset: u64, data: &[u8]) {
+            self.config.write_config_register(reg, offset, data);
+        }
+
+        fn on_mem_read(&mut self, addr: u64, data: &mut [u8]) -> std::result::Result<(), u8> {
+            let value = match addr & 0xfff {
+                0 => self.bdf as u32,
+                4 => self.scratch,
+                _ => return Err(CPL_UR),
+            };
+            data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+            Ok(())
+        }
+
+        fn on_mem_write(&mut self, addr: u64, data: &[u8]) {
+            if addr & 0xfff == 4 && data.len() == 4 {
+                self.scratch = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            }
+        }
+    }
+
+    #[test]
+    fn virtual_functions() {
+        let mut config = PcieConfiguration::new(pci_config(0x5678));
+        let sriov = SriovCapability::new(4, VF_DEVICE_ID)
+            .vf_offset(2, 2)
+            .vf_bar(
+                0,
+                0x1000,
+                PciBarRegionType::Memory64BitRegion,
+                PciBarPrefetchable::NotPrefetchable,
+            );
+        config.add_extended(&sriov).unwrap();
+        let device = PciSriovDevice::new(Pf(config), |bdf| Vf {
+            config: pci_config(VF_DEVICE_ID),
+            bdf,
+            scratch: 0,
+        });
+        let mut adapter = PciAdapter::start(Box::new(device));
+
+        let mut allocator = test_allocator();
+        assert!(adapter.allocate_bars(&mut allocator).unwrap().is_empty());
+        assert!(matches!(
+            adapter.enable_vfs(5, &mut allocator),
+            Err(PciAdapterError::InvalidVfCount(5))
+        ));
+
+        let mut vfs = adapter.enable_vfs(2, &mut allocator).unwrap();
+        let pf = adapter.bdf();
+        assert_eq!(vfs[0].bdf(), pf + 2);
+        assert_eq!(vfs[1].bdf(), pf + 4);
+        assert_eq!(vfs[1].config_read(0), (VF_DEVICE_ID as u32) << 16 | 0x1234);
+        assert_eq!(adapter.config_read(0), 0x5678_1234);
+
+        // The BARs of the VFs follow each other in VF BAR0.
+        let bars: Vec<_> = vfs
+            .iter_mut()
+            .map(|vf| vf.allocate_bars(&mut allocator).unwrap())
+            .collect();
+        assert_eq!(bars[0].len(), 1);
+        assert_eq!(bars[0][0].1, 0x1000);
+        let (vf0, vf1) = (bars[0][0].0.raw_value(), bars[1][0].0.raw_value());
+        assert_eq!(vf1, vf0 + 0x1000);
+
+        let read = |vf: &PciAdapter, addr: u64| {
+            let mut data = [0u8; 4];
+            vf.try_bar_mmio_read(addr, &mut data).unwrap();
+            u32::from_le_bytes(data)
+        };
+        assert_eq!(read(&vfs[0], vf0), (pf + 2) as u32);
+        assert_eq!(read(&vfs[1], vf1), (pf + 4) as u32);
+        vfs[1].bar_mmio_write(vf1 + 4, &0xcafe_u32.to_le_bytes());
+        assert_eq!(read(&vfs[1], vf1 + 4), 0xcafe);
+        assert_eq!(read(&vfs[0], vf0 + 4), 0);
+
+        // An FLR of a VF leaves the other one alone.
+        vfs[1].function_reset().unwrap();
+        vfs[0].bar_mmio_write(vf0 + 4, &0xbeef_u32.to_le_bytes());
+        assert_eq!(read(&vfs[1], vf1 + 4), 0);
+        assert_eq!(read(&vfs[0], vf0 + 4), 0xbeef);
+
+        adapter.disable_vfs(&mut allocator).unwrap();
+        assert!(vfs[0].try_config_read(0).is_err());
+        assert!(adapter.disable_vfs(&mut allocator).is_err());
+        assert_eq!(adapter.config_read(0), 0x5678_1234);
+
+        adapter.stop();
+        adapter.join();
+    }
+}
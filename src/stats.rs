@@ -0,0 +1,141 @@
+//! Transaction counters of the bridge, read by [`PciAdapter::stats`].
+
+use crate::*;
+
+/// Number of TLPs by type.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TlpCounts {
+    pub memory_read: u64,
+    pub memory_write: u64,
+    pub io_read: u64,
+    pub io_write: u64,
+    pub config_read: u64,
+    pub config_write: u64,
+    pub completion: u64,
+    pub message: u64,
+    /// Every other type, e.g. atomics.
+    pub other: u64,
+}
+
+/// Type of a TLP, as counted in [`TlpCounts`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TlpKind {
+    MemoryRead,
+    MemoryWrite,
+    IoRead,
+    IoWrite,
+    ConfigRead,
+    ConfigWrite,
+    Completion,
+    Message,
+    Other,
+}
+
+impl TlpKind {
+    pub const ALL: [TlpKind; 9] = [
+        TlpKind::MemoryRead,
+        TlpKind::MemoryWrite,
+        TlpKind::IoRead,
+        TlpKind::IoWrite,
+        TlpKind::ConfigRead,
+        TlpKind::ConfigWrite,
+        TlpKind::Completion,
+        TlpKind::Message,
+        TlpKind::Other,
+    ];
+
+    pub fn of(tlp: &Tlp) -> TlpKind {
+        use PacketType::*;
+
+        match tlp.header._type {
+            MemoryRead(_) | MemoryRead64(_) | MemoryReadLock | MemoryReadLock64 => {
+                TlpKind::MemoryRead
+            }
+            MemoryWrite(_) | MemoryWrite64(_) => TlpKind::MemoryWrite,
+            IoRead(_) => TlpKind::IoRead,
+            IoWrite(_) => TlpKind::IoWrite,
+            Config0Read(_) | Config1Read(_) => TlpKind::ConfigRead,
+            Config0Write(_) | Config1Write(_) => TlpKind::ConfigWrite,
+            Completion(_) | CompletionData(_) | CompletionLocked(_) | CompletionLockedData(_) => {
+                TlpKind::Completion
+            }
+            Message(_) | MessageData(_) | RoutedMessage(_) | RoutedMessageData(_)
+            | PageRequest(_) | PrgResponse(_) => TlpKind::Message,
+            _ => TlpKind::Other,
+        }
+    }
+}
+
+impl TlpCounts {
+    pub(crate) fn count(&mut self, tlp: &Tlp) {
+        let counter = match TlpKind::of(tlp) {
+            TlpKind::MemoryRead => &mut self.memory_read,
+            TlpKind::MemoryWrite => &mut self.memory_write,
+            TlpKind::IoRead => &mut self.io_read,
+            TlpKind::IoWrite => &mut self.io_write,
+            TlpKind::ConfigRead => &mut self.config_read,
+            TlpKind::ConfigWrite => &mut self.config_write,
+            TlpKind::Completion => &mut self.completion,
+            TlpKind::Message => &mut self.message,
+            TlpKind::Other => &mut self.other,
+        };
+        *counter += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.memory_read
+            + self.memory_write
+            + self.io_read
+            + self.io_write
+            + self.config_read
+            + self.config_write
+            + self.completion
+            + self.message
+            + self.other
+    }
+}
+
+/// Counters of the traffic between the bridge and the devices since the bridge started.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AdapterStats {
+    /// TLPs sent to the devices.
+    pub sent: TlpCounts,
+    /// TLPs received from the devices.
+    pub received: TlpCounts,
+    /// Payload bytes sent to the devices.
+    pub bytes_sent: u64,
+    /// Payload bytes received from the devices.
+    pub bytes_received: u64,
+    /// Requests of the bridge completed successfully.
+    pub completions: u64,
+    /// Requests of the bridge completed with an unsuccessful status.
+    pub completion_errors: u64,
+    /// Requests of the bridge which did not complete in time.
+    pub timeouts: u64,
+    /// TLPs of the devices the bridge could not handle, e.g. unexpected completions.
+    pub errors: u64,
+    /// Posted TLPs dropped on a full lane, see [`BackpressurePolicy::DropPosted`].
+    pub dropped: u64,
+    /// TLPs of the devices breaking the ordering rules, see [`OrderingPolicy::Strict`].
+    pub violations: u64,
+    /// Requests of the bridge waiting for their completion, each holding a tag.
+    pub outstanding: usize,
+    /// Requests of the adapter waiting for a free tag.
+    pub backlog: usize,
+}
+
+impl AdapterStats {
+    pub(crate) fn count_sent(&mut self, tlp: &Tlp) {
+        self.sent.count(tlp);
+        self.bytes_sent += payload_len(tlp);
+    }
+
+    pub(crate) fn count_received(&mut self, tlp: &Tlp) {
+        self.received.count(tlp);
+        self.bytes_received += payload_len(tlp);
+    }
+}
+
+fn payload_len(tlp: &Tlp) -> u64 {
+    tlp.data.as_ref().map_or(0, |dw| dw.len() as u64 * 4)
+}
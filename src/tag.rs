@@ -0,0 +1,318 @@
+//! Allocation of the tags of non-posted requests.
+
+use crate::*;
+
+use std::collections::{HashMap, HashSet};
+
+/// Number of tags available to a requester without extended tags.
+pub(crate) const DEFAULT_TAGS: usize = 256;
+
+/// The pool of transaction tags of a requester.
+///
+/// A tag stays outstanding from the request until its completion arrives or the transaction
+/// is given up, so a late completion can never be matched with an unrelated request. Tags are
+/// handed out in round-robin order to make the reuse of a tag as late as possible.
+#[derive(Debug)]
+pub(crate) struct TagPool {
+    outstanding: Vec<bool>,
+    next: usize,
+    free: usize,
+}
+
+impl TagPool {
+    pub fn new(size: usize) -> TagPool {
+        assert!(size > 0 && size <= DEFAULT_TAGS);
+
+        TagPool {
+            outstanding: vec![false; size],
+            next: 0,
+            free: size,
+        }
+    }
+
+    /// Allocate a free tag, `None` if every tag is outstanding.
+    pub fn alloc(&mut self) -> Option<u8> {
+        if self.free == 0 {
+            return None;
+        }
+
+        let size = self.outstanding.len();
+        let tag = (0..size)
+            .map(|i| (self.next + i) % size)
+            .find(|&tag| !self.outstanding[tag])?;
+
+        self.outstanding[tag] = true;
+        self.free -= 1;
+        self.next = (tag + 1) % size;
+        Some(tag as u8)
+    }
+
+    /// Mark a tag outstanding, e.g. one kept across a reconnection. Tags beyond the pool are
+    /// ignored.
+    pub fn claim(&mut self, tag: u8) {
+        if let Some(outstanding) = self.outstanding.get_mut(tag as usize) {
+            if !*outstanding {
+                *outstanding = true;
+                self.free -= 1;
+            }
+        }
+    }
+
+    /// Return a tag to the pool, freeing a tag which is not outstanding is a no-op.
+    pub fn free(&mut self, tag: u8) {
+        if let Some(outstanding) = self.outstanding.get_mut(tag as usize) {
+            if *outstanding {
+                *outstanding = false;
+                self.free += 1;
+            }
+        }
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.free == 0
+    }
+}
+
+/// What a completion taken by [`TagTracker::complete`] did to its request.
+#[derive(Debug, PartialEq)]
+pub enum Tracked<T> {
+    /// More completions are due, or the request was abandoned.
+    Pending,
+    /// Every byte arrived, in the order of the addresses.
+    Done { context: T, data: Vec<u8> },
+    /// The request is completed with a non-successful status.
+    Failed { context: T, status: u8 },
+}
+
+/// A request waiting for its completions.
+#[derive(Debug)]
+struct Outstanding<T> {
+    context: T,
+    data: Vec<u8>,
+}
+
+/// The non-posted requests a device model issues on its own, e.g. DMA reads or ATS
+/// translation requests.
+///
+/// The tracker hands out the tags of the requester, matches the completions coming down the
+/// lane with their requests and puts the data of split completions back together. Each request
+/// carries a context of the model, returned with its data.
+#[derive(Debug)]
+pub struct TagTracker<T> {
+    requester: u16,
+    tags: TagPool,
+    outstanding: HashMap<u8, Outstanding<T>>,
+    /// Tags of the requests given up, kept until their completions arrive.
+    abandoned: HashSet<u8>,
+}
+
+impl<T> TagTracker<T> {
+    /// The requests of function `requester` with `tags` tags, e.g. 32 without extended tags.
+    pub fn new(requester: u16, tags: usize) -> Self {
+        TagTracker {
+            requester,
+            tags: TagPool::new(tags.clamp(1, DEFAULT_TAGS)),
+            outstanding: HashMap::new(),
+            abandoned: HashSet::new(),
+        }
+    }
+
+    /// Give `request` a free tag and track it until it completes. Returns the tag, or `None`
+    /// if every tag is outstanding.
+    pub fn track(&mut self, request: &mut Tlp, context: T) -> Option<u8> {
+        let tag = self.tags.alloc()?;
+        request.header.set_tag(tag);
+        self.outstanding.insert(
+            tag,
+            Outstanding {
+                context,
+                data: vec![],
+            },
+        );
+        Some(tag)
+    }
+
+    /// Take `tlp` if it completes a tracked request, `None` for every other TLP.
+    pub fn complete(&mut self, tlp: &Tlp) -> Option<Tracked<T>> {
+        let extra = match tlp.header._type {
+            PacketType::Completion(extra) | PacketType::CompletionData(extra)
+                if extra.requester == self.requester =>
+            {
+                extra
+            }
+            _ => return None,
+        };
+        if self.abandoned.remove(&extra.tag) {
+            self.tags.free(extra.tag);
+            return Some(Tracked::Pending);
+        }
+        let outstanding = self.outstanding.get_mut(&extra.tag)?;
+
+        // The byte count is what is left of the request, this completion included, with 0
+        // standing for 4096 bytes.
+        let remaining = match extra.byte_count {
+            0 => 4096,
+            count => count as usize,
+        };
+        let done = match (extra.status, tlp.data.as_ref()) {
+            (CPL_SC, Some(dw)) => {
+                let skip = (extra.lower_address & 0b11) as usize;
+                let bytes = dw.iter().flat_map(|dw| dw.to_be_bytes()).skip(skip);
+                let before = outstanding.data.len();
+                outstanding.data.extend(bytes.take(remaining));
+                outstanding.data.len() - before >= remaining
+            }
+            _ => true,
+        };
+        if !done {
+            return Some(Tracked::Pending);
+        }
+
+        let outstanding = self.outstanding.remove(&extra.tag).unwrap();
+        self.tags.free(extra.tag);
+        Some(match extra.status {
+            CPL_SC => Tracked::Done {
+                context: outstanding.context,
+                data: outstanding.data,
+            },
+            status => Tracked::Failed {
+                context: outstanding.context,
+                status,
+            },
+        })
+    }
+
+    /// Give up the request of `tag`, e.g. on a completion timeout. The tag is only reused once
+    /// its completion arrives, which is then dropped.
+    pub fn abandon(&mut self, tag: u8) -> Option<T> {
+        let outstanding = self.outstanding.remove(&tag)?;
+        self.abandoned.insert(tag);
+        Some(outstanding.context)
+    }
+
+    /// Give up every request, e.g. on a reset. The tags are free at once, since the
+    /// completions of the old lane never arrive.
+    pub fn clear(&mut self) -> Vec<T> {
+        for tag in self.outstanding.keys().chain(self.abandoned.iter()) {
+            self.tags.free(*tag);
+        }
+        self.abandoned.clear();
+        self.outstanding.drain().map(|(_, o)| o.context).collect()
+    }
+
+    /// Number of requests waiting for their completions.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.tags.is_exhausted()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recycle() {
+        let mut pool = TagPool::new(4);
+
+        assert_eq!(pool.alloc(), Some(0));
+        assert_eq!(pool.alloc(), Some(1));
+        pool.free(0);
+        assert_eq!(pool.alloc(), Some(2));
+        assert_eq!(pool.alloc(), Some(3));
+        assert_eq!(pool.alloc(), Some(0));
+        assert!(pool.is_exhausted());
+        assert_eq!(pool.alloc(), None);
+
+        pool.free(2);
+        pool.free(2);
+        assert!(!pool.is_exhausted());
+        assert_eq!(pool.alloc(), Some(2));
+        assert_eq!(pool.alloc(), None);
+    }
+
+    fn completion(tag: u8, byte_count: u16, lower_address: u8, data: Option<Vec<u32>>) -> Tlp {
+        let extra = CompletionExtra {
+            requester: 0x0100,
+            completer: 0,
+            tag,
+            status: CPL_SC,
+            bcm: false,
+            byte_count,
+            lower_address,
+        };
+        match data {
+            Some(data) => TlpBuilder::completion_data(extra)
+                .length(data.len() as u16)
+                .data(data)
+                .build(),
+            None => TlpBuilder::completion(extra).build(),
+        }
+    }
+
+    #[test]
+    fn tracker() {
+        let mut tracker = TagTracker::new(0x0100, 2);
+        let read = |addr| {
+            TlpBuilder::memory_read(MemoryExtra {
+                requester: 0x0100,
+                tag: 0,
+                addr,
+            })
+            .build()
+        };
+
+        // 6 bytes at 0x3e, split at the 64 byte boundary.
+        let mut first = read(0x3c);
+        assert_eq!(tracker.track(&mut first, "first"), Some(0));
+        let mut second = read(0x80);
+        assert_eq!(tracker.track(&mut second, "second"), Some(1));
+        assert_eq!(second.header.tag(), Some(1));
+        assert!(tracker.is_exhausted());
+
+        let cpl = completion(0, 6, 0x3e, Some(vec![0x0000_0102]));
+        assert_eq!(tracker.complete(&cpl), Some(Tracked::Pending));
+        let cpl = completion(0, 4, 0x40, Some(vec![0x0304_0506]));
+        assert_eq!(
+            tracker.complete(&cpl),
+            Some(Tracked::Done {
+                context: "first",
+                data: vec![1, 2, 3, 4, 5, 6],
+            })
+        );
+
+        // A completion of another requester is not taken.
+        let mut other = completion(1, 4, 0, None);
+        if let PacketType::Completion(extra) = &mut other.header._type {
+            extra.requester = 0x0200;
+        }
+        assert_eq!(tracker.complete(&other), None);
+
+        assert_eq!(tracker.abandon(1), Some("second"));
+        assert_eq!(tracker.outstanding(), 0);
+        // The tag of the abandoned request is only free once its completion arrived.
+        let mut third = read(0x100);
+        assert_eq!(tracker.track(&mut third, "third"), Some(0));
+        assert!(tracker.is_exhausted());
+        assert_eq!(
+            tracker.complete(&completion(1, 4, 0, None)),
+            Some(Tracked::Pending)
+        );
+        assert!(!tracker.is_exhausted());
+
+        let mut cpl = completion(0, 4, 0, None);
+        if let PacketType::Completion(extra) = &mut cpl.header._type {
+            extra.status = CPL_UR;
+        }
+        assert_eq!(
+            tracker.complete(&cpl),
+            Some(Tracked::Failed {
+                context: "third",
+                status: CPL_UR,
+            })
+        );
+    }
+}
@@ -0,0 +1,586 @@
+//! A mapping between TLPs and the generic payloads of SystemC TLM-2.0.
+//!
+//! Device IP modeled at the transaction level takes reads and writes of a generic payload
+//! rather than TLPs. [`TlmDevice`] turns the requests of the bridge into [`GenericPayload`]s,
+//! passes them to a [`TlmTarget`] by the blocking or the non-blocking transport, and completes
+//! the non-posted ones with the response status of the target. In the other direction a
+//! [`TlmInitiator`] issues the generic payloads of the IP as DMA on the lane.
+//!
+//! The lane runs in wall clock time, so the delays annotated by a target are left to the
+//! simulation kernel of the target and never hold back a completion.
+
+use crate::*;
+
+use crate::device::reject;
+use crate::dma::enabled_bytes;
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// A payload and phase sent on the backward path.
+type Backward = (GenericPayload, TlmPhase);
+
+/// `tlm_command`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlmCommand {
+    Read = 0,
+    Write = 1,
+    Ignore = 2,
+}
+
+/// `tlm_response_status`, with the values of the standard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlmResponse {
+    Ok = 1,
+    Incomplete = 0,
+    GenericError = -1,
+    AddressError = -2,
+    CommandError = -3,
+    BurstError = -4,
+    ByteEnableError = -5,
+}
+
+impl TlmResponse {
+    /// The completion status a request answered with this response gets.
+    pub fn completion_status(self) -> u8 {
+        match self {
+            TlmResponse::Ok => CPL_SC,
+            TlmResponse::AddressError | TlmResponse::CommandError => CPL_UR,
+            _ => CPL_CA,
+        }
+    }
+}
+
+/// The address space of a request, which the generic payload lacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlmSpace {
+    Memory,
+    Io,
+    /// The configuration space of a function, addressed by byte.
+    Config(u16),
+}
+
+/// `tlm_phase` of the base protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlmPhase {
+    BeginReq,
+    EndReq,
+    BeginResp,
+    EndResp,
+}
+
+/// `tlm_sync_enum`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlmSync {
+    Accepted,
+    Updated,
+    Completed,
+}
+
+/// A TLM-2.0 generic payload, with the address space of the request as an extension.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenericPayload {
+    /// Identifies the transaction on the backward path, like the address of a payload does.
+    pub id: u64,
+    pub command: TlmCommand,
+    pub space: TlmSpace,
+    pub address: u64,
+    /// The bytes in memory order. The bytes of a config register are little endian.
+    pub data: Vec<u8>,
+    /// 0xff for an enabled byte and 0 otherwise. Empty enables every byte.
+    pub byte_enable: Vec<u8>,
+    pub streaming_width: u32,
+    pub response: TlmResponse,
+}
+
+impl GenericPayload {
+    /// A payload of `len` bytes at `address`, with every byte enabled.
+    pub fn new(command: TlmCommand, space: TlmSpace, address: u64, len: usize) -> Self {
+        GenericPayload {
+            id: 0,
+            command,
+            space,
+            address,
+            data: vec![0; len],
+            byte_enable: vec![],
+            streaming_width: len as u32,
+            response: TlmResponse::Incomplete,
+        }
+    }
+
+    /// Whether byte `i` of the data is enabled.
+    pub fn enabled(&self, i: usize) -> bool {
+        self.byte_enable.is_empty() || self.byte_enable[i % self.byte_enable.len()] != 0
+    }
+
+    /// The payload of a memory, IO or type 0 config request, or `None` for other TLPs. The
+    /// payload spans the DWs of the request, the bytes outside its byte enables disabled.
+    pub fn from_request(tlp: &Tlp) -> Option<GenericPayload> {
+        use PacketType::*;
+
+        let (command, space, address) = match tlp.header._type {
+            MemoryRead(e) => (TlmCommand::Read, TlmSpace::Memory, e.addr as u64),
+            MemoryRead64(e) => (TlmCommand::Read, TlmSpace::Memory, e.addr),
+            MemoryWrite(e) => (TlmCommand::Write, TlmSpace::Memory, e.addr as u64),
+            MemoryWrite64(e) => (TlmCommand::Write, TlmSpace::Memory, e.addr),
+            IoRead(e) => (TlmCommand::Read, TlmSpace::Io, e.addr as u64),
+            IoWrite(e) => (TlmCommand::Write, TlmSpace::Io, e.addr as u64),
+            Config0Read(e) => (
+                TlmCommand::Read,
+                TlmSpace::Config(e.completer),
+                e.reg as u64 * 4,
+            ),
+            Config0Write(e) => (
+                TlmCommand::Write,
+                TlmSpace::Config(e.completer),
+                e.reg as u64 * 4,
+            ),
+            _ => return None,
+        };
+        let length = match (space, tlp.header.length) {
+            (TlmSpace::Memory, 0) => 1024,
+            (TlmSpace::Memory, len) => len as usize,
+            _ => 1,
+        };
+
+        let mut payload = GenericPayload::new(command, space, address & !0b11, length * 4);
+        payload.byte_enable = enabled_bytes(length, tlp.header.byte_enable)
+            .into_iter()
+            .map(|enabled| if enabled { 0xff } else { 0 })
+            .collect();
+        if command == TlmCommand::Write {
+            let data = tlp.data.as_deref().unwrap_or(&[]);
+            for (bytes, dw) in payload.data.chunks_mut(4).zip(data) {
+                bytes.copy_from_slice(&match space {
+                    TlmSpace::Config(_) => dw.to_le_bytes(),
+                    _ => dw.to_be_bytes(),
+                });
+            }
+        }
+        Some(payload)
+    }
+
+    /// The completion of the non-posted `request` this payload is made from, by `completer`.
+    /// `None` for a posted request.
+    pub fn completion(&self, request: &Tlp, completer: u16) -> Option<Tlp> {
+        use PacketType::*;
+
+        let (requester, tag) = match request.header._type {
+            MemoryRead(e) | IoRead(e) | IoWrite(e) => (e.requester, e.tag),
+            MemoryRead64(e) => (e.requester, e.tag),
+            Config0Read(e) | Config0Write(e) => (e.requester, e.tag),
+            _ => return None,
+        };
+        let first = (0..self.data.len()).find(|&i| self.enabled(i)).unwrap_or(0);
+        let last = (0..self.data.len())
+            .rfind(|&i| self.enabled(i))
+            .map_or(first, |i| i + 1);
+        let mut cpl = CompletionExtra {
+            requester,
+            completer,
+            tag,
+            status: self.response.completion_status(),
+            bcm: false,
+            byte_count: 4,
+            lower_address: 0,
+        };
+        if self.space == TlmSpace::Memory {
+            // A byte count of 0 stands for 4096 bytes.
+            cpl.byte_count = ((last - first) & 0xfff) as u16;
+            cpl.lower_address = ((self.address as usize + first) & 0x7f) as u8;
+        }
+
+        if cpl.status != CPL_SC || self.command == TlmCommand::Write {
+            return Some(TlpBuilder::completion(cpl).build());
+        }
+        let data = self
+            .data
+            .chunks(4)
+            .map(|b| match self.space {
+                TlmSpace::Config(_) => u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                _ => u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+            })
+            .collect();
+        Some(
+            TlpBuilder::completion_data(cpl)
+                .byte_enable(request.header.byte_enable)
+                .data(data)
+                .build(),
+        )
+    }
+}
+
+/// The backward path of the non-blocking transport, given to a target by
+/// [`TlmTarget::bind`].
+#[derive(Clone)]
+pub struct TlmBackward {
+    tx: Sender<Backward>,
+}
+
+impl TlmBackward {
+    /// `nb_transport_bw`. The target sends [`TlmPhase::BeginResp`] once it has the response of
+    /// a transaction it accepted, and is answered with [`TlmPhase::EndResp`] on the forward
+    /// path. Fails once the device is gone.
+    pub fn nb_transport_bw(
+        &self,
+        payload: GenericPayload,
+        phase: TlmPhase,
+        _delay: Duration,
+    ) -> TlmSync {
+        match self.tx.send((payload, phase)) {
+            Ok(()) => TlmSync::Accepted,
+            Err(_) => TlmSync::Completed,
+        }
+    }
+}
+
+/// The target socket of TLM device IP.
+pub trait TlmTarget {
+    /// `b_transport`: carry out the transaction and set its response status.
+    fn b_transport(&mut self, payload: &mut GenericPayload, delay: &mut Duration);
+
+    /// `nb_transport_fw` of the base protocol. By default the transaction is carried out by
+    /// [`TlmTarget::b_transport`] and completed right away. A target answering later keeps
+    /// the payload, returns [`TlmSync::Accepted`] and sends the response on the backward path.
+    fn nb_transport_fw(
+        &mut self,
+        payload: &mut GenericPayload,
+        phase: &mut TlmPhase,
+        delay: &mut Duration,
+    ) -> TlmSync {
+        match *phase {
+            TlmPhase::BeginReq => {
+                self.b_transport(payload, delay);
+                *phase = TlmPhase::BeginResp;
+                TlmSync::Completed
+            }
+            _ => TlmSync::Completed,
+        }
+    }
+
+    /// Called before the first transaction with the backward path of the non-blocking
+    /// transport.
+    fn bind(&mut self, _backward: TlmBackward) {}
+}
+
+/// A device model whose requests are carried out by a [`TlmTarget`].
+pub struct TlmDevice<T> {
+    target: T,
+    backward: (Sender<Backward>, Receiver<Backward>),
+    /// The requests the target accepted and has not answered yet, by payload ID.
+    pending: HashMap<u64, Tlp>,
+    next_id: u64,
+    /// The completer ID, taken from the config requests.
+    completer: u16,
+}
+
+impl<T: TlmTarget> TlmDevice<T> {
+    pub fn new(mut target: T) -> Self {
+        let backward = unbounded();
+        target.bind(TlmBackward {
+            tx: backward.0.clone(),
+        });
+        TlmDevice {
+            target,
+            backward,
+            pending: HashMap::new(),
+            next_id: 0,
+            completer: 0,
+        }
+    }
+
+    pub fn target(&self) -> &T {
+        &self.target
+    }
+
+    pub fn target_mut(&mut self) -> &mut T {
+        &mut self.target
+    }
+
+    /// Start the transaction of `tlp` by the non-blocking transport.
+    fn request(&mut self, lane: &PciLane, tlp: Tlp) {
+        let mut payload = match GenericPayload::from_request(&tlp) {
+            Some(payload) => payload,
+            None => return reject(lane, &tlp, self.completer),
+        };
+        if let TlmSpace::Config(function) = payload.space {
+            self.completer = function;
+        }
+        payload.id = self.next_id;
+        self.next_id += 1;
+
+        let mut phase = TlmPhase::BeginReq;
+        let mut delay = Duration::default();
+        let sync = self
+            .target
+            .nb_transport_fw(&mut payload, &mut phase, &mut delay);
+        match (sync, phase) {
+            (TlmSync::Completed, _) | (TlmSync::Updated, TlmPhase::BeginResp) => {
+                self.respond(lane, &tlp, &payload, sync)
+            }
+            _ => {
+                self.pending.insert(payload.id, tlp);
+            }
+        }
+    }
+
+    /// Complete the request of `payload` whose response the target sent.
+    fn respond(&mut self, lane: &PciLane, tlp: &Tlp, payload: &GenericPayload, sync: TlmSync) {
+        if let Some(cpl) = payload.completion(tlp, self.completer) {
+            let _ = lane.tx.send(cpl);
+        }
+        if sync != TlmSync::Completed {
+            let mut payload = payload.clone();
+            let mut phase = TlmPhase::EndResp;
+            self.target
+                .nb_transport_fw(&mut payload, &mut phase, &mut Duration::default());
+        }
+    }
+}
+
+impl<T: TlmTarget> PciSimDevice for TlmDevice<T> {
+    fn run(&mut self, lane: &PciLane) {
+        let backward = self.backward.1.clone();
+        loop {
+            select! {
+                recv(lane.rx) -> tlp => match tlp {
+                    Ok(tlp) => self.request(lane, tlp),
+                    Err(_) => break,
+                },
+                recv(backward) -> msg => {
+                    let (payload, phase) = msg.unwrap();
+                    if phase != TlmPhase::BeginResp {
+                        continue;
+                    }
+                    match self.pending.remove(&payload.id) {
+                        Some(tlp) => self.respond(lane, &tlp, &payload, TlmSync::Accepted),
+                        None => error!("Response of unknown transaction {}", payload.id),
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_reset(&mut self, _kind: ResetKind) {
+        // The requests of the lane which is gone are never completed.
+        self.pending.clear();
+    }
+}
+
+/// The initiator socket of TLM device IP, which issues its transactions as DMA on the lane.
+///
+/// PCIe has no streaming bursts and only enables the bytes of the first and last DW, so
+/// payloads with a streaming width shorter than their data fail with
+/// [`TlmResponse::BurstError`] and those with byte enables with
+/// [`TlmResponse::ByteEnableError`].
+pub struct TlmInitiator<'a> {
+    dma: DmaHandle<'a>,
+}
+
+impl<'a> TlmInitiator<'a> {
+    pub fn new(dma: DmaHandle<'a>) -> Self {
+        TlmInitiator { dma }
+    }
+
+    /// The DMA handle, whose [`DmaHandle::recv`] takes the requests of the host.
+    pub fn dma(&mut self) -> &mut DmaHandle<'a> {
+        &mut self.dma
+    }
+
+    /// `b_transport`.
+    pub fn b_transport(&mut self, payload: &mut GenericPayload, _delay: &mut Duration) {
+        payload.response = if payload.space != TlmSpace::Memory {
+            TlmResponse::CommandError
+        } else if (payload.streaming_width as usize) < payload.data.len() {
+            TlmResponse::BurstError
+        } else if payload.byte_enable.iter().any(|&b| b != 0xff) {
+            TlmResponse::ByteEnableError
+        } else {
+            let result = match payload.command {
+                TlmCommand::Read => self
+                    .dma
+                    .read(payload.address, payload.data.len())
+                    .map(|data| payload.data = data),
+                TlmCommand::Write => self.dma.write(payload.address, &payload.data),
+                TlmCommand::Ignore => Ok(()),
+            };
+            match result {
+                Ok(()) => TlmResponse::Ok,
+                Err(PciAdapterError::Completion(CPL_UR)) => TlmResponse::AddressError,
+                Err(_) => TlmResponse::GenericError,
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    /// 256 bytes of registers behind the memory and IO space, with the IDs in config space.
+    struct Registers {
+        bytes: Vec<u8>,
+    }
+
+    impl TlmTarget for Registers {
+        fn b_transport(&mut self, payload: &mut GenericPayload, _: &mut Duration) {
+            if let TlmSpace::Config(_) = payload.space {
+                let ids: u32 = if payload.address == 0 { 0x5678_1234 } else { 0 };
+                if payload.command == TlmCommand::Read {
+                    payload.data.copy_from_slice(&ids.to_le_bytes());
+                }
+                payload.response = TlmResponse::Ok;
+                return;
+            }
+            let at = (payload.address & 0xff) as usize;
+            if at + payload.data.len() > self.bytes.len() {
+                payload.response = TlmResponse::AddressError;
+                return;
+            }
+            for i in 0..payload.data.len() {
+                if !payload.enabled(i) {
+                    continue;
+                }
+                match payload.command {
+                    TlmCommand::Read => payload.data[i] = self.bytes[at + i],
+                    TlmCommand::Write => self.bytes[at + i] = payload.data[i],
+                    TlmCommand::Ignore => (),
+                }
+            }
+            payload.response = TlmResponse::Ok;
+        }
+    }
+
+    #[test]
+    fn payload() {
+        let tlp = TlpBuilder::memory_write(MemoryExtra {
+            requester: 0,
+            tag: 0,
+            addr: 0x1000,
+        })
+        .length(2)
+        .byte_enable(0x3c)
+        .data(vec![0x0102_0304, 0x0506_0708])
+        .build();
+        let payload = GenericPayload::from_request(&tlp).unwrap();
+        assert_eq!(payload.command, TlmCommand::Write);
+        assert_eq!(payload.address, 0x1000);
+        assert_eq!(payload.data, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(payload.byte_enable, [0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0]);
+        assert!(payload.completion(&tlp, 0).is_none());
+
+        let tlp = TlpBuilder::io_read(MemoryExtra {
+            requester: 0x10,
+            tag: 7,
+            addr: 0x40,
+        })
+        .byte_enable(0x0f)
+        .build();
+        let mut payload = GenericPayload::from_request(&tlp).unwrap();
+        payload.response = TlmResponse::AddressError;
+        let cpl = payload.completion(&tlp, 0x20).unwrap();
+        match cpl.header._type {
+            PacketType::Completion(extra) => {
+                assert_eq!(extra.status, CPL_UR);
+                assert_eq!(
+                    (extra.requester, extra.completer, extra.tag),
+                    (0x10, 0x20, 7)
+                );
+            }
+            _ => panic!("no completion"),
+        }
+    }
+
+    #[test]
+    fn tlm_device() {
+        let registers = Registers {
+            bytes: (0..=255).collect(),
+        };
+        let (host, model) = PciLane::pair();
+        let device = std::thread::spawn(move || TlmDevice::new(registers).run(&model));
+
+        let read = TlpBuilder::memory_read(MemoryExtra {
+            requester: 0,
+            tag: 1,
+            addr: 0x1004,
+        })
+        .length(1)
+        .byte_enable(0x0f)
+        .build();
+        host.tx.send(read).unwrap();
+        let cpl = host.rx.recv().unwrap();
+        assert_eq!(cpl.data, Some(vec![0x0405_0607]));
+
+        let read = TlpBuilder::memory_read(MemoryExtra {
+            requester: 0,
+            tag: 2,
+            addr: 0x10fc,
+        })
+        .length(2)
+        .byte_enable(0xff)
+        .build();
+        host.tx.send(read).unwrap();
+        match host.rx.recv().unwrap().header._type {
+            PacketType::Completion(extra) => assert_eq!(extra.status, CPL_UR),
+            _ => panic!("no completion"),
+        }
+
+        drop(host);
+        device.join().unwrap();
+    }
+
+    /// A target which answers on the backward path from a thread of its own.
+    struct Deferred {
+        backward: Option<TlmBackward>,
+        answered: Arc<Mutex<Vec<TlmPhase>>>,
+    }
+
+    impl TlmTarget for Deferred {
+        fn b_transport(&mut self, payload: &mut GenericPayload, _: &mut Duration) {
+            payload.data.copy_from_slice(&0xabcd_0001u32.to_le_bytes());
+            payload.response = TlmResponse::Ok;
+        }
+
+        fn nb_transport_fw(
+            &mut self,
+            payload: &mut GenericPayload,
+            phase: &mut TlmPhase,
+            delay: &mut Duration,
+        ) -> TlmSync {
+            self.answered.lock().unwrap().push(*phase);
+            if *phase == TlmPhase::BeginReq {
+                let mut payload = payload.clone();
+                self.b_transport(&mut payload, delay);
+                let backward = self.backward.clone().unwrap();
+                std::thread::spawn(move || {
+                    backward.nb_transport_bw(payload, TlmPhase::BeginResp, Duration::default())
+                });
+            }
+            TlmSync::Accepted
+        }
+
+        fn bind(&mut self, backward: TlmBackward) {
+            self.backward = Some(backward);
+        }
+    }
+
+    #[test]
+    fn nb_transport() {
+        let answered = Arc::new(Mutex::new(vec![]));
+        let target = Deferred {
+            backward: None,
+            answered: answered.clone(),
+        };
+        let adapter = PciAdapter::start(Box::new(TlmDevice::new(target)));
+        assert_eq!(adapter.try_config_read(0), Ok(0xabcd_0001));
+
+        adapter.stop();
+        adapter.join();
+        assert_eq!(
+            answered.lock().unwrap()[..2],
+            [TlmPhase::BeginReq, TlmPhase::EndResp]
+        );
+    }
+}
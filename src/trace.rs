@@ -0,0 +1,31 @@
+//! Observers of the TLPs crossing the bridge.
+//!
+//! Every TLP the bridge sends to or receives from the devices is shown to the installed
+//! [`TlpObserver`]s before it is handled, e.g. to log the traffic, check the protocol or
+//! collect coverage without touching the bridge.
+
+use crate::*;
+
+/// Direction of a TLP, as seen from the bridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// From the bridge to a device.
+    Downstream,
+    /// From a device to the bridge.
+    Upstream,
+}
+
+/// Hook seeing every TLP crossing the bridge. It runs on the bridge thread, so it should
+/// return quickly.
+pub trait TlpObserver: Send {
+    fn observe(&mut self, direction: Direction, tlp: &Tlp);
+}
+
+impl<F> TlpObserver for F
+where
+    F: FnMut(Direction, &Tlp) + Send,
+{
+    fn observe(&mut self, direction: Direction, tlp: &Tlp) {
+        self(direction, tlp)
+    }
+}
@@ -0,0 +1,860 @@
+//! A device model served by an external vfio-user device process.
+//!
+//! [`VfioUserDevice`] is the client side of the vfio-user protocol: it connects to the socket
+//! of a vfio-user server, e.g. an SPDK target or one of the libvfio-user samples, and stands
+//! for the device of the server behind the bridge:
+//!
+//! - config requests become region accesses of the config region, memory and IO requests
+//!   accesses of the region of the BAR they hit, at the addresses the guest programmed into
+//!   the BARs of the server,
+//! - the DMA the server asks for with `VFIO_USER_DMA_READ` and `VFIO_USER_DMA_WRITE` goes to
+//!   guest memory as memory requests of the function, within the windows announced by
+//!   [`VfioUserDevice::dma_window`],
+//! - the server triggers its MSI-X vectors, or its MSI vectors if it has no MSI-X, through the
+//!   eventfds handed over at connection, which raise the interrupts of the function.
+//!
+//! The server is never handed file descriptors of guest memory, so it does its DMA through
+//! messages. INTx is not forwarded.
+
+use crate::*;
+
+use crate::adapter::BAR0_REG;
+use crate::device::dispatch;
+use crate::remote::{eventfd, send_with_fds};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+const VFIO_USER_VERSION: u16 = 1;
+const VFIO_USER_DMA_MAP: u16 = 2;
+const VFIO_USER_DEVICE_GET_INFO: u16 = 4;
+const VFIO_USER_DEVICE_GET_REGION_INFO: u16 = 5;
+const VFIO_USER_DEVICE_GET_IRQ_INFO: u16 = 7;
+const VFIO_USER_DEVICE_SET_IRQS: u16 = 8;
+const VFIO_USER_REGION_READ: u16 = 9;
+const VFIO_USER_REGION_WRITE: u16 = 10;
+const VFIO_USER_DMA_READ: u16 = 11;
+const VFIO_USER_DMA_WRITE: u16 = 12;
+const VFIO_USER_DEVICE_RESET: u16 = 13;
+
+/// Message ID, command, size, flags and error.
+const HEADER_LEN: usize = 16;
+const FLAGS_REPLY: u32 = 0x1;
+const FLAGS_ERROR: u32 = 0x20;
+/// Largest data of a message, the default of the protocol.
+const MAX_DATA_XFER: usize = 1 << 20;
+
+const VFIO_DEVICE_FLAGS_PCI: u32 = 0x2;
+const VFIO_REGION_INFO_FLAG_READ: u32 = 0x1;
+const VFIO_PCI_CONFIG_REGION_INDEX: u32 = 7;
+const VFIO_PCI_MSI_IRQ_INDEX: u32 = 1;
+const VFIO_PCI_MSIX_IRQ_INDEX: u32 = 2;
+const VFIO_IRQ_SET_DATA_EVENTFD: u32 = 0x4;
+const VFIO_IRQ_SET_ACTION_TRIGGER: u32 = 0x20;
+const VFIO_USER_F_DMA_REGION_READ: u32 = 0x1;
+const VFIO_USER_F_DMA_REGION_WRITE: u32 = 0x2;
+
+/// The capabilities of the client, sent with its version.
+const CAPABILITIES: &str = r#"{"capabilities":{"max_msg_fds":8,"max_data_xfer_size":1048576}}"#;
+/// How often the model looks for DMA requests and interrupts of the server.
+const VFIO_USER_POLL: Duration = Duration::from_millis(1);
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u32_at(buf, offset) as u64 | (u32_at(buf, offset + 4) as u64) << 32
+}
+
+/// A message of either peer.
+struct Message {
+    id: u16,
+    command: u16,
+    flags: u32,
+    error: u32,
+    payload: Vec<u8>,
+}
+
+impl Message {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.extend_from_slice(&self.id.to_le_bytes());
+        buf.extend_from_slice(&self.command.to_le_bytes());
+        buf.extend_from_slice(&((HEADER_LEN + self.payload.len()) as u32).to_le_bytes());
+        buf.extend_from_slice(&self.flags.to_le_bytes());
+        buf.extend_from_slice(&self.error.to_le_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    fn read_from<R: Read>(stream: &mut R) -> io::Result<Message> {
+        let mut header = [0u8; HEADER_LEN];
+        stream.read_exact(&mut header)?;
+        let size = u32_at(&header, 4) as usize;
+        if !(HEADER_LEN..=HEADER_LEN + MAX_DATA_XFER + 64).contains(&size) {
+            return Err(invalid("bad vfio-user message size"));
+        }
+
+        let mut payload = vec![0u8; size - HEADER_LEN];
+        stream.read_exact(&mut payload)?;
+        Ok(Message {
+            id: u16::from_le_bytes([header[0], header[1]]),
+            command: u16::from_le_bytes([header[2], header[3]]),
+            flags: u32_at(&header, 8),
+            error: u32_at(&header, 12),
+            payload,
+        })
+    }
+}
+
+/// Whether the eventfd was triggered since it was last looked at.
+fn triggered(mut eventfd: &File) -> bool {
+    let mut count = [0u8; 8];
+    eventfd.read(&mut count).is_ok()
+}
+
+/// Hand the messages of the server to the model until the connection is closed.
+fn read_messages(mut stream: UnixStream, tx: Sender<Message>) {
+    loop {
+        match Message::read_from(&mut stream) {
+            Ok(msg) => {
+                if tx.send(msg).is_err() {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                error!("Failed to read a vfio-user message: {}", e);
+                break;
+            }
+        }
+    }
+    debug!("vfio-user server disconnected");
+}
+
+/// A BAR of the server as the guest placed it.
+#[derive(Debug, Clone, Copy)]
+struct Bar {
+    region: u32,
+    base: u64,
+    size: u64,
+    io: bool,
+}
+
+/// The connection to the server.
+struct Client {
+    stream: UnixStream,
+    incoming: Receiver<Message>,
+    next_id: u16,
+    /// Size of each region, 0 if the server does not implement it.
+    regions: Vec<u64>,
+    bars: Vec<Bar>,
+    /// The IRQ index the eventfds are set for and the eventfd of each vector.
+    irqs: Option<(u32, Vec<File>)>,
+}
+
+impl Client {
+    fn send(&mut self, command: u16, payload: Vec<u8>, fds: &[RawFd]) -> io::Result<u16> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let msg = Message {
+            id,
+            command,
+            flags: 0,
+            error: 0,
+            payload,
+        };
+        send_with_fds(&self.stream, &msg.to_bytes(), fds)?;
+        Ok(id)
+    }
+
+    /// Send `command` and wait for its reply. The DMA the server asks for meanwhile is
+    /// serviced through `dma`, or refused without it.
+    fn call(
+        &mut self,
+        command: u16,
+        payload: Vec<u8>,
+        fds: &[RawFd],
+        mut dma: Option<&mut DmaHandle>,
+    ) -> io::Result<Vec<u8>> {
+        let id = self.send(command, payload, fds)?;
+        loop {
+            let msg = self
+                .incoming
+                .recv()
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))?;
+            if msg.flags & 0xf != FLAGS_REPLY {
+                self.serve(msg, dma.as_deref_mut());
+                continue;
+            }
+            if msg.id != id {
+                debug!("Stale vfio-user reply {}", msg.id);
+                continue;
+            }
+            if msg.flags & FLAGS_ERROR != 0 {
+                return Err(io::Error::from_raw_os_error(msg.error as i32));
+            }
+            return Ok(msg.payload);
+        }
+    }
+
+    /// Answer a request of the server.
+    fn serve(&mut self, msg: Message, dma: Option<&mut DmaHandle>) {
+        let result = match (msg.command, dma) {
+            (_, _) if msg.payload.len() < 16 => Err(libc::EINVAL),
+            (VFIO_USER_DMA_READ, Some(dma)) => {
+                let (addr, count) = (u64_at(&msg.payload, 0), u64_at(&msg.payload, 8));
+                if count as usize > MAX_DATA_XFER {
+                    Err(libc::EINVAL)
+                } else {
+                    dma.read(addr, count as usize)
+                        .map(|data| [&msg.payload[..16], &data].concat())
+                        .map_err(|_| libc::EFAULT)
+                }
+            }
+            (VFIO_USER_DMA_WRITE, Some(dma)) => {
+                let addr = u64_at(&msg.payload, 0);
+                dma.write(addr, &msg.payload[16..])
+                    .map(|()| msg.payload[..16].to_vec())
+                    .map_err(|_| libc::EFAULT)
+            }
+            (VFIO_USER_DMA_READ, None) | (VFIO_USER_DMA_WRITE, None) => Err(libc::EBUSY),
+            (command, _) => {
+                debug!("Unsupported vfio-user request {}", command);
+                Err(libc::ENOSYS)
+            }
+        };
+
+        let (flags, error, payload) = match result {
+            Ok(payload) => (FLAGS_REPLY, 0, payload),
+            Err(errno) => (FLAGS_REPLY | FLAGS_ERROR, errno as u32, vec![]),
+        };
+        let reply = Message {
+            id: msg.id,
+            command: msg.command,
+            flags,
+            error,
+            payload,
+        };
+        if let Err(e) = (&self.stream).write_all(&reply.to_bytes()) {
+            error!("Failed to answer the vfio-user server: {}", e);
+        }
+    }
+
+    fn region_read(
+        &mut self,
+        region: u32,
+        offset: u64,
+        len: usize,
+        dma: Option<&mut DmaHandle>,
+    ) -> io::Result<Vec<u8>> {
+        let mut payload = offset.to_le_bytes().to_vec();
+        payload.extend_from_slice(&region.to_le_bytes());
+        payload.extend_from_slice(&(len as u32).to_le_bytes());
+        let reply = self.call(VFIO_USER_REGION_READ, payload, &[], dma)?;
+        if reply.len() != 16 + len {
+            return Err(invalid("short vfio-user region read"));
+        }
+        Ok(reply[16..].to_vec())
+    }
+
+    fn region_write(
+        &mut self,
+        region: u32,
+        offset: u64,
+        data: &[u8],
+        dma: Option<&mut DmaHandle>,
+    ) -> io::Result<()> {
+        let mut payload = offset.to_le_bytes().to_vec();
+        payload.extend_from_slice(&region.to_le_bytes());
+        payload.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        payload.extend_from_slice(data);
+        self.call(VFIO_USER_REGION_WRITE, payload, &[], dma)?;
+        Ok(())
+    }
+
+    /// Read the config space of the server, as registers.
+    fn config(&mut self, dma: Option<&mut DmaHandle>) -> io::Result<Vec<u32>> {
+        let bytes = self.region_read(VFIO_PCI_CONFIG_REGION_INDEX, 0, 256, dma)?;
+        Ok(bytes.chunks(4).map(|dw| u32_at(dw, 0)).collect())
+    }
+
+    /// Find where the guest placed the BARs of the server.
+    fn locate_bars(&mut self, dma: Option<&mut DmaHandle>) -> io::Result<()> {
+        let offset = (BAR0_REG * 4) as u64;
+        let bytes = self.region_read(VFIO_PCI_CONFIG_REGION_INDEX, offset, 24, dma)?;
+        let regs: Vec<u32> = bytes.chunks(4).map(|dw| u32_at(dw, 0)).collect();
+
+        self.bars.clear();
+        let mut i = 0;
+        while i < regs.len() {
+            let size = self.regions.get(i).copied().unwrap_or(0);
+            let io = regs[i] & 0x1 != 0;
+            let is_64bit = !io && (regs[i] >> 1) & 0x3 == 0x2;
+            let mut base = if io {
+                (regs[i] & !0x3) as u64
+            } else {
+                (regs[i] & !0xf) as u64
+            };
+            if is_64bit {
+                base |= (regs.get(i + 1).copied().unwrap_or(0) as u64) << 32;
+            }
+            if size != 0 && base != 0 {
+                self.bars.push(Bar {
+                    region: i as u32,
+                    base,
+                    size,
+                    io,
+                });
+            }
+            i += if is_64bit { 2 } else { 1 };
+        }
+        Ok(())
+    }
+
+    /// The region and offset of `addr` within a BAR.
+    fn locate(&self, addr: u64, len: usize, io: bool) -> Option<(u32, u64)> {
+        self.bars
+            .iter()
+            .find(|bar| {
+                bar.io == io && addr >= bar.base && addr + len as u64 <= bar.base + bar.size
+            })
+            .map(|bar| (bar.region, addr - bar.base))
+    }
+}
+
+/// The device of a vfio-user server, see [`crate::vfio_user`].
+pub struct VfioUserDevice {
+    client: Client,
+    /// IOVA and size of the windows of guest memory the server does DMA in.
+    windows: Vec<(u64, u64)>,
+    bdf: u16,
+}
+
+impl VfioUserDevice {
+    /// Connect to the vfio-user server listening at `path`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(UnixStream::connect(path)?)
+    }
+
+    /// Talk vfio-user over `stream`: negotiate the version, learn the regions and interrupts
+    /// of the device and hand the server the eventfds of its interrupts.
+    pub fn new(stream: UnixStream) -> io::Result<Self> {
+        let (tx, incoming) = unbounded();
+        let reader = stream.try_clone()?;
+        std::thread::Builder::new()
+            .name("vfio-user".to_string())
+            .spawn(move || read_messages(reader, tx))?;
+
+        let mut client = Client {
+            stream,
+            incoming,
+            next_id: 0,
+            regions: vec![],
+            bars: vec![],
+            irqs: None,
+        };
+
+        let mut version = 0u16.to_le_bytes().to_vec();
+        version.extend_from_slice(&1u16.to_le_bytes());
+        version.extend_from_slice(CAPABILITIES.as_bytes());
+        version.push(0);
+        let reply = client.call(VFIO_USER_VERSION, version, &[], None)?;
+        if reply.len() < 4 || u16::from_le_bytes([reply[0], reply[1]]) != 0 {
+            return Err(invalid("unsupported vfio-user version"));
+        }
+
+        let mut info = 16u32.to_le_bytes().to_vec();
+        info.resize(16, 0);
+        let reply = client.call(VFIO_USER_DEVICE_GET_INFO, info, &[], None)?;
+        if reply.len() < 16 || u32_at(&reply, 4) & VFIO_DEVICE_FLAGS_PCI == 0 {
+            return Err(invalid("not a vfio-user PCI device"));
+        }
+        let (num_regions, num_irqs) = (u32_at(&reply, 8), u32_at(&reply, 12));
+        if num_regions <= VFIO_PCI_CONFIG_REGION_INDEX {
+            return Err(invalid("vfio-user device without config space"));
+        }
+
+        for index in 0..=VFIO_PCI_CONFIG_REGION_INDEX {
+            let mut info = 32u32.to_le_bytes().to_vec();
+            info.extend_from_slice(&0u32.to_le_bytes());
+            info.extend_from_slice(&index.to_le_bytes());
+            info.resize(32, 0);
+            let reply = client.call(VFIO_USER_DEVICE_GET_REGION_INFO, info, &[], None)?;
+            if reply.len() < 32 {
+                return Err(invalid("short vfio-user region info"));
+            }
+            let readable = u32_at(&reply, 4) & VFIO_REGION_INFO_FLAG_READ != 0;
+            client
+                .regions
+                .push(if readable { u64_at(&reply, 16) } else { 0 });
+        }
+
+        // MSI-X if the device has it, MSI otherwise.
+        for index in [VFIO_PCI_MSIX_IRQ_INDEX, VFIO_PCI_MSI_IRQ_INDEX] {
+            if index >= num_irqs {
+                continue;
+            }
+            let mut info = 16u32.to_le_bytes().to_vec();
+            info.extend_from_slice(&0u32.to_le_bytes());
+            info.extend_from_slice(&index.to_le_bytes());
+            info.resize(16, 0);
+            let count = u32_at(
+                &client.call(VFIO_USER_DEVICE_GET_IRQ_INFO, info, &[], None)?,
+                12,
+            );
+            if count == 0 {
+                continue;
+            }
+
+            let eventfds = (0..count)
+                .map(|_| eventfd())
+                .collect::<io::Result<Vec<_>>>()?;
+            let fds: Vec<RawFd> = eventfds.iter().map(|fd| fd.as_raw_fd()).collect();
+            let mut irqs = 20u32.to_le_bytes().to_vec();
+            let flags = VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER;
+            for dw in [flags, index, 0, count] {
+                irqs.extend_from_slice(&dw.to_le_bytes());
+            }
+            client.call(VFIO_USER_DEVICE_SET_IRQS, irqs, &fds, None)?;
+            client.irqs = Some((index, eventfds));
+            break;
+        }
+
+        Ok(VfioUserDevice {
+            client,
+            windows: vec![],
+            bdf: 0,
+        })
+    }
+
+    /// Let the server do DMA in `size` bytes of guest memory at `iova`, announced to it once
+    /// the model runs.
+    pub fn dma_window(mut self, iova: u64, size: u64) -> Self {
+        self.windows.push((iova, size));
+        self
+    }
+
+    fn map_windows(&mut self) {
+        for &(iova, size) in self.windows.iter() {
+            let mut map = 32u32.to_le_bytes().to_vec();
+            let flags = VFIO_USER_F_DMA_REGION_READ | VFIO_USER_F_DMA_REGION_WRITE;
+            map.extend_from_slice(&flags.to_le_bytes());
+            for qw in [0, iova, size] {
+                map.extend_from_slice(&qw.to_le_bytes());
+            }
+            if let Err(e) = self.client.call(VFIO_USER_DMA_MAP, map, &[], None) {
+                error!("Failed to map DMA window {:#x}: {}", iova, e);
+            }
+        }
+    }
+
+    /// Raise the interrupts the server triggered.
+    fn raise_irqs(&mut self, irq: &IrqHandle, dma: &mut DmaHandle) {
+        let (index, vectors) = match self.client.irqs.as_ref() {
+            Some((index, eventfds)) => (
+                *index,
+                eventfds
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, fd)| triggered(fd))
+                    .map(|(vector, _)| vector)
+                    .collect::<Vec<_>>(),
+            ),
+            None => return,
+        };
+        if vectors.is_empty() {
+            return;
+        }
+
+        let result = if index == VFIO_PCI_MSIX_IRQ_INDEX {
+            vectors
+                .iter()
+                .try_for_each(|&vector| irq.raise_msix(vector as u16))
+        } else {
+            // MSI is raised as the capability in the config space of the server says.
+            match self.client.config(Some(dma)) {
+                Ok(config) => vectors.iter().try_for_each(|&vector| {
+                    let config = |reg: usize| config.get(reg).copied().unwrap_or(0);
+                    irq.raise_msi(config, vector as u8).map(|_| ())
+                }),
+                Err(e) => {
+                    error!("Failed to read the MSI capability: {}", e);
+                    Ok(())
+                }
+            }
+        };
+        if let Err(e) = result {
+            error!("vfio-user interrupt failed: {}", e);
+        }
+    }
+}
+
+/// The requests of the bridge, forwarded to the server while the DMA it asks for meanwhile is
+/// serviced.
+struct Session<'a, 'b> {
+    client: &'a mut Client,
+    dma: &'a mut DmaHandle<'b>,
+}
+
+impl Session<'_, '_> {
+    fn read(&mut self, addr: u64, data: &mut [u8], io: bool) -> std::result::Result<(), u8> {
+        let (region, offset) = self.client.locate(addr, data.len(), io).ok_or(CPL_UR)?;
+        let bytes = self
+            .client
+            .region_read(region, offset, data.len(), Some(self.dma))
+            .map_err(|e| {
+                error!("vfio-user read of region {} failed: {}", region, e);
+                CPL_CA
+            })?;
+        data.copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8], io: bool) -> std::result::Result<(), u8> {
+        let (region, offset) = self.client.locate(addr, data.len(), io).ok_or(CPL_UR)?;
+        self.client
+            .region_write(region, offset, data, Some(self.dma))
+            .map_err(|e| {
+                error!("vfio-user write of region {} failed: {}", region, e);
+                CPL_CA
+            })
+    }
+}
+
+impl SimpleDevice for Session<'_, '_> {
+    fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+        let offset = (reg * 4) as u64;
+        match self
+            .client
+            .region_read(VFIO_PCI_CONFIG_REGION_INDEX, offset, 4, Some(self.dma))
+        {
+            Ok(bytes) => u32_at(&bytes, 0),
+            Err(e) => {
+                error!("vfio-user config read of {} failed: {}", reg, e);
+                u32::MAX
+            }
+        }
+    }
+
+    fn on_config_write(&mut self, _: u16, reg: usize, offset: u64, data: &[u8]) {
+        let offset = (reg * 4) as u64 + offset;
+        let result = self
+            .client
+            .region_write(VFIO_PCI_CONFIG_REGION_INDEX, offset, data, Some(self.dma))
+            .and_then(|()| {
+                if (BAR0_REG..BAR0_REG + 6).contains(&reg) {
+                    self.client.locate_bars(Some(self.dma))
+                } else {
+                    Ok(())
+                }
+            });
+        if let Err(e) = result {
+            error!("vfio-user config write of {} failed: {}", reg, e);
+        }
+    }
+
+    fn on_mem_read(&mut self, addr: u64, data: &mut [u8]) -> std::result::Result<(), u8> {
+        self.read(addr, data, false)
+    }
+
+    fn on_mem_write(&mut self, addr: u64, data: &[u8]) {
+        let _ = self.write(addr, data, false);
+    }
+
+    fn on_io_read(&mut self, addr: u32, data: &mut [u8]) -> std::result::Result<(), u8> {
+        self.read(addr as u64, data, true)
+    }
+
+    fn on_io_write(&mut self, addr: u32, data: &[u8]) -> std::result::Result<(), u8> {
+        self.write(addr as u64, data, true)
+    }
+}
+
+impl PciSimDevice for VfioUserDevice {
+    fn on_start(&mut self, info: &LaneInfo) {
+        self.bdf = info.functions[0];
+    }
+
+    fn on_reset(&mut self, _kind: ResetKind) {
+        if let Err(e) = self.client.call(VFIO_USER_DEVICE_RESET, vec![], &[], None) {
+            error!("vfio-user reset failed: {}", e);
+        }
+        self.client.bars.clear();
+    }
+
+    fn on_stop(&mut self) {
+        let _ = self.client.stream.shutdown(Shutdown::Both);
+    }
+
+    fn run(&mut self, lane: &PciLane) {
+        let mut dma = DmaHandle::new(lane, self.bdf);
+        let irq = IrqHandle::new(lane, self.bdf);
+        let mut completer = self.bdf;
+        if !self.windows.is_empty() {
+            self.map_windows();
+            self.windows.clear();
+        }
+
+        loop {
+            match dma.recv_timeout(VFIO_USER_POLL) {
+                Ok(tlp) => {
+                    let mut session = Session {
+                        client: &mut self.client,
+                        dma: &mut dma,
+                    };
+                    dispatch(&mut session, lane, &tlp, &mut completer);
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            loop {
+                match self.client.incoming.try_recv() {
+                    Ok(msg) => self.client.serve(msg, Some(&mut dma)),
+                    Err(TryRecvError::Empty) => break,
+                    // The server is gone, so is the device.
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+            self.raise_irqs(&irq, &mut dma);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::test_allocator;
+
+    use crate::remote::recv_with_fds;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[derive(Clone)]
+    struct VecMemory(Arc<Mutex<Vec<u8>>>);
+
+    impl DmaMemory for VecMemory {
+        fn read(&self, gpa: u64, data: &mut [u8]) -> io::Result<()> {
+            let gpa = gpa as usize;
+            data.copy_from_slice(&self.0.lock().unwrap()[gpa..gpa + data.len()]);
+            Ok(())
+        }
+
+        fn write(&self, gpa: u64, data: &[u8]) -> io::Result<()> {
+            let gpa = gpa as usize;
+            self.0.lock().unwrap()[gpa..gpa + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    struct ChannelSink(Sender<(u64, u32)>);
+
+    impl MsiSink for ChannelSink {
+        fn inject(&self, addr: u64, data: u32) -> io::Result<()> {
+            self.0.send((addr, data)).unwrap();
+            Ok(())
+        }
+    }
+
+    /// Read a message of the client, with the fds it carries.
+    fn recv_message(stream: &mut UnixStream) -> io::Result<(Message, Vec<File>)> {
+        let mut header = [0u8; HEADER_LEN];
+        let fds = recv_with_fds(stream, &mut header)?;
+        let mut payload = vec![0u8; u32_at(&header, 4) as usize - HEADER_LEN];
+        stream.read_exact(&mut payload)?;
+        let msg = Message {
+            id: u16::from_le_bytes([header[0], header[1]]),
+            command: u16::from_le_bytes([header[2], header[3]]),
+            flags: u32_at(&header, 8),
+            error: u32_at(&header, 12),
+            payload,
+        };
+        Ok((msg, fds))
+    }
+
+    fn reply(stream: &mut UnixStream, msg: &Message, payload: Vec<u8>) {
+        let reply = Message {
+            id: msg.id,
+            command: msg.command,
+            flags: FLAGS_REPLY,
+            error: 0,
+            payload,
+        };
+        stream.write_all(&reply.to_bytes()).unwrap();
+    }
+
+    /// Send a DMA request to the client and wait for its reply.
+    fn dma(stream: &mut UnixStream, command: u16, addr: u64, data: &[u8], len: u64) -> Vec<u8> {
+        let mut payload = addr.to_le_bytes().to_vec();
+        payload.extend_from_slice(&len.to_le_bytes());
+        payload.extend_from_slice(data);
+        let msg = Message {
+            id: 0x100,
+            command,
+            flags: 0,
+            error: 0,
+            payload,
+        };
+        stream.write_all(&msg.to_bytes()).unwrap();
+        let reply = Message::read_from(stream).unwrap();
+        assert_eq!((reply.id, reply.flags), (0x100, FLAGS_REPLY));
+        reply.payload[16..].to_vec()
+    }
+
+    fn put(config: &mut [u8], reg: usize, value: u32) {
+        config[reg * 4..reg * 4 + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// A vfio-user server with 4 KB of BAR0 holding a 2-vector MSI-X table. A write to offset
+    /// 0 of BAR0 copies 8 bytes at 0x1000 to 0x2000 and triggers vector 1.
+    fn server(mut stream: UnixStream) {
+        let mut config = [0u8; 256];
+        put(&mut config, 0, 0x1111_1234);
+        put(&mut config, 1, 0x0010_0000);
+        put(&mut config, 13, 0x40);
+        put(&mut config, 16, 0x0001_0011);
+        put(&mut config, 17, 0x800);
+        put(&mut config, 18, 0xc00);
+        let mut bar = vec![0u8; 0x1000];
+        let mut eventfds = vec![];
+
+        while let Ok((msg, fds)) = recv_message(&mut stream) {
+            let p = &msg.payload;
+            let payload = match msg.command {
+                VFIO_USER_VERSION => {
+                    let mut version = vec![0, 0, 1, 0];
+                    version.extend_from_slice(b"{}\0");
+                    version
+                }
+                VFIO_USER_DEVICE_GET_INFO => [16, 0x3, 9, 5]
+                    .iter()
+                    .flat_map(|dw: &u32| dw.to_le_bytes())
+                    .collect(),
+                VFIO_USER_DEVICE_GET_REGION_INFO => {
+                    let size: u64 = match u32_at(p, 8) {
+                        0 => 0x1000,
+                        VFIO_PCI_CONFIG_REGION_INDEX => 0x100,
+                        _ => 0,
+                    };
+                    let mut info = p[..16].to_vec();
+                    info[4] = 0x3;
+                    info.extend_from_slice(&size.to_le_bytes());
+                    info.extend_from_slice(&0u64.to_le_bytes());
+                    info
+                }
+                VFIO_USER_DEVICE_GET_IRQ_INFO => {
+                    let count = if u32_at(p, 8) == VFIO_PCI_MSIX_IRQ_INDEX {
+                        2
+                    } else {
+                        0
+                    };
+                    let mut info = p[..12].to_vec();
+                    info.extend_from_slice(&(count as u32).to_le_bytes());
+                    info
+                }
+                VFIO_USER_DEVICE_SET_IRQS => {
+                    assert_eq!(u32_at(p, 8), VFIO_PCI_MSIX_IRQ_INDEX);
+                    eventfds = fds;
+                    vec![]
+                }
+                VFIO_USER_REGION_READ => {
+                    let (offset, count) = (u64_at(p, 0) as usize, u32_at(p, 12) as usize);
+                    let region: &[u8] = match u32_at(p, 8) {
+                        0 => &bar,
+                        _ => &config,
+                    };
+                    [&p[..16], &region[offset..offset + count]].concat()
+                }
+                VFIO_USER_REGION_WRITE => {
+                    let (offset, count) = (u64_at(p, 0) as usize, u32_at(p, 12) as usize);
+                    if u32_at(p, 8) == 0 {
+                        bar[offset..offset + count].copy_from_slice(&p[16..]);
+                        if offset == 0 {
+                            let data = dma(&mut stream, VFIO_USER_DMA_READ, 0x1000, &[], 8);
+                            dma(&mut stream, VFIO_USER_DMA_WRITE, 0x2000, &data, 8);
+                            (&eventfds[1]).write_all(&1u64.to_le_bytes()).unwrap();
+                        }
+                    } else {
+                        config[offset..offset + count].copy_from_slice(&p[16..]);
+                        // BAR0 is 4 KB of 32-bit memory, the other BARs and the ROM are
+                        // not implemented.
+                        let base = u32_at(&config, 0x10) & !0xfff;
+                        put(&mut config, 4, base);
+                        for reg in (5..10).chain(Some(12)) {
+                            put(&mut config, reg, 0);
+                        }
+                    }
+                    p[..16].to_vec()
+                }
+                VFIO_USER_DMA_MAP | VFIO_USER_DEVICE_RESET => vec![],
+                command => panic!("unexpected command {}", command),
+            };
+            reply(&mut stream, &msg, payload);
+        }
+    }
+
+    #[test]
+    fn vfio_user() {
+        let (client, remote) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || server(remote));
+        let device = VfioUserDevice::new(client).unwrap().dma_window(0, 0x4000);
+
+        let mut adapter = PciAdapter::start(Box::new(device));
+        let (tx, rx) = unbounded();
+        adapter.set_msi_sink(Box::new(ChannelSink(tx)));
+        let memory = VecMemory(Arc::new(Mutex::new(vec![0; 0x4000])));
+        adapter.set_dma_memory(Box::new(memory.clone()));
+
+        let mut allocator = test_allocator();
+        let bars = adapter.allocate_bars(&mut allocator).unwrap();
+        assert_eq!(bars.len(), 1);
+        let base = bars[0].0.raw_value();
+        assert_eq!(adapter.config_read(0), 0x1111_1234);
+        assert_eq!(adapter.config_read(4) as u64, base);
+
+        // Program vector 1 of MSI-X, then have the server copy.
+        adapter.write_config_register(1, 0, &[0x06, 0x00]);
+        adapter.bar_mmio_write(base + 0x810, &0xfee0_1000u64.to_le_bytes());
+        adapter.bar_mmio_write(base + 0x818, &0x4021u32.to_le_bytes());
+        adapter.bar_mmio_write(base + 0x81c, &0u32.to_le_bytes());
+        adapter.write_config_register(16, 2, &[0x01, 0x80]);
+
+        memory.write(0x1000, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        adapter.bar_mmio_write(base + 4, &0x5a5a_5a5au32.to_le_bytes());
+        adapter.bar_mmio_write(base, &1u32.to_le_bytes());
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok((0xfee0_1000, 0x4021))
+        );
+        let mut data = [0u8; 8];
+        memory.read(0x2000, &mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let mut data = [0u8; 4];
+        adapter.bar_mmio_read(base + 4, &mut data);
+        assert_eq!(data, [0x5a; 4]);
+        // Past the 4 KB of the BAR.
+        adapter.bar_mmio_read(base + 0x1000, &mut data);
+        assert_eq!(data, [0xff; 4]);
+
+        adapter.stop();
+        adapter.join();
+        server.join().unwrap();
+    }
+}
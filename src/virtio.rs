@@ -0,0 +1,996 @@
+//! Virtio-blk device speaking TLPs.
+//!
+//! [`VirtioBlkDevice`] is a transitional virtio block device, as much a PCIe device as any
+//! other model: the guest finds it by its config space and drives it through its BARs, and the
+//! device reads its virtqueues and moves the data of the requests by DMA through the adapter.
+//!
+//! * BAR0 holds the legacy interface in IO space, for drivers older than virtio 1.0.
+//! * BAR1 holds the MSI-X table and PBA of the 2 vectors, emulated by the adapter.
+//! * BAR4 holds the structures of the modern interface, which the vendor specific
+//!   capabilities of the config space point at: the common config at 0, the ISR status at
+//!   0x1000, the block device config at 0x2000 and the notification registers at 0x3000.
+//!
+//! The device has a single request queue and offers `VIRTIO_BLK_F_FLUSH`. Without MSI-X, the
+//! interrupts go to INTA and the ISR status.
+
+use crate::*;
+
+use crate::adapter::{Result, BAR0_REG};
+use crate::device::dispatch;
+use pci::{PciCapability, PciCapabilityId, PciInterruptPin};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+/// Device ID of a transitional block device.
+const VIRTIO_BLK_TRANSITIONAL_ID: u16 = 0x1001;
+/// Virtio device type of a block device, in the subsystem ID.
+const VIRTIO_ID_BLOCK: u16 = 2;
+
+const LEGACY_BAR: usize = 0;
+const LEGACY_BAR_SIZE: u64 = 0x40;
+const MSIX_BAR: usize = 1;
+const MSIX_BAR_SIZE: u64 = 0x1000;
+const MSIX_PBA: u32 = 0x800;
+/// The config vector and the vector of the request queue.
+const MSIX_VECTORS: u16 = 2;
+const MODERN_BAR: usize = 4;
+const MODERN_BAR_SIZE: u64 = 0x4000;
+
+/// Offsets of the structures of the modern interface in its BAR.
+const COMMON_CFG: u64 = 0x0000;
+const ISR_CFG: u64 = 0x1000;
+const DEVICE_CFG: u64 = 0x2000;
+const NOTIFY_CFG: u64 = 0x3000;
+const COMMON_CFG_LEN: usize = 0x38;
+/// `struct virtio_blk_config` up to the block size.
+const DEVICE_CFG_LEN: usize = 0x18;
+const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+
+/// `cfg_type` of the virtio PCI capabilities.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+const VIRTIO_PCI_CAP_PCI_CFG: u8 = 5;
+
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Device status bit the device sets when it runs into an error it cannot recover from.
+const STATUS_NEEDS_RESET: u8 = 0x40;
+const ISR_QUEUE: u8 = 0x1;
+const NO_VECTOR: u16 = 0xffff;
+/// MSI-X Enable and Function Mask in the header register of the MSI-X capability.
+const MSIX_ENABLE: u32 = 1 << 31;
+const MSIX_CONTROL_WRITABLE: u32 = 0xc000_0000;
+
+const QUEUE_SIZE: u16 = 256;
+const VRING_DESC_F_NEXT: u16 = 0x1;
+const VRING_DESC_F_WRITE: u16 = 0x2;
+const VRING_DESC_SIZE: u64 = 16;
+/// Alignment of the used ring of a legacy virtqueue.
+const LEGACY_VRING_ALIGN: u64 = 0x1000;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+/// Size of the header of a request: type, reserved and sector.
+const BLK_HEADER_LEN: usize = 16;
+const SECTOR_SIZE: u64 = 512;
+/// The serial number returned by `VIRTIO_BLK_T_GET_ID`, padded with zeros to 20 bytes.
+const BLK_ID: &[u8] = b"pcie-tlp-virtio-blk";
+const BLK_ID_LEN: usize = 20;
+
+/// A capability given by its bytes after the ID and next pointer.
+struct Capability(PciCapabilityId, Vec<u8>);
+
+impl PciCapability for Capability {
+    fn bytes(&self) -> &[u8] {
+        &self.1
+    }
+
+    fn id(&self) -> PciCapabilityId {
+        self.0
+    }
+}
+
+/// A `struct virtio_pci_cap` locating a structure of the modern interface, followed by
+/// `extra`.
+fn virtio_cap(cfg_type: u8, bar: usize, offset: u64, length: usize, extra: &[u8]) -> Capability {
+    let mut bytes = vec![(16 + extra.len()) as u8, cfg_type, bar as u8, 0, 0, 0];
+    bytes.extend_from_slice(&(offset as u32).to_le_bytes());
+    bytes.extend_from_slice(&(length as u32).to_le_bytes());
+    bytes.extend_from_slice(extra);
+    Capability(PciCapabilityId::VendorSpecific, bytes)
+}
+
+/// Little endian value of up to 8 bytes.
+fn le_value(data: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    let len = data.len().min(8);
+    bytes[..len].copy_from_slice(&data[..len]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Fill `data` from `offset` into `bytes`, with zeros past its end.
+fn copy_out(bytes: &[u8], offset: usize, data: &mut [u8]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = bytes.get(offset + i).copied().unwrap_or(0);
+    }
+}
+
+/// A disk image the device serves, e.g. a [`std::fs::File`] or a [`std::io::Cursor`].
+trait Disk: Read + Write + Seek + Send + Sync {}
+
+impl<T: Read + Write + Seek + Send + Sync> Disk for T {}
+
+/// Transitional virtio-blk device backed by a disk image, see the
+/// [module documentation](crate::virtio).
+pub struct VirtioBlkDevice {
+    regs: BlkRegisters,
+    disk: Box<dyn Disk>,
+    bdf: u16,
+}
+
+impl VirtioBlkDevice {
+    /// A device serving `disk`, whose capacity is its length rounded down to 512 byte sectors.
+    pub fn new<D: Read + Write + Seek + Send + Sync + 'static>(mut disk: D) -> io::Result<Self> {
+        let capacity = disk.seek(SeekFrom::End(0))? / SECTOR_SIZE;
+
+        Ok(VirtioBlkDevice {
+            regs: BlkRegisters::new(capacity),
+            disk: Box::new(disk),
+            bdf: 0,
+        })
+    }
+
+    /// Serve the requests the driver made available since the last notification.
+    fn process_queue(&mut self, dma: &mut DmaHandle, irq: &IrqHandle) -> Result<()> {
+        let queue = self.regs.queues[0];
+        if !queue.ready || queue.size == 0 {
+            return Ok(());
+        }
+
+        let avail = read_u16(dma, queue.driver + 2)?;
+        let mut used = queue.used;
+        let mut next = queue.last_avail;
+        while next != avail {
+            let slot = (next % queue.size) as u64;
+            let head = read_u16(dma, queue.driver + 4 + 2 * slot)?;
+            let len = self.request(dma, &queue, head)?;
+
+            let mut elem = [0u8; 8];
+            elem[..4].copy_from_slice(&(head as u32).to_le_bytes());
+            elem[4..].copy_from_slice(&len.to_le_bytes());
+            let slot = (used % queue.size) as u64;
+            dma.write(queue.device + 4 + 8 * slot, &elem)?;
+
+            // Writes are posted in order, the element is there before the index tells.
+            used = used.wrapping_add(1);
+            next = next.wrapping_add(1);
+            dma.write(queue.device + 2, &used.to_le_bytes())?;
+            self.regs.queues[0].used = used;
+            self.regs.queues[0].last_avail = next;
+        }
+
+        if used == queue.used {
+            return Ok(());
+        }
+        if self.regs.msix_enabled() {
+            if queue.vector != NO_VECTOR {
+                irq.raise_msix(queue.vector)?;
+            }
+        } else {
+            self.regs.isr |= ISR_QUEUE;
+        }
+        Ok(())
+    }
+
+    /// Serve the request of the descriptor chain at `head`. Returns how many bytes are written
+    /// to the guest.
+    fn request(&mut self, dma: &mut DmaHandle, queue: &Virtqueue, head: u16) -> Result<u32> {
+        // The framing of the request is up to the driver: the header and the data to write
+        // are the readable buffers, the data read and the status the writable ones.
+        let mut readable = vec![];
+        let mut writable = vec![];
+        let mut index = head;
+        for _ in 0..queue.size {
+            let desc = dma.read(queue.desc + VRING_DESC_SIZE * index as u64, 16)?;
+            let addr = le_value(&desc[0..8]);
+            let len = le_value(&desc[8..12]) as usize;
+            let flags = le_value(&desc[12..14]) as u16;
+
+            if flags & VRING_DESC_F_WRITE != 0 {
+                writable.push((addr, len));
+            } else if len > 0 {
+                readable.extend(dma.read(addr, len)?);
+            }
+            if flags & VRING_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = le_value(&desc[14..16]) as u16;
+        }
+
+        let total: usize = writable.iter().map(|(_, len)| len).sum();
+        if readable.len() < BLK_HEADER_LEN || total == 0 {
+            error!("virtio-blk request {} has no header or status", head);
+            return Ok(0);
+        }
+
+        // The status is the last byte written.
+        let mut reply = vec![0u8; total];
+        let kind = le_value(&readable[0..4]) as u32;
+        let sector = le_value(&readable[8..16]);
+        let status = match kind {
+            VIRTIO_BLK_T_IN => self.disk_io(sector, total - 1, |disk| {
+                disk.read_exact(&mut reply[..total - 1])
+            }),
+            VIRTIO_BLK_T_OUT => {
+                let data = &readable[BLK_HEADER_LEN..];
+                self.disk_io(sector, data.len(), |disk| disk.write_all(data))
+            }
+            VIRTIO_BLK_T_FLUSH => match self.disk.flush() {
+                Ok(()) => VIRTIO_BLK_S_OK,
+                Err(_) => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let len = BLK_ID.len().min(BLK_ID_LEN).min(total - 1);
+                reply[..len].copy_from_slice(&BLK_ID[..len]);
+                VIRTIO_BLK_S_OK
+            }
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+        reply[total - 1] = status;
+
+        let mut at = 0;
+        for (addr, len) in writable {
+            if len > 0 {
+                dma.write(addr, &reply[at..at + len])?;
+            }
+            at += len;
+        }
+        Ok(total as u32)
+    }
+
+    /// Run `io` on the disk at `sector`, if the `len` bytes there are within its capacity.
+    fn disk_io(
+        &mut self,
+        sector: u64,
+        len: usize,
+        io: impl FnOnce(&mut dyn Disk) -> io::Result<()>,
+    ) -> u8 {
+        let end = sector
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|start| start.checked_add(len as u64));
+        if end.is_none_or(|end| end > self.regs.capacity * SECTOR_SIZE) {
+            error!(
+                "virtio-blk access of {} bytes at sector {} is out of the disk",
+                len, sector
+            );
+            return VIRTIO_BLK_S_IOERR;
+        }
+
+        let result = self
+            .disk
+            .seek(SeekFrom::Start(sector * SECTOR_SIZE))
+            .and_then(|_| io(&mut *self.disk));
+        match result {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(err) => {
+                error!("virtio-blk access at sector {} failed: {}", sector, err);
+                VIRTIO_BLK_S_IOERR
+            }
+        }
+    }
+}
+
+fn read_u16(dma: &mut DmaHandle, addr: u64) -> Result<u16> {
+    Ok(le_value(&dma.read(addr, 2)?) as u16)
+}
+
+impl PciSimDevice for VirtioBlkDevice {
+    fn on_start(&mut self, info: &LaneInfo) {
+        self.bdf = info.functions[0];
+    }
+
+    fn on_reset(&mut self, _kind: ResetKind) {
+        self.regs = BlkRegisters::new(self.regs.capacity);
+    }
+
+    fn run(&mut self, lane: &PciLane) {
+        let mut dma = DmaHandle::new(lane, self.bdf);
+        let irq = IrqHandle::new(lane, self.bdf);
+        let mut completer = self.bdf;
+
+        while let Ok(tlp) = dma.recv() {
+            dispatch(&mut self.regs, lane, &tlp, &mut completer);
+
+            if std::mem::take(&mut self.regs.notified) {
+                if let Err(err) = self.process_queue(&mut dma, &irq) {
+                    error!("virtio-blk queue failed: {}", err);
+                    self.regs.status |= STATUS_NEEDS_RESET;
+                }
+            }
+
+            // INTA follows the ISR status while MSI-X is off.
+            let regs = &self.regs;
+            let level = !regs.msix_enabled() && regs.isr != 0;
+            if let Err(err) = irq.set_intx(|reg| regs.config_read(reg), level) {
+                error!("virtio-blk interrupt failed: {}", err);
+            }
+        }
+    }
+}
+
+/// A virtqueue as the driver set it up.
+#[derive(Debug, Clone, Copy)]
+struct Virtqueue {
+    size: u16,
+    ready: bool,
+    vector: u16,
+    /// Addresses of the descriptor table, the available and the used ring.
+    desc: u64,
+    driver: u64,
+    device: u64,
+    /// Index of the next available entry to serve, and of the next used entry.
+    last_avail: u16,
+    used: u16,
+}
+
+impl Virtqueue {
+    fn new() -> Self {
+        Virtqueue {
+            size: QUEUE_SIZE,
+            ready: false,
+            vector: NO_VECTOR,
+            desc: 0,
+            driver: 0,
+            device: 0,
+            last_avail: 0,
+            used: 0,
+        }
+    }
+
+    /// Lay the queue out from the page frame number the legacy interface takes.
+    fn set_pfn(&mut self, pfn: u32) {
+        self.size = QUEUE_SIZE;
+        self.desc = (pfn as u64) << 12;
+        self.driver = self.desc + VRING_DESC_SIZE * QUEUE_SIZE as u64;
+        let avail_end = self.driver + 6 + 2 * QUEUE_SIZE as u64;
+        self.device = (avail_end + LEGACY_VRING_ALIGN - 1) & !(LEGACY_VRING_ALIGN - 1);
+        self.ready = pfn != 0;
+    }
+}
+
+/// The config space and registers of the device, which answer the requests of the bridge.
+/// Serving the queue needs the lane and is left to [`VirtioBlkDevice::run`].
+struct BlkRegisters {
+    config: PcieConfiguration,
+    /// Registers of the capabilities the guest writes, which the config space keeps read-only,
+    /// with their writable bits.
+    shadow: HashMap<usize, (u32, u32)>,
+    msix_reg: usize,
+    /// Register index of the `VIRTIO_PCI_CAP_PCI_CFG` capability.
+    pci_cfg_reg: usize,
+    /// Disk size in sectors.
+    capacity: u64,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    driver_features: u64,
+    status: u8,
+    isr: u8,
+    msix_config: u16,
+    queue_select: u16,
+    queues: Vec<Virtqueue>,
+    /// Whether the queue is notified and not served yet.
+    notified: bool,
+}
+
+impl BlkRegisters {
+    fn new(capacity: u64) -> Self {
+        let mut config = PciConfiguration::new(
+            VIRTIO_VENDOR_ID,
+            VIRTIO_BLK_TRANSITIONAL_ID,
+            0,
+            PciClassCode::MassStorage,
+            &PciMassStorageSubclass::ScsiStorage,
+            None,
+            PciHeaderType::Device,
+            VIRTIO_VENDOR_ID,
+            VIRTIO_ID_BLOCK,
+            None,
+        );
+
+        for (bar, size, type_) in [
+            (LEGACY_BAR, LEGACY_BAR_SIZE, PciBarRegionType::IoRegion),
+            (MSIX_BAR, MSIX_BAR_SIZE, PciBarRegionType::Memory32BitRegion),
+            (
+                MODERN_BAR,
+                MODERN_BAR_SIZE,
+                PciBarRegionType::Memory64BitRegion,
+            ),
+        ] {
+            let bar =
+                PciBarConfiguration::new(bar, size, type_, PciBarPrefetchable::NotPrefetchable);
+            config.add_pci_bar(&bar).unwrap();
+        }
+        config.set_irq(0, PciInterruptPin::IntA);
+
+        let mut msix = (MSIX_VECTORS - 1).to_le_bytes().to_vec();
+        msix.extend_from_slice(&(MSIX_BAR as u32).to_le_bytes());
+        msix.extend_from_slice(&(MSIX_PBA | MSIX_BAR as u32).to_le_bytes());
+        let msix_reg = config
+            .add_capability(&Capability(PciCapabilityId::MsiX, msix))
+            .unwrap()
+            / 4;
+
+        let caps = [
+            virtio_cap(
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                MODERN_BAR,
+                COMMON_CFG,
+                COMMON_CFG_LEN,
+                &[],
+            ),
+            virtio_cap(VIRTIO_PCI_CAP_ISR_CFG, MODERN_BAR, ISR_CFG, 1, &[]),
+            virtio_cap(
+                VIRTIO_PCI_CAP_DEVICE_CFG,
+                MODERN_BAR,
+                DEVICE_CFG,
+                DEVICE_CFG_LEN,
+                &[],
+            ),
+            virtio_cap(
+                VIRTIO_PCI_CAP_NOTIFY_CFG,
+                MODERN_BAR,
+                NOTIFY_CFG,
+                NOTIFY_OFF_MULTIPLIER as usize,
+                &NOTIFY_OFF_MULTIPLIER.to_le_bytes(),
+            ),
+        ];
+        for cap in caps.iter() {
+            config.add_capability(cap).unwrap();
+        }
+        // The window into the BARs, followed by `pci_cfg_data`.
+        let pci_cfg = virtio_cap(VIRTIO_PCI_CAP_PCI_CFG, 0, 0, 0, &[0; 4]);
+        let pci_cfg_reg = config.add_capability(&pci_cfg).unwrap() / 4;
+
+        let config = PcieConfiguration::new(config);
+        let mut shadow = HashMap::new();
+        for (reg, writable) in [
+            (msix_reg, MSIX_CONTROL_WRITABLE),
+            // The BAR, offset and length of the window.
+            (pci_cfg_reg + 1, 0xff),
+            (pci_cfg_reg + 2, 0xffff_ffff),
+            (pci_cfg_reg + 3, 0xffff_ffff),
+        ] {
+            shadow.insert(reg, (config.read_config_register(reg), writable));
+        }
+
+        BlkRegisters {
+            config,
+            shadow,
+            msix_reg,
+            pci_cfg_reg,
+            capacity,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: 0,
+            status: 0,
+            isr: 0,
+            msix_config: NO_VECTOR,
+            queue_select: 0,
+            queues: vec![Virtqueue::new()],
+            notified: false,
+        }
+    }
+
+    /// Reset the device, by the driver writing 0 to the device status.
+    fn reset(&mut self) {
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.isr = 0;
+        self.msix_config = NO_VECTOR;
+        self.queue_select = 0;
+        self.queues = vec![Virtqueue::new()];
+        self.notified = false;
+    }
+
+    fn config_read(&self, reg: usize) -> u32 {
+        match self.shadow.get(&reg) {
+            Some((value, _)) => *value,
+            None => self.config.read_config_register(reg),
+        }
+    }
+
+    fn msix_enabled(&self) -> bool {
+        self.config_read(self.msix_reg) & MSIX_ENABLE != 0
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_BLK_F_FLUSH | VIRTIO_F_VERSION_1
+    }
+
+    fn queue(&self) -> Option<&Virtqueue> {
+        self.queues.get(self.queue_select as usize)
+    }
+
+    fn queue_mut(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_select as usize)
+    }
+
+    fn set_status(&mut self, status: u8) {
+        if status == 0 {
+            self.reset();
+        } else {
+            self.status = status;
+        }
+    }
+
+    fn notify(&mut self, queue: u64) {
+        self.notified |= queue < self.queues.len() as u64;
+    }
+
+    /// The offset of `addr` into BAR `bar` of `size` bytes, if it falls in there.
+    fn bar_offset(&self, bar: usize, size: u64, addr: u64) -> Option<u64> {
+        let reg = BAR0_REG + bar;
+        let low = self.config.read_config_register(reg);
+        let base = if low & 0x1 != 0 {
+            (low & !0x3) as u64
+        } else if low & 0x6 == 0x4 {
+            (self.config.read_config_register(reg + 1) as u64) << 32 | (low & !0xf) as u64
+        } else {
+            (low & !0xf) as u64
+        };
+        if base == 0 || !(base..base + size).contains(&addr) {
+            return None;
+        }
+        Some(addr - base)
+    }
+
+    fn device_config(&self) -> Vec<u8> {
+        let mut bytes = self.capacity.to_le_bytes().to_vec();
+        bytes.resize(DEVICE_CFG_LEN, 0);
+        bytes
+    }
+
+    fn common_config(&self) -> Vec<u8> {
+        let features = match self.device_feature_select {
+            0 => self.device_features() as u32,
+            1 => (self.device_features() >> 32) as u32,
+            _ => 0,
+        };
+        let driver_features = match self.driver_feature_select {
+            0 => self.driver_features as u32,
+            1 => (self.driver_features >> 32) as u32,
+            _ => 0,
+        };
+        let queue = self.queue().copied();
+
+        let mut bytes = Vec::with_capacity(COMMON_CFG_LEN);
+        bytes.extend_from_slice(&self.device_feature_select.to_le_bytes());
+        bytes.extend_from_slice(&features.to_le_bytes());
+        bytes.extend_from_slice(&self.driver_feature_select.to_le_bytes());
+        bytes.extend_from_slice(&driver_features.to_le_bytes());
+        bytes.extend_from_slice(&self.msix_config.to_le_bytes());
+        bytes.extend_from_slice(&(self.queues.len() as u16).to_le_bytes());
+        // The config never changes, so neither does its generation.
+        bytes.extend_from_slice(&[self.status, 0]);
+        bytes.extend_from_slice(&self.queue_select.to_le_bytes());
+        // A queue which does not exist reads as size 0.
+        let queue_fields = queue.map_or([0; 4], |q| [q.size, q.vector, q.ready as u16, 0]);
+        for field in queue_fields.iter() {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        let addresses = queue.map_or([0; 3], |q| [q.desc, q.driver, q.device]);
+        for addr in addresses.iter() {
+            bytes.extend_from_slice(&addr.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn common_write(&mut self, offset: usize, data: &[u8]) {
+        let value = le_value(data);
+        match (offset, data.len()) {
+            (0x00, 4) => self.device_feature_select = value as u32,
+            (0x08, 4) => self.driver_feature_select = value as u32,
+            (0x0c, 4) if self.driver_feature_select < 2 => {
+                let shift = 32 * self.driver_feature_select;
+                self.driver_features =
+                    (self.driver_features & !(0xffff_ffff << shift)) | value << shift;
+            }
+            (0x10, 2) => self.msix_config = value as u16,
+            (0x14, 1) => self.set_status(value as u8),
+            (0x16, 2) => self.queue_select = value as u16,
+            (0x18, 2) if (value as u16).is_power_of_two() && value as u16 <= QUEUE_SIZE => {
+                if let Some(queue) = self.queue_mut() {
+                    queue.size = value as u16;
+                }
+            }
+            (0x1a, 2) => {
+                if let Some(queue) = self.queue_mut() {
+                    queue.vector = value as u16;
+                }
+            }
+            (0x1c, 2) => {
+                if let Some(queue) = self.queue_mut() {
+                    queue.ready = value == 1;
+                }
+            }
+            // The ring addresses, written whole or by halves.
+            (0x20..=0x37, 4) | (0x20..=0x37, 8) if offset.is_multiple_of(data.len()) => {
+                let shift = (offset % 8) * 8;
+                let mask = if data.len() == 8 {
+                    u64::MAX
+                } else {
+                    0xffff_ffff << shift
+                };
+                if let Some(queue) = self.queue_mut() {
+                    let addr = match (offset - 0x20) / 8 {
+                        0 => &mut queue.desc,
+                        1 => &mut queue.driver,
+                        _ => &mut queue.device,
+                    };
+                    *addr = (*addr & !mask) | (value << shift & mask);
+                }
+            }
+            _ => debug!(
+                "virtio-blk ignores a write of {} bytes to the common config at {:#x}",
+                data.len(),
+                offset
+            ),
+        }
+    }
+
+    fn modern_read(&mut self, offset: u64, data: &mut [u8]) {
+        let (base, bytes) = if offset < ISR_CFG {
+            (COMMON_CFG, self.common_config())
+        } else if offset < DEVICE_CFG {
+            // Reading the ISR status clears it.
+            (ISR_CFG, vec![std::mem::take(&mut self.isr)])
+        } else if offset < NOTIFY_CFG {
+            (DEVICE_CFG, self.device_config())
+        } else {
+            (NOTIFY_CFG, vec![])
+        };
+        copy_out(&bytes, (offset - base) as usize, data);
+    }
+
+    fn modern_write(&mut self, offset: u64, data: &[u8]) {
+        if offset < ISR_CFG {
+            self.common_write((offset - COMMON_CFG) as usize, data);
+        } else if offset >= NOTIFY_CFG {
+            self.notify((offset - NOTIFY_CFG) / NOTIFY_OFF_MULTIPLIER as u64);
+        }
+    }
+
+    /// The legacy interface, whose device config moves behind the MSI-X vectors while MSI-X
+    /// is enabled.
+    fn legacy_config(&self) -> Vec<u8> {
+        let queue = self.queue().copied();
+
+        let mut bytes = Vec::with_capacity(LEGACY_BAR_SIZE as usize);
+        bytes.extend_from_slice(&(self.device_features() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.driver_features as u32).to_le_bytes());
+        let pfn = queue.map_or(0, |q| (q.desc >> 12) as u32);
+        bytes.extend_from_slice(&pfn.to_le_bytes());
+        let size = queue.map_or(0, |_| QUEUE_SIZE);
+        bytes.extend_from_slice(&size.to_le_bytes());
+        bytes.extend_from_slice(&self.queue_select.to_le_bytes());
+        // Queue notify.
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&[self.status, self.isr]);
+        if self.msix_enabled() {
+            bytes.extend_from_slice(&self.msix_config.to_le_bytes());
+            let vector = queue.map_or(NO_VECTOR, |q| q.vector);
+            bytes.extend_from_slice(&vector.to_le_bytes());
+        }
+        bytes.extend(self.device_config());
+        bytes
+    }
+
+    fn legacy_read(&mut self, offset: u64, data: &mut [u8]) {
+        copy_out(&self.legacy_config(), offset as usize, data);
+        // Reading the ISR status clears it.
+        if (offset..offset + data.len() as u64).contains(&0x13) {
+            self.isr = 0;
+        }
+    }
+
+    fn legacy_write(&mut self, offset: u64, data: &[u8]) {
+        let value = le_value(data);
+        let msix = self.msix_enabled();
+        match (offset, data.len()) {
+            // The legacy interface has no feature bits past 31.
+            (0x04, 4) => self.driver_features = value,
+            (0x08, 4) => {
+                if let Some(queue) = self.queue_mut() {
+                    queue.set_pfn(value as u32);
+                }
+            }
+            (0x0e, 2) => self.queue_select = value as u16,
+            (0x10, 2) => self.notify(value),
+            (0x12, 1) => self.set_status(value as u8),
+            (0x14, 2) if msix => self.msix_config = value as u16,
+            (0x16, 2) if msix => {
+                if let Some(queue) = self.queue_mut() {
+                    queue.vector = value as u16;
+                }
+            }
+            _ => debug!(
+                "virtio-blk ignores a legacy write of {} bytes at {:#x}",
+                data.len(),
+                offset
+            ),
+        }
+    }
+
+    /// The BAR, offset and length of the window of the `VIRTIO_PCI_CAP_PCI_CFG` capability.
+    fn window(&self) -> (usize, u64, usize) {
+        (
+            (self.config_read(self.pci_cfg_reg + 1) & 0xff) as usize,
+            self.config_read(self.pci_cfg_reg + 2) as u64,
+            (self.config_read(self.pci_cfg_reg + 3) as usize).min(4),
+        )
+    }
+}
+
+impl SimpleDevice for BlkRegisters {
+    fn on_config_read(&mut self, _: u16, reg: usize) -> u32 {
+        if reg != self.pci_cfg_reg + 4 {
+            return self.config_read(reg);
+        }
+
+        let (bar, offset, len) = self.window();
+        let mut data = [0u8; 4];
+        match bar {
+            LEGACY_BAR => self.legacy_read(offset, &mut data[..len]),
+            MODERN_BAR => self.modern_read(offset, &mut data[..len]),
+            _ => (),
+        }
+        u32::from_le_bytes(data)
+    }
+
+    fn on_config_write(&mut self, _: u16, reg: usize, offset: u64, data: &[u8]) {
+        if reg == self.pci_cfg_reg + 4 {
+            let (bar, window, len) = self.window();
+            let mut bytes = [0u8; 4];
+            let offset = offset as usize;
+            bytes[offset..offset + data.len()].copy_from_slice(data);
+            match bar {
+                LEGACY_BAR => self.legacy_write(window, &bytes[..len]),
+                MODERN_BAR => self.modern_write(window, &bytes[..len]),
+                _ => (),
+            }
+            return;
+        }
+
+        let (value, writable) = match self.shadow.get_mut(&reg) {
+            Some(shadow) => shadow,
+            None => return self.config.write_config_register(reg, offset, data),
+        };
+        let (mut bits, mut mask) = (0u32, 0u32);
+        for (n, byte) in data.iter().enumerate() {
+            let shift = (offset as usize + n) * 8;
+            bits |= (*byte as u32) << shift;
+            mask |= 0xff << shift;
+        }
+        let mask = mask & *writable;
+        *value = (*value & !mask) | (bits & mask);
+    }
+
+    fn on_mem_read(&mut self, addr: u64, data: &mut [u8]) -> std::result::Result<(), u8> {
+        let offset = self
+            .bar_offset(MODERN_BAR, MODERN_BAR_SIZE, addr)
+            .ok_or(CPL_UR)?;
+        self.modern_read(offset, data);
+        Ok(())
+    }
+
+    fn on_mem_write(&mut self, addr: u64, data: &[u8]) {
+        if let Some(offset) = self.bar_offset(MODERN_BAR, MODERN_BAR_SIZE, addr) {
+            self.modern_write(offset, data);
+        }
+    }
+
+    fn on_io_read(&mut self, addr: u32, data: &mut [u8]) -> std::result::Result<(), u8> {
+        let offset = self
+            .bar_offset(LEGACY_BAR, LEGACY_BAR_SIZE, addr as u64)
+            .ok_or(CPL_UR)?;
+        self.legacy_read(offset, data);
+        Ok(())
+    }
+
+    fn on_io_write(&mut self, addr: u32, data: &[u8]) -> std::result::Result<(), u8> {
+        let offset = self
+            .bar_offset(LEGACY_BAR, LEGACY_BAR_SIZE, addr as u64)
+            .ok_or(CPL_UR)?;
+        self.legacy_write(offset, data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::test_allocator;
+
+    use crossbeam_channel::{unbounded, Sender};
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct VecMemory(Arc<Mutex<Vec<u8>>>);
+
+    impl DmaMemory for VecMemory {
+        fn read(&self, gpa: u64, data: &mut [u8]) -> io::Result<()> {
+            let gpa = gpa as usize;
+            data.copy_from_slice(&self.0.lock().unwrap()[gpa..gpa + data.len()]);
+            Ok(())
+        }
+
+        fn write(&self, gpa: u64, data: &[u8]) -> io::Result<()> {
+            let gpa = gpa as usize;
+            self.0.lock().unwrap()[gpa..gpa + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    impl VecMemory {
+        fn put(&self, gpa: u64, data: &[u8]) {
+            self.write(gpa, data).unwrap();
+        }
+
+        fn get(&self, gpa: u64, len: usize) -> Vec<u8> {
+            let mut data = vec![0; len];
+            self.read(gpa, &mut data).unwrap();
+            data
+        }
+    }
+
+    struct ChannelSink(Sender<(u64, u32)>);
+
+    impl MsiSink for ChannelSink {
+        fn inject(&self, addr: u64, data: u32) -> io::Result<()> {
+            self.0.send((addr, data)).unwrap();
+            Ok(())
+        }
+    }
+
+    /// Make the request of `header` with a buffer of `len` bytes at 0x6000 available in the
+    /// queue at 0x1000, as entry `n`.
+    fn submit(memory: &VecMemory, n: u16, header: &[u8], len: u32, write: bool) {
+        let desc = |addr: u64, len: u32, flags: u16, next: u16| {
+            let mut bytes = addr.to_le_bytes().to_vec();
+            bytes.extend_from_slice(&len.to_le_bytes());
+            bytes.extend_from_slice(&flags.to_le_bytes());
+            bytes.extend_from_slice(&next.to_le_bytes());
+            bytes
+        };
+        memory.put(0x5000, header);
+        let flags = VRING_DESC_F_NEXT | if write { 0 } else { VRING_DESC_F_WRITE };
+        let mut table = desc(0x5000, 16, VRING_DESC_F_NEXT, 1);
+        table.extend(desc(0x6000, len, flags, 2));
+        table.extend(desc(0x7000, 1, VRING_DESC_F_WRITE, 0));
+        memory.put(0x1000, &table);
+
+        memory.put(0x2004 + 2 * n as u64, &0u16.to_le_bytes());
+        memory.put(0x2002, &(n + 1).to_le_bytes());
+    }
+
+    #[test]
+    fn virtio_blk() {
+        let disk = Cursor::new(vec![0u8; 0x10000]);
+        let mut adapter = PciAdapter::start(Box::new(VirtioBlkDevice::new(disk).unwrap()));
+        let (tx, rx) = unbounded();
+        adapter.set_msi_sink(Box::new(ChannelSink(tx)));
+        let memory = VecMemory(Arc::new(Mutex::new(vec![0; 0x8000])));
+        adapter.set_dma_memory(Box::new(memory.clone()));
+
+        let mut allocator = test_allocator();
+        let bars = adapter.allocate_bars(&mut allocator).unwrap();
+        let base = |size: u64| {
+            bars.iter()
+                .find(|(_, len, _)| *len == size)
+                .unwrap()
+                .0
+                .raw_value()
+        };
+        let (legacy, msix, modern) = (
+            base(LEGACY_BAR_SIZE),
+            base(MSIX_BAR_SIZE),
+            base(MODERN_BAR_SIZE),
+        );
+        assert_eq!(adapter.config_read(0), 0x1001_1af4);
+        assert_eq!(adapter.config_read(11), 0x0002_1af4);
+
+        // The capability after MSI-X locates the common config.
+        let cap = (adapter.config_read(13) & 0xfc) as usize >> 2;
+        let next = (adapter.config_read(cap) >> 8 & 0xfc) as usize >> 2;
+        assert_eq!(
+            adapter.config_read(next),
+            0x0110_0009 | (next as u32 + 4) << 10
+        );
+        assert_eq!(adapter.config_read(next + 1), MODERN_BAR as u32);
+
+        // The legacy interface offers the same device.
+        let mut data = [0u8; 4];
+        adapter.bar_mmio_read(legacy, &mut data);
+        assert_eq!(le_value(&data), VIRTIO_BLK_F_FLUSH);
+
+        // Program vector 1 of MSI-X, then set the device up.
+        adapter.bar_mmio_write(msix + 0x10, &0xfee0_1000u64.to_le_bytes());
+        adapter.bar_mmio_write(msix + 0x18, &0x4021u32.to_le_bytes());
+        adapter.bar_mmio_write(msix + 0x1c, &0u32.to_le_bytes());
+        adapter.write_config_register(cap, 2, &[0x01, 0x80]);
+
+        let common = |adapter: &mut PciAdapter, offset: u64, data: &[u8]| {
+            adapter.bar_mmio_write(modern + COMMON_CFG + offset, data);
+        };
+        common(&mut adapter, 0x14, &[0x3]);
+        common(&mut adapter, 0x00, &1u32.to_le_bytes());
+        adapter.bar_mmio_read(modern + 0x04, &mut data);
+        assert_eq!(le_value(&data), VIRTIO_F_VERSION_1 >> 32);
+        common(&mut adapter, 0x08, &1u32.to_le_bytes());
+        common(&mut adapter, 0x0c, &1u32.to_le_bytes());
+        common(&mut adapter, 0x14, &[0xb]);
+        common(&mut adapter, 0x18, &16u16.to_le_bytes());
+        common(&mut adapter, 0x1a, &1u16.to_le_bytes());
+        common(&mut adapter, 0x20, &0x1000u64.to_le_bytes());
+        common(&mut adapter, 0x28, &0x2000u32.to_le_bytes());
+        common(&mut adapter, 0x30, &0x3000u32.to_le_bytes());
+        common(&mut adapter, 0x1c, &1u16.to_le_bytes());
+        common(&mut adapter, 0x14, &[0xf]);
+
+        adapter.bar_mmio_read(modern + DEVICE_CFG, &mut data);
+        assert_eq!(le_value(&data), 0x10000 / SECTOR_SIZE);
+
+        // Write sector 2, then read it back.
+        let header = |kind: u32, sector: u64| {
+            let mut bytes = kind.to_le_bytes().to_vec();
+            bytes.extend_from_slice(&[0; 4]);
+            bytes.extend_from_slice(&sector.to_le_bytes());
+            bytes
+        };
+        memory.put(0x6000, &[0xa5; 512]);
+        memory.put(0x7000, &[0xff]);
+        submit(&memory, 0, &header(VIRTIO_BLK_T_OUT, 2), 512, true);
+        adapter.bar_mmio_write(modern + NOTIFY_CFG, &0u16.to_le_bytes());
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok((0xfee0_1000, 0x4021))
+        );
+        assert_eq!(memory.get(0x3002, 2), [1, 0]);
+        assert_eq!(memory.get(0x3004, 8), [0, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(memory.get(0x7000, 1), [VIRTIO_BLK_S_OK]);
+
+        memory.put(0x6000, &[0; 512]);
+        submit(&memory, 1, &header(VIRTIO_BLK_T_IN, 2), 512, false);
+        adapter.bar_mmio_write(modern + NOTIFY_CFG, &0u16.to_le_bytes());
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok((0xfee0_1000, 0x4021))
+        );
+        assert_eq!(memory.get(0x3002, 2), [2, 0]);
+        assert_eq!(memory.get(0x3010, 4), 513u32.to_le_bytes());
+        assert_eq!(memory.get(0x6000, 512), [0xa5; 512]);
+        assert_eq!(memory.get(0x7000, 1), [VIRTIO_BLK_S_OK]);
+
+        // Past the end of the disk.
+        submit(&memory, 2, &header(VIRTIO_BLK_T_IN, 0x80), 512, false);
+        adapter.bar_mmio_write(modern + NOTIFY_CFG, &0u16.to_le_bytes());
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(memory.get(0x7000, 1), [VIRTIO_BLK_S_IOERR]);
+
+        adapter.stop();
+        adapter.join();
+    }
+}
@@ -0,0 +1,22 @@
+#!/bin/sh
+# Build the smoke test initramfs.
+#
+# usage: mkinitramfs.sh <static busybox> <pcie_tlp_smoke.ko> <output cpio>
+set -e
+
+BUSYBOX=$1
+MODULE=$2
+OUTPUT=$(realpath "$3")
+ROOT=$(mktemp -d)
+trap 'rm -rf "$ROOT"' EXIT
+
+mkdir -p "$ROOT/bin" "$ROOT/proc" "$ROOT/sys" "$ROOT/dev"
+cp "$BUSYBOX" "$ROOT/bin/busybox"
+for applet in sh mount insmod poweroff echo; do
+    ln -s busybox "$ROOT/bin/$applet"
+done
+cp "$MODULE" "$ROOT/pcie_tlp_smoke.ko"
+cp "$(dirname "$0")/init" "$ROOT/init"
+chmod +x "$ROOT/init"
+
+(cd "$ROOT" && find . | cpio -o -H newc --quiet) > "$OUTPUT"
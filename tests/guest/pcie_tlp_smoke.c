@@ -0,0 +1,245 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * Guest side of the pcie-tlp smoke test.
+ *
+ * Probes the PciTestDevice and the PciEduDevice exposed by the host harness and validates
+ * what the guest kernel sees through the whole stack: enumeration, BAR sizing and assignment
+ * and MMIO access to BAR0 of the test device, a DMA round trip through the buffer of the edu
+ * device and the MSI it raises once done. The result is reported as a single
+ * "pcie-tlp-smoke: PASS" or "pcie-tlp-smoke: FAIL" line which the harness looks for on the
+ * serial console.
+ */
+
+#include <linux/completion.h>
+#include <linux/dma-mapping.h>
+#include <linux/interrupt.h>
+#include <linux/module.h>
+#include <linux/pci.h>
+
+#define SMOKE_VENDOR_ID		0x1234
+#define SMOKE_DEVICE_ID		0x5678
+#define SMOKE_BAR0_SIZE		0x100000
+#define SMOKE_BAR2_SIZE		0x100
+/* PciTestDevice answers every BAR0 read with this pattern. */
+#define SMOKE_BAR0_PATTERN	0x78563412
+
+#define EDU_VENDOR_ID		0x1234
+#define EDU_DEVICE_ID		0x11e8
+#define EDU_IRQ_STATUS		0x24
+#define EDU_IRQ_ACK		0x64
+#define EDU_DMA_SRC		0x80
+#define EDU_DMA_DST		0x88
+#define EDU_DMA_COUNT		0x90
+#define EDU_DMA_CMD		0x98
+#define EDU_DMA_START		0x1
+#define EDU_DMA_TO_RAM		0x2
+#define EDU_DMA_IRQ		0x4
+#define EDU_IRQ_DMA		0x100
+/* The DMA buffer of the device, in the addresses of its DMA engine. */
+#define EDU_DMA_BUFFER		0x40000
+#define EDU_DMA_BITS		28
+#define SMOKE_DMA_SIZE		64
+
+enum smoke_device {
+	SMOKE_TEST,
+	SMOKE_EDU,
+	SMOKE_DEVICES,
+};
+
+/* The devices which passed their checks. */
+static unsigned long smoke_passed;
+
+struct smoke_edu {
+	void __iomem *bar0;
+	struct completion dma_done;
+	u32 irq_status;
+};
+
+static int smoke_check_bars(struct pci_dev *pdev)
+{
+	if (!(pci_resource_flags(pdev, 0) & IORESOURCE_MEM_64) ||
+	    pci_resource_len(pdev, 0) != SMOKE_BAR0_SIZE) {
+		pr_err("pcie-tlp-smoke: unexpected BAR0 %pR\n", &pdev->resource[0]);
+		return -EINVAL;
+	}
+
+	if (!(pci_resource_flags(pdev, 2) & IORESOURCE_IO) ||
+	    pci_resource_len(pdev, 2) != SMOKE_BAR2_SIZE) {
+		pr_err("pcie-tlp-smoke: unexpected BAR2 %pR\n", &pdev->resource[2]);
+		return -EINVAL;
+	}
+
+	return 0;
+}
+
+static int smoke_probe_test(struct pci_dev *pdev)
+{
+	void __iomem *bar0;
+	u32 value;
+	int ret;
+
+	ret = smoke_check_bars(pdev);
+	if (ret)
+		return ret;
+
+	ret = pcim_enable_device(pdev);
+	if (ret)
+		return ret;
+
+	ret = pcim_iomap_regions(pdev, BIT(0), "pcie-tlp-smoke");
+	if (ret)
+		return ret;
+
+	bar0 = pcim_iomap_table(pdev)[0];
+	value = ioread32(bar0);
+	if (value != SMOKE_BAR0_PATTERN) {
+		pr_err("pcie-tlp-smoke: BAR0 read %#x\n", value);
+		return -EIO;
+	}
+
+	/* Posted write, must not hang the vCPU. */
+	iowrite32(0xdeadbeef, bar0 + 4);
+
+	return 0;
+}
+
+static irqreturn_t smoke_edu_irq(int irq, void *data)
+{
+	struct smoke_edu *edu = data;
+
+	edu->irq_status = ioread32(edu->bar0 + EDU_IRQ_STATUS);
+	iowrite32(edu->irq_status, edu->bar0 + EDU_IRQ_ACK);
+	complete(&edu->dma_done);
+	return IRQ_HANDLED;
+}
+
+/* Run a DMA of the edu device, the registers take the low 32 bits of the addresses. */
+static void smoke_edu_dma(struct smoke_edu *edu, u32 src, u32 dst, u32 cmd)
+{
+	iowrite32(src, edu->bar0 + EDU_DMA_SRC);
+	iowrite32(dst, edu->bar0 + EDU_DMA_DST);
+	iowrite32(SMOKE_DMA_SIZE, edu->bar0 + EDU_DMA_COUNT);
+	iowrite32(EDU_DMA_START | cmd, edu->bar0 + EDU_DMA_CMD);
+}
+
+static int smoke_probe_edu(struct pci_dev *pdev)
+{
+	struct smoke_edu *edu;
+	dma_addr_t dma;
+	u8 *buf;
+	int ret, i;
+
+	edu = devm_kzalloc(&pdev->dev, sizeof(*edu), GFP_KERNEL);
+	if (!edu)
+		return -ENOMEM;
+	init_completion(&edu->dma_done);
+
+	ret = pcim_enable_device(pdev);
+	if (ret)
+		return ret;
+
+	ret = pcim_iomap_regions(pdev, BIT(0), "pcie-tlp-smoke");
+	if (ret)
+		return ret;
+	edu->bar0 = pcim_iomap_table(pdev)[0];
+	pci_set_master(pdev);
+
+	ret = dma_set_mask_and_coherent(&pdev->dev, DMA_BIT_MASK(EDU_DMA_BITS));
+	if (ret)
+		return ret;
+
+	/* The source in the first half, the destination in the second one. */
+	buf = dmam_alloc_coherent(&pdev->dev, 2 * SMOKE_DMA_SIZE, &dma, GFP_KERNEL);
+	if (!buf)
+		return -ENOMEM;
+	for (i = 0; i < SMOKE_DMA_SIZE; i++)
+		buf[i] = i ^ 0x5a;
+
+	ret = pci_alloc_irq_vectors(pdev, 1, 1, PCI_IRQ_MSI);
+	if (ret < 0)
+		return ret;
+
+	ret = devm_request_irq(&pdev->dev, pci_irq_vector(pdev, 0), smoke_edu_irq, 0,
+			       "pcie-tlp-smoke", edu);
+	if (ret)
+		return ret;
+
+	/* Into the buffer of the device, then back to RAM with an interrupt once done. */
+	smoke_edu_dma(edu, lower_32_bits(dma), EDU_DMA_BUFFER, 0);
+	smoke_edu_dma(edu, EDU_DMA_BUFFER, lower_32_bits(dma + SMOKE_DMA_SIZE),
+		      EDU_DMA_TO_RAM | EDU_DMA_IRQ);
+
+	if (!wait_for_completion_timeout(&edu->dma_done, HZ)) {
+		pr_err("pcie-tlp-smoke: no MSI after the DMA\n");
+		return -ETIMEDOUT;
+	}
+
+	if (edu->irq_status != EDU_IRQ_DMA) {
+		pr_err("pcie-tlp-smoke: interrupt status %#x\n", edu->irq_status);
+		return -EIO;
+	}
+
+	if (memcmp(buf, buf + SMOKE_DMA_SIZE, SMOKE_DMA_SIZE)) {
+		pr_err("pcie-tlp-smoke: DMA round trip mismatch\n");
+		return -EIO;
+	}
+
+	return 0;
+}
+
+static int smoke_probe(struct pci_dev *pdev, const struct pci_device_id *id)
+{
+	int ret;
+
+	if (id->driver_data == SMOKE_EDU)
+		ret = smoke_probe_edu(pdev);
+	else
+		ret = smoke_probe_test(pdev);
+
+	if (ret) {
+		pr_err("pcie-tlp-smoke: %s failed (%d)\n", pci_name(pdev), ret);
+		return ret;
+	}
+
+	set_bit(id->driver_data, &smoke_passed);
+	return 0;
+}
+
+static const struct pci_device_id smoke_ids[] = {
+	{ PCI_DEVICE(SMOKE_VENDOR_ID, SMOKE_DEVICE_ID), .driver_data = SMOKE_TEST },
+	{ PCI_DEVICE(EDU_VENDOR_ID, EDU_DEVICE_ID), .driver_data = SMOKE_EDU },
+	{ }
+};
+MODULE_DEVICE_TABLE(pci, smoke_ids);
+
+static struct pci_driver smoke_driver = {
+	.name		= "pcie-tlp-smoke",
+	.id_table	= smoke_ids,
+	.probe		= smoke_probe,
+};
+
+static int __init smoke_init(void)
+{
+	int ret;
+
+	/* The devices are already enumerated, so they are probed before this returns. */
+	ret = pci_register_driver(&smoke_driver);
+	if (ret)
+		return ret;
+
+	if (smoke_passed == BIT(SMOKE_DEVICES) - 1)
+		pr_info("pcie-tlp-smoke: PASS\n");
+	else
+		pr_err("pcie-tlp-smoke: FAIL (passed %#lx)\n", smoke_passed);
+	return 0;
+}
+module_init(smoke_init);
+
+static void __exit smoke_exit(void)
+{
+	pci_unregister_driver(&smoke_driver);
+}
+module_exit(smoke_exit);
+
+MODULE_DESCRIPTION("pcie-tlp guest smoke test");
+MODULE_LICENSE("GPL");
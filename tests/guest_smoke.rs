@@ -0,0 +1,368 @@
+//! Host harness of the guest smoke test.
+//!
+//! Boots a Linux bzImage together with the initramfs produced by `tests/guest/mkinitramfs.sh`
+//! in a bare KVM virtual machine which exposes a [`PciTestDevice`] at 00:03.0 and a
+//! [`PciEduDevice`] at 00:04.0, each through its own [`PciAdapter`]. The adapters reach the
+//! guest RAM for DMA and signal MSIs to the in-kernel local APIC, which needs no IO-APIC and so
+//! works with `noapic`. The guest module reports the result on the early serial console.
+//!
+//! The test is ignored by default since it needs a guest kernel:
+//!
+//! ```text
+//! PCIE_TLP_GUEST_KERNEL=bzImage PCIE_TLP_GUEST_INITRD=smoke.cpio \
+//!     cargo test --features kvm-demo --test guest_smoke -- --ignored
+//! ```
+
+use kvm_bindings::{kvm_pit_config, kvm_segment, kvm_userspace_memory_region};
+use kvm_ioctls::{Kvm, VcpuExit, VcpuFd, VmFd};
+use pci::PciDevice;
+use pcie_tlp::{DmaMemory, KvmMsiSink, PciAdapter, PciEduDevice, PciSimDevice, PciTestDevice};
+use std::io;
+use std::ops::Range;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use vm_allocator::{GsiApic, SystemAllocator};
+use vm_memory::GuestAddress;
+
+const MEM_SIZE: usize = 512 << 20;
+const GDT_ADDR: usize = 0x500;
+const ZERO_PAGE_ADDR: usize = 0x7000;
+const PML4_ADDR: usize = 0x9000;
+const PDPT_ADDR: usize = 0xa000;
+const PD_ADDR: usize = 0xb000;
+const CMDLINE_ADDR: usize = 0x20000;
+const KERNEL_ADDR: usize = 0x10_0000;
+const INITRD_ADDR: usize = 0x1000_0000;
+
+const CMDLINE: &str = "earlyprintk=serial,ttyS0,115200 keep_bootcon console=ttyS0 \
+                       noapic noacpi pci=conf1,nochecks reboot=k panic=-1 rdinit=/init";
+
+const SERIAL_DATA: u16 = 0x3f8;
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const TEST_DEVICE: u32 = 3;
+const EDU_DEVICE: u32 = 4;
+
+const PASS: &str = "pcie-tlp-smoke: PASS";
+const FAIL: &str = "pcie-tlp-smoke: FAIL";
+
+/// Guest memory backed by an anonymous mapping, which is never unmapped.
+#[derive(Clone, Copy)]
+struct GuestRam(*mut u8);
+
+unsafe impl Send for GuestRam {}
+
+impl GuestRam {
+    fn new() -> Self {
+        let mem = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                MEM_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(mem, libc::MAP_FAILED);
+        GuestRam(mem as *mut u8)
+    }
+
+    fn load(&self, addr: usize, data: &[u8]) {
+        assert!(addr + data.len() <= MEM_SIZE);
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), self.0.add(addr), data.len()) }
+    }
+
+    fn load_u64(&self, addr: usize, value: u64) {
+        self.load(addr, &value.to_le_bytes());
+    }
+
+    /// The host address of `len` bytes at `gpa`, if they are all in the RAM.
+    fn host(&self, gpa: u64, len: usize) -> io::Result<*mut u8> {
+        match (gpa as usize).checked_add(len) {
+            Some(end) if end <= MEM_SIZE => Ok(unsafe { self.0.add(gpa as usize) }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DMA outside of the guest RAM",
+            )),
+        }
+    }
+}
+
+impl DmaMemory for GuestRam {
+    fn read(&self, gpa: u64, data: &mut [u8]) -> io::Result<()> {
+        let src = self.host(gpa, data.len())?;
+        unsafe { std::ptr::copy_nonoverlapping(src, data.as_mut_ptr(), data.len()) };
+        Ok(())
+    }
+
+    fn write(&self, gpa: u64, data: &[u8]) -> io::Result<()> {
+        let dst = self.host(gpa, data.len())?;
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Ok(())
+    }
+}
+
+/// A device on bus 0 of the guest.
+struct Device {
+    number: u32,
+    adapter: PciAdapter,
+    bars: Vec<Range<u64>>,
+}
+
+impl Device {
+    fn start(
+        number: u32,
+        device: Box<dyn PciSimDevice + Send + Sync>,
+        allocator: &mut SystemAllocator,
+        ram: GuestRam,
+        vm: &Arc<VmFd>,
+    ) -> Self {
+        let mut adapter = PciAdapter::start(device);
+        adapter.set_dma_memory(Box::new(ram));
+        adapter.set_msi_sink(Box::new(KvmMsiSink::new(vm.clone())));
+        let bars = adapter
+            .allocate_bars(allocator)
+            .unwrap()
+            .into_iter()
+            .map(|(addr, size, _)| addr.0..addr.0 + size)
+            .collect();
+        Device {
+            number,
+            adapter,
+            bars,
+        }
+    }
+
+    fn owns(&self, addr: u64) -> bool {
+        self.bars.iter().any(|bar| bar.contains(&addr))
+    }
+}
+
+/// The adapter and the register which `address` of the configuration mechanism selects, for
+/// function 0 of the devices on bus 0.
+fn config_target(devices: &mut [Device], address: u32) -> Option<(&mut PciAdapter, usize)> {
+    if address & 0x8000_0000 == 0 || address & 0x00ff_0700 != 0 {
+        return None;
+    }
+    let number = (address >> 11) & 0x1f;
+    let device = devices.iter_mut().find(|device| device.number == number)?;
+    Some((&mut device.adapter, ((address & 0xfc) >> 2) as usize))
+}
+
+/// Load the kernel, initrd and zero page following the Linux x86 64-bit boot protocol.
+/// Returns the entry point of the kernel.
+fn load_linux(ram: &GuestRam, kernel: &[u8], initrd: &[u8]) -> u64 {
+    assert_eq!(&kernel[0x202..0x206], b"HdrS", "not a bzImage");
+
+    let setup_sects = match kernel[0x1f1] {
+        0 => 4,
+        n => n as usize,
+    };
+    let setup_size = (setup_sects + 1) * 512;
+    ram.load(KERNEL_ADDR, &kernel[setup_size..]);
+    ram.load(INITRD_ADDR, initrd);
+
+    let mut cmdline = CMDLINE.as_bytes().to_vec();
+    cmdline.push(0);
+    ram.load(CMDLINE_ADDR, &cmdline);
+
+    // The setup header is copied verbatim into the zero page, then patched.
+    let mut zero_page = vec![0u8; 4096];
+    let header_end = 0x202 + kernel[0x201] as usize;
+    zero_page[0x1f1..header_end].copy_from_slice(&kernel[0x1f1..header_end]);
+    zero_page[0x210] = 0xff; // type_of_loader
+    zero_page[0x211] |= 0x1; // loadflags: LOADED_HIGH
+    zero_page[0x218..0x21c].copy_from_slice(&(INITRD_ADDR as u32).to_le_bytes());
+    zero_page[0x21c..0x220].copy_from_slice(&(initrd.len() as u32).to_le_bytes());
+    zero_page[0x228..0x22c].copy_from_slice(&(CMDLINE_ADDR as u32).to_le_bytes());
+
+    // e820 map: low memory and everything above 1MB.
+    let e820 = [
+        (0u64, 0x9fc00u64),
+        (KERNEL_ADDR as u64, (MEM_SIZE - KERNEL_ADDR) as u64),
+    ];
+    for (i, (addr, size)) in e820.iter().enumerate() {
+        let entry = 0x2d0 + i * 20;
+        zero_page[entry..entry + 8].copy_from_slice(&addr.to_le_bytes());
+        zero_page[entry + 8..entry + 16].copy_from_slice(&size.to_le_bytes());
+        zero_page[entry + 16..entry + 20].copy_from_slice(&1u32.to_le_bytes());
+    }
+    zero_page[0x1e8] = e820.len() as u8;
+    ram.load(ZERO_PAGE_ADDR, &zero_page);
+
+    KERNEL_ADDR as u64 + 0x200
+}
+
+fn segment(selector: u16, type_: u8, long: bool) -> kvm_segment {
+    kvm_segment {
+        base: 0,
+        limit: 0xffff_ffff,
+        selector,
+        type_,
+        present: 1,
+        s: 1,
+        l: long as u8,
+        db: !long as u8,
+        g: 1,
+        ..Default::default()
+    }
+}
+
+/// Enter long mode with the first 1GB identity mapped.
+fn setup_long_mode(ram: &GuestRam, vcpu: &VcpuFd, entry: u64) {
+    ram.load_u64(GDT_ADDR, 0);
+    ram.load_u64(GDT_ADDR + 8, 0x00af_9b00_0000_ffff);
+    ram.load_u64(GDT_ADDR + 16, 0x00cf_9300_0000_ffff);
+
+    ram.load_u64(PML4_ADDR, PDPT_ADDR as u64 | 0x3);
+    ram.load_u64(PDPT_ADDR, PD_ADDR as u64 | 0x3);
+    for i in 0..512 {
+        ram.load_u64(PD_ADDR + i * 8, ((i as u64) << 21) | 0x83);
+    }
+
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.gdt.base = GDT_ADDR as u64;
+    sregs.gdt.limit = 23;
+    sregs.cs = segment(0x8, 0xb, true);
+    sregs.ds = segment(0x10, 0x3, false);
+    sregs.es = sregs.ds;
+    sregs.fs = sregs.ds;
+    sregs.gs = sregs.ds;
+    sregs.ss = sregs.ds;
+    sregs.cr3 = PML4_ADDR as u64;
+    sregs.cr4 |= 0x20; // PAE
+    sregs.cr0 |= 0x8000_0001; // PG | PE
+    sregs.efer |= 0x500; // LMA | LME
+    vcpu.set_sregs(&sregs).unwrap();
+
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = entry;
+    regs.rsi = ZERO_PAGE_ADDR as u64;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+}
+
+/// Run the guest until the smoke test result shows up on the serial console.
+fn run_guest(kernel: Vec<u8>, initrd: Vec<u8>, result: mpsc::Sender<String>) {
+    let kvm = Kvm::new().expect("failed to open /dev/kvm");
+    let vm = Arc::new(kvm.create_vm().unwrap());
+    let ram = GuestRam::new();
+
+    unsafe {
+        vm.set_user_memory_region(kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0,
+            memory_size: MEM_SIZE as u64,
+            userspace_addr: ram.0 as u64,
+            flags: 0,
+        })
+        .unwrap();
+    }
+    vm.set_tss_address(0xfffb_d000).unwrap();
+    vm.create_irq_chip().unwrap();
+    vm.create_pit2(kvm_pit_config::default()).unwrap();
+
+    let mut allocator = SystemAllocator::new(
+        GuestAddress(0xc000),
+        0x4000,
+        GuestAddress(0xd000_0000),
+        0x1000_0000,
+        GuestAddress(0xc000_0000),
+        0x1000_0000,
+        vec![GsiApic::new(24, 24)],
+    )
+    .unwrap();
+    let test = Box::new(PciTestDevice::new());
+    let edu = Box::new(PciEduDevice::new());
+    let mut devices = vec![
+        Device::start(TEST_DEVICE, test, &mut allocator, ram, &vm),
+        Device::start(EDU_DEVICE, edu, &mut allocator, ram, &vm),
+    ];
+
+    let entry = load_linux(&ram, &kernel, &initrd);
+    let mut vcpu = vm.create_vcpu(0).unwrap();
+    let cpuid = kvm
+        .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+        .unwrap();
+    vcpu.set_cpuid2(&cpuid).unwrap();
+    setup_long_mode(&ram, &vcpu, entry);
+
+    let mut config_address = 0u32;
+    let mut console = String::new();
+
+    loop {
+        match vcpu.run().expect("failed to run vCPU") {
+            VcpuExit::IoOut(SERIAL_DATA, data) => {
+                console.push(data[0] as char);
+                if data[0] == b'\n' {
+                    print!("{}", console);
+                    if console.contains(PASS) || console.contains(FAIL) {
+                        result.send(console).unwrap();
+                        break;
+                    }
+                    console.clear();
+                }
+            }
+            VcpuExit::IoOut(CONFIG_ADDRESS, data) if data.len() == 4 => {
+                config_address = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            }
+            VcpuExit::IoIn(CONFIG_ADDRESS, data) if data.len() == 4 => {
+                data.copy_from_slice(&config_address.to_le_bytes());
+            }
+            VcpuExit::IoOut(port, data) if (CONFIG_DATA..CONFIG_DATA + 4).contains(&port) => {
+                if let Some((adapter, reg_idx)) = config_target(&mut devices, config_address) {
+                    let offset = (port - CONFIG_DATA) as u64;
+                    adapter.write_config_register(reg_idx, offset, data);
+                }
+            }
+            VcpuExit::IoIn(port, data) if (CONFIG_DATA..CONFIG_DATA + 4).contains(&port) => {
+                let value = match config_target(&mut devices, config_address) {
+                    Some((adapter, reg_idx)) => adapter.read_config_register(reg_idx),
+                    None => u32::MAX,
+                };
+                let offset = (port - CONFIG_DATA) as usize;
+                data.copy_from_slice(&value.to_le_bytes()[offset..offset + data.len()]);
+            }
+            VcpuExit::IoIn(_, data) => data.fill(0xff),
+            VcpuExit::IoOut(_, _) => (),
+            VcpuExit::MmioRead(addr, data) => match devices.iter().find(|d| d.owns(addr)) {
+                Some(device) => device.adapter.bar_mmio_read(addr, data),
+                None => data.fill(0xff),
+            },
+            VcpuExit::MmioWrite(addr, data) => {
+                if let Some(device) = devices.iter().find(|d| d.owns(addr)) {
+                    device.adapter.bar_mmio_write(addr, data);
+                }
+            }
+            VcpuExit::Hlt => (),
+            VcpuExit::Shutdown => {
+                result.send(console).unwrap();
+                break;
+            }
+            exit => panic!("unexpected exit reason: {:?}", exit),
+        }
+    }
+
+    for device in devices {
+        device.adapter.stop();
+        device.adapter.join();
+    }
+}
+
+#[test]
+#[ignore]
+fn guest_smoke() {
+    let kernel = std::env::var("PCIE_TLP_GUEST_KERNEL").expect("PCIE_TLP_GUEST_KERNEL not set");
+    let initrd = std::env::var("PCIE_TLP_GUEST_INITRD").expect("PCIE_TLP_GUEST_INITRD not set");
+    let kernel = std::fs::read(kernel).unwrap();
+    let initrd = std::fs::read(initrd).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || run_guest(kernel, initrd, tx));
+
+    let console = rx
+        .recv_timeout(Duration::from_secs(120))
+        .expect("guest did not report the smoke test result");
+    assert!(console.contains(PASS), "guest reported: {}", console);
+}